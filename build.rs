@@ -0,0 +1,532 @@
+// Emits the build info `/version` reports and the skeleton of `/openapi.json`.
+
+use std::path::Path;
+
+use serde_json::{Value, json};
+
+/// Sets `FLYD_GIT_SHA`, `FLYD_BUILD_TIMESTAMP` and `FLYD_FEATURES` for `/version`. Builds
+/// outside a checkout, like the Docker image's, can pass `GIT_SHA` instead.
+fn emit_build_info() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+
+    let git_sha = std::env::var("GIT_SHA").ok().or_else(|| {
+        let output = std::process::Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=FLYD_GIT_SHA={}",
+        git_sha.unwrap_or_else(|| "unknown".to_string())
+    );
+
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+    println!("cargo:rustc-env=FLYD_BUILD_TIMESTAMP={}", built_at);
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=FLYD_FEATURES={}", features.join(","));
+}
+
+/// The JSON schema of a Rust type as written in `src/models.rs`, and whether a field of it
+/// may be left out.
+fn type_schema(ty: &str, schemas: &[String]) -> (Value, bool) {
+    let ty = ty.trim();
+    let (outer, inner) = match ty.split_once('<') {
+        Some((outer, rest)) => (outer, rest.strip_suffix('>').unwrap_or(rest)),
+        None => (ty, ""),
+    };
+    let outer = outer.rsplit("::").next().unwrap_or(outer);
+    let value_type = || split_top_level(inner).last().copied().unwrap_or(inner);
+    let schema = match outer {
+        "Option" => return (type_schema(inner, schemas).0, true),
+        "Box" | "Arc" => return type_schema(inner, schemas),
+        "Vec" | "VecDeque" | "HashSet" | "BTreeSet" => {
+            json!({ "type": "array", "items": type_schema(inner, schemas).0 })
+        }
+        "HashMap" | "BTreeMap" => {
+            json!({ "type": "object", "additionalProperties": type_schema(value_type(), schemas).0 })
+        }
+        "Map" => json!({ "type": "object" }),
+        "String" | "str" | "&str" => json!({ "type": "string" }),
+        "bool" => json!({ "type": "boolean" }),
+        "u8" | "u16" | "u32" | "u64" | "usize" | "i8" | "i16" | "i32" | "i64" | "isize" => {
+            json!({ "type": "integer" })
+        }
+        "f32" | "f64" => json!({ "type": "number" }),
+        "DateTime" => json!({ "type": "string", "format": "date-time" }),
+        "NaiveDate" => json!({ "type": "string", "format": "date" }),
+        name if schemas.iter().any(|schema| schema == name) => {
+            json!({ "$ref": format!("#/components/schemas/{}", name) })
+        }
+        _ => json!({}),
+    };
+    (schema, false)
+}
+
+/// Splits at the commas outside any `<...>`.
+fn split_top_level(list: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in list.char_indices() {
+        match c {
+            '<' | '(' => depth += 1,
+            '>' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(list[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(list[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn rename(name: &str, rename_all: Option<&str>) -> String {
+    let snake = || {
+        let mut out = String::new();
+        for (i, c) in name.chars().enumerate() {
+            if c.is_ascii_uppercase() && i > 0 {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        }
+        out
+    };
+    match rename_all {
+        Some("snake_case") => snake(),
+        Some("kebab-case") => snake().replace('_', "-"),
+        Some("lowercase") => name.to_lowercase(),
+        Some("SCREAMING_SNAKE_CASE") => snake().to_uppercase(),
+        _ => name.to_string(),
+    }
+}
+
+/// The `key = "value"` of a `#[serde(...)]` attribute, e.g. `rename_all`.
+fn serde_arg<'a>(attributes: &'a [String], key: &str) -> Option<&'a str> {
+    attributes.iter().find_map(|attribute| {
+        let start = attribute.find(&format!("{} = \"", key))? + key.len() + 4;
+        let end = attribute[start..].find('"')?;
+        Some(&attribute[start..start + end])
+    })
+}
+
+fn has_serde_flag(attributes: &[String], flag: &str) -> bool {
+    attributes.iter().any(|attribute| {
+        attribute.starts_with("#[serde(")
+            && attribute
+                .trim_start_matches("#[serde(")
+                .trim_end_matches(")]")
+                .split(',')
+                .any(|part| part.trim() == flag)
+    })
+}
+
+struct Item {
+    docs: Vec<String>,
+    attributes: Vec<String>,
+    name: String,
+    is_enum: bool,
+    /// Each field or variant's docs, attributes and line.
+    members: Vec<(Vec<String>, Vec<String>, String)>,
+}
+
+/// The structs and enums of a file that keeps to rustfmt's layout, as `models.rs` does.
+fn parse_items(source: &str) -> Vec<Item> {
+    let mut items = Vec::new();
+    let (mut docs, mut attributes) = (Vec::new(), Vec::new());
+    let mut current: Option<Item> = None;
+    let mut depth = 0;
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if let Some(item) = &mut current {
+            if depth == 1 && trimmed == "}" {
+                items.push(current.take().unwrap());
+                continue;
+            }
+            if let Some(doc) = trimmed.strip_prefix("///") {
+                if depth == 1 {
+                    docs.push(doc.trim().to_string());
+                }
+            } else if trimmed.starts_with("#[") {
+                if depth == 1 {
+                    attributes.push(trimmed.to_string());
+                }
+            } else if !trimmed.is_empty() {
+                match item.members.last_mut() {
+                    // A struct variant's fields, spread over the lines after it.
+                    Some((_, _, member)) if depth > 1 => {
+                        member.push(' ');
+                        member.push_str(trimmed);
+                    }
+                    _ => item.members.push((
+                        std::mem::take(&mut docs),
+                        std::mem::take(&mut attributes),
+                        trimmed.to_string(),
+                    )),
+                }
+                depth += trimmed.matches('{').count();
+                depth -= trimmed.matches('}').count();
+            }
+            continue;
+        }
+        if let Some(doc) = trimmed.strip_prefix("///") {
+            docs.push(doc.trim().to_string());
+        } else if trimmed.starts_with("#[") {
+            attributes.push(trimmed.to_string());
+        } else if let Some(rest) = trimmed
+            .strip_prefix("pub struct ")
+            .map(|rest| (rest, false))
+            .or_else(|| trimmed.strip_prefix("pub enum ").map(|rest| (rest, true)))
+            && let Some(name) = rest.0.strip_suffix(" {")
+        {
+            depth = 1;
+            current = Some(Item {
+                docs: std::mem::take(&mut docs),
+                attributes: std::mem::take(&mut attributes),
+                name: name.to_string(),
+                is_enum: rest.1,
+                members: Vec::new(),
+            });
+        } else {
+            docs.clear();
+            attributes.clear();
+        }
+    }
+    items
+}
+
+fn describe(schema: &mut Value, docs: &[String]) {
+    if !docs.is_empty() {
+        schema["description"] = json!(docs.join(" "));
+    }
+}
+
+/// The properties of a struct, or of an enum's struct variant, and which are required.
+fn object_schema(fields: &[(Vec<String>, Vec<String>, String)], names: &[String]) -> Value {
+    let mut properties = serde_json::Map::new();
+    let mut required = Vec::new();
+    let mut flattened = Vec::new();
+    let mut open = false;
+    for (docs, attributes, line) in fields {
+        let line = line.trim_end_matches(',');
+        let Some((field, ty)) = line.trim_start_matches("pub ").split_once(": ") else {
+            continue;
+        };
+        let (mut schema, optional) = type_schema(ty, names);
+        if has_serde_flag(attributes, "flatten") {
+            match schema.get("$ref") {
+                Some(_) => flattened.push(schema),
+                None => open = true,
+            }
+            continue;
+        }
+        if has_serde_flag(attributes, "skip") {
+            continue;
+        }
+        let field = serde_arg(attributes, "rename").unwrap_or(field).to_string();
+        let defaulted =
+            has_serde_flag(attributes, "default") || serde_arg(attributes, "default").is_some();
+        if !optional && !defaulted {
+            required.push(field.clone());
+        }
+        describe(&mut schema, docs);
+        properties.insert(field, schema);
+    }
+    let mut schema = json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    if open {
+        schema["additionalProperties"] = json!(true);
+    }
+    if flattened.is_empty() {
+        schema
+    } else {
+        flattened.push(schema);
+        json!({ "allOf": flattened })
+    }
+}
+
+fn model_schemas(source: &str) -> serde_json::Map<String, Value> {
+    let items = parse_items(source);
+    let names: Vec<String> = items.iter().map(|item| item.name.clone()).collect();
+    let mut schemas = serde_json::Map::new();
+    for item in &items {
+        let mut schema = if !item.is_enum {
+            let mut schema = object_schema(&item.members, &names);
+            // Every field may be left out of a struct that defaults as a whole.
+            if has_serde_flag(&item.attributes, "default")
+                && let Some(schema) = schema.as_object_mut()
+            {
+                schema.remove("required");
+            }
+            schema
+        } else {
+            let rename_all = serde_arg(&item.attributes, "rename_all");
+            let tag = serde_arg(&item.attributes, "tag");
+            let mut units = Vec::new();
+            let mut variants = Vec::new();
+            for (docs, _, line) in &item.members {
+                let name = line
+                    .split(|c: char| !c.is_ascii_alphanumeric())
+                    .next()
+                    .unwrap_or_default();
+                if name.is_empty() {
+                    continue;
+                }
+                let value = rename(name, rename_all);
+                if line.ends_with(',') && !line.contains(['(', '{']) {
+                    units.push(value);
+                    continue;
+                }
+                // `Variant { a: A, b: B },` on one line, or spread over the next ones.
+                let fields: Vec<(Vec<String>, Vec<String>, String)> = line
+                    .split_once('{')
+                    .map(|(_, rest)| rest.trim_end_matches(',').trim_end_matches('}'))
+                    .map(split_top_level)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|field| !field.starts_with("//"))
+                    .map(|field| (Vec::new(), Vec::new(), field.to_string()))
+                    .collect();
+                let mut variant = object_schema(&fields, &names);
+                if let Some(tag) = tag {
+                    variant["properties"][tag] = json!({ "type": "string", "enum": [&value] });
+                    let mut required = variant["required"].as_array().cloned().unwrap_or_default();
+                    required.push(json!(tag));
+                    variant["required"] = json!(required);
+                } else {
+                    variant = json!({
+                        "type": "object",
+                        "properties": { &value: variant },
+                        "required": [&value],
+                    });
+                }
+                describe(&mut variant, docs);
+                variants.push(variant);
+            }
+            match (units.is_empty(), variants.is_empty()) {
+                (_, true) => json!({ "type": "string", "enum": units }),
+                (true, false) => json!({ "oneOf": variants }),
+                (false, false) => {
+                    variants.push(json!({ "type": "string", "enum": units }));
+                    json!({ "oneOf": variants })
+                }
+            }
+        };
+        describe(&mut schema, &item.docs);
+        schemas.insert(item.name.clone(), schema);
+    }
+    schemas
+}
+
+/// Every route attribute in `file`, with the handler's doc comment and what it takes.
+fn routes(
+    module: &str,
+    source: &str,
+    schemas: &serde_json::Map<String, Value>,
+    paths: &mut serde_json::Map<String, Value>,
+) {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut docs = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        i += 1;
+        if let Some(doc) = line.strip_prefix("///") {
+            docs.push(doc.trim().to_string());
+            continue;
+        }
+        let attribute = ["get", "post", "put", "delete", "patch", "route"]
+            .iter()
+            .find(|method| line.starts_with(&format!("#[{}(", method)));
+        let Some(attribute) = attribute else {
+            if !line.starts_with("#[") {
+                docs.clear();
+            }
+            continue;
+        };
+        let mut text = line.to_string();
+        while !text.ends_with(")]") && i < lines.len() {
+            text.push_str(lines[i].trim());
+            i += 1;
+        }
+        let literals: Vec<&str> = text.split('"').skip(1).step_by(2).collect();
+        let Some(path) = literals.first() else {
+            continue;
+        };
+        let methods: Vec<String> = if *attribute == "route" {
+            text.split("method = \"")
+                .skip(1)
+                .filter_map(|rest| rest.split('"').next())
+                .map(str::to_lowercase)
+                .collect()
+        } else {
+            vec![attribute.to_string()]
+        };
+        // The handler's signature, up to its body.
+        let mut signature = String::new();
+        while i < lines.len() && !signature.contains('{') {
+            if lines[i].trim().starts_with("#[") && signature.is_empty() {
+                i += 1;
+                continue;
+            }
+            signature.push_str(lines[i].trim());
+            signature.push(' ');
+            i += 1;
+        }
+        let handler = signature
+            .split("fn ")
+            .nth(1)
+            .and_then(|rest| rest.split(['(', '<']).next())
+            .unwrap_or_default()
+            .to_string();
+        let extractor = |name: &str| {
+            signature
+                .split(&format!("web::{}<", name))
+                .nth(1)
+                .and_then(|rest| rest.split('>').next())
+                .map(str::to_string)
+        };
+
+        let mut parameters: Vec<Value> = path
+            .split('{')
+            .skip(1)
+            .filter_map(|rest| rest.split('}').next())
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+            .collect();
+        if let Some(query) = extractor("Query")
+            && let Some(schema) = schemas.get(&query)
+        {
+            let required: Vec<&str> = schema["required"]
+                .as_array()
+                .map(|required| required.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            for (name, property) in schema["properties"].as_object().into_iter().flatten() {
+                let mut parameter = json!({
+                    "name": name,
+                    "in": "query",
+                    "required": required.contains(&name.as_str()),
+                    "schema": property,
+                });
+                if let Some(description) = property.get("description") {
+                    parameter["description"] = description.clone();
+                }
+                parameters.push(parameter);
+            }
+        }
+        let mut operation = json!({
+            "operationId": handler,
+            "tags": [module],
+            "responses": {
+                "200": { "description": "OK" },
+                "default": {
+                    "description": "An error",
+                    "content": { "application/json": { "schema": { "$ref": "#/components/schemas/ErrorBody" } } },
+                },
+            },
+        });
+        if let Some(summary) = docs.first() {
+            operation["summary"] = json!(summary);
+            operation["description"] = json!(docs.join(" "));
+        }
+        if !parameters.is_empty() {
+            operation["parameters"] = json!(parameters);
+        }
+        if let Some(body) = extractor("Json") {
+            let schema = match schemas.get(&body) {
+                Some(_) => json!({ "$ref": format!("#/components/schemas/{}", body) }),
+                None => json!({}),
+            };
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema } },
+            });
+        }
+        // A guarded route shares its path with a plain one, which documents it better.
+        let guarded = text.contains("guard =");
+        for method in methods {
+            let path = paths.entry(path.to_string()).or_insert_with(|| json!({}));
+            if !(guarded && path.get(&method).is_some()) {
+                path[&method] = operation.clone();
+            }
+        }
+        docs.clear();
+    }
+}
+
+/// Writes the skeleton of `/openapi.json`: a path for every route in `src/`, and a schema
+/// for every struct and enum in `src/models.rs`. `openapi.rs` fills in the responses.
+fn emit_openapi(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=src");
+    let models = std::fs::read_to_string("src/models.rs").expect("src/models.rs exists");
+    let schemas = model_schemas(&models);
+
+    let mut files: Vec<_> = std::fs::read_dir("src")
+        .expect("src exists")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "rs"))
+        .collect();
+    files.sort();
+    let mut paths = serde_json::Map::new();
+    for file in files {
+        let module = file
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let module = if module == "main" {
+            "machines".to_string()
+        } else {
+            module
+        };
+        if let Ok(source) = std::fs::read_to_string(&file) {
+            routes(&module, &source, &schemas, &mut paths);
+        }
+    }
+
+    let document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "flyd",
+            "version": std::env::var("CARGO_PKG_VERSION").unwrap_or_default(),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+        },
+        "security": [{ "bearer": [] }],
+    });
+    std::fs::write(out_dir.join("openapi.json"), document.to_string())
+        .expect("failed to write the OpenAPI document");
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    emit_build_info();
+
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    emit_openapi(Path::new(&out_dir));
+}
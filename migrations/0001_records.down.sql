@@ -0,0 +1,3 @@
+-- Rolls back 0001, dropping every record flyd keeps. Only for going back to a flyd that
+-- used the memory store; copy the database first if its contents matter.
+DROP TABLE records;
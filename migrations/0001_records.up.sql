@@ -0,0 +1,7 @@
+-- Every collection in one table, keyed like the in-memory store.
+CREATE TABLE records (
+    collection TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (collection, key)
+);
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, put, web};
+use chrono::Utc;
+use flyd::models::{Annotations, StoredAnnotations};
+use serde_json::{Value, json};
+
+use crate::auth::{self, Identity};
+use crate::errors::AppError;
+use crate::fly_client;
+use crate::namespaces;
+use crate::store::Store;
+
+const ANNOTATIONS: &str = "annotations";
+
+fn key(app: &str, machine_id: Option<&str>) -> String {
+    match machine_id {
+        Some(machine_id) => format!("{}/{}", app, machine_id),
+        None => app.to_string(),
+    }
+}
+
+/// `machine`'s fields, falling back to `app`'s.
+fn overlay(app: Option<&Annotations>, machine: Option<&Annotations>) -> Annotations {
+    let pick = |field: fn(&Annotations) -> &Option<String>| {
+        machine
+            .and_then(|annotations| field(annotations).clone())
+            .or_else(|| app.and_then(|annotations| field(annotations).clone()))
+    };
+    Annotations {
+        owner: pick(|annotations| &annotations.owner),
+        description: pick(|annotations| &annotations.description),
+        runbook_url: pick(|annotations| &annotations.runbook_url),
+        slack_channel: pick(|annotations| &annotations.slack_channel),
+    }
+}
+
+/// Adds `annotations` to a machine or a list of them from `app`, from the app's and each
+/// machine's own. Machines with neither are left as they are.
+pub async fn merge(store: &Store, app: &str, machines: &mut Value) {
+    let stored = match store.list::<StoredAnnotations>(ANNOTATIONS).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            log::warn!("Failed to load annotations of {}: {}", app, e);
+            return;
+        }
+    };
+    let mut app_annotations = None;
+    let mut by_machine = HashMap::new();
+    for entry in stored.into_iter().filter(|entry| entry.app == app) {
+        match entry.machine_id {
+            Some(machine_id) => {
+                by_machine.insert(machine_id, entry.annotations);
+            }
+            None => app_annotations = Some(entry.annotations),
+        }
+    }
+    if app_annotations.is_none() && by_machine.is_empty() {
+        return;
+    }
+    let annotate_one = |machine: &mut Value| {
+        let own = machine["id"].as_str().and_then(|id| by_machine.get(id));
+        if app_annotations.is_some() || own.is_some() {
+            machine["annotations"] = json!(overlay(app_annotations.as_ref(), own));
+        }
+    };
+    match machines {
+        Value::Array(machines) => machines.iter_mut().for_each(annotate_one),
+        Value::Object(_) => annotate_one(machines),
+        _ => {}
+    }
+}
+
+/// Who owns a machine of `app`, or the app itself: the machine's own owner, else the app's.
+pub async fn owner(store: &Store, app: &str, machine_id: Option<&str>) -> Option<String> {
+    let stored = |machine_id| async move {
+        store
+            .get::<StoredAnnotations>(ANNOTATIONS, &key(app, machine_id))
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to load annotations of {}: {}", app, e);
+                None
+            })
+            .and_then(|stored| stored.annotations.owner)
+    };
+    match machine_id {
+        Some(machine_id) => match stored(Some(machine_id)).await {
+            Some(owner) => Some(owner),
+            None => stored(None).await,
+        },
+        None => stored(None).await,
+    }
+}
+
+fn validate(annotations: &Annotations) -> Result<(), String> {
+    if let Some(url) = &annotations.runbook_url {
+        let parsed =
+            reqwest::Url::parse(url).map_err(|e| format!("Invalid runbook_url {}: {}", url, e))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(format!("runbook_url {} must be http(s)", url));
+        }
+    }
+    Ok(())
+}
+
+async fn read(store: &Store, app: &str, machine_id: Option<&str>) -> HttpResponse {
+    match store
+        .get::<StoredAnnotations>(ANNOTATIONS, &key(app, machine_id))
+        .await
+    {
+        Ok(Some(stored)) => HttpResponse::Ok().json(stored),
+        Ok(None) => AppError::not_found(match machine_id {
+            Some(machine_id) => format!("No annotations on machine {} of {}", machine_id, app),
+            None => format!("No annotations on {}", app),
+        })
+        .into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+/// The caller, if they may act on `app`.
+fn may_write(req: &HttpRequest, app: &str) -> Result<Identity, HttpResponse> {
+    let identity = auth::require_role(req, None)?;
+    if app.contains('/') {
+        return Err(AppError::bad_request("Invalid app name").into_response());
+    }
+    if !identity.may_touch(app) || namespaces::of(req).is_some_and(|namespace| !namespace.owns(app))
+    {
+        return Err(AppError::forbidden(format!("You can't annotate {}", app)).into_response());
+    }
+    Ok(identity)
+}
+
+async fn write(
+    req: &HttpRequest,
+    store: &Store,
+    app: String,
+    machine_id: Option<String>,
+    annotations: Annotations,
+) -> HttpResponse {
+    let identity = match may_write(req, &app) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    if let Err(e) = validate(&annotations) {
+        return AppError::bad_request(e).into_response();
+    }
+    let stored = StoredAnnotations {
+        updated_by: Some(identity.subject),
+        updated_at: Utc::now(),
+        annotations,
+        machine_id,
+        app,
+    };
+    let key = key(&stored.app, stored.machine_id.as_deref());
+    if let Err(e) = store.put(ANNOTATIONS, &key, &stored).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    // Cached machines carry the annotations they were read with.
+    fly_client::cache().invalidate(&stored.app);
+    HttpResponse::Ok().json(stored)
+}
+
+async fn remove(
+    req: &HttpRequest,
+    store: &Store,
+    app: &str,
+    machine_id: Option<&str>,
+) -> HttpResponse {
+    if let Err(response) = may_write(req, app) {
+        return response;
+    }
+    match store.delete(ANNOTATIONS, &key(app, machine_id)).await {
+        Ok(_) => {
+            fly_client::cache().invalidate(app);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/apps/{app}/annotations")]
+async fn get_app_annotations(app: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    read(&store, &app, None).await
+}
+
+/// Replaces the app's annotations, which its machines fall back to.
+#[put("/v0/apps/{app}/annotations")]
+async fn put_app_annotations(
+    req: HttpRequest,
+    app: web::Path<String>,
+    body: web::Json<Annotations>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    write(&req, &store, app.into_inner(), None, body.into_inner()).await
+}
+
+#[delete("/v0/apps/{app}/annotations")]
+async fn delete_app_annotations(
+    req: HttpRequest,
+    app: web::Path<String>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    remove(&req, &store, &app, None).await
+}
+
+#[get("/v0/apps/{app}/machines/{id}/annotations")]
+async fn get_machine_annotations(
+    path: web::Path<(String, String)>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let (app, machine_id) = path.into_inner();
+    read(&store, &app, Some(&machine_id)).await
+}
+
+#[put("/v0/apps/{app}/machines/{id}/annotations")]
+async fn put_machine_annotations(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    body: web::Json<Annotations>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let (app, machine_id) = path.into_inner();
+    write(&req, &store, app, Some(machine_id), body.into_inner()).await
+}
+
+#[delete("/v0/apps/{app}/machines/{id}/annotations")]
+async fn delete_machine_annotations(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let (app, machine_id) = path.into_inner();
+    remove(&req, &store, &app, Some(&machine_id)).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_app_annotations)
+        .service(put_app_annotations)
+        .service(delete_app_annotations)
+        .service(get_machine_annotations)
+        .service(put_machine_annotations)
+        .service(delete_machine_annotations);
+}
@@ -0,0 +1,395 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::http::header::HeaderValue;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use chrono::{TimeDelta, Utc};
+use flyd::models::{ApiKey, IssuedApiKey, NewApiKey};
+use serde_json::json;
+
+use crate::auth::{self, AuthError, AuthProvider, Identity, token_hash};
+use crate::config::{ApiKeysConfig, AuthProviderKind, Config};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::store::{Store, StoreError};
+
+const API_KEYS: &str = "api_keys";
+const API_KEY_ISSUED: &str = "api_key.issued";
+const API_KEY_REVOKED: &str = "api_key.revoked";
+/// Every key flyd issues starts with this, so other providers' tokens are left alone.
+const KEY_PREFIX: &str = "flyd_";
+/// How often keys issued or revoked through another replica are picked up.
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+fn is_live(key: &ApiKey) -> bool {
+    key.revoked_at.is_none()
+        && key
+            .expires_at
+            .is_none_or(|expires_at| expires_at > Utc::now())
+}
+
+/// flyd's own API keys, checked against the store's records by hash. Each one calls the
+/// Machines API with one of `fly_tokens`, which callers never see.
+pub struct ApiKeys {
+    config: ApiKeysConfig,
+    fly_tokens: HashMap<String, HeaderValue>,
+    default_fly_token: Option<HeaderValue>,
+    /// Live keys by SHA-256.
+    keys: RwLock<HashMap<String, ApiKey>>,
+}
+
+impl ApiKeys {
+    /// `None` unless `api_keys` is one of the auth providers.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        if !config.auth.providers.contains(&AuthProviderKind::ApiKeys) {
+            return Ok(None);
+        }
+        let api_keys = config
+            .auth
+            .api_keys
+            .clone()
+            .ok_or("auth provider api_keys needs an [auth.api_keys] section")?;
+        let fly_tokens = api_keys
+            .fly_tokens
+            .iter()
+            .map(|(name, token)| {
+                Ok((
+                    name.clone(),
+                    auth::server_token("api_keys", Some(token), config)?,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Some(ApiKeys {
+            config: api_keys,
+            fly_tokens,
+            default_fly_token: auth::server_token("api_keys", None, config).ok(),
+            keys: RwLock::new(HashMap::new()),
+        }))
+    }
+
+    fn fly_authorization(&self, name: Option<&str>) -> Option<&HeaderValue> {
+        match name {
+            Some(name) => self.fly_tokens.get(name),
+            None => self.default_fly_token.as_ref(),
+        }
+    }
+
+    /// Replaces the live keys with the store's.
+    pub async fn load(&self, store: &Store) -> Result<(), StoreError> {
+        let keys = store
+            .list::<ApiKey>(API_KEYS)
+            .await?
+            .into_iter()
+            .filter(is_live)
+            .map(|key| (key.key_sha256.clone(), key))
+            .collect();
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    pub async fn sync_loop(keys: Arc<Self>, store: web::Data<Store>) {
+        let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = keys.load(&store).await {
+                log::error!("Failed to load API keys: {}", e);
+            }
+        }
+    }
+
+    fn admin(&self, req: &HttpRequest) -> Result<String, HttpResponse> {
+        auth::require_role(req, Some(&self.config.admin_role)).map(|identity| identity.subject)
+    }
+}
+
+impl AuthProvider for Arc<ApiKeys> {
+    fn name(&self) -> &'static str {
+        "api_keys"
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let Some(token) = auth::bearer_token(req).filter(|token| token.starts_with(KEY_PREFIX))
+        else {
+            return Ok(None);
+        };
+        let keys = self.keys.read().unwrap();
+        let key = keys
+            .get(&token_hash(token.as_bytes()))
+            .filter(|key| is_live(key))
+            .ok_or_else(|| AuthError("unknown, expired or revoked API key".to_string()))?;
+        let fly_authorization = self
+            .fly_authorization(key.fly_token.as_deref())
+            .ok_or_else(|| AuthError(format!("API key {} has no Fly token", key.id)))?;
+        Ok(Some(Identity {
+            subject: key.subject.clone(),
+            provider: self.name(),
+            roles: key.roles.clone(),
+            fly_authorization: fly_authorization.clone(),
+            apps: key.apps.clone(),
+        }))
+    }
+}
+
+fn record(events: &EventLog, kind: &str, key: &ApiKey) {
+    events.record(
+        kind,
+        None,
+        None,
+        json!({
+            "key_id": key.id,
+            "subject": key.subject,
+            "roles": key.roles,
+            "fly_token": key.fly_token,
+            "apps": key.apps,
+            "issued_by": key.issued_by,
+            "expires_at": key.expires_at,
+            "revoked_by": key.revoked_by,
+            "by": key.revoked_by.as_ref().unwrap_or(&key.issued_by),
+        }),
+    );
+}
+
+/// Issues a key. The response is the only place the key itself ever appears.
+#[post("/v0/admin/keys")]
+async fn issue_key(
+    req: HttpRequest,
+    body: web::Json<NewApiKey>,
+    keys: Option<web::Data<ApiKeys>>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+) -> impl Responder {
+    let Some(keys) = keys else {
+        return AppError::not_found("API keys aren't enabled").into_response();
+    };
+    let issued_by = match keys.admin(&req) {
+        Ok(subject) => subject,
+        Err(response) => return response,
+    };
+    let new = body.into_inner();
+    if new.subject.trim().is_empty() {
+        return AppError::bad_request("A key needs a subject").into_response();
+    }
+    if keys.fly_authorization(new.fly_token.as_deref()).is_none() {
+        return AppError::bad_request(match &new.fly_token {
+            Some(name) => format!("No Fly token named {} in [auth.api_keys.fly_tokens]", name),
+            None => "Without FLY_API_TOKEN a key needs a fly_token".to_string(),
+        })
+        .into_response();
+    }
+
+    let mut id = [0u8; 8];
+    getrandom::fill(&mut id).expect("the OS random number generator is available");
+    let mut secret = [0u8; 24];
+    getrandom::fill(&mut secret).expect("the OS random number generator is available");
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(secret));
+    let now = Utc::now();
+    let api_key = ApiKey {
+        id: hex::encode(id),
+        subject: new.subject,
+        roles: new.roles,
+        fly_token: new.fly_token,
+        description: new.description,
+        apps: new.apps,
+        key_sha256: token_hash(key.as_bytes()),
+        issued_by,
+        issued_at: now,
+        expires_at: new
+            .duration_secs
+            .map(|secs| now + TimeDelta::seconds(secs as i64)),
+        revoked_by: None,
+        revoked_at: None,
+    };
+    if let Err(e) = store.put(API_KEYS, &api_key.id, &api_key).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    keys.keys
+        .write()
+        .unwrap()
+        .insert(api_key.key_sha256.clone(), api_key.clone());
+    record(&events, API_KEY_ISSUED, &api_key);
+    HttpResponse::Created().json(IssuedApiKey { key, api_key })
+}
+
+/// Every key issued, newest first.
+#[get("/v0/admin/keys")]
+async fn list_keys(
+    req: HttpRequest,
+    keys: Option<web::Data<ApiKeys>>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let Some(keys) = keys else {
+        return AppError::not_found("API keys aren't enabled").into_response();
+    };
+    if let Err(response) = keys.admin(&req) {
+        return response;
+    }
+    let mut listed = match store.list::<ApiKey>(API_KEYS).await {
+        Ok(listed) => listed,
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    listed.sort_by_key(|key| Reverse(key.issued_at));
+    HttpResponse::Ok().json(listed)
+}
+
+#[delete("/v0/admin/keys/{id}")]
+async fn revoke_key(
+    req: HttpRequest,
+    path: web::Path<String>,
+    keys: Option<web::Data<ApiKeys>>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+) -> impl Responder {
+    let Some(keys) = keys else {
+        return AppError::not_found("API keys aren't enabled").into_response();
+    };
+    let revoked_by = match keys.admin(&req) {
+        Ok(subject) => subject,
+        Err(response) => return response,
+    };
+    let mut api_key = match store.get::<ApiKey>(API_KEYS, &path).await {
+        Ok(Some(api_key)) if api_key.revoked_at.is_none() => api_key,
+        Ok(_) => return AppError::not_found(format!("No live API key {}", path)).into_response(),
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    api_key.revoked_by = Some(revoked_by);
+    api_key.revoked_at = Some(Utc::now());
+    if let Err(e) = store.put(API_KEYS, &api_key.id, &api_key).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    keys.keys.write().unwrap().remove(&api_key.key_sha256);
+    record(&events, API_KEY_REVOKED, &api_key);
+    HttpResponse::Ok().json(api_key)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(issue_key)
+        .service(list_keys)
+        .service(revoke_key);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::config::AuthConfig;
+
+    fn api_keys() -> Arc<ApiKeys> {
+        let config = Config {
+            fly_api_token: Some("server".to_string()),
+            auth: AuthConfig {
+                providers: vec![AuthProviderKind::ApiKeys],
+                api_keys: Some(ApiKeysConfig {
+                    admin_role: "keys-admin".to_string(),
+                    fly_tokens: [("payments".to_string(), "FlyV1 payments".to_string())].into(),
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        Arc::new(ApiKeys::from_config(&config).unwrap().unwrap())
+    }
+
+    /// A key for `subject` stored as `flyd_<subject>`, live unless changed.
+    fn key(subject: &str) -> ApiKey {
+        ApiKey {
+            id: subject.to_string(),
+            subject: subject.to_string(),
+            roles: vec!["deployer".to_string()],
+            fly_token: None,
+            description: None,
+            apps: None,
+            key_sha256: token_hash(format!("{}{}", KEY_PREFIX, subject).as_bytes()),
+            issued_by: "carol".to_string(),
+            issued_at: Utc::now(),
+            expires_at: Some(Utc::now() + TimeDelta::hours(1)),
+            revoked_by: None,
+            revoked_at: None,
+        }
+    }
+
+    async fn load(keys: &ApiKeys, stored: &[ApiKey]) {
+        let store = Store::default();
+        for key in stored {
+            store.put(API_KEYS, &key.id, key).await.unwrap();
+        }
+        keys.load(&store).await.unwrap();
+    }
+
+    fn authenticate(keys: &Arc<ApiKeys>, token: &str) -> Result<Option<Identity>, AuthError> {
+        let req = TestRequest::default()
+            .insert_header(("authorization", format!("Bearer {}", token)))
+            .to_http_request();
+        keys.authenticate(&req)
+    }
+
+    #[actix_web::test]
+    async fn a_live_key_calls_fly_with_its_named_token() {
+        let keys = api_keys();
+        let mut payments = key("payments");
+        payments.fly_token = Some("payments".to_string());
+        load(&keys, &[key("alice"), payments]).await;
+
+        let alice = authenticate(&keys, "flyd_alice").unwrap().unwrap();
+        assert_eq!(alice.subject, "alice");
+        assert_eq!(alice.roles, ["deployer"]);
+        assert_eq!(alice.fly_authorization, "Bearer server");
+        let payments = authenticate(&keys, "flyd_payments").unwrap().unwrap();
+        assert_eq!(payments.fly_authorization, "FlyV1 payments");
+
+        assert!(
+            authenticate(&keys, "someone-elses-token")
+                .unwrap()
+                .is_none()
+        );
+        assert!(authenticate(&keys, "flyd_mallory").is_err());
+    }
+
+    #[actix_web::test]
+    async fn expired_keys_are_refused_even_before_the_next_sync() {
+        let keys = api_keys();
+        let mut expired = key("expired");
+        expired.expires_at = Some(Utc::now() - TimeDelta::seconds(1));
+        load(&keys, &[expired, key("expiring")]).await;
+        assert!(authenticate(&keys, "flyd_expired").is_err());
+
+        assert!(authenticate(&keys, "flyd_expiring").unwrap().is_some());
+        let hash = token_hash(b"flyd_expiring");
+        keys.keys
+            .write()
+            .unwrap()
+            .get_mut(&hash)
+            .unwrap()
+            .expires_at = Some(Utc::now() - TimeDelta::seconds(1));
+        assert!(authenticate(&keys, "flyd_expiring").is_err());
+    }
+
+    #[actix_web::test]
+    async fn revoked_keys_are_dropped_on_load() {
+        let keys = api_keys();
+        load(&keys, &[key("alice")]).await;
+        assert!(authenticate(&keys, "flyd_alice").unwrap().is_some());
+
+        let mut revoked = key("alice");
+        revoked.revoked_by = Some("carol".to_string());
+        revoked.revoked_at = Some(Utc::now());
+        load(&keys, &[revoked]).await;
+        assert!(authenticate(&keys, "flyd_alice").is_err());
+    }
+
+    #[actix_web::test]
+    async fn a_scoped_key_may_touch_only_its_apps() {
+        let keys = api_keys();
+        let mut scoped = key("alice");
+        scoped.apps = Some(vec!["web".to_string()]);
+        load(&keys, &[scoped, key("bob")]).await;
+
+        let alice = authenticate(&keys, "flyd_alice").unwrap().unwrap();
+        assert!(alice.may_touch("web"));
+        assert!(!alice.may_touch("api"));
+        let bob = authenticate(&keys, "flyd_bob").unwrap().unwrap();
+        assert!(bob.may_touch("api"));
+    }
+}
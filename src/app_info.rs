@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{AGE, CACHE_CONTROL};
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::AppRequest;
+use serde_json::{Value, json};
+
+use crate::cache::ResponseCache;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, FlyError, WithChecks};
+use crate::metrics;
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Ips,
+    Certificates,
+    Releases,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Ips => "ips",
+            Kind::Certificates => "certificates",
+            Kind::Releases => "releases",
+        }
+    }
+
+    fn query(self) -> &'static str {
+        match self {
+            Kind::Ips => {
+                "query($app: String!) { app(name: $app) { ipAddresses { nodes { \
+                 id address type region createdAt } } } }"
+            }
+            Kind::Certificates => {
+                "query($app: String!) { app(name: $app) { certificates { nodes { \
+                 id hostname clientStatus configured acmeDnsConfigured createdAt } } } }"
+            }
+            Kind::Releases => {
+                "query($app: String!) { app(name: $app) { releases(first: 25) { nodes { \
+                 id version status description reason imageRef createdAt user { email } } } } }"
+            }
+        }
+    }
+
+    fn field(self) -> &'static str {
+        match self {
+            Kind::Ips => "ipAddresses",
+            Kind::Certificates => "certificates",
+            Kind::Releases => "releases",
+        }
+    }
+}
+
+struct Entry {
+    stored_at: Instant,
+    value: Value,
+    refreshing: bool,
+}
+
+/// Read-through cache of app data that changes rarely: allocated IPs, certificates and
+/// releases. Like the response cache, entries are keyed by a hash of the caller's token.
+/// Entries older than `app_info_refresh_secs` are still served while a background
+/// fetch replaces them, so a dashboard load costs no upstream calls once warm.
+pub struct AppInfoCache {
+    ttl: Duration,
+    refresh_after: Duration,
+    graphql_url: String,
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl AppInfoCache {
+    pub fn from_config(config: &Config) -> Self {
+        AppInfoCache {
+            ttl: Duration::from_secs(config.cache.app_info_ttl_secs),
+            refresh_after: Duration::from_secs(config.cache.app_info_refresh_secs),
+            graphql_url: config.machines.graphql_api_url.clone(),
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    async fn fetch(&self, client: &FlyClient, app: &str, kind: Kind) -> Result<Value, FlyError> {
+        let data = client
+            .graphql(&self.graphql_url, kind.query(), json!({ "app": app }))
+            .await?;
+        Ok(match &data["app"][kind.field()]["nodes"] {
+            Value::Null => json!([]),
+            nodes => nodes.clone(),
+        })
+    }
+
+    /// The cached value and its age, and whether it's due a refresh no one has started
+    /// yet; claims that refresh if so.
+    fn lookup(&self, key: &str) -> Option<(Value, Duration, bool)> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        let age = entry.stored_at.elapsed();
+        if age >= self.ttl {
+            return None;
+        }
+        let refresh = age >= self.refresh_after && !entry.refreshing;
+        entry.refreshing |= refresh;
+        Some((entry.value.clone(), age, refresh))
+    }
+
+    fn insert(&self, key: String, value: Value) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.stored_at.elapsed() < self.ttl);
+        entries.insert(
+            key,
+            Entry {
+                stored_at: Instant::now(),
+                value,
+                refreshing: false,
+            },
+        );
+    }
+
+    fn respond(&self, value: &Value, age: Duration) -> HttpResponse {
+        HttpResponse::Ok()
+            .insert_header((
+                CACHE_CONTROL,
+                format!(
+                    "private, max-age={}",
+                    self.refresh_after.saturating_sub(age).as_secs()
+                ),
+            ))
+            .insert_header((AGE, age.as_secs().to_string()))
+            .json(value)
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        metrics::header(
+            out,
+            "flyd_app_info_cache_lookups_total",
+            "counter",
+            "App IP, certificate and release lookups, by whether the cache answered them.",
+        );
+        for (result, counter) in [("hit", &self.hits), ("miss", &self.misses)] {
+            metrics::sample(
+                out,
+                "flyd_app_info_cache_lookups_total",
+                &[("result", result.to_string())],
+                counter.load(Ordering::Relaxed) as f64,
+            );
+        }
+    }
+}
+
+async fn serve(
+    req: HttpRequest,
+    query: web::Query<AppRequest>,
+    kind: Kind,
+    cache: web::Data<AppInfoCache>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> HttpResponse {
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    let app = query.into_inner().app_name;
+    let key = ResponseCache::key(&req, &app, kind.name());
+
+    if !ResponseCache::client_bypasses(&req)
+        && let Some((value, age, refresh)) = cache.lookup(&key)
+    {
+        cache.hits.fetch_add(1, Ordering::Relaxed);
+        if refresh {
+            let cache = cache.clone();
+            actix_web::rt::spawn(async move {
+                match cache.fetch(&client, &app, kind).await {
+                    Ok(value) => cache.insert(key, value),
+                    Err(e) => {
+                        log::warn!("Failed to refresh the {} of {}: {}", kind.name(), app, e);
+                        if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+                            entry.refreshing = false;
+                        }
+                    }
+                }
+            });
+        }
+        return cache.respond(&value, age);
+    }
+
+    cache.misses.fetch_add(1, Ordering::Relaxed);
+    match cache.fetch(&client, &app, kind).await {
+        Ok(value) => {
+            cache.insert(key, value.clone());
+            cache.respond(&value, Duration::ZERO)
+        }
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+/// The app's allocated IP addresses.
+#[get("/v0/apps/ips")]
+async fn list_ips(
+    req: HttpRequest,
+    query: web::Query<AppRequest>,
+    cache: web::Data<AppInfoCache>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    serve(req, query, Kind::Ips, cache, http_client, slo).await
+}
+
+/// The app's certificates and their issuance status.
+#[get("/v0/apps/certificates")]
+async fn list_certificates(
+    req: HttpRequest,
+    query: web::Query<AppRequest>,
+    cache: web::Data<AppInfoCache>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    serve(req, query, Kind::Certificates, cache, http_client, slo).await
+}
+
+/// The app's last 25 releases, newest first.
+#[get("/v0/apps/releases")]
+async fn list_releases(
+    req: HttpRequest,
+    query: web::Query<AppRequest>,
+    cache: web::Data<AppInfoCache>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    serve(req, query, Kind::Releases, cache, http_client, slo).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_ips)
+        .service(list_certificates)
+        .service(list_releases);
+}
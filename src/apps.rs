@@ -0,0 +1,635 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::Utc;
+use flyd::models::{
+    AppRequest, BundledVolume, CloneAppReport, CloneAppRequest, CloneMachineRequest,
+    ClonedResource, ExportMachineQuery, ImportMachineReport, ImportMachineRequest, ListAppsRequest,
+    MachineBundle, NewAppRequest,
+};
+use serde_json::json;
+
+use crate::auth::Identity;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets::MANAGED_METADATA_KEY;
+use crate::fly_client::{FlyClient, FlyError, WithChecks};
+use crate::namespaces;
+use crate::pools::POOL_METADATA_KEY;
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::trash::TRASHED_METADATA_KEY;
+use crate::virtual_hosts;
+
+const APP_DELETED: &str = "app.deleted";
+const MACHINE_CLONED: &str = "machine.cloned";
+const MACHINE_IMPORTED: &str = "machine.imported";
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(120);
+const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn is_live(resource: &serde_json::Value) -> bool {
+    !matches!(
+        resource["state"].as_str(),
+        Some("destroyed") | Some("destroying") | Some("pending_destroy")
+    )
+}
+
+#[post("/v0/apps/clone")]
+async fn clone_app(
+    req: HttpRequest,
+    body: web::Json<CloneAppRequest>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_request_checks(&req);
+
+    // Read everything up front so a typo in the source app fails before anything is created.
+    let machines = match client.list_machines(&body.source_app).await {
+        Ok(machines) => machines,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+    let volumes = match client.list_volumes(&body.source_app).await {
+        Ok(volumes) => volumes,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+
+    if let Err(e) = client.create_app(&body.target_app, &body.org_slug).await {
+        return AppError::from(&e).into_response();
+    }
+
+    let mut report = CloneAppReport {
+        app: body.target_app.clone(),
+        ..Default::default()
+    };
+
+    let mut volume_ids = HashMap::new();
+    for volume in volumes.iter().filter(|volume| is_live(volume)) {
+        let source_id = volume["id"].as_str().unwrap_or_default().to_string();
+        let result =
+            clone_volume(&client, &body, volume, config.capacity.require_unique_zone).await;
+        match result {
+            Ok((id, snapshot_id)) => {
+                volume_ids.insert(source_id.clone(), id.clone());
+                report.volumes.push(ClonedResource {
+                    source_id,
+                    id: Some(id),
+                    snapshot_id,
+                    error: None,
+                });
+            }
+            Err(e) => report.volumes.push(ClonedResource {
+                source_id,
+                id: None,
+                snapshot_id: None,
+                error: Some(e.to_string()),
+            }),
+        }
+    }
+
+    for (name, value) in &body.secrets {
+        match client.set_secret(&body.target_app, name, value).await {
+            Ok(()) => report.secrets.push(name.clone()),
+            Err(e) => log::warn!(
+                "Failed to set secret {} on {}: {}",
+                name,
+                body.target_app,
+                e
+            ),
+        }
+    }
+    if let Ok(source_secrets) = client.list_secrets(&body.source_app).await {
+        report.uncopied_secrets = source_secrets
+            .iter()
+            .filter_map(|secret| secret["name"].as_str().or(secret["label"].as_str()))
+            .filter(|name| !body.secrets.contains_key(*name))
+            .map(str::to_string)
+            .collect();
+    }
+
+    for machine in machines.iter().filter(|machine| is_live(machine)) {
+        let source_id = machine["id"].as_str().unwrap_or_default().to_string();
+
+        let mut config = machine["config"].clone();
+        if let Some(metadata) = config["metadata"].as_object_mut() {
+            metadata.remove(MANAGED_METADATA_KEY);
+        }
+        if let Some(mounts) = config["mounts"].as_array_mut() {
+            for mount in mounts {
+                let cloned = mount["volume"]
+                    .as_str()
+                    .and_then(|volume| volume_ids.get(volume));
+                mount["volume"] = json!(cloned);
+            }
+        }
+
+        let create = json!({ "region": machine["region"], "config": config });
+        match client.create_machine(&body.target_app, &create).await {
+            Ok(created) => report.machines.push(ClonedResource {
+                source_id,
+                id: created["id"].as_str().map(str::to_string),
+                snapshot_id: None,
+                error: None,
+            }),
+            Err(e) => report.machines.push(ClonedResource {
+                source_id,
+                id: None,
+                snapshot_id: None,
+                error: Some(e.to_string()),
+            }),
+        }
+    }
+
+    log::info!(
+        "Cloned {} into {}: {} machines, {} volumes",
+        body.source_app,
+        body.target_app,
+        report.machines.len(),
+        report.volumes.len()
+    );
+
+    HttpResponse::Ok().json(report)
+}
+
+/// Recreates a volume in the target app from its most recent snapshot, or empty when
+/// the source has no snapshots yet.
+async fn clone_volume(
+    client: &FlyClient,
+    body: &CloneAppRequest,
+    volume: &serde_json::Value,
+    require_unique_zone: bool,
+) -> Result<(String, Option<String>), FlyError> {
+    let source_id = volume["id"].as_str().unwrap_or_default();
+    let snapshot_id = latest_snapshot(client, &body.source_app, source_id).await?;
+
+    let mut create = json!({
+        "name": volume["name"],
+        "region": volume["region"],
+        "size_gb": volume["size_gb"],
+        "require_unique_zone": require_unique_zone,
+    });
+    if let Some(snapshot_id) = &snapshot_id {
+        create["snapshot_id"] = json!(snapshot_id);
+    }
+
+    let created = client.create_volume(&body.target_app, &create).await?;
+    Ok((
+        created["id"].as_str().unwrap_or_default().to_string(),
+        snapshot_id,
+    ))
+}
+
+/// The volume's most recent snapshot that didn't fail.
+async fn latest_snapshot(
+    client: &FlyClient,
+    app_name: &str,
+    volume_id: &str,
+) -> Result<Option<String>, FlyError> {
+    let snapshots = client.list_snapshots(app_name, volume_id).await?;
+    Ok(snapshots
+        .iter()
+        .filter(|snapshot| snapshot["status"].as_str() != Some("failed"))
+        .max_by_key(|snapshot| {
+            snapshot["created_at"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .and_then(|snapshot| snapshot["id"].as_str())
+        .map(str::to_string))
+}
+
+/// Snapshots the volume and waits for the snapshot to be taken, giving up after
+/// `SNAPSHOT_TIMEOUT` with None.
+pub async fn take_snapshot(
+    client: &FlyClient,
+    app_name: &str,
+    volume_id: &str,
+) -> Result<Option<String>, FlyError> {
+    let existing: HashSet<String> = client
+        .list_snapshots(app_name, volume_id)
+        .await?
+        .iter()
+        .filter_map(|snapshot| snapshot["id"].as_str())
+        .map(str::to_string)
+        .collect();
+    client.create_snapshot(app_name, volume_id).await?;
+    let deadline = Instant::now() + SNAPSHOT_TIMEOUT;
+    while Instant::now() < deadline {
+        tokio::time::sleep(SNAPSHOT_POLL_INTERVAL).await;
+        let snapshots = client.list_snapshots(app_name, volume_id).await?;
+        let taken = snapshots.iter().find(|snapshot| {
+            snapshot["id"]
+                .as_str()
+                .is_some_and(|id| !existing.contains(id))
+                && snapshot["status"] == "created"
+        });
+        if let Some(id) = taken.and_then(|snapshot| snapshot["id"].as_str()) {
+            return Ok(Some(id.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// The machine's config as a bundle another app, in any org, can import. Each volume it
+/// mounts is exported as its latest snapshot, or a fresh one with `snapshot=true`.
+#[get("/v0/machines/export")]
+async fn export_machine(
+    req: HttpRequest,
+    query: web::Query<ExportMachineQuery>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let machine = match client.get_machine(&query.app_name, &query.machine_id).await {
+        Ok(machine) => machine,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+
+    let mut config = machine["config"].clone();
+    if let Some(metadata) = config
+        .get_mut("metadata")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        metadata.remove(MANAGED_METADATA_KEY);
+    }
+    let mounted: Vec<String> = config["mounts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|mount| mount["volume"].as_str())
+        .map(str::to_string)
+        .collect();
+
+    let mut volumes = Vec::new();
+    for volume_id in mounted {
+        let volume = match client.get_volume(&query.app_name, &volume_id).await {
+            Ok(volume) => volume,
+            Err(e) => return AppError::from(&e).into_response(),
+        };
+        let snapshot_id = if query.snapshot {
+            match take_snapshot(&client, &query.app_name, &volume_id).await {
+                Ok(Some(snapshot_id)) => Some(snapshot_id),
+                Ok(None) => {
+                    return AppError::new(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        format!(
+                            "The snapshot of volume {} wasn't taken within {}s",
+                            volume_id,
+                            SNAPSHOT_TIMEOUT.as_secs()
+                        ),
+                    )
+                    .into_response();
+                }
+                Err(e) => return AppError::from(&e).into_response(),
+            }
+        } else {
+            match latest_snapshot(&client, &query.app_name, &volume_id).await {
+                Ok(snapshot_id) => snapshot_id,
+                Err(e) => return AppError::from(&e).into_response(),
+            }
+        };
+        volumes.push(BundledVolume {
+            source_id: volume_id,
+            name: volume["name"].as_str().unwrap_or_default().to_string(),
+            region: volume["region"].as_str().unwrap_or_default().to_string(),
+            size_gb: volume["size_gb"].as_u64().unwrap_or(1),
+            snapshot_id,
+        });
+    }
+
+    HttpResponse::Ok().json(MachineBundle {
+        source_app: query.app_name.clone(),
+        source_machine_id: query.machine_id.clone(),
+        name: machine["name"].as_str().map(str::to_string),
+        region: machine["region"].as_str().unwrap_or_default().to_string(),
+        config,
+        volumes,
+        exported_at: Utc::now(),
+    })
+}
+
+/// Recreates an exported machine in `app_name`: its volumes from their snapshots, then
+/// the machine mounting them. Nothing is left behind if any of it fails. Restoring a
+/// snapshot from another org needs a token that can read it.
+#[post("/v0/machines/import")]
+async fn import_machine(
+    req: HttpRequest,
+    body: web::Json<ImportMachineRequest>,
+    config: web::Data<Config>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let bundle = &body.bundle;
+    let region = body.region.as_ref().unwrap_or(&bundle.region);
+
+    let mut report = ImportMachineReport {
+        machine: serde_json::Value::Null,
+        volumes: Vec::new(),
+    };
+    let mut volume_ids = HashMap::new();
+    let mut failure = None;
+    for volume in &bundle.volumes {
+        let mut create = json!({
+            "name": volume.name,
+            "region": region,
+            "size_gb": volume.size_gb,
+            "require_unique_zone": config.capacity.require_unique_zone,
+        });
+        if let Some(snapshot_id) = &volume.snapshot_id {
+            create["snapshot_id"] = json!(snapshot_id);
+        }
+        match client.create_volume(&body.app_name, &create).await {
+            Ok(created) => {
+                let id = created["id"].as_str().unwrap_or_default().to_string();
+                volume_ids.insert(volume.source_id.clone(), id.clone());
+                report.volumes.push(ClonedResource {
+                    source_id: volume.source_id.clone(),
+                    id: Some(id),
+                    snapshot_id: volume.snapshot_id.clone(),
+                    error: None,
+                });
+            }
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        }
+    }
+
+    if failure.is_none() {
+        let mut machine_config = bundle.config.clone();
+        if let Some(mounts) = machine_config
+            .get_mut("mounts")
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            for mount in mounts {
+                let imported = mount["volume"]
+                    .as_str()
+                    .and_then(|volume| volume_ids.get(volume));
+                mount["volume"] = json!(imported);
+            }
+        }
+        let create = json!({ "name": bundle.name, "region": region, "config": machine_config });
+        match client.create_machine(&body.app_name, &create).await {
+            Ok(machine) => report.machine = machine,
+            Err(e) => failure = Some(e),
+        }
+    }
+
+    if let Some(e) = failure {
+        for id in volume_ids.values() {
+            if let Err(e) = client.delete_volume(&body.app_name, id).await {
+                log::warn!("Failed to delete volume {} of a failed import: {}", id, e);
+            }
+        }
+        return AppError::from(&e).into_response();
+    }
+
+    events.record(
+        MACHINE_IMPORTED,
+        Some(&body.app_name),
+        report.machine["id"].as_str(),
+        json!({
+            "source_app": bundle.source_app,
+            "source_machine_id": bundle.source_machine_id,
+            "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+        }),
+    );
+    HttpResponse::Created().json(report)
+}
+
+/// Creates a machine from another's config, in its region or `region`, with `guest`'s
+/// fields replacing its size. flyd's own fleet, pool and trash tags aren't copied; the
+/// clone is nobody's but the caller's. Machines mounting volumes can't be cloned, since
+/// a volume belongs to one machine: export and import them instead.
+#[post("/v0/machines/clone")]
+async fn clone_machine(
+    req: HttpRequest,
+    body: web::Json<CloneMachineRequest>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let machine = match client.get_machine(&body.app_name, &body.machine_id).await {
+        Ok(machine) => machine,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+    let mut config = machine["config"].clone();
+    if config["mounts"]
+        .as_array()
+        .is_some_and(|mounts| !mounts.is_empty())
+    {
+        return AppError::unprocessable(format!(
+            "Machine {} mounts volumes; export and import it to copy them",
+            body.machine_id
+        ))
+        .into_response();
+    }
+    if let Some(metadata) = config
+        .get_mut("metadata")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        for key in [
+            MANAGED_METADATA_KEY,
+            POOL_METADATA_KEY,
+            TRASHED_METADATA_KEY,
+        ] {
+            metadata.remove(key);
+        }
+    }
+    if let Some(guest) = &body.guest {
+        let guest = match serde_json::to_value(guest) {
+            Ok(serde_json::Value::Object(guest)) => guest,
+            _ => serde_json::Map::new(),
+        };
+        if !config["guest"].is_object() {
+            config["guest"] = json!({});
+        }
+        for (field, value) in guest {
+            config["guest"][field] = value;
+        }
+    }
+
+    let target_app = body.target_app.as_ref().unwrap_or(&body.app_name);
+    let mut create = json!({
+        "region": body.region.as_deref().or(machine["region"].as_str()),
+        "config": config,
+    });
+    if let Some(name) = &body.name {
+        create["name"] = json!(name);
+    }
+    let created = match client.create_machine(target_app, &create).await {
+        Ok(created) => created,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+    events.record(
+        MACHINE_CLONED,
+        Some(target_app),
+        created["id"].as_str(),
+        json!({
+            "source_app": body.app_name,
+            "source_machine_id": body.machine_id,
+            "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+        }),
+    );
+    HttpResponse::Created().json(created)
+}
+
+fn client(
+    req: &HttpRequest,
+    use_private_api: bool,
+    http_client: &web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> Result<FlyClient, HttpResponse> {
+    let (headers, api_hostname) = prepare_request(req, use_private_api)?;
+    Ok(
+        FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner())
+            .with_request_checks(req),
+    )
+}
+
+/// The org to use: the one asked for, else the caller's namespace's, else the virtual
+/// host's.
+fn org_slug(req: &HttpRequest, requested: Option<&String>) -> Result<String, HttpResponse> {
+    requested
+        .cloned()
+        .or_else(|| namespaces::of(req).and_then(|namespace| namespace.config.org_slug.clone()))
+        .or_else(|| virtual_hosts::of(req).and_then(|host| host.config.org_slug.clone()))
+        .ok_or_else(|| AppError::bad_request("org_slug is required").into_response())
+}
+
+#[post("/v0/apps/new")]
+async fn create_app(
+    req: HttpRequest,
+    body: web::Json<NewAppRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let org_slug = match org_slug(&req, body.org_slug.as_ref()) {
+        Ok(org_slug) => org_slug,
+        Err(response) => return response,
+    };
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    if let Err(e) = client.create_app(&body.app_name, &org_slug).await {
+        return AppError::from(&e).into_response();
+    }
+    match client.get_app(&body.app_name).await {
+        Ok(app) => HttpResponse::Created().json(app),
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+/// The org's apps; namespaced callers only see their own.
+#[get("/v0/apps/list")]
+async fn list_apps(
+    req: HttpRequest,
+    query: web::Query<ListAppsRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let org_slug = match org_slug(&req, query.org_slug.as_ref()) {
+        Ok(org_slug) => org_slug,
+        Err(response) => return response,
+    };
+    let client = match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let mut apps = match client.list_apps(&org_slug).await {
+        Ok(apps) => apps,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+    if let Some(namespace) = namespaces::of(&req) {
+        apps.retain(|app| {
+            app["name"]
+                .as_str()
+                .is_some_and(|name| namespace.owns(name))
+        });
+    }
+    if let Some(identity) = req.extensions().get::<Identity>() {
+        apps.retain(|app| {
+            app["name"]
+                .as_str()
+                .is_some_and(|name| identity.may_touch(name))
+        });
+    }
+    HttpResponse::Ok().json(apps)
+}
+
+#[get("/v0/apps/get")]
+async fn get_app(
+    req: HttpRequest,
+    query: web::Query<AppRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => match client.get_app(&query.app_name).await {
+            Ok(app) => HttpResponse::Ok().json(app),
+            Err(e) => AppError::from(&e).into_response(),
+        },
+        Err(response) => response,
+    }
+}
+
+/// Deletes the app and everything in it, recording an `app.deleted` event.
+#[post("/v0/apps/delete")]
+async fn delete_app(
+    req: HttpRequest,
+    body: web::Json<AppRequest>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    if let Err(e) = client.delete_app(&body.app_name).await {
+        return AppError::from(&e).into_response();
+    }
+    events.record(
+        APP_DELETED,
+        Some(&body.app_name),
+        None,
+        json!({ "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()) }),
+    );
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(clone_app)
+        .service(clone_machine)
+        .service(export_machine)
+        .service(import_machine)
+        .service(create_app)
+        .service(list_apps)
+        .service(get_app)
+        .service(delete_app);
+}
@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::Utc;
+use flyd::models::{Artifact, ArtifactQuery, ArtifactUpload};
+use futures_util::StreamExt;
+use serde_json::{Value, json};
+
+use crate::auth;
+use crate::config::{ArtifactsConfig, Config};
+use crate::errors::AppError;
+use crate::namespaces;
+use crate::object_store::{ObjectStore, ObjectStoreError};
+
+fn signed(
+    objects: &ObjectStore,
+    config: &ArtifactsConfig,
+    key: String,
+    size_bytes: u64,
+) -> Artifact {
+    let ttl = Duration::from_secs(config.url_ttl_secs);
+    Artifact {
+        url: objects.presign_get(&key, ttl),
+        key,
+        size_bytes,
+        expires_at: Utc::now() + ttl,
+    }
+}
+
+/// Stores a job output under `{prefix}{app}/` and returns a signed URL for it.
+pub async fn upload(
+    objects: &ObjectStore,
+    config: &ArtifactsConfig,
+    app: &str,
+    name: &str,
+    body: Vec<u8>,
+    content_type: &str,
+) -> Result<Artifact, ObjectStoreError> {
+    let key = format!(
+        "{}{}/{}-{}",
+        config.prefix,
+        app,
+        Utc::now().format("%Y%m%dT%H%M%S%3fZ"),
+        name
+    );
+    let size_bytes = body.len() as u64;
+    objects.put(&key, body, content_type).await?;
+    Ok(signed(objects, config, key, size_bytes))
+}
+
+/// Moves a command's `stdout` and `stderr` into artifacts when they're longer than
+/// `artifacts.inline_max_bytes`, leaving their signed URLs under `artifacts` instead. An
+/// output that fails to upload stays inline.
+pub async fn offload(req: &HttpRequest, app: &str, name: &str, output: &mut Value) {
+    let (Some(config), Some(objects)) = (
+        req.app_data::<web::Data<Config>>(),
+        req.app_data::<web::Data<ObjectStore>>(),
+    ) else {
+        return;
+    };
+    let Some(inline_max_bytes) = config.artifacts.inline_max_bytes else {
+        return;
+    };
+    for stream in ["stdout", "stderr"] {
+        let Some(text) = output[stream]
+            .as_str()
+            .filter(|text| text.len() > inline_max_bytes)
+        else {
+            continue;
+        };
+        let body = text.as_bytes().to_vec();
+        let file = format!("{}-{}.txt", name, stream);
+        match upload(objects, &config.artifacts, app, &file, body, "text/plain").await {
+            Ok(artifact) => {
+                output[stream] = Value::Null;
+                output["artifacts"][stream] = json!(artifact);
+            }
+            Err(e) => log::warn!(
+                "Failed to upload {} of {} as an artifact: {}",
+                stream,
+                name,
+                e
+            ),
+        }
+    }
+}
+
+/// Whether the caller may touch `app`, whose artifacts they're after.
+fn may_touch(req: &HttpRequest, app: &str) -> Result<(), HttpResponse> {
+    let identity = auth::require_role(req, None)?;
+    if !identity.may_touch(app) || namespaces::of(req).is_some_and(|namespace| !namespace.owns(app))
+    {
+        return Err(
+            AppError::forbidden(format!("You can't touch {}'s artifacts", app)).into_response(),
+        );
+    }
+    Ok(())
+}
+
+/// Uploads the request body as an artifact, for outputs too large to pass around inline.
+#[post("/v0/artifacts")]
+async fn create_artifact(
+    req: HttpRequest,
+    query: web::Query<ArtifactUpload>,
+    mut payload: web::Payload,
+    config: web::Data<Config>,
+    objects: Option<web::Data<ObjectStore>>,
+) -> impl Responder {
+    let Some(objects) = objects else {
+        return AppError::not_found("Artifacts need [object_storage]").into_response();
+    };
+    if query.name.is_empty() || query.name.contains('/') || query.app.contains('/') {
+        return AppError::bad_request("Invalid artifact app or name").into_response();
+    }
+    if let Err(response) = may_touch(&req, &query.app) {
+        return response;
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        match chunk {
+            Ok(chunk) if body.len() + chunk.len() <= config.artifacts.max_upload_bytes => {
+                body.extend_from_slice(&chunk)
+            }
+            Ok(_) => {
+                return AppError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!(
+                        "Artifacts are limited to {} bytes",
+                        config.artifacts.max_upload_bytes
+                    ),
+                )
+                .into_response();
+            }
+            Err(e) => return AppError::bad_request(e.to_string()).into_response(),
+        }
+    }
+
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream");
+    match upload(
+        &objects,
+        &config.artifacts,
+        &query.app,
+        &query.name,
+        body,
+        content_type,
+    )
+    .await
+    {
+        Ok(artifact) => HttpResponse::Created().json(artifact),
+        Err(e) => AppError::bad_gateway(e.to_string()).into_response(),
+    }
+}
+
+/// A fresh signed URL for an existing artifact, e.g. once the original one expired.
+#[get("/v0/artifacts")]
+async fn get_artifact(
+    req: HttpRequest,
+    query: web::Query<ArtifactQuery>,
+    config: web::Data<Config>,
+    objects: Option<web::Data<ObjectStore>>,
+) -> impl Responder {
+    let Some(objects) = objects else {
+        return AppError::not_found("Artifacts need [object_storage]").into_response();
+    };
+    let Some((app, _)) = query
+        .key
+        .strip_prefix(&config.artifacts.prefix)
+        .and_then(|key| key.split_once('/'))
+    else {
+        return AppError::not_found(format!("No artifact {}", query.key)).into_response();
+    };
+    if let Err(response) = may_touch(&req, app) {
+        return response;
+    }
+
+    match objects.list(&query.key).await {
+        Ok(listed) => match listed.into_iter().find(|object| object.key == query.key) {
+            Some(object) => HttpResponse::Ok().json(signed(
+                &objects,
+                &config.artifacts,
+                object.key,
+                object.size,
+            )),
+            None => AppError::not_found(format!("No artifact {}", query.key)).into_response(),
+        },
+        Err(e) => AppError::bad_gateway(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_artifact).service(get_artifact);
+}
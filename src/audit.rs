@@ -0,0 +1,232 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse, Responder, get, web};
+use chrono::Utc;
+use flyd::models::{AuditQuery, AuditRecord};
+
+use crate::auth::{self, Identity};
+use crate::config::{AuditConfig, AuditSink};
+use crate::errors::AppError;
+use crate::namespaces;
+use crate::store::Store;
+
+/// One record per request, keyed by when it was made so keys sort in time order.
+const AUDIT: &str = "audit";
+
+tokio::task_local! {
+    static UPSTREAM_STATUS: Cell<Option<u16>>;
+}
+
+/// Call with the Machines API's answer to each call made while serving a request; the
+/// audit log keeps the last one. Background work isn't audited.
+pub fn upstream_status(status: u16) {
+    let _ = UPSTREAM_STATUS.try_with(|last| last.set(Some(status)));
+}
+
+/// Records every request that may change something. Requests are recorded once they've
+/// been answered, and a record that can't be written is logged rather than failing the
+/// request it describes.
+pub struct AuditLog {
+    config: AuditConfig,
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn from_config(config: Option<&AuditConfig>) -> Result<Option<Self>, String> {
+        let Some(config) = config else {
+            return Ok(None);
+        };
+        let file = match config.sink {
+            AuditSink::Store => None,
+            AuditSink::File => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)
+                    .map_err(|e| format!("audit: failed to open {}: {}", config.path, e))?,
+            )),
+        };
+        Ok(Some(AuditLog {
+            config: config.clone(),
+            file,
+        }))
+    }
+
+    async fn write(&self, store: Option<&Store>, record: &AuditRecord) -> Result<(), String> {
+        match &self.file {
+            Some(file) => {
+                let mut line = serde_json::to_vec(record).map_err(|e| e.to_string())?;
+                line.push(b'\n');
+                file.lock()
+                    .unwrap()
+                    .write_all(&line)
+                    .map_err(|e| e.to_string())
+            }
+            None => {
+                let Some(store) = store else {
+                    return Err("no store is configured".to_string());
+                };
+                let key = format!(
+                    "{}-{}",
+                    record.at.format("%Y%m%dT%H%M%S%.6fZ"),
+                    &flyd::fly::new_idempotency_key()[..8]
+                );
+                store
+                    .put(AUDIT, &key, record)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    async fn read(&self, store: &Store) -> Result<Vec<AuditRecord>, String> {
+        if self.file.is_none() {
+            return store.list(AUDIT).await.map_err(|e| e.to_string());
+        }
+        let contents = std::fs::read_to_string(&self.config.path)
+            .map_err(|e| format!("Failed to read {}: {}", self.config.path, e))?;
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    fn admin(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(role) = &self.config.admin_role else {
+            return Err(
+                AppError::forbidden("Reading the audit log needs audit.admin_role set")
+                    .into_response(),
+            );
+        };
+        auth::require_role(req, Some(role)).map(|_| ())
+    }
+}
+
+/// The machine a request names in its query or JSON body.
+fn machine_id(query: &str, body: Option<&serde_json::Value>) -> Option<String> {
+    web::Query::<BTreeMap<String, String>>::from_query(query)
+        .ok()
+        .and_then(|query| query.get("machine_id").cloned())
+        .or_else(|| body?["machine_id"].as_str().map(str::to_string))
+}
+
+/// Audits requests that aren't GET, HEAD or OPTIONS. Only a hash of the `Authorization`
+/// header is kept, never the header itself.
+pub async fn record<B: MessageBody>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let Some(audit) = req.app_data::<web::Data<AuditLog>>().cloned() else {
+        return next.call(req).await;
+    };
+    if req.method().is_safe() {
+        return next.call(req).await;
+    }
+    let store = req.app_data::<web::Data<Store>>().cloned();
+    let body = namespaces::request_body(&mut req).await?;
+    let app = namespaces::named_apps(req.path(), req.query_string(), body.as_ref())
+        .into_iter()
+        .next();
+    let machine_id = machine_id(req.query_string(), body.as_ref());
+    let token_hash = req
+        .headers()
+        .get(AUTHORIZATION)
+        .map(|header| auth::token_hash(header.as_bytes()));
+    let subject = req
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.subject.clone())
+        .unwrap_or_else(|| "anonymous".to_string());
+    let method = req.method().to_string();
+    let endpoint = req
+        .match_pattern()
+        .unwrap_or_else(|| req.path().to_string());
+
+    let at = Utc::now();
+    let started = Instant::now();
+    let (result, upstream_status) = UPSTREAM_STATUS
+        .scope(Cell::new(None), async {
+            let result = next.call(req).await;
+            (result, UPSTREAM_STATUS.with(Cell::get))
+        })
+        .await;
+
+    let record = AuditRecord {
+        at,
+        subject,
+        token_hash,
+        method,
+        endpoint,
+        app,
+        machine_id,
+        status: match &result {
+            Ok(response) => response.status().as_u16(),
+            Err(e) => e.as_response_error().status_code().as_u16(),
+        },
+        upstream_status,
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+    };
+    if let Err(e) = audit
+        .write(store.as_ref().map(|store| store.get_ref()), &record)
+        .await
+    {
+        log::error!(
+            "Failed to audit {} {} by {}: {}",
+            record.method,
+            record.endpoint,
+            record.subject,
+            e
+        );
+    }
+    result
+}
+
+/// Audited requests, most recent first.
+#[get("/v0/audit")]
+async fn list_audit(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    audit: Option<web::Data<AuditLog>>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let Some(audit) = audit else {
+        return AppError::not_found("Auditing is off; configure [audit] to turn it on")
+            .into_response();
+    };
+    if let Err(response) = audit.admin(&req) {
+        return response;
+    }
+    let mut records = match audit.read(&store).await {
+        Ok(records) => records,
+        Err(e) => return AppError::internal(e).into_response(),
+    };
+    records.retain(|record| {
+        query
+            .app_name
+            .as_ref()
+            .is_none_or(|app| record.app.as_ref() == Some(app))
+            && query
+                .machine_id
+                .as_ref()
+                .is_none_or(|id| record.machine_id.as_ref() == Some(id))
+            && query.subject.as_ref().is_none_or(|s| &record.subject == s)
+            && query.since.is_none_or(|since| record.at >= since)
+    });
+    records.sort_by_key(|record| Reverse(record.at));
+    records.truncate(query.limit.unwrap_or(100));
+    HttpResponse::Ok().json(records)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_audit);
+}
@@ -0,0 +1,702 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{AUTHORIZATION, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse, Responder, get, web};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::api_keys::ApiKeys;
+use crate::client_ip::TrustedProxies;
+use crate::config::{AuthProviderKind, Config, JwtConfig, MtlsConfig, OidcConfig};
+use crate::diagnostics::{self, Phase};
+use crate::errors::AppError;
+use crate::fly_client::authorization_value;
+use crate::virtual_hosts;
+
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Who is calling flyd, and which Authorization value to present to the Machines API
+/// on their behalf.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub provider: &'static str,
+    pub roles: Vec<String>,
+    pub fly_authorization: HeaderValue,
+    /// The only apps the caller may touch, when their credentials are scoped to some.
+    pub apps: Option<Vec<String>>,
+}
+
+impl Identity {
+    pub fn may_touch(&self, app: &str) -> bool {
+        self.apps
+            .as_ref()
+            .is_none_or(|apps| apps.iter().any(|allowed| allowed == app))
+    }
+}
+
+/// The caller, if they're authenticated and hold `role` when one is given.
+pub fn require_role(req: &HttpRequest, role: Option<&str>) -> Result<Identity, HttpResponse> {
+    let Some(identity) = req.extensions().get::<Identity>().cloned() else {
+        return Err(AppError::unauthorized("Authorization header required").into_response());
+    };
+    if let Some(role) = role
+        && !identity.roles.iter().any(|held| held == role)
+    {
+        return Err(AppError::forbidden(format!("This requires role {}", role)).into_response());
+    }
+    Ok(identity)
+}
+
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Authentication failed: {}", self.0)
+    }
+}
+
+pub trait AuthProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// `Ok(None)` means the credentials aren't meant for this provider and the next one
+    /// in the chain should try; `Err` rejects the request outright.
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError>;
+}
+
+pub fn token_hash(token: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(token))
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn fly_authorization(token: &str) -> Result<HeaderValue, String> {
+    let mut value = HeaderValue::from_str(&authorization_value(token))
+        .map_err(|e| format!("Invalid Fly token: {}", e))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+pub fn server_token(
+    provider: &str,
+    token: Option<&String>,
+    config: &Config,
+) -> Result<HeaderValue, String> {
+    match token.or(config.fly_api_token.as_ref()) {
+        Some(token) => fly_authorization(token),
+        None => Err(format!(
+            "Auth provider {} needs a fly_token or FLY_API_TOKEN to call the Machines API",
+            provider
+        )),
+    }
+}
+
+fn claim_roles(claims: &serde_json::Value, roles_claim: &str) -> Vec<String> {
+    match &claims[roles_claim] {
+        serde_json::Value::Array(roles) => roles
+            .iter()
+            .filter_map(|role| role.as_str().map(str::to_string))
+            .collect(),
+        serde_json::Value::String(roles) => roles.split_whitespace().map(str::to_string).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The claims of a JWT, without checking its signature or expiry; `None` if it isn't one.
+pub fn unverified_claims(token: &str) -> Option<serde_json::Value> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    let data = jsonwebtoken::decode::<serde_json::Value>(
+        token,
+        &DecodingKey::from_secret(&[]),
+        &validation,
+    )
+    .ok()?;
+    Some(data.claims)
+}
+
+fn unverified_issuer(token: &str) -> Option<String> {
+    unverified_claims(token)?["iss"]
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Forwards the caller's own Fly token, which is how flyd has always authenticated.
+struct FlyTokenProvider;
+
+impl AuthProvider for FlyTokenProvider {
+    fn name(&self) -> &'static str {
+        "fly_token"
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let Some(header) = req.headers().get(AUTHORIZATION) else {
+            return Ok(None);
+        };
+
+        let mut fly_authorization = header.clone();
+        fly_authorization.set_sensitive(true);
+
+        Ok(Some(Identity {
+            subject: format!("token:{}", &token_hash(header.as_bytes())[..12]),
+            provider: self.name(),
+            roles: Vec::new(),
+            fly_authorization,
+            apps: None,
+        }))
+    }
+}
+
+struct StaticKey {
+    key_sha256: String,
+    subject: String,
+    roles: Vec<String>,
+    fly_authorization: HeaderValue,
+    apps: Option<Vec<String>>,
+}
+
+struct StaticKeysProvider {
+    keys: Vec<StaticKey>,
+}
+
+impl AuthProvider for StaticKeysProvider {
+    fn name(&self) -> &'static str {
+        "static_keys"
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let Some(token) = bearer_token(req) else {
+            return Ok(None);
+        };
+
+        let presented = token_hash(token.as_bytes());
+        Ok(self
+            .keys
+            .iter()
+            .find(|key| key.key_sha256 == presented)
+            .map(|key| Identity {
+                subject: key.subject.clone(),
+                provider: self.name(),
+                roles: key.roles.clone(),
+                fly_authorization: key.fly_authorization.clone(),
+                apps: key.apps.clone(),
+            }))
+    }
+}
+
+struct JwtProvider {
+    config: JwtConfig,
+    key: DecodingKey,
+    algorithms: Vec<Algorithm>,
+    fly_authorization: HeaderValue,
+}
+
+impl AuthProvider for JwtProvider {
+    fn name(&self) -> &'static str {
+        "jwt"
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let Some(token) = bearer_token(req) else {
+            return Ok(None);
+        };
+        let Ok(header) = jsonwebtoken::decode_header(token) else {
+            return Ok(None);
+        };
+        if !self.algorithms.contains(&header.alg) {
+            return Ok(None);
+        }
+        if self.config.issuer.is_some() && unverified_issuer(token) != self.config.issuer {
+            return Ok(None);
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = self.algorithms.clone();
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &self.key, &validation)
+            .map_err(|e| AuthError(format!("invalid JWT: {}", e)))?
+            .claims;
+
+        Ok(Some(Identity {
+            subject: claims["sub"].as_str().unwrap_or_default().to_string(),
+            provider: self.name(),
+            roles: claim_roles(&claims, &self.config.roles_claim),
+            fly_authorization: self.fly_authorization.clone(),
+            apps: None,
+        }))
+    }
+}
+
+struct OidcProvider {
+    config: OidcConfig,
+    jwks: Arc<RwLock<JwkSet>>,
+    fly_authorization: HeaderValue,
+}
+
+impl AuthProvider for OidcProvider {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let Some(token) = bearer_token(req) else {
+            return Ok(None);
+        };
+        let Ok(header) = jsonwebtoken::decode_header(token) else {
+            return Ok(None);
+        };
+        if unverified_issuer(token).as_deref() != Some(self.config.issuer.as_str()) {
+            return Ok(None);
+        }
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(AuthError(
+                "OIDC tokens must be asymmetrically signed".to_string(),
+            ));
+        }
+
+        let key = {
+            let jwks = self.jwks.read().unwrap();
+            let jwk = match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }
+            .ok_or_else(|| AuthError("unknown OIDC signing key".to_string()))?;
+            DecodingKey::from_jwk(jwk).map_err(|e| AuthError(e.to_string()))?
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+            .map_err(|e| AuthError(format!("invalid OIDC token: {}", e)))?
+            .claims;
+
+        Ok(Some(Identity {
+            subject: claims["sub"].as_str().unwrap_or_default().to_string(),
+            provider: self.name(),
+            roles: claim_roles(&claims, &self.config.roles_claim),
+            fly_authorization: self.fly_authorization.clone(),
+            apps: None,
+        }))
+    }
+}
+
+/// Trusts a client certificate subject asserted by a TLS-terminating proxy in front of
+/// flyd. The header is ignored unless the immediate peer is a trusted proxy.
+struct MtlsProvider {
+    config: MtlsConfig,
+    trusted_proxies: TrustedProxies,
+    fly_authorization: HeaderValue,
+}
+
+impl AuthProvider for MtlsProvider {
+    fn name(&self) -> &'static str {
+        "mtls"
+    }
+
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let Some(subject) = req.headers().get(self.config.subject_header.as_str()) else {
+            return Ok(None);
+        };
+        if !req
+            .peer_addr()
+            .is_some_and(|peer| self.trusted_proxies.is_trusted(peer.ip()))
+        {
+            return Err(AuthError(
+                "client certificate subject from untrusted peer".to_string(),
+            ));
+        }
+
+        let subject = subject
+            .to_str()
+            .map_err(|e| AuthError(e.to_string()))?
+            .to_string();
+        Ok(Some(Identity {
+            roles: self.config.roles.get(&subject).cloned().unwrap_or_default(),
+            subject,
+            provider: self.name(),
+            fly_authorization: self.fly_authorization.clone(),
+            apps: None,
+        }))
+    }
+}
+
+pub struct Authenticator {
+    providers: Vec<Box<dyn AuthProvider>>,
+    oidc: Option<(String, Arc<RwLock<JwkSet>>)>,
+}
+
+impl Authenticator {
+    pub fn from_config(
+        config: &Config,
+        trusted_proxies: &TrustedProxies,
+        api_keys: Option<&Arc<ApiKeys>>,
+    ) -> Result<Self, String> {
+        let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        let mut oidc = None;
+
+        for kind in &config.auth.providers {
+            match kind {
+                AuthProviderKind::FlyToken => providers.push(Box::new(FlyTokenProvider)),
+                AuthProviderKind::StaticKeys => {
+                    let keys = config
+                        .auth
+                        .static_keys
+                        .iter()
+                        .map(|key| {
+                            Ok(StaticKey {
+                                key_sha256: key.key_sha256.to_lowercase(),
+                                subject: key.subject.clone(),
+                                roles: key.roles.clone(),
+                                apps: key.apps.clone(),
+                                fly_authorization: server_token(
+                                    "static_keys",
+                                    key.fly_token.as_ref(),
+                                    config,
+                                )?,
+                            })
+                        })
+                        .collect::<Result<Vec<_>, String>>()?;
+                    providers.push(Box::new(StaticKeysProvider { keys }));
+                }
+                AuthProviderKind::Jwt => {
+                    let jwt = config
+                        .auth
+                        .jwt
+                        .clone()
+                        .ok_or("auth provider jwt needs an [auth.jwt] section")?;
+                    let (key, algorithms) = match (&jwt.secret, &jwt.public_key_pem) {
+                        (Some(secret), None) => (
+                            DecodingKey::from_secret(secret.as_bytes()),
+                            vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+                        ),
+                        (None, Some(pem)) => (
+                            DecodingKey::from_rsa_pem(pem.as_bytes())
+                                .map_err(|e| format!("Invalid auth.jwt.public_key_pem: {}", e))?,
+                            vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512],
+                        ),
+                        _ => {
+                            return Err("auth.jwt needs exactly one of secret or public_key_pem"
+                                .to_string());
+                        }
+                    };
+                    providers.push(Box::new(JwtProvider {
+                        fly_authorization: server_token("jwt", jwt.fly_token.as_ref(), config)?,
+                        config: jwt,
+                        key,
+                        algorithms,
+                    }));
+                }
+                AuthProviderKind::Oidc => {
+                    let oidc_config = config
+                        .auth
+                        .oidc
+                        .clone()
+                        .ok_or("auth provider oidc needs an [auth.oidc] section")?;
+                    let jwks = Arc::new(RwLock::new(JwkSet { keys: Vec::new() }));
+                    oidc = Some((oidc_config.issuer.clone(), jwks.clone()));
+                    providers.push(Box::new(OidcProvider {
+                        fly_authorization: server_token(
+                            "oidc",
+                            oidc_config.fly_token.as_ref(),
+                            config,
+                        )?,
+                        config: oidc_config,
+                        jwks,
+                    }));
+                }
+                AuthProviderKind::ApiKeys => providers.push(Box::new(
+                    api_keys
+                        .cloned()
+                        .ok_or("auth provider api_keys needs an [auth.api_keys] section")?,
+                )),
+                AuthProviderKind::Mtls => {
+                    let mtls = config.auth.mtls.clone().unwrap_or_default();
+                    providers.push(Box::new(MtlsProvider {
+                        fly_authorization: server_token("mtls", mtls.fly_token.as_ref(), config)?,
+                        config: mtls,
+                        trusted_proxies: trusted_proxies.clone(),
+                    }));
+                }
+            }
+        }
+
+        log::info!(
+            "Auth providers: {}",
+            providers
+                .iter()
+                .map(|provider| provider.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        Ok(Authenticator { providers, oidc })
+    }
+
+    pub fn identify(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        for provider in &self.providers {
+            if let Some(identity) = provider.authenticate(req)? {
+                return Ok(Some(identity));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Keeps the OIDC signing keys fresh. Returns immediately when OIDC isn't configured.
+    pub async fn refresh_oidc_keys(authenticator: web::Data<Self>, http_client: reqwest::Client) {
+        let Some((issuer, jwks)) = authenticator.oidc.clone() else {
+            return;
+        };
+
+        let mut ticker = tokio::time::interval(JWKS_REFRESH_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match fetch_jwks(&http_client, &issuer).await {
+                Ok(keys) => *jwks.write().unwrap() = keys,
+                Err(e) => log::error!("Failed to refresh OIDC keys from {}: {}", issuer, e),
+            }
+        }
+    }
+}
+
+async fn fetch_jwks(http_client: &reqwest::Client, issuer: &str) -> Result<JwkSet, reqwest::Error> {
+    let discovery: serde_json::Value = http_client
+        .get(format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let jwks_uri = discovery["jwks_uri"].as_str().unwrap_or_default();
+    http_client
+        .get(jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+#[get("/v0/whoami")]
+async fn whoami(req: HttpRequest) -> impl Responder {
+    match require_role(&req, None) {
+        Ok(identity) => HttpResponse::Ok().json(json!({
+            "subject": identity.subject,
+            "provider": identity.provider,
+            "roles": identity.roles,
+            "apps": identity.apps,
+            "virtual_host": virtual_hosts::of(&req).map(|host| &host.name),
+        })),
+        Err(response) => response,
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(whoami);
+}
+
+/// Attaches the caller's `Identity` to the request. Requests without credentials pass
+/// through unauthenticated; handlers that need an identity reject them.
+pub async fn authenticate<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    if let Some(authenticator) = req.app_data::<web::Data<Authenticator>>().cloned() {
+        let started = Instant::now();
+        let identified = authenticator.identify(req.request());
+        diagnostics::record(Phase::Auth, started.elapsed());
+        match identified {
+            Ok(Some(mut identity)) => {
+                if let Err(e) = virtual_hosts::apply(req.request(), &mut identity) {
+                    return Ok(req
+                        .into_response(AppError::forbidden(e).into_response())
+                        .map_into_right_body());
+                }
+                req.extensions_mut().insert(identity);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                return Ok(req
+                    .into_response(AppError::unauthorized(e.to_string()).into_response())
+                    .map_into_right_body());
+            }
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+    use crate::config::{AuthConfig, StaticKeyConfig};
+
+    fn config(providers: Vec<AuthProviderKind>) -> Config {
+        Config {
+            fly_api_token: Some("server".to_string()),
+            auth: AuthConfig {
+                providers,
+                static_keys: vec![StaticKeyConfig {
+                    key_sha256: token_hash(b"alice-key").to_uppercase(),
+                    subject: "alice".to_string(),
+                    roles: vec!["admin".to_string()],
+                    fly_token: None,
+                    apps: Some(vec!["web".to_string()]),
+                }],
+                jwt: Some(JwtConfig {
+                    secret: Some("jwt-secret".to_string()),
+                    public_key_pem: None,
+                    issuer: Some("https://issuer.example.com".to_string()),
+                    audience: None,
+                    roles_claim: "roles".to_string(),
+                    fly_token: None,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn authenticator(providers: Vec<AuthProviderKind>) -> Authenticator {
+        Authenticator::from_config(&config(providers), &TrustedProxies::default(), None).unwrap()
+    }
+
+    fn bearer(token: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+            .to_http_request()
+    }
+
+    fn jwt(expires_in: i64) -> String {
+        let claims = json!({
+            "sub": "bob",
+            "iss": "https://issuer.example.com",
+            "roles": "deployer viewer",
+            "exp": chrono::Utc::now().timestamp() + expires_in,
+        });
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"jwt-secret"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn the_first_provider_that_recognizes_the_credentials_wins() {
+        let authenticator = authenticator(vec![
+            AuthProviderKind::StaticKeys,
+            AuthProviderKind::Jwt,
+            AuthProviderKind::FlyToken,
+        ]);
+
+        let alice = authenticator
+            .identify(&bearer("alice-key"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.provider, "static_keys");
+        assert_eq!(alice.subject, "alice");
+        assert_eq!(alice.fly_authorization, "Bearer server");
+        assert!(alice.may_touch("web") && !alice.may_touch("api"));
+
+        let bob = authenticator.identify(&bearer(&jwt(60))).unwrap().unwrap();
+        assert_eq!(bob.provider, "jwt");
+        assert_eq!(bob.roles, ["deployer", "viewer"]);
+        assert!(bob.may_touch("api"));
+
+        let own = authenticator
+            .identify(&bearer("fly-token"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(own.provider, "fly_token");
+        assert_eq!(own.fly_authorization, "Bearer fly-token");
+
+        let anonymous = TestRequest::default().to_http_request();
+        assert!(authenticator.identify(&anonymous).unwrap().is_none());
+    }
+
+    #[test]
+    fn an_expired_jwt_is_rejected_not_passed_on() {
+        let authenticator = authenticator(vec![AuthProviderKind::Jwt, AuthProviderKind::FlyToken]);
+        let error = authenticator.identify(&bearer(&jwt(-3600))).unwrap_err();
+        assert!(error.0.contains("invalid JWT"), "{}", error);
+    }
+
+    #[test]
+    fn a_client_certificate_subject_needs_a_trusted_peer() {
+        let mut config = config(vec![AuthProviderKind::Mtls]);
+        config.auth.mtls = Some(MtlsConfig {
+            roles: [("CN=ci".to_string(), vec!["deployer".to_string()])].into(),
+            ..Default::default()
+        });
+        let proxies = TrustedProxies::from_cidrs(&["10.0.0.0/8".to_string()]).unwrap();
+        let authenticator = Authenticator::from_config(&config, &proxies, None).unwrap();
+        let request = |peer: &str| {
+            TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("x-client-cert-subject", "CN=ci"))
+                .to_http_request()
+        };
+
+        let ci = authenticator
+            .identify(&request("10.0.0.2:443"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ci.roles, ["deployer"]);
+        assert!(authenticator.identify(&request("203.0.113.9:443")).is_err());
+    }
+
+    #[test]
+    fn require_role_refuses_the_anonymous_and_the_unprivileged() {
+        let req = TestRequest::default().to_http_request();
+        let refused = require_role(&req, None).unwrap_err();
+        assert_eq!(refused.status(), 401);
+
+        req.extensions_mut().insert(Identity {
+            subject: "bob".to_string(),
+            provider: "jwt",
+            roles: vec!["deployer".to_string()],
+            fly_authorization: HeaderValue::from_static("Bearer server"),
+            apps: None,
+        });
+        assert_eq!(require_role(&req, None).unwrap().subject, "bob");
+        assert_eq!(require_role(&req, Some("deployer")).unwrap().subject, "bob");
+        assert_eq!(require_role(&req, Some("admin")).unwrap_err().status(), 403);
+    }
+}
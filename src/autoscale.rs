@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::{AutoscalePolicy, MetricSample};
+use serde_json::{Value, json};
+
+use crate::auth;
+use crate::backend::Backend;
+use crate::config::AutoscaleConfig;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::features::{self, Features};
+use crate::fleets;
+use crate::store::Store;
+
+const POLICIES: &str = "autoscale_policies";
+const APP_AUTOSCALED: &str = "app.autoscaled";
+
+struct Sample {
+    value: f64,
+    at: DateTime<Utc>,
+}
+
+/// The latest sample of each app's metrics, and when each app was last scaled. Policies
+/// live in the store; samples don't outlast a restart, as they go stale in minutes anyway.
+pub struct Autoscaler {
+    config: AutoscaleConfig,
+    samples: Mutex<HashMap<(String, String), Sample>>,
+    scaled_at: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl Autoscaler {
+    pub fn new(config: AutoscaleConfig) -> Self {
+        Autoscaler {
+            config,
+            samples: Mutex::new(HashMap::new()),
+            scaled_at: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn sample(&self, app: &str, metric: &str) -> Option<f64> {
+        let cutoff = Utc::now() - TimeDelta::seconds(self.config.max_sample_age_secs as i64);
+        self.samples
+            .lock()
+            .unwrap()
+            .get(&(app.to_string(), metric.to_string()))
+            .filter(|sample| sample.at > cutoff)
+            .map(|sample| sample.value)
+    }
+
+    /// Fails closed without an `admin_role`.
+    fn admin(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(role) = &self.config.admin_role else {
+            return Err(
+                AppError::forbidden("Autoscale policies need autoscale.admin_role").into_response(),
+            );
+        };
+        auth::require_role(req, Some(role)).map(|_| ())
+    }
+
+    /// Whether the caller presented the metrics token, or else is an admin.
+    fn may_push(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        if let (Some(expected), Some(token)) =
+            (&self.config.metrics_token_sha256, auth::bearer_token(req))
+            && auth::token_hash(token.as_bytes()) == expected.to_lowercase()
+        {
+            return Ok(());
+        }
+        self.admin(req)
+    }
+
+    fn cooling_down(&self, app: &str) -> bool {
+        let cooldown = TimeDelta::seconds(self.config.scale_in_cooldown_secs as i64);
+        self.scaled_at
+            .lock()
+            .unwrap()
+            .get(app)
+            .is_some_and(|at| Utc::now() - *at < cooldown)
+    }
+
+    /// Starts or creates machines, or stops the newest started ones, until the app has as
+    /// many started as the policy wants.
+    async fn scale<B: Backend>(
+        &self,
+        backend: &B,
+        events: &EventLog,
+        drainer: Option<&web::Data<Drainer>>,
+        policy: &AutoscalePolicy,
+    ) -> Result<(), String> {
+        let app = &policy.app;
+        let mut machines: Vec<Value> = backend
+            .list_machines(app)
+            .await
+            .map_err(|e| format!("Failed to list machines: {}", e))?
+            .into_iter()
+            .filter(fleets::is_live)
+            .collect();
+        machines.sort_by(|a, b| a["created_at"].as_str().cmp(&b["created_at"].as_str()));
+        let (started, stopped): (Vec<&Value>, Vec<&Value>) = machines
+            .iter()
+            .filter(|machine| machine["state"] == "started" || machine["state"] == "stopped")
+            .partition(|machine| machine["state"] == "started");
+
+        let sample = self.sample(app, &policy.metric);
+        let wanted = match sample {
+            Some(value) => (value / policy.target_per_machine).ceil().max(0.0) as usize,
+            None => started.len(),
+        }
+        .clamp(policy.min_machines, policy.max_machines);
+        if wanted == started.len() || (wanted < started.len() && self.cooling_down(app)) {
+            return Ok(());
+        }
+
+        let mut changed = Vec::new();
+        if wanted > started.len() {
+            for machine in stopped.iter().take(wanted - started.len()) {
+                let id = machine["id"].as_str().unwrap_or_default();
+                backend
+                    .start_machine(app, id)
+                    .await
+                    .map_err(|e| format!("Failed to start machine {}: {}", id, e))?;
+                changed.push(id.to_string());
+            }
+            let missing = wanted - started.len() - changed.len();
+            if missing > 0 {
+                let Some(template) = machines.first() else {
+                    return Err(format!("{} has no machine to scale up from", app));
+                };
+                let body = json!({ "region": template["region"], "config": template["config"] });
+                for _ in 0..missing {
+                    let machine = backend
+                        .create_machine(app, &body)
+                        .await
+                        .map_err(|e| format!("Failed to create machine: {}", e))?;
+                    changed.push(machine["id"].as_str().unwrap_or_default().to_string());
+                }
+            }
+        } else {
+            for machine in started.iter().rev().take(started.len() - wanted) {
+                let id = machine["id"].as_str().unwrap_or_default();
+                drain::drain(drainer, backend, app, id).await;
+                backend
+                    .stop_machine(app, id)
+                    .await
+                    .map_err(|e| format!("Failed to stop machine {}: {}", id, e))?;
+                changed.push(id.to_string());
+            }
+        }
+
+        self.scaled_at
+            .lock()
+            .unwrap()
+            .insert(app.clone(), Utc::now());
+        events.record(
+            APP_AUTOSCALED,
+            Some(app),
+            None,
+            json!({
+                "from": started.len(),
+                "to": wanted,
+                "metric": policy.metric,
+                "value": sample,
+                "machines": changed,
+            }),
+        );
+        Ok(())
+    }
+}
+
+/// Scales every app with a policy every `autoscale.interval_secs`. Managed fleets are left
+/// alone, as the reconciler would undo it.
+pub async fn run<B: Backend>(
+    autoscaler: web::Data<Autoscaler>,
+    backend: B,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    drainer: Option<web::Data<Drainer>>,
+    features: web::Data<Features>,
+) {
+    let interval = Duration::from_secs(autoscaler.config.interval_secs.max(1));
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if !features.enabled(features::AUTOSCALER) {
+            continue;
+        }
+        let policies = match store.list::<AutoscalePolicy>(POLICIES).await {
+            Ok(policies) => policies,
+            Err(e) => {
+                log::error!("Failed to load autoscale policies: {}", e);
+                continue;
+            }
+        };
+        for policy in policies {
+            match fleets::fleet_spec(&store, &policy.app).await {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(e) => {
+                    log::error!("Failed to load the fleet spec of {}: {}", policy.app, e);
+                    continue;
+                }
+            }
+            if let Err(e) = autoscaler
+                .scale(&backend, &events, drainer.as_ref(), &policy)
+                .await
+            {
+                log::error!("Failed to autoscale {}: {}", policy.app, e);
+            }
+        }
+    }
+}
+
+/// Sets the app's policy, replacing any it had.
+#[post("/v0/autoscale/policies")]
+async fn put_policy(
+    req: HttpRequest,
+    body: web::Json<AutoscalePolicy>,
+    autoscaler: web::Data<Autoscaler>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    if let Err(response) = autoscaler.admin(&req) {
+        return response;
+    }
+    let policy = body.into_inner();
+    if policy.target_per_machine <= 0.0 {
+        return AppError::bad_request("target_per_machine must be positive").into_response();
+    }
+    if policy.min_machines > policy.max_machines {
+        return AppError::bad_request("min_machines can't exceed max_machines").into_response();
+    }
+    match store.put(POLICIES, &policy.app, &policy).await {
+        Ok(()) => HttpResponse::Created().json(policy),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/autoscale/policies")]
+async fn list_policies(store: web::Data<Store>) -> impl Responder {
+    match store.list::<AutoscalePolicy>(POLICIES).await {
+        Ok(policies) => HttpResponse::Ok().json(policies),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[delete("/v0/autoscale/policies/{app}")]
+async fn delete_policy(
+    req: HttpRequest,
+    path: web::Path<String>,
+    autoscaler: web::Data<Autoscaler>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    if let Err(response) = autoscaler.admin(&req) {
+        return response;
+    }
+    match store.delete(POLICIES, &path).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => {
+            AppError::not_found(format!("{} has no autoscale policy", path)).into_response()
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[post("/v0/autoscale/metrics")]
+async fn push_metric(
+    req: HttpRequest,
+    body: web::Json<MetricSample>,
+    autoscaler: web::Data<Autoscaler>,
+) -> impl Responder {
+    if let Err(response) = autoscaler.may_push(&req) {
+        return response;
+    }
+    if !body.value.is_finite() {
+        return AppError::bad_request("value must be a number").into_response();
+    }
+    let sample = body.into_inner();
+    autoscaler.samples.lock().unwrap().insert(
+        (sample.app, sample.metric),
+        Sample {
+            value: sample.value,
+            at: Utc::now(),
+        },
+    );
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(put_policy)
+        .service(list_policies)
+        .service(delete_policy)
+        .service(push_metric);
+}
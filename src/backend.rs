@@ -0,0 +1,137 @@
+use std::fmt::Display;
+
+use serde_json::Value;
+
+/// Where machines actually run. Orchestration (fleets, reports) is written against this
+/// instead of the Machines API directly, so it can be reused with other backends, e.g. a
+/// local one for testing without Fly. Machines, apps and volumes are exchanged as Machines
+/// API shaped JSON whatever the backend.
+pub trait Backend: Send + Sync {
+    type Error: Display + Send;
+
+    fn list_apps(
+        &self,
+        org_slug: &str,
+    ) -> impl Future<Output = Result<Vec<Value>, Self::Error>> + Send;
+
+    fn list_machines(
+        &self,
+        app_name: &str,
+    ) -> impl Future<Output = Result<Vec<Value>, Self::Error>> + Send;
+
+    fn list_region_machines(
+        &self,
+        app_name: &str,
+        region: &str,
+    ) -> impl Future<Output = Result<Vec<Value>, Self::Error>> + Send;
+
+    fn get_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+    ) -> impl Future<Output = Result<Value, Self::Error>> + Send;
+
+    fn create_machine(
+        &self,
+        app_name: &str,
+        body: &Value,
+    ) -> impl Future<Output = Result<Value, Self::Error>> + Send;
+
+    /// `lease_nonce` is only meaningful to backends with leases; others ignore it.
+    fn update_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        body: &Value,
+        lease_nonce: Option<&str>,
+    ) -> impl Future<Output = Result<Value, Self::Error>> + Send;
+
+    fn start_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn stop_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn restart_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Stops the proxy routing new requests to the machine; in-flight ones carry on.
+    fn cordon_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn uncordon_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Runs `command` on the machine, returning its `exit_code`, `stdout` and `stderr`.
+    fn exec_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        command: &[String],
+        timeout_secs: u64,
+    ) -> impl Future<Output = Result<Value, Self::Error>> + Send;
+
+    /// `lease_nonce` as for `update_machine`.
+    fn destroy_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        lease_nonce: Option<&str>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn set_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+        value: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn delete_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn list_volumes(
+        &self,
+        app_name: &str,
+    ) -> impl Future<Output = Result<Vec<Value>, Self::Error>> + Send;
+}
+
+/// `apps` plus every app in `org_slug`, sorted and deduplicated.
+pub async fn resolve_apps<B: Backend>(
+    backend: &B,
+    apps: &[String],
+    org_slug: Option<&str>,
+) -> Result<Vec<String>, B::Error> {
+    let mut apps = apps.to_vec();
+    if let Some(org_slug) = org_slug {
+        apps.extend(
+            backend
+                .list_apps(org_slug)
+                .await?
+                .iter()
+                .filter_map(|app| app["name"].as_str().map(str::to_string)),
+        );
+    }
+    apps.sort();
+    apps.dedup();
+    Ok(apps)
+}
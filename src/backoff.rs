@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::{QuarantinedMachine, ReleaseQuarantineRequest};
+use serde_json::json;
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::config::RestartBackoffConfig;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+const MACHINE_QUARANTINED: &str = "machine.quarantined";
+const MACHINE_RELEASED: &str = "machine.released";
+
+pub enum Verdict {
+    Go,
+    /// Too soon after the last attempt.
+    Wait,
+    /// Out of attempts: quarantine it.
+    Quarantine,
+    /// Already quarantined.
+    Quarantined,
+}
+
+struct Attempts {
+    count: u32,
+    last_at: DateTime<Utc>,
+    quarantined: Option<(DateTime<Utc>, Option<String>)>,
+}
+
+/// Keeps remediation and the fleet reconciler from restarting a machine forever: each
+/// attempt on the same machine waits twice as long as the one before, and after
+/// `quarantine_after` the machine is cordoned and left alone until someone releases it.
+/// Machines are tracked by name, which survives being recreated.
+pub struct RestartBackoff {
+    config: RestartBackoffConfig,
+    machines: Mutex<HashMap<(String, String), Attempts>>,
+}
+
+impl RestartBackoff {
+    pub fn new(config: RestartBackoffConfig) -> Self {
+        RestartBackoff {
+            config,
+            machines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn verdict(&self, app: &str, machine: &str) -> Verdict {
+        let mut machines = self.machines.lock().unwrap();
+        let key = (app.to_string(), machine.to_string());
+        let Some(attempts) = machines.get(&key) else {
+            return Verdict::Go;
+        };
+        if attempts.quarantined.is_some() {
+            return Verdict::Quarantined;
+        }
+        let since = Utc::now() - attempts.last_at;
+        if since > TimeDelta::seconds(self.config.reset_after_secs as i64) {
+            machines.remove(&key);
+            return Verdict::Go;
+        }
+        if attempts.count >= self.config.quarantine_after.max(1) {
+            return Verdict::Quarantine;
+        }
+        let delay = self
+            .config
+            .base_secs
+            .saturating_mul(1 << (attempts.count - 1).min(32))
+            .min(self.config.max_secs);
+        if since < TimeDelta::seconds(delay as i64) {
+            Verdict::Wait
+        } else {
+            Verdict::Go
+        }
+    }
+
+    pub fn attempted(&self, app: &str, machine: &str) {
+        let mut machines = self.machines.lock().unwrap();
+        let attempts = machines
+            .entry((app.to_string(), machine.to_string()))
+            .or_insert(Attempts {
+                count: 0,
+                last_at: Utc::now(),
+                quarantined: None,
+            });
+        attempts.count += 1;
+        attempts.last_at = Utc::now();
+    }
+
+    /// Cordons the machine, if there is one, and stops attempts on it. The event recorded
+    /// is what notifications alert on.
+    pub async fn quarantine<B: Backend>(
+        &self,
+        backend: &B,
+        events: &EventLog,
+        app: &str,
+        machine: &str,
+        machine_id: Option<&str>,
+    ) {
+        let count = {
+            let mut machines = self.machines.lock().unwrap();
+            let Some(attempts) = machines.get_mut(&(app.to_string(), machine.to_string())) else {
+                return;
+            };
+            attempts.quarantined = Some((Utc::now(), machine_id.map(str::to_string)));
+            attempts.count
+        };
+        let cordoned = match machine_id {
+            Some(id) => match backend.cordon_machine(app, id).await {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Failed to cordon quarantined machine {}: {}", id, e);
+                    false
+                }
+            },
+            None => false,
+        };
+        events.record(
+            MACHINE_QUARANTINED,
+            Some(app),
+            machine_id,
+            json!({ "machine": machine, "attempts": count, "cordoned": cordoned }),
+        );
+    }
+
+    fn quarantined(&self, app: &str) -> Vec<QuarantinedMachine> {
+        let machines = self.machines.lock().unwrap();
+        let mut quarantined: Vec<QuarantinedMachine> = machines
+            .iter()
+            .filter(|((machine_app, _), _)| machine_app == app)
+            .filter_map(|((app, machine), attempts)| {
+                let (quarantined_at, machine_id) = attempts.quarantined.clone()?;
+                Some(QuarantinedMachine {
+                    app: app.clone(),
+                    machine: machine.clone(),
+                    machine_id,
+                    attempts: attempts.count,
+                    quarantined_at,
+                })
+            })
+            .collect();
+        quarantined.sort_by_key(|machine| machine.quarantined_at);
+        quarantined
+    }
+
+    /// Forgets a quarantined machine's attempts, returning its ID if it had one. `None` if
+    /// it wasn't quarantined.
+    fn release(&self, app: &str, machine: &str) -> Option<Option<String>> {
+        let mut machines = self.machines.lock().unwrap();
+        let key = (app.to_string(), machine.to_string());
+        let (_, machine_id) = machines.get(&key)?.quarantined.clone()?;
+        machines.remove(&key);
+        Some(machine_id)
+    }
+}
+
+#[get("/v0/apps/{app}/quarantine")]
+async fn list_quarantined(
+    path: web::Path<String>,
+    backoff: web::Data<RestartBackoff>,
+) -> impl Responder {
+    HttpResponse::Ok().json(backoff.quarantined(&path))
+}
+
+async fn uncordon<B: Backend>(backend: &B, app: &str, machine_id: &str) -> Result<(), String> {
+    backend
+        .uncordon_machine(app, machine_id)
+        .await
+        .map_err(|e| format!("Released, but failed to uncordon {}: {}", machine_id, e))
+}
+
+/// Takes a machine out of quarantine and back into traffic; its attempts start over.
+#[post("/v0/quarantine/release")]
+async fn release_quarantined(
+    req: HttpRequest,
+    body: web::Json<ReleaseQuarantineRequest>,
+    backoff: web::Data<RestartBackoff>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match req.app_data::<web::Data<DockerBackend>>() {
+        Some(_) => None,
+        None => match prepare_request(&req, body.use_private_api) {
+            Ok((headers, api_hostname)) => Some(
+                FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+                    .with_slo(slo.into_inner()),
+            ),
+            Err(response) => return response,
+        },
+    };
+    let Some(machine_id) = backoff.release(&body.app_name, &body.machine) else {
+        return AppError::not_found(format!(
+            "{} of {} isn't quarantined",
+            body.machine, body.app_name
+        ))
+        .into_response();
+    };
+    events.record(
+        MACHINE_RELEASED,
+        Some(&body.app_name),
+        machine_id.as_deref(),
+        json!({
+            "machine": body.machine,
+            "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+        }),
+    );
+    let Some(machine_id) = machine_id else {
+        return HttpResponse::NoContent().finish();
+    };
+    let uncordoned = match (&client, req.app_data::<web::Data<DockerBackend>>()) {
+        (Some(client), _) => uncordon(client, &body.app_name, &machine_id).await,
+        (None, Some(docker)) => uncordon(docker.get_ref(), &body.app_name, &machine_id).await,
+        (None, None) => Ok(()),
+    };
+    match uncordoned {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => AppError::bad_gateway(e).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_quarantined).service(release_quarantined);
+}
@@ -0,0 +1,478 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, post, web};
+use flyd::models::{
+    BatchDryRun, BatchMachineResult, BatchNewMachinesReport, BatchNewMachinesRequest, BulkAction,
+    BulkMachineResult, BulkMachinesReport, BulkMachinesRequest, DryRunQuery, MachineDryRun,
+    MachineFilter,
+};
+use serde_json::{Value, json};
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::capacity::{self, CapacityMap};
+use crate::concurrency::{self, AdaptiveLimit};
+use crate::config::Config;
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{self, FlyClient, WithChecks};
+use crate::jobs::{self, Jobs, Work};
+use crate::secret_refs::{Resolved, SecretResolver};
+use crate::slo::SloTracker;
+use crate::store::Store;
+use crate::{
+    cordon, defaults, fleets, impact, namespaces, prepare_request, pricing, provenance, validation,
+};
+
+/// Whether a create was called with `?dry_run=true`.
+pub fn wants_dry_run(req: &HttpRequest) -> bool {
+    web::Query::<DryRunQuery>::from_query(req.query_string()).is_ok_and(|query| query.dry_run)
+}
+
+/// Creates `count` machines from one config, as many at a time as the `[concurrency]`
+/// limit allows. Every machine gets its own result, so one failing doesn't hide the others.
+/// Called async, it answers 202 with a job whose result is the report. With
+/// `?dry_run=true` it answers each machine's payload and estimated cost instead.
+#[post("/v0/machines/batch_new")]
+async fn batch_new(
+    req: HttpRequest,
+    body: web::Json<BatchNewMachinesRequest>,
+    http_client: web::Data<reqwest::Client>,
+    flyd_config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+    secrets: web::Data<SecretResolver>,
+    capacity: web::Data<CapacityMap>,
+) -> impl Responder {
+    let max_count = flyd_config.machines.batch_max_count;
+    if body.count == 0 || body.count > max_count {
+        return AppError::bad_request(format!("count must be between 1 and {}", max_count))
+            .into_response();
+    }
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let limit = req.app_data::<web::Data<AdaptiveLimit>>();
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_limit(limit.map(|limit| limit.clone().into_inner()))
+        .with_request_checks(&req);
+
+    let mut template = serde_json::to_value(&body.config).unwrap_or_default();
+    defaults::apply(&flyd_config.app_defaults, &body.app_name, &mut template);
+    if let Some(recorded) = &body.provenance {
+        provenance::set(&mut template["config"], recorded);
+    }
+    if let Err(e) = provenance::validate(&template["config"]) {
+        return AppError::unprocessable(e).into_response();
+    }
+    if let Err(e) = validation::machine_config(&template["config"]) {
+        return e.into_response();
+    }
+    // Each create checks the quota too, but not against the others in flight.
+    if let Some(namespace) = namespaces::of(&req)
+        && let Err(e) = namespace
+            .check_machine_room(&client, body.count, limit.map(|limit| limit.get_ref()))
+            .await
+    {
+        return AppError::unprocessable(e).into_response();
+    }
+    for region in &body.regions {
+        if capacity.is_blocked(&body.app_name, region).await {
+            return AppError::conflict(format!(
+                "New machines may not be placed in {} for {}",
+                region, body.app_name
+            ))
+            .into_response();
+        }
+    }
+    let resolved = match secrets
+        .scoped(&req)
+        .resolve(&body.app_name, &mut template)
+        .await
+    {
+        Ok(resolved) => resolved,
+        Err(e) => return AppError::unprocessable(e).into_response(),
+    };
+
+    let wait = body
+        .wait_for_started
+        .then(|| Duration::from_secs(body.timeout_secs.unwrap_or(60)));
+    let batch = Batch {
+        client,
+        app: body.app_name.clone(),
+        count: body.count,
+        regions: body.regions.clone(),
+        wait,
+        size: capacity::size(&template),
+        template,
+        resolved,
+        // One key per machine, so a retried create can't make a second one.
+        key: fly_client::idempotency_key(Some(&req)),
+        capacity,
+        store: req.app_data::<web::Data<Store>>().cloned(),
+        limit: limit.cloned(),
+    };
+
+    if wants_dry_run(&req) {
+        return HttpResponse::Ok().json(batch.dry_run());
+    }
+    match req.app_data::<web::Data<Jobs>>() {
+        Some(jobs) if jobs::wants_async(&req) => {
+            let batch = Arc::new(batch);
+            let work: Work = Box::new(move || {
+                let batch = batch.clone();
+                Box::pin(async move {
+                    serde_json::to_value(batch.run().await).map_err(|e| e.to_string())
+                })
+            });
+            // Creates aren't retried as a whole: the report says which machines failed.
+            let job = Jobs::submit_once(
+                jobs,
+                "machines.batch_new",
+                Some(&body.app_name),
+                format!("batch_new:{}", body.app_name),
+                jobs::submitter(&req),
+                work,
+            );
+            jobs::accepted(&job)
+        }
+        _ => HttpResponse::Ok().json(batch.run().await),
+    }
+}
+
+/// A validated `batch_new`, ready to run in the request or as a job.
+struct Batch {
+    client: FlyClient,
+    app: String,
+    count: usize,
+    regions: Vec<String>,
+    wait: Option<Duration>,
+    template: Value,
+    resolved: Vec<Resolved>,
+    size: String,
+    key: String,
+    capacity: web::Data<CapacityMap>,
+    store: Option<web::Data<Store>>,
+    limit: Option<web::Data<AdaptiveLimit>>,
+}
+
+impl Batch {
+    /// The `index`th machine's region and create body.
+    fn machine(&self, index: usize) -> (Option<String>, Value) {
+        let mut machine = self.template.clone();
+        let region = if self.regions.is_empty() {
+            self.template["region"].as_str().map(str::to_string)
+        } else {
+            Some(self.regions[index % self.regions.len()].clone())
+        };
+        if let Some(region) = &region {
+            machine["region"] = json!(region);
+        }
+        if let Some(name) = self.template["name"].as_str() {
+            machine["name"] = json!(format!("{}-{}", name, index));
+        }
+        (region, machine)
+    }
+
+    fn dry_run(&self) -> BatchDryRun {
+        let machines: Vec<MachineDryRun> = (0..self.count)
+            .map(|index| {
+                let (region, mut payload) = self.machine(index);
+                let estimated_monthly_cost = pricing::machine_monthly_cost_in(
+                    &payload["config"]["guest"],
+                    region.as_deref(),
+                );
+                SecretResolver::mask(&mut payload, &self.resolved);
+                MachineDryRun {
+                    payload,
+                    estimated_monthly_cost,
+                }
+            })
+            .collect();
+        BatchDryRun {
+            estimated_monthly_cost: machines
+                .iter()
+                .map(|machine| machine.estimated_monthly_cost)
+                .sum(),
+            machines,
+        }
+    }
+
+    async fn run(&self) -> BatchNewMachinesReport {
+        let done = AtomicUsize::new(0);
+        let done = &done;
+        let machines = concurrency::map(
+            self.limit.as_ref().map(|limit| limit.get_ref()),
+            (0..self.count).map(|index| {
+                let (region, machine) = self.machine(index);
+                async move {
+                    let client = self
+                        .client
+                        .clone()
+                        .with_idempotency_key(Some(format!("{}-{}", self.key, index)));
+                    let (mut machine, error) =
+                        create(&client, &self.app, &machine, self.wait).await;
+                    if let Some(machine) = &mut machine {
+                        SecretResolver::mask(machine, &self.resolved);
+                        if let Some(store) = &self.store {
+                            provenance::index(store, &self.app, machine).await;
+                        }
+                        self.capacity.placed(
+                            &self.app,
+                            region.as_deref(),
+                            region.as_deref(),
+                            &self.size,
+                        );
+                    }
+                    jobs::record_progress(done.fetch_add(1, Ordering::Relaxed) + 1, self.count);
+                    BatchMachineResult {
+                        index,
+                        region,
+                        machine,
+                        error,
+                    }
+                }
+            }),
+        )
+        .await;
+
+        let failed = machines
+            .iter()
+            .filter(|result| result.error.is_some())
+            .count();
+        BatchNewMachinesReport {
+            created: machines
+                .iter()
+                .filter(|result| result.machine.is_some())
+                .count(),
+            failed,
+            machines,
+        }
+    }
+}
+
+/// Creates one machine and, with `wait`, waits for it to start. A machine that doesn't
+/// start in time is returned along with the error.
+async fn create(
+    client: &FlyClient,
+    app: &str,
+    body: &Value,
+    wait: Option<Duration>,
+) -> (Option<Value>, Option<String>) {
+    let mut machine = match client.create_machine(app, body).await {
+        Ok(machine) => machine,
+        Err(e) => return (None, Some(e.to_string())),
+    };
+    let Some(timeout) = wait else {
+        return (Some(machine), None);
+    };
+    let id = machine["id"].as_str().unwrap_or_default().to_string();
+    let instance_id = machine["instance_id"].as_str().map(str::to_string);
+    match client
+        .wait_for_state(app, &id, "started", instance_id.as_deref(), timeout)
+        .await
+    {
+        Ok(_) => {
+            machine["state"] = json!("started");
+            (Some(machine), None)
+        }
+        Err(e) => (
+            Some(machine),
+            Some(format!("Machine {} didn't start: {}", id, e)),
+        ),
+    }
+}
+
+pub fn matches(filter: &MachineFilter, machine: &Value) -> bool {
+    fleets::is_live(machine)
+        && filter
+            .region
+            .as_ref()
+            .is_none_or(|region| machine["region"] == region.as_str())
+        && filter
+            .state
+            .as_ref()
+            .is_none_or(|state| machine["state"] == state.as_str())
+        && filter.name_prefix.as_ref().is_none_or(|prefix| {
+            machine["name"]
+                .as_str()
+                .is_some_and(|name| name.starts_with(prefix.as_str()))
+        })
+        && filter
+            .metadata
+            .iter()
+            .all(|(key, value)| machine["config"]["metadata"][key] == value.as_str())
+}
+
+/// Runs the action on one machine, draining it first.
+async fn act<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    body: &BulkMachinesRequest,
+    config: &Config,
+    events: &EventLog,
+    machine_id: &str,
+    cordoned: bool,
+) -> Result<(), String> {
+    let app = &body.app_name;
+    let drainer = req.app_data::<web::Data<Drainer>>();
+    match body.action {
+        BulkAction::Stop => {
+            drain::drain(drainer, backend, app, machine_id).await;
+            backend
+                .stop_machine(app, machine_id)
+                .await
+                .map_err(|e| e.to_string())
+        }
+        BulkAction::Restart => {
+            drain::drain(drainer, backend, app, machine_id).await;
+            backend
+                .restart_machine(app, machine_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            // A machine cordoned on purpose stays out of rotation.
+            if !cordoned {
+                drain::restore(drainer, backend, app, machine_id).await;
+            }
+            Ok(())
+        }
+        BulkAction::Destroy => {
+            let consequences = match impact::analyze(backend, app, machine_id).await? {
+                Some(impact) => {
+                    let unacknowledged = impact::unacknowledged(&impact, &body.acknowledge);
+                    if config.machines.require_impact_ack && !unacknowledged.is_empty() {
+                        return Err(format!(
+                            "Acknowledge {} to destroy",
+                            unacknowledged.join(", ")
+                        ));
+                    }
+                    impact.consequences
+                }
+                None => return Err("No such machine".to_string()),
+            };
+            drain::drain(drainer, backend, app, machine_id).await;
+            backend
+                .destroy_machine(app, machine_id, None)
+                .await
+                .map_err(|e| e.to_string())?;
+            events.record(
+                "machine.destroyed",
+                Some(app),
+                Some(machine_id),
+                json!({
+                    "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+                    "acknowledged": body.acknowledge,
+                    "consequences": consequences,
+                    "bulk": true,
+                }),
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn bulk<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    body: &BulkMachinesRequest,
+    config: &Config,
+    events: &EventLog,
+) -> HttpResponse {
+    let machines: Vec<Value> = match backend.list_machines(&body.app_name).await {
+        Ok(machines) => machines
+            .into_iter()
+            .filter(|machine| matches(&body.filter, machine))
+            .filter(|machine| body.include_cordoned || !cordon::is_cordoned(machine))
+            .collect(),
+        Err(e) => {
+            return AppError::internal(format!("Failed to list machines: {}", e)).into_response();
+        }
+    };
+
+    // Each destroy's impact is judged against the machines left, so with acknowledgements
+    // required they go one at a time.
+    let limit = if body.action == BulkAction::Destroy && config.machines.require_impact_ack {
+        None
+    } else {
+        req.app_data::<web::Data<AdaptiveLimit>>()
+            .map(|limit| limit.get_ref())
+    };
+    let results = concurrency::map(
+        limit,
+        machines.iter().map(|machine| async move {
+            let machine_id = machine["id"].as_str().unwrap_or_default();
+            let error = if body.dry_run {
+                None
+            } else {
+                let cordoned = cordon::is_cordoned(machine);
+                act(req, backend, body, config, events, machine_id, cordoned)
+                    .await
+                    .err()
+            };
+            BulkMachineResult {
+                machine_id: machine_id.to_string(),
+                name: machine["name"].as_str().map(str::to_string),
+                region: machine["region"].as_str().map(str::to_string),
+                error,
+            }
+        }),
+    )
+    .await;
+
+    HttpResponse::Ok().json(BulkMachinesReport {
+        action: body.action,
+        dry_run: body.dry_run,
+        matched: results.len(),
+        failed: results
+            .iter()
+            .filter(|result| result.error.is_some())
+            .count(),
+        machines: results,
+    })
+}
+
+/// Stops, restarts or destroys every machine of the app matching the filter, as many at a
+/// time as the `[concurrency]` limit allows. The filter can't be empty: naming every
+/// machine takes `name_prefix = ""`. Cordoned machines are skipped unless
+/// `include_cordoned` is set.
+#[post("/v0/machines/bulk")]
+async fn bulk_machines(
+    req: HttpRequest,
+    body: web::Json<BulkMachinesRequest>,
+    config: web::Data<Config>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let filter = &body.filter;
+    if filter.region.is_none()
+        && filter.state.is_none()
+        && filter.name_prefix.is_none()
+        && filter.metadata.is_empty()
+    {
+        return AppError::bad_request("A bulk action needs a filter").into_response();
+    }
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return bulk(&req, docker.get_ref(), &body, &config, &events).await;
+    }
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_limit(
+            req.app_data::<web::Data<AdaptiveLimit>>()
+                .map(|limit| limit.clone().into_inner()),
+        );
+    bulk(&req, &client, &body, &config, &events).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(batch_new).service(bulk_machines);
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::BreakerConfig;
+use crate::metrics;
+
+#[derive(Default)]
+struct Host {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// When the one call let through after `open_secs` went out, while it's in flight.
+    probing: Option<Instant>,
+    opened: u64,
+}
+
+/// Stops sending to a Machines API host after `failures` calls to it in a row failed,
+/// answering 503 for `open_secs` instead. After that one call is let through to see
+/// whether it's back; the rest keep failing fast until it answers.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    hosts: Mutex<HashMap<String, Host>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `Err` with how long until the host may be tried again while the circuit is open.
+    pub fn admit(&self, host: &str) -> Result<(), Duration> {
+        if self.config.failures == 0 {
+            return Ok(());
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(state) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < open_until {
+            return Err(open_until - now);
+        }
+        // A probe that never reported back, e.g. because its caller went away, doesn't
+        // keep the circuit shut for good.
+        let open_for = Duration::from_secs(self.config.open_secs);
+        if let Some(at) = state.probing
+            && now.duration_since(at) < open_for
+        {
+            return Err(open_for - now.duration_since(at));
+        }
+        state.probing = Some(now);
+        Ok(())
+    }
+
+    pub fn record(&self, host: &str, failed: bool) {
+        if self.config.failures == 0 {
+            return;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        let probe = state.probing.take().is_some();
+        if !failed {
+            if state.open_until.take().is_some() {
+                log::info!("Closed the circuit to {}", host);
+            }
+            state.failures = 0;
+            return;
+        }
+        state.failures += 1;
+        if probe || (state.open_until.is_none() && state.failures >= self.config.failures) {
+            state.open_until = Some(Instant::now() + Duration::from_secs(self.config.open_secs));
+            state.opened += 1;
+            log::warn!(
+                "Opened the circuit to {} for {}s after {} failures in a row",
+                host,
+                self.config.open_secs,
+                state.failures
+            );
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        let hosts = self.hosts.lock().unwrap();
+        let now = Instant::now();
+        metrics::header(
+            out,
+            "flyd_upstream_circuit_open",
+            "gauge",
+            "1 while calls to the upstream host fail fast.",
+        );
+        for (host, state) in hosts.iter() {
+            let open = state.open_until.is_some_and(|until| now < until);
+            metrics::sample(
+                out,
+                "flyd_upstream_circuit_open",
+                &[("host", host.clone())],
+                if open { 1.0 } else { 0.0 },
+            );
+        }
+        metrics::header(
+            out,
+            "flyd_upstream_circuit_opened_total",
+            "counter",
+            "Times the circuit to the upstream host opened.",
+        );
+        for (host, state) in hosts.iter() {
+            metrics::sample(
+                out,
+                "flyd_upstream_circuit_opened_total",
+                &[("host", host.clone())],
+                state.opened as f64,
+            );
+        }
+    }
+}
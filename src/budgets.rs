@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::{HttpResponse, Responder, get, web};
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use flyd::models::BudgetStatus;
+use serde_json::{Value, json};
+
+use crate::backend::{Backend, resolve_apps};
+use crate::config::{BudgetConfig, BudgetsConfig, Config};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::pricing::{machine_monthly_cost, volume_monthly_cost};
+use crate::store::Store;
+
+const BUDGETS: &str = "budgets";
+const PROJECTED_OVERSPEND: &str = "budget.projected_overspend";
+
+/// The start of `at`'s month and of the next.
+fn month_bounds(at: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let (year, month) = (at.year(), at.month());
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    (
+        Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap(),
+        Utc.with_ymd_and_hms(next_year, next_month, 1, 0, 0, 0)
+            .unwrap(),
+    )
+}
+
+/// How much of the month lies between `from` and `to`.
+fn fraction(
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    (start, end): (DateTime<Utc>, DateTime<Utc>),
+) -> f64 {
+    (to - from).num_seconds().max(0) as f64 / (end - start).num_seconds() as f64
+}
+
+/// What the app's started machines and its volumes cost a month.
+async fn run_rate<B: Backend>(backend: &B, app: &str) -> Result<f64, String> {
+    let machines = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines of {}: {}", app, e))?;
+    let volumes = backend
+        .list_volumes(app)
+        .await
+        .map_err(|e| format!("Failed to list volumes of {}: {}", app, e))?;
+    Ok(machines
+        .iter()
+        .filter(|machine| machine["state"] == "started")
+        .map(|machine| machine_monthly_cost(&machine["config"]["guest"]))
+        .sum::<f64>()
+        + volumes
+            .iter()
+            .map(|volume| volume_monthly_cost(volume["size_gb"].as_u64().unwrap_or_default()))
+            .sum::<f64>())
+}
+
+pub struct Budgets {
+    config: BudgetsConfig,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+}
+
+impl Budgets {
+    pub fn from_config(
+        config: &Config,
+        store: web::Data<Store>,
+        events: web::Data<EventLog>,
+    ) -> Option<Self> {
+        Some(Budgets {
+            config: config.budgets.clone()?,
+            store,
+            events,
+        })
+    }
+
+    /// Adds what was spent since the last look, at the run rate then, and projects the
+    /// rest of the month at the run rate now. A budget first looked at partway through
+    /// the month is taken to have run at today's rate since the month began.
+    async fn observe(
+        &self,
+        scope: String,
+        apps: Vec<String>,
+        budget: &BudgetConfig,
+        run_rate: f64,
+    ) -> Result<(), String> {
+        let now = Utc::now();
+        let bounds = month_bounds(now);
+        let month = now.format("%Y-%m").to_string();
+        let previous = self
+            .store
+            .get::<BudgetStatus>(BUDGETS, &scope)
+            .await
+            .map_err(|e| e.to_string())?
+            .filter(|previous| previous.month == month);
+        let (spent, alerted_at) = match previous {
+            Some(previous) => (
+                previous.spent + previous.run_rate * fraction(previous.updated_at, now, bounds),
+                previous.alerted_at,
+            ),
+            None => (run_rate * fraction(bounds.0, now, bounds), None),
+        };
+        let mut status = BudgetStatus {
+            projected: spent + run_rate * fraction(now, bounds.1, bounds),
+            budget: budget.monthly,
+            hard_cap: budget.hard_cap,
+            updated_at: now,
+            scope,
+            apps,
+            month,
+            spent,
+            run_rate,
+            alerted_at,
+        };
+        // Once a month, so a projection hovering around the budget doesn't page on every
+        // look.
+        if status.projected > status.budget && status.alerted_at.is_none() {
+            status.alerted_at = Some(now);
+            self.events.record(
+                PROJECTED_OVERSPEND,
+                status.scope.strip_prefix("app:"),
+                None,
+                json!({
+                    "scope": status.scope,
+                    "budget": status.budget,
+                    "projected": status.projected,
+                    "spent": status.spent,
+                    "run_rate": status.run_rate,
+                }),
+            );
+        }
+        self.store
+            .put(BUDGETS, &status.scope, &status)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Refuses a new machine of `app` that, started for the rest of the month, would take
+    /// the projected spend of a budget covering the app over its hard cap.
+    pub async fn check(&self, app: &str, config: &Value) -> Result<(), String> {
+        let now = Utc::now();
+        let bounds = month_bounds(now);
+        let month = now.format("%Y-%m").to_string();
+        let cost = machine_monthly_cost(&config["guest"]) * fraction(now, bounds.1, bounds);
+        let statuses = match self.store.list::<BudgetStatus>(BUDGETS).await {
+            Ok(statuses) => statuses,
+            Err(e) => {
+                log::warn!("Failed to load budgets to check {} against: {}", app, e);
+                return Ok(());
+            }
+        };
+        for status in statuses
+            .iter()
+            .filter(|status| status.month == month && status.apps.iter().any(|a| a == app))
+        {
+            if let Some(hard_cap) = status.hard_cap
+                && status.projected + cost > hard_cap
+            {
+                return Err(format!(
+                    "Another machine of {} would take {}'s projected spend this month to ${:.2}, over its hard cap of ${:.2}",
+                    app,
+                    status.scope,
+                    status.projected + cost,
+                    hard_cap
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sums the run rates of `apps`, looking each app up once per pass however many budgets
+/// cover it.
+async fn total_run_rate<B: Backend>(
+    backend: &B,
+    rates: &mut HashMap<String, f64>,
+    apps: &[String],
+) -> Result<f64, String> {
+    let mut total = 0.0;
+    for app in apps {
+        let rate = match rates.get(app) {
+            Some(rate) => *rate,
+            None => {
+                let rate = run_rate(backend, app).await?;
+                rates.insert(app.clone(), rate);
+                rate
+            }
+        };
+        total += rate;
+    }
+    Ok(total)
+}
+
+pub async fn run<B: Backend>(budgets: web::Data<Budgets>, backend: B) {
+    let config = &budgets.config;
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        let mut rates = HashMap::new();
+        let mut scopes = Vec::new();
+        for (app, budget) in &config.apps {
+            scopes.push((format!("app:{}", app), vec![app.clone()], budget));
+        }
+        for (org, budget) in &config.orgs {
+            match resolve_apps(&backend, &[], Some(org)).await {
+                Ok(apps) => scopes.push((format!("org:{}", org), apps, budget)),
+                Err(e) => log::error!("Budget of {}: failed to list its apps: {}", org, e),
+            }
+        }
+        for (scope, apps, budget) in scopes {
+            let observed = match total_run_rate(&backend, &mut rates, &apps).await {
+                Ok(rate) => budgets.observe(scope.clone(), apps, budget, rate).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = observed {
+                log::error!("Budget of {}: {}", scope, e);
+            }
+        }
+    }
+}
+
+async fn statuses(store: &Store) -> Result<Vec<BudgetStatus>, HttpResponse> {
+    let mut statuses = store
+        .list::<BudgetStatus>(BUDGETS)
+        .await
+        .map_err(|e| AppError::internal(e.to_string()).into_response())?;
+    statuses.sort_by(|a, b| a.scope.cmp(&b.scope));
+    Ok(statuses)
+}
+
+/// Every budget's spend and projection, as of its last look.
+#[get("/v0/budgets")]
+async fn list_budgets(store: web::Data<Store>) -> impl Responder {
+    match statuses(&store).await {
+        Ok(statuses) => HttpResponse::Ok().json(statuses),
+        Err(response) => response,
+    }
+}
+
+/// The budgets covering the app: its own and its org's.
+#[get("/v0/apps/{app}/budgets")]
+async fn get_app_budgets(app: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    match statuses(&store).await {
+        Ok(statuses) => HttpResponse::Ok().json(
+            statuses
+                .into_iter()
+                .filter(|status| status.apps.contains(&app))
+                .collect::<Vec<_>>(),
+        ),
+        Err(response) => response,
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_budgets).service(get_app_budgets);
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{AGE, AUTHORIZATION, CACHE_CONTROL};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+
+use crate::auth::token_hash;
+use crate::metrics;
+
+/// Whether a cacheable request was served from the cache.
+#[derive(Clone, Copy)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+struct Entry {
+    app: String,
+    stored_at: Instant,
+    value: serde_json::Value,
+}
+
+/// Short-lived cache of upstream GET responses. Entries are keyed by a hash of the
+/// caller's token, so one caller can never be served another caller's data, and dropped
+/// for everyone as soon as flyd changes anything in their app.
+pub struct ResponseCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        ResponseCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn key(req: &HttpRequest, app: &str, upstream_url: &str) -> String {
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .map(|value| value.as_bytes())
+            .unwrap_or_default();
+        format!("{}|{}|{}", token_hash(token), app, upstream_url)
+    }
+
+    pub fn client_bypasses(req: &HttpRequest) -> bool {
+        req.headers()
+            .get(CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value.split(',').map(str::trim).any(|directive| {
+                    directive.eq_ignore_ascii_case("no-cache")
+                        || directive.eq_ignore_ascii_case("no-store")
+                        || directive.eq_ignore_ascii_case("max-age=0")
+                })
+            })
+    }
+
+    /// The cached value and its age, unless caching is disabled, the entry is stale, or the
+    /// client asked to revalidate with `Cache-Control: no-cache`.
+    pub fn lookup_value(
+        &self,
+        req: &HttpRequest,
+        key: &str,
+    ) -> Option<(serde_json::Value, Duration)> {
+        let cached = self.fresh(req, key);
+        let (status, counter) = if cached.is_some() {
+            (CacheStatus::Hit, &self.hits)
+        } else {
+            (CacheStatus::Miss, &self.misses)
+        };
+        req.extensions_mut().insert(status);
+        if !self.ttl.is_zero() {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        cached
+    }
+
+    fn fresh(&self, req: &HttpRequest, key: &str) -> Option<(serde_json::Value, Duration)> {
+        if self.ttl.is_zero() || Self::client_bypasses(req) {
+            return None;
+        }
+
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        let age = entry.stored_at.elapsed();
+        if age >= self.ttl {
+            return None;
+        }
+
+        Some((entry.value.clone(), age))
+    }
+
+    pub fn insert(&self, key: String, app: &str, value: serde_json::Value) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.stored_at.elapsed() < self.ttl);
+        entries.insert(
+            key,
+            Entry {
+                app: app.to_string(),
+                stored_at: Instant::now(),
+                value,
+            },
+        );
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        metrics::header(
+            out,
+            "flyd_cache_lookups_total",
+            "counter",
+            "Cacheable GETs, by whether the cache answered them.",
+        );
+        for (result, counter) in [("hit", &self.hits), ("miss", &self.misses)] {
+            metrics::sample(
+                out,
+                "flyd_cache_lookups_total",
+                &[("result", result.to_string())],
+                counter.load(Ordering::Relaxed) as f64,
+            );
+        }
+    }
+
+    /// Drops every caller's entries for the app.
+    pub fn invalidate(&self, app: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.app != app);
+    }
+
+    pub fn respond(&self, value: &serde_json::Value, age: Duration) -> HttpResponse {
+        if self.ttl.is_zero() {
+            return HttpResponse::Ok()
+                .insert_header((CACHE_CONTROL, "private, no-cache"))
+                .json(value);
+        }
+
+        HttpResponse::Ok()
+            .insert_header((
+                CACHE_CONTROL,
+                format!(
+                    "private, max-age={}",
+                    self.ttl.saturating_sub(age).as_secs()
+                ),
+            ))
+            .insert_header((AGE, age.as_secs().to_string()))
+            .json(value)
+    }
+}
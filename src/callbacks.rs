@@ -0,0 +1,181 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use chrono::Utc;
+use flyd::models::MachineStateCallback;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::events::EventLog;
+
+pub const SIGNATURE_HEADER: &str = "x-flyd-signature";
+pub const TIMESTAMP_HEADER: &str = "x-flyd-timestamp";
+
+pub const MACHINE_STATE: &str = "machine.state";
+
+/// Last known state per machine, shared by the callback receiver and the poller so one
+/// doesn't re-announce what the other already reported.
+#[derive(Default)]
+pub struct MachineStates {
+    states: Mutex<HashMap<(String, String), String>>,
+}
+
+impl MachineStates {
+    /// Remembers `state` and returns the state it replaced, if there was one.
+    fn observe(&self, app: &str, machine_id: &str, state: &str) -> Option<String> {
+        self.states
+            .lock()
+            .unwrap()
+            .insert((app.to_string(), machine_id.to_string()), state.to_string())
+    }
+
+    /// Machine counts by state, per app.
+    pub fn counts(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        let mut counts: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+        for ((app, _), state) in self.states.lock().unwrap().iter() {
+            *counts
+                .entry(app.clone())
+                .or_default()
+                .entry(state.clone())
+                .or_default() += 1;
+        }
+        counts
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name)?.to_str().ok()
+}
+
+/// The `X-Flyd-Signature` of a body sent at `timestamp`.
+pub fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Checks `X-Flyd-Signature: sha256=<hex HMAC of "{timestamp}.{body}">`, and that the
+/// timestamp is recent enough not to be a replay.
+fn verify(req: &HttpRequest, body: &[u8], secret: &str, max_age_secs: i64) -> Result<(), String> {
+    let timestamp = header(req, TIMESTAMP_HEADER).ok_or("Missing X-Flyd-Timestamp")?;
+    let signature = header(req, SIGNATURE_HEADER)
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .and_then(|signature| hex::decode(signature).ok())
+        .ok_or("Missing or malformed X-Flyd-Signature")?;
+
+    let age = timestamp
+        .parse::<i64>()
+        .map(|timestamp| Utc::now().timestamp() - timestamp)
+        .map_err(|_| "Invalid X-Flyd-Timestamp")?;
+    if age.abs() > max_age_secs {
+        return Err("Callback timestamp is too old".to_string());
+    }
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| "Invalid callback secret")?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| "Signature mismatch".to_string())
+}
+
+#[post("/v0/callbacks/machine_state")]
+async fn machine_state(
+    req: HttpRequest,
+    body: web::Bytes,
+    config: web::Data<Config>,
+    events: web::Data<EventLog>,
+    states: web::Data<MachineStates>,
+) -> impl Responder {
+    let Some(secret) = &config.callbacks.secret else {
+        return AppError::not_found("Machine state callbacks are not enabled").into_response();
+    };
+    if let Err(e) = verify(&req, &body, secret, config.callbacks.max_age_secs) {
+        return AppError::unauthorized(e).into_response();
+    }
+
+    let callback: MachineStateCallback = match serde_json::from_slice(&body) {
+        Ok(callback) => callback,
+        Err(e) => return AppError::bad_request(format!("Invalid callback: {}", e)).into_response(),
+    };
+
+    let previous = states
+        .observe(&callback.app, &callback.machine_id, &callback.state)
+        .or(callback.previous_state);
+    if previous.as_deref() != Some(callback.state.as_str()) {
+        events.record(
+            MACHINE_STATE,
+            Some(&callback.app),
+            Some(&callback.machine_id),
+            json!({ "state": callback.state, "previous_state": previous, "source": "callback" }),
+        );
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(machine_state);
+}
+
+/// Records a `machine.state` event for each of the app's machines whose state changed
+/// since it was last seen. The first sighting of a machine only establishes its state.
+pub async fn poll_app<B: Backend>(
+    backend: &B,
+    events: &EventLog,
+    states: &MachineStates,
+    app: &str,
+) -> Result<(), String> {
+    let machines = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| e.to_string())?;
+    for machine in machines {
+        let (Some(id), Some(state)) = (machine["id"].as_str(), machine["state"].as_str()) else {
+            continue;
+        };
+        match states.observe(app, id, state) {
+            Some(previous) if previous != state => {
+                events.record(
+                    MACHINE_STATE,
+                    Some(app),
+                    Some(id),
+                    json!({ "state": state, "previous_state": previous, "source": "poll" }),
+                );
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Polling fallback for `poll_apps`: announces the same `machine.state` events as the
+/// callback receiver.
+pub async fn poll_loop<B: Backend>(
+    backend: B,
+    events: web::Data<EventLog>,
+    states: web::Data<MachineStates>,
+    apps: Vec<String>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        for app in &apps {
+            if let Err(e) = poll_app(&backend, &events, &states, app).await {
+                log::error!("Failed to poll machine states for {}: {}", app, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, web};
+use chrono::{TimeDelta, Utc};
+use flyd::models::{CapacityRejection, RegionBlock};
+use serde_json::{Value, json};
+
+use crate::auth;
+use crate::config::CapacityConfig;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::store::{Store, StoreError};
+
+const CAPACITY_FALLBACK: &str = "machine.capacity_fallback";
+const REGION_BLOCKS: &str = "region_blocks";
+
+/// Whether Fly refused a machine for lack of room in the region, rather than anything
+/// wrong with the request.
+pub fn is_capacity_error(status: reqwest::StatusCode, body: &str) -> bool {
+    let body = body.to_lowercase();
+    (status.is_client_error() || status.is_server_error())
+        && (body.contains("insufficient") || body.contains("capacity"))
+}
+
+/// The size of the machine a create request asks for, e.g. `shared-1x-256mb`.
+pub fn size(body: &Value) -> String {
+    let guest = &body["config"]["guest"];
+    match (guest["cpu_kind"].as_str(), guest["cpus"].as_u64()) {
+        (Some(kind), Some(cpus)) => format!(
+            "{}-{}x-{}mb",
+            kind,
+            cpus,
+            guest["memory_mb"].as_u64().unwrap_or_default()
+        ),
+        _ => body["config"]["size"]
+            .as_str()
+            .unwrap_or("default")
+            .to_string(),
+    }
+}
+
+fn block_key(app: &str, region: &str) -> String {
+    format!("{}/{}", app, region)
+}
+
+/// Which regions recently turned down which machine sizes, which regions apps may not
+/// use, and where to place machines instead.
+pub struct CapacityMap {
+    config: CapacityConfig,
+    events: web::Data<EventLog>,
+    store: web::Data<Store>,
+    rejections: Mutex<HashMap<(String, String), CapacityRejection>>,
+}
+
+impl CapacityMap {
+    pub fn new(
+        config: CapacityConfig,
+        events: web::Data<EventLog>,
+        store: web::Data<Store>,
+    ) -> Self {
+        CapacityMap {
+            config,
+            events,
+            store,
+            rejections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn fallbacks(&self, region: &str) -> Vec<String> {
+        self.config
+            .fallback_regions
+            .get(region)
+            .or_else(|| self.config.fallback_regions.get("*"))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn blocks(&self, app: &str) -> Result<Vec<RegionBlock>, StoreError> {
+        let blocks = self.store.list::<RegionBlock>(REGION_BLOCKS).await?;
+        Ok(blocks
+            .into_iter()
+            .filter(|block| block.app == app)
+            .collect())
+    }
+
+    pub async fn is_blocked(&self, app: &str, region: &str) -> bool {
+        self.store
+            .get::<RegionBlock>(REGION_BLOCKS, &block_key(app, region))
+            .await
+            .is_ok_and(|block| block.is_some())
+    }
+
+    pub async fn block(&self, block: &RegionBlock) -> Result<(), StoreError> {
+        self.store
+            .put(REGION_BLOCKS, &block_key(&block.app, &block.region), block)
+            .await?;
+        self.events.record(
+            "region.blocked",
+            Some(&block.app),
+            None,
+            json!({ "region": block.region, "reason": block.reason, "blocked_by": block.blocked_by }),
+        );
+        Ok(())
+    }
+
+    fn prune(&self, rejections: &mut HashMap<(String, String), CapacityRejection>) {
+        let ttl = TimeDelta::seconds(self.config.rejection_ttl_secs as i64);
+        let cutoff = Utc::now() - ttl;
+        rejections.retain(|_, rejection| rejection.last_rejected_at > cutoff);
+    }
+
+    /// The requested region, then its fallbacks in policy order, leaving out regions
+    /// blocked for `app`. Fallbacks known to be rejecting `size` go last, as they may have
+    /// capacity again.
+    pub async fn regions(
+        &self,
+        app: &str,
+        requested: Option<&str>,
+        size: &str,
+    ) -> Vec<Option<String>> {
+        let mut fallbacks = Vec::new();
+        for region in self.fallbacks(requested.unwrap_or("*")) {
+            if Some(region.as_str()) != requested && !self.is_blocked(app, &region).await {
+                fallbacks.push(region);
+            }
+        }
+        let mut rejections = self.rejections.lock().unwrap();
+        self.prune(&mut rejections);
+
+        // Stable, so the policy order holds within each half.
+        fallbacks
+            .sort_by_key(|region| rejections.contains_key(&(region.clone(), size.to_string())));
+
+        let mut regions = vec![requested.map(str::to_string)];
+        regions.extend(fallbacks.into_iter().map(Some));
+        regions
+    }
+
+    /// Whether the region recently turned down a machine of any size.
+    pub fn is_rejecting(&self, region: &str) -> bool {
+        let mut rejections = self.rejections.lock().unwrap();
+        self.prune(&mut rejections);
+        rejections.keys().any(|(rejecting, _)| rejecting == region)
+    }
+
+    pub fn reject(&self, region: &str, size: &str, error: &str) {
+        let now = Utc::now();
+        let mut rejections = self.rejections.lock().unwrap();
+        let rejection = rejections
+            .entry((region.to_string(), size.to_string()))
+            .or_insert_with(|| CapacityRejection {
+                region: region.to_string(),
+                size: size.to_string(),
+                rejecting_since: now,
+                last_rejected_at: now,
+                rejections: 0,
+                last_error: String::new(),
+            });
+        rejection.last_rejected_at = now;
+        rejection.rejections += 1;
+        rejection.last_error = error.to_string();
+    }
+
+    /// Clears the region's record for `size`, and records the fallback if the machine
+    /// landed somewhere other than requested.
+    pub fn placed(&self, app: &str, requested: Option<&str>, region: Option<&str>, size: &str) {
+        if let Some(region) = region {
+            self.rejections
+                .lock()
+                .unwrap()
+                .remove(&(region.to_string(), size.to_string()));
+        }
+        if region != requested {
+            self.events.record(
+                CAPACITY_FALLBACK,
+                Some(app),
+                None,
+                json!({ "requested": requested, "placed": region, "size": size }),
+            );
+        }
+    }
+
+    fn admin(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(role) = &self.config.admin_role else {
+            return Err(
+                AppError::forbidden("Lifting region blocks needs capacity.admin_role set")
+                    .into_response(),
+            );
+        };
+        auth::require_role(req, Some(role)).map(|_| ())
+    }
+}
+
+#[get("/v0/apps/{app}/region-blocks")]
+async fn list_region_blocks(
+    path: web::Path<String>,
+    capacity: web::Data<CapacityMap>,
+) -> impl Responder {
+    match capacity.blocks(&path).await {
+        Ok(blocks) => HttpResponse::Ok().json(blocks),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+/// Lifts a block, letting machines be placed in the region again.
+#[delete("/v0/apps/{app}/region-blocks/{region}")]
+async fn lift_region_block(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    capacity: web::Data<CapacityMap>,
+) -> impl Responder {
+    if let Err(response) = capacity.admin(&req) {
+        return response;
+    }
+    let (app, region) = path.into_inner();
+    match capacity
+        .store
+        .delete(REGION_BLOCKS, &block_key(&app, &region))
+        .await
+    {
+        Ok(true) => {
+            capacity.events.record(
+                "region.unblocked",
+                Some(&app),
+                None,
+                json!({ "region": region }),
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => {
+            AppError::not_found(format!("{} isn't blocked for {}", region, app)).into_response()
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/capacity")]
+async fn get_capacity(capacity: web::Data<CapacityMap>) -> impl Responder {
+    let mut rejections = capacity.rejections.lock().unwrap();
+    capacity.prune(&mut rejections);
+    let mut listed: Vec<CapacityRejection> = rejections.values().cloned().collect();
+    listed.sort_by(|a, b| (&a.region, &a.size).cmp(&(&b.region, &b.size)));
+    HttpResponse::Ok().json(listed)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_capacity)
+        .service(list_region_blocks)
+        .service(lift_region_block);
+}
@@ -0,0 +1,656 @@
+use reqwest::StatusCode;
+use reqwest::header::AUTHORIZATION;
+use serde::de::DeserializeOwned;
+
+use crate::fly::authorization_value;
+use crate::models::{
+    AcquireLeaseRequest, AppRequest, AuditQuery, AuditRecord, BatchDryRun, BatchNewMachinesReport,
+    BatchNewMachinesRequest, BulkMachinesReport, BulkMachinesRequest, BulkMetadataReport,
+    BulkMetadataRequest, CheckMachineQuery, CloneAppReport, CloneAppRequest, CloneMachineRequest,
+    DeleteMachineMetadataRequest, DryRunQuery, ErrorBody, ErrorDetail, Event, EventsQuery,
+    ExportMachineQuery, ExtendVolumeRequest, FleetDrift, FleetQuery, FleetReport, FleetSpec,
+    ImportMachineReport, ImportMachineRequest, ListAllMachinesRequest, ListAppsRequest,
+    ListMachinesRequest, ListSecretsRequest, ListVolumesRequest, MachineBundle, MachineDryRun,
+    MachineRequest, MachineTemplate, MultiAppMachines, NewAppRequest, NewMachineRequest,
+    NewVolumeRequest, PingQuery, PingReport, ReleaseLeaseRequest, SavedView, SecretDrift,
+    ServiceCheckReport, SetMachineMetadataRequest, SetSecretsRequest, SloStatus, TemplateQuery,
+    UnsetSecretRequest, UpdateMachineRequest, UsageQuery, UsageRecord, VersionInfo, VolumeRequest,
+    WaitMachineQuery,
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Request(reqwest::Error),
+    Status { status: StatusCode, body: String },
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Request(e) => write!(f, "flyd request failed: {}", e),
+            ClientError::Status { status, body } => write!(f, "flyd returned {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl ClientError {
+    /// flyd's `{ "error": ... }` body, for statuses that came with one.
+    pub fn detail(&self) -> Option<ErrorDetail> {
+        match self {
+            ClientError::Status { body, .. } => serde_json::from_str::<ErrorBody>(body)
+                .ok()
+                .map(|body| body.error),
+            ClientError::Request(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Request(e)
+    }
+}
+
+/// Typed client for flyd's own `/v0` endpoints, built on the same models the server uses.
+#[derive(Clone)]
+pub struct FlydClient {
+    http: reqwest::Client,
+    base_url: String,
+    authorization: String,
+}
+
+impl FlydClient {
+    pub fn new(base_url: impl Into<String>, fly_token: &str) -> Self {
+        FlydClient {
+            http: reqwest::Client::default(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            authorization: authorization_value(fly_token),
+        }
+    }
+
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, ClientError> {
+        let response = request
+            .header(AUTHORIZATION, &self.authorization)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(ClientError::Status { status, body });
+        }
+        Ok(response)
+    }
+
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, ClientError> {
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    pub async fn create_machine(
+        &self,
+        request: &NewMachineRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/machines/new")).json(request))
+            .await
+    }
+
+    /// What creating the machine would send Fly and cost, without creating it.
+    pub async fn dry_run_machine(
+        &self,
+        request: &NewMachineRequest,
+    ) -> Result<MachineDryRun, ClientError> {
+        self.send_json(
+            self.http
+                .post(self.url("/v0/machines/new"))
+                .query(&DryRunQuery { dry_run: true })
+                .json(request),
+        )
+        .await
+    }
+
+    /// Creates a machine from the named template, with `request` overriding it.
+    pub async fn create_machine_from_template(
+        &self,
+        template: &str,
+        request: &NewMachineRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(
+            self.http
+                .post(self.url("/v0/machines/new"))
+                .query(&TemplateQuery {
+                    template: Some(template.to_string()),
+                })
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn list_machines(
+        &self,
+        request: &ListMachinesRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/machines/list")).query(request))
+            .await
+    }
+
+    pub async fn list_all_machines(
+        &self,
+        request: &ListAllMachinesRequest,
+    ) -> Result<MultiAppMachines, ClientError> {
+        self.send_json(
+            self.http
+                .get(self.url("/v0/machines/list_all"))
+                .query(request),
+        )
+        .await
+    }
+
+    pub async fn get_machine(
+        &self,
+        request: &MachineRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/machines/get")).query(request))
+            .await
+    }
+
+    /// Blocks until the machine reaches `request.state`; fails with 408 once
+    /// `request.timeout` is up.
+    pub async fn wait_machine(
+        &self,
+        request: &WaitMachineQuery,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/machines/wait")).query(request))
+            .await
+    }
+
+    pub async fn get_machine_metadata(
+        &self,
+        request: &MachineRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(
+            self.http
+                .get(self.url("/v0/machines/metadata"))
+                .query(request),
+        )
+        .await
+    }
+
+    pub async fn set_machine_metadata(
+        &self,
+        request: &SetMachineMetadataRequest,
+    ) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .post(self.url("/v0/machines/metadata"))
+                .json(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_machine_metadata(
+        &self,
+        request: &DeleteMachineMetadataRequest,
+    ) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .delete(self.url("/v0/machines/metadata"))
+                .query(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn cordon_machine(&self, request: &MachineRequest) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .post(self.url("/v0/machines/cordon"))
+                .json(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn uncordon_machine(&self, request: &MachineRequest) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .post(self.url("/v0/machines/uncordon"))
+                .json(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn bulk_machine_metadata(
+        &self,
+        request: &BulkMetadataRequest,
+    ) -> Result<BulkMetadataReport, ClientError> {
+        self.send_json(
+            self.http
+                .post(self.url("/v0/machines/metadata/bulk"))
+                .json(request),
+        )
+        .await
+    }
+
+    /// Fly's lease response; its `data.nonce` goes in `fly-machine-lease-nonce` on
+    /// updates while the lease is held.
+    pub async fn acquire_lease(
+        &self,
+        request: &AcquireLeaseRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(
+            self.http
+                .post(self.url("/v0/machines/lease/acquire"))
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn release_lease(&self, request: &ReleaseLeaseRequest) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .post(self.url("/v0/machines/lease/release"))
+                .json(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// `if_match` is the machine's `instance_id` as returned by `get_machine`; flyd
+    /// rejects the update with 412 if the machine changed since.
+    pub async fn update_machine(
+        &self,
+        request: &UpdateMachineRequest,
+        if_match: Option<&str>,
+    ) -> Result<serde_json::Value, ClientError> {
+        let mut builder = self
+            .http
+            .post(self.url("/v0/machines/update"))
+            .json(request);
+        if let Some(version) = if_match {
+            builder = builder.header(reqwest::header::IF_MATCH, format!("\"{}\"", version));
+        }
+        self.send_json(builder).await
+    }
+
+    /// Applies `request.config` as a JSON Merge Patch to the machine's current config: only
+    /// the fields sent change, and `null` removes a field.
+    pub async fn patch_machine(
+        &self,
+        request: &UpdateMachineRequest,
+        if_match: Option<&str>,
+    ) -> Result<serde_json::Value, ClientError> {
+        let mut builder = self
+            .http
+            .patch(self.url("/v0/machines/update"))
+            .json(request);
+        if let Some(version) = if_match {
+            builder = builder.header(reqwest::header::IF_MATCH, format!("\"{}\"", version));
+        }
+        self.send_json(builder).await
+    }
+
+    /// Applies a JSON Patch (RFC 6902) to the machine's current config, e.g.
+    /// `[{"op": "add", "path": "/env/LOG_LEVEL", "value": "debug"}]`.
+    pub async fn json_patch_machine(
+        &self,
+        request: &MachineRequest,
+        patch: &serde_json::Value,
+        if_match: Option<&str>,
+    ) -> Result<serde_json::Value, ClientError> {
+        let mut builder = self
+            .http
+            .patch(self.url("/v0/machines/update"))
+            .query(request)
+            .header(reqwest::header::CONTENT_TYPE, "application/json-patch+json")
+            .body(patch.to_string());
+        if let Some(version) = if_match {
+            builder = builder.header(reqwest::header::IF_MATCH, format!("\"{}\"", version));
+        }
+        self.send_json(builder).await
+    }
+
+    pub async fn ping_machine(
+        &self,
+        machine_id: &str,
+        request: &PingQuery,
+    ) -> Result<PingReport, ClientError> {
+        let url = self.url(&format!("/v0/machines/{}/ping", machine_id));
+        self.send_json(self.http.get(url).query(request)).await
+    }
+
+    pub async fn check_machine(
+        &self,
+        machine_id: &str,
+        request: &CheckMachineQuery,
+    ) -> Result<ServiceCheckReport, ClientError> {
+        let url = self.url(&format!("/v0/machines/{}/check", machine_id));
+        self.send_json(self.http.get(url).query(request)).await
+    }
+
+    pub async fn batch_new_machines(
+        &self,
+        request: &BatchNewMachinesRequest,
+    ) -> Result<BatchNewMachinesReport, ClientError> {
+        self.send_json(
+            self.http
+                .post(self.url("/v0/machines/batch_new"))
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn dry_run_batch_new_machines(
+        &self,
+        request: &BatchNewMachinesRequest,
+    ) -> Result<BatchDryRun, ClientError> {
+        self.send_json(
+            self.http
+                .post(self.url("/v0/machines/batch_new"))
+                .query(&DryRunQuery { dry_run: true })
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn bulk_machines(
+        &self,
+        request: &BulkMachinesRequest,
+    ) -> Result<BulkMachinesReport, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/machines/bulk")).json(request))
+            .await
+    }
+
+    pub async fn clone_app(
+        &self,
+        request: &CloneAppRequest,
+    ) -> Result<CloneAppReport, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/apps/clone")).json(request))
+            .await
+    }
+
+    pub async fn clone_machine(
+        &self,
+        request: &CloneMachineRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/machines/clone")).json(request))
+            .await
+    }
+
+    pub async fn export_machine(
+        &self,
+        request: &ExportMachineQuery,
+    ) -> Result<MachineBundle, ClientError> {
+        self.send_json(
+            self.http
+                .get(self.url("/v0/machines/export"))
+                .query(request),
+        )
+        .await
+    }
+
+    pub async fn import_machine(
+        &self,
+        request: &ImportMachineRequest,
+    ) -> Result<ImportMachineReport, ClientError> {
+        self.send_json(
+            self.http
+                .post(self.url("/v0/machines/import"))
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn create_app(
+        &self,
+        request: &NewAppRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/apps/new")).json(request))
+            .await
+    }
+
+    pub async fn list_apps(
+        &self,
+        request: &ListAppsRequest,
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/apps/list")).query(request))
+            .await
+    }
+
+    pub async fn get_app(&self, request: &AppRequest) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/apps/get")).query(request))
+            .await
+    }
+
+    pub async fn list_app_ips(
+        &self,
+        request: &AppRequest,
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/apps/ips")).query(request))
+            .await
+    }
+
+    pub async fn list_app_certificates(
+        &self,
+        request: &AppRequest,
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
+        self.send_json(
+            self.http
+                .get(self.url("/v0/apps/certificates"))
+                .query(request),
+        )
+        .await
+    }
+
+    pub async fn list_app_releases(
+        &self,
+        request: &AppRequest,
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/apps/releases")).query(request))
+            .await
+    }
+
+    pub async fn delete_app(&self, request: &AppRequest) -> Result<(), ClientError> {
+        self.send(self.http.post(self.url("/v0/apps/delete")).json(request))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn create_volume(
+        &self,
+        request: &NewVolumeRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/volumes/new")).json(request))
+            .await
+    }
+
+    pub async fn list_volumes(
+        &self,
+        request: &ListVolumesRequest,
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/volumes/list")).query(request))
+            .await
+    }
+
+    pub async fn get_volume(
+        &self,
+        request: &VolumeRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/volumes/get")).query(request))
+            .await
+    }
+
+    pub async fn extend_volume(
+        &self,
+        request: &ExtendVolumeRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/volumes/extend")).json(request))
+            .await
+    }
+
+    pub async fn delete_volume(
+        &self,
+        request: &VolumeRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/volumes/delete")).json(request))
+            .await
+    }
+
+    pub async fn snapshot_volume(&self, request: &VolumeRequest) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .post(self.url("/v0/volumes/snapshot"))
+                .json(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list_snapshots(
+        &self,
+        request: &VolumeRequest,
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
+        self.send_json(
+            self.http
+                .get(self.url("/v0/volumes/snapshots"))
+                .query(request),
+        )
+        .await
+    }
+
+    pub async fn set_secrets(
+        &self,
+        request: &SetSecretsRequest,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/secrets/set")).json(request))
+            .await
+    }
+
+    pub async fn list_secrets(
+        &self,
+        request: &ListSecretsRequest,
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/secrets/list")).query(request))
+            .await
+    }
+
+    pub async fn secret_drift(
+        &self,
+        request: &ListSecretsRequest,
+    ) -> Result<SecretDrift, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/secrets/drift")).query(request))
+            .await
+    }
+
+    pub async fn unset_secret(&self, request: &UnsetSecretRequest) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .delete(self.url("/v0/secrets/unset"))
+                .query(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn put_template(
+        &self,
+        template: &MachineTemplate,
+    ) -> Result<MachineTemplate, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/templates")).json(template))
+            .await
+    }
+
+    pub async fn list_templates(&self) -> Result<Vec<MachineTemplate>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/templates")))
+            .await
+    }
+
+    pub async fn put_view(&self, view: &SavedView) -> Result<SavedView, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/views")).json(view))
+            .await
+    }
+
+    pub async fn list_views(&self) -> Result<Vec<SavedView>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/views"))).await
+    }
+
+    pub async fn adopt_fleet(&self, request: &FleetQuery) -> Result<FleetSpec, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/fleets/adopt")).query(request))
+            .await
+    }
+
+    pub async fn list_fleets(&self) -> Result<Vec<FleetSpec>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/fleets/list")))
+            .await
+    }
+
+    pub async fn get_fleet(&self, request: &FleetQuery) -> Result<FleetSpec, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/fleets/get")).query(request))
+            .await
+    }
+
+    pub async fn set_fleet_mode(&self, request: &FleetQuery) -> Result<FleetSpec, ClientError> {
+        self.send_json(self.http.post(self.url("/v0/fleets/mode")).query(request))
+            .await
+    }
+
+    pub async fn fleet_drift(&self, request: &FleetQuery) -> Result<FleetDrift, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/fleets/drift")).query(request))
+            .await
+    }
+
+    pub async fn release_fleet(&self, request: &FleetQuery) -> Result<(), ClientError> {
+        self.send(
+            self.http
+                .post(self.url("/v0/fleets/release"))
+                .query(request),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list_events(&self, request: &EventsQuery) -> Result<Vec<Event>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/events")).query(request))
+            .await
+    }
+
+    pub async fn report(&self, name: &str) -> Result<FleetReport, ClientError> {
+        self.send_json(self.http.get(self.url(&format!("/v0/reports/{}", name))))
+            .await
+    }
+
+    pub async fn slo(&self) -> Result<Vec<SloStatus>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/slo"))).await
+    }
+
+    pub async fn usage(&self, request: &UsageQuery) -> Result<Vec<UsageRecord>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/usage")).query(request))
+            .await
+    }
+
+    pub async fn audit(&self, request: &AuditQuery) -> Result<Vec<AuditRecord>, ClientError> {
+        self.send_json(self.http.get(self.url("/v0/audit")).query(request))
+            .await
+    }
+
+    pub async fn version(&self) -> Result<VersionInfo, ClientError> {
+        self.send_json(self.http.get(self.url("/version"))).await
+    }
+}
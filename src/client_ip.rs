@@ -0,0 +1,160 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::header::HeaderMap;
+use ipnet::IpNet;
+
+const FLY_CLIENT_IP: &str = "fly-client-ip";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Resolves the real client address when flyd sits behind Fly's edge or another proxy.
+/// Forwarding headers are only honored when the immediate peer is in a trusted CIDR.
+#[derive(Clone, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn from_cidrs(cidrs: &[String]) -> Result<Self, String> {
+        let cidrs = cidrs
+            .iter()
+            .map(|cidr| {
+                cidr.parse::<IpNet>()
+                    .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|e| format!("Invalid trusted proxy CIDR {}: {}", cidr, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TrustedProxies { cidrs })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(&ip))
+    }
+
+    pub fn client_ip(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?.ip();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        if let Some(ip) = headers
+            .get(FLY_CLIENT_IP)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+        {
+            return Some(ip);
+        }
+
+        // Walk X-Forwarded-For from the nearest hop outwards, stopping at the first
+        // address we don't trust: anything further left could be spoofed by the client.
+        let forwarded: Vec<IpAddr> = headers
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+
+        Some(
+            forwarded
+                .iter()
+                .rev()
+                .find(|ip| !self.is_trusted(**ip))
+                .or(forwarded.first())
+                .copied()
+                .unwrap_or(peer),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::from_cidrs(&["10.0.0.0/8".to_string(), "172.16.0.1".to_string()]).unwrap()
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 443))
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn an_untrusted_peer_is_the_client_whatever_it_forwards() {
+        let forwarded = headers(&[
+            (FLY_CLIENT_IP, "198.51.100.7"),
+            (X_FORWARDED_FOR, "198.51.100.8"),
+        ]);
+        assert_eq!(
+            proxies().client_ip(peer("203.0.113.9"), &forwarded),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn a_trusted_peer_is_believed_about_fly_client_ip_first() {
+        let forwarded = headers(&[
+            (FLY_CLIENT_IP, "198.51.100.7"),
+            (X_FORWARDED_FOR, "198.51.100.8"),
+        ]);
+        assert_eq!(
+            proxies().client_ip(peer("10.1.2.3"), &forwarded),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn forwarded_hops_are_walked_back_to_the_first_untrusted_one() {
+        // The client claimed 192.0.2.1 itself; 198.51.100.8 is where our proxies saw it.
+        let forwarded = headers(&[(X_FORWARDED_FOR, "192.0.2.1, 198.51.100.8, 10.9.9.9")]);
+        assert_eq!(
+            proxies().client_ip(peer("172.16.0.1"), &forwarded),
+            ip("198.51.100.8")
+        );
+
+        let split = headers(&[
+            (X_FORWARDED_FOR, "192.0.2.1, 198.51.100.8"),
+            (X_FORWARDED_FOR, "10.9.9.9, garbage"),
+        ]);
+        assert_eq!(
+            proxies().client_ip(peer("10.0.0.1"), &split),
+            ip("198.51.100.8")
+        );
+    }
+
+    #[test]
+    fn hops_that_are_all_trusted_fall_back_to_the_leftmost_then_the_peer() {
+        let internal = headers(&[(X_FORWARDED_FOR, "10.0.0.5, 10.0.0.6")]);
+        assert_eq!(
+            proxies().client_ip(peer("10.0.0.7"), &internal),
+            ip("10.0.0.5")
+        );
+        assert_eq!(
+            proxies().client_ip(peer("10.0.0.7"), &HeaderMap::new()),
+            ip("10.0.0.7")
+        );
+        assert_eq!(proxies().client_ip(None, &internal), None);
+    }
+
+    #[test]
+    fn refuses_a_bad_cidr() {
+        assert!(TrustedProxies::from_cidrs(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(proxies().is_trusted("172.16.0.1".parse().unwrap()));
+        assert!(!proxies().is_trusted("172.16.0.2".parse().unwrap()));
+    }
+}
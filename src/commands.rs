@@ -0,0 +1,227 @@
+use std::time::{Duration, Instant};
+
+use flyd::models::{Job, JobState, ScheduledOperation};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::config::CommandsConfig;
+use crate::jobs::{Jobs, Work};
+use crate::schedules::{self, Context};
+
+/// A machine to create, as `POST /v1/apps/{app}/machines` takes it.
+#[derive(Deserialize, Clone)]
+struct CreateCommand {
+    app: String,
+    name: Option<String>,
+    region: Option<String>,
+    config: Value,
+}
+
+enum Command {
+    Create(CreateCommand),
+    Operation(ScheduledOperation),
+}
+
+impl Command {
+    fn app(&self) -> &str {
+        match self {
+            Command::Create(create) => &create.app,
+            Command::Operation(operation) => operation.app(),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Command::Create(_) => "create",
+            Command::Operation(operation) => operation.kind(),
+        }
+    }
+}
+
+/// The command, and whether its reply waits for the job to finish, if it's one the
+/// config allows.
+fn parse(config: &CommandsConfig, payload: &[u8]) -> Result<(Command, bool), String> {
+    let value: Value = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+    let wait = value["wait"].as_bool().unwrap_or(false);
+    let command = if value["operation"] == "create" {
+        Command::Create(CreateCommand::deserialize(&value).map_err(|e| e.to_string())?)
+    } else {
+        Command::Operation(ScheduledOperation::deserialize(&value).map_err(|e| e.to_string())?)
+    };
+    if !config.apps.iter().any(|app| app == command.app()) {
+        return Err(format!("Commands may not act on {}", command.app()));
+    }
+    Ok((command, wait))
+}
+
+async fn create<B: Backend>(backend: B, create: CreateCommand) -> Result<Value, String> {
+    backend
+        .create_machine(
+            &create.app,
+            &json!({ "name": create.name, "region": create.region, "config": create.config }),
+        )
+        .await
+        .map_err(|e| format!("Failed to create machine: {}", e))
+}
+
+fn submit<B: Backend + Clone + 'static>(context: &Context<B>, command: Command) -> Job {
+    let kind = command.kind();
+    let app = command.app().to_string();
+    let group = context.jobs.group_for(&app);
+    let needs_approval = context.jobs.requires_approval(&group);
+    let work: Work = match command {
+        Command::Create(command) => {
+            let backend = context.backend.clone();
+            Box::new(move || Box::pin(create(backend.clone(), command.clone())))
+        }
+        Command::Operation(operation) => {
+            let context = context.clone();
+            Box::new(move || Box::pin(schedules::run_operation(context.clone(), operation.clone())))
+        }
+    };
+    Jobs::submit(
+        &context.jobs,
+        kind,
+        Some(&app),
+        group,
+        needs_approval,
+        None,
+        work,
+    )
+}
+
+/// The job once it's done, or as it stands when `timeout` is up.
+async fn finished(jobs: &Jobs, mut job: Job, timeout: Duration) -> Job {
+    let deadline = Instant::now() + timeout;
+    while !matches!(
+        job.state,
+        JobState::Succeeded | JobState::Failed | JobState::Rejected | JobState::Canceled
+    ) && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        match jobs.get(job.id).await {
+            Some(latest) => job = latest,
+            None => break,
+        }
+    }
+    job
+}
+
+/// Queues each command read from the subject as a job, replying with the job, or
+/// `{"error": ...}` for a message that isn't a command.
+pub async fn run<B: Backend + Clone + 'static>(config: CommandsConfig, context: Context<B>) {
+    let options = match (&config.username, &config.password) {
+        (Some(username), Some(password)) => {
+            async_nats::ConnectOptions::with_user_and_password(username.clone(), password.clone())
+        }
+        _ => async_nats::ConnectOptions::new(),
+    };
+    let client = match options
+        .retry_on_initial_connect()
+        .connect(config.url.as_str())
+        .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Failed to connect to NATS at {}: {}", config.url, e);
+            return;
+        }
+    };
+    let subscribed = match &config.queue_group {
+        Some(group) => {
+            client
+                .queue_subscribe(config.subject.clone(), group.clone())
+                .await
+        }
+        None => client.subscribe(config.subject.clone()).await,
+    };
+    let mut messages = match subscribed {
+        Ok(subscriber) => subscriber,
+        Err(e) => {
+            log::error!("Failed to subscribe to {}: {}", config.subject, e);
+            return;
+        }
+    };
+    log::info!("Taking commands from NATS subject {}", config.subject);
+
+    let wait_timeout = Duration::from_secs(config.wait_timeout_secs);
+    while let Some(message) = messages.next().await {
+        let reply = match parse(&config, &message.payload) {
+            Ok((command, wait)) => {
+                let job = submit(&context, command);
+                log::info!(
+                    "Queued job {} ({}) from NATS subject {}",
+                    job.id,
+                    job.kind,
+                    message.subject
+                );
+                match &message.reply {
+                    // Answered from its own task, so waiting doesn't hold up other commands.
+                    Some(reply) if wait => {
+                        let (client, jobs, reply) =
+                            (client.clone(), context.jobs.clone(), reply.clone());
+                        actix_web::rt::spawn(async move {
+                            let job = finished(&jobs, job, wait_timeout).await;
+                            respond(&client, reply, &json!(job)).await;
+                        });
+                        continue;
+                    }
+                    _ => json!(job),
+                }
+            }
+            Err(e) => {
+                log::warn!("Ignoring a command on {}: {}", message.subject, e);
+                json!({ "error": e })
+            }
+        };
+        if let Some(subject) = message.reply {
+            respond(&client, subject, &reply).await;
+        }
+    }
+    log::warn!("Stopped taking commands from {}", config.subject);
+}
+
+async fn respond(client: &async_nats::Client, subject: async_nats::Subject, reply: &Value) {
+    if let Err(e) = client
+        .publish(subject.clone(), reply.to_string().into())
+        .await
+    {
+        log::warn!("Failed to reply on {}: {}", subject, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(apps: &[&str]) -> CommandsConfig {
+        CommandsConfig {
+            url: "nats://localhost:4222".to_string(),
+            subject: "flyd.commands".to_string(),
+            apps: apps.iter().map(|app| app.to_string()).collect(),
+            queue_group: None,
+            username: None,
+            password: None,
+            wait_timeout_secs: 300,
+        }
+    }
+
+    #[test]
+    fn takes_commands_for_allowed_apps() {
+        let payload = br#"{"operation": "create", "app": "web", "config": {}, "wait": true}"#;
+        let (command, wait) = parse(&config(&["web"]), payload).unwrap();
+        assert_eq!(
+            (command.kind(), command.app(), wait),
+            ("create", "web", true)
+        );
+    }
+
+    #[test]
+    fn refuses_commands_outside_the_allowlist() {
+        let payload = br#"{"operation": "create", "app": "billing", "config": {}}"#;
+        assert!(parse(&config(&["web"]), payload).is_err());
+        assert!(parse(&config(&[]), payload).is_err());
+    }
+}
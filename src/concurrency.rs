@@ -0,0 +1,150 @@
+use std::pin::pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use tokio::sync::Notify;
+
+use crate::config::ConcurrencyConfig;
+use crate::metrics;
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    last_cut: Option<Instant>,
+    cuts: u64,
+}
+
+/// How many upstream calls fan-outs may have in flight, adjusted AIMD-style from how Fly
+/// answers: every call through a `FlyClient` with this attached reports its latency and
+/// whether it was throttled.
+pub struct AdaptiveLimit {
+    config: ConcurrencyConfig,
+    state: Mutex<State>,
+    freed: Notify,
+}
+
+pub struct Permit<'a>(&'a AdaptiveLimit);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().in_flight -= 1;
+        self.0.freed.notify_waiters();
+    }
+}
+
+impl AdaptiveLimit {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        let min = config.min.max(1);
+        let max = config.max.max(min);
+        let limit = config.initial.clamp(min, max) as f64;
+        AdaptiveLimit {
+            config: ConcurrencyConfig { min, max, ..config },
+            state: Mutex::new(State {
+                limit,
+                in_flight: 0,
+                last_cut: None,
+                cuts: 0,
+            }),
+            freed: Notify::new(),
+        }
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        loop {
+            // Registered before checking, so a permit freed in between still wakes us.
+            let mut freed = pin!(self.freed.notified());
+            freed.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit.floor() as usize {
+                    state.in_flight += 1;
+                    return Permit(self);
+                }
+            }
+            freed.await;
+        }
+    }
+
+    pub fn record(&self, latency: Duration, throttled: bool) {
+        let target = Duration::from_millis(self.config.target_latency_ms);
+        let mut state = self.state.lock().unwrap();
+        if throttled || latency > target {
+            // Calls already in flight when Fly pushed back mostly report the same thing,
+            // so cut once per round trip.
+            let now = Instant::now();
+            if state
+                .last_cut
+                .is_some_and(|at| now.duration_since(at) < latency.max(target))
+            {
+                return;
+            }
+            let backoff = self.config.backoff.clamp(0.1, 0.9);
+            state.limit = (state.limit * backoff).max(self.config.min as f64);
+            state.last_cut = Some(now);
+            state.cuts += 1;
+            log::info!(
+                "Upstream concurrency cut to {} after a {} call",
+                state.limit.floor(),
+                if throttled { "throttled" } else { "slow" }
+            );
+        } else if state.in_flight >= state.limit.floor() as usize {
+            // Only grow while the limit is what's holding fan-outs back.
+            state.limit = (state.limit + 1.0 / state.limit).min(self.config.max as f64);
+            drop(state);
+            self.freed.notify_waiters();
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        let state = self.state.lock().unwrap();
+        for (name, kind, help, value) in [
+            (
+                "flyd_upstream_concurrency_limit",
+                "gauge",
+                "Upstream calls fan-outs may currently have in flight.",
+                state.limit.floor(),
+            ),
+            (
+                "flyd_upstream_concurrency_in_flight",
+                "gauge",
+                "Upstream calls fan-outs have in flight.",
+                state.in_flight as f64,
+            ),
+            (
+                "flyd_upstream_concurrency_cuts_total",
+                "counter",
+                "Times the limit was cut for a throttled or slow upstream call.",
+                state.cuts as f64,
+            ),
+        ] {
+            metrics::header(out, name, kind, help);
+            metrics::sample(out, name, &[], value);
+        }
+    }
+}
+
+/// Runs every future, at most `limit` at a time, returning their outputs in order. Without
+/// a limit they run one at a time.
+pub fn map<'a, F: Future + 'a>(
+    limit: Option<&'a AdaptiveLimit>,
+    futures: impl IntoIterator<Item = F>,
+) -> impl Future<Output = Vec<F::Output>> + 'a {
+    // Collected up front so the caller's iterator, and any closure in it, isn't part of the
+    // returned future.
+    let futures: Vec<F> = futures.into_iter().collect();
+    async move {
+        let Some(limit) = limit else {
+            let mut outputs = Vec::new();
+            for future in futures {
+                outputs.push(future.await);
+            }
+            return outputs;
+        };
+        join_all(futures.into_iter().map(|future| async move {
+            let _permit = limit.acquire().await;
+            future.await
+        }))
+        .await
+    }
+}
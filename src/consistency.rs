@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::{
+    ConsistencyIssue, ConsistencyIssueKind, ConsistencyReport, HeldLease, MigrationState,
+    OrgMigration, Readiness, Schedule,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::auth;
+use crate::config::ConsistencyConfig;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets;
+use crate::fly_client::{FlyClient, FlyError};
+use crate::migrations::MIGRATIONS;
+use crate::prepare_request;
+use crate::schedules::SCHEDULES;
+use crate::shutdown;
+use crate::store::Store;
+
+const LEASES: &str = "leases";
+const CONSISTENCY_ISSUE: &str = "consistency.issue";
+
+fn lease_key(app: &str, machine_id: &str) -> String {
+    format!("{}/{}", app, machine_id)
+}
+
+/// Remembers a lease flyd took itself until it's `released`.
+pub async fn leased(
+    store: Option<&web::Data<Store>>,
+    app: &str,
+    machine_id: &str,
+    nonce: &str,
+    ttl_secs: u64,
+) {
+    let Some(store) = store else {
+        return;
+    };
+    let lease = HeldLease {
+        app: app.to_string(),
+        machine_id: machine_id.to_string(),
+        nonce: nonce.to_string(),
+        ttl_secs,
+        acquired_at: Utc::now(),
+    };
+    if let Err(e) = store.put(LEASES, &lease_key(app, machine_id), &lease).await {
+        log::warn!("Failed to record lease on machine {}: {}", machine_id, e);
+    }
+}
+
+pub async fn released(store: Option<&web::Data<Store>>, app: &str, machine_id: &str) {
+    let Some(store) = store else {
+        return;
+    };
+    if let Err(e) = store.delete(LEASES, &lease_key(app, machine_id)).await {
+        log::warn!("Failed to forget lease on machine {}: {}", machine_id, e);
+    }
+}
+
+/// Whether each app still exists, asked of Fly once per check.
+struct Apps<'a> {
+    client: &'a FlyClient,
+    known: HashMap<String, Option<bool>>,
+}
+
+impl Apps<'_> {
+    /// `None` when Fly couldn't say, so nothing is done about the app.
+    async fn exists(&mut self, app: &str) -> Option<bool> {
+        if let Some(known) = self.known.get(app) {
+            return *known;
+        }
+        let exists = match self.client.get_app(app).await {
+            Ok(_) => Some(true),
+            Err(FlyError::Status { status, .. }) if status == StatusCode::NOT_FOUND => Some(false),
+            Err(e) => {
+                log::warn!("Failed to check whether app {} exists: {}", app, e);
+                None
+            }
+        };
+        self.known.insert(app.to_string(), exists);
+        exists
+    }
+}
+
+/// Checks flyd's stored state against Fly: schedules and fleets of apps that are gone,
+/// leases flyd never released and migrations a restart cut short. Anything from before
+/// this process started belongs to a previous run that can't finish it.
+pub struct Consistency {
+    config: ConsistencyConfig,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    started_at: DateTime<Utc>,
+    report: Mutex<Option<ConsistencyReport>>,
+}
+
+impl Consistency {
+    pub fn new(
+        config: ConsistencyConfig,
+        store: web::Data<Store>,
+        events: web::Data<EventLog>,
+    ) -> Self {
+        Consistency {
+            config,
+            store,
+            events,
+            started_at: Utc::now(),
+            report: Mutex::new(None),
+        }
+    }
+
+    /// Whether the startup check has run, and, when strict, repaired all it found.
+    pub fn is_consistent(&self) -> bool {
+        self.report.lock().unwrap().as_ref().is_some_and(|report| {
+            !self.config.strict || report.issues.iter().all(|issue| issue.repaired)
+        })
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let draining = shutdown::is_draining();
+        Readiness {
+            ready: !draining && self.is_consistent(),
+            draining,
+            consistency: self.report.lock().unwrap().clone(),
+        }
+    }
+
+    /// Marks the startup check done without checking anything.
+    pub fn skip(&self, reason: &str) {
+        let now = Utc::now();
+        *self.report.lock().unwrap() = Some(ConsistencyReport {
+            started_at: now,
+            finished_at: now,
+            skipped: Some(reason.to_string()),
+            issues: Vec::new(),
+        });
+    }
+
+    fn found(&self, issues: &mut Vec<ConsistencyIssue>, issue: ConsistencyIssue) {
+        log::warn!(
+            "{} {}: {}{}",
+            issue.app.as_deref().unwrap_or("-"),
+            issue.resource,
+            issue.detail,
+            if issue.repaired { " (repaired)" } else { "" }
+        );
+        self.events.record(
+            CONSISTENCY_ISSUE,
+            issue.app.as_deref(),
+            None,
+            json!({
+                "kind": issue.kind,
+                "resource": issue.resource,
+                "detail": issue.detail,
+                "repaired": issue.repaired,
+            }),
+        );
+        issues.push(issue);
+    }
+
+    async fn schedules(&self, apps: &mut Apps<'_>, issues: &mut Vec<ConsistencyIssue>) {
+        let schedules = match self.store.list::<Schedule>(SCHEDULES).await {
+            Ok(schedules) => schedules,
+            Err(e) => return log::error!("Failed to load schedules: {}", e),
+        };
+        for mut schedule in schedules {
+            let app = schedule.operation.app().to_string();
+            if schedule.next_run_at.is_none() || apps.exists(&app).await != Some(false) {
+                continue;
+            }
+            let mut repaired = false;
+            if self.config.repair {
+                schedule.next_run_at = None;
+                match self.store.put(SCHEDULES, &schedule.id, &schedule).await {
+                    Ok(()) => repaired = true,
+                    Err(e) => log::error!("Failed to pause schedule {}: {}", schedule.id, e),
+                }
+            }
+            self.found(
+                issues,
+                ConsistencyIssue {
+                    kind: ConsistencyIssueKind::ScheduleAppMissing,
+                    app: Some(app.clone()),
+                    resource: schedule.id,
+                    detail: format!("Scheduled for {}, which no longer exists", app),
+                    repaired,
+                },
+            );
+        }
+    }
+
+    async fn fleets(&self, apps: &mut Apps<'_>, issues: &mut Vec<ConsistencyIssue>) {
+        let specs = match fleets::fleet_specs(&self.store).await {
+            Ok(specs) => specs,
+            Err(e) => return log::error!("Failed to load fleets: {}", e),
+        };
+        for spec in specs {
+            if apps.exists(&spec.app).await != Some(false) {
+                continue;
+            }
+            self.found(
+                issues,
+                ConsistencyIssue {
+                    kind: ConsistencyIssueKind::FleetAppMissing,
+                    app: Some(spec.app.clone()),
+                    resource: spec.app.clone(),
+                    detail: format!("Fleet for {}, which no longer exists", spec.app),
+                    repaired: false,
+                },
+            );
+        }
+    }
+
+    async fn leases(&self, client: &FlyClient, issues: &mut Vec<ConsistencyIssue>) {
+        let leases = match self.store.list::<HeldLease>(LEASES).await {
+            Ok(leases) => leases,
+            Err(e) => return log::error!("Failed to load leases: {}", e),
+        };
+        let now = Utc::now();
+        for lease in leases {
+            let expires_at = lease.acquired_at + TimeDelta::seconds(lease.ttl_secs as i64);
+            if lease.acquired_at >= self.started_at && expires_at > now {
+                continue;
+            }
+            let mut repaired = false;
+            if self.config.repair {
+                let release = if expires_at > now {
+                    client
+                        .release_lease(&lease.app, &lease.machine_id, &lease.nonce)
+                        .await
+                } else {
+                    Ok(())
+                };
+                match release {
+                    Ok(()) | Err(FlyError::Status { .. }) => {
+                        // A lease Fly refuses to release is already gone or someone else's.
+                        let store = Some(&self.store);
+                        released(store, &lease.app, &lease.machine_id).await;
+                        repaired = true;
+                    }
+                    Err(e) => log::error!(
+                        "Failed to release lease on machine {}: {}",
+                        lease.machine_id,
+                        e
+                    ),
+                }
+            }
+            self.found(
+                issues,
+                ConsistencyIssue {
+                    kind: ConsistencyIssueKind::LeaseUnreleased,
+                    app: Some(lease.app),
+                    resource: lease.machine_id,
+                    detail: format!(
+                        "Lease taken at {} was never released",
+                        lease.acquired_at.to_rfc3339()
+                    ),
+                    repaired,
+                },
+            );
+        }
+    }
+
+    async fn migrations(&self, issues: &mut Vec<ConsistencyIssue>) {
+        let migrations = match self.store.list::<OrgMigration>(MIGRATIONS).await {
+            Ok(migrations) => migrations,
+            Err(e) => return log::error!("Failed to load migrations: {}", e),
+        };
+        for mut migration in migrations {
+            if migration.updated_at >= self.started_at {
+                continue;
+            }
+            let pending = migration
+                .volumes
+                .iter_mut()
+                .chain(migration.machines.iter_mut())
+                .filter(|resource| resource.state == MigrationState::Pending);
+            let mut count = 0;
+            for resource in pending {
+                count += 1;
+                if self.config.repair {
+                    resource.state = MigrationState::Failed;
+                    resource.error = Some("Interrupted by a flyd restart".to_string());
+                }
+            }
+            if count == 0 {
+                continue;
+            }
+            let mut repaired = false;
+            if self.config.repair {
+                migration.updated_at = Utc::now();
+                match self.store.put(MIGRATIONS, &migration.id, &migration).await {
+                    Ok(()) => repaired = true,
+                    Err(e) => log::error!("Failed to update migration {}: {}", migration.id, e),
+                }
+            }
+            self.found(
+                issues,
+                ConsistencyIssue {
+                    kind: ConsistencyIssueKind::MigrationInterrupted,
+                    app: Some(migration.source_app.clone()),
+                    resource: migration.id,
+                    detail: format!("{} resources were still pending when flyd stopped", count),
+                    repaired,
+                },
+            );
+        }
+    }
+
+    pub async fn check(&self, client: &FlyClient) -> ConsistencyReport {
+        let started_at = Utc::now();
+        let mut apps = Apps {
+            client,
+            known: HashMap::new(),
+        };
+        let mut issues = Vec::new();
+        self.schedules(&mut apps, &mut issues).await;
+        self.fleets(&mut apps, &mut issues).await;
+        self.leases(client, &mut issues).await;
+        self.migrations(&mut issues).await;
+        let report = ConsistencyReport {
+            started_at,
+            finished_at: Utc::now(),
+            skipped: None,
+            issues,
+        };
+        *self.report.lock().unwrap() = Some(report.clone());
+        report
+    }
+
+    fn admin(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(role) = &self.config.admin_role else {
+            return Err(AppError::forbidden(
+                "Checking consistency needs consistency.admin_role set",
+            )
+            .into_response());
+        };
+        auth::require_role(req, Some(role)).map(|_| ())
+    }
+}
+
+/// The startup check, run before anything else gets the chance to act on stale state.
+pub async fn run(consistency: web::Data<Consistency>, client: FlyClient) {
+    let report = consistency.check(&client).await;
+    log::info!(
+        "Checked flyd's state against Fly: {} issues, {} repaired",
+        report.issues.len(),
+        report.issues.iter().filter(|issue| issue.repaired).count()
+    );
+}
+
+#[derive(Deserialize)]
+struct CheckQuery {
+    #[serde(default)]
+    use_private_api: bool,
+}
+
+#[get("/ready")]
+async fn ready(consistency: web::Data<Consistency>) -> impl Responder {
+    let readiness = consistency.readiness();
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[get("/v0/admin/consistency")]
+async fn get_report(req: HttpRequest, consistency: web::Data<Consistency>) -> impl Responder {
+    if let Err(response) = consistency.admin(&req) {
+        return response;
+    }
+    match consistency.readiness().consistency {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => AppError::not_found("The startup check hasn't finished yet").into_response(),
+    }
+}
+
+/// Checks again now, using the caller's credentials.
+#[post("/v0/admin/consistency")]
+async fn run_check(
+    req: HttpRequest,
+    query: web::Query<CheckQuery>,
+    consistency: web::Data<Consistency>,
+    http_client: web::Data<reqwest::Client>,
+) -> impl Responder {
+    if let Err(response) = consistency.admin(&req) {
+        return response;
+    }
+    if req.app_data::<web::Data<DockerBackend>>().is_some() {
+        return AppError::unprocessable("The consistency check only applies to Fly")
+            .into_response();
+    }
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname);
+    HttpResponse::Ok().json(consistency.check(&client).await)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(ready).service(get_report).service(run_check);
+}
@@ -0,0 +1,119 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, post, web};
+use chrono::Utc;
+use flyd::models::MachineRequest;
+use serde_json::{Value, json};
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{self, FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+/// Set on machines cordoned through flyd, to when they were. Fly doesn't report a
+/// machine's cordon in its listing, and drains cordon machines only for a while, so this
+/// is what marks one as taken out of rotation on purpose.
+pub const CORDONED_METADATA_KEY: &str = "flyd_cordoned_at";
+
+pub fn is_cordoned(machine: &Value) -> bool {
+    !machine["config"]["metadata"][CORDONED_METADATA_KEY].is_null()
+}
+
+async fn set_cordon<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    app: &str,
+    machine_id: &str,
+    cordon: bool,
+) -> HttpResponse {
+    let result = if cordon {
+        match backend.cordon_machine(app, machine_id).await {
+            Ok(()) => backend
+                .set_metadata(
+                    app,
+                    machine_id,
+                    CORDONED_METADATA_KEY,
+                    &Utc::now().to_rfc3339(),
+                )
+                .await
+                .map_err(|e| format!("Cordoned, but failed to tag: {}", e)),
+            Err(e) => Err(format!("Failed to cordon: {}", e)),
+        }
+    } else {
+        match backend.uncordon_machine(app, machine_id).await {
+            Ok(()) => backend
+                .delete_metadata(app, machine_id, CORDONED_METADATA_KEY)
+                .await
+                .map_err(|e| format!("Uncordoned, but failed to untag: {}", e)),
+            Err(e) => Err(format!("Failed to uncordon: {}", e)),
+        }
+    };
+    if let Err(e) = result {
+        return AppError::internal(e).into_response();
+    }
+    fly_client::cache().invalidate(app);
+    if let Some(events) = req.app_data::<web::Data<EventLog>>() {
+        events.record(
+            if cordon {
+                "machine.cordoned"
+            } else {
+                "machine.uncordoned"
+            },
+            Some(app),
+            Some(machine_id),
+            json!({
+                "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+            }),
+        );
+    }
+    HttpResponse::NoContent().finish()
+}
+
+async fn handle(
+    req: HttpRequest,
+    body: web::Json<MachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+    cordon: bool,
+) -> HttpResponse {
+    let (app, machine_id) = (&body.app_name, &body.machine_id);
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return set_cordon(&req, docker.get_ref(), app, machine_id, cordon).await;
+    }
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_request_checks(&req);
+    set_cordon(&req, &client, app, machine_id, cordon).await
+}
+
+/// Takes the machine out of Fly's proxy rotation until it's uncordoned. Rolling deploys
+/// and bulk actions leave it alone unless asked to include cordoned machines.
+#[post("/v0/machines/cordon")]
+async fn cordon_machine(
+    req: HttpRequest,
+    body: web::Json<MachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    handle(req, body, http_client, slo, true).await
+}
+
+#[post("/v0/machines/uncordon")]
+async fn uncordon_machine(
+    req: HttpRequest,
+    body: web::Json<MachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    handle(req, body, http_client, slo, false).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(cordon_machine).service(uncordon_machine);
+}
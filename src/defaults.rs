@@ -0,0 +1,22 @@
+use std::collections::HashMap;
+
+use serde_json::{Value, json};
+
+use crate::config::AppDefaults;
+use crate::merge::fill_defaults;
+
+const ALL_APPS: &str = "*";
+
+/// Applies the configured defaults for `app` to a Machines API create body: the app's
+/// own defaults, then `*`, then required metadata on top.
+pub fn apply(defaults: &HashMap<String, AppDefaults>, app: &str, body: &mut Value) {
+    let layers = [defaults.get(app), defaults.get(ALL_APPS)];
+    for layer in layers.iter().flatten() {
+        fill_defaults(body, &Value::Object(layer.create.clone()));
+    }
+    for layer in layers.iter().rev().flatten() {
+        for (key, value) in &layer.required_metadata {
+            body["config"]["metadata"][key] = json!(value);
+        }
+    }
+}
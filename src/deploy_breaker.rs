@@ -0,0 +1,355 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use chrono::Utc;
+use flyd::models::{ErrorRateSample, TrippedApp};
+use reqwest::header::AUTHORIZATION;
+use serde_json::{Value, json};
+
+use crate::auth::{self, Identity};
+use crate::config::{Config, DeployBreakerConfig};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::authorization_value;
+use crate::jobs;
+use crate::namespaces;
+use crate::store::Store;
+
+/// Apps whose deploys are paused, keyed by app. Apps that aren't aren't kept.
+const TRIPPED: &str = "deploy_breaker";
+const BREAKER_TRIPPED: &str = "deploy_breaker.tripped";
+const BREAKER_RECOVERED: &str = "deploy_breaker.recovered";
+const BREAKER_RESET: &str = "deploy_breaker.reset";
+
+/// Which apps have too many requests failing to deploy to. Tripped apps are kept in the
+/// store, so a restart doesn't resume their deploys early.
+pub struct DeployBreaker {
+    config: DeployBreakerConfig,
+    http: reqwest::Client,
+    token: Option<String>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+}
+
+impl DeployBreaker {
+    pub fn from_config(
+        config: &Config,
+        http: reqwest::Client,
+        store: web::Data<Store>,
+        events: web::Data<EventLog>,
+    ) -> Result<Option<Self>, String> {
+        let Some(breaker) = &config.deploy_breaker else {
+            return Ok(None);
+        };
+        if !(breaker.threshold > 0.0 && breaker.threshold <= 1.0) {
+            return Err(format!(
+                "deploy_breaker.threshold {} must be over 0 and at most 1",
+                breaker.threshold
+            ));
+        }
+        if let Some(recover_below) = breaker.recover_below
+            && !(recover_below > 0.0 && recover_below <= breaker.threshold)
+        {
+            return Err(format!(
+                "deploy_breaker.recover_below {} must be over 0 and at most the threshold",
+                recover_below
+            ));
+        }
+        if breaker.prometheus_url.is_some() && breaker.apps.is_empty() {
+            return Err("deploy_breaker.prometheus_url needs apps to query".to_string());
+        }
+        Ok(Some(DeployBreaker {
+            config: breaker.clone(),
+            http,
+            token: config.fly_api_token.clone(),
+            store,
+            events,
+        }))
+    }
+
+    /// Whether the app's deploys are paused. If the store can't say, they aren't.
+    async fn tripped(&self, app: &str) -> Option<TrippedApp> {
+        self.store
+            .get::<TrippedApp>(TRIPPED, app)
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("Failed to load the deploy breaker of {}: {}", app, e);
+                None
+            })
+    }
+
+    /// Trips the app's breaker at `threshold`, and closes it again under `recover_below`.
+    /// Returns it if it's left tripped.
+    async fn observe(
+        &self,
+        app: &str,
+        error_rate: f64,
+        source: &str,
+    ) -> Result<Option<TrippedApp>, String> {
+        let recover_below = self.config.recover_below.unwrap_or(self.config.threshold);
+        let now = Utc::now();
+        let tripped = match self.tripped(app).await {
+            Some(_) if error_rate < recover_below => {
+                self.store
+                    .delete(TRIPPED, app)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                log::info!(
+                    "Resuming deploys of {}: its error rate is down to {}",
+                    app,
+                    error_rate
+                );
+                self.events.record(
+                    BREAKER_RECOVERED,
+                    Some(app),
+                    None,
+                    json!({ "error_rate": error_rate, "source": source }),
+                );
+                return Ok(None);
+            }
+            Some(tripped) => TrippedApp {
+                error_rate,
+                checked_at: now,
+                ..tripped
+            },
+            None if error_rate >= self.config.threshold => {
+                log::warn!(
+                    "Pausing deploys of {}: its error rate is {}, at or over {}",
+                    app,
+                    error_rate,
+                    self.config.threshold
+                );
+                self.events.record(
+                    BREAKER_TRIPPED,
+                    Some(app),
+                    None,
+                    json!({
+                        "error_rate": error_rate,
+                        "threshold": self.config.threshold,
+                        "source": source,
+                    }),
+                );
+                TrippedApp {
+                    app: app.to_string(),
+                    error_rate,
+                    threshold: self.config.threshold,
+                    source: source.to_string(),
+                    tripped_at: now,
+                    checked_at: now,
+                }
+            }
+            None => return Ok(None),
+        };
+        self.store
+            .put(TRIPPED, app, &tripped)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Some(tripped))
+    }
+
+    /// The app's error rate from Prometheus. `None` if it served no requests to have a
+    /// rate of.
+    async fn error_rate(&self, prometheus_url: &str, app: &str) -> Result<Option<f64>, String> {
+        let query = self.config.query.replace("{app}", app);
+        let mut request = self
+            .http
+            .get(format!(
+                "{}/api/v1/query",
+                prometheus_url.trim_end_matches('/')
+            ))
+            .query(&[("query", &query)]);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, authorization_value(token));
+        }
+        let body: Value = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("Failed to query {}: {}", query, e))?
+            .json()
+            .await
+            .map_err(|e| format!("Failed to query {}: {}", query, e))?;
+        match body["data"]["result"][0]["value"][1].as_str() {
+            // A rate of no requests is NaN.
+            Some(value) => value
+                .parse::<f64>()
+                .map(|rate| rate.is_finite().then_some(rate))
+                .map_err(|e| format!("Unexpected error rate {}: {}", value, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits while the app's breaker is tripped, as a step of the running job, so a deploy
+    /// pauses before its next machine rather than rolling a bad version further. Fails only
+    /// if the job runs out of time first.
+    pub async fn wait_closed(&self, app: &str) -> Result<(), String> {
+        let Some(tripped) = self.tripped(app).await else {
+            return Ok(());
+        };
+        log::info!(
+            "Deploy of {} paused: its error rate is {}",
+            app,
+            tripped.error_rate
+        );
+        let interval = Duration::from_secs(self.config.interval_secs.max(1));
+        jobs::step(&format!("wait for {}'s error rate", app), None, async {
+            while self.tripped(app).await.is_some() {
+                tokio::time::sleep(interval).await;
+            }
+        })
+        .await?;
+        log::info!("Deploy of {} resumed", app);
+        Ok(())
+    }
+
+    /// A 423 for a new deploy of any of `apps` while one's breaker is tripped.
+    pub async fn refusal<'a>(
+        &self,
+        apps: impl IntoIterator<Item = &'a String>,
+    ) -> Option<HttpResponse> {
+        for app in apps {
+            if let Some(tripped) = self.tripped(app).await {
+                return Some(
+                    AppError::new(
+                        StatusCode::LOCKED,
+                        format!(
+                            "Deploys of {} are paused: its error rate is {}, over {}",
+                            app, tripped.error_rate, tripped.threshold
+                        ),
+                    )
+                    .with_code("deploys_paused")
+                    .with_retry_after(Duration::from_secs(self.config.interval_secs))
+                    .into_response(),
+                );
+            }
+        }
+        None
+    }
+}
+
+/// Asks Prometheus for each app's error rate every `interval_secs`, tripping and closing
+/// their breakers.
+pub async fn run(breaker: web::Data<DeployBreaker>) {
+    let Some(prometheus_url) = breaker.config.prometheus_url.clone() else {
+        return;
+    };
+    let mut ticker =
+        tokio::time::interval(Duration::from_secs(breaker.config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        for app in &breaker.config.apps {
+            let observed = match breaker.error_rate(&prometheus_url, app).await {
+                Ok(Some(rate)) => breaker.observe(app, rate, "prometheus").await.map(|_| ()),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = observed {
+                log::warn!("Failed to check the error rate of {}: {}", app, e);
+            }
+        }
+    }
+}
+
+/// The breaker and whoever's changing it, if they may.
+fn admin(
+    req: &HttpRequest,
+    breaker: Option<web::Data<DeployBreaker>>,
+) -> Result<(web::Data<DeployBreaker>, Identity), HttpResponse> {
+    let Some(breaker) = breaker else {
+        return Err(AppError::not_found("The deploy breaker isn't configured").into_response());
+    };
+    let Some(role) = &breaker.config.admin_role else {
+        return Err(AppError::forbidden(
+            "Managing the deploy breaker needs deploy_breaker.admin_role set",
+        )
+        .into_response());
+    };
+    let identity = auth::require_role(req, Some(role))?;
+    Ok((breaker, identity))
+}
+
+/// Takes an app's error rate from elsewhere, e.g. an alert webhook. Answers with the app
+/// if its deploys are paused, and 204 if they aren't.
+#[post("/v0/deploy_breaker/error_rates")]
+async fn push_error_rate(
+    req: HttpRequest,
+    body: web::Json<ErrorRateSample>,
+    breaker: Option<web::Data<DeployBreaker>>,
+) -> impl Responder {
+    let (breaker, _) = match admin(&req, breaker) {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    if !(body.error_rate.is_finite() && body.error_rate >= 0.0) {
+        return AppError::bad_request("error_rate must be a number, at least 0").into_response();
+    }
+    match breaker.observe(&body.app, body.error_rate, "webhook").await {
+        Ok(Some(tripped)) => HttpResponse::Ok().json(tripped),
+        Ok(None) => HttpResponse::NoContent().finish(),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+/// The apps the caller may touch whose deploys are paused.
+#[get("/v0/deploy_breaker")]
+async fn list_tripped(
+    req: HttpRequest,
+    breaker: Option<web::Data<DeployBreaker>>,
+) -> impl Responder {
+    let identity = match auth::require_role(&req, None) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let Some(breaker) = breaker else {
+        return AppError::not_found("The deploy breaker isn't configured").into_response();
+    };
+    let namespace = namespaces::of(&req);
+    match breaker.store.list::<TrippedApp>(TRIPPED).await {
+        Ok(mut tripped) => {
+            tripped.retain(|tripped| {
+                identity.may_touch(&tripped.app)
+                    && namespace
+                        .as_ref()
+                        .is_none_or(|namespace| namespace.owns(&tripped.app))
+            });
+            HttpResponse::Ok().json(tripped)
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+/// Resumes the app's deploys whatever its error rate. A source still reporting it over
+/// the threshold trips it again.
+#[delete("/v0/deploy_breaker/{app}")]
+async fn reset_breaker(
+    req: HttpRequest,
+    path: web::Path<String>,
+    breaker: Option<web::Data<DeployBreaker>>,
+) -> impl Responder {
+    let (breaker, identity) = match admin(&req, breaker) {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    match breaker.store.delete(TRIPPED, &path).await {
+        Ok(true) => {
+            breaker.events.record(
+                BREAKER_RESET,
+                Some(&path),
+                None,
+                json!({ "by": identity.subject }),
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => {
+            AppError::not_found(format!("Deploys of {} aren't paused", path)).into_response()
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(push_error_rate)
+        .service(list_tripped)
+        .service(reset_breaker);
+}
@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use flyd::models::{DeployRequest, FleetSpec, RegionalRollout, TrafficStep};
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::cordon;
+use crate::deploy_breaker::DeployBreaker;
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::environments;
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::jobs::{self, Jobs, Work};
+use crate::merge;
+use crate::prepare_request;
+use crate::provenance;
+use crate::slo::SloTracker;
+use crate::store::Store;
+use crate::warm_up;
+
+pub fn apply(request: &DeployRequest, config: &mut Value) {
+    if let Some(patch) = &request.config {
+        merge::merge_patch(config, patch);
+    }
+    if let Some(image) = &request.image {
+        config["image"] = json!(image);
+    }
+    if let Some(recorded) = &request.provenance {
+        provenance::set(config, recorded);
+    }
+}
+
+/// A managed fleet's spec changes too, or the reconciler would roll the deploy back.
+/// Returns the spec as it was.
+pub async fn update_fleet_spec(
+    store: &Store,
+    request: &DeployRequest,
+) -> Result<Option<FleetSpec>, String> {
+    let Some(previous) = fleets::fleet_spec(store, &request.app)
+        .await
+        .map_err(|e| e.to_string())?
+    else {
+        return Ok(None);
+    };
+    let mut spec = previous.clone();
+    for machine in &mut spec.machines {
+        apply(request, &mut machine.config);
+    }
+    fleets::save_fleet_spec(store, &spec)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Some(previous))
+}
+
+pub async fn deploy<B: Backend>(
+    backend: B,
+    store: web::Data<Store>,
+    drainer: Option<web::Data<Drainer>>,
+    breaker: Option<web::Data<DeployBreaker>>,
+    request: DeployRequest,
+) -> Result<Value, String> {
+    update_fleet_spec(&store, &request).await?;
+
+    let machines = backend
+        .list_machines(&request.app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?;
+    let live: Vec<&Value> = machines
+        .iter()
+        .filter(|machine| fleets::is_live(machine))
+        .collect();
+    let waves = match &request.rollout {
+        Some(rollout) => waves(rollout, live),
+        None => vec![(None, live)],
+    };
+
+    let mut updated = Vec::new();
+    let mut regions = Vec::new();
+    for (region, machines) in waves {
+        for step in steps(request.traffic_step.as_ref(), machines) {
+            let mut held = Vec::new();
+            let result = async {
+                for machine in step {
+                    let Some(id) = machine["id"].as_str() else {
+                        continue;
+                    };
+                    if let Some(breaker) = &breaker {
+                        breaker.wait_closed(&request.app).await?;
+                    }
+                    let mut config = machine["config"].clone();
+                    apply(&request, &mut config);
+                    if request.traffic_step.is_some() {
+                        hold(&backend, &request.app, machine, &mut held).await?;
+                    }
+                    jobs::step(&format!("update {}", id), None, async {
+                        // The update restarts the machine, so it's drained first.
+                        drain::drain(drainer.as_ref(), &backend, &request.app, id).await;
+                        let machine = backend
+                            .update_machine(&request.app, id, &json!({ "config": config }), None)
+                            .await
+                            .map_err(|e| format!("Failed to update machine {}: {}", id, e))?;
+                        provenance::index(&store, &request.app, &machine).await;
+                        if let Some(warm_up) = &request.warm_up {
+                            warm_up::run(&backend, &request.app, &machine, warm_up)
+                                .await
+                                .map_err(|e| format!("Machine {} didn't warm up: {}", id, e))?;
+                        }
+                        if request.traffic_step.is_none() {
+                            drain::restore(drainer.as_ref(), &backend, &request.app, id).await;
+                        }
+                        Ok::<_, String>(())
+                    })
+                    .await??;
+                    updated.push(id.to_string());
+                }
+                if let Some(traffic_step) = &request.traffic_step {
+                    shift(
+                        &backend,
+                        &request.app,
+                        &held,
+                        traffic_step,
+                        region.as_deref(),
+                    )
+                    .await?;
+                }
+                Ok::<_, String>(())
+            }
+            .await;
+            if let Err(e) = result {
+                // The machine that failed stays cordoned, as after a failed warm-up.
+                held.retain(|id| updated.contains(id));
+                release(&backend, &request.app, &held).await;
+                return Err(format!("{}; updated {:?}", e, updated));
+            }
+        }
+        if let (Some(rollout), Some(region)) = (&request.rollout, region) {
+            tokio::time::sleep(Duration::from_secs(rollout.soak_secs)).await;
+            if let Some(reason) =
+                environments::unhealthy(&backend, &request.app, Some(&region)).await
+            {
+                return Err(format!(
+                    "Stopped after {}, updated {:?}: {}",
+                    region, updated, reason
+                ));
+            }
+            regions.push(region);
+        }
+    }
+    match request.rollout {
+        Some(_) => Ok(json!({ "updated_machines": updated, "regions": regions })),
+        None => Ok(json!({ "updated_machines": updated })),
+    }
+}
+
+/// The machines split into traffic steps, each taking the new version up by
+/// `step.percent` of them. Without a step they're all one.
+pub fn steps<'a>(step: Option<&TrafficStep>, machines: Vec<&'a Value>) -> Vec<Vec<&'a Value>> {
+    let Some(step) = step else {
+        return vec![machines];
+    };
+    let percent = step.percent.clamp(1, 100) as usize;
+    let mut steps = Vec::new();
+    let (mut taken, mut shifted) = (0, 0);
+    while taken < machines.len() {
+        shifted = (shifted + percent).min(100);
+        let until = (machines.len() * shifted).div_ceil(100).max(taken + 1);
+        steps.push(machines[taken..until].to_vec());
+        taken = until;
+    }
+    steps
+}
+
+/// Cordons the machine for the rest of its traffic step, noting it to put back into
+/// traffic with the step. Machines cordoned through `/v0/machines/cordon` stay out.
+pub async fn hold<B: Backend>(
+    backend: &B,
+    app: &str,
+    machine: &Value,
+    held: &mut Vec<String>,
+) -> Result<(), String> {
+    let id = machine["id"].as_str().unwrap_or_default();
+    if cordon::is_cordoned(machine) {
+        return Ok(());
+    }
+    backend
+        .cordon_machine(app, id)
+        .await
+        .map_err(|e| format!("Failed to cordon machine {}: {}", id, e))?;
+    held.push(id.to_string());
+    Ok(())
+}
+
+/// Puts a step's machines into traffic together, then waits out its pause.
+pub async fn shift<B: Backend>(
+    backend: &B,
+    app: &str,
+    held: &[String],
+    step: &TrafficStep,
+    region: Option<&str>,
+) -> Result<(), String> {
+    for id in held {
+        backend
+            .uncordon_machine(app, id)
+            .await
+            .map_err(|e| format!("Failed to uncordon machine {}: {}", id, e))?;
+    }
+    tokio::time::sleep(Duration::from_secs(step.pause_secs)).await;
+    match environments::unhealthy(backend, app, region).await {
+        Some(reason) => Err(format!("Unhealthy after shifting traffic: {}", reason)),
+        None => Ok(()),
+    }
+}
+
+/// Puts held machines back into traffic when their step won't finish.
+pub async fn release<B: Backend>(backend: &B, app: &str, held: &[String]) {
+    for id in held {
+        if let Err(e) = backend.uncordon_machine(app, id).await {
+            log::warn!("Failed to uncordon machine {}: {}", id, e);
+        }
+    }
+}
+
+/// The machines grouped by region, in the rollout's order: its regions, then the rest by
+/// name.
+pub fn waves<'a>(
+    rollout: &RegionalRollout,
+    machines: Vec<&'a Value>,
+) -> Vec<(Option<String>, Vec<&'a Value>)> {
+    let mut by_region: BTreeMap<String, Vec<&Value>> = BTreeMap::new();
+    for machine in machines {
+        let region = machine["region"].as_str().unwrap_or_default().to_string();
+        by_region.entry(region).or_default().push(machine);
+    }
+    let mut waves = Vec::new();
+    for region in &rollout.regions {
+        if let Some(machines) = by_region.remove(region) {
+            waves.push((Some(region.clone()), machines));
+        }
+    }
+    waves.extend(
+        by_region
+            .into_iter()
+            .map(|(region, machines)| (Some(region), machines)),
+    );
+    waves
+}
+
+/// Queues a deploy, returning its job. Deploys of apps in the same concurrency group run
+/// one after another in the order they were submitted, each after approval if required.
+#[post("/v0/deploys")]
+async fn create_deploy(
+    req: HttpRequest,
+    body: web::Json<DeployRequest>,
+    http_client: web::Data<reqwest::Client>,
+    store: web::Data<Store>,
+    slo: web::Data<SloTracker>,
+    jobs: web::Data<Jobs>,
+    drainer: Option<web::Data<Drainer>>,
+) -> impl Responder {
+    let request = body.into_inner();
+    if request.image.is_none() && request.config.is_none() {
+        return AppError::bad_request("A deploy needs an image or a config").into_response();
+    }
+    if let Some(provenance) = &request.provenance
+        && let Err(e) = provenance::check(provenance)
+    {
+        return AppError::bad_request(e).into_response();
+    }
+    let breaker = req.app_data::<web::Data<DeployBreaker>>().cloned();
+    if let Some(breaker) = &breaker
+        && let Some(response) = breaker.refusal([&request.app]).await
+    {
+        return response;
+    }
+    let group = request
+        .group
+        .clone()
+        .unwrap_or_else(|| jobs.group_for(&request.app));
+    let app = request.app.clone();
+    let needs_approval = request.require_approval || jobs.requires_approval(&group);
+
+    let work: Work = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        let docker = docker.get_ref().clone();
+        Box::new(move || {
+            Box::pin(deploy(
+                docker.clone(),
+                store.clone(),
+                drainer.clone(),
+                breaker.clone(),
+                request.clone(),
+            ))
+        })
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, request.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner())
+            .with_request_checks(&req);
+        Box::new(move || {
+            Box::pin(deploy(
+                client.clone(),
+                store.clone(),
+                drainer.clone(),
+                breaker.clone(),
+                request.clone(),
+            ))
+        })
+    };
+
+    let job = Jobs::submit(
+        &jobs,
+        "deploy",
+        Some(&app),
+        group,
+        needs_approval,
+        jobs::submitter(&req),
+        work,
+    );
+    HttpResponse::Accepted().json(job)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_deploy);
+}
@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpRequest, HttpResponse, Responder, routes, web};
+use chrono::Utc;
+use flyd::models::{RequestPhases, SlowRequest};
+
+use crate::auth;
+use crate::config::SlowRequestsConfig;
+use crate::errors::AppError;
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Auth,
+    Policy,
+    UpstreamConnect,
+}
+
+#[derive(Default)]
+struct Trace {
+    auth: Option<Duration>,
+    policy: Option<Duration>,
+    upstream_calls: u32,
+    upstream_connect: Option<Duration>,
+    upstream_ttfb: Option<Duration>,
+    last_upstream_headers: Option<Instant>,
+}
+
+tokio::task_local! {
+    static TRACE: RefCell<Trace>;
+}
+
+fn add(total: &mut Option<Duration>, elapsed: Duration) {
+    *total = Some(total.unwrap_or_default() + elapsed);
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Attributes `elapsed` to the request being served, if any; background work has no trace.
+pub fn record(phase: Phase, elapsed: Duration) {
+    let _ = TRACE.try_with(|trace| {
+        let mut trace = trace.borrow_mut();
+        match phase {
+            Phase::Auth => add(&mut trace.auth, elapsed),
+            Phase::Policy => add(&mut trace.policy, elapsed),
+            Phase::UpstreamConnect => add(&mut trace.upstream_connect, elapsed),
+        }
+    });
+}
+
+/// Call when an upstream request sent at `started` has its response headers.
+pub fn upstream_headers(started: Instant) {
+    let _ = TRACE.try_with(|trace| {
+        let mut trace = trace.borrow_mut();
+        trace.upstream_calls += 1;
+        add(&mut trace.upstream_ttfb, started.elapsed());
+        trace.last_upstream_headers = Some(Instant::now());
+    });
+}
+
+/// The slowest recent requests. Entries age out after `window_secs` so that one bad hour
+/// doesn't hide everything after it.
+pub struct SlowRequests {
+    config: SlowRequestsConfig,
+    requests: Mutex<Vec<SlowRequest>>,
+}
+
+impl SlowRequests {
+    pub fn new(config: SlowRequestsConfig) -> Self {
+        SlowRequests {
+            config,
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn offer(&self, request: SlowRequest) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let cutoff = Utc::now() - Duration::from_secs(self.config.window_secs);
+        let mut requests = self.requests.lock().unwrap();
+        requests.retain(|kept| kept.at > cutoff);
+
+        if requests.len() < self.config.capacity {
+            requests.push(request);
+            return;
+        }
+        if let Some(fastest) = requests
+            .iter_mut()
+            .min_by(|a, b| a.duration_ms.total_cmp(&b.duration_ms))
+            && fastest.duration_ms < request.duration_ms
+        {
+            *fastest = request;
+        }
+    }
+
+    fn slowest(&self) -> Vec<SlowRequest> {
+        let cutoff = Utc::now() - Duration::from_secs(self.config.window_secs);
+        let mut requests: Vec<SlowRequest> = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.at > cutoff)
+            .cloned()
+            .collect();
+        requests.sort_by(|a, b| b.duration_ms.total_cmp(&a.duration_ms));
+        requests
+    }
+
+    fn admin(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(role) = &self.config.admin_role else {
+            return Err(AppError::forbidden(
+                "Listing slow requests needs slow_requests.admin_role set",
+            )
+            .into_response());
+        };
+        auth::require_role(req, Some(role)).map(|_| ())
+    }
+}
+
+/// Outermost middleware: times the whole request and collects the phases recorded while
+/// serving it.
+pub async fn trace<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let Some(slow_requests) = req.app_data::<web::Data<SlowRequests>>().cloned() else {
+        return next.call(req).await;
+    };
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+
+    let started = Instant::now();
+    let (result, trace) = TRACE
+        .scope(RefCell::new(Trace::default()), async {
+            let result = next.call(req).await;
+            (result, TRACE.with(|trace| trace.take()))
+        })
+        .await;
+
+    let phases = RequestPhases {
+        auth_ms: trace.auth.map(millis),
+        policy_ms: trace.policy.map(millis),
+        upstream_calls: trace.upstream_calls,
+        upstream_connect_ms: trace.upstream_connect.map(millis),
+        upstream_ttfb_ms: trace.upstream_ttfb.map(millis),
+        body_ms: trace
+            .last_upstream_headers
+            .map(|headers| millis(headers.elapsed())),
+    };
+    slow_requests.offer(SlowRequest {
+        at: Utc::now(),
+        method,
+        path,
+        status: match &result {
+            Ok(response) => response.status().as_u16(),
+            Err(e) => e.as_response_error().status_code().as_u16(),
+        },
+        duration_ms: millis(started.elapsed()),
+        phases,
+    });
+    result
+}
+
+/// `reqwest` connector layer recording how long new upstream connections take.
+#[derive(Clone)]
+pub struct ConnectTiming;
+
+impl<S> tower::Layer<S> for ConnectTiming {
+    type Service = TimedConnect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimedConnect(inner)
+    }
+}
+
+#[derive(Clone)]
+pub struct TimedConnect<S>(S);
+
+impl<S, R> tower::Service<R> for TimedConnect<S>
+where
+    S: tower::Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let connecting = self.0.call(request);
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = connecting.await;
+            record(Phase::UpstreamConnect, started.elapsed());
+            result
+        })
+    }
+}
+
+/// Served at `/admin/slow_requests`, and alongside flyd's other admin routes under `/v0`.
+#[routes]
+#[get("/admin/slow_requests")]
+#[get("/v0/admin/slow_requests")]
+async fn list_slow_requests(
+    req: HttpRequest,
+    slow_requests: web::Data<SlowRequests>,
+) -> impl Responder {
+    if let Err(response) = slow_requests.admin(&req) {
+        return response;
+    }
+    HttpResponse::Ok().json(slow_requests.slowest())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_slow_requests);
+}
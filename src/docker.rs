@@ -0,0 +1,487 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use bollard::Docker;
+use bollard::container::LogOutput;
+use bollard::exec::StartExecResults;
+use bollard::models::{ContainerCreateBody, ContainerSummary, ExecConfig, HostConfig};
+use bollard::query_parameters::{
+    CreateContainerOptions, CreateImageOptions, ListContainersOptions, ListVolumesOptions,
+    LogsOptionsBuilder, RemoveContainerOptions,
+};
+use chrono::{DateTime, Utc};
+use flyd::models::LogLine;
+use futures_util::{Stream, TryStreamExt};
+use serde_json::{Map, Value, json};
+
+use crate::backend::Backend;
+
+const APP_LABEL: &str = "flyd.app";
+const NAME_LABEL: &str = "flyd.name";
+/// The machine config the container was created from, as JSON.
+const CONFIG_LABEL: &str = "flyd.config";
+const REGION: &str = "local";
+
+#[derive(Debug)]
+pub enum DockerError {
+    Docker(bollard::errors::Error),
+    NotFound(String),
+    TimedOut,
+}
+
+impl std::fmt::Display for DockerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DockerError::Docker(e) => write!(f, "Docker request failed: {}", e),
+            DockerError::NotFound(id) => write!(f, "No machine {}", id),
+            DockerError::TimedOut => write!(f, "Timed out"),
+        }
+    }
+}
+
+impl From<bollard::errors::Error> for DockerError {
+    fn from(e: bollard::errors::Error) -> Self {
+        DockerError::Docker(e)
+    }
+}
+
+/// Runs machines as containers on the local Docker daemon (honouring `DOCKER_HOST`), so
+/// fleets can be developed and exercised offline. Apps are container labels and every
+/// machine is in region `local`.
+///
+/// Container labels can't change after creation, so metadata set on a running machine is
+/// kept in memory, like the rest of flyd's state. Updating a machine recreates its
+/// container, which gives it a new id.
+#[derive(Clone)]
+pub struct DockerBackend {
+    docker: Docker,
+    metadata: Arc<Mutex<HashMap<String, Map<String, Value>>>>,
+}
+
+fn label_filter(labels: &[String]) -> Option<HashMap<String, Vec<String>>> {
+    Some(HashMap::from([("label".to_string(), labels.to_vec())]))
+}
+
+fn state(container: &ContainerSummary) -> &'static str {
+    match container.state.map(|state| state.to_string()).as_deref() {
+        Some("running") => "started",
+        Some("created") | Some("exited") => "stopped",
+        Some("paused") => "suspended",
+        Some("restarting") => "starting",
+        Some("stopping") => "stopping",
+        Some("removing") => "destroying",
+        _ => "failed",
+    }
+}
+
+/// Pull references without a tag pull every tag, so default to `latest` like `docker pull`.
+fn image_and_tag(image: &str) -> (&str, &str) {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if name.contains(':') || name.contains('@') {
+        (image, "")
+    } else {
+        (image, "latest")
+    }
+}
+
+fn container_body(app_name: &str, name: &str, config: &Value) -> ContainerCreateBody {
+    let strings = |value: &Value| -> Option<Vec<String>> {
+        value.as_array().map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str().map(str::to_string))
+                .collect()
+        })
+    };
+    let env = config["env"].as_object().map(|env| {
+        env.iter()
+            .map(|(key, value)| format!("{}={}", key, value.as_str().unwrap_or_default()))
+            .collect()
+    });
+
+    ContainerCreateBody {
+        image: config["image"].as_str().map(str::to_string),
+        env,
+        cmd: strings(&config["init"]["cmd"]),
+        entrypoint: strings(&config["init"]["entrypoint"]),
+        labels: Some(HashMap::from([
+            (APP_LABEL.to_string(), app_name.to_string()),
+            (NAME_LABEL.to_string(), name.to_string()),
+            (CONFIG_LABEL.to_string(), config.to_string()),
+        ])),
+        host_config: Some(HostConfig {
+            memory: config["guest"]["memory_mb"]
+                .as_i64()
+                .map(|mb| mb * 1024 * 1024),
+            nano_cpus: config["guest"]["cpus"]
+                .as_i64()
+                .map(|cpus| cpus * 1_000_000_000),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+impl DockerBackend {
+    pub fn connect() -> Result<Self, String> {
+        let docker = Docker::connect_with_defaults()
+            .map_err(|e| format!("Failed to connect to Docker: {}", e))?;
+        Ok(DockerBackend {
+            docker,
+            metadata: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// The container's output, line by line; with `follow`, until it exits.
+    pub fn logs(
+        &self,
+        machine_id: &str,
+        follow: bool,
+    ) -> impl Stream<Item = Result<LogLine, DockerError>> + use<> {
+        let options = LogsOptionsBuilder::new()
+            .follow(follow)
+            .stdout(true)
+            .stderr(true)
+            .timestamps(true)
+            .build();
+        let machine_id = machine_id.to_string();
+        self.docker
+            .logs(&machine_id, Some(options))
+            .map_err(DockerError::from)
+            .map_ok(move |output| {
+                let (level, message) = match output {
+                    LogOutput::StdErr { message } => ("stderr", message),
+                    LogOutput::StdOut { message }
+                    | LogOutput::Console { message }
+                    | LogOutput::StdIn { message } => ("stdout", message),
+                };
+                let message = String::from_utf8_lossy(&message);
+                let (timestamp, message) =
+                    message.split_once(' ').unwrap_or(("", message.as_ref()));
+                LogLine {
+                    timestamp: Some(timestamp.to_string()).filter(|t| !t.is_empty()),
+                    message: message.trim_end().to_string(),
+                    level: Some(level.to_string()),
+                    region: Some(REGION.to_string()),
+                    machine_id: Some(machine_id.clone()),
+                }
+            })
+    }
+
+    async fn containers(&self, labels: &[String]) -> Result<Vec<ContainerSummary>, DockerError> {
+        let options = ListContainersOptions {
+            all: true,
+            filters: label_filter(labels),
+            ..Default::default()
+        };
+        Ok(self.docker.list_containers(Some(options)).await?)
+    }
+
+    fn machine(&self, container: &ContainerSummary) -> Value {
+        let id = container.id.clone().unwrap_or_default();
+        let labels = container.labels.clone().unwrap_or_default();
+        let mut config = labels
+            .get(CONFIG_LABEL)
+            .and_then(|config| serde_json::from_str(config).ok())
+            .unwrap_or_else(|| json!({}));
+        if let Some(metadata) = self.metadata.lock().unwrap().get(&id) {
+            for (key, value) in metadata {
+                config["metadata"][key] = value.clone();
+            }
+        }
+
+        json!({
+            "id": id,
+            "name": labels.get(NAME_LABEL),
+            "region": REGION,
+            "state": state(container),
+            "config": config,
+            "created_at": container
+                .created
+                .and_then(|created| DateTime::<Utc>::from_timestamp(created, 0)),
+        })
+    }
+
+    async fn pull(&self, image: &str) -> Result<(), DockerError> {
+        let (image, tag) = image_and_tag(image);
+        log::info!("Pulling {}", image);
+        let options = CreateImageOptions {
+            from_image: Some(image.to_string()),
+            tag: (!tag.is_empty()).then(|| tag.to_string()),
+            ..Default::default()
+        };
+        self.docker
+            .create_image(Some(options), None, None)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
+    /// Creates (pulling the image if needed) and optionally starts a container, returning
+    /// its id.
+    async fn create_container(
+        &self,
+        app_name: &str,
+        name: &str,
+        config: &Value,
+        start: bool,
+    ) -> Result<String, DockerError> {
+        let body = container_body(app_name, name, config);
+        let options = || CreateContainerOptions {
+            name: Some(format!("flyd-{}-{}", app_name, name)),
+            ..Default::default()
+        };
+
+        let created = match self
+            .docker
+            .create_container(Some(options()), body.clone())
+            .await
+        {
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                self.pull(body.image.as_deref().unwrap_or_default()).await?;
+                self.docker.create_container(Some(options()), body).await?
+            }
+            result => result?,
+        };
+
+        if start {
+            self.docker.start_container(&created.id, None).await?;
+        }
+        Ok(created.id)
+    }
+
+    async fn remove_container(&self, machine_id: &str) -> Result<(), DockerError> {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        self.docker
+            .remove_container(machine_id, Some(options))
+            .await?;
+        Ok(())
+    }
+}
+
+impl Backend for DockerBackend {
+    type Error = DockerError;
+
+    async fn list_apps(&self, _org_slug: &str) -> Result<Vec<Value>, Self::Error> {
+        let apps: BTreeSet<String> = self
+            .containers(&[APP_LABEL.to_string()])
+            .await?
+            .into_iter()
+            .filter_map(|container| container.labels?.remove(APP_LABEL))
+            .collect();
+        Ok(apps
+            .into_iter()
+            .map(|name| json!({ "name": name }))
+            .collect())
+    }
+
+    async fn list_machines(&self, app_name: &str) -> Result<Vec<Value>, Self::Error> {
+        let containers = self
+            .containers(&[format!("{}={}", APP_LABEL, app_name)])
+            .await?;
+        Ok(containers
+            .iter()
+            .map(|container| self.machine(container))
+            .collect())
+    }
+
+    /// Containers all run in one region.
+    async fn list_region_machines(
+        &self,
+        app_name: &str,
+        region: &str,
+    ) -> Result<Vec<Value>, Self::Error> {
+        if region != REGION {
+            return Ok(Vec::new());
+        }
+        self.list_machines(app_name).await
+    }
+
+    async fn get_machine(&self, app_name: &str, machine_id: &str) -> Result<Value, Self::Error> {
+        self.list_machines(app_name)
+            .await?
+            .into_iter()
+            .find(|machine| machine["id"] == machine_id)
+            .ok_or_else(|| DockerError::NotFound(machine_id.to_string()))
+    }
+
+    async fn create_machine(&self, app_name: &str, body: &Value) -> Result<Value, Self::Error> {
+        let name = body["name"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("machine-{}", Utc::now().timestamp_millis()));
+        let start = !body["skip_launch"].as_bool().unwrap_or_default();
+        let id = self
+            .create_container(app_name, &name, &body["config"], start)
+            .await?;
+        self.get_machine(app_name, &id).await
+    }
+
+    async fn update_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        body: &Value,
+        _lease_nonce: Option<&str>,
+    ) -> Result<Value, Self::Error> {
+        let current = self.get_machine(app_name, machine_id).await?;
+        let name = current["name"].as_str().unwrap_or(machine_id).to_string();
+
+        self.remove_container(machine_id).await?;
+        let id = self
+            .create_container(app_name, &name, &body["config"], true)
+            .await?;
+
+        {
+            let mut metadata = self.metadata.lock().unwrap();
+            if let Some(overlay) = metadata.remove(machine_id) {
+                metadata.insert(id.clone(), overlay);
+            }
+        }
+
+        self.get_machine(app_name, &id).await
+    }
+
+    async fn start_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        self.docker.start_container(machine_id, None).await?;
+        Ok(())
+    }
+
+    async fn stop_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        self.docker.stop_container(machine_id, None).await?;
+        Ok(())
+    }
+
+    async fn restart_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        self.docker.restart_container(machine_id, None).await?;
+        Ok(())
+    }
+
+    // There's no proxy in front of containers, so nothing to take them out of.
+    async fn cordon_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        Ok(())
+    }
+
+    async fn uncordon_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        Ok(())
+    }
+
+    async fn exec_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        command: &[String],
+        timeout_secs: u64,
+    ) -> Result<Value, Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        let exec = self
+            .docker
+            .create_exec(
+                machine_id,
+                ExecConfig {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(command.to_vec()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        if let StartExecResults::Attached { mut output, .. } =
+            self.docker.start_exec(&exec.id, None).await?
+        {
+            let collect = async {
+                while let Some(chunk) = output.try_next().await? {
+                    match chunk {
+                        LogOutput::StdOut { message } => {
+                            stdout.push_str(&String::from_utf8_lossy(&message))
+                        }
+                        LogOutput::StdErr { message } => {
+                            stderr.push_str(&String::from_utf8_lossy(&message))
+                        }
+                        _ => {}
+                    }
+                }
+                Ok::<(), bollard::errors::Error>(())
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), collect)
+                .await
+                .map_err(|_| DockerError::TimedOut)??;
+        }
+        let inspect = self.docker.inspect_exec(&exec.id).await?;
+        Ok(json!({ "exit_code": inspect.exit_code, "stdout": stdout, "stderr": stderr }))
+    }
+
+    async fn destroy_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        _lease_nonce: Option<&str>,
+    ) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        self.remove_container(machine_id).await?;
+        self.metadata.lock().unwrap().remove(machine_id);
+        Ok(())
+    }
+
+    async fn set_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        self.metadata
+            .lock()
+            .unwrap()
+            .entry(machine_id.to_string())
+            .or_default()
+            .insert(key.to_string(), json!(value));
+        Ok(())
+    }
+
+    async fn delete_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+    ) -> Result<(), Self::Error> {
+        self.get_machine(app_name, machine_id).await?;
+        if let Some(metadata) = self.metadata.lock().unwrap().get_mut(machine_id) {
+            metadata.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn list_volumes(&self, app_name: &str) -> Result<Vec<Value>, Self::Error> {
+        let options = ListVolumesOptions {
+            filters: label_filter(&[format!("{}={}", APP_LABEL, app_name)]),
+        };
+        let volumes = self.docker.list_volumes(Some(options)).await?;
+        Ok(volumes
+            .volumes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|volume| {
+                json!({
+                    "id": volume.name,
+                    "name": volume.name,
+                    "region": REGION,
+                    "size_gb": 0,
+                })
+            })
+            .collect())
+    }
+}
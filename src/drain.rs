@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use reqwest::header::AUTHORIZATION;
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::config::{Config, DrainConfig};
+use crate::events::EventLog;
+use crate::fly_client::authorization_value;
+
+const MACHINE_DRAINED: &str = "machine.drained";
+
+/// Takes machines out of traffic before orchestration stops, destroys or replaces them,
+/// so deploys, scaling and evacuations don't cut off requests in flight.
+pub struct Drainer {
+    config: DrainConfig,
+    http: reqwest::Client,
+    token: Option<String>,
+    events: web::Data<EventLog>,
+}
+
+impl Drainer {
+    /// Returns `None` if `drain.enabled` is off.
+    pub fn from_config(
+        config: &Config,
+        http: reqwest::Client,
+        events: web::Data<EventLog>,
+    ) -> Option<Self> {
+        config.drain.enabled.then(|| Drainer {
+            config: config.drain.clone(),
+            http,
+            token: config.fly_api_token.clone(),
+            events,
+        })
+    }
+
+    /// The machine's open connections, as fly-proxy last reported them.
+    async fn connections(
+        &self,
+        metrics_url: &str,
+        app: &str,
+        machine_id: &str,
+    ) -> Result<u64, String> {
+        let query = format!(
+            "sum(fly_app_concurrency{{app=\"{}\",instance=\"{}\"}})",
+            app, machine_id
+        );
+        let mut request = self
+            .http
+            .get(format!(
+                "{}/api/v1/query",
+                metrics_url.trim_end_matches('/')
+            ))
+            .query(&[("query", query)]);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, authorization_value(token));
+        }
+        let body: Value = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        // No series means no connections were seen at all.
+        match body["data"]["result"][0]["value"][1].as_str() {
+            Some(value) => value
+                .parse::<f64>()
+                .map(|connections| connections.max(0.0).ceil() as u64)
+                .map_err(|e| format!("Unexpected concurrency value {}: {}", value, e)),
+            None => Ok(0),
+        }
+    }
+
+    /// Cordons the machine, then waits until it has no connections left, or for
+    /// `delay_secs` without a metrics source. Never fails: a machine that won't drain is
+    /// stopped once `timeout_secs` is up, as the orchestration needs it gone regardless.
+    pub async fn drain<B: Backend>(&self, backend: &B, app: &str, machine_id: &str) {
+        let started = Instant::now();
+        if let Err(e) = backend.cordon_machine(app, machine_id).await {
+            log::warn!("Failed to cordon machine {}: {}", machine_id, e);
+        }
+
+        let delay = Duration::from_secs(self.config.delay_secs);
+        let mut connections = None;
+        let mut timed_out = false;
+        match &self.config.metrics_url {
+            Some(metrics_url) => {
+                let deadline = started + Duration::from_secs(self.config.timeout_secs);
+                let poll_interval = Duration::from_secs(self.config.poll_interval_secs.max(1));
+                loop {
+                    match self.connections(metrics_url, app, machine_id).await {
+                        Ok(0) => {
+                            connections = Some(0);
+                            break;
+                        }
+                        Ok(open) => {
+                            connections = Some(open);
+                            if Instant::now() >= deadline {
+                                timed_out = true;
+                                break;
+                            }
+                            tokio::time::sleep(poll_interval).await;
+                        }
+                        Err(e) => {
+                            log::warn!(
+                                "Failed to read connections of machine {}, waiting {}s instead: {}",
+                                machine_id,
+                                self.config.delay_secs,
+                                e
+                            );
+                            tokio::time::sleep(delay.saturating_sub(started.elapsed())).await;
+                            break;
+                        }
+                    }
+                }
+            }
+            None => tokio::time::sleep(delay).await,
+        }
+
+        self.events.record(
+            MACHINE_DRAINED,
+            Some(app),
+            Some(machine_id),
+            json!({
+                "waited_ms": started.elapsed().as_millis() as u64,
+                "connections": connections,
+                "timed_out": timed_out,
+            }),
+        );
+    }
+}
+
+/// Drains the machine if draining is on.
+pub async fn drain<B: Backend>(
+    drainer: Option<&web::Data<Drainer>>,
+    backend: &B,
+    app: &str,
+    machine_id: &str,
+) {
+    if let Some(drainer) = drainer {
+        drainer.drain(backend, app, machine_id).await;
+    }
+}
+
+/// Puts a machine drained for an update back into traffic once it's running again.
+pub async fn restore<B: Backend>(
+    drainer: Option<&web::Data<Drainer>>,
+    backend: &B,
+    app: &str,
+    machine_id: &str,
+) {
+    if drainer.is_some()
+        && let Err(e) = backend.uncordon_machine(app, machine_id).await
+    {
+        log::warn!("Failed to uncordon machine {}: {}", machine_id, e);
+    }
+}
@@ -0,0 +1,847 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use flyd::models::{
+    DeployRequest, Environment, EnvironmentAppStatus, EnvironmentDeployRequest, EnvironmentHealth,
+    EnvironmentQuery, EnvironmentStatus, EnvironmentTask, FleetSpec,
+};
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::config::{EnvironmentConfig, StageGateConfig};
+use crate::deploy_breaker::DeployBreaker;
+use crate::deploys;
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::jobs::{self, Jobs, Work};
+use crate::prepare_request;
+use crate::provenance;
+use crate::secret_drift;
+use crate::slo::SloTracker;
+use crate::store::Store;
+use crate::verify::Verifier;
+
+const GATE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The apps and tasks of an environment with each one's dependencies before it, keeping
+/// the configured order (apps, then tasks by name) otherwise.
+fn dependency_order(name: &str, config: &EnvironmentConfig) -> Result<Vec<String>, String> {
+    let mut tasks: Vec<&String> = config.tasks.keys().collect();
+    tasks.sort();
+    let stages: Vec<String> = config.apps.iter().chain(tasks).cloned().collect();
+    for (task, task_config) in &config.tasks {
+        if config.apps.contains(task) {
+            return Err(format!(
+                "Environment {} has a task and an app both named {}",
+                name, task
+            ));
+        }
+        if !config.apps.contains(&task_config.app) {
+            return Err(format!(
+                "Task {} of environment {} runs on {}, which isn't one of its apps",
+                task, name, task_config.app
+            ));
+        }
+    }
+    for (app, dependencies) in &config.depends_on {
+        for listed in std::iter::once(app).chain(dependencies) {
+            if !stages.contains(listed) {
+                return Err(format!(
+                    "Environment {} has a dependency on {}, which isn't one of its apps or tasks",
+                    name, listed
+                ));
+            }
+        }
+    }
+
+    let mut ordered: Vec<String> = Vec::new();
+    while ordered.len() < stages.len() {
+        let next = stages.iter().find(|app| {
+            !ordered.contains(app)
+                && config.depends_on.get(*app).is_none_or(|dependencies| {
+                    dependencies
+                        .iter()
+                        .all(|dependency| ordered.contains(dependency))
+                })
+        });
+        match next {
+            Some(app) => ordered.push(app.clone()),
+            None => {
+                return Err(format!(
+                    "Environment {} has a dependency cycle among its apps and tasks",
+                    name
+                ));
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+/// The configured environments, with their apps already in dependency order.
+pub struct Environments {
+    environments: BTreeMap<String, Environment>,
+    gates: HashMap<String, HashMap<String, StageGateConfig>>,
+}
+
+impl Environments {
+    pub fn new(config: &HashMap<String, EnvironmentConfig>) -> Result<Self, String> {
+        let mut environments = BTreeMap::new();
+        let mut gates = HashMap::new();
+        for (name, environment) in config {
+            let stages = dependency_order(name, environment)?;
+            environments.insert(
+                name.clone(),
+                Environment {
+                    name: name.clone(),
+                    description: environment.description.clone(),
+                    apps: stages
+                        .iter()
+                        .filter(|stage| environment.apps.contains(stage))
+                        .cloned()
+                        .collect(),
+                    depends_on: environment
+                        .depends_on
+                        .iter()
+                        .map(|(app, dependencies)| (app.clone(), dependencies.clone()))
+                        .collect(),
+                    tasks: environment
+                        .tasks
+                        .iter()
+                        .map(|(task, config)| {
+                            let task_spec = EnvironmentTask {
+                                app: config.app.clone(),
+                                command: config.command.clone(),
+                                timeout_secs: config.timeout_secs,
+                            };
+                            (task.clone(), task_spec)
+                        })
+                        .collect(),
+                    stages,
+                },
+            );
+            gates.insert(name.clone(), environment.gates.clone());
+        }
+        Ok(Environments {
+            environments,
+            gates,
+        })
+    }
+
+    fn get(&self, name: &str) -> Result<&Environment, HttpResponse> {
+        self.environments
+            .get(name)
+            .ok_or_else(|| AppError::not_found(format!("No environment {}", name)).into_response())
+    }
+}
+
+async fn app_status<B: Backend>(backend: &B, store: &Store, app: &str) -> EnvironmentAppStatus {
+    let machines = match backend.list_machines(app).await {
+        Ok(machines) => machines,
+        Err(e) => {
+            return EnvironmentAppStatus {
+                app: app.to_string(),
+                status: EnvironmentHealth::Unknown,
+                by_state: BTreeMap::new(),
+                error: Some(e.to_string()),
+                stale_secrets: Vec::new(),
+            };
+        }
+    };
+    let mut by_state = BTreeMap::new();
+    for machine in machines.iter().filter(|machine| fleets::is_live(machine)) {
+        let state = machine["state"].as_str().unwrap_or("unknown").to_string();
+        *by_state.entry(state).or_default() += 1;
+    }
+    let total: usize = by_state.values().sum();
+    let started = by_state.get("started").copied().unwrap_or_default();
+    let status = match (total, started) {
+        (0, _) => EnvironmentHealth::Empty,
+        (_, 0) => EnvironmentHealth::Stopped,
+        _ if started == total => EnvironmentHealth::Running,
+        _ => EnvironmentHealth::Degraded,
+    };
+    EnvironmentAppStatus {
+        app: app.to_string(),
+        status,
+        by_state,
+        error: None,
+        stale_secrets: secret_drift::stale_ids(store, app, &machines).await,
+    }
+}
+
+/// Apps without machines don't count towards the environment's status.
+fn rollup(apps: &[EnvironmentAppStatus]) -> EnvironmentHealth {
+    let mut statuses = apps
+        .iter()
+        .map(|app| app.status)
+        .filter(|status| *status != EnvironmentHealth::Empty);
+    let Some(first) = statuses.next() else {
+        return EnvironmentHealth::Empty;
+    };
+    match first {
+        EnvironmentHealth::Running | EnvironmentHealth::Stopped
+            if statuses.all(|status| status == first) =>
+        {
+            first
+        }
+        _ => EnvironmentHealth::Degraded,
+    }
+}
+
+async fn status<B: Backend>(
+    backend: B,
+    store: &Store,
+    environment: &Environment,
+) -> EnvironmentStatus {
+    let mut apps = Vec::new();
+    for app in &environment.apps {
+        apps.push(app_status(&backend, store, app).await);
+    }
+    EnvironmentStatus {
+        name: environment.name.clone(),
+        status: rollup(&apps),
+        apps,
+    }
+}
+
+/// An environment deploy, with the gates each stage has to pass and the breaker that can
+/// pause it.
+#[derive(Clone)]
+struct Rollout {
+    request: EnvironmentDeployRequest,
+    gates: HashMap<String, StageGateConfig>,
+    breaker: Option<web::Data<DeployBreaker>>,
+}
+
+#[derive(Clone)]
+enum Operation {
+    Start,
+    Stop,
+    Deploy(Rollout),
+}
+
+impl Operation {
+    fn kind(&self) -> &'static str {
+        match self {
+            Operation::Start => "environment.start",
+            Operation::Stop => "environment.stop",
+            Operation::Deploy(_) => "environment.deploy",
+        }
+    }
+}
+
+/// The ids of the app's live machines whose state is (or, with `negate`, isn't) `state`.
+async fn machines_in<B: Backend>(
+    backend: &B,
+    app: &str,
+    state: &str,
+    negate: bool,
+) -> Result<Vec<String>, String> {
+    let machines = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines of {}: {}", app, e))?;
+    Ok(machines
+        .iter()
+        .filter(|machine| fleets::is_live(machine) && (machine["state"] == state) != negate)
+        .filter_map(|machine| machine["id"].as_str().map(str::to_string))
+        .collect())
+}
+
+async fn start_or_stop<B: Backend>(
+    backend: &B,
+    drainer: Option<&web::Data<Drainer>>,
+    app: &str,
+    stop: bool,
+) -> Result<Value, String> {
+    if !stop {
+        let mut started = Vec::new();
+        for id in machines_in(backend, app, "started", true).await? {
+            backend
+                .start_machine(app, &id)
+                .await
+                .map_err(|e| format!("Failed to start machine {}: {}", id, e))?;
+            started.push(id);
+        }
+        return Ok(json!({ "app": app, "started_machines": started }));
+    }
+    let mut stopped = Vec::new();
+    for id in machines_in(backend, app, "started", false).await? {
+        drain::drain(drainer, backend, app, &id).await;
+        backend
+            .stop_machine(app, &id)
+            .await
+            .map_err(|e| format!("Failed to stop machine {}: {}", id, e))?;
+        stopped.push(id);
+    }
+    Ok(json!({ "app": app, "stopped_machines": stopped }))
+}
+
+/// The deploy of `app` this rollout makes, if it deploys it at all.
+fn deploy_request(request: &EnvironmentDeployRequest, app: &str) -> Option<DeployRequest> {
+    let image = request.images.get(app).cloned();
+    if image.is_none() && request.config.is_none() {
+        return None;
+    }
+    Some(DeployRequest {
+        app: app.to_string(),
+        image,
+        config: request.config.clone(),
+        provenance: request.provenance.clone(),
+        group: None,
+        require_approval: false,
+        warm_up: None,
+        rollout: None,
+        traffic_step: None,
+        use_private_api: request.use_private_api,
+    })
+}
+
+/// What a deploy stage changed from: the app's fleet spec and each machine's config.
+struct Deployed {
+    app: String,
+    spec: Option<FleetSpec>,
+    configs: HashMap<String, Value>,
+}
+
+async fn before_deploy<B: Backend>(
+    backend: &B,
+    store: &Store,
+    app: &str,
+) -> Result<Deployed, String> {
+    let spec = fleets::fleet_spec(store, app)
+        .await
+        .map_err(|e| e.to_string())?;
+    let machines = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines of {}: {}", app, e))?;
+    let configs = machines
+        .iter()
+        .filter(|machine| fleets::is_live(machine))
+        .filter_map(|machine| {
+            Some((
+                machine["id"].as_str()?.to_string(),
+                machine["config"].clone(),
+            ))
+        })
+        .collect();
+    Ok(Deployed {
+        app: app.to_string(),
+        spec,
+        configs,
+    })
+}
+
+/// Puts back the config of every machine the stage changed, returning their ids.
+async fn roll_back<B: Backend>(
+    backend: &B,
+    store: &Store,
+    drainer: Option<&web::Data<Drainer>>,
+    deployed: &Deployed,
+) -> Result<Vec<String>, String> {
+    if let Some(spec) = &deployed.spec {
+        fleets::save_fleet_spec(store, spec)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let machines = backend
+        .list_machines(&deployed.app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?;
+    let mut restored = Vec::new();
+    for machine in &machines {
+        let Some(id) = machine["id"].as_str() else {
+            continue;
+        };
+        let Some(config) = deployed.configs.get(id) else {
+            continue;
+        };
+        if &machine["config"] == config {
+            continue;
+        }
+        drain::drain(drainer, backend, &deployed.app, id).await;
+        backend
+            .update_machine(&deployed.app, id, &json!({ "config": config }), None)
+            .await
+            .map_err(|e| format!("Failed to restore machine {}: {}", id, e))?;
+        drain::restore(drainer, backend, &deployed.app, id).await;
+        restored.push(id.to_string());
+    }
+    Ok(restored)
+}
+
+/// Why the app, or its machines in `region`, isn't healthy yet, if it isn't: a machine not
+/// started or with a check not passing.
+pub async fn unhealthy<B: Backend>(backend: &B, app: &str, region: Option<&str>) -> Option<String> {
+    let machines = match backend.list_machines(app).await {
+        Ok(machines) => machines,
+        Err(e) => return Some(format!("Failed to list machines: {}", e)),
+    };
+    for machine in machines.iter().filter(|machine| {
+        fleets::is_live(machine) && region.is_none_or(|region| machine["region"] == region)
+    }) {
+        let id = machine["id"].as_str().unwrap_or_default();
+        if machine["state"] != "started" {
+            return Some(format!("machine {} is {}", id, machine["state"]));
+        }
+        let failing = machine["checks"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|check| check["status"] != "passing");
+        if let Some(check) = failing {
+            return Some(format!(
+                "check {} of machine {} is {}",
+                check["name"], id, check["status"]
+            ));
+        }
+    }
+    None
+}
+
+async fn pass_gate<B: Backend>(
+    backend: &B,
+    app: &str,
+    gate: &StageGateConfig,
+) -> Result<(), String> {
+    if gate.timeout_secs == 0 {
+        return Ok(());
+    }
+    let deadline = Instant::now() + Duration::from_secs(gate.timeout_secs);
+    while let Some(reason) = unhealthy(backend, app, None).await {
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "Not healthy after {}s: {}",
+                gate.timeout_secs, reason
+            ));
+        }
+        tokio::time::sleep(GATE_POLL_INTERVAL).await;
+    }
+    if gate.soak_secs > 0 {
+        tokio::time::sleep(Duration::from_secs(gate.soak_secs)).await;
+        if let Some(reason) = unhealthy(backend, app, None).await {
+            return Err(format!(
+                "Unhealthy during the {}s soak: {}",
+                gate.soak_secs, reason
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the task on a copy of one of its app's machines, with the deploy applied and no
+/// services so it takes no traffic, and waits for it to exit successfully.
+async fn run_task<B: Backend>(
+    backend: &B,
+    task: &EnvironmentTask,
+    deploy: &DeployRequest,
+) -> Result<Value, String> {
+    let machines = backend
+        .list_machines(&task.app)
+        .await
+        .map_err(|e| format!("Failed to list machines of {}: {}", task.app, e))?;
+    let Some(template) = machines.iter().find(|machine| fleets::is_live(machine)) else {
+        return Err(format!("{} has no machine to run the task from", task.app));
+    };
+    let mut config = template["config"].clone();
+    deploys::apply(deploy, &mut config);
+    config["init"]["cmd"] = json!(task.command);
+    config["restart"] = json!({ "policy": "no" });
+    config["services"] = json!([]);
+    let machine = backend
+        .create_machine(
+            &task.app,
+            &json!({ "region": template["region"], "config": config }),
+        )
+        .await
+        .map_err(|e| format!("Failed to create task machine: {}", e))?;
+    let id = machine["id"].as_str().unwrap_or_default();
+    await_exit(backend, &task.app, id, task.timeout_secs).await
+}
+
+/// Waits for a task machine to exit, destroys it, and fails unless it exited with 0.
+pub async fn await_exit<B: Backend>(
+    backend: &B,
+    app: &str,
+    id: &str,
+    timeout_secs: u64,
+) -> Result<Value, String> {
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+    let finished = loop {
+        tokio::time::sleep(GATE_POLL_INTERVAL).await;
+        match backend.get_machine(app, id).await {
+            Ok(machine) if matches!(machine["state"].as_str(), Some("stopped" | "failed")) => {
+                break Ok(machine);
+            }
+            Ok(_) if Instant::now() >= deadline => {
+                break Err(format!("Task didn't finish within {}s", timeout_secs));
+            }
+            Ok(_) => {}
+            Err(e) => break Err(format!("Failed to get task machine {}: {}", id, e)),
+        }
+    };
+    if let Err(e) = backend.destroy_machine(app, id, None).await {
+        log::warn!("Failed to destroy task machine {}: {}", id, e);
+    }
+
+    // Without an exit event (e.g. on Docker), a stopped task is taken to have succeeded.
+    let machine = finished?;
+    let exit_code = machine["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|event| event["type"] == "exit")
+        .and_then(|event| event["request"]["exit_event"]["exit_code"].as_i64());
+    match exit_code {
+        Some(code) if code != 0 => Err(format!("Task exited with {}", code)),
+        _ => Ok(json!({ "machine_id": id, "exit_code": exit_code })),
+    }
+}
+
+/// Deploys the environment stage by stage, each one's gate passed before the next starts.
+/// When a stage fails, every deployed stage, the failed one included, is rolled back in
+/// reverse; tasks that already ran can't be undone.
+async fn rollout<B: Backend + Clone>(
+    backend: B,
+    store: web::Data<Store>,
+    drainer: Option<web::Data<Drainer>>,
+    verifier: &Verifier,
+    environment: &Environment,
+    rollout: &Rollout,
+) -> Result<Value, String> {
+    let Rollout {
+        request,
+        gates,
+        breaker,
+    } = rollout;
+    let mut stages = Vec::new();
+    let mut deployed: Vec<Deployed> = Vec::new();
+    for stage in &environment.stages {
+        let task = environment.tasks.get(stage);
+        let app = task.map_or(stage.as_str(), |task| &task.app);
+        let Some(deploy) = deploy_request(request, app) else {
+            continue;
+        };
+
+        let result = match task {
+            Some(task) => run_task(&backend, task, &deploy).await,
+            None => match before_deploy(&backend, &store, app).await {
+                Ok(before) => {
+                    deployed.push(before);
+                    let gate = gates
+                        .get(stage)
+                        .or_else(|| gates.get("*"))
+                        .cloned()
+                        .unwrap_or_default();
+                    let deployed = deploys::deploy(
+                        backend.clone(),
+                        store.clone(),
+                        drainer.clone(),
+                        breaker.clone(),
+                        deploy,
+                    );
+                    match deployed.await {
+                        Ok(result) => match pass_gate(&backend, app, &gate).await {
+                            Ok(()) => verifier
+                                .run(&backend, app, &gate.verify)
+                                .await
+                                .map(|()| result),
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            },
+        };
+        match result {
+            Ok(mut result) => {
+                result["stage"] = json!(stage);
+                stages.push(result);
+            }
+            Err(e) => {
+                let mut rolled_back = Vec::new();
+                for stage in deployed.iter().rev() {
+                    let outcome = match roll_back(&backend, &store, drainer.as_ref(), stage).await {
+                        Ok(restored) => json!({ "app": stage.app, "restored_machines": restored }),
+                        Err(e) => json!({ "app": stage.app, "error": e }),
+                    };
+                    rolled_back.push(outcome);
+                }
+                jobs::record_partial(json!({ "stages": stages, "rolled_back": rolled_back }));
+                return Err(format!(
+                    "Stage {} failed: {}; rolled back: {}",
+                    stage,
+                    e,
+                    Value::Array(rolled_back)
+                ));
+            }
+        }
+    }
+    Ok(json!({ "stages": stages }))
+}
+
+/// Runs the operation app by app: dependencies first, or last when stopping, so nothing
+/// runs without what it needs. Stops at the first app that fails.
+async fn operate<B: Backend + Clone>(
+    backend: B,
+    store: web::Data<Store>,
+    drainer: Option<web::Data<Drainer>>,
+    verifier: web::Data<Verifier>,
+    environment: Environment,
+    operation: Operation,
+) -> Result<Value, String> {
+    let stop = match &operation {
+        Operation::Deploy(deploy) => {
+            return rollout(backend, store, drainer, &verifier, &environment, deploy).await;
+        }
+        Operation::Start => false,
+        Operation::Stop => true,
+    };
+    let mut apps: Vec<&String> = environment.apps.iter().collect();
+    if stop {
+        apps.reverse();
+    }
+    let mut done = Vec::new();
+    for app in apps {
+        match start_or_stop(&backend, drainer.as_ref(), app, stop).await {
+            Ok(result) => done.push(result),
+            Err(e) => {
+                jobs::record_partial(json!({ "apps": done }));
+                return Err(format!(
+                    "{} failed on {}: {}; done before it: {}",
+                    operation.kind(),
+                    app,
+                    e,
+                    Value::Array(done)
+                ));
+            }
+        }
+    }
+    Ok(json!({ "apps": done }))
+}
+
+/// Queues the operation in the concurrency group named after the environment, so
+/// operations on one environment run one at a time and `[jobs.groups.<environment>]`
+/// can require approval for them.
+fn submit(
+    req: &HttpRequest,
+    environment: &Environment,
+    operation: Operation,
+    require_approval: bool,
+    use_private_api: bool,
+    store: web::Data<Store>,
+    jobs: &web::Data<Jobs>,
+) -> HttpResponse {
+    let group = environment.name.clone();
+    let needs_approval = require_approval || jobs.requires_approval(&group);
+    let kind = operation.kind();
+    let drainer = req.app_data::<web::Data<Drainer>>().cloned();
+    let Some(verifier) = req.app_data::<web::Data<Verifier>>().cloned() else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    let environment = environment.clone();
+
+    let work: Work = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        let docker = docker.get_ref().clone();
+        Box::new(move || {
+            Box::pin(operate(
+                docker.clone(),
+                store.clone(),
+                drainer.clone(),
+                verifier.clone(),
+                environment.clone(),
+                operation.clone(),
+            ))
+        })
+    } else {
+        let (headers, api_hostname) = match prepare_request(req, use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let Some(http_client) = req.app_data::<web::Data<reqwest::Client>>() else {
+            return HttpResponse::InternalServerError().finish();
+        };
+        let mut client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_request_checks(req);
+        if let Some(slo) = req.app_data::<web::Data<SloTracker>>() {
+            client = client.with_slo(slo.clone().into_inner());
+        }
+        Box::new(move || {
+            Box::pin(operate(
+                client.clone(),
+                store.clone(),
+                drainer.clone(),
+                verifier.clone(),
+                environment.clone(),
+                operation.clone(),
+            ))
+        })
+    };
+
+    let job = Jobs::submit(
+        jobs,
+        kind,
+        None,
+        group,
+        needs_approval,
+        jobs::submitter(req),
+        work,
+    );
+    HttpResponse::Accepted().json(job)
+}
+
+#[get("/v0/environments")]
+async fn list_environments(environments: web::Data<Environments>) -> impl Responder {
+    let listed: Vec<&Environment> = environments.environments.values().collect();
+    HttpResponse::Ok().json(listed)
+}
+
+/// The environment's status rolled up from its apps' machines, with those running stale
+/// secrets.
+#[get("/v0/environments/{name}")]
+async fn get_environment(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<EnvironmentQuery>,
+    environments: web::Data<Environments>,
+    store: web::Data<Store>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let environment = match environments.get(&path) {
+        Ok(environment) => environment,
+        Err(response) => return response,
+    };
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return HttpResponse::Ok()
+            .json(status(docker.get_ref().clone(), &store, environment).await);
+    }
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    HttpResponse::Ok().json(status(client, &store, environment).await)
+}
+
+#[post("/v0/environments/{name}/start")]
+async fn start_environment(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<EnvironmentQuery>,
+    environments: web::Data<Environments>,
+    store: web::Data<Store>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    match environments.get(&path) {
+        Ok(environment) => submit(
+            &req,
+            environment,
+            Operation::Start,
+            false,
+            query.use_private_api,
+            store,
+            &jobs,
+        ),
+        Err(response) => response,
+    }
+}
+
+#[post("/v0/environments/{name}/stop")]
+async fn stop_environment(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<EnvironmentQuery>,
+    environments: web::Data<Environments>,
+    store: web::Data<Store>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    match environments.get(&path) {
+        Ok(environment) => submit(
+            &req,
+            environment,
+            Operation::Stop,
+            false,
+            query.use_private_api,
+            store,
+            &jobs,
+        ),
+        Err(response) => response,
+    }
+}
+
+#[post("/v0/environments/{name}/deploy")]
+async fn deploy_environment(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<EnvironmentDeployRequest>,
+    environments: web::Data<Environments>,
+    store: web::Data<Store>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    let environment = match environments.get(&path) {
+        Ok(environment) => environment,
+        Err(response) => return response,
+    };
+    let request = body.into_inner();
+    if request.images.is_empty() && request.config.is_none() {
+        return AppError::bad_request("An environment deploy needs images or a config")
+            .into_response();
+    }
+    if let Some(app) = request
+        .images
+        .keys()
+        .find(|app| !environment.apps.contains(app))
+    {
+        return AppError::bad_request(format!(
+            "{} isn't part of environment {}",
+            app, environment.name
+        ))
+        .into_response();
+    }
+    if let Some(provenance) = &request.provenance
+        && let Err(e) = provenance::check(provenance)
+    {
+        return AppError::bad_request(e).into_response();
+    }
+    let breaker = req.app_data::<web::Data<DeployBreaker>>().cloned();
+    if let Some(breaker) = &breaker
+        && let Some(response) = breaker.refusal(&environment.apps).await
+    {
+        return response;
+    }
+    let (require_approval, use_private_api) = (request.require_approval, request.use_private_api);
+    let gates = environments
+        .gates
+        .get(&environment.name)
+        .cloned()
+        .unwrap_or_default();
+    submit(
+        &req,
+        environment,
+        Operation::Deploy(Rollout {
+            request,
+            gates,
+            breaker,
+        }),
+        require_approval,
+        use_private_api,
+        store,
+        &jobs,
+    )
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_environments)
+        .service(get_environment)
+        .service(start_environment)
+        .service(stop_environment)
+        .service(deploy_environment);
+}
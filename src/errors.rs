@@ -0,0 +1,207 @@
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpRequest, HttpResponse, ResponseError};
+use flyd::models::{ErrorBody, ErrorDetail, FieldError};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// A failed request, answered with `{ "error": { "code", "message", "upstream_status",
+/// "request_id" } }`. `upstream_status` is the Machines API's status when the error came
+/// from Fly, which is also the status flyd answers with.
+#[derive(Debug)]
+pub struct AppError {
+    status: StatusCode,
+    code: Option<&'static str>,
+    message: String,
+    upstream_status: Option<u16>,
+    fields: Vec<FieldError>,
+    retry_after: Option<Duration>,
+}
+
+impl AppError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        AppError {
+            status,
+            code: None,
+            message: message.into(),
+            upstream_status: None,
+            fields: Vec::new(),
+            retry_after: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::FORBIDDEN, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::CONFLICT, message)
+    }
+
+    pub fn unprocessable(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::UNPROCESSABLE_ENTITY, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    pub fn bad_gateway(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::BAD_GATEWAY, message)
+    }
+
+    pub fn service_unavailable(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::SERVICE_UNAVAILABLE, message)
+    }
+
+    /// A non-success answer from the Machines API, passed through with its status. Fly's
+    /// `{"error": "..."}` bodies become the message.
+    pub fn upstream(status: u16, body: &str) -> Self {
+        let message = serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|json| json["error"].as_str().map(str::to_string))
+            .unwrap_or_else(|| body.trim().to_string());
+        AppError {
+            status: StatusCode::from_u16(status)
+                .ok()
+                .filter(|status| status.is_client_error() || status.is_server_error())
+                .unwrap_or(StatusCode::BAD_GATEWAY),
+            code: None,
+            message,
+            upstream_status: Some(status),
+            fields: Vec::new(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_fields(mut self, fields: Vec<FieldError>) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Sent as `Retry-After`, rounded up to whole seconds.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// The status's reason in snake case unless a code was given, e.g. `not_found`.
+    pub fn code(&self) -> String {
+        match self.code {
+            Some(code) => code.to_string(),
+            None => self
+                .status
+                .canonical_reason()
+                .unwrap_or("error")
+                .to_lowercase()
+                .replace([' ', '-'], "_"),
+        }
+    }
+
+    fn body(&self, request_id: Option<String>) -> ErrorBody {
+        ErrorBody {
+            error: ErrorDetail {
+                code: self.code(),
+                message: if self.message.is_empty() {
+                    self.status
+                        .canonical_reason()
+                        .unwrap_or_default()
+                        .to_string()
+                } else {
+                    self.message.clone()
+                },
+                upstream_status: self.upstream_status,
+                request_id,
+                fields: self.fields.clone(),
+            },
+        }
+    }
+
+    /// The error as a response that still carries it, so `request_id` can fill in the id.
+    pub fn into_response(self) -> HttpResponse {
+        HttpResponse::from_error(self)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(retry_after) = self.retry_after {
+            response.insert_header((RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string()));
+        }
+        response.json(self.body(None))
+    }
+}
+
+/// Rejected `Json`, `Query` and `Path` extractors, in the same shape as every other error.
+pub fn extractor_error<E: ResponseError>(e: E, _req: &HttpRequest) -> Error {
+    AppError::new(e.status_code(), e.to_string()).into()
+}
+
+fn generate_id() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    hex::encode(bytes)
+}
+
+/// Gives every request an id, the caller's `X-Request-Id` when it sent a usable one, and
+/// returns it in `X-Request-Id` and in the body of any `AppError`.
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(generate_id);
+    let mut response = next.call(req).await?.map_into_boxed_body();
+    let body = response
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<AppError>())
+        .map(|e| e.body(Some(id.clone())));
+    if let Some(body) = body {
+        let body = serde_json::to_vec(&body).unwrap_or_default();
+        response = response.map_body(|_, _| BoxBody::new(body));
+    }
+    if let Ok(value) = HeaderValue::try_from(id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
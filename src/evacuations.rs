@@ -0,0 +1,290 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, post, web};
+use chrono::Utc;
+use flyd::models::{EvacuationQuery, FleetMode, FleetSpec, RegionBlock};
+use serde_json::{Value, json};
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::capacity::CapacityMap;
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::jobs::{self, Jobs, Work};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+/// Moves every live machine of `app` out of `region`, each to whichever of `targets` has
+/// the fewest of the app's machines, so the app stays spread evenly. Machines with
+/// volumes are left alone, as their data can't follow them.
+///
+/// A managed fleet's spec moves too. An enforced fleet's machines are only destroyed; the
+/// reconciler recreates them from the spec in their new regions.
+async fn evacuate<B: Backend>(
+    backend: B,
+    store: web::Data<Store>,
+    drainer: Option<web::Data<Drainer>>,
+    app: String,
+    region: String,
+    targets: Vec<String>,
+) -> Result<Value, String> {
+    let machines: Vec<Value> = backend
+        .list_machines(&app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?
+        .into_iter()
+        .filter(fleets::is_live)
+        .collect();
+    let mut counts: Vec<(String, usize)> = targets
+        .iter()
+        .map(|target| {
+            let count = machines
+                .iter()
+                .filter(|machine| machine["region"] == target.as_str())
+                .count();
+            (target.clone(), count)
+        })
+        .collect();
+
+    let mut spec = fleets::fleet_spec(&store, &app)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut moved = Vec::new();
+    let mut skipped = Vec::new();
+    let mut failed = Vec::new();
+    for machine in machines
+        .iter()
+        .filter(|machine| machine["region"] == region.as_str())
+    {
+        let Some(id) = machine["id"].as_str() else {
+            continue;
+        };
+        if machine["config"]["mounts"]
+            .as_array()
+            .is_some_and(|mounts| !mounts.is_empty())
+        {
+            skipped.push(json!({ "machine_id": id, "reason": "has a volume" }));
+            continue;
+        }
+        // The first of the least used targets, so ties go by the order given.
+        let Some(slot) = counts.iter_mut().min_by_key(|(_, count)| *count) else {
+            break;
+        };
+        slot.1 += 1;
+        let target = slot.0.clone();
+
+        let name = machine["name"].as_str().unwrap_or_default();
+        let managed = spec.as_mut().filter(|spec| {
+            spec.machines
+                .iter()
+                .any(|machine_spec| machine_spec.name == name)
+        });
+        let result = match managed {
+            Some(spec) => {
+                for machine_spec in &mut spec.machines {
+                    if machine_spec.name == name {
+                        machine_spec.region = target.clone();
+                    }
+                }
+                replace_managed(
+                    &backend,
+                    &store,
+                    drainer.as_ref(),
+                    spec,
+                    &app,
+                    machine,
+                    &target,
+                )
+                .await
+            }
+            None => replace(&backend, drainer.as_ref(), &app, machine, &target).await,
+        };
+        match result {
+            Ok(new_id) => moved.push(json!({
+                "machine_id": id,
+                "new_machine_id": new_id,
+                "region": target,
+            })),
+            Err(e) => failed.push(json!({ "machine_id": id, "error": e })),
+        }
+    }
+
+    if !failed.is_empty() {
+        jobs::record_partial(json!({ "moved": moved, "skipped": skipped, "failed": failed }));
+        return Err(format!(
+            "Failed to move {} of {} machines out of {}: {}",
+            failed.len(),
+            failed.len() + moved.len(),
+            region,
+            Value::Array(failed)
+        ));
+    }
+    Ok(json!({ "moved": moved, "skipped": skipped }))
+}
+
+/// Creates the machine's replacement in `target`, then destroys it.
+async fn replace<B: Backend>(
+    backend: &B,
+    drainer: Option<&web::Data<Drainer>>,
+    app: &str,
+    machine: &Value,
+    target: &str,
+) -> Result<Option<String>, String> {
+    let id = machine["id"].as_str().unwrap_or_default();
+    // Left unnamed so Fly names it; the original still holds the name for now.
+    let created = backend
+        .create_machine(
+            app,
+            &json!({ "region": target, "config": machine["config"] }),
+        )
+        .await
+        .map_err(|e| format!("Failed to create replacement: {}", e))?;
+    drain::drain(drainer, backend, app, id).await;
+    backend
+        .destroy_machine(app, id, None)
+        .await
+        .map_err(|e| format!("Failed to destroy: {}", e))?;
+    Ok(created["id"].as_str().map(str::to_string))
+}
+
+/// Moves a fleet machine: saves the spec with its new region, then destroys it, then
+/// (unless the reconciler will) recreates it under the same name.
+async fn replace_managed<B: Backend>(
+    backend: &B,
+    store: &Store,
+    drainer: Option<&web::Data<Drainer>>,
+    spec: &FleetSpec,
+    app: &str,
+    machine: &Value,
+    target: &str,
+) -> Result<Option<String>, String> {
+    fleets::save_fleet_spec(store, spec)
+        .await
+        .map_err(|e| e.to_string())?;
+    let id = machine["id"].as_str().unwrap_or_default();
+    drain::drain(drainer, backend, app, id).await;
+    backend
+        .destroy_machine(app, id, None)
+        .await
+        .map_err(|e| format!("Failed to destroy: {}", e))?;
+    if spec.mode == FleetMode::Enforce {
+        return Ok(None);
+    }
+    let created = backend
+        .create_machine(
+            app,
+            &json!({ "name": machine["name"], "region": target, "config": machine["config"] }),
+        )
+        .await
+        .map_err(|e| format!("Failed to recreate: {}", e))?;
+    Ok(created["id"].as_str().map(str::to_string))
+}
+
+/// Queues moving every machine of an app out of a region, returning the job. With
+/// `block`, new machines can't be placed there from now until the block is lifted.
+#[post("/v0/apps/{app}/evacuate")]
+async fn evacuate_app(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<EvacuationQuery>,
+    store: web::Data<Store>,
+    capacity: web::Data<CapacityMap>,
+    jobs: web::Data<Jobs>,
+    http_client: web::Data<reqwest::Client>,
+) -> impl Responder {
+    let app = path.into_inner();
+    let region = query.region.clone();
+    let mut targets: Vec<String> = match &query.to {
+        Some(to) => to
+            .split(',')
+            .map(str::trim)
+            .filter(|target| !target.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => capacity.fallbacks(&region),
+    };
+    targets.retain(|target| target != &region);
+    let mut usable = Vec::new();
+    for target in targets {
+        if !capacity.is_blocked(&app, &target).await {
+            usable.push(target);
+        }
+    }
+    if usable.is_empty() {
+        return AppError::bad_request(format!(
+            "No region to move machines to: pass to= or set capacity.fallback_regions for {}",
+            region
+        ))
+        .into_response();
+    }
+
+    let block = query.block.then(|| RegionBlock {
+        app: app.clone(),
+        region: region.clone(),
+        reason: "evacuated".to_string(),
+        blocked_by: req
+            .extensions()
+            .get::<Identity>()
+            .map(|identity| identity.subject.clone()),
+        blocked_at: Utc::now(),
+    });
+    let (group, job_app) = (jobs.group_for(&app), app.clone());
+    let needs_approval = jobs.requires_approval(&group);
+    let drainer = req.app_data::<web::Data<Drainer>>().cloned();
+    let work: Work = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        let docker = docker.get_ref().clone();
+        Box::new(move || {
+            Box::pin(evacuate(
+                docker.clone(),
+                store.clone(),
+                drainer.clone(),
+                app.clone(),
+                region.clone(),
+                usable.clone(),
+            ))
+        })
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let mut client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_request_checks(&req);
+        if let Some(slo) = req.app_data::<web::Data<SloTracker>>() {
+            client = client.with_slo(slo.clone().into_inner());
+        }
+        Box::new(move || {
+            Box::pin(evacuate(
+                client.clone(),
+                store.clone(),
+                drainer.clone(),
+                app.clone(),
+                region.clone(),
+                usable.clone(),
+            ))
+        })
+    };
+
+    // Only once the request is known to be good, so a refused one blocks nothing.
+    if let Some(block) = &block
+        && let Err(e) = capacity.block(block).await
+    {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    let job = Jobs::submit(
+        &jobs,
+        "evacuate",
+        Some(&job_app),
+        group,
+        needs_approval,
+        jobs::submitter(&req),
+        work,
+    );
+    HttpResponse::Accepted().json(job)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(evacuate_app);
+}
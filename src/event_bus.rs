@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use flyd::models::Event;
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::client::{Client, ClientBuilder, Credentials, SaslConfig};
+use rskafka::record::Record;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::config::{EventBusConfig, EventBusKind, EventFormat};
+use crate::notify;
+
+fn encode(event: &Event, format: EventFormat) -> Result<Vec<u8>, String> {
+    match format {
+        EventFormat::Json => serde_json::to_vec(event).map_err(|e| e.to_string()),
+        EventFormat::Cloudevents => {
+            let source = match &event.app {
+                Some(app) => format!("flyd/apps/{}", app),
+                None => "flyd".to_string(),
+            };
+            let envelope = json!({
+                "specversion": "1.0",
+                "id": event.id.to_string(),
+                "source": source,
+                "type": event.kind,
+                "time": event.at,
+                "subject": event.machine_id,
+                "datacontenttype": "application/json",
+                "data": event.detail,
+            });
+            serde_json::to_vec(&envelope).map_err(|e| e.to_string())
+        }
+        EventFormat::Msgpack => rmp_serde::to_vec_named(event).map_err(|e| e.to_string()),
+    }
+}
+
+fn topic(template: &str, event: &Event) -> String {
+    template
+        .replace("{kind}", &event.kind)
+        .replace("{app}", event.app.as_deref().unwrap_or("-"))
+}
+
+/// A Kafka client with a producer per partition of each topic written to so far.
+struct Kafka {
+    client: Client,
+    partitions: HashMap<String, Vec<PartitionClient>>,
+}
+
+impl Kafka {
+    async fn connect(config: &EventBusConfig) -> Result<Self, String> {
+        let brokers = config
+            .url
+            .split(',')
+            .map(|broker| broker.trim().to_string())
+            .collect();
+        let mut builder = ClientBuilder::new(brokers);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.sasl_config(SaslConfig::Plain(Credentials::new(
+                username.clone(),
+                password.clone(),
+            )));
+        }
+        let client = builder.build().await.map_err(|e| e.to_string())?;
+        Ok(Kafka {
+            client,
+            partitions: HashMap::new(),
+        })
+    }
+
+    async fn partitions(&mut self, topic: &str) -> Result<&[PartitionClient], String> {
+        if !self.partitions.contains_key(topic) {
+            let topics = self.client.list_topics().await.map_err(|e| e.to_string())?;
+            let Some(found) = topics.into_iter().find(|found| found.name == topic) else {
+                return Err(format!("No Kafka topic {}", topic));
+            };
+            let mut partitions = Vec::new();
+            for partition in found.partitions {
+                let client = self
+                    .client
+                    .partition_client(topic, partition, UnknownTopicHandling::Error)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                partitions.push(client);
+            }
+            self.partitions.insert(topic.to_string(), partitions);
+        }
+        Ok(&self.partitions[topic])
+    }
+
+    async fn produce(
+        &mut self,
+        topic: &str,
+        key: &str,
+        value: Vec<u8>,
+        event: &Event,
+    ) -> Result<(), String> {
+        let partitions = self.partitions(topic).await?;
+        if partitions.is_empty() {
+            return Err(format!("Kafka topic {} has no partitions", topic));
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let partition = &partitions[hasher.finish() as usize % partitions.len()];
+        let record = Record {
+            key: Some(key.as_bytes().to_vec()),
+            value: Some(value),
+            headers: BTreeMap::from([("kind".to_string(), event.kind.as_bytes().to_vec())]),
+            timestamp: event.at,
+        };
+        partition
+            .produce(vec![record], Compression::NoCompression)
+            .await
+            .map(drop)
+            .map_err(|e| e.to_string())
+    }
+}
+
+enum Connection {
+    Nats(async_nats::Client),
+    Kafka(Option<Kafka>),
+}
+
+/// Publishes every matching event to one bus. A NATS client reconnects on its own; a
+/// Kafka client that fails is rebuilt for the next event. Events published while the bus
+/// is unreachable are dropped, not retried.
+pub async fn run(config: EventBusConfig, mut events: broadcast::Receiver<Event>) {
+    let mut connection = match config.kind {
+        EventBusKind::Nats => {
+            let options = match (&config.username, &config.password) {
+                (Some(username), Some(password)) => {
+                    async_nats::ConnectOptions::with_user_and_password(
+                        username.clone(),
+                        password.clone(),
+                    )
+                }
+                _ => async_nats::ConnectOptions::new(),
+            };
+            match options
+                .retry_on_initial_connect()
+                .connect(config.url.as_str())
+                .await
+            {
+                Ok(client) => Connection::Nats(client),
+                Err(e) => {
+                    log::error!("Failed to connect to NATS at {}: {}", config.url, e);
+                    return;
+                }
+            }
+        }
+        EventBusKind::Kafka => Connection::Kafka(None),
+    };
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!(
+                    "Event publisher for {} fell behind, skipped {} events",
+                    config.url,
+                    skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !notify::matches(&config.events, &event.kind) {
+            continue;
+        }
+        let payload = match encode(&event, config.format) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Failed to encode event {}: {}", event.id, e);
+                continue;
+            }
+        };
+        let topic = topic(&config.topic, &event);
+
+        let result = match &mut connection {
+            Connection::Nats(client) => client
+                .publish(topic.clone(), payload.into())
+                .await
+                .map_err(|e| e.to_string()),
+            Connection::Kafka(kafka) => {
+                if kafka.is_none() {
+                    *kafka = Kafka::connect(&config)
+                        .await
+                        .inspect_err(|e| {
+                            log::warn!("Failed to connect to Kafka at {}: {}", config.url, e)
+                        })
+                        .ok();
+                }
+                match kafka {
+                    Some(client) => {
+                        let key = event.app.as_deref().unwrap_or(&event.kind);
+                        let result = client.produce(&topic, key, payload, &event).await;
+                        if result.is_err() {
+                            *kafka = None;
+                        }
+                        result
+                    }
+                    None => continue,
+                }
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to publish event {} to {}: {}", event.id, topic, e);
+        }
+    }
+}
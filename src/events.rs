@@ -0,0 +1,263 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::{Event, EventsQuery};
+use tokio::sync::broadcast;
+
+use crate::auth;
+use crate::config::{Config, EventsConfig};
+use crate::errors::AppError;
+use crate::namespaces;
+use crate::store::{Store, StoreError};
+use crate::write_queue::{Write, WriteQueue};
+
+const EVENT_LOG_CAPACITY: usize = 1000;
+const EVENTS: &str = "events";
+
+/// Recent flyd events kept in memory and fanned out to subscribers such as the notifier.
+pub struct EventLog {
+    events: Mutex<VecDeque<Event>>,
+    next_id: AtomicU64,
+    sender: broadcast::Sender<Event>,
+    persist: WriteQueue,
+}
+
+impl EventLog {
+    /// Events are also written to the store's `events` collection through `persist`,
+    /// outliving the in-memory ring buffer.
+    pub fn new(persist: WriteQueue) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_LOG_CAPACITY);
+        EventLog {
+            events: Mutex::new(VecDeque::with_capacity(EVENT_LOG_CAPACITY)),
+            next_id: AtomicU64::new(1),
+            sender,
+            persist,
+        }
+    }
+
+    /// Numbers new events after the ones already in the store, so a persistent store's
+    /// history isn't overwritten after a restart, and brings back the most recent.
+    pub async fn load(&self, store: &Store) -> Result<(), StoreError> {
+        let mut persisted = store.list::<Event>(EVENTS).await?;
+        persisted.sort_by_key(|event| event.id);
+        if let Some(last) = persisted.last() {
+            self.next_id.fetch_max(last.id + 1, Ordering::Relaxed);
+        }
+        let recent = persisted.len().saturating_sub(EVENT_LOG_CAPACITY);
+        let mut events = self.events.lock().unwrap();
+        events.extend(persisted.drain(recent..));
+        events.truncate(EVENT_LOG_CAPACITY);
+        Ok(())
+    }
+
+    pub fn record(
+        &self,
+        kind: &str,
+        app: Option<&str>,
+        machine_id: Option<&str>,
+        detail: serde_json::Value,
+    ) -> Event {
+        let event = Event {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            at: Utc::now(),
+            kind: kind.to_string(),
+            app: app.map(str::to_string),
+            machine_id: machine_id.map(str::to_string),
+            detail,
+        };
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == EVENT_LOG_CAPACITY {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        drop(events);
+
+        self.persist.try_push(Write::Put {
+            collection: EVENTS,
+            key: format!("{:020}", event.id),
+            value: serde_json::to_value(&event).unwrap_or_default(),
+        });
+
+        // No subscribers is not an error: the event is still kept in the log.
+        let _ = self.sender.send(event.clone());
+        event
+    }
+
+    pub fn since(&self, since: DateTime<Utc>) -> Vec<Event> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .filter(|event| event.at >= since)
+            .cloned()
+            .collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+/// The caller's events: those of apps they may touch, and flyd's own unless their
+/// credentials or namespace are scoped to some apps.
+/// Drops stored events beyond `history` or older than `max_age_days`.
+async fn prune(store: &Store, config: &EventsConfig) -> Result<usize, StoreError> {
+    let mut persisted = store.list::<Event>(EVENTS).await?;
+    persisted.sort_by_key(|event| event.id);
+    let cutoff = Utc::now() - TimeDelta::days(config.max_age_days as i64);
+    let excess = persisted.len().saturating_sub(config.history);
+    let mut dropped = 0;
+    for (index, event) in persisted.iter().enumerate() {
+        if index >= excess && (config.max_age_days == 0 || event.at >= cutoff) {
+            continue;
+        }
+        store.delete(EVENTS, &format!("{:020}", event.id)).await?;
+        dropped += 1;
+    }
+    Ok(dropped)
+}
+
+pub async fn prune_loop(store: web::Data<Store>, config: EventsConfig) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.prune_interval_secs));
+    loop {
+        ticker.tick().await;
+        match prune(&store, &config).await {
+            Ok(0) => {}
+            Ok(dropped) => log::info!("Dropped {} old events", dropped),
+            Err(e) => log::error!("Failed to drop old events: {}", e),
+        }
+    }
+}
+
+#[get("/v0/events")]
+async fn list_events(
+    req: HttpRequest,
+    query: web::Query<EventsQuery>,
+    events: web::Data<EventLog>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let identity = match auth::require_role(&req, None) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let namespace = namespaces::of(&req);
+    if identity.apps.is_none() && namespace.is_none() {
+        let Some(role) = &config.events.admin_role else {
+            return AppError::forbidden(
+                "Listing every app's events needs events.admin_role set; use an app-scoped key",
+            )
+            .into_response();
+        };
+        if let Err(response) = auth::require_role(&req, Some(role)) {
+            return response;
+        }
+    }
+    let visible = |event: &Event| match &event.app {
+        Some(app) => {
+            identity.may_touch(app)
+                && namespace
+                    .as_ref()
+                    .is_none_or(|namespace| namespace.owns(app))
+        }
+        None => identity.apps.is_none() && namespace.is_none(),
+    };
+    let events: Vec<Event> = match query.before {
+        Some(before) => match store.list::<Event>(EVENTS).await {
+            Ok(mut persisted) => {
+                persisted.retain(|event| event.id < before);
+                persisted.sort_by_key(|event| event.id);
+                persisted
+            }
+            Err(e) => return AppError::internal(e.to_string()).into_response(),
+        },
+        None => events.events.lock().unwrap().iter().cloned().collect(),
+    };
+    let matching: Vec<&Event> = events
+        .iter()
+        .rev()
+        .filter(|event| visible(event))
+        .filter(|event| query.app.is_none() || event.app == query.app)
+        .filter(|event| query.kind.as_ref().is_none_or(|kind| &event.kind == kind))
+        .take(query.limit.unwrap_or(100))
+        .collect();
+    HttpResponse::Ok().json(matching)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_events);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::WriteQueueConfig;
+
+    async fn stored(store: &Store, id: u64, age_days: i64) {
+        let event = Event {
+            id,
+            at: Utc::now() - TimeDelta::days(age_days),
+            kind: "test".to_string(),
+            app: None,
+            machine_id: None,
+            detail: json!({}),
+        };
+        store
+            .put(EVENTS, &format!("{:020}", id), &event)
+            .await
+            .unwrap();
+    }
+
+    async fn ids(store: &Store) -> Vec<u64> {
+        let events = store.list::<Event>(EVENTS).await.unwrap();
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[tokio::test]
+    async fn prunes_beyond_history_and_age() {
+        let store = Store::default();
+        for (id, age_days) in [(1, 40), (2, 10), (3, 5), (4, 1), (5, 0)] {
+            stored(&store, id, age_days).await;
+        }
+        let config = EventsConfig {
+            history: 3,
+            max_age_days: 30,
+            ..Default::default()
+        };
+        assert_eq!(prune(&store, &config).await.unwrap(), 2);
+        assert_eq!(ids(&store).await, [3, 4, 5]);
+
+        let config = EventsConfig {
+            history: 10,
+            max_age_days: 2,
+            ..Default::default()
+        };
+        assert_eq!(prune(&store, &config).await.unwrap(), 1);
+        assert_eq!(ids(&store).await, [4, 5]);
+    }
+
+    #[tokio::test]
+    async fn load_numbers_after_the_stored_events() {
+        let store = Store::default();
+        stored(&store, 7, 0).await;
+        stored(&store, 3, 0).await;
+        let (persist, _writes) = WriteQueue::new(&WriteQueueConfig::default());
+        let events = EventLog::new(persist);
+        events.load(&store).await.unwrap();
+
+        let event = events.record("test", None, None, json!({}));
+        assert_eq!(event.id, 8);
+        let recent: Vec<u64> = events
+            .since(Utc::now() - TimeDelta::hours(1))
+            .iter()
+            .map(|event| event.id)
+            .collect();
+        assert_eq!(recent, [3, 7, 8]);
+    }
+}
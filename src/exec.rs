@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use chrono::Utc;
+use flyd::models::ExecRequest;
+use regex::Regex;
+use serde_json::{Value, json};
+
+use crate::artifacts;
+use crate::auth::{self, Identity};
+use crate::backend::Backend;
+use crate::config::{CommandRule, ExecConfig};
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::grants::Grants;
+use crate::prepare_request;
+use crate::sessions::{Recording, SessionRecorder};
+use crate::slo::SloTracker;
+
+const MACHINE_EXEC: &str = "machine.exec";
+
+enum Rule {
+    /// The prefix's words, and whether further arguments may follow them.
+    Prefix {
+        words: Vec<String>,
+        open: bool,
+    },
+    Regex(Regex),
+}
+
+impl Rule {
+    fn prefix(prefix: &str) -> Self {
+        Rule::Prefix {
+            words: prefix.split_whitespace().map(str::to_string).collect(),
+            open: prefix.ends_with(' '),
+        }
+    }
+
+    /// Whether an allow rule lets `argv` run. Prefixes are matched argument by argument,
+    /// so no argument can pass for another or trail after a prefix that doesn't allow
+    /// more, and none may climb out of a path through `..`.
+    fn allows(&self, argv: &[String]) -> bool {
+        match self {
+            Rule::Prefix { words, open } => {
+                !words.is_empty()
+                    && (argv.len() == words.len() || *open && argv.len() > words.len())
+                    && !argv
+                        .iter()
+                        .any(|arg| arg.split('/').any(|part| part == ".."))
+                    && words
+                        .iter()
+                        .zip(argv)
+                        .all(|(word, arg)| names(word, &normalize(arg)))
+            }
+            Rule::Regex(regex) => regex.is_match(&argv.join(" ")),
+        }
+    }
+
+    /// Whether a deny rule refuses `argv`: its leading words start the command, and its
+    /// last names any argument after them, once paths are normalized. So `cat /etc/`
+    /// refuses `cat /etc/../etc/shadow` and `cat /dev/null /etc/shadow` alike.
+    fn denies(&self, argv: &[String]) -> bool {
+        let normalized: Vec<String> = argv.iter().map(|arg| normalize(arg)).collect();
+        match self {
+            Rule::Prefix { words, .. } => {
+                let Some((last, leading)) = words.split_last() else {
+                    return false;
+                };
+                let Some(rest) = normalized.get(leading.len()..) else {
+                    return false;
+                };
+                // The first word is the program, not any of its arguments.
+                let rest = if leading.is_empty() {
+                    &rest[..rest.len().min(1)]
+                } else {
+                    rest
+                };
+                leading
+                    .iter()
+                    .zip(&normalized)
+                    .all(|(word, arg)| names(word, arg))
+                    && rest.iter().any(|arg| names(last, arg))
+            }
+            Rule::Regex(regex) => {
+                regex.is_match(&argv.join(" ")) || regex.is_match(&normalized.join(" "))
+            }
+        }
+    }
+}
+
+/// Whether `arg` is what a prefix's `word` names: any argument starting with it when it
+/// ends in `/` or `*`, else only the word itself, so `ls` doesn't name `lsof`.
+fn names(word: &str, arg: &str) -> bool {
+    match word.strip_suffix('*') {
+        Some(start) => arg.starts_with(start),
+        None if word.ends_with('/') => arg.starts_with(word),
+        None => arg == word,
+    }
+}
+
+/// `arg` with repeated slashes, `.` and `..` resolved where it's a path, so
+/// `/etc/../etc//shadow` reads as `/etc/shadow`. A relative path keeps the `..` that
+/// climb above its start, since where it lands depends on the working directory.
+fn normalize(arg: &str) -> String {
+    if !arg.contains('/') {
+        return arg.to_string();
+    }
+    let absolute = arg.starts_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+    for part in arg.split('/') {
+        match part {
+            "" | "." => {}
+            ".." if parts.last().is_some_and(|last| *last != "..") => {
+                parts.pop();
+            }
+            ".." if absolute => {}
+            part => parts.push(part),
+        }
+    }
+    let mut normalized = parts.join("/");
+    if absolute {
+        normalized.insert(0, '/');
+    }
+    if arg.ends_with('/') && !normalized.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+struct Policy {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+fn compile(rules: &[CommandRule], role: &str) -> Result<Vec<Rule>, String> {
+    rules
+        .iter()
+        .map(|rule| match rule {
+            CommandRule::Prefix(prefix) => Ok(Rule::prefix(prefix)),
+            CommandRule::Regex(pattern) => Regex::new(&format!("^(?:{})$", pattern))
+                .map(Rule::Regex)
+                .map_err(|e| format!("Invalid exec rule {} for role {}: {}", pattern, role, e)),
+        })
+        .collect()
+}
+
+/// The per-role allow and deny lists `/v0/machines/exec` enforces.
+pub struct ExecPolicies {
+    roles: HashMap<String, Policy>,
+    default_timeout_secs: u64,
+    max_timeout_secs: u64,
+    events: web::Data<EventLog>,
+}
+
+impl ExecPolicies {
+    pub fn new(config: &ExecConfig, events: web::Data<EventLog>) -> Result<Self, String> {
+        let mut roles = HashMap::new();
+        for (role, policy) in &config.roles {
+            roles.insert(
+                role.clone(),
+                Policy {
+                    allow: compile(&policy.allow, role)?,
+                    deny: compile(&policy.deny, role)?,
+                },
+            );
+        }
+        Ok(ExecPolicies {
+            roles,
+            default_timeout_secs: config.default_timeout_secs,
+            max_timeout_secs: config.max_timeout_secs,
+            events,
+        })
+    }
+
+    /// Why `identity` may not run `argv`, if it may not. Denies win over allows.
+    fn refusal(&self, identity: &Identity, argv: &[String]) -> Option<&'static str> {
+        let policies: Vec<&Policy> = identity
+            .roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .collect();
+        if policies
+            .iter()
+            .any(|policy| policy.deny.iter().any(|rule| rule.denies(argv)))
+        {
+            Some("denied by rule")
+        } else if !policies
+            .iter()
+            .any(|policy| policy.allow.iter().any(|rule| rule.allows(argv)))
+        {
+            Some("not allowed")
+        } else {
+            None
+        }
+    }
+}
+
+async fn exec<B: Backend>(
+    backend: B,
+    request: &ExecRequest,
+    timeout_secs: u64,
+) -> Result<Value, String> {
+    backend
+        .exec_machine(
+            &request.app_name,
+            &request.machine_id,
+            &request.command,
+            timeout_secs,
+        )
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Runs a command on a machine if the caller's roles allow it. Every attempt, allowed or
+/// not, is recorded as a `machine.exec` event.
+#[post("/v0/machines/exec")]
+async fn exec_machine(
+    req: HttpRequest,
+    body: web::Json<ExecRequest>,
+    policies: web::Data<ExecPolicies>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let request = body.into_inner();
+    if request.command.is_empty() {
+        return AppError::bad_request("command is empty").into_response();
+    }
+    let mut identity = match auth::require_role(&req, None) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    if let Some(grants) = req.app_data::<web::Data<Grants>>() {
+        identity = grants
+            .elevate(&identity, &request.app_name, MACHINE_EXEC)
+            .await;
+    }
+
+    let command = request.command.join(" ");
+    let recorder = req.app_data::<web::Data<SessionRecorder>>();
+    let audited_command = recorder.map_or_else(
+        || request.command.clone(),
+        |recorder| recorder.redact_command(&request.command),
+    );
+    let record = |outcome: Value| {
+        policies.events.record(
+            MACHINE_EXEC,
+            Some(&request.app_name),
+            Some(&request.machine_id),
+            json!({
+                "subject": identity.subject,
+                "roles": identity.roles,
+                "command": audited_command,
+                "outcome": outcome,
+            }),
+        );
+    };
+    if let Some(reason) = policies.refusal(&identity, &request.command) {
+        record(json!({ "denied": reason }));
+        return AppError::forbidden(format!("Not allowed to run {}", command)).into_response();
+    }
+
+    let started_at = Utc::now();
+    let timeout_secs = request
+        .timeout_secs
+        .unwrap_or(policies.default_timeout_secs)
+        .min(policies.max_timeout_secs);
+    let result = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        exec(docker.get_ref().clone(), &request, timeout_secs).await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, request.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner());
+        exec(client, &request, timeout_secs).await
+    };
+
+    let session_id = match recorder {
+        Some(recorder) => {
+            let recording = Recording {
+                kind: "exec",
+                script: None,
+                app: &request.app_name,
+                machine_id: &request.machine_id,
+                subject: &identity.subject,
+                command: &request.command,
+                started_at,
+            };
+            recorder.record(recording, &result).await
+        }
+        None => None,
+    };
+    match result {
+        Ok(mut output) => {
+            let mut outcome = output_summary(&output);
+            let name = format!("exec-{}", request.machine_id);
+            artifacts::offload(&req, &request.app_name, &name, &mut output).await;
+            outcome["session_id"] = json!(session_id);
+            record(outcome);
+            HttpResponse::Ok().json(output)
+        }
+        Err(e) => {
+            record(json!({ "error": e, "session_id": session_id }));
+            AppError::bad_gateway(e).into_response()
+        }
+    }
+}
+
+/// What the audit trail keeps of a command's output: its exit code and size, not the
+/// output itself.
+pub fn output_summary(output: &Value) -> Value {
+    let size = |stream: &str| output[stream].as_str().map_or(0, str::len);
+    json!({
+        "exit_code": output["exit_code"],
+        "stdout_bytes": size("stdout"),
+        "stderr_bytes": size("stderr"),
+    })
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(exec_machine);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderValue;
+
+    use super::*;
+    use crate::config::{ExecPolicyConfig, WriteQueueConfig};
+    use crate::write_queue::WriteQueue;
+
+    fn with_rules(allow: Vec<CommandRule>, deny: Vec<CommandRule>) -> ExecPolicies {
+        let config = ExecConfig {
+            roles: HashMap::from([("ops".to_string(), ExecPolicyConfig { allow, deny })]),
+            ..Default::default()
+        };
+        let (persist, _writes) = WriteQueue::new(&WriteQueueConfig::default());
+        ExecPolicies::new(&config, web::Data::new(EventLog::new(persist))).unwrap()
+    }
+
+    fn ops() -> Identity {
+        Identity {
+            subject: "alice".to_string(),
+            provider: "static_keys",
+            roles: vec!["ops".to_string()],
+            fly_authorization: HeaderValue::from_static("Bearer token"),
+            apps: None,
+        }
+    }
+
+    fn argv(command: &str) -> Vec<String> {
+        command.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn prefixes_match_argument_by_argument() {
+        let policies = with_rules(
+            vec![CommandRule::Prefix("cat /var/log/".to_string())],
+            vec![],
+        );
+        assert!(
+            policies
+                .refusal(&ops(), &argv("cat /var/log/syslog"))
+                .is_none()
+        );
+        assert!(
+            policies
+                .refusal(&ops(), &argv("cat /var/log/a /etc/shadow"))
+                .is_some()
+        );
+        assert!(
+            policies
+                .refusal(&ops(), &argv("cat /var/log/../../etc/shadow"))
+                .is_some()
+        );
+        assert!(
+            policies
+                .refusal(&ops(), &["cat /var/log/a".to_string()])
+                .is_some()
+        );
+        assert!(policies.refusal(&ops(), &argv("cat")).is_some());
+    }
+
+    #[test]
+    fn prefixes_ending_in_a_space_take_any_arguments() {
+        let policies = with_rules(vec![CommandRule::Prefix("ls ".to_string())], vec![]);
+        assert!(policies.refusal(&ops(), &argv("ls -la /tmp")).is_none());
+        assert!(policies.refusal(&ops(), &argv("lsof -i")).is_some());
+
+        let policies = with_rules(vec![CommandRule::Prefix("ls".to_string())], vec![]);
+        assert!(policies.refusal(&ops(), &argv("ls")).is_none());
+        assert!(policies.refusal(&ops(), &argv("lsof")).is_some());
+        assert!(policies.refusal(&ops(), &argv("ls -la")).is_some());
+
+        let policies = with_rules(vec![CommandRule::Prefix("ls*".to_string())], vec![]);
+        assert!(policies.refusal(&ops(), &argv("lsof")).is_none());
+    }
+
+    #[test]
+    fn denies_win_over_allows() {
+        let policies = with_rules(
+            vec![CommandRule::Regex("cat .*".to_string())],
+            vec![CommandRule::Prefix("cat /etc/".to_string())],
+        );
+        assert!(policies.refusal(&ops(), &argv("cat /tmp/a")).is_none());
+        assert_eq!(
+            policies.refusal(&ops(), &argv("cat /etc/shadow")),
+            Some("denied by rule")
+        );
+    }
+
+    #[test]
+    fn denies_catch_later_arguments_and_normalized_paths() {
+        let policies = with_rules(
+            vec![CommandRule::Prefix("cat ".to_string())],
+            vec![
+                CommandRule::Prefix("cat /etc/".to_string()),
+                CommandRule::Prefix("rm".to_string()),
+            ],
+        );
+        for denied in [
+            "cat /etc/shadow",
+            "cat /etc/shadow /dev/null",
+            "cat /dev/null /etc/shadow",
+            "cat /etc/../etc/shadow",
+            "cat /tmp/../etc//shadow",
+        ] {
+            assert_eq!(
+                policies.refusal(&ops(), &argv(denied)),
+                Some("denied by rule"),
+                "{}",
+                denied
+            );
+        }
+        assert!(policies.refusal(&ops(), &argv("cat /tmp/etc/a")).is_none());
+        assert_eq!(
+            policies.refusal(&ops(), &argv("rm -rf /")),
+            Some("denied by rule")
+        );
+        assert_eq!(policies.refusal(&ops(), &argv("cat rm")), None);
+    }
+
+    #[test]
+    fn normalize_resolves_dots_and_slashes() {
+        assert_eq!(normalize("/etc/../etc//./shadow"), "/etc/shadow");
+        assert_eq!(normalize("/../etc/"), "/etc/");
+        assert_eq!(normalize("logs/../../etc"), "../etc");
+        assert_eq!(normalize("-la"), "-la");
+    }
+
+    #[test]
+    fn callers_without_a_policy_cant_exec() {
+        let policies = with_rules(vec![CommandRule::Prefix("ls ".to_string())], vec![]);
+        let mut caller = ops();
+        caller.roles = vec!["dev".to_string()];
+        assert_eq!(
+            policies.refusal(&caller, &argv("ls -la")),
+            Some("not allowed")
+        );
+    }
+}
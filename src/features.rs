@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use flyd::models::{FeatureFlag, SetFeatureFlag};
+use serde_json::json;
+
+use crate::auth;
+use crate::config::FeaturesConfig;
+use crate::errors::AppError;
+use crate::events::EventLog;
+
+pub const AUTOSCALER: &str = "autoscaler";
+pub const RECONCILER: &str = "reconciler";
+pub const WEBHOOKS: &str = "webhooks";
+
+/// Subsystems that can be switched off while flyd runs. Each is on unless the config says
+/// otherwise.
+const KNOWN: [&str; 3] = [AUTOSCALER, RECONCILER, WEBHOOKS];
+
+const FEATURE_TOGGLED: &str = "feature.toggled";
+
+/// This instance's feature flags: the config's, as changed through
+/// `/v0/admin/features` since it started.
+pub struct Features {
+    config: FeaturesConfig,
+    flags: RwLock<BTreeMap<&'static str, bool>>,
+}
+
+impl Features {
+    pub fn new(config: &FeaturesConfig) -> Result<Self, String> {
+        if let Some(name) = config
+            .flags
+            .keys()
+            .find(|name| !KNOWN.contains(&name.as_str()))
+        {
+            return Err(format!(
+                "Unknown feature {}; known features are {}",
+                name,
+                KNOWN.join(", ")
+            ));
+        }
+        let flags = KNOWN
+            .iter()
+            .map(|name| (*name, config.flags.get(*name).copied().unwrap_or(true)))
+            .collect();
+        Ok(Features {
+            config: config.clone(),
+            flags: RwLock::new(flags),
+        })
+    }
+
+    /// Whether the subsystem should run. Background loops check on every tick, so
+    /// switching one off pauses it rather than stopping it.
+    pub fn enabled(&self, name: &str) -> bool {
+        self.flags
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or(true)
+    }
+
+    fn listed(&self) -> Vec<FeatureFlag> {
+        self.flags
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, enabled)| FeatureFlag {
+                name: name.to_string(),
+                enabled: *enabled,
+                configured: self.config.flags.get(*name).copied().unwrap_or(true),
+            })
+            .collect()
+    }
+
+    /// Flags switch subsystems off for every app, so only admins may change them.
+    fn admin(&self, req: &HttpRequest) -> Result<String, HttpResponse> {
+        let Some(role) = &self.config.admin_role else {
+            return Err(
+                AppError::forbidden("Changing features needs features.admin_role set")
+                    .into_response(),
+            );
+        };
+        auth::require_role(req, Some(role)).map(|identity| identity.subject)
+    }
+}
+
+#[get("/v0/admin/features")]
+async fn list_features(req: HttpRequest, features: web::Data<Features>) -> impl Responder {
+    if let Err(response) = auth::require_role(&req, None) {
+        return response;
+    }
+    HttpResponse::Ok().json(features.listed())
+}
+
+/// Switches a subsystem on or off on this instance until it restarts, when the config's
+/// flags apply again.
+#[post("/v0/admin/features/{name}")]
+async fn set_feature(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<SetFeatureFlag>,
+    features: web::Data<Features>,
+) -> impl Responder {
+    let subject = match features.admin(&req) {
+        Ok(subject) => subject,
+        Err(response) => return response,
+    };
+    let Some(name) = KNOWN.iter().find(|name| **name == path.as_str()) else {
+        return AppError::not_found(format!("Unknown feature {}", path)).into_response();
+    };
+    let previous = features.flags.write().unwrap().insert(name, body.enabled);
+    if previous != Some(body.enabled) {
+        log::info!(
+            "{} {} feature {}",
+            subject,
+            if body.enabled { "enabled" } else { "disabled" },
+            name
+        );
+        if let Some(events) = req.app_data::<web::Data<EventLog>>() {
+            events.record(
+                FEATURE_TOGGLED,
+                None,
+                None,
+                json!({ "feature": name, "enabled": body.enabled, "by": subject }),
+            );
+        }
+    }
+    HttpResponse::Ok().json(features.listed())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_features).service(set_feature);
+}
@@ -0,0 +1,540 @@
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::Utc;
+use flyd::models::{
+    ConfigConflict, ConflictPolicy, Drift, FleetDrift, FleetMode, FleetQuery, FleetSpec,
+    MachineSpec,
+};
+use serde_json::{Value, json};
+
+use crate::auth;
+use crate::backend::Backend;
+use crate::backoff::{RestartBackoff, Verdict};
+use crate::config::Config;
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::features::{self, Features};
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::merge;
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::{Store, StoreError};
+
+const FLEETS: &str = "fleets";
+const FLEET_DRIFT: &str = "fleet_drift";
+
+pub const MANAGED_METADATA_KEY: &str = "flyd_fleet";
+
+pub async fn fleet_specs(store: &Store) -> Result<Vec<FleetSpec>, StoreError> {
+    store.list(FLEETS).await
+}
+
+pub async fn fleet_spec(store: &Store, app: &str) -> Result<Option<FleetSpec>, StoreError> {
+    store.get(FLEETS, app).await
+}
+
+pub async fn save_fleet_spec(store: &Store, spec: &FleetSpec) -> Result<(), StoreError> {
+    store.put(FLEETS, &spec.app, spec).await
+}
+
+pub async fn drift_report(store: &Store, app: &str) -> Result<Option<FleetDrift>, StoreError> {
+    store.get(FLEET_DRIFT, app).await
+}
+
+pub fn is_live(machine: &serde_json::Value) -> bool {
+    !matches!(
+        machine["state"].as_str(),
+        Some("destroyed") | Some("destroying")
+    )
+}
+
+fn is_managed(machine: &serde_json::Value, app: &str) -> bool {
+    machine["config"]["metadata"][MANAGED_METADATA_KEY].as_str() == Some(app)
+}
+
+/// Takes the app's live machines as the desired state and marks them as managed.
+async fn adopt<B: Backend>(backend: &B, store: &Store, query: &FleetQuery) -> HttpResponse {
+    let live = match backend.list_machines(&query.app).await {
+        Ok(machines) => machines,
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+
+    let mut machines = Vec::new();
+    for machine in live.iter().filter(|machine| is_live(machine)) {
+        let (Some(id), Some(name), Some(region)) = (
+            machine["id"].as_str(),
+            machine["name"].as_str(),
+            machine["region"].as_str(),
+        ) else {
+            continue;
+        };
+
+        if let Err(e) = backend
+            .set_metadata(&query.app, id, MANAGED_METADATA_KEY, &query.app)
+            .await
+        {
+            return AppError::internal(format!("Failed to mark machine {} as managed: {}", id, e))
+                .into_response();
+        }
+
+        let mut config = machine["config"].clone();
+        config["metadata"][MANAGED_METADATA_KEY] = json!(query.app);
+
+        machines.push(MachineSpec {
+            name: name.to_string(),
+            region: region.to_string(),
+            last_applied: Some(config.clone()),
+            config,
+        });
+    }
+
+    let spec = FleetSpec {
+        app: query.app.clone(),
+        mode: query.mode.unwrap_or_default(),
+        conflict_policy: query.conflict_policy.unwrap_or_default(),
+        machines,
+        adopted_at: Utc::now(),
+    };
+
+    if let Err(e) = store.put(FLEETS, &spec.app, &spec).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+
+    log::info!(
+        "Adopted {} machines into fleet {}",
+        spec.machines.len(),
+        spec.app
+    );
+
+    HttpResponse::Ok().json(spec)
+}
+
+/// Fleets are reconciled with flyd's own token, so only admins may manage them.
+fn admin(req: &HttpRequest, config: &Config) -> Result<(), HttpResponse> {
+    let Some(role) = &config.fleets.admin_role else {
+        return Err(
+            AppError::forbidden("Managing fleets needs fleets.admin_role set").into_response(),
+        );
+    };
+    auth::require_role(req, Some(role)).map(|_| ())
+}
+
+#[post("/v0/fleets/adopt")]
+async fn adopt_fleet(
+    req: HttpRequest,
+    query: web::Query<FleetQuery>,
+    http_client: web::Data<reqwest::Client>,
+    store: web::Data<Store>,
+    slo: web::Data<SloTracker>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = admin(&req, &config) {
+        return response;
+    }
+    match store.get::<FleetSpec>(FLEETS, &query.app).await {
+        Ok(Some(_)) => {
+            return AppError::conflict(format!("Fleet for app {} is already managed", query.app))
+                .into_response();
+        }
+        Ok(None) => {}
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    }
+
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return adopt(docker.get_ref(), &store, &query).await;
+    }
+
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    adopt(&client, &store, &query).await
+}
+
+#[get("/v0/fleets/list")]
+async fn list_fleets(
+    req: HttpRequest,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = admin(&req, &config) {
+        return response;
+    }
+    match store.list::<FleetSpec>(FLEETS).await {
+        Ok(fleets) => HttpResponse::Ok().json(fleets),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/fleets/get")]
+async fn get_fleet(
+    req: HttpRequest,
+    query: web::Query<FleetQuery>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = admin(&req, &config) {
+        return response;
+    }
+    match store.get::<FleetSpec>(FLEETS, &query.app).await {
+        Ok(Some(spec)) => HttpResponse::Ok().json(spec),
+        Ok(None) => AppError::not_found(format!("No fleet for app {}", query.app)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+/// Sets the fleet's `mode`, its `conflict_policy`, or both.
+#[post("/v0/fleets/mode")]
+async fn set_fleet_mode(
+    req: HttpRequest,
+    query: web::Query<FleetQuery>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = admin(&req, &config) {
+        return response;
+    }
+    if query.mode.is_none() && query.conflict_policy.is_none() {
+        return AppError::bad_request(
+            "mode (enforce or detect) or conflict_policy (overwrite or keep) is required",
+        )
+        .into_response();
+    }
+
+    let mut spec = match store.get::<FleetSpec>(FLEETS, &query.app).await {
+        Ok(Some(spec)) => spec,
+        Ok(None) => {
+            return AppError::not_found(format!("No fleet for app {}", query.app)).into_response();
+        }
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+
+    spec.mode = query.mode.unwrap_or(spec.mode);
+    spec.conflict_policy = query.conflict_policy.unwrap_or(spec.conflict_policy);
+    if let Err(e) = store.put(FLEETS, &spec.app, &spec).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+
+    HttpResponse::Ok().json(spec)
+}
+
+#[get("/v0/fleets/drift")]
+async fn get_fleet_drift(
+    req: HttpRequest,
+    query: web::Query<FleetQuery>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = admin(&req, &config) {
+        return response;
+    }
+    match store.get::<FleetDrift>(FLEET_DRIFT, &query.app).await {
+        Ok(Some(report)) => HttpResponse::Ok().json(report),
+        Ok(None) => AppError::not_found(format!("No drift report for app {} yet", query.app))
+            .into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[post("/v0/fleets/release")]
+async fn release_fleet(
+    req: HttpRequest,
+    query: web::Query<FleetQuery>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = admin(&req, &config) {
+        return response;
+    }
+    if let Err(e) = store.delete(FLEET_DRIFT, &query.app).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+
+    match store.delete(FLEETS, &query.app).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => AppError::not_found(format!("No fleet for app {}", query.app)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(adopt_fleet)
+        .service(list_fleets)
+        .service(get_fleet)
+        .service(set_fleet_mode)
+        .service(get_fleet_drift)
+        .service(release_fleet);
+}
+
+pub async fn reconcile_loop<B: Backend>(
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    backoff: web::Data<RestartBackoff>,
+    drainer: Option<web::Data<Drainer>>,
+    backend: B,
+    interval: Duration,
+    features: web::Data<Features>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if !features.enabled(features::RECONCILER) {
+            continue;
+        }
+
+        let fleets = match store.list::<FleetSpec>(FLEETS).await {
+            Ok(fleets) => fleets,
+            Err(e) => {
+                log::error!("Failed to load fleet specs: {}", e);
+                continue;
+            }
+        };
+
+        for fleet in fleets {
+            if let Err(e) = reconcile(
+                &backend,
+                &store,
+                &events,
+                &backoff,
+                drainer.as_ref(),
+                &fleet,
+            )
+            .await
+            {
+                log::error!("Failed to reconcile fleet {}: {}", fleet.app, e);
+            }
+        }
+    }
+}
+
+/// The config `spec`'s machine should have: the spec merged with the live config, keeping
+/// changes made to the machine out of band, and the keys both changed. Without a last
+/// applied config to merge from, it's the spec's.
+fn desired_config(
+    fleet: &FleetSpec,
+    spec: &MachineSpec,
+    machine: &Value,
+) -> (Value, Vec<ConfigConflict>) {
+    let Some(last_applied) = &spec.last_applied else {
+        return (spec.config.clone(), Vec::new());
+    };
+    let (config, conflicts) = merge::merge3(
+        last_applied,
+        &spec.config,
+        &machine["config"],
+        fleet.conflict_policy == ConflictPolicy::Overwrite,
+    );
+    let conflicts = conflicts
+        .into_iter()
+        .map(|conflict| ConfigConflict {
+            name: spec.name.clone(),
+            machine_id: machine["id"].as_str().unwrap_or_default().to_string(),
+            path: conflict.path,
+            last_applied: conflict.base,
+            spec: conflict.ours,
+            live: conflict.theirs,
+            resolution: fleet.conflict_policy,
+        })
+        .collect();
+    (config, conflicts)
+}
+
+fn detect_drift(fleet: &FleetSpec, live: &[Value]) -> (Vec<Drift>, Vec<ConfigConflict>) {
+    let managed: Vec<&Value> = live
+        .iter()
+        .filter(|machine| is_live(machine) && is_managed(machine, &fleet.app))
+        .collect();
+
+    let mut drift = Vec::new();
+    let mut conflicts = Vec::new();
+
+    for spec in &fleet.machines {
+        let Some(machine) = managed.iter().find(|machine| machine["name"] == spec.name) else {
+            drift.push(Drift::Missing {
+                name: spec.name.clone(),
+            });
+            continue;
+        };
+        let (config, conflicted) = desired_config(fleet, spec, machine);
+        conflicts.extend(conflicted);
+        if machine["config"] != config {
+            drift.push(Drift::ConfigChanged {
+                name: spec.name.clone(),
+                machine_id: machine["id"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+    }
+
+    for machine in managed {
+        if !fleet
+            .machines
+            .iter()
+            .any(|spec| machine["name"] == spec.name)
+        {
+            drift.push(Drift::Unexpected {
+                name: machine["name"].as_str().unwrap_or_default().to_string(),
+                machine_id: machine["id"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+    }
+
+    (drift, conflicts)
+}
+
+/// Records `config` as the one last applied to the machine `name`, for the next merge.
+async fn record_applied(store: &Store, app: &str, name: &str, config: Value) {
+    let result = async {
+        let Some(mut spec) = fleet_spec(store, app).await? else {
+            return Ok(());
+        };
+        if let Some(machine) = spec.machines.iter_mut().find(|spec| spec.name == name) {
+            machine.last_applied = Some(config);
+            save_fleet_spec(store, &spec).await?;
+        }
+        Ok::<_, StoreError>(())
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!(
+            "Failed to record the config applied to {} in {}: {}",
+            name,
+            app,
+            e
+        );
+    }
+}
+
+async fn correct<B: Backend>(
+    backend: &B,
+    store: &Store,
+    drainer: Option<&web::Data<Drainer>>,
+    fleet: &FleetSpec,
+    live: &[Value],
+    drift: &Drift,
+) -> Result<(), B::Error> {
+    match drift {
+        Drift::Missing { name } => {
+            let Some(spec) = fleet.machines.iter().find(|spec| &spec.name == name) else {
+                return Ok(());
+            };
+            backend
+                .create_machine(
+                    &fleet.app,
+                    &json!({ "name": spec.name, "region": spec.region, "config": spec.config }),
+                )
+                .await?;
+            record_applied(store, &fleet.app, name, spec.config.clone()).await;
+        }
+        Drift::ConfigChanged { name, machine_id } => {
+            let (Some(spec), Some(machine)) = (
+                fleet.machines.iter().find(|spec| &spec.name == name),
+                live.iter().find(|machine| machine["id"] == *machine_id),
+            ) else {
+                return Ok(());
+            };
+            let (config, _) = desired_config(fleet, spec, machine);
+            drain::drain(drainer, backend, &fleet.app, machine_id).await;
+            backend
+                .update_machine(&fleet.app, machine_id, &json!({ "config": config }), None)
+                .await?;
+            drain::restore(drainer, backend, &fleet.app, machine_id).await;
+            record_applied(store, &fleet.app, name, config).await;
+        }
+        Drift::Unexpected { machine_id, .. } => {
+            drain::drain(drainer, backend, &fleet.app, machine_id).await;
+            backend
+                .destroy_machine(&fleet.app, machine_id, None)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn reconcile<B: Backend>(
+    backend: &B,
+    store: &Store,
+    events: &EventLog,
+    backoff: &RestartBackoff,
+    drainer: Option<&web::Data<Drainer>>,
+    fleet: &FleetSpec,
+) -> Result<(), B::Error> {
+    let live = backend.list_machines(&fleet.app).await?;
+    let (drift, conflicts) = detect_drift(fleet, &live);
+
+    let (previous, previous_conflicts) =
+        match store.get::<FleetDrift>(FLEET_DRIFT, &fleet.app).await {
+            Ok(report) => report
+                .map(|report| (report.drift, report.conflicts))
+                .unwrap_or_default(),
+            Err(e) => {
+                log::error!("Failed to load drift report for {}: {}", fleet.app, e);
+                Default::default()
+            }
+        };
+
+    if drift != previous {
+        if drift.is_empty() {
+            events.record("fleet.drift_resolved", Some(&fleet.app), None, json!({}));
+        } else {
+            events.record(
+                "fleet.drift",
+                Some(&fleet.app),
+                None,
+                json!({ "mode": fleet.mode, "drift": drift }),
+            );
+        }
+    }
+
+    if !conflicts.is_empty() && conflicts != previous_conflicts {
+        events.record(
+            "fleet.conflict",
+            Some(&fleet.app),
+            None,
+            json!({ "conflict_policy": fleet.conflict_policy, "conflicts": conflicts }),
+        );
+    }
+
+    let report = FleetDrift {
+        app: fleet.app.clone(),
+        mode: fleet.mode,
+        checked_at: Utc::now(),
+        drift,
+        conflicts,
+    };
+    if let Err(e) = store.put(FLEET_DRIFT, &fleet.app, &report).await {
+        log::error!("Failed to save drift report for {}: {}", fleet.app, e);
+    }
+
+    if fleet.mode == FleetMode::Enforce {
+        for drift in &report.drift {
+            // A machine that keeps going missing or drifting backs off like any restart.
+            if let Drift::Missing { name } | Drift::ConfigChanged { name, .. } = drift {
+                match backoff.verdict(&fleet.app, name) {
+                    Verdict::Go => backoff.attempted(&fleet.app, name),
+                    Verdict::Wait | Verdict::Quarantined => continue,
+                    Verdict::Quarantine => {
+                        backoff
+                            .quarantine(backend, events, &fleet.app, name, drift.machine_id())
+                            .await;
+                        continue;
+                    }
+                }
+            }
+            log::info!("Fleet {}: correcting {:?}", fleet.app, drift);
+            correct(backend, store, drainer, fleet, &live, drift).await?;
+            events.record(
+                "fleet.corrected",
+                Some(&fleet.app),
+                drift.machine_id(),
+                json!(drift),
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,806 @@
+//! A Machines API client for services that want flyd's client without running flyd, and
+//! the conventions it follows: which failures are retried and how long to wait,
+//! idempotency keys and the headers they travel in. flyd itself uses the same client,
+//! with a `Layer` that adds its circuit breaking, hedging, caching and policy checks.
+
+use std::time::{Duration, Instant};
+
+use reqwest::StatusCode;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue, RETRY_AFTER};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::models::LogLine;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const LEASE_NONCE_HEADER: &str = "fly-machine-lease-nonce";
+
+/// The longest single wait the Machines API allows.
+pub const MAX_WAIT_SECS: u64 = 60;
+
+/// How Machines API calls that fail transiently (connection errors, 429, 502, 503) are
+/// retried: up to `attempts` more times, waiting a random time up to `base_ms` doubled
+/// per attempt, or Fly's `Retry-After`, and never more than `max_ms`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub attempts: u32,
+    pub base_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 3,
+            base_ms: 100,
+            max_ms: 5000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// How long to wait before retrying after the `attempt`th try, counting from 0.
+    pub fn delay(&self, attempt: u32, result: &reqwest::Result<reqwest::Response>) -> Duration {
+        let backoff = self.base_ms.saturating_mul(1 << attempt.min(16));
+        retry_after(result)
+            .unwrap_or_else(|| Duration::from_millis(random_below(backoff + 1)))
+            .min(Duration::from_millis(self.max_ms))
+    }
+}
+
+fn random_below(bound: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    u64::from_le_bytes(bytes) % bound.max(1)
+}
+
+/// A new key for a machine creation, so retrying it can't create a second machine.
+pub fn new_idempotency_key() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    hex::encode(bytes)
+}
+
+/// Connection failures and 429s or 503s mean Fly didn't act on the request, so any request
+/// may retry them. A reset or 502 may come after it did, so those only retry requests that
+/// are safe to repeat.
+pub fn transient(result: &reqwest::Result<reqwest::Response>, repeatable: bool) -> bool {
+    match result {
+        Ok(response) => match response.status() {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+            StatusCode::BAD_GATEWAY => repeatable,
+            _ => false,
+        },
+        Err(e) if e.is_connect() => true,
+        Err(e) => repeatable && (e.is_request() || e.is_timeout()),
+    }
+}
+
+/// POSTs are only safe to repeat with an `Idempotency-Key`.
+pub fn repeatable(request: &reqwest::Request) -> bool {
+    request.method() != reqwest::Method::POST
+        || request.headers().contains_key(IDEMPOTENCY_KEY_HEADER)
+}
+
+fn retry_after(result: &reqwest::Result<reqwest::Response>) -> Option<Duration> {
+    let seconds = result
+        .as_ref()
+        .ok()?
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+pub fn authorization_value(token: &str) -> String {
+    if token.starts_with("FlyV1 ") || token.starts_with("Bearer ") {
+        token.to_string()
+    } else {
+        format!("Bearer {}", token)
+    }
+}
+
+/// Sends `request` with `send`, retrying it per `retry` while it fails transiently. POSTs
+/// are only repeated after Fly may have acted on them when they carry an `Idempotency-Key`.
+/// `send` may refuse an attempt with an error, which ends the retries.
+pub async fn retrying<F, Fut>(
+    mut request: reqwest::Request,
+    retry: &RetryConfig,
+    mut send: F,
+) -> Result<reqwest::Response, Error>
+where
+    F: FnMut(reqwest::Request) -> Fut,
+    Fut: Future<Output = Result<reqwest::Result<reqwest::Response>, Error>>,
+{
+    let repeatable = repeatable(&request);
+    let mut attempt = 0;
+    loop {
+        let next = if attempt < retry.attempts {
+            request.try_clone()
+        } else {
+            None
+        };
+        let result = send(request).await?;
+        let Some(next) = next.filter(|_| transient(&result, repeatable)) else {
+            return Ok(result?);
+        };
+
+        let delay = retry.delay(attempt, &result);
+        log::debug!(
+            "Retrying {} {} in {:?} after {}",
+            next.method(),
+            next.url().path(),
+            delay,
+            match &result {
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            }
+        );
+        tokio::time::sleep(delay).await;
+        request = next;
+        attempt += 1;
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Request(reqwest::Error),
+    Status {
+        status: StatusCode,
+        body: String,
+    },
+    /// Refused before reaching Fly, e.g. for an unsigned image.
+    Rejected(String),
+    /// Not sent because calls to the host keep failing.
+    CircuitOpen {
+        host: String,
+        retry_after: Duration,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Request(e) => write!(f, "API request failed: {}", e),
+            Error::Status { status, body } => {
+                write!(f, "API returned {}: {}", status, body)
+            }
+            Error::Rejected(reason) => write!(f, "{}", reason),
+            Error::CircuitOpen { host, retry_after } => write!(
+                f,
+                "Calls to {} keep failing; not trying again for {}s",
+                host,
+                retry_after.as_secs_f64().ceil()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Request(e)
+    }
+}
+
+/// What a `FlyClient` does around its calls beyond building them: how they're sent, and
+/// what's checked before machines and apps are created or changed and after Fly answers
+/// with machines.
+pub trait Layer: Send + Sync + Sized {
+    /// Sends a request, answering with Fly's response whatever its status.
+    fn execute(
+        &self,
+        http: &reqwest::Client,
+        request: reqwest::Request,
+    ) -> impl Future<Output = Result<reqwest::Response, Error>> + Send;
+
+    /// Checks a machine about to be created or updated, answering with the body to send
+    /// instead when it has to change.
+    fn verify(
+        &self,
+        _client: &FlyClient<Self>,
+        _app_name: &str,
+        _body: &Value,
+        _creating: bool,
+    ) -> impl Future<Output = Result<Option<Value>, Error>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Checks an app about to be created.
+    fn verify_app(
+        &self,
+        _client: &FlyClient<Self>,
+        _app_name: &str,
+        _org_slug: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Sees the machines Fly answers `endpoint` with, e.g. `GET /v1/apps/{app}/machines`.
+    fn answered(&self, _endpoint: &str, _machines: &[Value]) {}
+}
+
+/// Sends calls as they are, retrying transient failures per its `RetryConfig`.
+#[derive(Clone, Default)]
+pub struct Direct {
+    pub retry: RetryConfig,
+}
+
+impl Layer for Direct {
+    async fn execute(
+        &self,
+        http: &reqwest::Client,
+        request: reqwest::Request,
+    ) -> Result<reqwest::Response, Error> {
+        retrying(request, &self.retry, |request| async move {
+            Ok(http.execute(request).await)
+        })
+        .await
+    }
+}
+
+/// Typed client for the Machines API, e.g. `https://api.machines.dev`, or
+/// `http://_api.internal:4280` over Fly's private network. Every call carries `headers`.
+#[derive(Clone)]
+pub struct FlyClient<L: Layer = Direct> {
+    http: reqwest::Client,
+    headers: HeaderMap,
+    api_hostname: String,
+    layer: L,
+    idempotency_key: Option<String>,
+}
+
+impl<L: Layer + Default> FlyClient<L> {
+    pub fn new(http: reqwest::Client, headers: HeaderMap, api_hostname: String) -> Self {
+        FlyClient {
+            http,
+            headers,
+            api_hostname,
+            layer: L::default(),
+            idempotency_key: None,
+        }
+    }
+
+    /// A client calling the Machines API at `api_hostname` with a Fly token, given as
+    /// `FlyV1 ...`, `Bearer ...` or bare.
+    pub fn from_token(http: reqwest::Client, token: &str, api_hostname: &str) -> Option<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&authorization_value(token)).ok()?,
+        );
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Some(FlyClient::new(http, headers, api_hostname.to_string()))
+    }
+}
+
+impl FlyClient {
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.layer.retry = retry;
+        self
+    }
+}
+
+impl<L: Layer> FlyClient<L> {
+    pub fn layer(&self) -> &L {
+        &self.layer
+    }
+
+    pub fn layer_mut(&mut self) -> &mut L {
+        &mut self.layer
+    }
+
+    /// Sent with machine creations instead of a new key each time.
+    pub fn with_idempotency_key(mut self, key: Option<String>) -> Self {
+        self.idempotency_key = key;
+        self
+    }
+
+    fn app_url(&self, app_name: &str) -> String {
+        format!("{}/v1/apps/{}", self.api_hostname, app_name)
+    }
+
+    fn machines_url(&self, app_name: &str) -> String {
+        format!("{}/machines", self.app_url(app_name))
+    }
+
+    fn volumes_url(&self, app_name: &str) -> String {
+        format!("{}/volumes", self.app_url(app_name))
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, Error> {
+        let (http, request) = request.headers(self.headers.clone()).build_split();
+        let response = self.layer.execute(&http, request?).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::Status { status, body });
+        }
+        Ok(response)
+    }
+
+    /// Runs `start`, `stop`, `restart` or `signal` on a machine, returning Fly's response.
+    pub async fn machine_action(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        action: &str,
+        query: &[(&str, String)],
+        body: Option<&Value>,
+    ) -> Result<Value, Error> {
+        let url = format!("{}/{}/{}", self.machines_url(app_name), machine_id, action);
+        let mut request = self.http.post(url).query(query);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let text = self.send(request).await?.text().await?;
+        if text.trim().is_empty() {
+            return Ok(json!({ "ok": true }));
+        }
+        Ok(serde_json::from_str(&text).unwrap_or(Value::String(text)))
+    }
+
+    /// Blocks until the machine reaches `state` or `timeout` is up, answering 408 then.
+    /// Fly caps each wait at a minute, so longer ones are made of several.
+    pub async fn wait_for_state(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        state: &str,
+        instance_id: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Value, Error> {
+        let url = format!("{}/{}/wait", self.machines_url(app_name), machine_id);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let chunk = (remaining.as_millis() as u64)
+                .div_ceil(1000)
+                .clamp(1, MAX_WAIT_SECS);
+            let mut query = vec![("state", state.to_string()), ("timeout", chunk.to_string())];
+            if let Some(instance_id) = instance_id {
+                query.push(("instance_id", instance_id.to_string()));
+            }
+            // Leaves Fly time to answer 408 itself rather than cutting it off.
+            let request = self
+                .http
+                .get(&url)
+                .query(&query)
+                .timeout(Duration::from_secs(chunk + 10));
+            match self.send(request).await {
+                Ok(response) => return Ok(response.json().await.unwrap_or(json!({ "ok": true }))),
+                Err(Error::Status { status, .. })
+                    if status == StatusCode::REQUEST_TIMEOUT
+                        && Instant::now() + Duration::from_secs(1) < deadline => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The Machines API's lease, its `data.nonce` passed to subsequent calls and to
+    /// `release_lease`. Fails with 409 while someone else holds one.
+    pub async fn lease(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        ttl_secs: u64,
+        description: Option<&str>,
+    ) -> Result<Value, Error> {
+        let url = format!("{}/{}/lease", self.machines_url(app_name), machine_id);
+        let mut body = json!({ "ttl": ttl_secs });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        let response = self.send(self.http.post(url).json(&body)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Returns the lease nonce to pass to subsequent calls and to `release_lease`.
+    pub async fn acquire_lease(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        ttl_secs: u64,
+    ) -> Result<String, Error> {
+        let lease = self.lease(app_name, machine_id, ttl_secs, None).await?;
+        Ok(lease["data"]["nonce"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    pub async fn release_lease(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        nonce: &str,
+    ) -> Result<(), Error> {
+        let url = format!("{}/{}/lease", self.machines_url(app_name), machine_id);
+        self.send(self.http.delete(url).header(LEASE_NONCE_HEADER, nonce))
+            .await?;
+        Ok(())
+    }
+
+    /// The machine's metadata, keys to values.
+    pub async fn get_metadata(&self, app_name: &str, machine_id: &str) -> Result<Value, Error> {
+        let url = format!("{}/{}/metadata", self.machines_url(app_name), machine_id);
+        let response = self.send(self.http.get(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn set_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        let url = format!(
+            "{}/{}/metadata/{}",
+            self.machines_url(app_name),
+            machine_id,
+            key
+        );
+        self.send(self.http.post(url).json(&json!({ "value": value })))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+    ) -> Result<(), Error> {
+        let url = format!(
+            "{}/{}/metadata/{}",
+            self.machines_url(app_name),
+            machine_id,
+            key
+        );
+        self.send(self.http.delete(url)).await?;
+        Ok(())
+    }
+
+    pub async fn list_apps(&self, org_slug: &str) -> Result<Vec<Value>, Error> {
+        let url = format!("{}/v1/apps", self.api_hostname);
+        let response = self
+            .send(self.http.get(url).query(&[("org_slug", org_slug)]))
+            .await?;
+        let apps: Value = response.json().await?;
+        Ok(apps["apps"].as_array().cloned().unwrap_or_default())
+    }
+
+    pub async fn create_app(&self, app_name: &str, org_slug: &str) -> Result<Value, Error> {
+        self.layer.verify_app(self, app_name, org_slug).await?;
+        let url = format!("{}/v1/apps", self.api_hostname);
+        let response = self
+            .send(
+                self.http
+                    .post(url)
+                    .json(&json!({ "app_name": app_name, "org_slug": org_slug })),
+            )
+            .await?;
+        // App creation answers 201 with an empty body.
+        Ok(response.json().await.unwrap_or_default())
+    }
+
+    pub async fn get_app(&self, app_name: &str) -> Result<Value, Error> {
+        let response = self.send(self.http.get(self.app_url(app_name))).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Deletes the app along with its machines, volumes and IPs.
+    pub async fn delete_app(&self, app_name: &str) -> Result<(), Error> {
+        self.send(self.http.delete(self.app_url(app_name))).await?;
+        Ok(())
+    }
+
+    pub async fn list_machines(&self, app_name: &str) -> Result<Vec<Value>, Error> {
+        let response = self
+            .send(self.http.get(self.machines_url(app_name)))
+            .await?;
+        let machines: Vec<Value> = response.json().await?;
+        self.layer
+            .answered("GET /v1/apps/{app}/machines", &machines);
+        Ok(machines)
+    }
+
+    pub async fn list_region_machines(
+        &self,
+        app_name: &str,
+        region: &str,
+    ) -> Result<Vec<Value>, Error> {
+        let request = self
+            .http
+            .get(self.machines_url(app_name))
+            .query(&[("region", region)]);
+        let machines: Vec<Value> = self.send(request).await?.json().await?;
+        self.layer
+            .answered("GET /v1/apps/{app}/machines", &machines);
+        Ok(machines)
+    }
+
+    pub async fn get_machine(&self, app_name: &str, machine_id: &str) -> Result<Value, Error> {
+        let url = format!("{}/{}", self.machines_url(app_name), machine_id);
+        let response = self.send(self.http.get(url)).await?;
+        let machine: Value = response.json().await?;
+        self.layer.answered(
+            "GET /v1/apps/{app}/machines/{id}",
+            std::slice::from_ref(&machine),
+        );
+        Ok(machine)
+    }
+
+    /// Sent with an `Idempotency-Key`, so it's retried like any other call.
+    pub async fn create_machine(&self, app_name: &str, body: &Value) -> Result<Value, Error> {
+        let verified = self.layer.verify(self, app_name, body, true).await?;
+        let key = match &self.idempotency_key {
+            Some(key) => key.clone(),
+            None => new_idempotency_key(),
+        };
+        let response = self
+            .send(
+                self.http
+                    .post(self.machines_url(app_name))
+                    .header(IDEMPOTENCY_KEY_HEADER, key)
+                    .json(verified.as_ref().unwrap_or(body)),
+            )
+            .await?;
+        let machine: Value = response.json().await?;
+        self.layer.answered(
+            "POST /v1/apps/{app}/machines",
+            std::slice::from_ref(&machine),
+        );
+        Ok(machine)
+    }
+
+    pub async fn update_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        body: &Value,
+        lease_nonce: Option<&str>,
+    ) -> Result<Value, Error> {
+        let verified = self.layer.verify(self, app_name, body, false).await?;
+        let url = format!("{}/{}", self.machines_url(app_name), machine_id);
+        let mut request = self.http.post(url).json(verified.as_ref().unwrap_or(body));
+        if let Some(nonce) = lease_nonce {
+            request = request.header(LEASE_NONCE_HEADER, nonce);
+        }
+        let response = self.send(request).await?;
+        let machine: Value = response.json().await?;
+        self.layer.answered(
+            "POST /v1/apps/{app}/machines/{id}",
+            std::slice::from_ref(&machine),
+        );
+        Ok(machine)
+    }
+
+    pub async fn start_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Error> {
+        let url = format!("{}/{}/start", self.machines_url(app_name), machine_id);
+        self.send(self.http.post(url)).await?;
+        Ok(())
+    }
+
+    pub async fn stop_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Error> {
+        let url = format!("{}/{}/stop", self.machines_url(app_name), machine_id);
+        self.send(self.http.post(url)).await?;
+        Ok(())
+    }
+
+    pub async fn restart_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Error> {
+        let url = format!("{}/{}/restart", self.machines_url(app_name), machine_id);
+        self.send(self.http.post(url)).await?;
+        Ok(())
+    }
+
+    pub async fn cordon_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Error> {
+        let url = format!("{}/{}/cordon", self.machines_url(app_name), machine_id);
+        self.send(self.http.post(url)).await?;
+        Ok(())
+    }
+
+    pub async fn uncordon_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Error> {
+        let url = format!("{}/{}/uncordon", self.machines_url(app_name), machine_id);
+        self.send(self.http.post(url)).await?;
+        Ok(())
+    }
+
+    pub async fn exec_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        command: &[String],
+        timeout_secs: u64,
+    ) -> Result<Value, Error> {
+        let url = format!("{}/{}/exec", self.machines_url(app_name), machine_id);
+        let body = json!({ "command": command, "timeout": timeout_secs });
+        let response = self.send(self.http.post(url).json(&body)).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn destroy_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        lease_nonce: Option<&str>,
+    ) -> Result<(), Error> {
+        let url = format!("{}/{}?force=true", self.machines_url(app_name), machine_id);
+        let mut request = self.http.delete(url);
+        if let Some(nonce) = lease_nonce {
+            request = request.header(LEASE_NONCE_HEADER, nonce);
+        }
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn list_volumes(&self, app_name: &str) -> Result<Vec<Value>, Error> {
+        let response = self.send(self.http.get(self.volumes_url(app_name))).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn create_volume(&self, app_name: &str, body: &Value) -> Result<Value, Error> {
+        let response = self
+            .send(self.http.post(self.volumes_url(app_name)).json(body))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn get_volume(&self, app_name: &str, volume_id: &str) -> Result<Value, Error> {
+        let url = format!("{}/{}", self.volumes_url(app_name), volume_id);
+        let response = self.send(self.http.get(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Grows the volume to `size_gb`; the response's `needs_restart` says whether the
+    /// machine mounting it must restart to see the space.
+    pub async fn extend_volume(
+        &self,
+        app_name: &str,
+        volume_id: &str,
+        size_gb: u64,
+    ) -> Result<Value, Error> {
+        let url = format!("{}/{}/extend", self.volumes_url(app_name), volume_id);
+        let response = self
+            .send(self.http.put(url).json(&json!({ "size_gb": size_gb })))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn delete_volume(&self, app_name: &str, volume_id: &str) -> Result<Value, Error> {
+        let url = format!("{}/{}", self.volumes_url(app_name), volume_id);
+        let response = self.send(self.http.delete(url)).await?;
+        Ok(response.json().await.unwrap_or_default())
+    }
+
+    /// Starts an on-demand snapshot; it shows up in `list_snapshots` once taken.
+    pub async fn create_snapshot(&self, app_name: &str, volume_id: &str) -> Result<(), Error> {
+        let url = format!("{}/{}/snapshots", self.volumes_url(app_name), volume_id);
+        self.send(self.http.post(url)).await?;
+        Ok(())
+    }
+
+    pub async fn list_snapshots(
+        &self,
+        app_name: &str,
+        volume_id: &str,
+    ) -> Result<Vec<Value>, Error> {
+        let url = format!("{}/{}/snapshots", self.volumes_url(app_name), volume_id);
+        let response = self.send(self.http.get(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// The app's public and private IP addresses; empty where the API doesn't offer them.
+    pub async fn list_ip_assignments(&self, app_name: &str) -> Result<Vec<Value>, Error> {
+        let url = format!("{}/ip_assignments", self.app_url(app_name));
+        let ips: Value = match self.send(self.http.get(url)).await {
+            Ok(response) => response.json().await?,
+            Err(Error::Status { status, .. }) if status == StatusCode::NOT_FOUND => {
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(ips["ips"]
+            .as_array()
+            .or(ips.as_array())
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    pub async fn list_secrets(&self, app_name: &str) -> Result<Vec<Value>, Error> {
+        let url = format!("{}/secrets", self.app_url(app_name));
+        let response = self.send(self.http.get(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn set_secret(&self, app_name: &str, name: &str, value: &str) -> Result<(), Error> {
+        let url = format!("{}/secrets/{}", self.app_url(app_name), name);
+        self.send(self.http.post(url).json(&json!({ "value": value })))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn unset_secret(&self, app_name: &str, name: &str) -> Result<(), Error> {
+        let url = format!("{}/secrets/{}", self.app_url(app_name), name);
+        self.send(self.http.delete(url)).await?;
+        Ok(())
+    }
+
+    /// A page of the machine's logs from Fly's log API at `logs_url`, and the token to
+    /// ask for the lines after it with.
+    pub async fn machine_logs(
+        &self,
+        logs_url: &str,
+        app_name: &str,
+        machine_id: &str,
+        next_token: Option<&str>,
+    ) -> Result<(Vec<LogLine>, Option<String>), Error> {
+        let url = format!(
+            "{}/api/v1/apps/{}/logs",
+            logs_url.trim_end_matches('/'),
+            app_name
+        );
+        let mut query = vec![("instance", machine_id)];
+        if let Some(next_token) = next_token {
+            query.push(("next_token", next_token));
+        }
+        let response = self.send(self.http.get(url).query(&query)).await?;
+        let body: Value = response.json().await?;
+        let lines = body["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|entry| &entry["attributes"])
+            .map(|attributes| LogLine {
+                timestamp: attributes["timestamp"].as_str().map(str::to_string),
+                message: attributes["message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                level: attributes["level"].as_str().map(str::to_string),
+                region: attributes["region"].as_str().map(str::to_string),
+                machine_id: attributes["instance"].as_str().map(str::to_string),
+            })
+            .collect();
+        let next_token = body["meta"]["next_token"]
+            .as_str()
+            .filter(|token| !token.is_empty())
+            .map(str::to_string);
+        Ok((lines, next_token))
+    }
+
+    /// Runs a query against Fly's GraphQL API at `graphql_url`, for the app data the
+    /// Machines API doesn't serve. Errors Fly answers with come back as a 502.
+    pub async fn graphql(
+        &self,
+        graphql_url: &str,
+        query: &str,
+        variables: Value,
+    ) -> Result<Value, Error> {
+        let request = self
+            .http
+            .post(graphql_url)
+            .json(&json!({ "query": query, "variables": variables }));
+        let body: Value = self.send(request).await?.json().await?;
+        match body["errors"]
+            .as_array()
+            .filter(|errors| !errors.is_empty())
+        {
+            Some(errors) => Err(Error::Status {
+                status: StatusCode::BAD_GATEWAY,
+                body: errors
+                    .iter()
+                    .filter_map(|error| error["message"].as_str())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            }),
+            None => Ok(body["data"].clone()),
+        }
+    }
+}
@@ -0,0 +1,508 @@
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, web};
+use flyd::fly::{self, Layer};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use crate::audit;
+use crate::backend::Backend;
+use crate::breaker::CircuitBreaker;
+use crate::cache::ResponseCache;
+use crate::concurrency::AdaptiveLimit;
+use crate::config::{CacheConfig, UpstreamConfig};
+use crate::diagnostics;
+use crate::errors::AppError;
+use crate::hedge::Hedger;
+use crate::idempotency::IDEMPOTENCY_KEY_HEADER;
+use crate::namespaces::{self, Namespace};
+use crate::scans::ScanGate;
+use crate::schemas::Schemas;
+use crate::signatures::ImageVerifier;
+use crate::slo::{Scope, SloTracker, upstream_endpoint};
+use crate::telemetry::UpstreamMetrics;
+use crate::validation_webhook::ValidationWebhook;
+
+pub use flyd::fly::{Error as FlyError, LEASE_NONCE_HEADER, authorization_value};
+
+/// flyd's Machines API client: the library's, with `Checks` around every call.
+pub type FlyClient = fly::FlyClient<Checks>;
+
+/// The nonce of a lease the caller already holds, sent in `fly-machine-lease-nonce`.
+/// Operations use it instead of taking their own.
+pub fn held_lease(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(LEASE_NONCE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+struct Upstream {
+    config: UpstreamConfig,
+    breaker: CircuitBreaker,
+    cache: ResponseCache,
+    metrics: UpstreamMetrics,
+    validation: Option<ValidationWebhook>,
+}
+
+static UPSTREAM: OnceLock<Upstream> = OnceLock::new();
+
+/// Sets how every Machines API call is timed out, retried and cut off, and how long GETs
+/// may be cached; the defaults apply until then.
+pub fn configure(config: &UpstreamConfig, cache: &CacheConfig) {
+    let _ = UPSTREAM.set(Upstream {
+        config: config.clone(),
+        breaker: CircuitBreaker::new(config.breaker.clone()),
+        cache: ResponseCache::new(Duration::from_secs(cache.list_ttl_secs)),
+        metrics: UpstreamMetrics::default(),
+        validation: config
+            .validation_webhook
+            .clone()
+            .map(ValidationWebhook::new),
+    });
+}
+
+fn upstream() -> &'static Upstream {
+    UPSTREAM.get_or_init(|| Upstream {
+        config: UpstreamConfig::default(),
+        breaker: CircuitBreaker::new(Default::default()),
+        cache: ResponseCache::new(Duration::ZERO),
+        metrics: UpstreamMetrics::default(),
+        validation: None,
+    })
+}
+
+/// The Machines API's base URL, over Fly's private network or not.
+pub fn api_hostname(use_private: bool) -> &'static str {
+    let config = &upstream().config;
+    if use_private {
+        &config.private_hostname
+    } else {
+        &config.public_hostname
+    }
+}
+
+pub fn breaker() -> &'static CircuitBreaker {
+    &upstream().breaker
+}
+
+/// Cached GETs, dropped for an app whenever a call through `execute` may have changed it.
+pub fn cache() -> &'static ResponseCache {
+    &upstream().cache
+}
+
+pub fn metrics() -> &'static UpstreamMetrics {
+    &upstream().metrics
+}
+
+/// The app a Machines API path is under, e.g. `a` for `/v1/apps/a/machines`.
+fn path_app(path: &str) -> Option<&str> {
+    let mut segments = path.split('/').skip_while(|segment| *segment != "apps");
+    segments.next()?;
+    segments.next().filter(|app| !app.is_empty())
+}
+
+/// The caller's `Idempotency-Key`, or a new one, for a machine creation.
+pub fn idempotency_key(req: Option<&HttpRequest>) -> String {
+    req.and_then(|req| req.headers().get(IDEMPOTENCY_KEY_HEADER))
+        .and_then(|value| value.to_str().ok())
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(fly::new_idempotency_key)
+}
+
+/// Connection errors, timeouts and gateway errors count against the host's circuit; other
+/// errors are Fly answering.
+fn failed(result: &reqwest::Result<reqwest::Response>) -> bool {
+    result.as_ref().map_or(true, |response| {
+        matches!(
+            response.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        )
+    })
+}
+
+/// Sends a Machines API request, hedged if there's a hedger, and retries it with jittered
+/// exponential backoff while it fails transiently. POSTs are only repeated after Fly may
+/// have acted on them when they carry an `Idempotency-Key`. Requests without a timeout get
+/// the one `upstream.timeouts` sets for their endpoint, and none are sent while the host's
+/// circuit is open. Calls that may change something must first pass the validation
+/// webhook, if there is one.
+pub async fn execute(
+    http: &reqwest::Client,
+    mut request: reqwest::Request,
+    hedger: Option<&Hedger>,
+    limit: Option<&AdaptiveLimit>,
+) -> Result<reqwest::Response, FlyError> {
+    let upstream = upstream();
+    let timeouts = &upstream.config.timeouts;
+    let endpoint = upstream_endpoint(request.method(), request.url().path());
+    if request.timeout().is_none() {
+        let timeout_ms = timeouts
+            .routes
+            .get(&endpoint)
+            .copied()
+            .unwrap_or(timeouts.default_ms);
+        if timeout_ms > 0 {
+            *request.timeout_mut() = Some(Duration::from_millis(timeout_ms));
+        }
+    }
+    let host = request.url().origin().ascii_serialization();
+    let mutating = !matches!(
+        *request.method(),
+        reqwest::Method::GET | reqwest::Method::HEAD
+    );
+    if mutating && let Some(validation) = &upstream.validation {
+        validation
+            .check(http, &request)
+            .await
+            .map_err(FlyError::Rejected)?;
+    }
+    let mutated_app = mutating
+        .then(|| path_app(request.url().path()).map(str::to_string))
+        .flatten();
+    let (host, endpoint) = (&host, &endpoint);
+    let result = fly::retrying(request, &upstream.config.retry, |request| async move {
+        if let Err(retry_after) = upstream.breaker.admit(host) {
+            return Err(FlyError::CircuitOpen {
+                host: host.clone(),
+                retry_after,
+            });
+        }
+        let started = Instant::now();
+        let result = match hedger {
+            Some(hedger) => hedger.execute(http, request).await,
+            None => http.execute(request).await,
+        };
+        upstream.breaker.record(host, failed(&result));
+        if let Ok(response) = &result {
+            audit::upstream_status(response.status().as_u16());
+        }
+        upstream.metrics.record(
+            endpoint,
+            result
+                .as_ref()
+                .ok()
+                .map(|response| response.status().as_u16()),
+            started.elapsed(),
+        );
+        if let (Some(limit), Ok(response)) = (limit, &result) {
+            let throttled = matches!(
+                response.status(),
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+            );
+            limit.record(started.elapsed(), throttled);
+        }
+        Ok(result)
+    })
+    .await;
+    // Even a failed call may have gone through, e.g. one that timed out.
+    if let Some(app) = &mutated_app {
+        upstream.cache.invalidate(app);
+    }
+    result
+}
+
+impl From<&FlyError> for AppError {
+    fn from(e: &FlyError) -> Self {
+        match e {
+            FlyError::Request(request) if request.is_timeout() => {
+                AppError::new(actix_web::http::StatusCode::GATEWAY_TIMEOUT, e.to_string())
+                    .with_code("upstream_timeout")
+            }
+            FlyError::Request(_) => {
+                AppError::bad_gateway(e.to_string()).with_code("upstream_unreachable")
+            }
+            FlyError::Status { status, body } => AppError::upstream(status.as_u16(), body),
+            FlyError::Rejected(reason) => {
+                AppError::unprocessable(reason.clone()).with_code("rejected")
+            }
+            FlyError::CircuitOpen { retry_after, .. } => {
+                AppError::service_unavailable(e.to_string())
+                    .with_code("upstream_circuit_open")
+                    .with_retry_after(*retry_after)
+            }
+        }
+    }
+}
+
+/// What flyd adds around the Machines API calls it makes, either on behalf of a caller
+/// (reusing the headers from `prepare_request`) or by background subsystems using
+/// `FLY_API_TOKEN`: `execute`'s breaker, retries and caching, SLO tracking, and the
+/// policy checks `WithChecks` adds.
+#[derive(Clone, Default)]
+pub struct Checks {
+    slo: Option<Arc<SloTracker>>,
+    hedger: Option<Arc<Hedger>>,
+    signatures: Option<Arc<ImageVerifier>>,
+    scans: Option<Arc<ScanGate>>,
+    namespace: Option<Arc<Namespace>>,
+    limit: Option<Arc<AdaptiveLimit>>,
+    schemas: Option<Arc<Schemas>>,
+}
+
+impl Layer for Checks {
+    async fn execute(
+        &self,
+        http: &reqwest::Client,
+        request: reqwest::Request,
+    ) -> Result<reqwest::Response, FlyError> {
+        let endpoint = upstream_endpoint(request.method(), request.url().path());
+        let started = Instant::now();
+        let result = execute(http, request, self.hedger.as_deref(), self.limit.as_deref()).await;
+        diagnostics::upstream_headers(started);
+        if let Some(slo) = &self.slo {
+            let failed = result
+                .as_ref()
+                .map_or(true, |response| response.status().is_server_error());
+            slo.record(Scope::Upstream, endpoint, started.elapsed(), failed);
+        }
+        result
+    }
+
+    async fn verify(
+        &self,
+        client: &FlyClient,
+        app_name: &str,
+        body: &Value,
+        creating: bool,
+    ) -> Result<Option<Value>, FlyError> {
+        if self.signatures.is_none() && self.scans.is_none() && self.namespace.is_none() {
+            return Ok(None);
+        }
+        let mut body = body.clone();
+        if let Some(namespace) = &self.namespace {
+            if creating {
+                namespace.fill(&mut body);
+            }
+            namespace
+                .check(app_name, &body)
+                .map_err(FlyError::Rejected)?;
+            if creating {
+                namespace
+                    .check_machine_quota(client, self.limit.as_deref())
+                    .await
+                    .map_err(FlyError::Rejected)?;
+            }
+        }
+        if let Some(signatures) = &self.signatures {
+            signatures
+                .check(app_name, &mut body["config"])
+                .await
+                .map_err(FlyError::Rejected)?;
+        }
+        if let Some(scans) = &self.scans {
+            scans
+                .check(app_name, &body["config"])
+                .await
+                .map_err(FlyError::Rejected)?;
+        }
+        Ok(Some(body))
+    }
+
+    async fn verify_app(
+        &self,
+        client: &FlyClient,
+        app_name: &str,
+        org_slug: &str,
+    ) -> Result<(), FlyError> {
+        let Some(namespace) = &self.namespace else {
+            return Ok(());
+        };
+        if !namespace.owns(app_name) {
+            return Err(FlyError::Rejected(format!(
+                "Namespace {} names its apps {}*",
+                namespace.name, namespace.config.prefix
+            )));
+        }
+        namespace
+            .check_app_quota(client, app_name, org_slug)
+            .await
+            .map_err(FlyError::Rejected)
+    }
+
+    fn answered(&self, endpoint: &str, machines: &[Value]) {
+        if let Some(schemas) = &self.schemas {
+            schemas.check_upstream(endpoint, "Machine", machines);
+        }
+    }
+}
+
+/// Adds flyd's `Checks` to a `FlyClient`.
+pub trait WithChecks: Sized {
+    fn with_slo(self, slo: Arc<SloTracker>) -> Self;
+
+    /// Hedges slow GETs; meant for interactive handlers, not background loops.
+    fn with_hedger(self, hedger: Option<Arc<Hedger>>) -> Self;
+
+    /// Checks the image of every machine created or updated against the signature policy.
+    fn with_signatures(self, signatures: Option<Arc<ImageVerifier>>) -> Self;
+
+    /// Refuses images with vulnerabilities at the app's blocking severity.
+    fn with_scans(self, scans: Option<Arc<ScanGate>>) -> Self;
+
+    /// Holds every machine and app created or updated to the caller's namespace.
+    fn with_namespace(self, namespace: Option<Arc<Namespace>>) -> Self;
+
+    /// Reports every call's latency and throttling to the fan-out concurrency limit.
+    fn with_limit(self, limit: Option<Arc<AdaptiveLimit>>) -> Self;
+
+    /// Checks the machines Fly answers with against flyd's models, with `schemas.strict`.
+    fn with_schemas(self, schemas: Option<Arc<Schemas>>) -> Self;
+
+    /// Checks images with whichever of the signature policy and the scan gate flyd has,
+    /// and machines against the caller's namespace.
+    fn with_request_checks(self, req: &HttpRequest) -> Self {
+        self.with_namespace(namespaces::of(req))
+            .with_signatures(
+                req.app_data::<web::Data<ImageVerifier>>()
+                    .map(|signatures| signatures.clone().into_inner()),
+            )
+            .with_scans(
+                req.app_data::<web::Data<ScanGate>>()
+                    .map(|scans| scans.clone().into_inner()),
+            )
+            .with_schemas(
+                req.app_data::<web::Data<Schemas>>()
+                    .map(|schemas| schemas.clone().into_inner()),
+            )
+    }
+}
+
+impl WithChecks for FlyClient {
+    fn with_slo(mut self, slo: Arc<SloTracker>) -> Self {
+        self.layer_mut().slo = Some(slo);
+        self
+    }
+
+    fn with_hedger(mut self, hedger: Option<Arc<Hedger>>) -> Self {
+        self.layer_mut().hedger = hedger;
+        self
+    }
+
+    fn with_signatures(mut self, signatures: Option<Arc<ImageVerifier>>) -> Self {
+        self.layer_mut().signatures = signatures;
+        self
+    }
+
+    fn with_scans(mut self, scans: Option<Arc<ScanGate>>) -> Self {
+        self.layer_mut().scans = scans;
+        self
+    }
+
+    fn with_namespace(mut self, namespace: Option<Arc<Namespace>>) -> Self {
+        self.layer_mut().namespace = namespace;
+        self
+    }
+
+    fn with_limit(mut self, limit: Option<Arc<AdaptiveLimit>>) -> Self {
+        self.layer_mut().limit = limit;
+        self
+    }
+
+    fn with_schemas(mut self, schemas: Option<Arc<Schemas>>) -> Self {
+        self.layer_mut().schemas = schemas;
+        self
+    }
+}
+
+impl Backend for FlyClient {
+    type Error = FlyError;
+
+    async fn list_apps(&self, org_slug: &str) -> Result<Vec<Value>, Self::Error> {
+        fly::FlyClient::list_apps(self, org_slug).await
+    }
+
+    async fn list_machines(&self, app_name: &str) -> Result<Vec<Value>, Self::Error> {
+        fly::FlyClient::list_machines(self, app_name).await
+    }
+
+    async fn list_region_machines(
+        &self,
+        app_name: &str,
+        region: &str,
+    ) -> Result<Vec<Value>, Self::Error> {
+        fly::FlyClient::list_region_machines(self, app_name, region).await
+    }
+
+    async fn get_machine(&self, app_name: &str, machine_id: &str) -> Result<Value, Self::Error> {
+        fly::FlyClient::get_machine(self, app_name, machine_id).await
+    }
+
+    async fn create_machine(&self, app_name: &str, body: &Value) -> Result<Value, Self::Error> {
+        fly::FlyClient::create_machine(self, app_name, body).await
+    }
+
+    async fn update_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        body: &Value,
+        lease_nonce: Option<&str>,
+    ) -> Result<Value, Self::Error> {
+        fly::FlyClient::update_machine(self, app_name, machine_id, body, lease_nonce).await
+    }
+
+    async fn start_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        fly::FlyClient::start_machine(self, app_name, machine_id).await
+    }
+
+    async fn stop_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        fly::FlyClient::stop_machine(self, app_name, machine_id).await
+    }
+
+    async fn restart_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        fly::FlyClient::restart_machine(self, app_name, machine_id).await
+    }
+
+    async fn cordon_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        fly::FlyClient::cordon_machine(self, app_name, machine_id).await
+    }
+
+    async fn uncordon_machine(&self, app_name: &str, machine_id: &str) -> Result<(), Self::Error> {
+        fly::FlyClient::uncordon_machine(self, app_name, machine_id).await
+    }
+
+    async fn exec_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        command: &[String],
+        timeout_secs: u64,
+    ) -> Result<Value, Self::Error> {
+        fly::FlyClient::exec_machine(self, app_name, machine_id, command, timeout_secs).await
+    }
+
+    async fn destroy_machine(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        lease_nonce: Option<&str>,
+    ) -> Result<(), Self::Error> {
+        fly::FlyClient::destroy_machine(self, app_name, machine_id, lease_nonce).await
+    }
+
+    async fn set_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Self::Error> {
+        fly::FlyClient::set_metadata(self, app_name, machine_id, key, value).await
+    }
+
+    async fn delete_metadata(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        key: &str,
+    ) -> Result<(), Self::Error> {
+        fly::FlyClient::delete_metadata(self, app_name, machine_id, key).await
+    }
+
+    async fn list_volumes(&self, app_name: &str) -> Result<Vec<Value>, Self::Error> {
+        fly::FlyClient::list_volumes(self, app_name).await
+    }
+}
@@ -0,0 +1,332 @@
+use std::str::FromStr;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use flyd::models::{Freeze, NewFreeze};
+use serde_json::json;
+
+use crate::auth::Identity;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::grants::Grants;
+use crate::namespaces;
+use crate::store::Store;
+
+const FREEZES: &str = "freezes";
+const FREEZE_DECLARED: &str = "freeze.declared";
+const FREEZE_LIFTED: &str = "freeze.lifted";
+const FREEZE_OVERRIDDEN: &str = "freeze.overridden";
+
+/// When the window of the freeze that `at` falls in ends, if it falls in one.
+fn window_end(freeze: &Freeze, at: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+    match (&freeze.cron, freeze.from, freeze.until) {
+        (Some(expression), _, _) => {
+            let timezone = match &freeze.timezone {
+                Some(name) => {
+                    Tz::from_str(name).map_err(|_| format!("Unknown timezone {}", name))?
+                }
+                None => Tz::UTC,
+            };
+            let cron = Cron::from_str(expression)
+                .map_err(|e| format!("Invalid cron expression {}: {}", expression, e))?;
+            let duration = TimeDelta::minutes(freeze.duration_mins.unwrap_or(0) as i64);
+            // The first window to start after `at - duration` is the one `at` is in, if it
+            // has started yet.
+            let start = cron
+                .find_next_occurrence(&(at - duration).with_timezone(&timezone), false)
+                .map_err(|e| format!("No next window for {}: {}", expression, e))?
+                .with_timezone(&Utc);
+            Ok((start <= at).then_some(start + duration))
+        }
+        (None, Some(from), Some(until)) => Ok((from <= at && at < until).then_some(until)),
+        _ => Ok(None),
+    }
+}
+
+fn in_force(freeze: &Freeze, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    window_end(freeze, at).unwrap_or_else(|e| {
+        log::warn!("Ignoring freeze {} of {}: {}", freeze.id, freeze.app, e);
+        None
+    })
+}
+
+/// Whether the caller has `role`, of their own or through a grant on `app`.
+async fn may_override(
+    identity: Option<&Identity>,
+    role: &str,
+    grants: Option<&Grants>,
+    app: &str,
+    action: &str,
+) -> bool {
+    match (identity, grants) {
+        (Some(identity), _) if identity.roles.iter().any(|held| held == role) => true,
+        (Some(identity), Some(grants)) => grants
+            .elevate(identity, app, action)
+            .await
+            .roles
+            .iter()
+            .any(|held| held == role),
+        _ => false,
+    }
+}
+
+/// `/v0/apps/{app}/freezes...`, which stay open so a freeze can be declared or looked at
+/// during one. Lifting one in force takes the override role.
+fn is_freeze_route(path: &str) -> bool {
+    path.strip_prefix("/v0/apps/")
+        .and_then(|rest| rest.split('/').nth(1))
+        .is_some_and(|segment| segment == "freezes")
+}
+
+/// Refuses requests that change an app while one of its freezes is in force, unless the
+/// caller has `freezes.override_role`, of their own or through a grant. Every override is
+/// recorded. Only requests are held back; schedules and reconcilers keep running.
+pub async fn enforce<B: MessageBody + 'static>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let store = req
+        .app_data::<web::Data<Store>>()
+        .cloned()
+        .filter(|_| !req.method().is_safe() && !is_freeze_route(req.path()));
+    let Some(store) = store else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let apps = namespaces::request_apps(&mut req).await?;
+    if apps.is_empty() {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+    let freezes = match store.list::<Freeze>(FREEZES).await {
+        Ok(freezes) => freezes,
+        Err(e) => {
+            return Ok(req.into_response(
+                AppError::internal(format!("Failed to load freezes: {}", e)).into_response(),
+            ));
+        }
+    };
+    let now = Utc::now();
+    let frozen: Vec<(Freeze, DateTime<Utc>)> = freezes
+        .into_iter()
+        .filter(|freeze| apps.contains(&freeze.app))
+        .filter_map(|freeze| in_force(&freeze, now).map(|until| (freeze, until)))
+        .collect();
+    if frozen.is_empty() {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+
+    let identity = req.extensions().get::<Identity>().cloned();
+    let role = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.freezes.override_role.clone())
+        .unwrap_or_default();
+    let grants = req.app_data::<web::Data<Grants>>().cloned();
+    for (freeze, until) in &frozen {
+        if may_override(
+            identity.as_ref(),
+            &role,
+            grants.as_ref().map(|grants| grants.get_ref()),
+            &freeze.app,
+            "freeze_override",
+        )
+        .await
+        {
+            continue;
+        }
+        let message = format!(
+            "{} is frozen until {}{}",
+            freeze.app,
+            until.to_rfc3339(),
+            freeze
+                .reason
+                .as_ref()
+                .map(|reason| format!(": {}", reason))
+                .unwrap_or_default()
+        );
+        log::info!("Refused {} {}: {}", req.method(), req.path(), message);
+        let retry_after = (*until - now).to_std().unwrap_or_default();
+        return Ok(req.into_response(
+            AppError::new(StatusCode::LOCKED, message)
+                .with_code("app_frozen")
+                .with_retry_after(retry_after)
+                .into_response(),
+        ));
+    }
+
+    if let Some(events) = req.app_data::<web::Data<EventLog>>() {
+        for (freeze, until) in &frozen {
+            events.record(
+                FREEZE_OVERRIDDEN,
+                Some(&freeze.app),
+                None,
+                json!({
+                    "freeze_id": freeze.id,
+                    "reason": freeze.reason,
+                    "until": until,
+                    "method": req.method().as_str(),
+                    "path": req.path(),
+                    "by": identity.as_ref().map(|identity| identity.subject.clone()),
+                }),
+            );
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+fn caller(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<Identity>()
+        .map(|identity| identity.subject.clone())
+}
+
+#[post("/v0/apps/{app}/freezes")]
+async fn create_freeze(
+    req: HttpRequest,
+    app: web::Path<String>,
+    body: web::Json<NewFreeze>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+) -> impl Responder {
+    let new = body.into_inner();
+    let now = Utc::now();
+    match (&new.cron, new.from, new.until) {
+        (Some(_), None, None) if new.duration_mins.is_some_and(|mins| mins > 0) => {}
+        (Some(_), None, None) => {
+            return AppError::bad_request("A recurring freeze needs a duration_mins")
+                .into_response();
+        }
+        (None, Some(from), Some(until)) if from < until && until > now => {}
+        (None, Some(_), Some(_)) => {
+            return AppError::bad_request("until must be after from and in the future")
+                .into_response();
+        }
+        _ => {
+            return AppError::bad_request(
+                "A freeze needs either cron and duration_mins or from and until",
+            )
+            .into_response();
+        }
+    }
+
+    let mut id = [0u8; 8];
+    getrandom::fill(&mut id).expect("the OS random number generator is available");
+    let mut freeze = Freeze {
+        id: hex::encode(id),
+        app: app.into_inner(),
+        cron: new.cron,
+        timezone: new.timezone,
+        duration_mins: new.duration_mins,
+        from: new.from,
+        until: new.until,
+        reason: new.reason,
+        created_by: caller(&req),
+        created_at: now,
+        in_force_until: None,
+    };
+    if let Err(e) = window_end(&freeze, now) {
+        return AppError::bad_request(e).into_response();
+    }
+    if let Err(e) = store.put(FREEZES, &freeze.id, &freeze).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    events.record(
+        FREEZE_DECLARED,
+        Some(&freeze.app),
+        None,
+        json!({ "freeze": freeze, "by": freeze.created_by }),
+    );
+    freeze.in_force_until = in_force(&freeze, now);
+    HttpResponse::Created().json(freeze)
+}
+
+/// The app's freezes, with when the one in force ends.
+#[get("/v0/apps/{app}/freezes")]
+async fn list_freezes(app: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    let now = Utc::now();
+    match store.list::<Freeze>(FREEZES).await {
+        Ok(freezes) => HttpResponse::Ok().json(
+            freezes
+                .into_iter()
+                .filter(|freeze| freeze.app == *app)
+                .map(|mut freeze| {
+                    freeze.in_force_until = in_force(&freeze, now);
+                    freeze
+                })
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[delete("/v0/apps/{app}/freezes/{id}")]
+async fn delete_freeze(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    config: web::Data<Config>,
+    grants: Option<web::Data<Grants>>,
+) -> impl Responder {
+    let (app, id) = path.into_inner();
+    let freeze = match store.get::<Freeze>(FREEZES, &id).await {
+        Ok(Some(freeze)) if freeze.app == app => freeze,
+        Ok(_) => {
+            return AppError::not_found(format!("No freeze {} on {}", id, app)).into_response();
+        }
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    if in_force(&freeze, Utc::now()).is_some() {
+        let identity = req.extensions().get::<Identity>().cloned();
+        let role = &config.freezes.override_role;
+        if !may_override(
+            identity.as_ref(),
+            role,
+            grants.as_ref().map(|grants| grants.get_ref()),
+            &app,
+            "freeze_lift",
+        )
+        .await
+        {
+            return AppError::forbidden(format!(
+                "Lifting a freeze while it's in force requires role {}",
+                role
+            ))
+            .into_response();
+        }
+    }
+    match store.delete(FREEZES, &id).await {
+        Ok(_) => {
+            events.record(
+                FREEZE_LIFTED,
+                Some(&app),
+                None,
+                json!({ "freeze_id": id, "reason": freeze.reason, "by": caller(&req) }),
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_freeze)
+        .service(list_freezes)
+        .service(delete_freeze);
+}
@@ -0,0 +1,360 @@
+use std::cmp::Reverse;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use chrono::{TimeDelta, Utc};
+use flyd::models::{Grant, GrantsQuery, NewGrant};
+use serde_json::json;
+
+use crate::auth::{self, Identity};
+use crate::config::{Config, GrantsConfig};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::store::{Store, StoreError};
+
+const GRANTS: &str = "grants";
+const GRANT_ISSUED: &str = "grant.issued";
+const GRANT_USED: &str = "grant.used";
+const GRANT_REVOKED: &str = "grant.revoked";
+const GRANT_EXPIRED: &str = "grant.expired";
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
+fn is_active(grant: &Grant) -> bool {
+    grant.ended_at.is_none() && grant.expires_at > Utc::now()
+}
+
+/// Time-limited roles on top of a caller's own. Every grant issued, used, revoked or
+/// expired is recorded as an event.
+pub struct Grants {
+    config: GrantsConfig,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+}
+
+impl Grants {
+    pub fn from_config(
+        config: &Config,
+        store: web::Data<Store>,
+        events: web::Data<EventLog>,
+    ) -> Option<Self> {
+        Some(Grants {
+            config: config.grants.clone()?,
+            store,
+            events,
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<Grant>, StoreError> {
+        self.store.list::<Grant>(GRANTS).await
+    }
+
+    /// The identity with the roles of its active grants for `app` added. Each grant that
+    /// adds a role is recorded as used.
+    pub async fn elevate(&self, identity: &Identity, app: &str, action: &str) -> Identity {
+        let mut elevated = identity.clone();
+        let grants = match self.list().await {
+            Ok(grants) => grants,
+            Err(e) => {
+                log::error!("Failed to load grants: {}", e);
+                return elevated;
+            }
+        };
+        for grant in grants.iter().filter(|grant| {
+            grant.subject == identity.subject
+                && is_active(grant)
+                && (grant.apps.is_empty() || grant.apps.iter().any(|granted| granted == app))
+        }) {
+            let added: Vec<&String> = grant
+                .roles
+                .iter()
+                .filter(|role| !elevated.roles.contains(role))
+                .collect();
+            if added.is_empty() {
+                continue;
+            }
+            self.events.record(
+                GRANT_USED,
+                Some(app),
+                None,
+                json!({ "grant_id": grant.id, "subject": grant.subject, "roles": added, "action": action }),
+            );
+            elevated.roles.extend(added.into_iter().cloned());
+        }
+        elevated
+    }
+
+    fn admin(&self, req: &HttpRequest) -> Result<String, HttpResponse> {
+        auth::require_role(req, Some(&self.config.admin_role)).map(|identity| identity.subject)
+    }
+
+    fn record(&self, kind: &str, grant: &Grant) {
+        let apps: Vec<Option<&str>> = if grant.apps.is_empty() {
+            vec![None]
+        } else {
+            grant.apps.iter().map(|app| Some(app.as_str())).collect()
+        };
+        for app in apps {
+            self.events.record(
+                kind,
+                app,
+                None,
+                json!({
+                    "grant_id": grant.id,
+                    "subject": grant.subject,
+                    "roles": grant.roles,
+                    "reason": grant.reason,
+                    "granted_by": grant.granted_by,
+                    "expires_at": grant.expires_at,
+                    "revoked_by": grant.revoked_by,
+                }),
+            );
+        }
+    }
+
+    /// Ends grants past their expiry, so their end is on the record.
+    pub async fn expire_loop(grants: web::Data<Self>) {
+        let mut ticker = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let expired = match grants.list().await {
+                Ok(listed) => listed
+                    .into_iter()
+                    .filter(|grant| grant.ended_at.is_none() && grant.expires_at <= Utc::now()),
+                Err(e) => {
+                    log::error!("Failed to load grants: {}", e);
+                    continue;
+                }
+            };
+            for mut grant in expired {
+                grant.ended_at = Some(grant.expires_at);
+                if let Err(e) = grants.store.put(GRANTS, &grant.id, &grant).await {
+                    log::error!("Failed to expire grant {}: {}", grant.id, e);
+                    continue;
+                }
+                grants.record(GRANT_EXPIRED, &grant);
+            }
+        }
+    }
+}
+
+#[post("/v0/admin/grants")]
+async fn issue_grant(
+    req: HttpRequest,
+    body: web::Json<NewGrant>,
+    grants: Option<web::Data<Grants>>,
+) -> impl Responder {
+    let Some(grants) = grants else {
+        return AppError::not_found("Grants aren't enabled").into_response();
+    };
+    let granted_by = match grants.admin(&req) {
+        Ok(subject) => subject,
+        Err(response) => return response,
+    };
+    let new = body.into_inner();
+    if new.roles.is_empty() {
+        return AppError::bad_request("A grant needs at least one role").into_response();
+    }
+    if new.reason.trim().is_empty() {
+        return AppError::bad_request("A grant needs a reason").into_response();
+    }
+    if new.duration_secs == 0 || new.duration_secs > grants.config.max_duration_secs {
+        return AppError::bad_request(format!(
+            "duration_secs must be between 1 and {}",
+            grants.config.max_duration_secs
+        ))
+        .into_response();
+    }
+
+    let mut id = [0u8; 8];
+    getrandom::fill(&mut id).expect("the OS random number generator is available");
+    let now = Utc::now();
+    let grant = Grant {
+        id: hex::encode(id),
+        subject: new.subject,
+        roles: new.roles,
+        apps: new.apps,
+        reason: new.reason,
+        granted_by,
+        granted_at: now,
+        expires_at: now + TimeDelta::seconds(new.duration_secs as i64),
+        revoked_by: None,
+        ended_at: None,
+    };
+    if let Err(e) = grants.store.put(GRANTS, &grant.id, &grant).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    grants.record(GRANT_ISSUED, &grant);
+    HttpResponse::Created().json(grant)
+}
+
+/// Active grants, newest first; with `all`, expired and revoked ones too.
+#[get("/v0/admin/grants")]
+async fn list_grants(
+    req: HttpRequest,
+    query: web::Query<GrantsQuery>,
+    grants: Option<web::Data<Grants>>,
+) -> impl Responder {
+    let Some(grants) = grants else {
+        return AppError::not_found("Grants aren't enabled").into_response();
+    };
+    if let Err(response) = grants.admin(&req) {
+        return response;
+    }
+    let mut listed: Vec<Grant> = match grants.list().await {
+        Ok(listed) => listed
+            .into_iter()
+            .filter(|grant| query.all || is_active(grant))
+            .filter(|grant| {
+                query
+                    .subject
+                    .as_ref()
+                    .is_none_or(|subject| &grant.subject == subject)
+            })
+            .collect(),
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    listed.sort_by_key(|grant| Reverse(grant.granted_at));
+    HttpResponse::Ok().json(listed)
+}
+
+/// Ends a grant before it expires.
+#[delete("/v0/admin/grants/{id}")]
+async fn revoke_grant(
+    req: HttpRequest,
+    path: web::Path<String>,
+    grants: Option<web::Data<Grants>>,
+) -> impl Responder {
+    let Some(grants) = grants else {
+        return AppError::not_found("Grants aren't enabled").into_response();
+    };
+    let revoked_by = match grants.admin(&req) {
+        Ok(subject) => subject,
+        Err(response) => return response,
+    };
+    let mut grant = match grants.store.get::<Grant>(GRANTS, &path).await {
+        Ok(Some(grant)) if is_active(&grant) => grant,
+        Ok(_) => return AppError::not_found(format!("No active grant {}", path)).into_response(),
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    grant.revoked_by = Some(revoked_by);
+    grant.ended_at = Some(Utc::now());
+    if let Err(e) = grants.store.put(GRANTS, &grant.id, &grant).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    grants.record(GRANT_REVOKED, &grant);
+    HttpResponse::Ok().json(grant)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(issue_grant)
+        .service(list_grants)
+        .service(revoke_grant);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderValue;
+
+    use super::*;
+    use crate::config::WriteQueueConfig;
+    use crate::write_queue::WriteQueue;
+
+    fn grants() -> Grants {
+        let (persist, _writes) = WriteQueue::new(&WriteQueueConfig::default());
+        Grants {
+            config: GrantsConfig {
+                admin_role: "grants-admin".to_string(),
+                max_duration_secs: 3600,
+            },
+            store: web::Data::new(Store::default()),
+            events: web::Data::new(EventLog::new(persist)),
+        }
+    }
+
+    fn identity(subject: &str, roles: &[&str]) -> Identity {
+        Identity {
+            subject: subject.to_string(),
+            provider: "static_keys",
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            fly_authorization: HeaderValue::from_static("Bearer own"),
+            apps: None,
+        }
+    }
+
+    /// An active grant of `roles` to alice on `apps`, expiring in an hour.
+    async fn issue(grants: &Grants, id: &str, roles: &[&str], apps: &[&str]) -> Grant {
+        let now = Utc::now();
+        let grant = Grant {
+            id: id.to_string(),
+            subject: "alice".to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            apps: apps.iter().map(|app| app.to_string()).collect(),
+            reason: "incident".to_string(),
+            granted_by: "carol".to_string(),
+            granted_at: now,
+            expires_at: now + TimeDelta::hours(1),
+            revoked_by: None,
+            ended_at: None,
+        };
+        grants.store.put(GRANTS, id, &grant).await.unwrap();
+        grant
+    }
+
+    fn used(grants: &Grants) -> Vec<String> {
+        grants
+            .events
+            .since(Utc::now() - TimeDelta::minutes(1))
+            .into_iter()
+            .filter(|event| event.kind == GRANT_USED)
+            .map(|event| event.detail["grant_id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn elevates_only_the_grantee_on_the_granted_apps() {
+        let grants = grants();
+        issue(&grants, "web-exec", &["exec"], &["web"]).await;
+        issue(&grants, "anywhere", &["deployer"], &[]).await;
+
+        let alice = identity("alice", &["viewer"]);
+        let mut on_web = grants.elevate(&alice, "web", "exec").await.roles;
+        on_web.sort();
+        assert_eq!(on_web, ["deployer", "exec", "viewer"]);
+        let on_api = grants.elevate(&alice, "api", "exec").await;
+        assert_eq!(on_api.roles, ["viewer", "deployer"]);
+        let mut recorded = used(&grants);
+        recorded.sort();
+        assert_eq!(recorded, ["anywhere", "anywhere", "web-exec"]);
+
+        let bob = identity("bob", &[]);
+        assert!(grants.elevate(&bob, "web", "exec").await.roles.is_empty());
+        assert_eq!(used(&grants).len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn a_role_already_held_isnt_recorded_as_used() {
+        let grants = grants();
+        issue(&grants, "web-exec", &["exec"], &["web"]).await;
+        let alice = identity("alice", &["exec"]);
+        assert_eq!(grants.elevate(&alice, "web", "exec").await.roles, ["exec"]);
+        assert!(used(&grants).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn expired_and_revoked_grants_elevate_nothing() {
+        let grants = grants();
+        let mut expired = issue(&grants, "expired", &["exec"], &["web"]).await;
+        expired.expires_at = Utc::now() - TimeDelta::seconds(1);
+        grants.store.put(GRANTS, "expired", &expired).await.unwrap();
+        let mut revoked = issue(&grants, "revoked", &["deployer"], &["web"]).await;
+        revoked.revoked_by = Some("carol".to_string());
+        revoked.ended_at = Some(Utc::now());
+        grants.store.put(GRANTS, "revoked", &revoked).await.unwrap();
+
+        assert!(!is_active(&expired));
+        assert!(!is_active(&revoked));
+        let alice = identity("alice", &[]);
+        assert!(grants.elevate(&alice, "web", "exec").await.roles.is_empty());
+        assert!(used(&grants).is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::{GraphEdge, GraphFormat, GraphNode, GraphQuery, ResourceGraph};
+use serde_json::Value;
+
+use crate::backend::Backend;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+fn field(resource: &Value, key: &str) -> Option<String> {
+    resource[key].as_str().map(str::to_string)
+}
+
+/// Adds the node once, returning its id.
+fn node(graph: &mut ResourceGraph, kind: &str, id: &str, resource: &Value) -> String {
+    let node_id = format!("{}:{}", kind, id);
+    if !graph.nodes.iter().any(|node| node.id == node_id) {
+        graph.nodes.push(GraphNode {
+            id: node_id.clone(),
+            kind: kind.to_string(),
+            name: field(resource, "name"),
+            state: field(resource, "state"),
+            region: field(resource, "region"),
+        });
+    }
+    node_id
+}
+
+fn edge(graph: &mut ResourceGraph, from: &str, to: &str, relation: &str) {
+    graph.edges.push(GraphEdge {
+        from: from.to_string(),
+        to: to.to_string(),
+        relation: relation.to_string(),
+    });
+}
+
+/// The app, its machines and volumes, and the machines' private IPs.
+async fn build<B: Backend>(backend: &B, app: &str) -> Result<ResourceGraph, String> {
+    let machines = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?;
+    let volumes = backend
+        .list_volumes(app)
+        .await
+        .map_err(|e| format!("Failed to list volumes: {}", e))?;
+
+    let mut graph = ResourceGraph {
+        app: app.to_string(),
+        nodes: Vec::new(),
+        edges: Vec::new(),
+    };
+    let root = node(&mut graph, "app", app, &serde_json::json!({ "name": app }));
+    for volume in &volumes {
+        let Some(id) = volume["id"].as_str() else {
+            continue;
+        };
+        let volume = node(&mut graph, "volume", id, volume);
+        edge(&mut graph, &root, &volume, "owns");
+    }
+    for machine in &machines {
+        let Some(id) = machine["id"].as_str() else {
+            continue;
+        };
+        let machine_node = node(&mut graph, "machine", id, machine);
+        edge(&mut graph, &root, &machine_node, "runs");
+        for mount in machine["config"]["mounts"].as_array().into_iter().flatten() {
+            if let Some(volume) = mount["volume"].as_str() {
+                let volume = node(&mut graph, "volume", volume, mount);
+                edge(&mut graph, &machine_node, &volume, "mounts");
+            }
+        }
+        if let Some(ip) = machine["private_ip"].as_str() {
+            let ip = node(&mut graph, "ip", ip, &Value::Null);
+            edge(&mut graph, &machine_node, &ip, "private_ip");
+        }
+    }
+    Ok(graph)
+}
+
+/// Adds what only the Machines API knows: volume snapshots and the app's IP assignments.
+async fn add_fly_resources(client: &FlyClient, graph: &mut ResourceGraph) -> Result<(), String> {
+    let root = format!("app:{}", graph.app);
+    let app = graph.app.clone();
+    let volumes: Vec<String> = graph
+        .nodes
+        .iter()
+        .filter(|node| node.kind == "volume")
+        .filter_map(|node| node.id.strip_prefix("volume:").map(str::to_string))
+        .collect();
+    for volume in volumes {
+        let snapshots = client
+            .list_snapshots(&app, &volume)
+            .await
+            .map_err(|e| format!("Failed to list snapshots of {}: {}", volume, e))?;
+        for snapshot in &snapshots {
+            if let Some(id) = snapshot["id"].as_str() {
+                let snapshot = node(graph, "snapshot", id, snapshot);
+                edge(graph, &format!("volume:{}", volume), &snapshot, "snapshot");
+            }
+        }
+    }
+    let ips = client
+        .list_ip_assignments(&app)
+        .await
+        .map_err(|e| format!("Failed to list IPs: {}", e))?;
+    for ip in &ips {
+        if let Some(address) = ip["ip"].as_str() {
+            let ip = node(graph, "ip", address, ip);
+            edge(graph, &root, &ip, "ip");
+        }
+    }
+    Ok(())
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", escape(value))
+}
+
+fn to_dot(graph: &ResourceGraph) -> String {
+    let mut dot = format!("digraph {} {{\n", quoted(&graph.app));
+    for node in &graph.nodes {
+        let id = node
+            .id
+            .split_once(':')
+            .map_or(node.id.as_str(), |(_, id)| id);
+        let mut label = escape(node.name.as_deref().unwrap_or(id));
+        if let Some(state) = &node.state {
+            label.push_str(&format!(" ({})", escape(state)));
+        }
+        dot.push_str(&format!(
+            "  {} [label=\"{}\\n{}\"];\n",
+            quoted(&node.id),
+            node.kind,
+            label
+        ));
+    }
+    for edge in &graph.edges {
+        dot.push_str(&format!(
+            "  {} -> {} [label={}];\n",
+            quoted(&edge.from),
+            quoted(&edge.to),
+            quoted(&edge.relation)
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Everything flyd can see of an app and how it hangs together, as JSON or, with
+/// `format=dot`, Graphviz. Certificates aren't in the Machines API, so aren't included.
+#[get("/v0/graph")]
+async fn get_graph(
+    req: HttpRequest,
+    query: web::Query<GraphQuery>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let graph = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        build(docker.get_ref(), &query.app).await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner());
+        match build(&client, &query.app).await {
+            Ok(mut graph) => add_fly_resources(&client, &mut graph).await.map(|()| graph),
+            Err(e) => Err(e),
+        }
+    };
+    match (graph, query.format) {
+        (Ok(graph), GraphFormat::Json) => HttpResponse::Ok().json(graph),
+        (Ok(graph), GraphFormat::Dot) => HttpResponse::Ok()
+            .content_type("text/vnd.graphviz")
+            .body(to_dot(&graph)),
+        (Err(e), _) => AppError::internal(e).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_graph);
+}
@@ -0,0 +1,315 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::web;
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::HealthInsight;
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::backoff::RestartBackoff;
+use crate::config::{HealthConfig, MemoryBumpConfig};
+use crate::drain::{self, Drainer};
+use crate::events::EventLog;
+use crate::fleets;
+use crate::remediation::Remediation;
+use crate::store::{Store, StoreError};
+
+const MACHINE_OOM: &str = "machine.oom";
+const MACHINE_CRASH_LOOP: &str = "machine.crash_loop";
+const MACHINE_MEMORY_BUMPED: &str = "machine.memory_bumped";
+
+struct Crash {
+    at: DateTime<Utc>,
+    exit_code: Option<i64>,
+    oom_killed: bool,
+}
+
+/// Exits the machine didn't ask for, newest first. Fly reports them as `exit` events whose
+/// `request.exit_event` says how the process ended.
+fn crashes(machine: &Value) -> Vec<Crash> {
+    let mut crashes: Vec<Crash> = machine["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|event| event["type"] == "exit")
+        .filter_map(|event| {
+            let exit = &event["request"]["exit_event"];
+            let oom_killed = exit["oom_killed"].as_bool().unwrap_or(false);
+            let exit_code = exit["exit_code"].as_i64();
+            let failed = oom_killed || exit_code.is_some_and(|code| code != 0);
+            if exit["requested_stop"].as_bool().unwrap_or(false) || !failed {
+                return None;
+            }
+            Some(Crash {
+                at: DateTime::from_timestamp_millis(event["timestamp"].as_i64()?)?,
+                exit_code,
+                oom_killed,
+            })
+        })
+        .collect();
+    crashes.sort_by_key(|crash| Reverse(crash.at));
+    crashes
+}
+
+pub fn insight(machine: &Value, config: &HealthConfig) -> Option<HealthInsight> {
+    let crashes = crashes(machine);
+    let last = crashes.first()?;
+    let cutoff = Utc::now() - TimeDelta::minutes(config.crash_loop_window_minutes);
+    let recent: Vec<&Crash> = crashes.iter().filter(|crash| crash.at > cutoff).collect();
+    Some(HealthInsight {
+        oom_killed: last.oom_killed,
+        crash_looping: recent.len() >= config.crash_loop_restarts.max(1),
+        recent_crashes: recent.len(),
+        recent_oom_kills: recent.iter().filter(|crash| crash.oom_killed).count(),
+        last_exit_code: last.exit_code,
+        last_crash_at: last.at,
+    })
+}
+
+/// Adds `health_insight` to a machine, or to each machine in a list, that has crashed.
+pub fn annotate(machines: &mut Value, config: &HealthConfig) {
+    let annotate_one = |machine: &mut Value| {
+        if let Some(insight) = insight(machine, config) {
+            machine["health_insight"] = json!(insight);
+        }
+    };
+    match machines {
+        Value::Array(machines) => machines.iter_mut().for_each(annotate_one),
+        Value::Object(_) => annotate_one(machines),
+        _ => {}
+    }
+}
+
+/// A machine in a managed fleet keeps its bump in the fleet's spec, or the reconciler
+/// would take it back.
+async fn bump_fleet_spec(
+    store: &Store,
+    app: &str,
+    machine: &Value,
+    memory_mb: u64,
+) -> Result<(), StoreError> {
+    let Some(mut spec) = fleets::fleet_spec(store, app).await? else {
+        return Ok(());
+    };
+    let Some(machine) = spec
+        .machines
+        .iter_mut()
+        .find(|spec| machine["name"] == spec.name)
+    else {
+        return Ok(());
+    };
+    machine.config["guest"]["memory_mb"] = json!(memory_mb);
+    fleets::save_fleet_spec(store, &spec).await
+}
+
+/// Raises the machine's memory one step, within `max_memory_mb`. The event recorded for
+/// it is the audit trail, and what notifications alert on.
+async fn bump_memory<B: Backend>(
+    backend: &B,
+    store: &Store,
+    bump: &MemoryBumpConfig,
+    events: &EventLog,
+    app: &str,
+    machine: &Value,
+    oom_kills: usize,
+) -> bool {
+    let (Some(id), Some(memory_mb)) = (
+        machine["id"].as_str(),
+        machine["config"]["guest"]["memory_mb"].as_u64(),
+    ) else {
+        return false;
+    };
+    let target_mb = (memory_mb + bump.step_mb).min(bump.max_memory_mb);
+    if target_mb <= memory_mb {
+        log::warn!(
+            "Machine {} keeps being OOM killed but already has {}MB, the most it may be given",
+            id,
+            memory_mb
+        );
+        return false;
+    }
+
+    if let Err(e) = bump_fleet_spec(store, app, machine, target_mb).await {
+        log::error!(
+            "Failed to bump memory of machine {} in its fleet: {}",
+            id,
+            e
+        );
+        return false;
+    }
+    let mut config = machine["config"].clone();
+    config["guest"]["memory_mb"] = json!(target_mb);
+    match backend
+        .update_machine(app, id, &json!({ "config": config }), None)
+        .await
+    {
+        Ok(_) => {
+            events.record(
+                MACHINE_MEMORY_BUMPED,
+                Some(app),
+                Some(id),
+                json!({ "from_mb": memory_mb, "to_mb": target_mb, "oom_kills": oom_kills }),
+            );
+            true
+        }
+        Err(e) => {
+            log::error!("Failed to bump memory of machine {}: {}", id, e);
+            false
+        }
+    }
+}
+
+/// Watches `watch_apps`, recording each new OOM kill and crash loop as an event (which
+/// notifications can alert on). Machines being OOM killed get a memory bump if
+/// `memory_bump` is set; other crash loops are stopped with `stop_crash_looping`. Machines
+/// failing their checks are restarted or recreated if `remediation` is set.
+pub async fn watch<B: Backend>(
+    backend: B,
+    store: web::Data<Store>,
+    config: HealthConfig,
+    events: web::Data<EventLog>,
+    drainer: Option<web::Data<Drainer>>,
+    backoff: web::Data<RestartBackoff>,
+) {
+    // The newest crash seen per machine and whether it was looping then, so each crash
+    // and each loop is reported once.
+    let mut reported: HashMap<(String, String), (DateTime<Utc>, bool)> = HashMap::new();
+    // When each machine was last bumped; OOM kills before that were on less memory.
+    let mut bumped: HashMap<(String, String), DateTime<Utc>> = HashMap::new();
+    let mut remediation = config.remediation.clone().map(Remediation::new);
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.watch_interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+
+        for app in &config.watch_apps {
+            let machines = match backend.list_machines(app).await {
+                Ok(machines) => machines,
+                Err(e) => {
+                    log::error!(
+                        "Failed to list machines of {} for health checks: {}",
+                        app,
+                        e
+                    );
+                    continue;
+                }
+            };
+            if let Some(remediation) = &mut remediation {
+                remediation
+                    .check(
+                        &backend,
+                        &events,
+                        &backoff,
+                        drainer.as_ref(),
+                        app,
+                        &machines,
+                    )
+                    .await;
+            }
+
+            for machine in machines {
+                let (Some(id), Some(insight)) =
+                    (machine["id"].as_str(), insight(&machine, &config))
+                else {
+                    continue;
+                };
+                let key = (app.clone(), id.to_string());
+                let previous =
+                    reported.insert(key.clone(), (insight.last_crash_at, insight.crash_looping));
+                if previous.is_some_and(|(last_crash_at, _)| last_crash_at == insight.last_crash_at)
+                {
+                    continue;
+                }
+
+                if insight.oom_killed {
+                    events.record(MACHINE_OOM, Some(app), Some(id), json!(insight));
+
+                    if let Some(bump) = &config.memory_bump {
+                        let cutoff =
+                            Utc::now() - TimeDelta::minutes(config.crash_loop_window_minutes);
+                        let since = bumped.get(&key).map_or(cutoff, |at| (*at).max(cutoff));
+                        let oom_kills = crashes(&machine)
+                            .iter()
+                            .filter(|crash| crash.oom_killed && crash.at > since)
+                            .count();
+                        if oom_kills >= bump.min_oom_kills.max(1)
+                            && bump_memory(
+                                &backend, &store, bump, &events, app, &machine, oom_kills,
+                            )
+                            .await
+                        {
+                            // The bump restarts the machine, so it isn't stopped for looping.
+                            bumped.insert(key, Utc::now());
+                            continue;
+                        }
+                    }
+                }
+                let was_looping = previous.is_some_and(|(_, looping)| looping);
+                if !insight.crash_looping || was_looping {
+                    continue;
+                }
+                if config.stop_crash_looping {
+                    drain::drain(drainer.as_ref(), &backend, app, id).await;
+                }
+                let stopped = config.stop_crash_looping
+                    && match backend.stop_machine(app, id).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            log::error!("Failed to stop crash looping machine {}: {}", id, e);
+                            false
+                        }
+                    };
+                events.record(
+                    MACHINE_CRASH_LOOP,
+                    Some(app),
+                    Some(id),
+                    json!({ "insight": insight, "stopped": stopped }),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flyd::models::{FleetSpec, MachineSpec};
+
+    use super::*;
+
+    fn spec(name: &str, memory_mb: u64) -> MachineSpec {
+        MachineSpec {
+            name: name.to_string(),
+            region: "ord".to_string(),
+            config: json!({ "guest": { "memory_mb": memory_mb } }),
+            last_applied: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bumps_only_the_machine_in_its_fleet_spec() {
+        let store = Store::default();
+        let fleet = FleetSpec {
+            app: "web".to_string(),
+            mode: Default::default(),
+            conflict_policy: Default::default(),
+            machines: vec![spec("web-1", 256), spec("web-2", 256)],
+            adopted_at: Utc::now(),
+        };
+        fleets::save_fleet_spec(&store, &fleet).await.unwrap();
+
+        let machine = json!({ "id": "m1", "name": "web-1" });
+        bump_fleet_spec(&store, "web", &machine, 512).await.unwrap();
+        let fleet = fleets::fleet_spec(&store, "web").await.unwrap().unwrap();
+        let memory: Vec<&Value> = fleet
+            .machines
+            .iter()
+            .map(|spec| &spec.config["guest"]["memory_mb"])
+            .collect();
+        assert_eq!(memory, [&json!(512), &json!(256)]);
+
+        // Apps outside any fleet have no spec to keep the bump in.
+        bump_fleet_spec(&store, "api", &machine, 512).await.unwrap();
+        assert!(fleets::fleet_spec(&store, "api").await.unwrap().is_none());
+    }
+}
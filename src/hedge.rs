@@ -0,0 +1,106 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::config::HedgingConfig;
+use crate::metrics;
+
+/// Sends a second copy of a slow GET after `delay_ms` and takes whichever answers first.
+/// Hedges spend from a budget that each request refills by `budget_percent`, so a
+/// degraded upstream sees at most that much extra load.
+pub struct Hedger {
+    delay: Duration,
+    budget_ratio: f64,
+    tokens: Mutex<f64>,
+    hedged: AtomicU64,
+    won: AtomicU64,
+}
+
+impl Hedger {
+    /// Returns `None` when hedging is disabled.
+    pub fn from_config(config: &HedgingConfig) -> Option<Self> {
+        if config.delay_ms == 0 {
+            return None;
+        }
+        Some(Hedger {
+            delay: Duration::from_millis(config.delay_ms),
+            budget_ratio: config.budget_percent / 100.0,
+            tokens: Mutex::new(1.0),
+            hedged: AtomicU64::new(0),
+            won: AtomicU64::new(0),
+        })
+    }
+
+    fn earn(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        // Cap the bank so a long quiet spell can't fund a burst of hedges.
+        *tokens = (*tokens + self.budget_ratio).min(10.0);
+    }
+
+    fn spend(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    pub async fn execute(
+        &self,
+        http: &reqwest::Client,
+        request: reqwest::Request,
+    ) -> reqwest::Result<reqwest::Response> {
+        let hedge = match request.method() {
+            &reqwest::Method::GET => request.try_clone(),
+            _ => None,
+        };
+        let Some(hedge) = hedge else {
+            return http.execute(request).await;
+        };
+        self.earn();
+
+        let mut primary = std::pin::pin!(http.execute(request));
+        tokio::select! {
+            result = &mut primary => return result,
+            _ = tokio::time::sleep(self.delay) => {}
+        }
+        if !self.spend() {
+            return primary.await;
+        }
+        self.hedged.fetch_add(1, Ordering::Relaxed);
+
+        let mut secondary = std::pin::pin!(http.execute(hedge));
+        tokio::select! {
+            result = &mut primary => match result {
+                Ok(response) => Ok(response),
+                Err(_) => secondary.await,
+            },
+            result = &mut secondary => match result {
+                Ok(response) => {
+                    self.won.fetch_add(1, Ordering::Relaxed);
+                    Ok(response)
+                }
+                Err(_) => primary.await,
+            },
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        for (name, help, value) in [
+            (
+                "flyd_hedged_requests_total",
+                "Hedged second requests sent upstream.",
+                &self.hedged,
+            ),
+            (
+                "flyd_hedge_wins_total",
+                "Hedged requests that answered before the original.",
+                &self.won,
+            ),
+        ] {
+            metrics::header(out, name, "counter", help);
+            metrics::sample(out, name, &[], value.load(Ordering::Relaxed) as f64);
+        }
+    }
+}
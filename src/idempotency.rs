@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpResponse, web};
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::token_hash;
+use crate::config::{IdempotencyConfig, SharedBackend};
+use crate::errors::AppError;
+
+pub use flyd::fly::IDEMPOTENCY_KEY_HEADER;
+
+const REPLAYED_HEADER: &str = "idempotency-replayed";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum Entry {
+    Pending,
+    Done {
+        status: u16,
+        content_type: Option<String>,
+        body: String,
+    },
+}
+
+enum Backend {
+    Memory(Mutex<HashMap<String, (Instant, Entry)>>),
+    Redis(ConnectionManager),
+}
+
+/// Remembers responses to mutating requests that carry an `Idempotency-Key`, so a client
+/// retrying after a timeout gets the original result instead of creating a duplicate.
+pub struct IdempotencyCache {
+    ttl: Duration,
+    backend: Backend,
+}
+
+impl IdempotencyCache {
+    pub fn from_config(
+        config: &IdempotencyConfig,
+        redis: Option<&ConnectionManager>,
+    ) -> Result<Self, String> {
+        let backend = match (config.backend, redis) {
+            (SharedBackend::Memory, _) => Backend::Memory(Mutex::new(HashMap::new())),
+            (SharedBackend::Redis, Some(redis)) => Backend::Redis(redis.clone()),
+            (SharedBackend::Redis, None) => {
+                return Err("idempotency.backend = \"redis\" requires [redis]".to_string());
+            }
+        };
+        Ok(IdempotencyCache {
+            ttl: Duration::from_secs(config.ttl_secs),
+            backend,
+        })
+    }
+
+    /// Claims `key` for a new request, or returns the entry already stored under it.
+    async fn reserve(&self, key: &str) -> Result<Option<Entry>, redis::RedisError> {
+        match &self.backend {
+            Backend::Memory(entries) => {
+                let now = Instant::now();
+                let mut entries = entries.lock().unwrap();
+                entries.retain(|_, (expires, _)| *expires > now);
+                if let Some((_, entry)) = entries.get(key) {
+                    return Ok(Some(entry.clone()));
+                }
+                entries.insert(key.to_string(), (now + self.ttl, Entry::Pending));
+                Ok(None)
+            }
+            Backend::Redis(connection) => {
+                let mut connection = connection.clone();
+                let pending = serde_json::to_string(&Entry::Pending).unwrap_or_default();
+                let claimed: Option<String> = redis::cmd("SET")
+                    .arg(key)
+                    .arg(pending)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(self.ttl.as_millis() as u64)
+                    .query_async(&mut connection)
+                    .await?;
+                if claimed.is_some() {
+                    return Ok(None);
+                }
+                let existing: Option<String> = redis::cmd("GET")
+                    .arg(key)
+                    .query_async(&mut connection)
+                    .await?;
+                Ok(existing.and_then(|entry| serde_json::from_str(&entry).ok()))
+            }
+        }
+    }
+
+    async fn complete(&self, key: &str, entry: Entry) -> Result<(), redis::RedisError> {
+        match &self.backend {
+            Backend::Memory(entries) => {
+                entries
+                    .lock()
+                    .unwrap()
+                    .insert(key.to_string(), (Instant::now() + self.ttl, entry));
+                Ok(())
+            }
+            Backend::Redis(connection) => {
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(serde_json::to_string(&entry).unwrap_or_default())
+                    .arg("PX")
+                    .arg(self.ttl.as_millis() as u64)
+                    .query_async(&mut connection.clone())
+                    .await
+            }
+        }
+    }
+
+    /// Forgets a key whose request failed, so the client can retry it.
+    async fn release(&self, key: &str) -> Result<(), redis::RedisError> {
+        match &self.backend {
+            Backend::Memory(entries) => {
+                entries.lock().unwrap().remove(key);
+                Ok(())
+            }
+            Backend::Redis(connection) => {
+                redis::cmd("DEL")
+                    .arg(key)
+                    .query_async(&mut connection.clone())
+                    .await
+            }
+        }
+    }
+}
+
+fn replay(status: u16, content_type: Option<String>, body: String) -> HttpResponse {
+    let mut response = HttpResponse::build(StatusCode::from_u16(status).unwrap_or(StatusCode::OK));
+    response.insert_header((REPLAYED_HEADER, "true"));
+    if let Some(content_type) = content_type {
+        response.insert_header((CONTENT_TYPE, content_type));
+    }
+    response.body(body)
+}
+
+pub async fn enforce<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let cache = req.app_data::<web::Data<IdempotencyCache>>().cloned();
+    let idempotency_key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .filter(|_| !req.method().is_safe())
+        .map(str::to_string);
+    let (Some(cache), Some(idempotency_key)) = (cache, idempotency_key) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+
+    // Scoped to the caller and route so keys can't collide across tokens or endpoints.
+    let caller = req
+        .headers()
+        .get(AUTHORIZATION)
+        .map(|authorization| token_hash(authorization.as_bytes()))
+        .unwrap_or_default();
+    let key = format!(
+        "flyd:idempotency:{}:{} {}:{}",
+        caller,
+        req.method(),
+        req.path(),
+        idempotency_key
+    );
+
+    match cache.reserve(&key).await {
+        Ok(None) => {}
+        Ok(Some(Entry::Pending)) => {
+            let response =
+                AppError::conflict("A request with this Idempotency-Key is still in progress")
+                    .into_response();
+            return Ok(req.into_response(response));
+        }
+        Ok(Some(Entry::Done {
+            status,
+            content_type,
+            body,
+        })) => return Ok(req.into_response(replay(status, content_type, body))),
+        Err(e) => {
+            log::error!(
+                "Idempotency cache unavailable, passing request through: {}",
+                e
+            );
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_boxed_body);
+        }
+    }
+
+    let response = match next.call(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            let _ = cache.release(&key).await;
+            return Err(e);
+        }
+    };
+
+    let status = response.status();
+    if !status.is_success() {
+        if let Err(e) = cache.release(&key).await {
+            log::warn!("Failed to release idempotency key: {}", e);
+        }
+        return Ok(response.map_into_boxed_body());
+    }
+
+    let (request, response) = response.into_parts();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let (response, body) = response.into_parts();
+    let body = actix_web::body::to_bytes(body).await.map_err(|e| {
+        let e: Box<dyn std::error::Error> = e.into();
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    })?;
+
+    let entry = Entry::Done {
+        status: status.as_u16(),
+        content_type,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+    if let Err(e) = cache.complete(&key, entry).await {
+        log::warn!("Failed to store idempotent response: {}", e);
+    }
+
+    Ok(ServiceResponse::new(
+        request,
+        response.set_body(BoxBody::new(body)),
+    ))
+}
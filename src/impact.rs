@@ -0,0 +1,309 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, post, web};
+use flyd::models::{Consequence, DestroyMachineRequest, Impact, MachineRequest};
+use serde_json::{Value, json};
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::consistency;
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets::{self, MANAGED_METADATA_KEY};
+use crate::fly_client::{self, FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::Store;
+use crate::trash;
+
+fn consequence(
+    code: &str,
+    high_impact: bool,
+    message: String,
+    resource: Option<&str>,
+) -> Consequence {
+    Consequence {
+        code: code.to_string(),
+        high_impact,
+        message,
+        resource: resource.map(str::to_string),
+    }
+}
+
+fn standbys(machine: &Value) -> Vec<&str> {
+    machine["config"]["standbys"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect()
+}
+
+/// What destroying the machine would do, from the app's other machines. `None` if the
+/// app has no such machine.
+pub async fn analyze<B: Backend>(
+    backend: &B,
+    app: &str,
+    machine_id: &str,
+) -> Result<Option<Impact>, String> {
+    let machines: Vec<Value> = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?
+        .into_iter()
+        .filter(fleets::is_live)
+        .collect();
+    let Some(machine) = machines.iter().find(|machine| machine["id"] == machine_id) else {
+        return Ok(None);
+    };
+    let others: Vec<&Value> = machines
+        .iter()
+        .filter(|other| other["id"] != machine_id)
+        .collect();
+
+    let mut consequences = Vec::new();
+    if others.is_empty() {
+        consequences.push(consequence(
+            "last_machine",
+            true,
+            format!("{} will have no machines left", app),
+            None,
+        ));
+    } else if let Some(region) = machine["region"].as_str()
+        && !others.iter().any(|other| other["region"] == region)
+    {
+        consequences.push(consequence(
+            "last_in_region",
+            true,
+            format!("{} will have no machines left in {}", app, region),
+            Some(region),
+        ));
+    }
+    if machine["state"] == "started" && !others.iter().any(|other| other["state"] == "started") {
+        consequences.push(consequence(
+            "last_started",
+            true,
+            format!("{} will have no started machines", app),
+            None,
+        ));
+    }
+    for mount in machine["config"]["mounts"].as_array().into_iter().flatten() {
+        if let Some(volume) = mount["volume"].as_str() {
+            consequences.push(consequence(
+                "orphans_volume",
+                false,
+                format!(
+                    "Volume {} will be left unattached, keeping its data (and its cost)",
+                    volume
+                ),
+                Some(volume),
+            ));
+        }
+    }
+    // A machine's `standbys` are the machines it stands by for.
+    for other in &others {
+        let other_id = other["id"].as_str().unwrap_or_default();
+        if standbys(other).contains(&machine_id) {
+            consequences.push(consequence(
+                "loses_standby",
+                false,
+                format!("Standby {} will have no machine to stand by for", other_id),
+                Some(other_id),
+            ));
+        }
+    }
+    for primary in standbys(machine) {
+        if others.iter().any(|other| other["id"] == primary) {
+            consequences.push(consequence(
+                "breaks_standby",
+                true,
+                format!("{} will have no standby", primary),
+                Some(primary),
+            ));
+        }
+    }
+    if machine["config"]["metadata"][MANAGED_METADATA_KEY] == app {
+        consequences.push(consequence(
+            "recreated_by_fleet",
+            false,
+            format!(
+                "{} is in {}'s fleet spec; an enforced fleet recreates it",
+                machine_id, app
+            ),
+            None,
+        ));
+    }
+
+    Ok(Some(Impact {
+        app: app.to_string(),
+        machine_id: machine_id.to_string(),
+        consequences,
+    }))
+}
+
+/// Codes of the impact's high-impact consequences missing from `acknowledge`.
+pub fn unacknowledged<'a>(impact: &'a Impact, acknowledge: &[String]) -> Vec<&'a str> {
+    impact
+        .consequences
+        .iter()
+        .filter(|consequence| consequence.high_impact)
+        .map(|consequence| consequence.code.as_str())
+        .filter(|code| acknowledge.iter().all(|acknowledged| acknowledged != code))
+        .collect()
+}
+
+fn not_found(app: &str, machine_id: &str) -> HttpResponse {
+    AppError::not_found(format!("No machine {} in {}", machine_id, app)).into_response()
+}
+
+/// What destroying a machine would do: orphaned volumes, regions or apps left without
+/// machines, broken standby pairs.
+#[get("/v0/impact")]
+async fn get_impact(
+    req: HttpRequest,
+    query: web::Query<MachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let impact = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        analyze(docker.get_ref(), &query.app_name, &query.machine_id).await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner());
+        analyze(&client, &query.app_name, &query.machine_id).await
+    };
+    match impact {
+        Ok(Some(impact)) => HttpResponse::Ok().json(impact),
+        Ok(None) => not_found(&query.app_name, &query.machine_id),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+async fn destroy<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    body: &DestroyMachineRequest,
+    lease_nonce: Option<&str>,
+    config: &Config,
+    events: &EventLog,
+) -> HttpResponse {
+    let (app, machine_id) = (&body.app_name, &body.machine_id);
+    let impact = match analyze(backend, app, machine_id).await {
+        Ok(Some(impact)) => impact,
+        Ok(None) => return not_found(app, machine_id),
+        Err(e) => return AppError::internal(e).into_response(),
+    };
+    let unacknowledged = unacknowledged(&impact, &body.acknowledge);
+    if config.machines.require_impact_ack && !unacknowledged.is_empty() {
+        return HttpResponse::Conflict().json(json!({
+            "error": format!("Acknowledge {} to destroy {}", unacknowledged.join(", "), machine_id),
+            "impact": impact,
+        }));
+    }
+
+    if let Some(trash) = &config.trash
+        && !body.permanent
+    {
+        return match trash::trash(req, backend, trash, app, machine_id, impact.consequences).await {
+            Ok(trashed) => HttpResponse::Accepted().json(trashed),
+            Err(response) => response,
+        };
+    }
+    let drainer = req.app_data::<web::Data<Drainer>>();
+    drain::drain(drainer, backend, app, machine_id).await;
+    if let Err(e) = backend.destroy_machine(app, machine_id, lease_nonce).await {
+        return AppError::internal(format!("Failed to destroy: {}", e)).into_response();
+    }
+    events.record(
+        "machine.destroyed",
+        Some(app),
+        Some(machine_id),
+        json!({
+            "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+            "acknowledged": body.acknowledge,
+            "consequences": impact.consequences,
+        }),
+    );
+    HttpResponse::Ok().json(impact)
+}
+
+/// Destroys a machine, returning what that did. With `machines.require_impact_ack`,
+/// high-impact consequences must be listed in `acknowledge` first. With `lease`, the
+/// destroy holds the machine's lease, unless the caller sent one it already holds. With
+/// `[trash]`, the machine is only stopped until its retention passes, unless `permanent`.
+#[post("/v0/machines/destroy")]
+async fn destroy_machine(
+    req: HttpRequest,
+    body: web::Json<DestroyMachineRequest>,
+    config: web::Data<Config>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return destroy(&req, docker.get_ref(), &body, None, &config, &events).await;
+    }
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    if let Some(nonce) = fly_client::held_lease(&req) {
+        return destroy(&req, &client, &body, Some(&nonce), &config, &events).await;
+    }
+    if !body.lease {
+        return destroy(&req, &client, &body, None, &config, &events).await;
+    }
+
+    let nonce = match client
+        .acquire_lease(
+            &body.app_name,
+            &body.machine_id,
+            config.machines.update_lease_ttl_secs,
+        )
+        .await
+    {
+        Ok(nonce) => nonce,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+    let store = req.app_data::<web::Data<Store>>();
+    consistency::leased(
+        store,
+        &body.app_name,
+        &body.machine_id,
+        &nonce,
+        config.machines.update_lease_ttl_secs,
+    )
+    .await;
+    let response = destroy(&req, &client, &body, Some(&nonce), &config, &events).await;
+    // A destroyed machine's lease goes with it, so only a failed destroy leaves one.
+    let released = response.status().is_success()
+        || match client
+            .release_lease(&body.app_name, &body.machine_id, &nonce)
+            .await
+        {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!(
+                    "Failed to release lease on machine {}: {}",
+                    body.machine_id,
+                    e
+                );
+                false
+            }
+        };
+    if released {
+        consistency::released(store, &body.app_name, &body.machine_id).await;
+    }
+    response
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_impact).service(destroy_machine);
+}
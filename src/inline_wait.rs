@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, web};
+use flyd::models::JobState;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::WAIT_STATES;
+use crate::errors::AppError;
+use crate::fly_client::FlyClient;
+use crate::jobs::{self, Jobs, Work};
+
+/// Names the job that waited, so a caller that got 202 can look up why.
+pub const WAIT_JOB_HEADER: &str = "x-flyd-wait-job";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Deserialize)]
+struct WaitForQuery {
+    wait_for: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Condition {
+    state: Option<String>,
+    timeout: Option<String>,
+}
+
+/// `60`, `60s` or `2m`.
+fn parse_timeout(timeout: &str) -> Option<Duration> {
+    let (number, unit) = match timeout.strip_suffix('m') {
+        Some(minutes) => (minutes, 60),
+        None => (timeout.strip_suffix('s').unwrap_or(timeout), 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|n| Duration::from_secs(n.saturating_mul(unit)))
+}
+
+/// What a mutating call should wait for before answering: its `wait_for` query parameter,
+/// e.g. `?wait_for=state%3Dstarted%26timeout%3D60s`, or just `?wait_for=started`.
+pub struct WaitFor {
+    state: String,
+    timeout: Duration,
+}
+
+impl WaitFor {
+    /// Checked before the call is made, so a bad condition changes nothing.
+    pub fn from_request(req: &HttpRequest) -> Result<Option<Self>, HttpResponse> {
+        let Some(wait_for) = web::Query::<WaitForQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().wait_for)
+        else {
+            return Ok(None);
+        };
+        let condition = if wait_for.contains('=') {
+            web::Query::<Condition>::from_query(&wait_for)
+                .map_err(|e| {
+                    AppError::bad_request(format!("Invalid wait_for {}: {}", wait_for, e))
+                        .into_response()
+                })?
+                .into_inner()
+        } else {
+            Condition {
+                state: Some(wait_for.clone()),
+                timeout: None,
+            }
+        };
+
+        let state = condition.state.unwrap_or_else(|| "started".to_string());
+        if !WAIT_STATES.contains(&state.as_str()) {
+            return Err(AppError::bad_request(format!(
+                "Can't wait for {}, only for {}",
+                state,
+                WAIT_STATES.join(", ")
+            ))
+            .into_response());
+        }
+        let timeout = match &condition.timeout {
+            Some(timeout) => parse_timeout(timeout).ok_or_else(|| {
+                AppError::bad_request(format!("Invalid wait_for timeout {}", timeout))
+                    .into_response()
+            })?,
+            None => DEFAULT_TIMEOUT,
+        };
+        if timeout.is_zero() || timeout > MAX_TIMEOUT {
+            return Err(AppError::bad_request(format!(
+                "wait_for timeout must be between 1s and {}s",
+                MAX_TIMEOUT.as_secs()
+            ))
+            .into_response());
+        }
+        Ok(Some(WaitFor { state, timeout }))
+    }
+
+    /// Waits, as a job, for the machine a call just changed to reach the state, returning
+    /// how to answer the call: 200 once the machine is there, or 202 if it didn't get
+    /// there in time. The job names the machine's version when the call returned one, so
+    /// an update waits for the new version rather than the old one.
+    pub async fn wait(
+        &self,
+        req: &HttpRequest,
+        client: &FlyClient,
+        app: &str,
+        machine_id: &str,
+        instance_id: Option<&str>,
+    ) -> HttpResponseBuilder {
+        let Some(jobs) = req.app_data::<web::Data<Jobs>>() else {
+            return HttpResponse::Ok();
+        };
+        let (client, app_name, id, state, instance_id, timeout) = (
+            client.clone(),
+            app.to_string(),
+            machine_id.to_string(),
+            self.state.clone(),
+            instance_id.map(str::to_string),
+            self.timeout,
+        );
+        let work: Work = Box::new(move || {
+            let (client, app_name, id, state, instance_id) = (
+                client.clone(),
+                app_name.clone(),
+                id.clone(),
+                state.clone(),
+                instance_id.clone(),
+            );
+            Box::pin(async move {
+                client
+                    .wait_for_state(&app_name, &id, &state, instance_id.as_deref(), timeout)
+                    .await
+                    .map(|_| json!({ "machine_id": id, "state": state }))
+                    .map_err(|e| e.to_string())
+            })
+        });
+        // One group per machine, so waits queue behind nothing but each other and the
+        // overall job limit.
+        let job = Jobs::submit_once(
+            jobs,
+            "wait",
+            Some(app),
+            format!("wait:{}", machine_id),
+            jobs::submitter(req),
+            work,
+        );
+
+        // Queued behind other jobs for longer than the timeout counts as not getting there.
+        let finished = tokio::time::timeout(
+            self.timeout + Duration::from_secs(10),
+            jobs.finished(job.id),
+        )
+        .await;
+        let reached = matches!(finished, Ok(Some(ref job)) if job.state == JobState::Succeeded);
+        let mut response = HttpResponse::build(if reached {
+            StatusCode::OK
+        } else {
+            StatusCode::ACCEPTED
+        });
+        response.insert_header((WAIT_JOB_HEADER, job.id.to_string()));
+        response
+    }
+}
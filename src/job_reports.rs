@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use actix_web::{HttpResponse, Responder, get, web};
+use chrono::{DateTime, Utc};
+use flyd::models::{
+    AttemptReport, Job, JobReport, JobReportQuery, JobState, ReportFormat, ResourceOutcome,
+};
+use serde_json::Value;
+
+use crate::errors::AppError;
+use crate::jobs::Jobs;
+
+/// Outcomes that undid the job's changes.
+const ROLLBACK_OUTCOMES: &[&str] = &["rolled_back", "restored"];
+
+fn secs(from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> Option<f64> {
+    to.map(|to| (to - from).num_milliseconds() as f64 / 1000.0)
+}
+
+/// Collects the machines in a job's result: the ids listed under `<outcome>_machines`, and
+/// objects with a `machine_id` under `<outcome>`. Other lists of objects, e.g. an
+/// environment's `stages`, are looked through, each object's `app` applying to what's
+/// inside it.
+fn collect(value: &Value, app: Option<&str>, resources: &mut Vec<ResourceOutcome>) {
+    let Value::Object(object) = value else {
+        return;
+    };
+    let app = object.get("app").and_then(Value::as_str).or(app);
+    for (key, value) in object {
+        let Value::Array(items) = value else {
+            if value.is_object() {
+                collect(value, app, resources);
+            }
+            continue;
+        };
+        let listed = key.strip_suffix("_machines");
+        for item in items {
+            let id = match (item, listed) {
+                (Value::String(id), Some(_)) => Some(id.as_str()),
+                (Value::Object(_), _) => item["machine_id"].as_str(),
+                _ => None,
+            };
+            match id {
+                Some(id) => resources.push(ResourceOutcome {
+                    kind: "machine".to_string(),
+                    id: id.to_string(),
+                    app: item["app"].as_str().or(app).map(str::to_string),
+                    outcome: listed.unwrap_or(key).to_string(),
+                }),
+                None => collect(item, app, resources),
+            }
+        }
+    }
+}
+
+fn report(job: &Job) -> JobReport {
+    let mut resources = Vec::new();
+    if let Some(result) = &job.result {
+        collect(result, None, &mut resources);
+    }
+    // The job's own app goes without saying.
+    for resource in &mut resources {
+        if resource.app == job.app {
+            resource.app = None;
+        }
+    }
+    let mut summary = BTreeMap::new();
+    for resource in &resources {
+        *summary.entry(resource.outcome.clone()).or_insert(0) += 1;
+    }
+    let rolled_back = ROLLBACK_OUTCOMES
+        .iter()
+        .any(|outcome| summary.contains_key(*outcome))
+        || job
+            .result
+            .as_ref()
+            .is_some_and(|result| !result["rolled_back"].is_null());
+    JobReport {
+        job_id: job.id,
+        kind: job.kind.clone(),
+        app: job.app.clone(),
+        state: job.state,
+        succeeded: job.state == JobState::Succeeded,
+        created_at: job.created_at,
+        started_at: job.started_at,
+        finished_at: job.finished_at,
+        queued_secs: job
+            .started_at
+            .and_then(|started_at| secs(job.created_at, Some(started_at))),
+        duration_secs: job
+            .started_at
+            .and_then(|started_at| secs(started_at, job.finished_at)),
+        attempts: job
+            .attempts
+            .iter()
+            .map(|attempt| AttemptReport {
+                started_at: attempt.started_at,
+                finished_at: attempt.finished_at,
+                duration_secs: secs(attempt.started_at, attempt.finished_at),
+                error: attempt.error.clone(),
+            })
+            .collect(),
+        summary,
+        resources,
+        rolled_back,
+        error: job.error.clone(),
+    }
+}
+
+fn optional_secs(secs: Option<f64>) -> String {
+    secs.map_or("-".to_string(), |secs| format!("{:.1}s", secs))
+}
+
+fn render_text(report: &JobReport) -> String {
+    let state = serde_json::to_value(report.state)
+        .ok()
+        .and_then(|state| state.as_str().map(str::to_string))
+        .unwrap_or_default();
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "job {} {} of {}: {}",
+        report.job_id,
+        report.kind,
+        report.app.as_deref().unwrap_or("-"),
+        state
+    );
+    let _ = writeln!(
+        text,
+        "queued {}, ran {}, {} attempt(s)",
+        optional_secs(report.queued_secs),
+        optional_secs(report.duration_secs),
+        report.attempts.len()
+    );
+    for (i, attempt) in report.attempts.iter().enumerate() {
+        let _ = writeln!(
+            text,
+            "  attempt {}: {}{}",
+            i + 1,
+            optional_secs(attempt.duration_secs),
+            attempt
+                .error
+                .as_ref()
+                .map_or(String::new(), |error| format!(", {}", error))
+        );
+    }
+    if !report.summary.is_empty() {
+        let counts: Vec<String> = report
+            .summary
+            .iter()
+            .map(|(outcome, count)| format!("{} {}", count, outcome))
+            .collect();
+        let _ = writeln!(text, "summary: {}", counts.join(", "));
+    }
+    for resource in &report.resources {
+        let _ = writeln!(
+            text,
+            "  {} {}{}: {}",
+            resource.kind,
+            resource.id,
+            resource
+                .app
+                .as_ref()
+                .map_or(String::new(), |app| format!(" ({})", app)),
+            resource.outcome
+        );
+    }
+    let _ = writeln!(
+        text,
+        "rolled back: {}",
+        if report.rolled_back { "yes" } else { "no" }
+    );
+    if let Some(error) = &report.error {
+        let _ = writeln!(text, "error: {}", error);
+    }
+    text
+}
+
+/// The final report of a finished job, as JSON or, with `format=text`, plain lines. It's
+/// served as a download, for attaching to CI runs.
+#[get("/v0/jobs/{id}/report")]
+async fn get_job_report(
+    path: web::Path<u64>,
+    query: web::Query<JobReportQuery>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(job) = jobs.get(id).await else {
+        return AppError::not_found(format!("No job {}", id)).into_response();
+    };
+    if job.finished_at.is_none() {
+        return AppError::conflict(format!("Job {} hasn't finished", id)).into_response();
+    }
+    let report = report(&job);
+    let (extension, content_type, body) = match query.format {
+        ReportFormat::Json => match serde_json::to_string_pretty(&report) {
+            Ok(body) => ("json", "application/json", body),
+            Err(e) => return AppError::internal(e.to_string()).into_response(),
+        },
+        ReportFormat::Text => ("txt", "text/plain; charset=utf-8", render_text(&report)),
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"job-{}-report.{}\"", id, extension),
+        ))
+        .body(body)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_job_report);
+}
@@ -0,0 +1,1190 @@
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::LOCATION;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::{TimeDelta, Utc};
+use flyd::models::{
+    Approval, ApprovalQuery, Job, JobAttempt, JobProgress, JobState, JobStep, JobsQuery, Schedule,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::sync::Notify;
+
+use crate::auth::{self, Identity};
+use crate::config::JobsConfig;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::namespaces;
+use crate::notify::{Notifier, escape_html};
+use crate::store::{Store, StoreError};
+
+/// Finished jobs, keyed by zero-padded id so they list oldest first.
+const JOBS: &str = "jobs";
+
+/// One attempt at a job. It isn't polled, and so doesn't start, until the job's turn comes.
+pub type Task = Pin<Box<dyn Future<Output = Result<Value, String>> + Send>>;
+/// A job's work, making a fresh task for each attempt.
+pub type Work = Box<dyn FnMut() -> Task + Send>;
+/// Undoes what an attempt leaves behind if it's stopped part way, e.g. releases a lease.
+pub type Cleanup = Box<dyn FnOnce() -> Task + Send>;
+
+/// What one attempt leaves behind, which outlives the attempt's task when it's stopped.
+/// Everything the attempt does runs within its task, so dropping the task at a
+/// cancellation or timeout drops every upstream call still in flight with it.
+struct Attempt {
+    jobs: web::Data<Jobs>,
+    id: u64,
+    deadline: Option<Instant>,
+    /// What the attempt has done so far, kept as the result if it fails.
+    partial: Mutex<Option<Value>>,
+    cleanups: Mutex<Vec<(String, Cleanup)>>,
+}
+
+impl Attempt {
+    /// Runs the cleanups the attempt didn't settle itself, whether it finished or not.
+    async fn clean_up(&self) {
+        let cleanups = std::mem::take(&mut *self.cleanups.lock().unwrap());
+        for (key, cleanup) in cleanups {
+            if let Err(e) = cleanup().await {
+                log::warn!("Job {}: failed to clean up {}: {}", self.id, key, e);
+            }
+        }
+    }
+}
+
+tokio::task_local! {
+    /// The attempt running on this task.
+    static RUNNING: Arc<Attempt>;
+}
+
+/// Records what the running attempt has done so far, in the shape of its result, so that a
+/// failed job's report still lists what it changed and rolled back. Outside a job it does
+/// nothing.
+pub fn record_partial(result: Value) {
+    let _ = RUNNING.try_with(|attempt| *attempt.partial.lock().unwrap() = Some(result));
+}
+
+/// Records that `done` of the running attempt's `total` steps are done, for whoever polls
+/// the job. Outside a job it does nothing.
+pub fn record_progress(done: usize, total: usize) {
+    let _ = RUNNING.try_with(|attempt| {
+        if let Some(job) = attempt.jobs.queue.lock().unwrap().jobs.get_mut(&attempt.id) {
+            job.progress = Some(JobProgress { done, total });
+        }
+    });
+}
+
+/// Has `cleanup` run if the running attempt is canceled or times out before [`settle`] is
+/// called with the same `key`. Outside a job it does nothing.
+pub fn defer(key: String, cleanup: Cleanup) {
+    let _ = RUNNING.try_with(|attempt| attempt.cleanups.lock().unwrap().push((key, cleanup)));
+}
+
+/// Drops the cleanup deferred under `key`, once the attempt has done it itself.
+pub fn settle(key: &str) {
+    let _ = RUNNING.try_with(|attempt| {
+        attempt
+            .cleanups
+            .lock()
+            .unwrap()
+            .retain(|(deferred, _)| deferred != key)
+    });
+}
+
+/// Runs `step` for at most `budget`, and no longer than the running attempt has left,
+/// recording on the attempt how long it took. Fails if it runs out of time.
+pub async fn step<F: Future>(
+    name: &str,
+    budget: Option<Duration>,
+    step: F,
+) -> Result<F::Output, String> {
+    let left = RUNNING
+        .try_with(|attempt| attempt.deadline)
+        .ok()
+        .flatten()
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    let budget = match (budget, left) {
+        (Some(budget), Some(left)) => Some(budget.min(left)),
+        (budget, left) => budget.or(left),
+    };
+    let started = Instant::now();
+    let output = match budget {
+        Some(budget) => tokio::time::timeout(budget, step).await.ok(),
+        None => Some(step.await),
+    };
+    let _ = RUNNING.try_with(|attempt| {
+        let mut queue = attempt.jobs.queue.lock().unwrap();
+        if let Some(last) = queue
+            .jobs
+            .get_mut(&attempt.id)
+            .and_then(|job| job.attempts.last_mut())
+        {
+            last.steps.push(JobStep {
+                name: name.to_string(),
+                budget_ms: budget.map(|budget| budget.as_millis() as u64),
+                spent_ms: started.elapsed().as_millis() as u64,
+                timed_out: output.is_none(),
+            });
+        }
+    });
+    output.ok_or_else(|| {
+        format!(
+            "{} ran out of time after {}s",
+            name,
+            started.elapsed().as_secs()
+        )
+    })
+}
+
+#[derive(Deserialize)]
+struct AsyncQuery {
+    #[serde(default, rename = "async")]
+    run_async: bool,
+}
+
+/// Whether the caller would rather have a slow call run as a job than wait for it:
+/// `?async=true`, or `Prefer: respond-async`.
+pub fn wants_async(req: &HttpRequest) -> bool {
+    let prefers = req
+        .headers()
+        .get_all("Prefer")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"));
+    prefers
+        || web::Query::<AsyncQuery>::from_query(req.query_string())
+            .is_ok_and(|query| query.run_async)
+}
+
+/// Who's submitting a job through `req`, to record on it.
+pub fn submitter(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<Identity>()
+        .map(|identity| identity.subject.clone())
+}
+
+/// Answers a call that went on as a job: 202 with the job, and where to follow it.
+pub fn accepted(job: &Job) -> HttpResponse {
+    HttpResponse::Accepted()
+        .insert_header((LOCATION, format!("/v0/jobs/{}", job.id)))
+        .json(job)
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: BTreeMap<u64, Job>,
+    work: HashMap<u64, Work>,
+    /// Signals the running or retrying jobs to stop.
+    cancels: HashMap<u64, Arc<Notify>>,
+    /// Secrets in the approval links of jobs awaiting approval.
+    approval_tokens: HashMap<u64, String>,
+    next_id: u64,
+}
+
+impl Queue {
+    fn position(&self, job: &Job) -> Option<usize> {
+        (job.state == JobState::Queued).then(|| {
+            self.jobs
+                .range(..job.id)
+                .filter(|(_, other)| other.state == JobState::Queued && other.group == job.group)
+                .count()
+                + 1
+        })
+    }
+
+    fn view(&self, job: &Job) -> Job {
+        Job {
+            queue_position: self.position(job),
+            ..job.clone()
+        }
+    }
+}
+
+fn holds_slot(state: JobState) -> bool {
+    matches!(
+        state,
+        JobState::AwaitingApproval | JobState::Running | JobState::Retrying
+    )
+}
+
+fn start(job: &mut Job) {
+    job.state = JobState::Running;
+    job.next_retry_at = None;
+    job.progress = None;
+    job.started_at.get_or_insert_with(Utc::now);
+    job.attempts.push(JobAttempt {
+        started_at: Utc::now(),
+        finished_at: None,
+        error: None,
+        steps: Vec::new(),
+    });
+}
+
+fn approval_token() -> String {
+    let mut bytes = [0u8; 24];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    hex::encode(bytes)
+}
+
+/// One FIFO queue for every unfinished job; finished ones move to the store. A job starts
+/// once it is first in its concurrency group, the group has a free slot and fewer than
+/// `max_concurrent` jobs are running overall. Jobs needing approval then wait, holding
+/// their slot, until an approver decides.
+pub struct Jobs {
+    config: JobsConfig,
+    events: web::Data<EventLog>,
+    store: web::Data<Store>,
+    /// Sends approval links to `config.approval_route`.
+    notifier: Notifier,
+    queue: Mutex<Queue>,
+    /// Woken whenever a job is archived.
+    archived: Notify,
+}
+
+impl Jobs {
+    pub fn new(
+        config: JobsConfig,
+        events: web::Data<EventLog>,
+        store: web::Data<Store>,
+        notifier: Notifier,
+    ) -> Self {
+        Jobs {
+            config,
+            events,
+            store,
+            notifier,
+            queue: Mutex::new(Queue {
+                next_id: 1,
+                ..Default::default()
+            }),
+            archived: Notify::new(),
+        }
+    }
+
+    /// Numbers new jobs after the finished ones already in the store, so a persistent
+    /// store's history isn't overwritten after a restart.
+    pub async fn load(&self) -> Result<(), StoreError> {
+        let finished = self.store.list::<Job>(JOBS).await?;
+        if let Some(last) = finished.iter().map(|job| job.id).max() {
+            let mut queue = self.queue.lock().unwrap();
+            queue.next_id = queue.next_id.max(last + 1);
+        }
+        Ok(())
+    }
+
+    /// The configured group listing `app`, or one named after the app.
+    pub fn group_for(&self, app: &str) -> String {
+        let mut groups: Vec<&String> = self
+            .config
+            .groups
+            .iter()
+            .filter(|(_, group)| group.apps.iter().any(|listed| listed == app))
+            .map(|(name, _)| name)
+            .collect();
+        groups.sort();
+        groups
+            .first()
+            .map_or_else(|| app.to_string(), |name| name.to_string())
+    }
+
+    pub fn requires_approval(&self, group: &str) -> bool {
+        self.config
+            .groups
+            .get(group)
+            .is_some_and(|group| group.require_approval)
+    }
+
+    fn concurrency(&self, group: &str) -> usize {
+        self.config
+            .groups
+            .get(group)
+            .map_or(1, |group| group.concurrency.max(1))
+    }
+
+    fn record(&self, job: &Job, what: &str) {
+        self.events.record(
+            &format!("{}.{}", job.kind, what),
+            job.app.as_deref(),
+            None,
+            json!({
+                "job_id": job.id,
+                "group": job.group,
+                "schedule_id": job.schedule_id,
+                "retries": job.retries,
+                "error": job.error,
+            }),
+        );
+    }
+
+    /// Records that `job` is waiting on an approver, and sends the approval link to
+    /// `approval_route` only: events are readable through `/v0/events` and exported to
+    /// sinks, so the recorded one leaves the link out.
+    fn request_approval(&self, job: &Job, token: &str) {
+        let mut event = self.events.record(
+            &format!("{}.approval_requested", job.kind),
+            job.app.as_deref(),
+            None,
+            json!({ "job_id": job.id, "group": job.group }),
+        );
+        let Some(route) = self.config.approval_route.clone() else {
+            log::warn!(
+                "Job {} awaits approval, but jobs.approval_route isn't set to send its link to",
+                job.id
+            );
+            return;
+        };
+        let base = self.config.public_url.as_deref().unwrap_or_default();
+        event.detail["approval_url"] = json!(format!(
+            "{}/v0/jobs/{}/approve?token={}",
+            base.trim_end_matches('/'),
+            job.id,
+            token
+        ));
+        let notifier = self.notifier.clone();
+        actix_web::rt::spawn(async move {
+            notifier.deliver(&route, &event).await;
+        });
+    }
+
+    pub fn submit(
+        jobs: &web::Data<Jobs>,
+        kind: &str,
+        app: Option<&str>,
+        group: String,
+        needs_approval: bool,
+        submitted_by: Option<String>,
+        work: Work,
+    ) -> Job {
+        let mut job = jobs.new_job(kind, app, group, needs_approval);
+        job.submitted_by = submitted_by;
+        Jobs::enqueue(jobs, job, work)
+    }
+
+    /// Submits a job that fails the first time an attempt does.
+    pub fn submit_once(
+        jobs: &web::Data<Jobs>,
+        kind: &str,
+        app: Option<&str>,
+        group: String,
+        submitted_by: Option<String>,
+        work: Work,
+    ) -> Job {
+        let mut job = jobs.new_job(kind, app, group, false);
+        job.max_retries = 0;
+        job.submitted_by = submitted_by;
+        Jobs::enqueue(jobs, job, work)
+    }
+
+    /// Submits a run of `schedule`, retried as often as the schedule says.
+    pub fn submit_scheduled(
+        jobs: &web::Data<Jobs>,
+        schedule: &Schedule,
+        group: String,
+        needs_approval: bool,
+        work: Work,
+    ) -> Job {
+        let mut job = jobs.new_job(
+            schedule.operation.kind(),
+            Some(schedule.operation.app()),
+            group,
+            needs_approval,
+        );
+        job.schedule_id = Some(schedule.id.clone());
+        job.submitted_by = schedule
+            .created_by
+            .as_ref()
+            .map(|owner| owner.subject.clone());
+        job.max_retries = schedule.max_retries.unwrap_or(job.max_retries);
+        Jobs::enqueue(jobs, job, work)
+    }
+
+    fn new_job(&self, kind: &str, app: Option<&str>, group: String, needs_approval: bool) -> Job {
+        Job {
+            id: 0,
+            kind: kind.to_string(),
+            app: app.map(str::to_string),
+            group,
+            state: JobState::Queued,
+            queue_position: None,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+            result: None,
+            progress: None,
+            approval: needs_approval.then(Approval::default),
+            schedule_id: None,
+            max_retries: self.config.max_retries,
+            retries: 0,
+            next_retry_at: None,
+            attempts: Vec::new(),
+            submitted_by: None,
+            canceled_by: None,
+        }
+    }
+
+    fn enqueue(jobs: &web::Data<Jobs>, mut job: Job, work: Work) -> Job {
+        let job = {
+            let mut queue = jobs.queue.lock().unwrap();
+            job.id = queue.next_id;
+            queue.next_id += 1;
+            queue.jobs.insert(job.id, job.clone());
+            queue.work.insert(job.id, work);
+            job
+        };
+        jobs.record(&job, "queued");
+
+        Jobs::dispatch(jobs);
+        jobs.live(job.id).unwrap_or(job)
+    }
+
+    /// A job that hasn't finished, or has only just.
+    fn live(&self, id: u64) -> Option<Job> {
+        let queue = self.queue.lock().unwrap();
+        queue.jobs.get(&id).map(|job| queue.view(job))
+    }
+
+    pub async fn get(&self, id: u64) -> Option<Job> {
+        if let Some(job) = self.live(id) {
+            return Some(job);
+        }
+        self.store
+            .get(JOBS, &format!("{:020}", id))
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("Failed to load job {}: {}", id, e);
+                None
+            })
+    }
+
+    /// Job `id` once it has finished, however long that takes.
+    pub async fn finished(&self, id: u64) -> Option<Job> {
+        loop {
+            let archived = self.archived.notified();
+            tokio::pin!(archived);
+            archived.as_mut().enable();
+            match self.get(id).await {
+                Some(job) if job.finished_at.is_some() => return Some(job),
+                Some(_) => archived.await,
+                None => return None,
+            }
+        }
+    }
+
+    /// Gives every queued job whose turn it is its slot, starting it unless it must wait
+    /// for approval.
+    fn dispatch(jobs: &web::Data<Jobs>) {
+        let mut started = Vec::new();
+        let mut awaiting = Vec::new();
+        {
+            let mut queue = jobs.queue.lock().unwrap();
+            let mut running: HashMap<String, usize> = HashMap::new();
+            for job in queue.jobs.values() {
+                if holds_slot(job.state) {
+                    *running.entry(job.group.clone()).or_default() += 1;
+                }
+            }
+            let mut total: usize = running.values().sum();
+
+            let queued: Vec<u64> = queue
+                .jobs
+                .values()
+                .filter(|job| job.state == JobState::Queued)
+                .map(|job| job.id)
+                .collect();
+            for id in queued {
+                if total >= jobs.config.max_concurrent.max(1) {
+                    break;
+                }
+                let Some(job) = queue.jobs.get_mut(&id) else {
+                    continue;
+                };
+                let group_running = running.entry(job.group.clone()).or_default();
+                if *group_running >= jobs.concurrency(&job.group) {
+                    continue;
+                }
+                *group_running += 1;
+                total += 1;
+
+                if job
+                    .approval
+                    .as_ref()
+                    .is_some_and(|approval| approval.approved.is_none())
+                {
+                    job.state = JobState::AwaitingApproval;
+                    let job = job.clone();
+                    let token = approval_token();
+                    queue.approval_tokens.insert(id, token.clone());
+                    awaiting.push((job, token));
+                    continue;
+                }
+
+                start(job);
+                let job = job.clone();
+                if let Some(work) = queue.work.remove(&id) {
+                    started.push((job, work));
+                }
+            }
+        }
+
+        for (job, token) in awaiting {
+            jobs.request_approval(&job, &token);
+        }
+        for (job, work) in started {
+            Jobs::run(jobs, job, work);
+        }
+    }
+
+    /// Runs the job until an attempt succeeds, it runs out of retries or it's canceled,
+    /// backing off between attempts without giving up its slot. An attempt that's canceled
+    /// or outlives `attempt_timeout_secs` is dropped where it was, and its deferred
+    /// cleanups run before the job moves on.
+    fn run(jobs: &web::Data<Jobs>, job: Job, mut work: Work) {
+        jobs.record(&job, "started");
+        let jobs = jobs.clone();
+        // A cancel may already be waiting, if it came before the job's task started.
+        let cancel = jobs
+            .queue
+            .lock()
+            .unwrap()
+            .cancels
+            .entry(job.id)
+            .or_default()
+            .clone();
+        let timeout = Duration::from_secs(jobs.config.attempt_timeout_secs);
+        actix_web::rt::spawn(async move {
+            loop {
+                let attempt = Arc::new(Attempt {
+                    jobs: jobs.clone(),
+                    id: job.id,
+                    deadline: (!timeout.is_zero()).then(|| Instant::now() + timeout),
+                    partial: Mutex::new(None),
+                    cleanups: Mutex::new(Vec::new()),
+                });
+                let task = RUNNING.scope(attempt.clone(), async {
+                    match attempt.deadline {
+                        Some(deadline) => tokio::time::timeout_at(deadline.into(), work())
+                            .await
+                            .unwrap_or_else(|_| {
+                                Err(format!("Timed out after {}s", timeout.as_secs()))
+                            }),
+                        None => work().await,
+                    }
+                });
+                let result = tokio::select! {
+                    result = task => Some(result),
+                    () = cancel.notified() => None,
+                };
+                attempt.clean_up().await;
+                let partial = attempt.partial.lock().unwrap().take();
+                let Some(backoff) = jobs.finish_attempt(job.id, result, partial) else {
+                    break;
+                };
+                tokio::select! {
+                    () = tokio::time::sleep(backoff) => {}
+                    () = cancel.notified() => {
+                        jobs.finish_attempt(job.id, None, None);
+                        break;
+                    }
+                }
+                if let Some(job) = jobs.queue.lock().unwrap().jobs.get_mut(&job.id) {
+                    start(job);
+                }
+            }
+            jobs.archive(job.id).await;
+            Jobs::dispatch(&jobs);
+        });
+    }
+
+    /// Cancels a job that hasn't finished. Queued jobs and those awaiting approval never
+    /// run; running ones are stopped and give up their slot once they've cleaned up.
+    fn cancel(jobs: &web::Data<Jobs>, id: u64, canceled_by: String) -> Result<Job, HttpResponse> {
+        let (job, stopped) = {
+            let mut queue = jobs.queue.lock().unwrap();
+            let Some(job) = queue.jobs.get_mut(&id) else {
+                return Err(
+                    AppError::conflict(format!("Job {} isn't running or queued", id))
+                        .into_response(),
+                );
+            };
+            if job.canceled_by.is_some() {
+                return Err(
+                    AppError::conflict(format!("Job {} is already being canceled", id))
+                        .into_response(),
+                );
+            }
+            job.canceled_by = Some(canceled_by);
+            let running = matches!(job.state, JobState::Running | JobState::Retrying);
+            if !running {
+                job.state = JobState::Canceled;
+                job.finished_at = Some(Utc::now());
+            }
+            let job = job.clone();
+            if running {
+                queue.cancels.entry(id).or_default().notify_one();
+            } else {
+                queue.work.remove(&id);
+                queue.approval_tokens.remove(&id);
+            }
+            (job, !running)
+        };
+
+        if stopped {
+            jobs.record(&job, "canceled");
+            let archived = jobs.clone();
+            actix_web::rt::spawn(async move {
+                archived.archive(id).await;
+                Jobs::dispatch(&archived);
+            });
+        }
+        Ok(job)
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let secs = self
+            .config
+            .retry_backoff_secs
+            .saturating_mul(1 << retry.saturating_sub(1).min(32))
+            .min(self.config.max_retry_backoff_secs);
+        Duration::from_secs(secs)
+    }
+
+    /// Approves or rejects a job awaiting approval. A rejected job never runs, and frees
+    /// its slot for the next job in the group.
+    fn decide(
+        jobs: &web::Data<Jobs>,
+        id: u64,
+        approved: bool,
+        decided_by: String,
+    ) -> Result<Job, HttpResponse> {
+        let (job, work) = {
+            let mut queue = jobs.queue.lock().unwrap();
+            let Some(job) = queue.jobs.get_mut(&id) else {
+                return Err(AppError::not_found(format!("No job {}", id)).into_response());
+            };
+            if job.state != JobState::AwaitingApproval {
+                return Err(
+                    AppError::conflict(format!("Job {} isn't awaiting approval", id))
+                        .into_response(),
+                );
+            }
+
+            job.approval = Some(Approval {
+                approved: Some(approved),
+                decided_by: Some(decided_by),
+                decided_at: Some(Utc::now()),
+            });
+            if approved {
+                start(job);
+            } else {
+                job.state = JobState::Rejected;
+                job.finished_at = Some(Utc::now());
+            }
+            let job = job.clone();
+            queue.approval_tokens.remove(&id);
+            (job, queue.work.remove(&id))
+        };
+
+        if approved {
+            jobs.record(&job, "approved");
+            if let Some(work) = work {
+                Jobs::run(jobs, job.clone(), work);
+            }
+        } else {
+            jobs.record(&job, "rejected");
+            let archived = jobs.clone();
+            actix_web::rt::spawn(async move {
+                archived.archive(id).await;
+                Jobs::dispatch(&archived);
+            });
+        }
+        Ok(job)
+    }
+
+    /// Whether `identity` may cancel `job`: its submitter, or a jobs admin.
+    fn may_cancel(&self, identity: &Identity, job: &Job) -> bool {
+        job.submitted_by.as_ref() == Some(&identity.subject)
+            || self
+                .config
+                .admin_role
+                .as_ref()
+                .is_some_and(|role| identity.roles.contains(role))
+    }
+
+    /// Who may decide on job `id`: an authenticated caller holding its approval link, or
+    /// an approver, but never the job's submitter.
+    fn approver(
+        &self,
+        req: &HttpRequest,
+        id: u64,
+        token: Option<&str>,
+    ) -> Result<String, HttpResponse> {
+        let identity = auth::require_role(req, None)?;
+        let (has_link, submitted_by) = {
+            let queue = self.queue.lock().unwrap();
+            let has_link = token.is_some_and(|token| {
+                queue.approval_tokens.get(&id).map(String::as_str) == Some(token)
+            });
+            let submitted_by = queue.jobs.get(&id).and_then(|job| job.submitted_by.clone());
+            (has_link, submitted_by)
+        };
+        if submitted_by.as_ref() == Some(&identity.subject) {
+            return Err(
+                AppError::forbidden("A job can't be approved by its submitter").into_response(),
+            );
+        }
+        let is_approver = self
+            .config
+            .approver_role
+            .as_ref()
+            .is_some_and(|role| identity.roles.contains(role));
+        if !is_approver && !has_link {
+            return Err(
+                AppError::forbidden("An approval link or the approver role is required")
+                    .into_response(),
+            );
+        }
+        Ok(identity.subject)
+    }
+
+    /// Records how an attempt went, `None` if it was canceled, returning how long to back
+    /// off if the job is to be retried. A failed attempt's result is what it recorded with
+    /// [`record_partial`].
+    fn finish_attempt(
+        &self,
+        id: u64,
+        result: Option<Result<Value, String>>,
+        partial: Option<Value>,
+    ) -> Option<Duration> {
+        let (job, backoff) = {
+            let mut queue = self.queue.lock().unwrap();
+            let job = queue.jobs.get_mut(&id)?;
+            let now = Utc::now();
+            if let Some(attempt) = job.attempts.last_mut()
+                && attempt.finished_at.is_none()
+            {
+                attempt.finished_at = Some(now);
+                attempt.error = match &result {
+                    Some(result) => result.as_ref().err().cloned(),
+                    None => Some("Canceled".to_string()),
+                };
+            }
+            let mut backoff = None;
+            match result {
+                None => {
+                    job.state = JobState::Canceled;
+                    job.error = Some(format!(
+                        "Canceled by {}",
+                        job.canceled_by.as_deref().unwrap_or("unknown")
+                    ));
+                    job.result = partial.or(job.result.take());
+                    job.next_retry_at = None;
+                    job.finished_at = Some(now);
+                }
+                Some(Ok(result)) => {
+                    job.state = JobState::Succeeded;
+                    job.error = None;
+                    job.result = Some(result);
+                    job.finished_at = Some(now);
+                }
+                Some(Err(e)) if job.retries < job.max_retries => {
+                    job.retries += 1;
+                    let wait = self.backoff(job.retries);
+                    job.state = JobState::Retrying;
+                    job.error = Some(e);
+                    job.result = partial;
+                    job.next_retry_at = TimeDelta::from_std(wait).ok().map(|wait| now + wait);
+                    backoff = Some(wait);
+                }
+                Some(Err(e)) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(e);
+                    job.result = partial;
+                    job.finished_at = Some(now);
+                }
+            }
+            (job.clone(), backoff)
+        };
+        match job.state {
+            JobState::Succeeded => self.record(&job, "succeeded"),
+            JobState::Retrying => self.record(&job, "retrying"),
+            JobState::Canceled => self.record(&job, "canceled"),
+            _ => self.record(&job, "failed"),
+        }
+        backoff
+    }
+
+    /// Newest first: unfinished jobs, then the finished history.
+    pub async fn list(&self) -> Result<Vec<Job>, String> {
+        let finished = self
+            .store
+            .list::<Job>(JOBS)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut all: BTreeMap<u64, Job> = finished.into_iter().map(|job| (job.id, job)).collect();
+        {
+            let queue = self.queue.lock().unwrap();
+            all.extend(queue.jobs.values().map(|job| (job.id, queue.view(job))));
+        }
+        Ok(all.into_values().rev().collect())
+    }
+
+    /// Moves a finished job from the queue to the store, dropping the oldest finished jobs
+    /// beyond `history`.
+    async fn archive(&self, id: u64) {
+        let Some(job) = self.live(id) else {
+            return;
+        };
+        // Stored before it leaves the queue, so it is always in one or the other.
+        if let Err(e) = self.store.put(JOBS, &format!("{:020}", id), &job).await {
+            log::error!("Failed to store job {}: {}", id, e);
+        }
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.jobs.remove(&id);
+            queue.cancels.remove(&id);
+        }
+        self.archived.notify_waiters();
+
+        let finished = match self.store.list::<Job>(JOBS).await {
+            Ok(finished) => finished,
+            Err(e) => {
+                log::error!("Failed to list finished jobs: {}", e);
+                return;
+            }
+        };
+        let excess = finished.len().saturating_sub(self.config.history);
+        for job in &finished[..excess] {
+            if let Err(e) = self.store.delete(JOBS, &format!("{:020}", job.id)).await {
+                log::error!("Failed to drop job {}: {}", job.id, e);
+            }
+        }
+    }
+}
+
+/// Whether the caller may see `job`: one for an app only if they may touch the app, and
+/// one for no app only if they submitted it or aren't limited to some apps.
+fn visible(req: &HttpRequest, identity: &Identity, job: &Job) -> bool {
+    let namespace = namespaces::of(req);
+    match &job.app {
+        Some(app) => {
+            identity.may_touch(app) && namespace.is_none_or(|namespace| namespace.owns(app))
+        }
+        None => {
+            job.submitted_by.as_ref() == Some(&identity.subject)
+                || (identity.apps.is_none() && namespace.is_none())
+        }
+    }
+}
+
+#[get("/v0/jobs")]
+async fn list_jobs(
+    req: HttpRequest,
+    query: web::Query<JobsQuery>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    let identity = match auth::require_role(&req, None) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let all = match jobs.list().await {
+        Ok(all) => all,
+        Err(e) => return AppError::internal(e).into_response(),
+    };
+    let matching: Vec<Job> = all
+        .into_iter()
+        .filter(|job| visible(&req, &identity, job))
+        .filter(|job| query.kind.as_ref().is_none_or(|kind| &job.kind == kind))
+        .filter(|job| query.app.is_none() || job.app == query.app)
+        .filter(|job| query.group.as_ref().is_none_or(|group| &job.group == group))
+        .filter(|job| query.state.is_none_or(|state| job.state == state))
+        .filter(|job| query.schedule_id.is_none() || job.schedule_id == query.schedule_id)
+        .collect();
+    HttpResponse::Ok().json(matching)
+}
+
+#[get("/v0/jobs/{id}")]
+async fn get_job(req: HttpRequest, path: web::Path<u64>, jobs: web::Data<Jobs>) -> impl Responder {
+    let identity = match auth::require_role(&req, None) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let id = path.into_inner();
+    match jobs.get(id).await {
+        Some(job) if visible(&req, &identity, &job) => HttpResponse::Ok().json(job),
+        _ => AppError::not_found(format!("No job {}", id)).into_response(),
+    }
+}
+
+/// Where approval links land. Deciding takes an authenticated POST, so link previews and
+/// mail scanners fetching the link can't approve anything.
+#[get("/v0/jobs/{id}/approve")]
+async fn approval_page(
+    path: web::Path<u64>,
+    query: web::Query<ApprovalQuery>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let Some(job) = jobs.get(id).await else {
+        return AppError::not_found(format!("No job {}", id)).into_response();
+    };
+    let token = escape_html(query.token.as_deref().unwrap_or_default());
+    let summary = format!(
+        "{} of {} (job {}, group {})",
+        job.kind,
+        job.app.as_deref().unwrap_or("-"),
+        job.id,
+        job.group
+    );
+
+    let body = if job.state == JobState::AwaitingApproval {
+        format!(
+            "<h2>Approve {summary}?</h2>\n\
+             <form method=\"post\" action=\"?token={token}\"><button>Approve</button></form>\n\
+             <form method=\"post\" action=\"?token={token}&amp;reject=true\"><button>Reject</button></form>\n\
+             <p>Deciding needs your own flyd Authorization header; the job's submitter can't.</p>\n",
+            summary = escape_html(&summary),
+            token = token
+        )
+    } else {
+        format!(
+            "<h2>{}</h2>\n<p>Not awaiting approval: {}</p>\n",
+            escape_html(&summary),
+            serde_json::to_string(&job.state).unwrap_or_default()
+        )
+    };
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+#[post("/v0/jobs/{id}/approve")]
+async fn approve_job(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    query: web::Query<ApprovalQuery>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let decided_by = match jobs.approver(&req, id, query.token.as_deref()) {
+        Ok(decided_by) => decided_by,
+        Err(response) => return response,
+    };
+    match Jobs::decide(&jobs, id, !query.reject, decided_by) {
+        Ok(job) => HttpResponse::Ok().json(job),
+        Err(response) => response,
+    }
+}
+
+/// Stops the job: one that hasn't started never will, and a running one is dropped
+/// mid-step, its in-flight calls with it, once its leases are released.
+#[post("/v0/jobs/{id}/cancel")]
+async fn cancel_job(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    jobs: web::Data<Jobs>,
+) -> impl Responder {
+    let identity = match auth::require_role(&req, None) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let id = path.into_inner();
+    let Some(job) = jobs.get(id).await else {
+        return AppError::not_found(format!("No job {}", id)).into_response();
+    };
+    if !jobs.may_cancel(&identity, &job) {
+        return AppError::forbidden("Only the job's submitter or a jobs admin may cancel it")
+            .into_response();
+    }
+    match Jobs::cancel(&jobs, id, identity.subject) {
+        Ok(job) => HttpResponse::Ok().json(job),
+        Err(response) => response,
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_jobs)
+        .service(get_job)
+        .service(approval_page)
+        .service(approve_job)
+        .service(cancel_job);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderValue;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::config::{ConcurrencyGroupConfig, NotificationsConfig, WriteQueueConfig};
+    use crate::write_queue::WriteQueue;
+
+    fn jobs(config: JobsConfig) -> web::Data<Jobs> {
+        let (persist, _writes) = WriteQueue::new(&WriteQueueConfig::default());
+        web::Data::new(Jobs::new(
+            config,
+            web::Data::new(EventLog::new(persist)),
+            web::Data::new(Store::default()),
+            Notifier::new(
+                reqwest::Client::new(),
+                None,
+                &NotificationsConfig::default(),
+            ),
+        ))
+    }
+
+    fn identity(subject: &str, roles: &[&str]) -> Identity {
+        Identity {
+            subject: subject.to_string(),
+            provider: "static_keys",
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            fly_authorization: HeaderValue::from_static("Bearer own"),
+            apps: None,
+        }
+    }
+
+    /// A deploy by `submitted_by` that waits for approval, so it never runs.
+    fn awaiting(jobs: &web::Data<Jobs>, submitted_by: &str) -> Job {
+        let work: Work = Box::new(|| Box::pin(async { Ok(Value::Null) }));
+        Jobs::submit(
+            jobs,
+            "deploy",
+            Some("web"),
+            "web".to_string(),
+            true,
+            Some(submitted_by.to_string()),
+            work,
+        )
+    }
+
+    #[actix_web::test]
+    async fn only_the_submitter_or_an_admin_may_cancel() {
+        let jobs = jobs(JobsConfig {
+            admin_role: Some("jobs-admin".to_string()),
+            ..Default::default()
+        });
+        let job = awaiting(&jobs, "alice");
+        assert!(jobs.may_cancel(&identity("alice", &[]), &job));
+        assert!(!jobs.may_cancel(&identity("bob", &["deployer"]), &job));
+        assert!(jobs.may_cancel(&identity("carol", &["jobs-admin"]), &job));
+
+        let unowned = Jobs::submit_once(
+            &jobs,
+            "wait",
+            None,
+            "wait".to_string(),
+            None,
+            Box::new(|| Box::pin(async { Ok(Value::Null) })),
+        );
+        assert!(!jobs.may_cancel(&identity("alice", &[]), &unowned));
+    }
+
+    fn request(identity: Option<Identity>) -> HttpRequest {
+        let req = TestRequest::default().to_http_request();
+        if let Some(identity) = identity {
+            req.extensions_mut().insert(identity);
+        }
+        req
+    }
+
+    fn link(jobs: &Jobs, job: &Job) -> String {
+        jobs.queue.lock().unwrap().approval_tokens[&job.id].clone()
+    }
+
+    #[actix_web::test]
+    async fn the_submitter_may_not_approve() {
+        let jobs = jobs(JobsConfig {
+            approver_role: Some("approver".to_string()),
+            ..Default::default()
+        });
+        let job = awaiting(&jobs, "alice");
+        let token = link(&jobs, &job);
+        let alice = request(Some(identity("alice", &["approver"])));
+        assert!(jobs.approver(&alice, job.id, Some(&token)).is_err());
+        let bob = request(Some(identity("bob", &["approver"])));
+        assert_eq!(jobs.approver(&bob, job.id, None).unwrap(), "bob");
+    }
+
+    #[actix_web::test]
+    async fn without_an_approver_role_only_the_link_approves() {
+        let jobs = jobs(JobsConfig::default());
+        let job = awaiting(&jobs, "alice");
+        let token = link(&jobs, &job);
+        let bob = request(Some(identity("bob", &["admin"])));
+        assert!(jobs.approver(&bob, job.id, None).is_err());
+        assert!(jobs.approver(&bob, job.id, Some("guessed")).is_err());
+        assert_eq!(jobs.approver(&bob, job.id, Some(&token)).unwrap(), "bob");
+        assert!(jobs.approver(&request(None), job.id, Some(&token)).is_err());
+        let alice = request(Some(identity("alice", &[])));
+        assert!(jobs.approver(&alice, job.id, Some(&token)).is_err());
+    }
+
+    #[actix_web::test]
+    async fn the_approval_event_leaves_the_link_out() {
+        let jobs = jobs(JobsConfig {
+            public_url: Some("https://flyd.example.com".to_string()),
+            ..Default::default()
+        });
+        let job = awaiting(&jobs, "alice");
+        let token = link(&jobs, &job);
+        let events = jobs.events.since(Utc::now() - TimeDelta::minutes(1));
+        let requested = events
+            .iter()
+            .find(|event| event.kind == "deploy.approval_requested")
+            .unwrap();
+        assert_eq!(requested.detail["job_id"], job.id);
+        assert!(!requested.detail.to_string().contains(&token));
+    }
+
+    #[actix_web::test]
+    async fn callers_see_only_the_jobs_of_apps_they_may_touch() {
+        let jobs = jobs(JobsConfig::default());
+        let web_job = awaiting(&jobs, "alice");
+        let unowned = Jobs::submit_once(
+            &jobs,
+            "wait",
+            None,
+            "wait".to_string(),
+            Some("alice".to_string()),
+            Box::new(|| Box::pin(async { Ok(Value::Null) })),
+        );
+        let req = request(None);
+
+        let mut scoped = identity("bob", &[]);
+        scoped.apps = Some(vec!["api".to_string()]);
+        assert!(!visible(&req, &scoped, &web_job));
+        assert!(!visible(&req, &scoped, &unowned));
+        scoped.apps = Some(vec!["web".to_string()]);
+        assert!(visible(&req, &scoped, &web_job));
+
+        let mut submitter = identity("alice", &[]);
+        submitter.apps = Some(vec!["api".to_string()]);
+        assert!(visible(&req, &submitter, &unowned));
+        assert!(visible(&req, &identity("carol", &[]), &unowned));
+    }
+
+    #[actix_web::test]
+    async fn approving_starts_the_job_and_rejecting_finishes_it() {
+        let group = ConcurrencyGroupConfig {
+            apps: vec!["web".to_string()],
+            concurrency: 2,
+            require_approval: false,
+        };
+        let jobs = jobs(JobsConfig {
+            groups: HashMap::from([("web".to_string(), group)]),
+            ..Default::default()
+        });
+        let approved = awaiting(&jobs, "alice");
+        let job = Jobs::decide(&jobs, approved.id, true, "bob".to_string()).unwrap();
+        assert_eq!(job.state, JobState::Running);
+        assert!(Jobs::decide(&jobs, approved.id, true, "bob".to_string()).is_err());
+
+        let rejected = awaiting(&jobs, "alice");
+        let job = Jobs::decide(&jobs, rejected.id, false, "bob".to_string()).unwrap();
+        assert_eq!(job.state, JobState::Rejected);
+        assert!(job.finished_at.is_some());
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use chrono::Utc;
+use chrono_tz::Tz;
+use croner::Cron;
+use futures_util::future::join_all;
+
+use crate::config::KeepWarmConfig;
+use crate::metrics;
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    pings: u64,
+    wakes: u64,
+    failures: u64,
+}
+
+struct Target {
+    app: String,
+    url: String,
+    cron: Cron,
+    timezone: Tz,
+    wake_threshold: Duration,
+    timeout: Duration,
+}
+
+/// Pings each `[keep_warm]` app on its schedule. A ping slower than the app's
+/// `wake_threshold_ms` most likely waited for Fly's proxy to start a machine, and is
+/// counted as a wake the ping induced.
+pub struct KeepWarm {
+    http: reqwest::Client,
+    targets: Vec<Target>,
+    counts: Mutex<BTreeMap<String, Counts>>,
+}
+
+impl KeepWarm {
+    pub fn from_config(
+        configs: &BTreeMap<String, KeepWarmConfig>,
+        http: reqwest::Client,
+    ) -> Result<Option<Self>, String> {
+        if configs.is_empty() {
+            return Ok(None);
+        }
+        let targets = configs
+            .iter()
+            .map(|(app, config)| {
+                let cron = Cron::from_str(&config.cron).map_err(|e| {
+                    format!("keep_warm.{}: invalid cron {}: {}", app, config.cron, e)
+                })?;
+                let timezone = match &config.timezone {
+                    Some(name) => Tz::from_str(name)
+                        .map_err(|_| format!("keep_warm.{}: unknown timezone {}", app, name))?,
+                    None => Tz::UTC,
+                };
+                let url = match &config.url {
+                    Some(url) => url.clone(),
+                    None if config.private => {
+                        format!("http://{}.flycast:{}{}", app, config.port, config.path)
+                    }
+                    None => format!("https://{}.fly.dev{}", app, config.path),
+                };
+                Ok(Target {
+                    app: app.clone(),
+                    url,
+                    cron,
+                    timezone,
+                    wake_threshold: Duration::from_millis(config.wake_threshold_ms),
+                    timeout: Duration::from_secs(config.timeout_secs),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let counts = targets
+            .iter()
+            .map(|target| (target.app.clone(), Counts::default()))
+            .collect();
+        Ok(Some(KeepWarm {
+            http,
+            targets,
+            counts: Mutex::new(counts),
+        }))
+    }
+
+    async fn ping(&self, target: &Target) {
+        let started = Instant::now();
+        let result = self
+            .http
+            .get(&target.url)
+            .timeout(target.timeout)
+            .send()
+            .await;
+        let took = started.elapsed();
+        // Any answer means the proxy reached a machine; only failing to get one doesn't.
+        let error = match &result {
+            Ok(response) if response.status().is_server_error() => {
+                Some(format!("answered {}", response.status()))
+            }
+            Ok(_) => None,
+            Err(e) => Some(e.to_string()),
+        };
+
+        let mut counts = self.counts.lock().unwrap();
+        let counts = counts.entry(target.app.clone()).or_default();
+        counts.pings += 1;
+        match error {
+            Some(e) => {
+                counts.failures += 1;
+                log::warn!("Keep-warm ping of {} failed: {}", target.url, e);
+            }
+            None if took >= target.wake_threshold => {
+                counts.wakes += 1;
+                log::info!(
+                    "Keep-warm ping of {} took {}ms, likely waking it",
+                    target.app,
+                    took.as_millis()
+                );
+            }
+            None => {}
+        }
+    }
+
+    async fn keep_warm(&self, target: &Target) {
+        loop {
+            let now = Utc::now().with_timezone(&target.timezone);
+            let next = match target.cron.find_next_occurrence(&now, false) {
+                Ok(next) => next,
+                Err(e) => {
+                    log::error!("Keep-warm of {}: no next ping: {}", target.app, e);
+                    return;
+                }
+            };
+            let wait = (next.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            self.ping(target).await;
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        let counts = self.counts.lock().unwrap().clone();
+        let samples = |count: fn(&Counts) -> u64| -> Vec<(String, u64)> {
+            counts
+                .iter()
+                .map(|(app, counts)| (app.clone(), count(counts)))
+                .collect()
+        };
+        for (name, help, samples) in [
+            (
+                "flyd_keep_warm_pings_total",
+                "Keep-warm pings sent to the app.",
+                samples(|counts| counts.pings),
+            ),
+            (
+                "flyd_keep_warm_wakes_total",
+                "Keep-warm pings slow enough to have started one of the app's machines.",
+                samples(|counts| counts.wakes),
+            ),
+            (
+                "flyd_keep_warm_failures_total",
+                "Keep-warm pings that got no answer, or a 5xx.",
+                samples(|counts| counts.failures),
+            ),
+        ] {
+            metrics::header(out, name, "counter", help);
+            for (app, value) in samples {
+                metrics::sample(out, name, &[("app", app)], value as f64);
+            }
+        }
+    }
+}
+
+pub async fn run(keep_warm: web::Data<KeepWarm>) {
+    join_all(
+        keep_warm
+            .targets
+            .iter()
+            .map(|target| keep_warm.keep_warm(target)),
+    )
+    .await;
+}
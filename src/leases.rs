@@ -0,0 +1,68 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use flyd::models::{AcquireLeaseRequest, ReleaseLeaseRequest};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+/// Proxies the Machines API's lease: while it's held, updates and destroys must send its
+/// nonce in `fly-machine-lease-nonce`. flyd's own update and destroy endpoints use a
+/// nonce sent that way instead of taking a lease of their own.
+#[post("/v0/machines/lease/acquire")]
+async fn acquire_lease(
+    req: HttpRequest,
+    body: web::Json<AcquireLeaseRequest>,
+    config: web::Data<Config>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    let ttl_secs = body
+        .ttl_secs
+        .unwrap_or(config.machines.update_lease_ttl_secs);
+    match client
+        .lease(
+            &body.app_name,
+            &body.machine_id,
+            ttl_secs,
+            body.description.as_deref(),
+        )
+        .await
+    {
+        Ok(lease) => HttpResponse::Ok().json(lease),
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+#[post("/v0/machines/lease/release")]
+async fn release_lease(
+    req: HttpRequest,
+    body: web::Json<ReleaseLeaseRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    match client
+        .release_lease(&body.app_name, &body.machine_id, &body.nonce)
+        .await
+    {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(acquire_lease).service(release_lease);
+}
@@ -0,0 +1,5 @@
+pub mod fly;
+pub mod models;
+
+#[cfg(feature = "flyd-client")]
+pub mod client;
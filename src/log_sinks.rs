@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use flyd::models::Event;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::{LogSinkConfig, LogSinkKind};
+use crate::notify;
+
+/// Records written before the shipper starts (or while a sink is slow) wait here; past
+/// this they're dropped rather than blocking the caller.
+const BUFFER: usize = 10_000;
+const BATCH_SIZE: usize = 500;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Entry {
+    at: DateTime<Utc>,
+    level: Level,
+    target: String,
+    message: String,
+    event: Option<Event>,
+}
+
+impl Entry {
+    fn from_event(event: Event) -> Self {
+        Entry {
+            at: event.at,
+            level: Level::Info,
+            target: "flyd::events".to_string(),
+            message: format!("{} {}", event.kind, event.detail),
+            event: Some(event),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut entry = json!({
+            "at": self.at,
+            "level": self.level.as_str().to_lowercase(),
+            "target": self.target,
+            "message": self.message,
+        });
+        if let Some(event) = &self.event {
+            entry["event"] = json!(event);
+        }
+        entry
+    }
+}
+
+/// Echoes every record to the terminal logger and queues it for the sinks. The sinks'
+/// own failures aren't queued, so a sink that is down can't feed itself.
+struct Tee<L> {
+    inner: L,
+    sender: mpsc::Sender<Entry>,
+}
+
+impl<L: Log> Log for Tee<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        if record.target().starts_with(module_path!()) {
+            return;
+        }
+        let _ = self.sender.try_send(Entry {
+            at: Utc::now(),
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            event: None,
+        });
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs `inner` as the global logger, returning the records to hand to `run`.
+pub fn install<L: Log + 'static>(
+    inner: L,
+    max_level: LevelFilter,
+) -> Result<mpsc::Receiver<Entry>, SetLoggerError> {
+    let (sender, receiver) = mpsc::channel(BUFFER);
+    log::set_boxed_logger(Box::new(Tee { inner, sender }))?;
+    log::set_max_level(max_level);
+    Ok(receiver)
+}
+
+pub enum Transport {
+    Http,
+    Udp(String),
+    Tcp(String),
+}
+
+impl Transport {
+    /// `udp://host:port` or `tcp://host:port`.
+    pub fn syslog(url: &str) -> Option<Self> {
+        match url.split_once("://") {
+            Some(("udp", address)) => Some(Transport::Udp(address.to_string())),
+            Some(("tcp", address)) => Some(Transport::Tcp(address.to_string())),
+            _ => None,
+        }
+    }
+}
+
+struct Sink {
+    config: LogSinkConfig,
+    level: LevelFilter,
+    transport: Transport,
+}
+
+impl Sink {
+    fn from_config(config: &LogSinkConfig) -> Result<Self, String> {
+        let level = config
+            .level
+            .parse()
+            .map_err(|_| format!("Invalid log sink level {}", config.level))?;
+        let transport = match config.kind {
+            LogSinkKind::Loki | LogSinkKind::Vector => Transport::Http,
+            LogSinkKind::Syslog => Transport::syslog(&config.url).ok_or_else(|| {
+                format!(
+                    "Syslog sink {} must be udp://host:port or tcp://host:port",
+                    config.url
+                )
+            })?,
+        };
+        Ok(Sink {
+            config: config.clone(),
+            level,
+            transport,
+        })
+    }
+
+    fn wants(&self, entry: &Entry) -> bool {
+        match (&entry.event, &self.config.events) {
+            (Some(event), Some(patterns)) => notify::matches(patterns, &event.kind),
+            (Some(_), None) => false,
+            (None, _) => entry.level <= self.level,
+        }
+    }
+}
+
+pub struct LogSinks {
+    sinks: Vec<Sink>,
+}
+
+impl LogSinks {
+    pub fn from_config(configs: &[LogSinkConfig]) -> Result<Option<Self>, String> {
+        if configs.is_empty() {
+            return Ok(None);
+        }
+        let sinks = configs
+            .iter()
+            .map(Sink::from_config)
+            .collect::<Result<_, _>>()?;
+        Ok(Some(LogSinks { sinks }))
+    }
+}
+
+fn loki_body(entries: &[&Entry], labels: &BTreeMap<String, String>) -> serde_json::Value {
+    let mut streams: BTreeMap<&str, Vec<serde_json::Value>> = BTreeMap::new();
+    for entry in entries {
+        let at = entry.at.timestamp_nanos_opt().unwrap_or_default();
+        streams
+            .entry(entry.level.as_str())
+            .or_default()
+            .push(json!([at.to_string(), entry.to_json().to_string()]));
+    }
+
+    let streams: Vec<serde_json::Value> = streams
+        .into_iter()
+        .map(|(level, values)| {
+            let mut stream = json!({ "service": "flyd", "level": level.to_lowercase() });
+            for (key, value) in labels {
+                stream[key] = json!(value);
+            }
+            json!({ "stream": stream, "values": values })
+        })
+        .collect();
+    json!({ "streams": streams })
+}
+
+/// RFC 5424, from facility `user`.
+pub fn syslog_frame(severity: u8, at: DateTime<Utc>, hostname: &str, message: &str) -> String {
+    format!(
+        "<{}>1 {} {} flyd - - - {}",
+        8 + severity,
+        at.to_rfc3339(),
+        hostname,
+        message.replace('\n', " ")
+    )
+}
+
+fn syslog_line(entry: &Entry, hostname: &str) -> String {
+    let severity = match entry.level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    syslog_frame(severity, entry.at, hostname, &entry.message)
+}
+
+pub async fn send_syslog(transport: &Transport, lines: &[String]) -> std::io::Result<()> {
+    match transport {
+        Transport::Udp(address) => {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(address).await?;
+            for line in lines {
+                socket.send(line.as_bytes()).await?;
+            }
+        }
+        Transport::Tcp(address) => {
+            // Octet counting framing (RFC 6587), so messages may contain newlines.
+            let mut stream = TcpStream::connect(address).await?;
+            for line in lines {
+                stream
+                    .write_all(format!("{} {}", line.len(), line).as_bytes())
+                    .await?;
+            }
+            stream.flush().await?;
+        }
+        Transport::Http => {}
+    }
+    Ok(())
+}
+
+async fn ship(sink: &Sink, entries: &[&Entry], http_client: &reqwest::Client, hostname: &str) {
+    let result = match sink.config.kind {
+        LogSinkKind::Loki => http_client
+            .post(&sink.config.url)
+            .json(&loki_body(entries, &sink.config.labels))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        LogSinkKind::Vector => {
+            let body: Vec<String> = entries
+                .iter()
+                .map(|entry| entry.to_json().to_string())
+                .collect();
+            http_client
+                .post(&sink.config.url)
+                .header("content-type", "application/x-ndjson")
+                .body(body.join("\n"))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(drop)
+                .map_err(|e| e.to_string())
+        }
+        LogSinkKind::Syslog => {
+            let lines: Vec<String> = entries
+                .iter()
+                .map(|entry| syslog_line(entry, hostname))
+                .collect();
+            send_syslog(&sink.transport, &lines)
+                .await
+                .map_err(|e| e.to_string())
+        }
+    };
+
+    if let Err(e) = result {
+        log::warn!(
+            "Failed to ship {} log entries to {}: {}",
+            entries.len(),
+            sink.config.url,
+            e
+        );
+    }
+}
+
+async fn flush(
+    sinks: &LogSinks,
+    batch: &mut Vec<Entry>,
+    http_client: &reqwest::Client,
+    hostname: &str,
+) {
+    for sink in &sinks.sinks {
+        let entries: Vec<&Entry> = batch.iter().filter(|entry| sink.wants(entry)).collect();
+        if !entries.is_empty() {
+            ship(sink, &entries, http_client, hostname).await;
+        }
+    }
+    batch.clear();
+}
+
+pub async fn run(
+    sinks: LogSinks,
+    mut logs: mpsc::Receiver<Entry>,
+    mut events: broadcast::Receiver<Event>,
+    http_client: reqwest::Client,
+) {
+    let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string());
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+    let mut batch = Vec::new();
+    loop {
+        tokio::select! {
+            entry = logs.recv() => match entry {
+                Some(entry) => batch.push(entry),
+                None => return,
+            },
+            event = events.recv() => match event {
+                Ok(event) => batch.push(Entry::from_event(event)),
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Log shipper fell behind, skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = ticker.tick() => {
+                flush(&sinks, &mut batch, &http_client, &hostname).await;
+                continue;
+            }
+        }
+        if batch.len() >= BATCH_SIZE {
+            flush(&sinks, &mut batch, &http_client, &hostname).await;
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::time::Duration;
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::MachineLogsQuery;
+use futures_util::{StreamExt, stream};
+use serde_json::json;
+
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::shutdown;
+use crate::slo::SloTracker;
+use crate::watch::{KEEPALIVE, event};
+
+/// A stream's place in Fly's log API.
+struct Tail {
+    client: FlyClient,
+    logs_url: String,
+    app: String,
+    machine_id: String,
+    follow: bool,
+    interval: Duration,
+    next_token: Option<String>,
+    polled: bool,
+    done: bool,
+    pending: VecDeque<web::Bytes>,
+}
+
+impl Tail {
+    async fn poll(&mut self) {
+        let result = self
+            .client
+            .machine_logs(
+                &self.logs_url,
+                &self.app,
+                &self.machine_id,
+                self.next_token.as_deref(),
+            )
+            .await;
+        self.polled = true;
+        match result {
+            Ok((lines, next_token)) => {
+                for line in &lines {
+                    self.pending.push_back(event("log", line));
+                }
+                self.next_token = next_token.or(self.next_token.take());
+            }
+            Err(e) => {
+                self.pending
+                    .push_back(event("error", &json!({ "error": e.to_string() })));
+            }
+        }
+        self.done = !self.follow;
+    }
+}
+
+fn respond(events: impl stream::Stream<Item = web::Bytes> + 'static) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(
+            events
+                .take_until(shutdown::draining())
+                .map(Ok::<_, Infallible>),
+        )
+}
+
+/// The machine's logs as Server-Sent Events, one `log` event per line. With `follow`, the
+/// stream stays open and Fly's log API is asked for new lines every
+/// `machines.logs_poll_interval_secs`.
+#[get("/v0/machines/logs")]
+async fn machine_logs(
+    req: HttpRequest,
+    query: web::Query<MachineLogsQuery>,
+    config: web::Data<Config>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let query = query.into_inner();
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        if let Err(e) = docker.get_machine(&query.app_name, &query.machine_id).await {
+            return AppError::not_found(e.to_string()).into_response();
+        }
+        let lines = docker
+            .logs(&query.machine_id, query.follow)
+            .map(|line| match line {
+                Ok(line) => event("log", &line),
+                Err(e) => event("error", &json!({ "error": e.to_string() })),
+            });
+        return respond(lines);
+    }
+
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    let tail = Tail {
+        client,
+        logs_url: config.machines.logs_api_url.clone(),
+        app: query.app_name,
+        machine_id: query.machine_id,
+        follow: query.follow,
+        interval: Duration::from_secs(config.machines.logs_poll_interval_secs.max(1)),
+        next_token: None,
+        polled: false,
+        done: false,
+        pending: VecDeque::new(),
+    };
+    respond(stream::unfold(tail, |mut tail| async move {
+        loop {
+            if let Some(bytes) = tail.pending.pop_front() {
+                return Some((bytes, tail));
+            }
+            if tail.done {
+                return None;
+            }
+            if tail.polled {
+                tokio::time::sleep(tail.interval).await;
+            }
+            tail.poll().await;
+            // Lets the stream notice a client that went away.
+            if tail.pending.is_empty() && !tail.done {
+                tail.pending.push_back(web::Bytes::from_static(KEEPALIVE));
+            }
+        }
+    }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(machine_logs);
+}
@@ -0,0 +1,238 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::Utc;
+use flyd::models::{AgentUsage, ByteUsage, CpuUsage, MachineUsage, MachineUsageQuery, UsageSource};
+use serde_json::Value;
+
+use crate::backend::Backend;
+use crate::config::{Config, MachineUsageConfig};
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::reachability;
+use crate::slo::SloTracker;
+
+/// Prints `/proc/stat`'s CPU line twice, `cpu_sample_ms` apart, then the memory lines of
+/// `/proc/meminfo` and the disk's use from `df`. Only `df` and `sleep` need to exist in
+/// the image; the rest is shell builtins.
+fn script(config: &MachineUsageConfig) -> String {
+    let path = format!("'{}'", config.disk_path.replace('\'', r"'\''"));
+    format!(
+        "read -r line < /proc/stat; echo \"$line\"; sleep {}; \
+         read -r line < /proc/stat; echo \"$line\"; \
+         while read -r key value _; do \
+           case $key in MemTotal:|MemAvailable:) echo \"$key $value\";; esac; \
+         done < /proc/meminfo; \
+         df -Pk {} | {{ read -r _; read -r _ _ used available _; echo \"disk $used $available\"; }}",
+        config.cpu_sample_ms as f64 / 1000.0,
+        path
+    )
+}
+
+fn numbers(fields: &[&str]) -> Option<Vec<u64>> {
+    fields.iter().map(|field| field.parse().ok()).collect()
+}
+
+/// The busy and total jiffies of a `cpu` line: user through steal, idle and iowait idle.
+fn cpu_times(fields: &[&str]) -> Option<(u64, u64)> {
+    let times = numbers(fields.get(..8)?)?;
+    let total: u64 = times.iter().sum();
+    Some((total - times[3] - times[4], total))
+}
+
+fn percent(used: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        used as f64 * 100.0 / total as f64
+    }
+}
+
+/// Reads what [`script`] printed.
+fn parse(output: &str) -> Result<AgentUsage, String> {
+    let mut cpu = Vec::new();
+    let (mut memory_total, mut memory_available, mut disk) = (None, None, None);
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.split_first() {
+            Some((&"cpu", times)) => cpu.extend(cpu_times(times)),
+            Some((&"MemTotal:", [kb])) => memory_total = kb.parse::<u64>().ok(),
+            Some((&"MemAvailable:", [kb])) => memory_available = kb.parse::<u64>().ok(),
+            Some((&"disk", [used, available])) => disk = numbers(&[used, available]),
+            _ => {}
+        }
+    }
+    let [(busy_before, total_before), (busy_after, total_after)] = cpu[..] else {
+        return Err("Couldn't read /proc/stat".to_string());
+    };
+    let (Some(memory_total), Some(memory_available)) = (memory_total, memory_available) else {
+        return Err("Couldn't read /proc/meminfo".to_string());
+    };
+    let Some([disk_used, disk_available]) = disk.as_deref() else {
+        return Err("Couldn't read the disk's use from df".to_string());
+    };
+    Ok(AgentUsage {
+        cpu_percent: percent(
+            busy_after.saturating_sub(busy_before),
+            total_after.saturating_sub(total_before),
+        ),
+        memory_used_bytes: memory_total.saturating_sub(memory_available) * 1024,
+        memory_total_bytes: memory_total * 1024,
+        disk_used_bytes: disk_used * 1024,
+        // What df counts as the disk's size includes blocks reserved for root.
+        disk_total_bytes: (disk_used + disk_available) * 1024,
+    })
+}
+
+fn started(machine: &Value, machine_id: &str) -> Result<(), HttpResponse> {
+    let state = machine["state"].as_str().unwrap_or("unknown");
+    if state != "started" {
+        return Err(AppError::conflict(format!(
+            "Machine {} is {}, not started",
+            machine_id, state
+        ))
+        .into_response());
+    }
+    Ok(())
+}
+
+async fn via_exec<B: Backend>(
+    backend: &B,
+    app_name: &str,
+    machine_id: &str,
+    config: &MachineUsageConfig,
+) -> Result<(Value, AgentUsage), HttpResponse> {
+    let machine = backend
+        .get_machine(app_name, machine_id)
+        .await
+        .map_err(|e| AppError::bad_gateway(e.to_string()).into_response())?;
+    started(&machine, machine_id)?;
+    let command = ["sh".to_string(), "-c".to_string(), script(config)];
+    let output = backend
+        .exec_machine(app_name, machine_id, &command, config.timeout_secs)
+        .await
+        .map_err(|e| AppError::bad_gateway(e.to_string()).into_response())?;
+    let failed = |e: String| {
+        AppError::bad_gateway(format!("Failed to read the machine's usage: {}", e)).into_response()
+    };
+    let stderr = output["stderr"].as_str().unwrap_or_default().trim();
+    if output["exit_code"].as_i64().is_some_and(|code| code != 0) {
+        return Err(failed(stderr.to_string()));
+    }
+    let usage = parse(output["stdout"].as_str().unwrap_or_default()).map_err(|e| {
+        if stderr.is_empty() {
+            failed(e)
+        } else {
+            failed(format!("{} ({})", e, stderr))
+        }
+    })?;
+    Ok((machine, usage))
+}
+
+async fn via_agent(
+    req: &HttpRequest,
+    query: &MachineUsageQuery,
+    machine_id: &str,
+    port: u16,
+    http_client: &reqwest::Client,
+    config: &MachineUsageConfig,
+    slo: web::Data<SloTracker>,
+) -> Result<(Value, AgentUsage), HttpResponse> {
+    let machine = reachability::fetch_machine(
+        req,
+        &query.app_name,
+        machine_id,
+        query.use_private_api,
+        http_client,
+        slo,
+    )
+    .await?;
+    started(&machine, machine_id)?;
+    let Some(ip) = machine["private_ip"]
+        .as_str()
+        .and_then(|address| address.parse::<IpAddr>().ok())
+    else {
+        return Err(
+            AppError::bad_gateway("Fly returned no private IP for the machine").into_response(),
+        );
+    };
+    let url = format!("http://{}{}", SocketAddr::new(ip, port), config.agent_path);
+    let usage = http_client
+        .get(&url)
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| format!("Failed to ask {}: {}", url, e));
+    let usage = match usage {
+        Ok(response) => response
+            .json::<AgentUsage>()
+            .await
+            .map_err(|e| format!("{} answered with no usage: {}", url, e)),
+        Err(e) => Err(e),
+    };
+    let usage = usage.map_err(|e| AppError::bad_gateway(e).into_response())?;
+    Ok((machine, usage))
+}
+
+/// What the machine is using right now: CPU over a short sample, memory and disk, each
+/// against what it has. Read through exec, or from an agent in the machine with
+/// `machine_usage.agent_port` set.
+#[get("/v0/machines/{id}/usage")]
+async fn machine_usage(
+    req: HttpRequest,
+    machine_id: web::Path<String>,
+    query: web::Query<MachineUsageQuery>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let config = &config.machine_usage;
+    let (source, result) = if let Some(port) = config.agent_port {
+        let result = via_agent(&req, &query, &machine_id, port, &http_client, config, slo).await;
+        (UsageSource::Agent, result)
+    } else if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        let result = via_exec(docker.get_ref(), &query.app_name, &machine_id, config).await;
+        (UsageSource::Exec, result)
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner());
+        let result = via_exec(&client, &query.app_name, &machine_id, config).await;
+        (UsageSource::Exec, result)
+    };
+    let (machine, usage) = match result {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+
+    HttpResponse::Ok().json(MachineUsage {
+        machine_id: machine_id.into_inner(),
+        source,
+        cpu: CpuUsage {
+            percent: usage.cpu_percent.clamp(0.0, 100.0),
+            cpus: machine["config"]["guest"]["cpus"].as_u64(),
+        },
+        memory: ByteUsage {
+            used_bytes: usage.memory_used_bytes,
+            total_bytes: usage.memory_total_bytes,
+            percent: percent(usage.memory_used_bytes, usage.memory_total_bytes),
+        },
+        disk: ByteUsage {
+            used_bytes: usage.disk_used_bytes,
+            total_bytes: usage.disk_total_bytes,
+            percent: percent(usage.disk_used_bytes, usage.disk_total_bytes),
+        },
+        sampled_at: Utc::now(),
+    })
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(machine_usage);
+}
@@ -1,99 +1,532 @@
+mod annotations;
+mod api_keys;
+mod app_info;
+mod apps;
+mod artifacts;
+mod audit;
+mod auth;
+mod autoscale;
+mod backend;
+mod backoff;
+mod batch;
+mod breaker;
+mod budgets;
+mod cache;
+mod callbacks;
+mod capacity;
+mod client_ip;
+mod commands;
+mod concurrency;
+mod config;
+mod consistency;
+mod cordon;
+mod defaults;
+mod deploy_breaker;
+mod deploys;
+mod diagnostics;
+mod docker;
+mod drain;
+mod environments;
+mod errors;
+mod evacuations;
+mod event_bus;
+mod events;
+mod exec;
+mod features;
+mod fleets;
+mod fly_client;
+mod freezes;
+mod grants;
+mod graph;
+mod health;
+mod hedge;
+mod idempotency;
+mod impact;
+mod inline_wait;
+mod job_reports;
+mod jobs;
+mod keep_warm;
+mod leases;
+mod log_sinks;
+mod logs;
+mod machine_usage;
+mod merge;
+mod metadata;
+mod metrics;
+mod migrations;
+mod multi_app;
+mod namespaces;
+mod notify;
+mod object_store;
+mod openapi;
+mod pipeline;
+mod placement;
+mod plugins;
+mod pools;
+mod port_forward;
+mod preemptible;
+mod preflight;
+mod pricing;
+mod probes;
+mod provenance;
+mod query;
+mod rate_limit;
+mod reachability;
+mod regions;
+mod registry;
+mod remediation;
+mod reports;
+mod response_headers;
+mod rolling;
+mod scans;
+mod schedules;
+mod schemas;
+mod scripts;
+mod secret_drift;
+mod secret_refs;
+mod secrets;
+mod sessions;
+mod shutdown;
+mod siem;
+mod signatures;
+mod slo;
+mod snapshot_catalog;
+mod snapshots;
+mod store;
+mod telemetry;
+mod templates;
+mod tls;
+mod token_health;
+mod trash;
+mod usage;
+mod validation;
+mod validation_webhook;
+mod verify;
+mod version;
+mod views;
+mod virtual_hosts;
+mod volumes;
+mod warm_up;
+mod watch;
+mod webhooks;
+mod write_queue;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::guard::GuardContext;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{ETAG, ETag, EntityTag, Header, IfMatch};
 use actix_web::{
-    App, HttpRequest, HttpResponse, HttpServer, Responder, get, middleware, post, web,
+    App, HttpRequest, HttpResponse, HttpServer, Responder, get, middleware, patch, post, route, web,
+};
+use flyd::models::{
+    ListMachinesRequest, Machine, MachineDryRun, MachineLifecycleRequest, MachineRequest,
+    NewMachineRequest, UpdateMachineRequest, WaitMachineQuery,
 };
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderValue};
-use serde::{Deserialize, Serialize};
-
-#[derive(Deserialize)]
-struct NewMachineRequest {
-    app_name: String,
-    #[serde(default)]
-    use_private_api: bool,
-    #[serde(flatten)]
-    config: MachineConfig,
-}
 
-#[derive(Deserialize, Serialize)]
-struct MachineConfig {
-    name: Option<String>,
-    region: Option<String>,
-    #[serde(flatten)]
-    other: serde_json::Value,
-}
+use crate::api_keys::ApiKeys;
+use crate::app_info::AppInfoCache;
+use crate::audit::AuditLog;
+use crate::auth::Authenticator;
+use crate::autoscale::Autoscaler;
+use crate::backend::Backend;
+use crate::backoff::RestartBackoff;
+use crate::budgets::Budgets;
+use crate::cache::ResponseCache;
+use crate::callbacks::MachineStates;
+use crate::capacity::CapacityMap;
+use crate::client_ip::TrustedProxies;
+use crate::concurrency::AdaptiveLimit;
+use crate::config::{BackendKind, Config};
+use crate::consistency::Consistency;
+use crate::deploy_breaker::DeployBreaker;
+use crate::diagnostics::{ConnectTiming, SlowRequests};
+use crate::docker::DockerBackend;
+use crate::drain::Drainer;
+use crate::environments::Environments;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::exec::ExecPolicies;
+use crate::features::Features;
+use crate::fly_client::{FlyClient, FlyError, WithChecks};
+use crate::grants::Grants;
+use crate::hedge::Hedger;
+use crate::idempotency::{IDEMPOTENCY_KEY_HEADER, IdempotencyCache};
+use crate::inline_wait::WaitFor;
+use crate::jobs::Jobs;
+use crate::keep_warm::KeepWarm;
+use crate::log_sinks::LogSinks;
+use crate::namespaces::Namespaces;
+use crate::object_store::ObjectStore;
+use crate::pipeline::Pipeline;
+use crate::placement::Placer;
+use crate::plugins::Plugins;
+use crate::pools::WarmPools;
+use crate::port_forward::PortForwards;
+use crate::probes::Probes;
+use crate::rate_limit::RateLimiter;
+use crate::response_headers::ResponseHeaders;
+use crate::scans::ScanGate;
+use crate::schemas::Schemas;
+use crate::scripts::ScriptLibrary;
+use crate::secret_refs::{Scoped, SecretResolver};
+use crate::sessions::SessionRecorder;
+use crate::siem::SiemExporters;
+use crate::signatures::ImageVerifier;
+use crate::slo::{Scope, SloTracker};
+use crate::store::Store;
+use crate::telemetry::HttpMetrics;
+use crate::token_health::TokenMonitor;
+use crate::verify::Verifier;
+use crate::version::UpdateChecker;
+use crate::views::{Page, View};
+use crate::virtual_hosts::VirtualHosts;
+use crate::write_queue::WriteQueue;
 
-#[derive(Deserialize)]
-struct ListMachinesRequest {
-    app_name: String,
-    #[serde(default)]
-    use_private_api: bool,
-    #[serde(default)]
-    include_deleted: bool,
-    region: Option<String>,
-}
+const UPSTREAM_HOST_HEADER: &str = "x-flyd-upstream-host";
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+/// Nearest regions tried for a machine placed near its caller, after any fallbacks of the
+/// nearest.
+const NEARBY_REGIONS: usize = 3;
 
 fn prepare_request(
     req: &HttpRequest,
     use_private: bool,
 ) -> Result<(reqwest::header::HeaderMap, String), HttpResponse> {
-    let auth_header = match req.headers().get(actix_web::http::header::AUTHORIZATION) {
-        Some(header) => header,
-        None => return Err(HttpResponse::Unauthorized().body("Authorization header required")),
-    };
+    let fly_authorization = auth::require_role(req, None)?.fly_authorization;
 
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
         AUTHORIZATION,
-        HeaderValue::from_bytes(auth_header.as_bytes())
-            .map_err(|e| HttpResponse::InternalServerError().body(e.to_string()))?,
+        HeaderValue::from_bytes(fly_authorization.as_bytes())
+            .map_err(|e| AppError::internal(e.to_string()).into_response())?,
     );
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
-    let api_hostname = if use_private {
-        "http://fly-api.internal:4280"
-    } else {
-        "https://api.machines.dev"
-    };
+    if let Some(override_host) = req.headers().get(UPSTREAM_HOST_HEADER) {
+        let override_host = override_host
+            .to_str()
+            .map_err(|e| AppError::bad_request(e.to_string()).into_response())?
+            .trim_end_matches('/');
 
+        let allowed = req.app_data::<web::Data<Config>>().is_some_and(|config| {
+            config
+                .upstream
+                .allowed_hosts
+                .iter()
+                .any(|host| host.trim_end_matches('/') == override_host)
+        });
+        if !allowed {
+            return Err(AppError::forbidden(format!(
+                "Upstream host {} is not allowed",
+                override_host
+            ))
+            .into_response());
+        }
+
+        response_headers::record_upstream(req, override_host);
+        return Ok((headers, override_host.to_string()));
+    }
+
+    let api_hostname = virtual_hosts::of(req)
+        .and_then(|host| host.api_hostname(use_private))
+        .unwrap_or_else(|| fly_client::api_hostname(use_private));
+    response_headers::record_upstream(req, api_hostname);
     Ok((headers, api_hostname.to_string()))
 }
 
+fn record_upstream(
+    slo: &SloTracker,
+    method: &str,
+    started: Instant,
+    response: &Result<reqwest::Response, FlyError>,
+) {
+    diagnostics::upstream_headers(started);
+    let failed = response
+        .as_ref()
+        .map_or(true, |response| response.status().is_server_error());
+    slo.record(
+        Scope::Upstream,
+        format!("{} /v1/apps/{{app}}/machines", method),
+        started.elapsed(),
+        failed,
+    );
+}
+
+/// With `?dry_run=true`, the machine is checked and placed as for real, but instead of
+/// being created, the payload Fly would get comes back with its estimated monthly cost.
 #[post("/v0/machines/new")]
 async fn create_machine(
     req: HttpRequest,
     body: web::Json<NewMachineRequest>,
     http_client: web::Data<reqwest::Client>,
+    flyd_config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+    secrets: web::Data<SecretResolver>,
+    capacity: web::Data<CapacityMap>,
 ) -> impl Responder {
+    let wait_for = match WaitFor::from_request(&req) {
+        Ok(wait_for) => wait_for,
+        Err(response) => return response,
+    };
     let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
         Ok(result) => result,
         Err(response) => return response,
     };
 
-    let config = serde_json::to_value(&body.config).unwrap_or_default();
+    let mut config = serde_json::to_value(&body.config).unwrap_or_default();
+    if let Err(response) = templates::apply(&req, &mut config).await {
+        return response;
+    }
+    defaults::apply(&flyd_config.app_defaults, &body.app_name, &mut config);
+    if let Some(recorded) = &body.provenance {
+        provenance::set(&mut config["config"], recorded);
+    }
+    if let Err(e) = provenance::validate(&config["config"]) {
+        return AppError::unprocessable(e).into_response();
+    }
+    if let Err(e) = validation::machine_config(&config["config"]) {
+        return e.into_response();
+    }
+    if let Some(namespace) = namespaces::of(&req) {
+        namespace.fill(&mut config);
+        let client = FlyClient::new(
+            http_client.get_ref().clone(),
+            headers.clone(),
+            api_hostname.clone(),
+        );
+        let checked = match namespace.check(&body.app_name, &config) {
+            Ok(()) => {
+                namespace
+                    .check_machine_quota(
+                        &client,
+                        req.app_data::<web::Data<AdaptiveLimit>>()
+                            .map(|limit| limit.get_ref()),
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = checked {
+            return AppError::unprocessable(e).into_response();
+        }
+    }
+    if let Some(signatures) = req.app_data::<web::Data<ImageVerifier>>()
+        && let Err(e) = signatures
+            .check(&body.app_name, &mut config["config"])
+            .await
+    {
+        return AppError::unprocessable(e).into_response();
+    }
+    if let Some(scans) = req.app_data::<web::Data<ScanGate>>()
+        && let Err(e) = scans.check(&body.app_name, &config["config"]).await
+    {
+        return AppError::unprocessable(e).into_response();
+    }
+    if let Some(budgets) = req.app_data::<web::Data<Budgets>>()
+        && let Err(e) = budgets.check(&body.app_name, &config["config"]).await
+    {
+        return AppError::unprocessable(e)
+            .with_code("budget_exceeded")
+            .into_response();
+    }
+    let resolved = match secrets
+        .scoped(&req)
+        .resolve(&body.app_name, &mut config)
+        .await
+    {
+        Ok(resolved) => resolved,
+        Err(e) => return AppError::unprocessable(e).into_response(),
+    };
 
     let url = format!("{}/v1/apps/{}/machines", api_hostname, body.app_name);
 
-    let response = match http_client
-        .post(&url)
-        .headers(headers)
-        .json(&config)
-        .send()
-        .await
+    // `auto` is no region at all. Placement rules pick one, unless the caller said where
+    // they are.
+    if config["region"] == "auto" {
+        config["region"] = serde_json::Value::Null;
+    }
+    if config["region"].is_null()
+        && body.near.is_none()
+        && let Some(placer) = req.app_data::<web::Data<Placer>>()
     {
-        Ok(response) => response,
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(format!("API request failed: {}", e));
+        let client = FlyClient::new(
+            http_client.get_ref().clone(),
+            headers.clone(),
+            api_hostname.clone(),
+        );
+        if let Err(e) = placer
+            .place(&client, &capacity, &body.app_name, &mut config)
+            .await
+        {
+            return AppError::bad_gateway(e).into_response();
+        }
+    }
+
+    // A region out of capacity for this size falls through to the next in its policy.
+    let size = capacity::size(&config);
+    let requested = config["region"].as_str().map(str::to_string);
+    if let Some(region) = &requested
+        && capacity.is_blocked(&body.app_name, region).await
+    {
+        return AppError::conflict(format!(
+            "New machines may not be placed in {} for {}",
+            region, body.app_name
+        ))
+        .into_response();
+    }
+    // Without a region, a machine goes near the caller: where they said they are, or for
+    // an ephemeral one, where they're connecting from.
+    let mut nearby = Vec::new();
+    if requested.is_none() {
+        let location = match body.near.as_deref().map(regions::locate) {
+            Some(Ok(location)) => Some(location),
+            Some(Err(e)) => return AppError::bad_request(e).into_response(),
+            None if config["config"]["auto_destroy"].as_bool() == Some(true) => {
+                regions::caller_location(&req)
+            }
+            None => None,
+        };
+        for region in location
+            .map(|location| regions::nearest(location, &flyd_config.capacity.nearest_regions))
+            .unwrap_or_default()
+        {
+            if !capacity.is_blocked(&body.app_name, &region).await {
+                nearby.push(region);
+            }
+        }
+        // Stable, so regions rejecting machines lately only lose to ones equally close.
+        nearby.sort_by_key(|region| capacity.is_rejecting(region));
+        nearby.truncate(NEARBY_REGIONS);
+    }
+    let requested = requested.or_else(|| nearby.first().cloned());
+    let mut candidates = capacity
+        .regions(&body.app_name, requested.as_deref(), &size)
+        .await;
+    for region in nearby.into_iter().skip(1) {
+        if !candidates.contains(&Some(region.clone())) {
+            candidates.push(Some(region));
+        }
+    }
+    let mut regions = candidates.into_iter().peekable();
+    if batch::wants_dry_run(&req) {
+        if let Some(Some(region)) = regions.peek() {
+            config["region"] = serde_json::json!(region);
+        }
+        let estimated_monthly_cost =
+            pricing::machine_monthly_cost_in(&config["config"]["guest"], config["region"].as_str());
+        SecretResolver::mask(&mut config, &resolved);
+        return HttpResponse::Ok().json(MachineDryRun {
+            payload: config,
+            estimated_monthly_cost,
+        });
+    }
+    // Retries of one attempt reuse its key; each region tried after the first is a
+    // different machine, so it gets its own.
+    let key = fly_client::idempotency_key(Some(&req));
+    let limit = req
+        .app_data::<web::Data<AdaptiveLimit>>()
+        .map(|limit| limit.get_ref());
+    let mut attempts = 0;
+    let (status, body_text) = loop {
+        let region = regions.next().flatten();
+        if let Some(region) = &region {
+            config["region"] = serde_json::json!(region);
+        }
+        let attempt_key = match attempts {
+            0 => key.clone(),
+            n => format!("{}-{}", key, n),
+        };
+        attempts += 1;
+
+        let started = Instant::now();
+        let response = match http_client
+            .post(&url)
+            .headers(headers.clone())
+            .header(IDEMPOTENCY_KEY_HEADER, attempt_key)
+            .json(&config)
+            .build()
+        {
+            Ok(request) => fly_client::execute(&http_client, request, None, limit).await,
+            Err(e) => Err(e.into()),
+        };
+        record_upstream(&slo, "POST", started, &response);
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => return AppError::from(&e).into_response(),
+        };
+        let status = response.status();
+        let text = match response.text().await {
+            Ok(text) => text,
+            Err(e) => {
+                return AppError::internal(format!("Failed to read response body: {}", e))
+                    .into_response();
+            }
+        };
+
+        if !capacity::is_capacity_error(status, &text) {
+            if status.is_success() {
+                capacity.placed(
+                    &body.app_name,
+                    requested.as_deref(),
+                    region.as_deref(),
+                    &size,
+                );
+            }
+            break (status, text);
+        }
+        if let Some(region) = &region {
+            capacity.reject(region, &size, &text);
+        }
+        if regions.peek().is_none() {
+            break (status, text);
         }
     };
 
-    let json = match response.json::<serde_json::Value>().await {
-        Ok(json) => json,
+    if !status.is_success() {
+        return AppError::upstream(status.as_u16(), &body_text).into_response();
+    }
+    let machine = serde_json::from_str::<serde_json::Value>(&body_text).and_then(|machine| {
+        if let Some(schemas) = req.app_data::<web::Data<Schemas>>() {
+            schemas.check_upstream(
+                "POST /v1/apps/{app}/machines",
+                "Machine",
+                std::slice::from_ref(&machine),
+            );
+        }
+        serde_json::from_value::<Machine>(machine)
+    });
+    let mut json = match machine {
+        Ok(machine) => serde_json::json!(machine),
         Err(e) => {
-            return HttpResponse::InternalServerError()
-                .body(format!("Failed to read response body: {}", e));
+            return AppError::internal(format!("Failed to read response body: {}", e))
+                .into_response();
         }
     };
+    SecretResolver::mask(&mut json, &resolved);
+    if let Some(store) = req.app_data::<web::Data<Store>>() {
+        provenance::index(store, &body.app_name, &json).await;
+    }
 
-    HttpResponse::Ok().json(json)
+    match (&wait_for, json["id"].as_str()) {
+        (Some(wait_for), Some(machine_id)) => {
+            let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+                .with_slo(slo.into_inner());
+            wait_for
+                .wait(
+                    &req,
+                    &client,
+                    &body.app_name,
+                    machine_id,
+                    json["instance_id"].as_str(),
+                )
+                .await
+                .json(json)
+        }
+        _ => HttpResponse::Ok().json(json),
+    }
 }
 
 #[get("/v0/machines/list")]
@@ -101,7 +534,18 @@ async fn list_machines(
     req: HttpRequest,
     query: web::Query<ListMachinesRequest>,
     http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+    hedger: Option<web::Data<Hedger>>,
 ) -> impl Responder {
+    let view = match View::from_query(&req, &query).await {
+        Ok(view) => view,
+        Err(response) => return response,
+    };
+    let page = match Page::from_query(&query) {
+        Ok(page) => page,
+        Err(e) => return AppError::bad_request(e).into_response(),
+    };
     let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
         Ok(result) => result,
         Err(response) => return response,
@@ -113,7 +557,7 @@ async fn list_machines(
     )) {
         Ok(url) => url,
         Err(e) => {
-            return HttpResponse::InternalServerError().body(format!("Failed to parse URL: {}", e));
+            return AppError::internal(format!("Failed to parse URL: {}", e)).into_response();
         }
     };
 
@@ -128,21 +572,643 @@ async fn list_machines(
         }
     }
 
-    let response = match http_client.get(url).headers(headers).send().await {
+    let cache = fly_client::cache();
+    let cache_key = ResponseCache::key(&req, &query.app_name, url.as_str());
+    // The whole list is cached, and filtered for each caller.
+    let respond = |mut machines: serde_json::Value, age| {
+        match &page {
+            Some(page) => view.apply_paged(&mut machines, page),
+            None => view.apply(&mut machines),
+        }
+        cache.respond(&machines, age)
+    };
+    if let Some((machines, age)) = cache.lookup_value(&req, &cache_key) {
+        return respond(machines, age);
+    }
+
+    let request = match http_client.get(url).headers(headers).build() {
+        Ok(request) => request,
+        Err(e) => {
+            return AppError::internal(format!("API request failed: {}", e)).into_response();
+        }
+    };
+    let started = Instant::now();
+    let response = fly_client::execute(
+        &http_client,
+        request,
+        hedger.as_ref().map(|hedger| hedger.get_ref()),
+        req.app_data::<web::Data<AdaptiveLimit>>()
+            .map(|limit| limit.get_ref()),
+    )
+    .await;
+    record_upstream(&slo, "GET", started, &response);
+    let response = match response {
         Ok(response) => response,
+        Err(e) => return AppError::from(&e).into_response(),
+    };
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return AppError::upstream(status.as_u16(), &body).into_response();
+    }
+
+    let machines = response
+        .json::<Vec<serde_json::Value>>()
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|machines| {
+            if let Some(schemas) = req.app_data::<web::Data<Schemas>>() {
+                schemas.check_upstream("GET /v1/apps/{app}/machines", "Machine", &machines);
+            }
+            serde_json::from_value::<Vec<Machine>>(serde_json::Value::Array(machines))
+                .map_err(|e| e.to_string())
+        });
+    let mut machines = match machines {
+        Ok(machines) => serde_json::json!(machines),
         Err(e) => {
-            return HttpResponse::InternalServerError().body(format!("API request failed: {}", e));
+            return AppError::internal(format!("Failed to read response body: {}", e))
+                .into_response();
         }
     };
+    health::annotate(&mut machines, &config.health);
+    if let Some(store) = req.app_data::<web::Data<Store>>() {
+        annotations::merge(store, &query.app_name, &mut machines).await;
+    }
+
+    let response = respond(machines.clone(), Duration::ZERO);
+    cache.insert(cache_key, &query.app_name, machines);
+    response
+}
 
-    let machines = match response.json::<serde_json::Value>().await {
-        Ok(machines) => machines,
+fn machine_etag(machine: &serde_json::Value) -> Option<EntityTag> {
+    machine["instance_id"]
+        .as_str()
+        .map(|version| EntityTag::new_strong(version.to_string()))
+}
+
+#[get("/v0/machines/get")]
+async fn get_machine(
+    req: HttpRequest,
+    query: web::Query<MachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+    hedger: Option<web::Data<Hedger>>,
+) -> impl Responder {
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let cache = fly_client::cache();
+    let cache_key = ResponseCache::key(
+        &req,
+        &query.app_name,
+        &format!(
+            "{}/v1/apps/{}/machines/{}",
+            api_hostname, query.app_name, query.machine_id
+        ),
+    );
+    let with_etag = |mut response: HttpResponse, machine: &serde_json::Value| {
+        if let Some(etag) = machine_etag(machine)
+            && let Ok(value) = etag.to_string().parse()
+        {
+            response.headers_mut().insert(ETAG, value);
+        }
+        response
+    };
+    if let Some((machine, age)) = cache.lookup_value(&req, &cache_key) {
+        return with_etag(cache.respond(&machine, age), &machine);
+    }
+
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_hedger(hedger.map(web::Data::into_inner));
+
+    match client.get_machine(&query.app_name, &query.machine_id).await {
+        Ok(machine) => {
+            let mut machine = match serde_json::from_value::<Machine>(machine) {
+                Ok(machine) => serde_json::json!(machine),
+                Err(e) => {
+                    return AppError::internal(format!("Failed to read response body: {}", e))
+                        .into_response();
+                }
+            };
+            health::annotate(&mut machine, &config.health);
+            if let Some(store) = req.app_data::<web::Data<Store>>() {
+                annotations::merge(store, &query.app_name, &mut machine).await;
+            }
+            let response = with_etag(cache.respond(&machine, Duration::ZERO), &machine);
+            cache.insert(cache_key, &query.app_name, machine);
+            response
+        }
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+/// States `/v0/machines/wait` can wait for.
+pub const WAIT_STATES: &[&str] = &["started", "stopped", "suspended", "destroyed"];
+
+/// Blocks until the machine reaches `state`, e.g. to create a machine and then wait for
+/// it to start without polling. Called async, it answers 202 with a job instead.
+#[get("/v0/machines/wait")]
+async fn wait_machine(
+    req: HttpRequest,
+    query: web::Query<WaitMachineQuery>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let state = query.state.as_deref().unwrap_or("started");
+    if !WAIT_STATES.contains(&state) {
+        return AppError::bad_request(format!(
+            "Can't wait for {}, only for {}",
+            state,
+            WAIT_STATES.join(", ")
+        ))
+        .into_response();
+    }
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(60));
+
+    if let Some(jobs) = req.app_data::<web::Data<Jobs>>()
+        && jobs::wants_async(&req)
+    {
+        let state = state.to_string();
+        let query = query.into_inner();
+        let (app_name, group) = (query.app_name.clone(), format!("wait:{}", query.machine_id));
+        let work: jobs::Work = Box::new(move || {
+            let (client, query, state) = (client.clone(), query.clone(), state.clone());
+            Box::pin(async move {
+                client
+                    .wait_for_state(
+                        &query.app_name,
+                        &query.machine_id,
+                        &state,
+                        query.instance_id.as_deref(),
+                        timeout,
+                    )
+                    .await
+                    .map_err(|e| e.to_string())
+            })
+        });
+        let job = Jobs::submit_once(
+            jobs,
+            "wait",
+            Some(&app_name),
+            group,
+            jobs::submitter(&req),
+            work,
+        );
+        return jobs::accepted(&job);
+    }
+
+    match client
+        .wait_for_state(
+            &query.app_name,
+            &query.machine_id,
+            state,
+            query.instance_id.as_deref(),
+            timeout,
+        )
+        .await
+    {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+/// Signals the Machines API accepts.
+const SIGNALS: &[&str] = &[
+    "SIGABRT", "SIGALRM", "SIGFPE", "SIGHUP", "SIGILL", "SIGINT", "SIGKILL", "SIGPIPE", "SIGQUIT",
+    "SIGSEGV", "SIGTERM", "SIGTRAP", "SIGUSR1", "SIGUSR2",
+];
+
+async fn lifecycle(
+    req: HttpRequest,
+    body: &MachineLifecycleRequest,
+    action: &str,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> HttpResponse {
+    let signal = body.signal.as_deref().map(str::to_uppercase);
+    if let Some(signal) = &signal
+        && !SIGNALS.contains(&signal.as_str())
+    {
+        return AppError::bad_request(format!("Unknown signal {}", signal)).into_response();
+    }
+    let timeout = body.timeout_secs.map(|secs| format!("{}s", secs));
+
+    let (query, upstream_body) = match action {
+        "stop" => {
+            let mut stop = serde_json::json!({});
+            if let Some(signal) = &signal {
+                stop["signal"] = serde_json::json!(signal);
+            }
+            if let Some(timeout) = &timeout {
+                stop["timeout"] = serde_json::json!(timeout);
+            }
+            (Vec::new(), Some(stop))
+        }
+        "restart" => {
+            let mut query = Vec::new();
+            if let Some(signal) = &signal {
+                query.push(("signal", signal.clone()));
+            }
+            if let Some(timeout) = &timeout {
+                query.push(("timeout", timeout.clone()));
+            }
+            (query, None)
+        }
+        "signal" => match &signal {
+            Some(signal) => (Vec::new(), Some(serde_json::json!({ "signal": signal }))),
+            None => return AppError::bad_request("signal is required").into_response(),
+        },
+        _ => (Vec::new(), None),
+    };
+    // Only start and restart end in a state worth waiting for.
+    let wait_for = match WaitFor::from_request(&req) {
+        Ok(wait_for) if matches!(action, "start" | "restart") => wait_for,
+        Ok(_) => None,
+        Err(response) => return response,
+    };
+
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    match client
+        .machine_action(
+            &body.app_name,
+            &body.machine_id,
+            action,
+            &query,
+            upstream_body.as_ref(),
+        )
+        .await
+    {
+        Ok(response) => match &wait_for {
+            Some(wait_for) => wait_for
+                .wait(&req, &client, &body.app_name, &body.machine_id, None)
+                .await
+                .json(response),
+            None => HttpResponse::Ok().json(response),
+        },
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+#[post("/v0/machines/start")]
+async fn start_machine(
+    req: HttpRequest,
+    body: web::Json<MachineLifecycleRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    lifecycle(req, &body, "start", http_client, slo).await
+}
+
+#[post("/v0/machines/stop")]
+async fn stop_machine(
+    req: HttpRequest,
+    body: web::Json<MachineLifecycleRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    lifecycle(req, &body, "stop", http_client, slo).await
+}
+
+#[post("/v0/machines/restart")]
+async fn restart_machine(
+    req: HttpRequest,
+    body: web::Json<MachineLifecycleRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    lifecycle(req, &body, "restart", http_client, slo).await
+}
+
+#[post("/v0/machines/signal")]
+async fn signal_machine(
+    req: HttpRequest,
+    body: web::Json<MachineLifecycleRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    lifecycle(req, &body, "signal", http_client, slo).await
+}
+
+#[post("/v0/machines/update")]
+async fn update_machine(
+    req: HttpRequest,
+    body: web::Json<UpdateMachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let change = if body.merge {
+        ConfigChange::MergePatch(body.config.clone())
+    } else {
+        ConfigChange::Replace(body.config.clone())
+    };
+    update(req, &body, change, http_client, config, slo).await
+}
+
+/// Like `update_machine`, but `config` is a JSON Merge Patch applied to the machine's
+/// current config: only the fields sent change, and `null` removes a field.
+#[patch("/v0/machines/update")]
+async fn patch_machine(
+    req: HttpRequest,
+    body: web::Json<UpdateMachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let change = ConfigChange::MergePatch(body.config.clone());
+    update(req, &body, change, http_client, config, slo).await
+}
+
+fn is_json_patch(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(JSON_PATCH_CONTENT_TYPE))
+}
+
+/// Either update method with an `application/json-patch+json` body: a JSON Patch
+/// (RFC 6902) applied to the machine's current config. The machine is named in the query.
+#[route(
+    "/v0/machines/update",
+    method = "POST",
+    method = "PATCH",
+    guard = "is_json_patch"
+)]
+async fn json_patch_machine(
+    req: HttpRequest,
+    query: web::Query<MachineRequest>,
+    body: web::Bytes,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let patch: json_patch::Patch = match serde_json::from_slice(&body) {
+        Ok(patch) => patch,
         Err(e) => {
-            return HttpResponse::InternalServerError()
-                .body(format!("Failed to read response body: {}", e));
+            return AppError::bad_request(format!("Invalid JSON Patch: {}", e)).into_response();
         }
     };
-    HttpResponse::Ok().json(machines)
+    let query = query.into_inner();
+    let body = UpdateMachineRequest {
+        app_name: query.app_name,
+        machine_id: query.machine_id,
+        use_private_api: query.use_private_api,
+        region: None,
+        config: serde_json::Value::Null,
+        merge: false,
+    };
+    update(
+        req,
+        &body,
+        ConfigChange::JsonPatch(patch),
+        http_client,
+        config,
+        slo,
+    )
+    .await
+}
+
+/// How an update's config relates to the machine's current one.
+enum ConfigChange {
+    Replace(serde_json::Value),
+    MergePatch(serde_json::Value),
+    JsonPatch(json_patch::Patch),
+}
+
+async fn update(
+    req: HttpRequest,
+    body: &UpdateMachineRequest,
+    change: ConfigChange,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> HttpResponse {
+    let wait_for = match WaitFor::from_request(&req) {
+        Ok(wait_for) => wait_for,
+        Err(response) => return response,
+    };
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_request_checks(&req);
+
+    let if_match = match IfMatch::parse(&req) {
+        Ok(if_match) => if_match,
+        Err(e) => return AppError::bad_request(format!("Invalid If-Match: {}", e)).into_response(),
+    };
+    if matches!(if_match, IfMatch::Items(ref tags) if tags.is_empty())
+        && config.machines.require_if_match
+    {
+        return AppError::new(
+            StatusCode::PRECONDITION_REQUIRED,
+            "If-Match with the machine's ETag is required for updates",
+        )
+        .into_response();
+    }
+
+    let secrets = req
+        .app_data::<web::Data<SecretResolver>>()
+        .map(|secrets| secrets.scoped(&req));
+    let store = req.app_data::<web::Data<Store>>();
+    let updated = match fly_client::held_lease(&req) {
+        Some(nonce) => {
+            update_under_lease(
+                &client,
+                body,
+                change,
+                &if_match,
+                &nonce,
+                secrets.as_ref(),
+                store,
+            )
+            .await
+        }
+        None => {
+            update_leased(
+                &client,
+                body,
+                change,
+                &if_match,
+                &config,
+                secrets.as_ref(),
+                store,
+            )
+            .await
+        }
+    };
+    let (machine, etag) = match updated {
+        Ok(updated) => updated,
+        Err(response) => return response,
+    };
+    // Waits after the lease is released, so others can read the machine meanwhile.
+    let mut response = match &wait_for {
+        Some(wait_for) => {
+            wait_for
+                .wait(
+                    &req,
+                    &client,
+                    &body.app_name,
+                    &body.machine_id,
+                    machine["instance_id"].as_str(),
+                )
+                .await
+        }
+        None => HttpResponse::Ok(),
+    };
+    if let Some(etag) = etag {
+        response.insert_header(ETag(etag));
+    }
+    response.json(machine)
+}
+
+/// The update in a lease of its own.
+async fn update_leased(
+    client: &FlyClient,
+    body: &UpdateMachineRequest,
+    change: ConfigChange,
+    if_match: &IfMatch,
+    config: &Config,
+    secrets: Option<&Scoped<'_>>,
+    store: Option<&web::Data<Store>>,
+) -> Result<(serde_json::Value, Option<EntityTag>), HttpResponse> {
+    // Hold a lease for the read-compare-write so a concurrent writer can't slip in
+    // between our version check and the update.
+    let nonce = match client
+        .acquire_lease(
+            &body.app_name,
+            &body.machine_id,
+            config.machines.update_lease_ttl_secs,
+        )
+        .await
+    {
+        Ok(nonce) => nonce,
+        Err(e) => return Err(AppError::from(&e).into_response()),
+    };
+    consistency::leased(
+        store,
+        &body.app_name,
+        &body.machine_id,
+        &nonce,
+        config.machines.update_lease_ttl_secs,
+    )
+    .await;
+
+    let updated = update_under_lease(client, body, change, if_match, &nonce, secrets, store).await;
+
+    match client
+        .release_lease(&body.app_name, &body.machine_id, &nonce)
+        .await
+    {
+        Ok(()) => consistency::released(store, &body.app_name, &body.machine_id).await,
+        Err(e) => log::warn!(
+            "Failed to release lease on machine {}: {}",
+            body.machine_id,
+            e
+        ),
+    }
+
+    updated
+}
+
+/// The updated machine, its secrets masked, and its ETag.
+async fn update_under_lease(
+    client: &FlyClient,
+    body: &UpdateMachineRequest,
+    change: ConfigChange,
+    if_match: &IfMatch,
+    nonce: &str,
+    secrets: Option<&Scoped<'_>>,
+    store: Option<&web::Data<Store>>,
+) -> Result<(serde_json::Value, Option<EntityTag>), HttpResponse> {
+    let patching = !matches!(change, ConfigChange::Replace(_));
+    let mut current = None;
+    if patching || matches!(if_match, IfMatch::Items(tags) if !tags.is_empty()) {
+        let machine = match client.get_machine(&body.app_name, &body.machine_id).await {
+            Ok(machine) => machine,
+            Err(e) => return Err(AppError::from(&e).into_response()),
+        };
+        if let IfMatch::Items(tags) = if_match
+            && !tags.is_empty()
+        {
+            let up_to_date = machine_etag(&machine)
+                .is_some_and(|etag| tags.iter().any(|tag| tag.strong_eq(&etag)));
+            if !up_to_date {
+                return Err(AppError::new(
+                    StatusCode::PRECONDITION_FAILED,
+                    "Machine was modified since the supplied ETag was read",
+                )
+                .into_response());
+            }
+        }
+        current = Some(machine["config"].clone());
+    }
+
+    let config = match (change, current) {
+        (ConfigChange::Replace(config), _) => config,
+        (ConfigChange::MergePatch(patch), Some(mut config)) => {
+            merge::merge_patch(&mut config, &patch);
+            config
+        }
+        (ConfigChange::JsonPatch(patch), Some(mut config)) => {
+            if let Err(e) = json_patch::patch(&mut config, &patch) {
+                return Err(
+                    AppError::unprocessable(format!("Failed to apply JSON Patch: {}", e))
+                        .into_response(),
+                );
+            }
+            config
+        }
+        (_, None) => unreachable!("patches always fetch the current config"),
+    };
+
+    let mut config = config;
+    if let Err(e) = provenance::validate(&config) {
+        return Err(AppError::unprocessable(e).into_response());
+    }
+    if let Err(e) = validation::machine_config(&config) {
+        return Err(e.into_response());
+    }
+    let resolved = match secrets {
+        Some(secrets) => match secrets.resolve(&body.app_name, &mut config).await {
+            Ok(resolved) => resolved,
+            Err(e) => return Err(AppError::unprocessable(e).into_response()),
+        },
+        None => Vec::new(),
+    };
+
+    let mut update = serde_json::json!({ "config": config });
+    if let Some(region) = &body.region {
+        update["region"] = serde_json::json!(region);
+    }
+
+    let mut machine = client
+        .update_machine(&body.app_name, &body.machine_id, &update, Some(nonce))
+        .await
+        .map_err(|e| AppError::from(&e).into_response())?;
+    if let Some(store) = store {
+        provenance::index(store, &body.app_name, &machine).await;
+    }
+    let etag = machine_etag(&machine);
+    SecretResolver::mask(&mut machine, &resolved);
+    Ok((machine, etag))
 }
 
 #[get("/")]
@@ -150,9 +1216,172 @@ async fn hello() -> impl Responder {
     HttpResponse::Ok().body("flyd!")
 }
 
+/// Also sends `X-Flyd-Update-Available` with the version, while there's a newer release.
 #[get("/health")]
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().body("YES!")
+async fn health_check(updates: web::Data<UpdateChecker>) -> impl Responder {
+    let mut response = HttpResponse::Ok();
+    if let Some(version) = updates.available() {
+        response.insert_header((version::UPDATE_AVAILABLE_HEADER, version));
+    }
+    response.body("YES!")
+}
+
+/// What the background subsystems share with the HTTP handlers.
+struct Shared {
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    machine_states: web::Data<MachineStates>,
+    objects: Option<web::Data<ObjectStore>>,
+    jobs: web::Data<Jobs>,
+    drainer: Option<web::Data<Drainer>>,
+    budgets: Option<web::Data<Budgets>>,
+    capacity: web::Data<CapacityMap>,
+    backoff: web::Data<RestartBackoff>,
+    pools: web::Data<WarmPools>,
+    autoscaler: web::Data<Autoscaler>,
+    limit: web::Data<AdaptiveLimit>,
+    features: web::Data<Features>,
+    breaker: Option<web::Data<DeployBreaker>>,
+    notifier: notify::Notifier,
+}
+
+/// Starts the background subsystems that drive machines: scheduled reports, the machine
+/// state poller, the health watcher, inventory snapshots, schedules, the preemptible tier,
+/// budgets, warm pools, the webhook watcher, the autoscaler and the fleet reconciler.
+fn spawn_orchestration<B: Backend + Clone + 'static>(backend: B, config: &Config, shared: &Shared) {
+    for report in &config.reports {
+        shutdown::spawn(
+            "reports",
+            reports::run(
+                report.clone(),
+                backend.clone(),
+                shared.store.clone(),
+                shared.events.clone(),
+                shared.limit.clone(),
+                shared.notifier.clone(),
+            ),
+        );
+    }
+    if !config.callbacks.poll_apps.is_empty() {
+        shutdown::spawn(
+            "machine state poller",
+            callbacks::poll_loop(
+                backend.clone(),
+                shared.events.clone(),
+                shared.machine_states.clone(),
+                config.callbacks.poll_apps.clone(),
+                Duration::from_secs(config.callbacks.poll_interval_secs.max(1)),
+            ),
+        );
+    }
+    if !config.health.watch_apps.is_empty() {
+        shutdown::spawn(
+            "health watcher",
+            health::watch(
+                backend.clone(),
+                shared.store.clone(),
+                config.health.clone(),
+                shared.events.clone(),
+                shared.drainer.clone(),
+                shared.backoff.clone(),
+            ),
+        );
+    }
+    if let (Some(snapshots), Some(objects)) = (&config.snapshots, &shared.objects) {
+        shutdown::spawn(
+            "snapshots",
+            snapshots::run(
+                snapshots.clone(),
+                backend.clone(),
+                shared.store.clone(),
+                objects.clone(),
+            ),
+        );
+    }
+    let context = schedules::Context {
+        backend: backend.clone(),
+        store: shared.store.clone(),
+        jobs: shared.jobs.clone(),
+        drainer: shared.drainer.clone(),
+        breaker: shared.breaker.clone(),
+        objects: shared.objects.clone(),
+        snapshots: config.snapshots.clone(),
+    };
+    shutdown::spawn(
+        "schedules",
+        schedules::run(context.clone(), config.schedules.clone()),
+    );
+    if let Some(commands) = &config.commands {
+        shutdown::spawn("commands", commands::run(commands.clone(), context));
+    }
+    if let Some(preemptible) = &config.preemptible {
+        shutdown::spawn(
+            "preemptible tier",
+            preemptible::run(
+                preemptible.clone(),
+                backend.clone(),
+                shared.store.clone(),
+                shared.events.clone(),
+                shared.capacity.clone(),
+                shared.drainer.clone(),
+            ),
+        );
+    }
+    if let Some(budgets) = &shared.budgets {
+        shutdown::spawn("budgets", budgets::run(budgets.clone(), backend.clone()));
+    }
+    if !config.pools.is_empty() {
+        shutdown::spawn(
+            "warm pools",
+            pools::run(shared.pools.clone(), backend.clone()),
+        );
+    }
+    shutdown::spawn(
+        "webhook watcher",
+        webhooks::watch(
+            backend.clone(),
+            config.webhooks.clone(),
+            config.callbacks.poll_apps.clone(),
+            shared.store.clone(),
+            shared.events.clone(),
+            shared.machine_states.clone(),
+            shared.features.clone(),
+        ),
+    );
+    shutdown::spawn(
+        "autoscaler",
+        autoscale::run(
+            shared.autoscaler.clone(),
+            backend.clone(),
+            shared.store.clone(),
+            shared.events.clone(),
+            shared.drainer.clone(),
+            shared.features.clone(),
+        ),
+    );
+    if let Some(trash) = &config.trash {
+        shutdown::spawn(
+            "trash",
+            trash::run(
+                trash.clone(),
+                backend.clone(),
+                shared.store.clone(),
+                shared.events.clone(),
+            ),
+        );
+    }
+    shutdown::spawn(
+        "fleet reconciler",
+        fleets::reconcile_loop(
+            shared.store.clone(),
+            shared.events.clone(),
+            shared.backoff.clone(),
+            shared.drainer.clone(),
+            backend,
+            Duration::from_secs(config.fleets.reconcile_interval_secs),
+            shared.features.clone(),
+        ),
+    );
 }
 
 #[actix_web::main]
@@ -160,25 +1389,617 @@ async fn main() -> std::io::Result<()> {
     #[cfg(debug_assertions)]
     dotenvy::from_filename_override(".env.local").ok();
 
-    pretty_env_logger::formatted_builder()
-        .filter_module("flyd", log::LevelFilter::Info)
-        .filter_module("actix", log::LevelFilter::Info)
-        .init();
+    if preflight::requested() {
+        std::process::exit(if preflight::run().await { 0 } else { 1 });
+    }
+
+    let config = Config::load().map_err(std::io::Error::other)?;
+    let logger = pretty_env_logger::formatted_builder()
+        .parse_filters(&config.server.log)
+        .build();
+    let max_level = logger.filter();
+    let log_receiver = log_sinks::install(logger, max_level).map_err(std::io::Error::other)?;
 
-    let reqwest_client = reqwest::Client::default();
+    fly_client::configure(&config.upstream, &config.cache);
+    let schemas = web::Data::new(Schemas::new(&config.schemas));
+    let trusted_proxies =
+        TrustedProxies::from_cidrs(&config.proxy.trusted_cidrs).map_err(std::io::Error::other)?;
 
-    log::info!("flyd");
+    let reqwest_client = reqwest::Client::builder()
+        .connector_layer(ConnectTiming)
+        .build()
+        .map_err(std::io::Error::other)?;
+    let store = web::Data::new(
+        Store::from_config(&config.store)
+            .await
+            .map_err(std::io::Error::other)?,
+    );
+    let api_keys = ApiKeys::from_config(&config)
+        .map_err(std::io::Error::other)?
+        .map(Arc::new);
+    if let Some(api_keys) = &api_keys {
+        api_keys
+            .load(&store)
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        shutdown::spawn(
+            "API key sync",
+            ApiKeys::sync_loop(api_keys.clone(), store.clone()),
+        );
+    }
+    let authenticator = web::Data::new(
+        Authenticator::from_config(&config, &trusted_proxies, api_keys.as_ref())
+            .map_err(std::io::Error::other)?,
+    );
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(reqwest_client.clone()))
-            .wrap(middleware::Logger::new("IP - %a | Time - %D ms"))
+    let redis = match &config.redis {
+        Some(redis) => Some(
+            redis::Client::open(redis.url.as_str())
+                .map_err(std::io::Error::other)?
+                .get_connection_manager()
+                .await
+                .map_err(std::io::Error::other)?,
+        ),
+        None => None,
+    };
+    let rate_limiter = RateLimiter::from_config(&config.rate_limit, redis.as_ref())
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    let hedger = Hedger::from_config(&config.hedging).map(web::Data::new);
+    let limit = web::Data::new(AdaptiveLimit::new(config.concurrency.clone()));
+    let pipeline = web::Data::new(Pipeline::from_config(&config).map_err(std::io::Error::other)?);
+    let response_headers = web::Data::new(
+        ResponseHeaders::from_config(&config.response_headers).map_err(std::io::Error::other)?,
+    );
+    let http_metrics = web::Data::new(HttpMetrics::default());
+    let plugins = Plugins::from_config(&config.plugins)
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    let namespaces = Namespaces::from_config(&config)
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    let virtual_hosts = VirtualHosts::from_config(&config)
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    let idempotency = web::Data::new(
+        IdempotencyCache::from_config(&config.idempotency, redis.as_ref())
+            .map_err(std::io::Error::other)?,
+    );
+    let (write_queue, write_receiver) = WriteQueue::new(&config.write_queue);
+    shutdown::spawn(
+        "write queue",
+        write_queue::run(
+            write_queue.clone(),
+            write_receiver,
+            store.clone(),
+            config.write_queue.batch_size,
+        ),
+    );
+    let events = web::Data::new(EventLog::new(write_queue.clone()));
+    events
+        .load(&store)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    shutdown::spawn(
+        "event pruning",
+        events::prune_loop(store.clone(), config.events.clone()),
+    );
+    let write_queue = web::Data::new(write_queue);
+    let slo = web::Data::new(SloTracker::new(config.slo.clone()));
+    let machine_states = web::Data::new(MachineStates::default());
+    let app_info = web::Data::new(AppInfoCache::from_config(&config));
+    let slow_requests = web::Data::new(SlowRequests::new(config.slow_requests.clone()));
+    let backoff = web::Data::new(RestartBackoff::new(config.restart_backoff.clone()));
+    let pools = web::Data::new(WarmPools::new(config.pools.clone()));
+    let autoscaler = web::Data::new(Autoscaler::new(config.autoscale.clone()));
+    let capacity = web::Data::new(CapacityMap::new(
+        config.capacity.clone(),
+        events.clone(),
+        store.clone(),
+    ));
+    let placer = (!config.placement.is_empty())
+        .then(|| web::Data::new(Placer::new(config.placement.clone())));
+    let mailer = config
+        .notifications
+        .smtp
+        .as_ref()
+        .map(notify::Mailer::from_config)
+        .transpose()
+        .map_err(std::io::Error::other)?;
+    let notifier = notify::Notifier::new(reqwest_client.clone(), mailer, &config.notifications);
+    let jobs = web::Data::new(Jobs::new(
+        config.jobs.clone(),
+        events.clone(),
+        store.clone(),
+        notifier.clone(),
+    ));
+    jobs.load()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let consistency = web::Data::new(Consistency::new(
+        config.consistency.clone(),
+        store.clone(),
+        events.clone(),
+    ));
+    let probes = web::Data::new(Probes::new(&config));
+    let token_monitor = web::Data::new(TokenMonitor::new(
+        &config,
+        reqwest_client.clone(),
+        events.clone(),
+    ));
+    let drainer =
+        Drainer::from_config(&config, reqwest_client.clone(), events.clone()).map(web::Data::new);
+    let signatures = ImageVerifier::from_config(&config, reqwest_client.clone())
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    let scans =
+        ScanGate::from_config(&config, reqwest_client.clone(), events.clone()).map(web::Data::new);
+    let budgets = Budgets::from_config(&config, store.clone(), events.clone()).map(web::Data::new);
+    let deploy_breaker = DeployBreaker::from_config(
+        &config,
+        reqwest_client.clone(),
+        store.clone(),
+        events.clone(),
+    )
+    .map_err(std::io::Error::other)?
+    .map(web::Data::new);
+    if let Some(breaker) = &deploy_breaker {
+        shutdown::spawn("deploy breaker", deploy_breaker::run(breaker.clone()));
+    }
+    let environments = Environments::new(&config.environments)
+        .map(web::Data::new)
+        .map_err(std::io::Error::other)?;
+    let verifier = web::Data::new(Verifier::new(&config, reqwest_client.clone()));
+    let updates = web::Data::new(UpdateChecker::new(
+        config.updates.clone(),
+        reqwest_client.clone(),
+    ));
+    shutdown::spawn("update checker", version::run(updates.clone()));
+    let exec_policies = ExecPolicies::new(&config.exec, events.clone())
+        .map(web::Data::new)
+        .map_err(std::io::Error::other)?;
+    let sessions = SessionRecorder::from_config(&config.sessions, store.clone())
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    let features = web::Data::new(Features::new(&config.features).map_err(std::io::Error::other)?);
+    let grants = Grants::from_config(&config, store.clone(), events.clone()).map(web::Data::new);
+    if let Some(grants) = &grants {
+        shutdown::spawn("grant expiry", Grants::expire_loop(grants.clone()));
+    }
+    let scripts = ScriptLibrary::new(&config.scripts, events.clone())
+        .map(web::Data::new)
+        .map_err(std::io::Error::other)?;
+    let port_forwards = web::Data::new(PortForwards::new(config.port_forward.clone()));
+    let secrets = web::Data::new(SecretResolver::new(
+        config.secret_managers.clone(),
+        reqwest_client.clone(),
+    ));
+
+    shutdown::spawn(
+        "OIDC key refresh",
+        Authenticator::refresh_oidc_keys(authenticator.clone(), reqwest_client.clone()),
+    );
+    shutdown::spawn(
+        "notifications",
+        notify::run(
+            events.subscribe(),
+            config.notifications.clone(),
+            notifier.clone(),
+            store.clone(),
+        ),
+    );
+    shutdown::spawn(
+        "webhook delivery",
+        webhooks::deliver(
+            events.subscribe(),
+            reqwest_client.clone(),
+            config.webhooks.clone(),
+            store.clone(),
+            features.clone(),
+        ),
+    );
+    shutdown::spawn("SLO alerts", slo::alert_loop(slo.clone(), events.clone()));
+    for bus in &config.event_buses {
+        shutdown::spawn(
+            "event buses",
+            event_bus::run(bus.clone(), events.subscribe()),
+        );
+    }
+    if let Some(sinks) = LogSinks::from_config(&config.log_sinks).map_err(std::io::Error::other)? {
+        shutdown::spawn(
+            "log sinks",
+            log_sinks::run(
+                sinks,
+                log_receiver,
+                events.subscribe(),
+                reqwest_client.clone(),
+            ),
+        );
+    }
+    let siem = SiemExporters::from_config(&config.siem, reqwest_client.clone())
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    if let Some(siem) = &siem {
+        shutdown::spawn("SIEM export", siem::run(siem.clone(), events.subscribe()));
+    }
+    let keep_warm = KeepWarm::from_config(&config.keep_warm, reqwest_client.clone())
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    if let Some(keep_warm) = &keep_warm {
+        shutdown::spawn("keep warm", keep_warm::run(keep_warm.clone()));
+    }
+    let audit = AuditLog::from_config(config.audit.as_ref())
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+
+    let objects = config
+        .object_storage
+        .as_ref()
+        .map(|storage| ObjectStore::from_config(storage, reqwest_client.clone()))
+        .transpose()
+        .map_err(std::io::Error::other)?
+        .map(web::Data::new);
+    if config.snapshots.is_some() && objects.is_none() {
+        return Err(std::io::Error::other(
+            "[snapshots] needs [object_storage] to write to",
+        ));
+    }
+    let shared = Shared {
+        store: store.clone(),
+        events: events.clone(),
+        machine_states: machine_states.clone(),
+        objects: objects.clone(),
+        jobs: jobs.clone(),
+        drainer: drainer.clone(),
+        budgets: budgets.clone(),
+        capacity: capacity.clone(),
+        backoff: backoff.clone(),
+        pools: pools.clone(),
+        autoscaler: autoscaler.clone(),
+        limit: limit.clone(),
+        features: features.clone(),
+        breaker: deploy_breaker.clone(),
+        notifier,
+    };
+
+    if config.backend == BackendKind::Fly {
+        shutdown::spawn("token health", token_health::run(token_monitor.clone()));
+    }
+    let mut docker = None;
+    match config.backend {
+        BackendKind::Fly => match config.fly_api_token.as_deref().and_then(|token| {
+            FlyClient::from_token(
+                reqwest_client.clone(),
+                token,
+                fly_client::api_hostname(config.use_private_api),
+            )
+            .map(|client| {
+                client
+                    .with_slo(slo.clone().into_inner())
+                    .with_limit(Some(limit.clone().into_inner()))
+                    .with_signatures(signatures.clone().map(web::Data::into_inner))
+                    .with_scans(scans.clone().map(web::Data::into_inner))
+                    .with_schemas(Some(schemas.clone().into_inner()))
+            })
+        }) {
+            Some(client) => {
+                shutdown::spawn(
+                    "consistency check",
+                    consistency::run(consistency.clone(), client.clone()),
+                );
+                if let Some(drift) = config.secret_drift.clone() {
+                    shutdown::spawn(
+                        "secret drift",
+                        secret_drift::run(
+                            drift,
+                            client.clone(),
+                            store.clone(),
+                            drainer.clone(),
+                            events.clone(),
+                        ),
+                    );
+                }
+                if let Some(catalog) = config.snapshot_catalog.clone() {
+                    shutdown::spawn(
+                        "snapshot catalog",
+                        snapshot_catalog::run(
+                            catalog,
+                            client.clone(),
+                            store.clone(),
+                            events.clone(),
+                        ),
+                    );
+                }
+                spawn_orchestration(client, &config, &shared);
+            }
+            None => {
+                log::warn!(
+                    "FLY_API_TOKEN not set, fleet reconciler, reports and schedules disabled"
+                );
+                consistency.skip("FLY_API_TOKEN isn't set");
+            }
+        },
+        BackendKind::Docker => {
+            let backend = DockerBackend::connect().map_err(std::io::Error::other)?;
+            log::info!("Running fleets and reports against the local Docker daemon");
+            if signatures.is_some() || scans.is_some() {
+                log::warn!(
+                    "image_signatures and vulnerability_scans only apply to Fly machines, not Docker containers"
+                );
+            }
+            if namespaces.is_some() {
+                log::warn!(
+                    "Namespace quotas and policies only apply to Fly machines; Docker callers are only held to their prefix"
+                );
+            }
+            spawn_orchestration(backend.clone(), &config, &shared);
+            consistency.skip("The consistency check only applies to Fly");
+            docker = Some(web::Data::new(backend));
+        }
+    }
+
+    let bind = config.server.bind.clone();
+    let grace = Duration::from_secs(config.server.shutdown_grace_secs);
+    let delay = Duration::from_secs(config.server.shutdown_delay_secs);
+    let tls = match &config.server.tls {
+        Some(tls) => {
+            let (server_config, certificates) =
+                tls::server_config(tls).map_err(std::io::Error::other)?;
+            shutdown::spawn("TLS reload", tls::reload(tls.clone(), certificates));
+            Some(server_config)
+        }
+        None => None,
+    };
+    let client_auth = config
+        .server
+        .tls
+        .as_ref()
+        .filter(|tls| tls.client_ca_path.is_some())
+        .map(|tls| {
+            web::Data::new(tls::ClientAuth {
+                routes: tls.client_auth_routes.clone(),
+            })
+        });
+    log::info!(
+        "flyd listening on {}{}",
+        bind,
+        if tls.is_some() { " with TLS" } else { "" }
+    );
+
+    let server = HttpServer::new(move || {
+        let trusted_proxies = trusted_proxies.clone();
+        let mut app = App::new();
+        if let Some(rate_limiter) = &rate_limiter {
+            app = app.app_data(rate_limiter.clone());
+        }
+        if let Some(hedger) = &hedger {
+            app = app.app_data(hedger.clone());
+        }
+        if let Some(siem) = &siem {
+            app = app.app_data(siem.clone());
+        }
+        if let Some(keep_warm) = &keep_warm {
+            app = app.app_data(keep_warm.clone());
+        }
+        if let Some(audit) = &audit {
+            app = app.app_data(audit.clone());
+        }
+        if let Some(client_auth) = &client_auth {
+            app = app.app_data(client_auth.clone());
+        }
+        if let Some(api_keys) = &api_keys {
+            app = app.app_data(web::Data::from(api_keys.clone()));
+        }
+        if let Some(plugins) = &plugins {
+            app = app.app_data(plugins.clone());
+        }
+        if let Some(namespaces) = &namespaces {
+            app = app.app_data(namespaces.clone());
+        }
+        if let Some(virtual_hosts) = &virtual_hosts {
+            app = app.app_data(virtual_hosts.clone());
+        }
+        if let Some(placer) = &placer {
+            app = app.app_data(placer.clone());
+        }
+        if let Some(sessions) = &sessions {
+            app = app.app_data(sessions.clone());
+        }
+        if let Some(grants) = &grants {
+            app = app.app_data(grants.clone());
+        }
+        if let Some(docker) = &docker {
+            app = app.app_data(docker.clone());
+        }
+        if let Some(drainer) = &drainer {
+            app = app.app_data(drainer.clone());
+        }
+        if let Some(signatures) = &signatures {
+            app = app.app_data(signatures.clone());
+        }
+        if let Some(scans) = &scans {
+            app = app.app_data(scans.clone());
+        }
+        if let Some(budgets) = &budgets {
+            app = app.app_data(budgets.clone());
+        }
+        if let Some(objects) = &objects {
+            app = app.app_data(objects.clone());
+        }
+        if let Some(deploy_breaker) = &deploy_breaker {
+            app = app.app_data(deploy_breaker.clone());
+        }
+        app.app_data(web::Data::new(reqwest_client.clone()))
+            .app_data(web::JsonConfig::default().error_handler(errors::extractor_error))
+            .app_data(web::QueryConfig::default().error_handler(errors::extractor_error))
+            .app_data(web::PathConfig::default().error_handler(errors::extractor_error))
+            .app_data(web::Data::new(config.clone()))
+            .app_data(authenticator.clone())
+            .app_data(store.clone())
+            .app_data(events.clone())
+            .app_data(slo.clone())
+            .app_data(write_queue.clone())
+            .app_data(idempotency.clone())
+            .app_data(web::Data::new(trusted_proxies.clone()))
+            .app_data(machine_states.clone())
+            .app_data(app_info.clone())
+            .app_data(slow_requests.clone())
+            .app_data(secrets.clone())
+            .app_data(port_forwards.clone())
+            .app_data(jobs.clone())
+            .app_data(capacity.clone())
+            .app_data(backoff.clone())
+            .app_data(pools.clone())
+            .app_data(autoscaler.clone())
+            .app_data(environments.clone())
+            .app_data(verifier.clone())
+            .app_data(updates.clone())
+            .app_data(consistency.clone())
+            .app_data(probes.clone())
+            .app_data(token_monitor.clone())
+            .app_data(limit.clone())
+            .app_data(exec_policies.clone())
+            .app_data(scripts.clone())
+            .app_data(pipeline.clone())
+            .app_data(response_headers.clone())
+            .app_data(http_metrics.clone())
+            .app_data(schemas.clone())
+            .app_data(features.clone())
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(9, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(8, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(7, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(6, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(5, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(4, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(3, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(2, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(1, req, next)
+            }))
+            .wrap(middleware::from_fn(|req, next| {
+                pipeline::slot(0, req, next)
+            }))
+            .wrap(middleware::from_fn(response_headers::inject))
+            .wrap(middleware::from_fn(tls::require_client_certificate))
+            .wrap(middleware::from_fn(diagnostics::trace))
+            .wrap(middleware::from_fn(errors::request_id))
+            .wrap(middleware::from_fn(telemetry::track))
+            .wrap(
+                middleware::Logger::new("IP - %{client_ip}xi | Time - %D ms")
+                    .custom_request_replace("client_ip", move |req| {
+                        trusted_proxies
+                            .client_ip(req.peer_addr(), req.headers())
+                            .map(|ip| ip.to_string())
+                            .unwrap_or_else(|| "-".to_string())
+                    }),
+            )
             .service(hello)
             .service(create_machine)
             .service(list_machines)
+            .service(get_machine)
+            .service(wait_machine)
+            .service(json_patch_machine)
+            .service(update_machine)
+            .service(patch_machine)
+            .service(start_machine)
+            .service(stop_machine)
+            .service(restart_machine)
+            .service(signal_machine)
             .service(health_check)
+            .configure(auth::configure)
+            .configure(api_keys::configure)
+            .configure(annotations::configure)
+            .configure(apps::configure)
+            .configure(app_info::configure)
+            .configure(audit::configure)
+            .configure(reachability::configure)
+            .configure(port_forward::configure)
+            .configure(machine_usage::configure)
+            .configure(fleets::configure)
+            .configure(events::configure)
+            .configure(callbacks::configure)
+            .configure(reports::configure)
+            .configure(slo::configure)
+            .configure(metadata::configure)
+            .configure(cordon::configure)
+            .configure(metrics::configure)
+            .configure(usage::configure)
+            .configure(diagnostics::configure)
+            .configure(snapshots::configure)
+            .configure(artifacts::configure)
+            .configure(jobs::configure)
+            .configure(job_reports::configure)
+            .configure(deploys::configure)
+            .configure(rolling::configure)
+            .configure(deploy_breaker::configure)
+            .configure(capacity::configure)
+            .configure(schedules::configure)
+            .configure(exec::configure)
+            .configure(scripts::configure)
+            .configure(sessions::configure)
+            .configure(evacuations::configure)
+            .configure(environments::configure)
+            .configure(provenance::configure)
+            .configure(namespaces::configure)
+            .configure(graph::configure)
+            .configure(impact::configure)
+            .configure(placement::configure)
+            .configure(leases::configure)
+            .configure(preemptible::configure)
+            .configure(budgets::configure)
+            .configure(grants::configure)
+            .configure(features::configure)
+            .configure(freezes::configure)
+            .configure(volumes::configure)
+            .configure(snapshot_catalog::configure)
+            .configure(secrets::configure)
+            .configure(secret_drift::configure)
+            .configure(batch::configure)
+            .configure(backoff::configure)
+            .configure(pools::configure)
+            .configure(autoscale::configure)
+            .configure(templates::configure)
+            .configure(token_health::configure)
+            .configure(views::configure)
+            .configure(multi_app::configure)
+            .configure(trash::configure)
+            .configure(watch::configure)
+            .configure(migrations::configure)
+            .configure(webhooks::configure)
+            .configure(logs::configure)
+            .configure(version::configure)
+            .configure(openapi::configure)
+            .configure(schemas::configure)
+            .configure(consistency::configure)
+            .configure(probes::configure)
     })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+    .shutdown_timeout(grace.as_secs())
+    .disable_signals()
+    .on_connect(tls::on_connect);
+    let server = match tls {
+        Some(tls) => server.bind_rustls_0_23(bind, tls)?,
+        None => server.bind(bind)?,
+    }
+    .run();
+    actix_web::rt::spawn(shutdown::on_signal(server.handle(), delay));
+    server.await?;
+    log::info!("Requests drained, stopping background tasks");
+    shutdown::stop_tasks(grace).await;
+    Ok(())
 }
@@ -0,0 +1,198 @@
+use serde_json::{Map, Value};
+
+/// Fills in whatever `target` leaves unset (missing or null) from `defaults`, recursing
+/// into objects.
+pub fn fill_defaults(target: &mut Value, defaults: &Value) {
+    let (Value::Object(target), Value::Object(defaults)) = (target, defaults) else {
+        return;
+    };
+    for (key, default) in defaults {
+        match target.get_mut(key) {
+            Some(value) if !value.is_null() => fill_defaults(value, default),
+            _ => {
+                target.insert(key.clone(), default.clone());
+            }
+        }
+    }
+}
+
+/// JSON Merge Patch (RFC 7386): objects in `patch` are merged into `target` key by key,
+/// `null` deletes a key, and anything else replaces the value outright (arrays included).
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        return;
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// A key both sides of a three-way merge changed from the base, differently. `None` is a
+/// missing key.
+pub struct Conflict {
+    /// JSON Pointer to the key.
+    pub path: String,
+    pub base: Option<Value>,
+    pub ours: Option<Value>,
+    pub theirs: Option<Value>,
+}
+
+/// Three-way merge of `ours` and `theirs`, both changed from `base`: a key only one side
+/// changed takes that side's value, and a key both changed differently is a conflict,
+/// resolved to `ours` if `prefer_ours` and to `theirs` otherwise. Recurses into objects;
+/// anything else, arrays included, is changed as a whole.
+pub fn merge3(
+    base: &Value,
+    ours: &Value,
+    theirs: &Value,
+    prefer_ours: bool,
+) -> (Value, Vec<Conflict>) {
+    let mut conflicts = Vec::new();
+    let merged = merge3_at(
+        "",
+        Some(base),
+        Some(ours),
+        Some(theirs),
+        prefer_ours,
+        &mut conflicts,
+    );
+    (merged.unwrap_or(Value::Null), conflicts)
+}
+
+fn merge3_at(
+    path: &str,
+    base: Option<&Value>,
+    ours: Option<&Value>,
+    theirs: Option<&Value>,
+    prefer_ours: bool,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<Value> {
+    if ours == theirs || theirs == base {
+        return ours.cloned();
+    }
+    if ours == base {
+        return theirs.cloned();
+    }
+    if let (Some(Value::Object(ours)), Some(Value::Object(theirs))) = (ours, theirs) {
+        let base = base.and_then(Value::as_object);
+        let mut merged = Map::new();
+        let keys = ours
+            .keys()
+            .chain(theirs.keys().filter(|key| !ours.contains_key(*key)));
+        for key in keys {
+            let path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+            if let Some(value) = merge3_at(
+                &path,
+                base.and_then(|base| base.get(key)),
+                ours.get(key),
+                theirs.get(key),
+                prefer_ours,
+                conflicts,
+            ) {
+                merged.insert(key.clone(), value);
+            }
+        }
+        return Some(Value::Object(merged));
+    }
+    conflicts.push(Conflict {
+        path: path.to_string(),
+        base: base.cloned(),
+        ours: ours.cloned(),
+        theirs: theirs.cloned(),
+    });
+    if prefer_ours { ours } else { theirs }.cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn merge_patch_follows_rfc_7386() {
+        let mut config = json!({
+            "image": "web:1",
+            "env": { "A": "1", "B": "2" },
+            "services": [{ "internal_port": 8080 }],
+        });
+        merge_patch(
+            &mut config,
+            &json!({
+                "image": "web:2",
+                "env": { "B": null, "C": "3" },
+                "services": [],
+                "guest": { "cpus": 2 },
+            }),
+        );
+        assert_eq!(
+            config,
+            json!({
+                "image": "web:2",
+                "env": { "A": "1", "C": "3" },
+                "services": [],
+                "guest": { "cpus": 2 },
+            })
+        );
+
+        merge_patch(&mut config, &json!(["not", "an", "object"]));
+        assert_eq!(config, json!(["not", "an", "object"]));
+    }
+
+    #[test]
+    fn fill_defaults_only_fills_what_is_unset() {
+        let mut config = json!({ "guest": { "cpus": 2, "memory_mb": null }, "image": "web" });
+        fill_defaults(
+            &mut config,
+            &json!({ "guest": { "cpus": 1, "memory_mb": 256 }, "auto_destroy": true }),
+        );
+        assert_eq!(
+            config,
+            json!({
+                "guest": { "cpus": 2, "memory_mb": 256 },
+                "image": "web",
+                "auto_destroy": true,
+            })
+        );
+    }
+
+    #[test]
+    fn merge3_takes_each_sides_changes() {
+        let base = json!({ "image": "web:1", "env": { "A": "1" }, "restart": "always" });
+        let ours = json!({ "image": "web:2", "env": { "A": "1" }, "restart": "always" });
+        let theirs = json!({ "image": "web:1", "env": { "A": "1", "B": "2" } });
+        let (merged, conflicts) = merge3(&base, &ours, &theirs, true);
+        assert_eq!(
+            merged,
+            json!({ "image": "web:2", "env": { "A": "1", "B": "2" } })
+        );
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn merge3_reports_and_resolves_conflicts() {
+        let base = json!({ "env": { "a/b~c": "1" } });
+        let ours = json!({ "env": { "a/b~c": "2" } });
+        let theirs = json!({ "env": { "a/b~c": "3" } });
+
+        let (merged, conflicts) = merge3(&base, &ours, &theirs, true);
+        assert_eq!(merged, ours);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, "/env/a~1b~0c");
+        assert_eq!(conflicts[0].base, Some(json!("1")));
+
+        let (merged, _) = merge3(&base, &ours, &theirs, false);
+        assert_eq!(merged, theirs);
+    }
+}
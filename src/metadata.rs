@@ -0,0 +1,209 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use flyd::models::{
+    BulkMachineResult, BulkMetadataReport, BulkMetadataRequest, DeleteMachineMetadataRequest,
+    MachineRequest, SetMachineMetadataRequest,
+};
+use serde_json::Value;
+
+use crate::backend::Backend;
+use crate::batch;
+use crate::concurrency::{self, AdaptiveLimit};
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fly_client::{self, FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::query::{self, Expression};
+use crate::slo::SloTracker;
+
+fn client(
+    req: &HttpRequest,
+    use_private_api: bool,
+    http_client: &web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> Result<FlyClient, HttpResponse> {
+    let (headers, api_hostname) = prepare_request(req, use_private_api)?;
+    Ok(
+        FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner())
+            .with_request_checks(req),
+    )
+}
+
+#[get("/v0/machines/metadata")]
+async fn get_metadata(
+    req: HttpRequest,
+    query: web::Query<MachineRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => match client
+            .get_metadata(&query.app_name, &query.machine_id)
+            .await
+        {
+            Ok(metadata) => HttpResponse::Ok().json(metadata),
+            Err(e) => AppError::from(&e).into_response(),
+        },
+        Err(response) => response,
+    }
+}
+
+/// Sets one metadata key. Fly versions the machine's config with it, but doesn't restart
+/// the machine.
+#[post("/v0/machines/metadata")]
+async fn set_metadata(
+    req: HttpRequest,
+    body: web::Json<SetMachineMetadataRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => match client
+            .set_metadata(&body.app_name, &body.machine_id, &body.key, &body.value)
+            .await
+        {
+            Ok(()) => HttpResponse::NoContent().finish(),
+            Err(e) => AppError::from(&e).into_response(),
+        },
+        Err(response) => response,
+    }
+}
+
+#[delete("/v0/machines/metadata")]
+async fn delete_metadata(
+    req: HttpRequest,
+    query: web::Query<DeleteMachineMetadataRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => match client
+            .delete_metadata(&query.app_name, &query.machine_id, &query.key)
+            .await
+        {
+            Ok(()) => HttpResponse::NoContent().finish(),
+            Err(e) => AppError::from(&e).into_response(),
+        },
+        Err(response) => response,
+    }
+}
+
+async fn retag<B: Backend>(
+    backend: &B,
+    body: &BulkMetadataRequest,
+    machine_id: &str,
+) -> Result<(), String> {
+    let app = &body.app_name;
+    for (key, value) in &body.set {
+        backend
+            .set_metadata(app, machine_id, key, value)
+            .await
+            .map_err(|e| format!("Failed to set {}: {}", key, e))?;
+    }
+    for key in &body.unset {
+        backend
+            .delete_metadata(app, machine_id, key)
+            .await
+            .map_err(|e| format!("Failed to unset {}: {}", key, e))?;
+    }
+    Ok(())
+}
+
+async fn bulk<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    body: &BulkMetadataRequest,
+    q: Option<Expression>,
+) -> HttpResponse {
+    let machines: Vec<Value> = match backend.list_machines(&body.app_name).await {
+        Ok(machines) => machines
+            .into_iter()
+            .filter(|machine| batch::matches(&body.filter, machine))
+            .filter(|machine| q.as_ref().is_none_or(|q| q.matches(machine)))
+            .collect(),
+        Err(e) => {
+            return AppError::internal(format!("Failed to list machines: {}", e)).into_response();
+        }
+    };
+
+    let limit = req
+        .app_data::<web::Data<AdaptiveLimit>>()
+        .map(|limit| limit.get_ref());
+    let results = concurrency::map(
+        limit,
+        machines.iter().map(|machine| async move {
+            let machine_id = machine["id"].as_str().unwrap_or_default();
+            let error = if body.dry_run {
+                None
+            } else {
+                retag(backend, body, machine_id).await.err()
+            };
+            BulkMachineResult {
+                machine_id: machine_id.to_string(),
+                name: machine["name"].as_str().map(str::to_string),
+                region: machine["region"].as_str().map(str::to_string),
+                error,
+            }
+        }),
+    )
+    .await;
+    if !body.dry_run {
+        fly_client::cache().invalidate(&body.app_name);
+    }
+
+    HttpResponse::Ok().json(BulkMetadataReport {
+        dry_run: body.dry_run,
+        matched: results.len(),
+        failed: results
+            .iter()
+            .filter(|result| result.error.is_some())
+            .count(),
+        machines: results,
+    })
+}
+
+/// Sets and unsets metadata keys on every machine of the app matching the selector, as
+/// many at a time as the `[concurrency]` limit allows. As with `/v0/machines/bulk`, the
+/// selector can't be empty.
+#[post("/v0/machines/metadata/bulk")]
+async fn bulk_metadata(
+    req: HttpRequest,
+    body: web::Json<BulkMetadataRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let filter = &body.filter;
+    if filter.region.is_none()
+        && filter.state.is_none()
+        && filter.name_prefix.is_none()
+        && filter.metadata.is_empty()
+        && body.q.is_none()
+    {
+        return AppError::bad_request("A bulk metadata update needs a filter or q").into_response();
+    }
+    if body.set.is_empty() && body.unset.is_empty() {
+        return AppError::bad_request("Nothing to set or unset").into_response();
+    }
+    let q = match body.q.as_deref().map(query::parse).transpose() {
+        Ok(q) => q,
+        Err(e) => return AppError::bad_request(format!("Invalid q: {}", e)).into_response(),
+    };
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return bulk(&req, docker.get_ref(), &body, q).await;
+    }
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client.with_limit(
+            req.app_data::<web::Data<AdaptiveLimit>>()
+                .map(|limit| limit.clone().into_inner()),
+        ),
+        Err(response) => return response,
+    };
+    bulk(&req, &client, &body, q).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_metadata)
+        .service(set_metadata)
+        .service(delete_metadata)
+        .service(bulk_metadata);
+}
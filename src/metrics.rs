@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::Drift;
+
+use crate::app_info::AppInfoCache;
+use crate::callbacks::MachineStates;
+use crate::concurrency::AdaptiveLimit;
+use crate::config::{AppMetricsConfig, Config, MetricsConfig};
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client;
+use crate::hedge::Hedger;
+use crate::keep_warm::KeepWarm;
+use crate::siem::SiemExporters;
+use crate::slo::SloTracker;
+use crate::store::{Store, StoreError};
+use crate::telemetry::HttpMetrics;
+use crate::version::UpdateChecker;
+use crate::write_queue::WriteQueue;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub fn sample(out: &mut String, name: &str, labels: &[(&str, String)], value: f64) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+/// What flyd knows about one managed app. Machine states come from callbacks or polling;
+/// the fleet gauges only exist for apps with a fleet.
+#[derive(Default)]
+struct AppGauges {
+    machines: BTreeMap<String, u64>,
+    desired_machines: Option<usize>,
+    drift: Option<Vec<Drift>>,
+}
+
+async fn app_gauges(
+    store: &Store,
+    states: &MachineStates,
+) -> Result<BTreeMap<String, AppGauges>, StoreError> {
+    let mut apps: BTreeMap<String, AppGauges> = BTreeMap::new();
+    for (app, machines) in states.counts() {
+        apps.entry(app).or_default().machines = machines;
+    }
+    for fleet in fleets::fleet_specs(store).await? {
+        let drift = fleets::drift_report(store, &fleet.app).await?;
+        let gauges = apps.entry(fleet.app).or_default();
+        gauges.desired_machines = Some(fleet.machines.len());
+        gauges.drift = drift.map(|report| report.drift);
+    }
+    Ok(apps)
+}
+
+fn drift_kind(drift: &Drift) -> &'static str {
+    match drift {
+        Drift::Missing { .. } => "missing",
+        Drift::ConfigChanged { .. } => "config_changed",
+        Drift::Unexpected { .. } => "unexpected",
+    }
+}
+
+/// Renders each app under its configured namespace, with `app` and its configured labels
+/// on every sample. Apps sharing a namespace share the `# HELP`/`# TYPE` lines.
+fn render_apps(out: &mut String, config: &MetricsConfig, apps: &BTreeMap<String, AppGauges>) {
+    let default = AppMetricsConfig::default();
+    let settings = |app: &str| config.apps.get(app).unwrap_or(&default);
+    let namespaces: BTreeSet<&str> = apps
+        .keys()
+        .map(|app| settings(app).namespace.as_str())
+        .collect();
+
+    for namespace in namespaces {
+        let members: Vec<(&String, &AppGauges)> = apps
+            .iter()
+            .filter(|(app, _)| settings(app).namespace == namespace)
+            .collect();
+        let labelled = |app: &str| {
+            let mut labels = vec![("app", app.to_string())];
+            labels.extend(
+                settings(app)
+                    .labels
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.clone())),
+            );
+            labels
+        };
+
+        let name = format!("{}_machines", namespace);
+        header(out, &name, "gauge", "Machines by last known state.");
+        for (app, gauges) in &members {
+            for (state, count) in &gauges.machines {
+                let mut labels = labelled(app);
+                labels.push(("state", state.clone()));
+                sample(out, &name, &labels, *count as f64);
+            }
+        }
+
+        let name = format!("{}_fleet_desired_machines", namespace);
+        header(out, &name, "gauge", "Machines in the app's fleet spec.");
+        for (app, gauges) in &members {
+            if let Some(desired) = gauges.desired_machines {
+                sample(out, &name, &labelled(app), desired as f64);
+            }
+        }
+
+        let name = format!("{}_fleet_drift", namespace);
+        header(
+            out,
+            &name,
+            "gauge",
+            "Drift found by the last reconcile, by kind.",
+        );
+        for (app, gauges) in &members {
+            let Some(drift) = &gauges.drift else {
+                continue;
+            };
+            for kind in ["missing", "config_changed", "unexpected"] {
+                let count = drift
+                    .iter()
+                    .filter(|drift| drift_kind(drift) == kind)
+                    .count();
+                let mut labels = labelled(app);
+                labels.push(("kind", kind.to_string()));
+                sample(out, &name, &labels, count as f64);
+            }
+        }
+    }
+}
+
+/// Prometheus text exposition of flyd's metrics.
+#[get("/metrics")]
+async fn metrics(
+    req: HttpRequest,
+    slo: web::Data<SloTracker>,
+    write_queue: web::Data<WriteQueue>,
+    config: web::Data<Config>,
+    store: web::Data<Store>,
+    states: web::Data<MachineStates>,
+    updates: web::Data<UpdateChecker>,
+) -> impl Responder {
+    let apps = match app_gauges(&store, &states).await {
+        Ok(apps) => apps,
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+
+    let mut out = String::new();
+    updates.render_metrics(&mut out);
+    slo.render_metrics(&mut out);
+    write_queue.render_metrics(&mut out);
+    fly_client::breaker().render_metrics(&mut out);
+    fly_client::metrics().render_metrics(&mut out);
+    fly_client::cache().render_metrics(&mut out);
+    if let Some(http) = req.app_data::<web::Data<HttpMetrics>>() {
+        http.render_metrics(&mut out);
+    }
+    if let Some(limit) = req.app_data::<web::Data<AdaptiveLimit>>() {
+        limit.render_metrics(&mut out);
+    }
+    if let Some(hedger) = req.app_data::<web::Data<Hedger>>() {
+        hedger.render_metrics(&mut out);
+    }
+    if let Some(siem) = req.app_data::<web::Data<SiemExporters>>() {
+        siem.render_metrics(&mut out);
+    }
+    if let Some(keep_warm) = req.app_data::<web::Data<KeepWarm>>() {
+        keep_warm.render_metrics(&mut out);
+    }
+    if let Some(app_info) = req.app_data::<web::Data<AppInfoCache>>() {
+        app_info.render_metrics(&mut out);
+    }
+    render_apps(&mut out, &config.metrics, &apps);
+    HttpResponse::Ok().content_type(CONTENT_TYPE).body(out)
+}
+
+/// One app's metrics only, for apps configured with `endpoint = true`.
+#[get("/metrics/app/{app}")]
+async fn app_metrics(
+    app: web::Path<String>,
+    config: web::Data<Config>,
+    store: web::Data<Store>,
+    states: web::Data<MachineStates>,
+) -> impl Responder {
+    if !config
+        .metrics
+        .apps
+        .get(app.as_str())
+        .is_some_and(|settings| settings.endpoint)
+    {
+        return AppError::not_found(format!("No metrics endpoint for app {}", app)).into_response();
+    }
+
+    let mut apps = match app_gauges(&store, &states).await {
+        Ok(apps) => apps,
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    apps.retain(|name, _| *name == *app);
+
+    let mut out = String::new();
+    render_apps(&mut out, &config.metrics, &apps);
+    HttpResponse::Ok().content_type(CONTENT_TYPE).body(out)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics).service(app_metrics);
+}
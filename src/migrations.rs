@@ -0,0 +1,434 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::Utc;
+use flyd::models::{MigratedResource, MigrationState, OrgMigration, OrgMigrationRequest};
+use reqwest::StatusCode;
+use reqwest::header::{AUTHORIZATION, HeaderValue};
+use serde_json::{Value, json};
+
+use crate::apps;
+use crate::auth;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::fleets::{self, MANAGED_METADATA_KEY};
+use crate::fly_client::{FlyClient, FlyError, WithChecks, authorization_value};
+use crate::jobs::{self, Jobs, Work};
+use crate::namespaces;
+use crate::prepare_request;
+use crate::store::Store;
+
+pub const MIGRATIONS: &str = "migrations";
+
+fn pending(source_id: &str, name: Option<&str>) -> MigratedResource {
+    MigratedResource {
+        source_id: source_id.to_string(),
+        name: name.map(str::to_string),
+        id: None,
+        snapshot_id: None,
+        state: MigrationState::Pending,
+        error: None,
+    }
+}
+
+fn migrated(resource: &mut MigratedResource, id: Option<&str>) {
+    resource.id = id.map(str::to_string);
+    resource.state = MigrationState::Migrated;
+    resource.error = None;
+}
+
+fn failed(resource: &mut MigratedResource, error: String) {
+    resource.state = MigrationState::Failed;
+    resource.error = Some(error);
+}
+
+/// `name`, or the first of `name-migrated`, `name-migrated-2`, ... not in `taken`.
+fn free_name(name: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(name) {
+        return name.to_string();
+    }
+    let mut candidate = format!("{}-migrated", name);
+    let mut n = 2;
+    while taken.contains(&candidate) {
+        candidate = format!("{}-migrated-{}", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+async fn save(store: &Store, migration: &mut OrgMigration) -> Result<(), String> {
+    migration.updated_at = Utc::now();
+    store
+        .put(MIGRATIONS, &migration.id, migration)
+        .await
+        .map_err(|e| format!("Failed to save migration {}: {}", migration.id, e))
+}
+
+/// Creates the target app, unless it's already in the target org.
+async fn ensure_target_app(target: &FlyClient, migration: &OrgMigration) -> Result<(), String> {
+    match target.get_app(&migration.target_app).await {
+        Ok(app) if app["organization"]["slug"] == migration.target_org.as_str() => Ok(()),
+        Ok(app) => Err(format!(
+            "App name {} is taken by org {}",
+            migration.target_app, app["organization"]["slug"]
+        )),
+        Err(FlyError::Status { status, .. }) if status == StatusCode::NOT_FOUND => target
+            .create_app(&migration.target_app, &migration.target_org)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to create {}: {}", migration.target_app, e)),
+        Err(e) => Err(format!("Failed to look up {}: {}", migration.target_app, e)),
+    }
+}
+
+/// Copies the source app's volumes, each from a fresh snapshot, then its machines mounting
+/// the copies. The progress is saved after each resource, so a retry picks up the ones
+/// not yet migrated. The source app is left as it was.
+async fn migrate(
+    source: FlyClient,
+    target: FlyClient,
+    store: web::Data<Store>,
+    require_unique_zone: bool,
+    initial: OrgMigration,
+) -> Result<Value, String> {
+    let mut migration = store
+        .get::<OrgMigration>(MIGRATIONS, &initial.id)
+        .await
+        .map_err(|e| e.to_string())?
+        .unwrap_or(initial);
+    ensure_target_app(&target, &migration).await?;
+
+    let machines: Vec<Value> = source
+        .list_machines(&migration.source_app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?
+        .into_iter()
+        .filter(fleets::is_live)
+        .collect();
+    let volumes: Vec<Value> = source
+        .list_volumes(&migration.source_app)
+        .await
+        .map_err(|e| format!("Failed to list volumes: {}", e))?
+        .into_iter()
+        .filter(fleets::is_live)
+        .collect();
+    if migration.volumes.is_empty() && migration.machines.is_empty() {
+        migration.volumes = volumes
+            .iter()
+            .filter_map(|volume| Some(pending(volume["id"].as_str()?, volume["name"].as_str())))
+            .collect();
+        migration.machines = machines
+            .iter()
+            .filter_map(|machine| Some(pending(machine["id"].as_str()?, machine["name"].as_str())))
+            .collect();
+        save(&store, &mut migration).await?;
+    }
+
+    for index in 0..migration.volumes.len() {
+        if migration.volumes[index].state == MigrationState::Migrated {
+            continue;
+        }
+        let source_id = migration.volumes[index].source_id.clone();
+        let Some(volume) = volumes
+            .iter()
+            .find(|volume| volume["id"] == source_id.as_str())
+        else {
+            failed(
+                &mut migration.volumes[index],
+                "Gone from the source app".to_string(),
+            );
+            save(&store, &mut migration).await?;
+            continue;
+        };
+        match apps::take_snapshot(&source, &migration.source_app, &source_id).await {
+            Ok(Some(snapshot_id)) => {
+                migration.volumes[index].snapshot_id = Some(snapshot_id.clone());
+                let create = json!({
+                    "name": volume["name"],
+                    "region": volume["region"],
+                    "size_gb": volume["size_gb"],
+                    "snapshot_id": snapshot_id,
+                    "require_unique_zone": require_unique_zone,
+                });
+                match target.create_volume(&migration.target_app, &create).await {
+                    Ok(created) => migrated(&mut migration.volumes[index], created["id"].as_str()),
+                    Err(e) => failed(
+                        &mut migration.volumes[index],
+                        format!("Failed to create: {}", e),
+                    ),
+                }
+            }
+            Ok(None) => failed(
+                &mut migration.volumes[index],
+                "The snapshot wasn't taken in time".to_string(),
+            ),
+            Err(e) => failed(
+                &mut migration.volumes[index],
+                format!("Failed to snapshot: {}", e),
+            ),
+        }
+        save(&store, &mut migration).await?;
+    }
+
+    let volume_ids: HashMap<String, String> = migration
+        .volumes
+        .iter()
+        .filter_map(|volume| Some((volume.source_id.clone(), volume.id.clone()?)))
+        .collect();
+    let mut taken: HashSet<String> = target
+        .list_machines(&migration.target_app)
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to list the machines of {}: {}",
+                migration.target_app, e
+            )
+        })?
+        .iter()
+        .filter(|machine| fleets::is_live(machine))
+        .filter_map(|machine| machine["name"].as_str())
+        .map(str::to_string)
+        .collect();
+
+    for index in 0..migration.machines.len() {
+        if migration.machines[index].state == MigrationState::Migrated {
+            continue;
+        }
+        let source_id = migration.machines[index].source_id.clone();
+        let Some(machine) = machines
+            .iter()
+            .find(|machine| machine["id"] == source_id.as_str())
+        else {
+            failed(
+                &mut migration.machines[index],
+                "Gone from the source app".to_string(),
+            );
+            save(&store, &mut migration).await?;
+            continue;
+        };
+
+        let mut config = machine["config"].clone();
+        if let Some(metadata) = config.get_mut("metadata").and_then(Value::as_object_mut) {
+            metadata.remove(MANAGED_METADATA_KEY);
+        }
+        let mut unmigrated = None;
+        if let Some(mounts) = config.get_mut("mounts").and_then(Value::as_array_mut) {
+            for mount in mounts {
+                let volume = mount["volume"].as_str().unwrap_or_default().to_string();
+                match volume_ids.get(&volume) {
+                    Some(id) => mount["volume"] = json!(id),
+                    None => unmigrated = Some(volume),
+                }
+            }
+        }
+        if let Some(volume) = unmigrated {
+            failed(
+                &mut migration.machines[index],
+                format!("Its volume {} wasn't migrated", volume),
+            );
+            save(&store, &mut migration).await?;
+            continue;
+        }
+
+        let name = machine["name"].as_str().map(|name| free_name(name, &taken));
+        let create = json!({ "name": name, "region": machine["region"], "config": config });
+        match target.create_machine(&migration.target_app, &create).await {
+            Ok(created) => {
+                if let Some(name) = &name {
+                    taken.insert(name.clone());
+                }
+                migration.machines[index].name = name;
+                migrated(&mut migration.machines[index], created["id"].as_str());
+            }
+            Err(e) => failed(
+                &mut migration.machines[index],
+                format!("Failed to create: {}", e),
+            ),
+        }
+        save(&store, &mut migration).await?;
+    }
+
+    let failures = migration
+        .volumes
+        .iter()
+        .chain(&migration.machines)
+        .filter(|resource| resource.state == MigrationState::Failed)
+        .count();
+    if failures > 0 {
+        return Err(format!(
+            "{} resources weren't migrated; see /v0/migrate/org/{}",
+            failures, migration.id
+        ));
+    }
+    Ok(json!(migration))
+}
+
+/// Whether the caller's own token can read `app`, before it's acted on with an org's.
+async fn caller_reads(
+    req: &HttpRequest,
+    http_client: &reqwest::Client,
+    app: &str,
+    use_private_api: bool,
+) -> Result<(), HttpResponse> {
+    let identity = auth::require_role(req, None)?;
+    if !identity.may_touch(app) {
+        return Err(AppError::forbidden(format!("You can't act on {}", app)).into_response());
+    }
+    let (headers, api_hostname) = prepare_request(req, use_private_api)?;
+    let caller =
+        FlyClient::new(http_client.clone(), headers, api_hostname).with_request_checks(req);
+    match caller.get_app(app).await {
+        Ok(_) => Ok(()),
+        Err(FlyError::Status { status, .. })
+            if [
+                StatusCode::NOT_FOUND,
+                StatusCode::FORBIDDEN,
+                StatusCode::UNAUTHORIZED,
+            ]
+            .contains(&status) =>
+        {
+            Err(AppError::forbidden(format!("Your token can't read {}", app)).into_response())
+        }
+        Err(e) => {
+            Err(AppError::bad_gateway(format!("Failed to look up {}: {}", app, e)).into_response())
+        }
+    }
+}
+
+/// A client acting in `org` with its `[orgs]` token, rather than the caller's, for
+/// callers with the org's role.
+fn org_client(
+    req: &HttpRequest,
+    config: &Config,
+    http_client: &reqwest::Client,
+    org: &str,
+    use_private_api: bool,
+) -> Result<FlyClient, HttpResponse> {
+    let Some(org_config) = config.orgs.get(org) else {
+        return Err(AppError::bad_request(format!(
+            "No token for org {}: set [orgs.{}] fly_token",
+            org, org
+        ))
+        .into_response());
+    };
+    let Some(role) = &org_config.role else {
+        return Err(AppError::forbidden(format!(
+            "No one may act in org {}: set [orgs.{}] role",
+            org, org
+        ))
+        .into_response());
+    };
+    auth::require_role(req, Some(role))?;
+    let (mut headers, api_hostname) = prepare_request(req, use_private_api)?;
+    let authorization = HeaderValue::from_str(&authorization_value(&org_config.fly_token))
+        .map_err(|e| AppError::internal(e.to_string()).into_response())?;
+    headers.insert(AUTHORIZATION, authorization);
+    Ok(FlyClient::new(http_client.clone(), headers, api_hostname).with_request_checks(req))
+}
+
+/// Queues copying an app's machines and volume data into an app in another org. The
+/// response has the migration, whose progress `GET /v0/migrate/org/{id}` reports, and
+/// the job running it.
+#[post("/v0/migrate/org")]
+async fn migrate_org(
+    req: HttpRequest,
+    body: web::Json<OrgMigrationRequest>,
+    config: web::Data<Config>,
+    store: web::Data<Store>,
+    jobs: web::Data<Jobs>,
+    http_client: web::Data<reqwest::Client>,
+) -> impl Responder {
+    let body = body.into_inner();
+    if body.source_org == body.target_org {
+        return AppError::bad_request("source_org and target_org are the same").into_response();
+    }
+    let source = match org_client(
+        &req,
+        &config,
+        &http_client,
+        &body.source_org,
+        body.use_private_api,
+    ) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let target = match org_client(
+        &req,
+        &config,
+        &http_client,
+        &body.target_org,
+        body.use_private_api,
+    ) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    if let Err(response) =
+        caller_reads(&req, &http_client, &body.source_app, body.use_private_api).await
+    {
+        return response;
+    }
+
+    let mut id = [0u8; 8];
+    getrandom::fill(&mut id).expect("the OS random number generator is available");
+    let now = Utc::now();
+    let mut migration = OrgMigration {
+        id: hex::encode(id),
+        source_app: body.source_app,
+        source_org: body.source_org,
+        target_app: body.target_app,
+        target_org: body.target_org,
+        volumes: Vec::new(),
+        machines: Vec::new(),
+        created_at: now,
+        updated_at: now,
+    };
+    if let Err(e) = save(&store, &mut migration).await {
+        return AppError::internal(e).into_response();
+    }
+
+    let group = jobs.group_for(&migration.source_app);
+    let needs_approval = jobs.requires_approval(&group);
+    let require_unique_zone = config.capacity.require_unique_zone;
+    let initial = migration.clone();
+    let work: Work = Box::new(move || {
+        Box::pin(migrate(
+            source.clone(),
+            target.clone(),
+            store.clone(),
+            require_unique_zone,
+            initial.clone(),
+        ))
+    });
+    let job = Jobs::submit(
+        &jobs,
+        "migrate",
+        Some(&migration.source_app),
+        group,
+        needs_approval,
+        jobs::submitter(&req),
+        work,
+    );
+    HttpResponse::Accepted().json(json!({ "migration": migration, "job": job }))
+}
+
+#[get("/v0/migrate/org/{id}")]
+async fn get_migration(
+    req: HttpRequest,
+    path: web::Path<String>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    match store.get::<OrgMigration>(MIGRATIONS, &path).await {
+        Ok(Some(migration))
+            if namespaces::of(&req)
+                .is_none_or(|namespace| namespace.owns(&migration.source_app)) =>
+        {
+            HttpResponse::Ok().json(migration)
+        }
+        Ok(_) => AppError::not_found(format!("No migration {}", path)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(migrate_org).service(get_migration);
+}
@@ -0,0 +1,2534 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewMachineRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub provenance: Option<Provenance>,
+    /// Where the caller is, as a region, e.g. `lhr`, or `latitude,longitude`, for flyd to
+    /// pick the nearest region when `region` isn't set.
+    pub near: Option<String>,
+    #[serde(flatten)]
+    pub config: MachineConfig,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MachineConfig {
+    pub name: Option<String>,
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<FlyMachineConfig>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A machine as the Machines API returns it, with what flyd adds. Everything else passes
+/// through in `other`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Machine {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// E.g. `started`, `stopped` or `destroyed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// The machine's version, which changes with every update.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<FlyMachineConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_insight: Option<HealthInsight>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Annotations>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The Machines API's machine `config`, typed as far as flyd checks it before forwarding.
+/// Everything else passes through in `other`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct FlyMachineConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest: Option<MachineGuest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<Vec<MachineService>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mounts: Option<Vec<MachineMount>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart: Option<MachineRestart>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<std::collections::BTreeMap<String, String>>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MachineGuest {
+    /// `shared` or `performance`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u32>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MachineService {
+    /// `tcp` or `udp`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub internal_port: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<Vec<MachinePort>>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Either one `port` or the range `start_port` to `end_port`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MachinePort {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_port: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_port: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handlers: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MachineMount {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MachineRestart {
+    /// `no`, `always`, `on-failure` or `spot-price`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The body of every error flyd answers with.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ErrorDetail {
+    /// The status's reason in snake case, e.g. `not_found`, unless a more specific code
+    /// applies.
+    pub code: String,
+    pub message: String,
+    /// The Machines API's status, when the error came from Fly.
+    pub upstream_status: Option<u16>,
+    pub request_id: Option<String>,
+    /// What's wrong with each field of an invalid request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FieldError {
+    /// Dotted path to the field, e.g. `config.guest.cpu_kind`.
+    pub field: String,
+    pub message: String,
+}
+
+/// One event of a `/v0/machines/watch` stream. A `created` machine has no previous state,
+/// and a `destroyed` one no state; the initial `snapshot` is the list of machines instead.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineChange {
+    pub machine_id: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub state: Option<String>,
+    pub previous_state: Option<String>,
+}
+
+/// `GET /version`. `latest_version` is the newest release the update check has seen.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct VersionInfo {
+    pub version: String,
+    pub git_sha: String,
+    pub built_at: Option<DateTime<Utc>>,
+    pub features: Vec<String>,
+    pub latest_version: Option<String>,
+    pub release_url: Option<String>,
+    pub update_available: bool,
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+/// `GET /v0/machines/logs`: the machine's recent logs, then new ones as they arrive with
+/// `follow`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineLogsQuery {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub follow: bool,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// One line of a machine's output, sent as a `log` event.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct LogLine {
+    pub timestamp: Option<String>,
+    pub message: String,
+    /// e.g. `info`, or `stderr` for Docker machines.
+    pub level: Option<String>,
+    pub region: Option<String>,
+    pub machine_id: Option<String>,
+}
+
+/// A named create body. `POST /v0/machines/new?template=<name>` starts from it: fields the
+/// request sets win, and objects are merged key by key.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineTemplate {
+    pub name: String,
+    pub description: Option<String>,
+    /// Shaped like a Machines API create body, e.g. `region` and `config`.
+    pub machine: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TemplateQuery {
+    pub template: Option<String>,
+}
+
+/// A named machine query, shared by everyone listing, watching or exporting metrics with
+/// `view=<name>`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SavedView {
+    pub name: String,
+    pub description: Option<String>,
+    /// A `q=` expression machines must match.
+    pub q: Option<String>,
+    /// Dotted paths to keep of each machine, e.g. `id` or `config.image`; all of it when
+    /// empty.
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Dotted paths to order machines by, each descending when prefixed with `-`, e.g.
+    /// `["region", "-created_at"]`.
+    #[serde(default)]
+    pub sort: Vec<String>,
+    #[serde(default)]
+    pub updated_by: Option<String>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ViewMetricsQuery {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// `POST /v0/machines/batch_new`: `count` machines from one config. With `regions`, the
+/// machines go to each in turn; with a `name`, each is named `{name}-{index}`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BatchNewMachinesRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub count: usize,
+    #[serde(default)]
+    pub regions: Vec<String>,
+    /// Wait for each machine to reach `started` before reporting it.
+    #[serde(default)]
+    pub wait_for_started: bool,
+    /// How long to wait for each machine, 60s by default.
+    pub timeout_secs: Option<u64>,
+    pub provenance: Option<Provenance>,
+    #[serde(flatten)]
+    pub config: MachineConfig,
+}
+
+/// One machine of a batch. A machine created but not started in time has both.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BatchMachineResult {
+    pub index: usize,
+    pub region: Option<String>,
+    pub machine: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+/// `?dry_run=true` on `/v0/machines/new` and `/v0/machines/batch_new`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DryRunQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// What a dry run would have sent Fly, secrets masked, and what the machine would cost
+/// a month if it stayed started.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineDryRun {
+    pub payload: serde_json::Value,
+    pub estimated_monthly_cost: f64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BatchDryRun {
+    pub estimated_monthly_cost: f64,
+    pub machines: Vec<MachineDryRun>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BatchNewMachinesReport {
+    pub created: usize,
+    pub failed: usize,
+    pub machines: Vec<BatchMachineResult>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkAction {
+    Stop,
+    Restart,
+    Destroy,
+}
+
+/// Machines matching every field set. Destroyed machines never match.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MachineFilter {
+    pub region: Option<String>,
+    pub state: Option<String>,
+    pub name_prefix: Option<String>,
+    #[serde(default)]
+    pub metadata: std::collections::BTreeMap<String, String>,
+}
+
+/// `POST /v0/machines/bulk`: `action` on every machine of the app matching `filter`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BulkMachinesRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub action: BulkAction,
+    pub filter: MachineFilter,
+    /// Report the matching machines without touching them.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// As for `/v0/machines/destroy`, for each machine destroyed.
+    #[serde(default)]
+    pub acknowledge: Vec<String>,
+    /// Acts on machines cordoned through `/v0/machines/cordon` too; they're skipped
+    /// otherwise.
+    #[serde(default)]
+    pub include_cordoned: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BulkMachineResult {
+    pub machine_id: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BulkMachinesReport {
+    pub action: BulkAction,
+    pub dry_run: bool,
+    pub matched: usize,
+    pub failed: usize,
+    pub machines: Vec<BulkMachineResult>,
+}
+
+/// A machine flyd stopped restarting after it kept coming back broken.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct QuarantinedMachine {
+    pub app: String,
+    /// The machine's name, or its ID if it has none.
+    pub machine: String,
+    pub machine_id: Option<String>,
+    pub attempts: u32,
+    pub quarantined_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ReleaseQuarantineRequest {
+    pub app_name: String,
+    pub machine: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// `POST /v0/pool/checkout`. Without a region, the machine comes from any of the app's
+/// pools.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PoolCheckoutRequest {
+    pub app_name: String,
+    pub region: Option<String>,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// Keeps an app's started machines at the latest sample of `metric` divided by
+/// `target_per_machine`, rounded up, within `min_machines..=max_machines`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AutoscalePolicy {
+    pub app: String,
+    pub metric: String,
+    pub target_per_machine: f64,
+    #[serde(default)]
+    pub min_machines: usize,
+    pub max_machines: usize,
+}
+
+/// `GET /v0/admin/features`: whether a subsystem runs on this instance.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FeatureFlag {
+    pub name: String,
+    pub enabled: bool,
+    /// What the config says, which applies again after a restart.
+    pub configured: bool,
+}
+
+/// `POST /v0/admin/features/{name}`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SetFeatureFlag {
+    pub enabled: bool,
+}
+
+/// `POST /v0/autoscale/metrics`, e.g. a queue's depth.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MetricSample {
+    pub app: String,
+    pub metric: String,
+    pub value: f64,
+}
+
+/// `POST /v0/webhooks`. Without a secret, flyd generates one.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewWebhook {
+    pub url: String,
+    pub apps: Vec<String>,
+    /// States to deliver transitions into, e.g. `stopped`. Empty means all.
+    #[serde(default)]
+    pub states: Vec<String>,
+    pub secret: Option<String>,
+}
+
+/// A URL flyd posts the `machine.state` events of `apps` to, signed like machine state
+/// callbacks: `X-Flyd-Signature: sha256=<hex HMAC of "{timestamp}.{body}">`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub apps: Vec<String>,
+    pub states: Vec<String>,
+    /// Only returned when the webhook is registered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A region that recently had no capacity for machines of a size.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CapacityRejection {
+    pub region: String,
+    /// e.g. `shared-1x-256mb`.
+    pub size: String,
+    pub rejecting_since: DateTime<Utc>,
+    pub last_rejected_at: DateTime<Utc>,
+    pub rejections: u64,
+    pub last_error: String,
+}
+
+/// Where a machine's code came from. Kept in its metadata as `flyd_git_sha`,
+/// `flyd_build_url` and `flyd_sbom`, so it travels with the config.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provenance {
+    pub git_sha: Option<String>,
+    /// The CI run that built the image.
+    pub build_url: Option<String>,
+    /// Where the image's SBOM is, as a URL or a digest like `sha256:...`.
+    pub sbom: Option<String>,
+}
+
+/// A machine flyd last saw created, updated or deployed with provenance.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineProvenance {
+    pub app: String,
+    pub machine_id: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub image: Option<String>,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// `GET /v0/provenance`: machines matching every filter given. `git_sha` matches by
+/// prefix, so a short SHA works.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ProvenanceQuery {
+    pub app: Option<String>,
+    pub git_sha: Option<String>,
+    pub build_url: Option<String>,
+    pub sbom: Option<String>,
+    pub image: Option<String>,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// New machines may not be placed in `region` for `app` until the block is lifted.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RegionBlock {
+    pub app: String,
+    pub region: String,
+    pub reason: String,
+    pub blocked_by: Option<String>,
+    pub blocked_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PreemptionReason {
+    /// The app's started machines cost more than its `max_monthly_cost`.
+    Budget,
+    /// The machine's region was recently out of capacity.
+    Capacity,
+}
+
+/// A preemptible machine flyd stopped and has yet to start again. The machine's name,
+/// region and config are kept so it can be recreated if it's gone by then.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Preemption {
+    pub app: String,
+    pub machine_id: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub reason: PreemptionReason,
+    pub monthly_cost: f64,
+    pub preempted_at: DateTime<Utc>,
+    pub config: serde_json::Value,
+}
+
+/// How often an app's preemptible machines have been stopped and brought back.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PreemptibleChurn {
+    pub app: String,
+    pub preemptions: u64,
+    /// Preempted machines started again.
+    pub restorations: u64,
+    /// Preempted machines that were gone and created anew.
+    pub recreations: u64,
+    pub last_preempted_at: Option<DateTime<Utc>>,
+}
+
+/// Where an app's or an org's spend stands this month against its budget.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BudgetStatus {
+    /// `app:<name>` or `org:<slug>`.
+    pub scope: String,
+    pub apps: Vec<String>,
+    /// `2026-10`.
+    pub month: String,
+    pub budget: f64,
+    pub hard_cap: Option<f64>,
+    /// Spent so far this month, in USD.
+    pub spent: f64,
+    /// What the started machines and volumes would cost over a whole month.
+    pub run_rate: f64,
+    /// `spent` plus `run_rate` for the rest of the month.
+    pub projected: f64,
+    /// When this month's overspend alert went out.
+    pub alerted_at: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PreemptibleReport {
+    pub churn: PreemptibleChurn,
+    pub preempted: Vec<Preemption>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct EvacuationQuery {
+    pub region: String,
+    /// Comma-separated regions to move machines to. Defaults to the region's
+    /// `capacity.fallback_regions`.
+    pub to: Option<String>,
+    /// Also block new placements in `region` until lifted.
+    #[serde(default)]
+    pub block: bool,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// `POST /v0/volumes/new`. The rest of the body is Fly's create volume request, e.g.
+/// `name`, `region` and `size_gb`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewVolumeRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    #[serde(flatten)]
+    pub config: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ListVolumesRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct VolumeRequest {
+    pub app_name: String,
+    pub volume_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ExtendVolumeRequest {
+    pub app_name: String,
+    pub volume_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// The new size; volumes only grow.
+    pub size_gb: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ListMachinesRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    #[serde(default)]
+    pub include_deleted: bool,
+    pub region: Option<String>,
+    /// Only machines matching this expression, e.g.
+    /// `state == "started" && metadata.team == "payments"`.
+    pub q: Option<String>,
+    /// A saved view to filter, sort and project the machines with. A `q` narrows it
+    /// further.
+    pub view: Option<String>,
+    /// Only machines in one of these comma-separated states, e.g. `started,stopped`.
+    pub state: Option<String>,
+    /// Only machines whose name contains this.
+    pub name: Option<String>,
+    /// Only machines with all of these comma-separated metadata, each `key=value` or just
+    /// `key` for any value, e.g. `tenant=acme,tier`.
+    pub metadata: Option<String>,
+    /// Comma-separated fields to order by, each descending when prefixed with `-`, e.g.
+    /// `-created_at,name`. Overrides the view's order.
+    pub sort: Option<String>,
+    /// Answers with a `MachinePage` of at most this many machines instead of all of them.
+    pub limit: Option<usize>,
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    /// Machines to skip, for callers that page by position instead of by cursor.
+    pub offset: Option<usize>,
+}
+
+/// One page of `GET /v0/machines/list`, when it's asked for a `limit`, `cursor` or
+/// `offset`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachinePage {
+    pub machines: Vec<serde_json::Value>,
+    /// Machines matching the filters, across every page.
+    pub total: usize,
+    /// Where the next page starts; unset on the last.
+    pub next_cursor: Option<String>,
+}
+
+/// `GET /v0/machines/list_all`: the filters, sorting and paging of `ListMachinesRequest`,
+/// across several apps.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ListAllMachinesRequest {
+    /// Comma-separated apps, e.g. `web,worker`.
+    pub apps: Option<String>,
+    /// Every app in this org, instead of `apps`.
+    pub org: Option<String>,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub region: Option<String>,
+    /// Comma-separated regions, each listed separately from every app.
+    pub regions: Option<String>,
+    /// Fails the whole request when any app or region can't be listed, instead of
+    /// answering with the rest.
+    #[serde(default)]
+    pub strict: bool,
+    pub q: Option<String>,
+    pub view: Option<String>,
+    pub state: Option<String>,
+    pub name: Option<String>,
+    pub metadata: Option<String>,
+    pub sort: Option<String>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub offset: Option<usize>,
+}
+
+impl ListAllMachinesRequest {
+    /// The same filters, sorting and paging for one app.
+    pub fn for_app(&self, app_name: &str) -> ListMachinesRequest {
+        ListMachinesRequest {
+            app_name: app_name.to_string(),
+            use_private_api: self.use_private_api,
+            include_deleted: false,
+            region: self.region.clone(),
+            q: self.q.clone(),
+            view: self.view.clone(),
+            state: self.state.clone(),
+            name: self.name.clone(),
+            metadata: self.metadata.clone(),
+            sort: self.sort.clone(),
+            limit: self.limit,
+            cursor: self.cursor.clone(),
+            offset: self.offset,
+        }
+    }
+}
+
+/// The machines of several apps, each with its `app`. Apps and regions that couldn't be
+/// listed are in `errors` instead of failing the rest, unless the request was `strict`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MultiAppMachines {
+    pub machines: Vec<serde_json::Value>,
+    pub total: usize,
+    pub next_cursor: Option<String>,
+    /// Whether some machines are missing because of `errors`.
+    pub partial: bool,
+    pub errors: Vec<SourceError>,
+}
+
+/// An app, or one of its regions, that a fan-out couldn't list.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SourceError {
+    pub app: String,
+    pub region: Option<String>,
+    pub error: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// `POST /v0/machines/metadata`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SetMachineMetadataRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub key: String,
+    pub value: String,
+}
+
+/// `DELETE /v0/machines/metadata`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DeleteMachineMetadataRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub key: String,
+}
+
+/// `POST /v0/machines/metadata/bulk`: sets the keys in `set`, then deletes those in
+/// `unset`, on every machine of the app matching both `filter` and the `q=` expression.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BulkMetadataRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    #[serde(default)]
+    pub filter: MachineFilter,
+    pub q: Option<String>,
+    #[serde(default)]
+    pub set: std::collections::BTreeMap<String, String>,
+    #[serde(default)]
+    pub unset: Vec<String>,
+    /// Report the matching machines without touching them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BulkMetadataReport {
+    pub dry_run: bool,
+    pub matched: usize,
+    pub failed: usize,
+    pub machines: Vec<BulkMachineResult>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DestroyMachineRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Codes of the high-impact consequences the caller accepts, e.g. `last_machine`.
+    #[serde(default)]
+    pub acknowledge: Vec<String>,
+    /// Holds a lease on the machine for the destroy, so it fails fast if someone else
+    /// holds one.
+    #[serde(default)]
+    pub lease: bool,
+    /// Destroys the machine now even with `[trash]` configured.
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+/// A destroyed machine that's only stopped for now, and can be restored until
+/// `destroy_after`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TrashedMachine {
+    pub app: String,
+    pub machine_id: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    /// Restores start machines that were started.
+    pub previous_state: Option<String>,
+    pub trashed_by: Option<String>,
+    pub trashed_at: DateTime<Utc>,
+    pub destroy_after: DateTime<Utc>,
+    /// What destroying it will do, as found when it was trashed.
+    #[serde(default)]
+    pub consequences: Vec<Consequence>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TrashQuery {
+    pub app_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RestoreMachineRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AcquireLeaseRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Defaults to `machines.update_lease_ttl_secs`.
+    pub ttl_secs: Option<u64>,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ReleaseLeaseRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// As returned by acquire.
+    pub nonce: String,
+}
+
+/// What destroying a machine would do beyond removing it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Impact {
+    pub app: String,
+    pub machine_id: String,
+    pub consequences: Vec<Consequence>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Consequence {
+    /// e.g. `orphans_volume`, `last_in_region` or `loses_standby`.
+    pub code: String,
+    /// High-impact consequences must be acknowledged when `machines.require_impact_ack`
+    /// is on.
+    pub high_impact: bool,
+    pub message: String,
+    /// The other resource affected, e.g. a volume or machine ID.
+    pub resource: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WaitMachineQuery {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// `started` (the default), `stopped`, `suspended` or `destroyed`.
+    pub state: Option<String>,
+    /// Seconds to wait before giving up with 408. Defaults to 60.
+    pub timeout: Option<u64>,
+    /// Waits for this version of the machine, e.g. the one an update returned.
+    pub instance_id: Option<String>,
+}
+
+/// `POST /v0/machines/start`, `/stop`, `/restart` and `/signal`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineLifecycleRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// e.g. `SIGTERM`. Required by `signal`; `stop` and `restart` default to the
+    /// machine's own kill signal.
+    pub signal: Option<String>,
+    /// How long `stop` and `restart` wait for the machine to exit before killing it.
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ExecRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    pub command: Vec<String>,
+    /// Defaults to `exec.default_timeout_secs`, and can't exceed `exec.max_timeout_secs`.
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UpdateMachineRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub region: Option<String>,
+    pub config: serde_json::Value,
+    /// Merges `config` into the machine's current config, as `PATCH` does, instead of
+    /// replacing it.
+    #[serde(default)]
+    pub merge: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewAppRequest {
+    pub app_name: String,
+    /// Defaults to the caller's namespace's `org_slug`.
+    pub org_slug: Option<String>,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ListAppsRequest {
+    /// Defaults to the caller's namespace's `org_slug`.
+    pub org_slug: Option<String>,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AppRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// `POST /v0/secrets/set`. Its `Debug` leaves the values out, so they can't end up in
+/// flyd's logs.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SetSecretsRequest {
+    pub app_name: String,
+    pub secrets: std::collections::BTreeMap<String, String>,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+impl std::fmt::Debug for SetSecretsRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SetSecretsRequest")
+            .field("app_name", &self.app_name)
+            .field("secrets", &self.secrets.keys().collect::<Vec<_>>())
+            .field("use_private_api", &self.use_private_api)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ListSecretsRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UnsetSecretRequest {
+    pub app_name: String,
+    pub name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// An app's secrets' digests as last imported from Fly, and when they last changed.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SecretDigests {
+    pub app: String,
+    pub digests: std::collections::BTreeMap<String, String>,
+    /// Unknown until flyd sees a change, or Fly says when a secret was set.
+    pub changed_at: Option<DateTime<Utc>>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// A started machine that was started before the app's secrets last changed, and so
+/// still has the old values.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StaleMachine {
+    pub id: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SecretDrift {
+    pub app: String,
+    pub changed_at: Option<DateTime<Utc>>,
+    pub checked_at: DateTime<Utc>,
+    pub stale_machines: Vec<StaleMachine>,
+}
+
+/// A volume's snapshots as flyd last saw them upstream, with what breaks the catalog's
+/// policy.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct VolumeSnapshots {
+    pub app: String,
+    pub volume_id: String,
+    pub volume_name: Option<String>,
+    pub region: Option<String>,
+    /// `daily` while Fly takes the volume's automatic snapshots.
+    pub schedule: Option<String>,
+    /// How long Fly keeps the volume's snapshots.
+    pub retention_days: Option<u64>,
+    /// Set once the volume no longer shows up upstream.
+    pub destroyed: bool,
+    pub snapshots: Vec<CatalogedSnapshot>,
+    pub violations: Vec<SnapshotViolation>,
+    pub verified_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CatalogedSnapshot {
+    pub id: String,
+    /// Bytes.
+    pub size: Option<u64>,
+    pub status: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub first_seen_at: DateTime<Utc>,
+    /// When it was last listed upstream.
+    pub verified_at: DateTime<Utc>,
+    /// Set once it stops being listed before its retention runs out.
+    pub missing_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SnapshotViolation {
+    /// A snapshot disappeared before its retention ran out.
+    Missing {
+        snapshot_id: String,
+    },
+    /// No snapshot newer than `max_age_hours`.
+    Stale {
+        latest_at: Option<DateTime<Utc>>,
+        max_age_hours: u64,
+    },
+    TooFew {
+        count: usize,
+        min: usize,
+    },
+    RetentionTooShort {
+        retention_days: u64,
+        min: u64,
+    },
+    /// Fly's automatic daily snapshots are off.
+    BackupsDisabled,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SnapshotCatalogQuery {
+    pub app_name: Option<String>,
+    pub volume_id: Option<String>,
+    /// Only volumes breaking the policy.
+    #[serde(default)]
+    pub violations: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RestartStaleRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    #[serde(default)]
+    pub require_approval: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CloneAppRequest {
+    pub source_app: String,
+    pub target_app: String,
+    pub org_slug: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Secret values to set on the new app. Fly never returns secret values, so they
+    /// can't be copied from the source and have to be supplied here.
+    #[serde(default)]
+    pub secrets: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CloneAppReport {
+    pub app: String,
+    pub volumes: Vec<ClonedResource>,
+    pub machines: Vec<ClonedResource>,
+    pub secrets: Vec<String>,
+    /// Secrets present on the source app that were not supplied in the request.
+    pub uncopied_secrets: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ClonedResource {
+    pub source_id: String,
+    pub id: Option<String>,
+    pub snapshot_id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ExportMachineQuery {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Snapshots the machine's volumes before exporting, rather than pointing at their
+    /// latest existing snapshots.
+    #[serde(default)]
+    pub snapshot: bool,
+}
+
+/// A machine's definition, portable to another app or org: its config with the volumes
+/// it mounts, each by the snapshot to restore it from.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineBundle {
+    pub source_app: String,
+    pub source_machine_id: String,
+    pub name: Option<String>,
+    pub region: String,
+    pub config: serde_json::Value,
+    #[serde(default)]
+    pub volumes: Vec<BundledVolume>,
+    pub exported_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BundledVolume {
+    /// The volume's ID in the source app, as the bundle's `config.mounts` refer to it.
+    pub source_id: String,
+    pub name: String,
+    pub region: String,
+    pub size_gb: u64,
+    /// None when the volume had no snapshot, so it's recreated empty.
+    pub snapshot_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ImportMachineRequest {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Creates the machine and its volumes here instead of the bundle's region.
+    pub region: Option<String>,
+    pub bundle: MachineBundle,
+}
+
+/// `POST /v0/machines/clone`: a new machine with another's config, and whatever's
+/// overridden here.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CloneMachineRequest {
+    pub app_name: String,
+    pub machine_id: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Creates the clone in this app instead of the source's.
+    pub target_app: Option<String>,
+    pub region: Option<String>,
+    pub name: Option<String>,
+    /// Only the fields set replace the source's.
+    pub guest: Option<MachineGuest>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ImportMachineReport {
+    pub machine: serde_json::Value,
+    pub volumes: Vec<ClonedResource>,
+}
+
+/// `POST /v0/migrate/org`. Both orgs need a token under `[orgs]`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct OrgMigrationRequest {
+    pub source_app: String,
+    pub source_org: String,
+    /// Created in `target_org` unless it's already there.
+    pub target_app: String,
+    pub target_org: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// An app being copied into another org, resource by resource.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct OrgMigration {
+    pub id: String,
+    pub source_app: String,
+    pub source_org: String,
+    pub target_app: String,
+    pub target_org: String,
+    pub volumes: Vec<MigratedResource>,
+    pub machines: Vec<MigratedResource>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MigratedResource {
+    pub source_id: String,
+    /// The name in the target app, which gets a suffix when the source's is taken there.
+    pub name: Option<String>,
+    pub id: Option<String>,
+    pub snapshot_id: Option<String>,
+    pub state: MigrationState,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Pending,
+    Migrated,
+    Failed,
+}
+
+/// A lease flyd took itself, kept until it's released so one a crash left behind can be
+/// found again.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HeldLease {
+    pub app: String,
+    pub machine_id: String,
+    pub nonce: String,
+    pub ttl_secs: u64,
+    pub acquired_at: DateTime<Utc>,
+}
+
+/// What the last check of flyd's stored state against Fly found, at startup or through
+/// `POST /v0/admin/consistency`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ConsistencyReport {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Why nothing was checked, e.g. without a Fly backend.
+    pub skipped: Option<String>,
+    pub issues: Vec<ConsistencyIssue>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ConsistencyIssue {
+    pub kind: ConsistencyIssueKind,
+    pub app: Option<String>,
+    /// The schedule, fleet, machine or migration the issue is with.
+    pub resource: String,
+    pub detail: String,
+    pub repaired: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsistencyIssueKind {
+    /// Paused when repaired, so it stops submitting jobs that can only fail.
+    ScheduleAppMissing,
+    /// Only flagged; dropping a fleet is left to its owner.
+    FleetAppMissing,
+    /// Released when repaired.
+    LeaseUnreleased,
+    /// Its pending resources are marked failed when repaired, so a retry picks them up.
+    MigrationInterrupted,
+}
+
+/// One dependency `/health/ready` probed, or one thing `flyd check` checked.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DependencyStatus {
+    pub ok: bool,
+    /// What was probed, e.g. the Machines API's URL.
+    pub target: Option<String>,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// `GET /health/ready`: ready while every dependency is.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub draining: bool,
+    /// Keyed by dependency, e.g. `fly_api`, `store` or `consistency`.
+    pub checks: std::collections::BTreeMap<String, DependencyStatus>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// `POST /v0/deploy_breaker/error_rates`: the fraction of an app's requests failing.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ErrorRateSample {
+    pub app: String,
+    pub error_rate: f64,
+}
+
+/// An app whose deploys are paused, its error rate having reached
+/// `deploy_breaker.threshold`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TrippedApp {
+    pub app: String,
+    /// The latest error rate seen.
+    pub error_rate: f64,
+    pub threshold: f64,
+    /// `prometheus`, or `webhook` for a pushed error rate.
+    pub source: String,
+    pub tripped_at: DateTime<Utc>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// What `flyd check` prints: passing while every check does.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PreflightReport {
+    pub ok: bool,
+    /// Keyed by what was checked, e.g. `config`, `upstream.public`, `store`,
+    /// `token.FLY_API_TOKEN` or `policy.namespaces`.
+    pub checks: std::collections::BTreeMap<String, DependencyStatus>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// `GET /ready`: ready once the startup consistency check has run.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Set once flyd is shutting down.
+    #[serde(default)]
+    pub draining: bool,
+    pub consistency: Option<ConsistencyReport>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FleetQuery {
+    pub app: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    pub mode: Option<FleetMode>,
+    pub conflict_policy: Option<ConflictPolicy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FleetSpec {
+    pub app: String,
+    #[serde(default)]
+    pub mode: FleetMode,
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    pub machines: Vec<MachineSpec>,
+    pub adopted_at: DateTime<Utc>,
+}
+
+/// `Detect` only reports drift; `Enforce` also corrects it.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FleetMode {
+    #[default]
+    Enforce,
+    Detect,
+}
+
+/// What reconciling does with a config key changed both in the spec and, out of band, on
+/// the machine: `Overwrite` puts the spec's value back, `Keep` leaves the machine's.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    #[default]
+    Overwrite,
+    Keep,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineSpec {
+    pub name: String,
+    pub region: String,
+    pub config: serde_json::Value,
+    /// The config flyd last put on the machine: what the spec and the live config are
+    /// each compared with to tell whose change a difference is.
+    #[serde(default)]
+    pub last_applied: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    Missing { name: String },
+    ConfigChanged { name: String, machine_id: String },
+    Unexpected { name: String, machine_id: String },
+}
+
+impl Drift {
+    pub fn machine_id(&self) -> Option<&str> {
+        match self {
+            Drift::Missing { .. } => None,
+            Drift::ConfigChanged { machine_id, .. } | Drift::Unexpected { machine_id, .. } => {
+                Some(machine_id)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FleetDrift {
+    pub app: String,
+    pub mode: FleetMode,
+    pub checked_at: DateTime<Utc>,
+    pub drift: Vec<Drift>,
+    #[serde(default)]
+    pub conflicts: Vec<ConfigConflict>,
+}
+
+/// A config key the spec and the live machine both changed since it was last applied.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct ConfigConflict {
+    pub name: String,
+    pub machine_id: String,
+    /// JSON Pointer to the key, e.g. `/guest/memory_mb`.
+    pub path: String,
+    pub last_applied: Option<serde_json::Value>,
+    pub spec: Option<serde_json::Value>,
+    pub live: Option<serde_json::Value>,
+    pub resolution: ConflictPolicy,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Event {
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    pub kind: String,
+    pub app: Option<String>,
+    pub machine_id: Option<String>,
+    pub detail: serde_json::Value,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct EventsQuery {
+    pub app: Option<String>,
+    pub kind: Option<String>,
+    pub limit: Option<usize>,
+    /// Events with a lower ID, read from the store, for history older than the recent
+    /// events flyd keeps in memory.
+    pub before: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FleetReport {
+    pub name: String,
+    pub generated_at: DateTime<Utc>,
+    pub period_start: DateTime<Utc>,
+    pub apps: Vec<AppSummary>,
+    pub total_machines: usize,
+    pub total_started: usize,
+    pub estimated_monthly_cost_usd: f64,
+    /// Counts of notable events (drift, corrections, failures) seen during the period.
+    pub incidents: std::collections::BTreeMap<String, usize>,
+    pub recommendations: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AppSummary {
+    pub app: String,
+    pub machines: usize,
+    pub by_state: std::collections::BTreeMap<String, usize>,
+    pub by_region: std::collections::BTreeMap<String, usize>,
+    pub volumes: usize,
+    pub estimated_monthly_cost_usd: f64,
+    pub drift: Vec<Drift>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SloStatus {
+    /// `upstream` for Fly API calls, `flyd` for flyd's own endpoints.
+    pub scope: String,
+    pub endpoint: String,
+    pub objective: f64,
+    pub latency_ms: u64,
+    /// Requests and bad (failed or slow) requests over the long window.
+    pub requests: u64,
+    pub bad: u64,
+    pub short_burn_rate: f64,
+    pub long_burn_rate: f64,
+    pub alerting: bool,
+}
+
+/// Requests per caller and endpoint for one UTC day.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UsageRecord {
+    pub day: String,
+    pub subject: String,
+    pub endpoint: String,
+    pub requests: u64,
+    pub errors: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UsageQuery {
+    /// `YYYY-MM-DD`; defaults to today.
+    pub day: Option<String>,
+    pub subject: Option<String>,
+}
+
+/// One mutating request, as the audit log records it. The caller's token is only ever
+/// kept as a hash.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub at: DateTime<Utc>,
+    pub subject: String,
+    /// SHA-256 of the request's `Authorization` header, if it sent one.
+    pub token_hash: Option<String>,
+    pub method: String,
+    /// The route, e.g. `/v0/machines/destroy`, or the path when no route matched.
+    pub endpoint: String,
+    pub app: Option<String>,
+    pub machine_id: Option<String>,
+    pub status: u16,
+    /// What the Machines API answered the last call made for the request, if it made any.
+    pub upstream_status: Option<u16>,
+    pub latency_ms: f64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AuditQuery {
+    pub app_name: Option<String>,
+    pub machine_id: Option<String>,
+    pub subject: Option<String>,
+    /// RFC 3339.
+    pub since: Option<DateTime<Utc>>,
+    /// Most recent first; defaults to 100.
+    pub limit: Option<usize>,
+}
+
+/// Where the Machines API answered with something flyd's model of it doesn't expect,
+/// seen with `schemas.strict`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SchemaDrift {
+    /// The model the answer was checked against, e.g. `Machine`.
+    pub schema: String,
+    /// E.g. `GET /v1/apps/{app}/machines/{id}`.
+    pub endpoint: String,
+    /// E.g. `config.guest.memory_mb`.
+    pub field: String,
+    pub message: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub count: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PingQuery {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Port to probe; defaults to the machine's service ports.
+    pub port: Option<u16>,
+    /// Also GET this path on each open port, e.g. `/healthz`.
+    pub http_path: Option<String>,
+}
+
+/// `GET /v0/machines/proxy`: the machine port a WebSocket tunnels to.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PortForwardQuery {
+    pub app_name: String,
+    pub machine_id: String,
+    pub port: u16,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineUsageQuery {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageSource {
+    /// A shell command run in the machine through exec, reading `/proc` and `df`.
+    Exec,
+    /// An agent in the machine, asked over the private network.
+    Agent,
+}
+
+/// What a guest agent answers `machine_usage.agent_path` with.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AgentUsage {
+    /// Of all the machine's CPUs together, 0 to 100.
+    pub cpu_percent: f64,
+    pub memory_used_bytes: u64,
+    pub memory_total_bytes: u64,
+    pub disk_used_bytes: u64,
+    pub disk_total_bytes: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CpuUsage {
+    /// Of all the machine's CPUs together, 0 to 100.
+    pub percent: f64,
+    /// From the machine's guest config.
+    pub cpus: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ByteUsage {
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+    pub percent: f64,
+}
+
+/// `GET /v0/machines/{id}/usage`: what a machine is using right now, against what it
+/// has, e.g. for right-sizing.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineUsage {
+    pub machine_id: String,
+    pub source: UsageSource,
+    pub cpu: CpuUsage,
+    pub memory: ByteUsage,
+    /// Of `machine_usage.disk_path`.
+    pub disk: ByteUsage,
+    pub sampled_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reachability {
+    /// At least one port accepted a connection.
+    Reachable,
+    /// The machine answered but refused every port: started, but the app isn't listening.
+    NotListening,
+    /// No answer at all, so the machine (or the network path to it) is down.
+    Unreachable,
+    /// Fly doesn't report the machine as started, so it wasn't probed.
+    NotStarted,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PortProbe {
+    pub port: u16,
+    pub open: bool,
+    pub rtt_ms: Option<f64>,
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PingReport {
+    pub machine_id: String,
+    pub state: String,
+    pub address: Option<String>,
+    pub reachability: Reachability,
+    pub probes: Vec<PortProbe>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckProtocol {
+    /// A GET answered with a 2xx or 3xx.
+    #[default]
+    Http,
+    /// `grpc.health.v1.Health/Check` over plaintext HTTP/2, answered `SERVING`.
+    Grpc,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CheckMachineQuery {
+    pub app_name: String,
+    #[serde(default)]
+    pub use_private_api: bool,
+    /// Defaults to the machine's first service port.
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub protocol: CheckProtocol,
+    /// For HTTP checks; defaults to `/`.
+    pub path: Option<String>,
+    /// For gRPC checks: the service to ask about; the server as a whole when unset.
+    pub service: Option<String>,
+}
+
+/// `GET /v0/machines/{id}/check`: what the machine's service said when asked directly.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ServiceCheckReport {
+    pub machine_id: String,
+    pub state: String,
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub protocol: CheckProtocol,
+    pub healthy: bool,
+    /// `SERVING`, `NOT_SERVING`, `SERVICE_UNKNOWN` or `UNKNOWN` from gRPC checks.
+    pub serving_status: Option<String>,
+    pub http_status: Option<u16>,
+    /// Set when the gRPC call itself failed, e.g. 12 when the server has no health service.
+    pub grpc_status: Option<u32>,
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// Body of `POST /v0/callbacks/machine_state`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MachineStateCallback {
+    pub app: String,
+    pub machine_id: String,
+    pub state: String,
+    pub previous_state: Option<String>,
+}
+
+/// Where a request's time went. Upstream phases are summed over every Machines API call
+/// the request made.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct RequestPhases {
+    pub auth_ms: Option<f64>,
+    pub policy_ms: Option<f64>,
+    pub upstream_calls: u32,
+    /// Only counts new connections; pooled ones connect in no time.
+    pub upstream_connect_ms: Option<f64>,
+    /// From sending each upstream request to its response headers, connecting included.
+    pub upstream_ttfb_ms: Option<f64>,
+    /// From the last upstream response's headers until flyd's response was ready.
+    pub body_ms: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SlowRequest {
+    pub at: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    pub phases: RequestPhases,
+}
+
+/// One app's inventory at a point in time, as written by scheduled snapshots.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct InventorySnapshot {
+    pub app: String,
+    pub taken_at: DateTime<Utc>,
+    pub machines: Vec<serde_json::Value>,
+    pub volumes: Vec<serde_json::Value>,
+    pub fleet: Option<FleetSpec>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SnapshotInfo {
+    pub app: String,
+    /// Pass to `GET /v0/snapshots/{app}/{name}`.
+    pub name: String,
+    pub taken_at: Option<DateTime<Utc>>,
+    pub size_bytes: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SnapshotQuery {
+    pub app: String,
+}
+
+/// A stored job output. `url` can be fetched without credentials until `expires_at`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Artifact {
+    pub key: String,
+    pub url: String,
+    pub size_bytes: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ArtifactUpload {
+    pub app: String,
+    /// File name, e.g. `stdout.log`; stored under a timestamped key.
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ArtifactQuery {
+    pub key: String,
+}
+
+/// What a machine's recent exits say about it, from its Machines API events. Only present
+/// on machines that have crashed.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HealthInsight {
+    /// The most recent crash was an OOM kill.
+    pub oom_killed: bool,
+    pub crash_looping: bool,
+    /// Crashes, and how many of them were OOM kills, within the crash loop window.
+    pub recent_crashes: usize,
+    pub recent_oom_kills: usize,
+    pub last_exit_code: Option<i64>,
+    pub last_crash_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    /// Its turn came, and it holds its group's slot until approved or rejected.
+    AwaitingApproval,
+    Running,
+    /// An attempt failed; it holds its slot while it backs off before the next.
+    Retrying,
+    Succeeded,
+    Failed,
+    Rejected,
+    /// Canceled before it finished. A running attempt is stopped where it was, and the
+    /// leases it held are released.
+    Canceled,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Approval {
+    /// `None` until decided.
+    pub approved: Option<bool>,
+    pub decided_by: Option<String>,
+    pub decided_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Job {
+    pub id: u64,
+    /// What the job does, e.g. `deploy`.
+    pub kind: String,
+    pub app: Option<String>,
+    /// The concurrency group the job waits its turn in.
+    pub group: String,
+    pub state: JobState,
+    /// 1 for the next queued job in the group to run.
+    pub queue_position: Option<usize>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    pub result: Option<serde_json::Value>,
+    /// How far the running attempt has got, for jobs that count their steps.
+    #[serde(default)]
+    pub progress: Option<JobProgress>,
+    /// Present on jobs that need approval to run.
+    pub approval: Option<Approval>,
+    /// The schedule that submitted the job.
+    pub schedule_id: Option<String>,
+    /// Failed attempts are retried up to this many times.
+    pub max_retries: u32,
+    pub retries: u32,
+    pub next_retry_at: Option<DateTime<Utc>>,
+    /// Every run of the job, oldest first.
+    pub attempts: Vec<JobAttempt>,
+    /// Who submitted the job; `None` for jobs flyd submitted on its own, e.g. NATS
+    /// commands.
+    #[serde(default)]
+    pub submitted_by: Option<String>,
+    #[serde(default)]
+    pub canceled_by: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct JobProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JobAttempt {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    /// The attempt's timed steps, in the order they finished.
+    #[serde(default)]
+    pub steps: Vec<JobStep>,
+}
+
+/// How long one step of an attempt took against the time it was given.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JobStep {
+    pub name: String,
+    /// The least of the step's own budget and what was left of the attempt's.
+    pub budget_ms: Option<u64>,
+    pub spent_ms: u64,
+    pub timed_out: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JobsQuery {
+    pub kind: Option<String>,
+    pub app: Option<String>,
+    pub group: Option<String>,
+    pub state: Option<JobState>,
+    pub schedule_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    /// Plain lines, e.g. for a CI log.
+    Text,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JobReportQuery {
+    #[serde(default)]
+    pub format: ReportFormat,
+}
+
+/// What a finished job did, for attaching to a CI run.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JobReport {
+    pub job_id: u64,
+    pub kind: String,
+    pub app: Option<String>,
+    pub state: JobState,
+    pub succeeded: bool,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// From submission until the first attempt started.
+    pub queued_secs: Option<f64>,
+    /// From the first attempt starting until the job finished, backoffs included.
+    pub duration_secs: Option<f64>,
+    pub attempts: Vec<AttemptReport>,
+    /// How many resources had each outcome, e.g. `updated: 3`.
+    pub summary: std::collections::BTreeMap<String, usize>,
+    pub resources: Vec<ResourceOutcome>,
+    pub rolled_back: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AttemptReport {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_secs: Option<f64>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ResourceOutcome {
+    pub kind: String,
+    pub id: String,
+    /// Set when the job spans apps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    /// e.g. `updated`, `failed` or `rolled_back`.
+    pub outcome: String,
+}
+
+/// Rolls a new image and/or a config change (an RFC 7386 merge patch) out to every machine
+/// of an app, one machine at a time.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DeployRequest {
+    pub app: String,
+    pub image: Option<String>,
+    pub config: Option<serde_json::Value>,
+    /// Recorded on every machine the deploy updates.
+    pub provenance: Option<Provenance>,
+    /// Concurrency group to queue in, instead of the one configured for the app.
+    pub group: Option<String>,
+    /// Wait for approval before deploying, even if the group doesn't require it.
+    #[serde(default)]
+    pub require_approval: bool,
+    /// Must succeed on each updated machine before it's put back into traffic.
+    pub warm_up: Option<WarmUp>,
+    /// Updates machines region by region instead of all in one pass.
+    pub rollout: Option<RegionalRollout>,
+    /// Shifts traffic to the new version in steps instead of machine by machine.
+    pub traffic_step: Option<TrafficStep>,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// `POST /v0/deploy/rolling`: a deploy that updates `max_unavailable` machines at a time
+/// under a lease, each started with passing checks before the next, and rolls every
+/// updated machine back when one fails.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RollingDeployRequest {
+    #[serde(flatten)]
+    pub deploy: DeployRequest,
+    /// 1 by default.
+    pub max_unavailable: Option<usize>,
+    /// How long each machine gets to start with passing checks; 300 by default.
+    pub health_timeout_secs: Option<u64>,
+    /// Leaves the machines already updated on the new version when one fails.
+    #[serde(default)]
+    pub keep_failed: bool,
+    /// Updates machines cordoned through `/v0/machines/cordon` too, leaving them
+    /// cordoned; they're skipped otherwise.
+    #[serde(default)]
+    pub include_cordoned: bool,
+}
+
+/// The regions listed go first, in order, then the rest by name; the first is usually a
+/// canary. Each region's machines must still be started with passing checks `soak_secs`
+/// after the last of them is updated, or the deploy stops there.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RegionalRollout {
+    pub regions: Vec<String>,
+    #[serde(default)]
+    pub soak_secs: u64,
+}
+
+/// fly-proxy spreads a region's requests across its uncordoned machines, so the share
+/// going to the new version follows how many of them run it. Each step updates `percent`
+/// of the machines (of each region, with a rollout) holding them cordoned, then puts them
+/// into traffic together; the deploy stops unless every machine is still started with
+/// passing checks `pause_secs` later.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TrafficStep {
+    pub percent: u8,
+    #[serde(default)]
+    pub pause_secs: u64,
+}
+
+/// A deploy's warm-up step. HTTP and exec warm-ups are retried until `timeout_secs`
+/// (60 by default) is up; the deploy stops at the first machine that doesn't warm up,
+/// leaving it cordoned.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WarmUp {
+    /// GETs `path` on the machine's private address until it answers with a 2xx.
+    Http {
+        port: u16,
+        path: String,
+        timeout_secs: Option<u64>,
+    },
+    /// Runs the command on the machine until it exits 0.
+    Exec {
+        command: Vec<String>,
+        timeout_secs: Option<u64>,
+    },
+    /// Waits a fixed time.
+    Delay { secs: u64 },
+}
+
+/// `POST /v0/jobs/{id}/approve`. `token` comes from the approval link; callers with the
+/// approver role don't need it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ApprovalQuery {
+    pub token: Option<String>,
+    #[serde(default)]
+    pub reject: bool,
+}
+
+/// Something a schedule runs, as a job of the same kind.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum ScheduledOperation {
+    /// Creates a machine from `config` that is destroyed once it exits. With `wait`, the
+    /// run lasts until it does, and fails unless it exits with 0.
+    RunTask {
+        app: String,
+        region: Option<String>,
+        config: serde_json::Value,
+        #[serde(default)]
+        wait: bool,
+        /// How long a waited-for task may run before the run fails. Defaults to an hour.
+        timeout_secs: Option<u64>,
+    },
+    Deploy {
+        app: String,
+        image: Option<String>,
+        config: Option<serde_json::Value>,
+    },
+    /// Creates or destroys machines until the app has `count` of them.
+    Scale { app: String, count: usize },
+    /// An inventory snapshot, as `[snapshots]` takes them.
+    Snapshot { app: String },
+}
+
+impl ScheduledOperation {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ScheduledOperation::RunTask { .. } => "run_task",
+            ScheduledOperation::Deploy { .. } => "deploy",
+            ScheduledOperation::Scale { .. } => "scale",
+            ScheduledOperation::Snapshot { .. } => "snapshot",
+        }
+    }
+
+    pub fn app(&self) -> &str {
+        match self {
+            ScheduledOperation::RunTask { app, .. }
+            | ScheduledOperation::Deploy { app, .. }
+            | ScheduledOperation::Scale { app, .. }
+            | ScheduledOperation::Snapshot { app } => app,
+        }
+    }
+}
+
+/// What happens to a run that was due while flyd wasn't running.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRunPolicy {
+    #[default]
+    Skip,
+    /// Run once on catching up, however many runs were missed.
+    RunOnce,
+}
+
+/// `POST /v0/schedules`: exactly one of `cron` and `at`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewSchedule {
+    #[serde(flatten)]
+    pub operation: ScheduledOperation,
+    pub cron: Option<String>,
+    /// IANA time zone `cron` is evaluated in, e.g. `Europe/Berlin`. Defaults to UTC.
+    pub timezone: Option<String>,
+    pub at: Option<DateTime<Utc>>,
+    /// Each run is delayed by a random amount up to this.
+    #[serde(default)]
+    pub jitter_secs: u64,
+    #[serde(default)]
+    pub missed: MissedRunPolicy,
+    /// Overrides `jobs.max_retries` for this schedule's runs.
+    pub max_retries: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Schedule {
+    pub id: String,
+    #[serde(flatten)]
+    pub operation: ScheduledOperation,
+    pub cron: Option<String>,
+    pub timezone: Option<String>,
+    pub at: Option<DateTime<Utc>>,
+    pub jitter_secs: u64,
+    pub missed: MissedRunPolicy,
+    pub max_retries: Option<u32>,
+    pub created_at: DateTime<Utc>,
+    /// `None` once a one-shot schedule has run.
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub last_job_id: Option<u64>,
+    /// Who created the schedule. Each run is checked against them before it's submitted.
+    #[serde(default)]
+    pub created_by: Option<ScheduleOwner>,
+}
+
+/// A schedule's creator, as they were authenticated when they created it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ScheduleOwner {
+    pub subject: String,
+    pub roles: Vec<String>,
+    /// The only apps the creator's credentials could touch, when they were scoped to some.
+    pub apps: Option<Vec<String>>,
+}
+
+/// `POST /v0/apps/{app}/freezes`: `cron` and `duration_mins` for a recurring window, or
+/// `from` and `until` for a one-off one.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewFreeze {
+    /// When each window starts, e.g. `0 18 * * FRI` with 3720 minutes for the weekend.
+    pub cron: Option<String>,
+    /// IANA time zone `cron` is evaluated in, e.g. `Europe/Berlin`. Defaults to UTC.
+    pub timezone: Option<String>,
+    pub duration_mins: Option<u64>,
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Freeze {
+    pub id: String,
+    pub app: String,
+    pub cron: Option<String>,
+    pub timezone: Option<String>,
+    pub duration_mins: Option<u64>,
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// When the window in force ends, while one is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_force_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Script {
+    pub name: String,
+    pub description: Option<String>,
+    pub command: Vec<String>,
+    pub params: Vec<ScriptParam>,
+    pub role: Option<String>,
+    pub apps: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ScriptParam {
+    pub name: String,
+    pub pattern: String,
+    /// `None` for a required parameter.
+    pub default: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ScriptRunQuery {
+    pub app: String,
+    pub machine: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ScriptRunRequest {
+    #[serde(default)]
+    pub params: std::collections::BTreeMap<String, String>,
+}
+
+/// The recorded transcript of an exec or script run, after redaction.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    /// `exec` or `script`.
+    pub kind: String,
+    pub script: Option<String>,
+    pub app: String,
+    pub machine_id: String,
+    pub subject: String,
+    pub command: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub exit_code: Option<i64>,
+    pub stdout: String,
+    pub stderr: String,
+    pub error: Option<String>,
+    /// How many matches the redaction rules replaced.
+    pub redactions: usize,
+    /// Output beyond `sessions.max_transcript_bytes` wasn't kept.
+    pub truncated: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct SessionsQuery {
+    pub app: Option<String>,
+    pub machine_id: Option<String>,
+    pub subject: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewGrant {
+    pub subject: String,
+    pub roles: Vec<String>,
+    /// Apps the roles apply to; empty for any.
+    #[serde(default)]
+    pub apps: Vec<String>,
+    pub duration_secs: u64,
+    pub reason: String,
+}
+
+/// Roles held by `subject` on top of its own until `expires_at` or until revoked.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Grant {
+    pub id: String,
+    pub subject: String,
+    pub roles: Vec<String>,
+    pub apps: Vec<String>,
+    pub reason: String,
+    pub granted_by: String,
+    pub granted_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_by: Option<String>,
+    /// When the grant expired or was revoked.
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+/// `POST /v0/admin/keys`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NewApiKey {
+    pub subject: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// The `[auth.api_keys.fly_tokens]` entry the key calls the Machines API with;
+    /// FLY_API_TOKEN without one.
+    pub fly_token: Option<String>,
+    /// The key stops working after this long; it doesn't expire without one.
+    pub duration_secs: Option<u64>,
+    pub description: Option<String>,
+    /// The only apps the key may touch. Without it, it may touch any.
+    pub apps: Option<Vec<String>>,
+}
+
+/// A key flyd issued. Only its SHA-256 is kept; the key itself is shown once, when it's
+/// issued.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub subject: String,
+    pub roles: Vec<String>,
+    pub fly_token: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub apps: Option<Vec<String>>,
+    pub key_sha256: String,
+    pub issued_by: String,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_by: Option<String>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct IssuedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct GrantsQuery {
+    pub subject: Option<String>,
+    /// Include expired and revoked grants.
+    #[serde(default)]
+    pub all: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Environment {
+    pub name: String,
+    pub description: Option<String>,
+    /// Dependencies before the apps that need them.
+    pub apps: Vec<String>,
+    pub depends_on: std::collections::BTreeMap<String, Vec<String>>,
+    pub tasks: std::collections::BTreeMap<String, EnvironmentTask>,
+    /// The order deploys run apps and tasks in.
+    pub stages: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct EnvironmentTask {
+    pub app: String,
+    pub command: Vec<String>,
+    pub timeout_secs: u64,
+}
+
+/// `running` when every machine is started, `stopped` when none are, `degraded` in
+/// between, `empty` without machines and `unknown` when they couldn't be listed.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvironmentHealth {
+    Running,
+    Stopped,
+    Degraded,
+    Empty,
+    Unknown,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct EnvironmentStatus {
+    pub name: String,
+    pub status: EnvironmentHealth,
+    pub apps: Vec<EnvironmentAppStatus>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct EnvironmentAppStatus {
+    pub app: String,
+    pub status: EnvironmentHealth,
+    pub by_state: std::collections::BTreeMap<String, usize>,
+    pub error: Option<String>,
+    /// Started machines that predate the app's last secrets change.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stale_secrets: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct EnvironmentQuery {
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// Deploys each app in `images`, keyed by app, stage by stage in dependency order. A
+/// `config` patch goes to every app of the environment; without one, apps missing from
+/// `images` are skipped. If a stage fails, the stages before it are rolled back.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct EnvironmentDeployRequest {
+    #[serde(default)]
+    pub images: std::collections::BTreeMap<String, String>,
+    pub config: Option<serde_json::Value>,
+    /// Recorded on every machine of every app deployed.
+    pub provenance: Option<Provenance>,
+    #[serde(default)]
+    pub require_approval: bool,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphFormat {
+    #[default]
+    Json,
+    /// Graphviz, e.g. for `dot -Tsvg`.
+    Dot,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct GraphQuery {
+    pub app: String,
+    #[serde(default)]
+    pub format: GraphFormat,
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// An app's resources and what depends on what: tearing a node down affects every node
+/// reachable from it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ResourceGraph {
+    pub app: String,
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct GraphNode {
+    /// `<kind>:<id>`, e.g. `machine:1781973f9e5d89`.
+    pub id: String,
+    /// `app`, `machine`, `volume`, `snapshot` or `ip`.
+    pub kind: String,
+    pub name: Option<String>,
+    pub state: Option<String>,
+    pub region: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    /// e.g. `mounts`.
+    pub relation: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PlacementQuery {
+    #[serde(default)]
+    pub use_private_api: bool,
+}
+
+/// How an app's machines are spread over regions and, through their volumes, over
+/// hardware zones (hosts), with what to change so one host failing can't take out a
+/// region.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PlacementReport {
+    pub app: String,
+    pub regions: Vec<RegionPlacement>,
+    pub recommendations: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RegionPlacement {
+    pub region: String,
+    pub machines: usize,
+    /// Machine IDs by the zone of the volume they mount.
+    pub zones: std::collections::BTreeMap<String, Vec<String>>,
+    /// Machines without a volume, which Fly spreads best-effort on its own.
+    pub unpinned: Vec<String>,
+}
+
+/// Context for on-call about an app or one of its machines, merged into `annotations` on
+/// the machines flyd returns. A machine's own fields win over its app's.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Annotations {
+    pub owner: Option<String>,
+    pub description: Option<String>,
+    pub runbook_url: Option<String>,
+    pub slack_channel: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StoredAnnotations {
+    pub app: String,
+    /// `None` for the app's own.
+    pub machine_id: Option<String>,
+    #[serde(flatten)]
+    pub annotations: Annotations,
+    pub updated_by: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenState {
+    /// Not checked yet, or the last check couldn't reach Fly.
+    Unknown,
+    Valid,
+    /// Valid, but expiring within `token_health.warn_before_days`.
+    Expiring,
+    Expired,
+    /// Fly rejected it.
+    Invalid,
+}
+
+/// A Fly token flyd holds, identified by where it's configured and its fingerprint, never
+/// by the token itself.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TokenStatus {
+    /// Where it's configured, e.g. `FLY_API_TOKEN` or `auth.static_keys.ci`.
+    pub name: String,
+    /// Other places the same token is configured.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub also_used_by: Vec<String>,
+    /// The start of its SHA-256, as in `token:` subjects.
+    pub fingerprint: String,
+    pub state: TokenState,
+    pub checked_at: Option<DateTime<Utc>>,
+    pub last_valid_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+/// `GET /v0/admin/tokens/status`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TokenHealth {
+    pub tokens: Vec<TokenStatus>,
+}
@@ -0,0 +1,232 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::{ListAllMachinesRequest, MachinePage, MultiAppMachines, SourceError};
+use serde_json::{Value, json};
+
+use crate::annotations;
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::concurrency::{self, AdaptiveLimit};
+use crate::config::Config;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::health;
+use crate::hedge::Hedger;
+use crate::namespaces;
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::Store;
+use crate::views::{Page, View};
+
+/// The items of comma-separated `apps` or `regions`, each once, in order.
+pub fn comma_list(items: Option<&str>) -> Vec<String> {
+    let mut listed: Vec<String> = Vec::new();
+    for item in items.into_iter().flat_map(|items| items.split(',')) {
+        let item = item.trim();
+        if !item.is_empty() && !listed.iter().any(|listed| listed == item) {
+            listed.push(item.to_string());
+        }
+    }
+    listed
+}
+
+/// The org's apps the caller may see: those of its namespace, and of its credentials
+/// when they're scoped to some.
+async fn org_apps<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    org: &str,
+) -> Result<Vec<String>, String> {
+    let apps = backend
+        .list_apps(org)
+        .await
+        .map_err(|e| format!("Failed to list apps in {}: {}", org, e))?;
+    let namespace = namespaces::of(req);
+    let allowed = req
+        .extensions()
+        .get::<Identity>()
+        .and_then(|identity| identity.apps.clone());
+    Ok(apps
+        .iter()
+        .filter_map(|app| app["name"].as_str())
+        .filter(|app| {
+            namespace
+                .as_ref()
+                .is_none_or(|namespace| namespace.owns(app))
+        })
+        .filter(|app| {
+            allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.iter().any(|allowed| allowed == app))
+        })
+        .map(str::to_string)
+        .collect())
+}
+
+async fn list_all<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    query: &ListAllMachinesRequest,
+    config: &Config,
+) -> Result<MultiAppMachines, HttpResponse> {
+    // An app name is only needed to resolve the view and page, which don't depend on it.
+    let template = query.for_app("");
+    let view = View::from_query(req, &template).await?;
+    let page = Page::from_query(&template).map_err(|e| AppError::bad_request(e).into_response())?;
+    let apps = match &query.org {
+        Some(org) => org_apps(req, backend, org)
+            .await
+            .map_err(|e| AppError::bad_gateway(e).into_response())?,
+        None => comma_list(query.apps.as_deref()),
+    };
+
+    // Each app is listed whole, or once for each region asked for.
+    let mut regions = comma_list(query.regions.as_deref());
+    regions.extend(
+        query
+            .region
+            .clone()
+            .filter(|region| !regions.contains(region)),
+    );
+    let sources: Vec<(&String, Option<&String>)> = if regions.is_empty() {
+        apps.iter().map(|app| (app, None)).collect()
+    } else {
+        apps.iter()
+            .flat_map(|app| regions.iter().map(move |region| (app, Some(region))))
+            .collect()
+    };
+
+    let limit = req.app_data::<web::Data<AdaptiveLimit>>();
+    let listed = concurrency::map(
+        limit.map(|limit| limit.get_ref()),
+        sources.iter().copied().map(|(app, region)| async move {
+            let result = match region {
+                Some(region) => backend.list_region_machines(app, region).await,
+                None => backend.list_machines(app).await,
+            };
+            (app, region, result)
+        }),
+    )
+    .await;
+    let store = req.app_data::<web::Data<Store>>();
+    let mut merged = Vec::new();
+    let mut errors = Vec::new();
+    for (app, region, result) in listed {
+        let machines = match result {
+            Ok(machines) => machines,
+            Err(e) => {
+                errors.push(SourceError {
+                    app: app.clone(),
+                    region: region.cloned(),
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let mut machines = Value::Array(machines);
+        health::annotate(&mut machines, &config.health);
+        if let Some(store) = store {
+            annotations::merge(store, app, &mut machines).await;
+        }
+        let Value::Array(machines) = machines else {
+            continue;
+        };
+        merged.extend(machines.into_iter().map(|mut machine| {
+            machine["app"] = json!(app);
+            machine
+        }));
+    }
+    if query.strict
+        && let Some(failed) = errors.first()
+    {
+        let source = match &failed.region {
+            Some(region) => format!("{} in {}", failed.app, region),
+            None => failed.app.clone(),
+        };
+        return Err(AppError::bad_gateway(format!(
+            "Failed to list machines of {} ({} of {} failed): {}",
+            source,
+            errors.len(),
+            sources.len(),
+            failed.error
+        ))
+        .into_response());
+    }
+
+    let mut machines = Value::Array(merged);
+    let page = match &page {
+        Some(page) => {
+            view.apply_paged(&mut machines, page);
+            serde_json::from_value::<MachinePage>(machines)
+                .map_err(|e| AppError::internal(e.to_string()).into_response())?
+        }
+        None => {
+            view.apply(&mut machines);
+            let machines = match machines {
+                Value::Array(machines) => machines,
+                _ => Vec::new(),
+            };
+            MachinePage {
+                total: machines.len(),
+                machines,
+                next_cursor: None,
+            }
+        }
+    };
+    Ok(MultiAppMachines {
+        machines: page.machines,
+        total: page.total,
+        next_cursor: page.next_cursor,
+        partial: !errors.is_empty(),
+        errors,
+    })
+}
+
+/// The machines of every app in `apps`, or in `org`, listed concurrently and merged, each
+/// with its `app`. `q`, `view`, `state`, `name` and `sort` apply across the merged list,
+/// and so does paging. Apps and regions that fail are reported alongside the rest, or
+/// fail the request with `strict=true`.
+#[get("/v0/machines/list_all")]
+async fn list_all_machines(
+    req: HttpRequest,
+    query: web::Query<ListAllMachinesRequest>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+    hedger: Option<web::Data<Hedger>>,
+) -> impl Responder {
+    match (query.apps.is_some(), query.org.is_some()) {
+        (false, false) => {
+            return AppError::bad_request("list_all needs apps or an org").into_response();
+        }
+        (true, true) => {
+            return AppError::bad_request("list_all takes apps or an org, not both")
+                .into_response();
+        }
+        _ => {}
+    }
+    let result = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        list_all(&req, docker.get_ref(), &query, &config).await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner())
+            .with_hedger(hedger.map(web::Data::into_inner))
+            .with_limit(
+                req.app_data::<web::Data<AdaptiveLimit>>()
+                    .map(|limit| limit.clone().into_inner()),
+            );
+        list_all(&req, &client, &query, &config).await
+    };
+    match result {
+        Ok(machines) => HttpResponse::Ok().json(machines),
+        Err(response) => response,
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_all_machines);
+}
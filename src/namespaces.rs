@@ -0,0 +1,506 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse, Responder, get, web};
+use serde_json::{Value, json};
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::concurrency::{self, AdaptiveLimit};
+use crate::config::{Config, MiddlewareStage, NamespaceConfig};
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::merge::fill_defaults;
+use crate::multi_app;
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+/// Request fields, in the query or a JSON body, that name an app.
+const APP_FIELDS: [&str; 4] = ["app", "app_name", "source_app", "target_app"];
+
+/// Routes whose next path segment is an app name.
+const APP_PATHS: [&str; 4] = [
+    "/v0/apps/",
+    "/v0/snapshots/",
+    "/metrics/app/",
+    "/v0/autoscale/policies/",
+];
+
+/// Routes under `/v0/apps/` whose next segment isn't an app name.
+const APP_ROUTES: [&str; 5] = [
+    "/v0/apps/clone",
+    "/v0/apps/new",
+    "/v0/apps/list",
+    "/v0/apps/get",
+    "/v0/apps/delete",
+];
+
+/// Routes that name no app but any caller may use. `/v0/apps/list` only lists the
+/// namespace's apps.
+const OPEN_ROUTES: [&str; 11] = [
+    "/",
+    "/health",
+    "/health/live",
+    "/health/ready",
+    "/openapi.json",
+    "/docs",
+    "/ready",
+    "/version",
+    "/v0/whoami",
+    "/v0/namespace",
+    "/v0/apps/list",
+];
+
+/// A namespace a caller belongs to.
+pub struct Namespace {
+    pub name: String,
+    pub config: NamespaceConfig,
+}
+
+impl Namespace {
+    pub fn owns(&self, app: &str) -> bool {
+        app.starts_with(&self.config.prefix)
+    }
+
+    fn refuse(&self, reason: String) -> String {
+        format!("Namespace {} {}", self.name, reason)
+    }
+
+    /// Fills a Machines API create body in from the namespace's template.
+    pub fn fill(&self, body: &mut Value) {
+        let template = &self.config.template;
+        fill_defaults(body, &Value::Object(template.create.clone()));
+        for (key, value) in &template.required_metadata {
+            body["config"]["metadata"][key] = json!(value);
+        }
+    }
+
+    /// Checks a Machines API create or update body against the namespace's policy.
+    pub fn check(&self, app: &str, body: &Value) -> Result<(), String> {
+        if !self.owns(app) {
+            return Err(self.refuse(format!(
+                "can't touch {}: its apps are named {}*",
+                app, self.config.prefix
+            )));
+        }
+        let policy = &self.config;
+        if let Some(region) = body["region"].as_str()
+            && !policy.regions.is_empty()
+            && !policy.regions.iter().any(|allowed| allowed == region)
+        {
+            return Err(self.refuse(format!(
+                "can't place machines in {}, only in {}",
+                region,
+                policy.regions.join(", ")
+            )));
+        }
+        let config = &body["config"];
+        if let Some(image) = config["image"].as_str()
+            && !policy.images.is_empty()
+            && !policy.images.iter().any(|prefix| image.starts_with(prefix))
+        {
+            return Err(self.refuse(format!("can't run {}", image)));
+        }
+        let guest = &config["guest"];
+        if let (Some(max), Some(cpus)) = (policy.max_cpus, guest["cpus"].as_u64())
+            && cpus > max
+        {
+            return Err(self.refuse(format!("allows at most {} CPUs, not {}", max, cpus)));
+        }
+        if let (Some(max), Some(memory)) = (policy.max_memory_mb, guest["memory_mb"].as_u64())
+            && memory > max
+        {
+            return Err(self.refuse(format!(
+                "allows at most {} MB of memory, not {}",
+                max, memory
+            )));
+        }
+        Ok(())
+    }
+
+    /// The namespace's apps in its org.
+    async fn apps<B: Backend>(&self, backend: &B) -> Result<Vec<String>, String> {
+        let Some(org_slug) = &self.config.org_slug else {
+            return Ok(Vec::new());
+        };
+        let apps = backend
+            .list_apps(org_slug)
+            .await
+            .map_err(|e| format!("Failed to list apps in {}: {}", org_slug, e))?;
+        Ok(apps
+            .iter()
+            .filter_map(|app| app["name"].as_str())
+            .filter(|app| self.owns(app))
+            .map(str::to_string)
+            .collect())
+    }
+
+    async fn machines<B: Backend>(
+        &self,
+        backend: &B,
+        apps: &[String],
+        limit: Option<&AdaptiveLimit>,
+    ) -> Result<usize, String> {
+        let listed = concurrency::map(
+            limit,
+            apps.iter().map(|app| async move {
+                backend
+                    .list_machines(app)
+                    .await
+                    .map_err(|e| format!("Failed to list machines of {}: {}", app, e))
+            }),
+        )
+        .await;
+        let mut count = 0;
+        for machines in listed {
+            count += machines?
+                .iter()
+                .filter(|machine| fleets::is_live(machine))
+                .count();
+        }
+        Ok(count)
+    }
+
+    /// Refuses one more machine once the namespace is at `max_machines`.
+    pub async fn check_machine_quota<B: Backend>(
+        &self,
+        backend: &B,
+        limit: Option<&AdaptiveLimit>,
+    ) -> Result<(), String> {
+        self.check_machine_room(backend, 1, limit).await
+    }
+
+    /// Refuses `count` more machines if they'd take the namespace past `max_machines`.
+    pub async fn check_machine_room<B: Backend>(
+        &self,
+        backend: &B,
+        count: usize,
+        limit: Option<&AdaptiveLimit>,
+    ) -> Result<(), String> {
+        let Some(max) = self.config.max_machines else {
+            return Ok(());
+        };
+        let apps = self.apps(backend).await?;
+        let machines = self.machines(backend, &apps, limit).await?;
+        if machines >= max {
+            return Err(self.refuse(format!("is at its quota of {} machines", max)));
+        }
+        if machines + count > max {
+            return Err(self.refuse(format!(
+                "has room for {} more machines, not {}",
+                max - machines,
+                count
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuses a new app outside the namespace's org, or once it's at `max_apps`.
+    pub async fn check_app_quota<B: Backend>(
+        &self,
+        backend: &B,
+        app: &str,
+        org_slug: &str,
+    ) -> Result<(), String> {
+        if let Some(org) = &self.config.org_slug
+            && org != org_slug
+        {
+            return Err(self.refuse(format!("creates its apps in {}", org)));
+        }
+        let Some(max) = self.config.max_apps else {
+            return Ok(());
+        };
+        let apps = self.apps(backend).await?;
+        if !apps.iter().any(|existing| existing == app) && apps.len() >= max {
+            return Err(self.refuse(format!("is at its quota of {} apps", max)));
+        }
+        Ok(())
+    }
+}
+
+/// Which namespace, if any, each caller belongs to. Callers in none are unconstrained.
+pub struct Namespaces {
+    namespaces: Vec<Arc<Namespace>>,
+}
+
+impl Namespaces {
+    /// Returns `None` when no namespaces are configured.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        if config.namespaces.is_empty() {
+            return Ok(None);
+        }
+        // Wherever callers are identified, they must be scoped right after.
+        let middleware = &config.middleware;
+        let chains = std::iter::once(&middleware.chain)
+            .chain(middleware.groups.iter().map(|group| &group.chain));
+        for chain in chains {
+            let position = |stage| chain.iter().position(|s| *s == stage);
+            if let Some(auth) = position(MiddlewareStage::Auth)
+                && position(MiddlewareStage::Namespaces).is_none_or(|scope| scope < auth)
+            {
+                return Err(
+                    "namespaces need the namespaces middleware stage after auth in every chain"
+                        .to_string(),
+                );
+            }
+        }
+
+        let mut namespaces = Vec::new();
+        for (name, namespace) in &config.namespaces {
+            if namespace.prefix.is_empty() {
+                return Err(format!("Namespace {} needs a prefix", name));
+            }
+            if (namespace.max_apps.is_some() || namespace.max_machines.is_some())
+                && namespace.org_slug.is_none()
+            {
+                return Err(format!(
+                    "Namespace {} needs an org_slug to count its quotas in",
+                    name
+                ));
+            }
+            namespaces.push(Arc::new(Namespace {
+                name: name.clone(),
+                config: namespace.clone(),
+            }));
+        }
+        Ok(Some(Namespaces { namespaces }))
+    }
+
+    /// The caller's namespace, the first by name they belong to.
+    fn of(&self, identity: &Identity) -> Option<Arc<Namespace>> {
+        self.namespaces
+            .iter()
+            .find(|namespace| {
+                namespace.config.subjects.contains(&identity.subject)
+                    || identity
+                        .roles
+                        .iter()
+                        .any(|role| namespace.config.roles.contains(role))
+            })
+            .cloned()
+    }
+}
+
+/// The namespace of the caller making `req`, as the namespaces stage found it.
+pub fn of(req: &HttpRequest) -> Option<Arc<Namespace>> {
+    req.extensions().get::<Arc<Namespace>>().cloned()
+}
+
+/// Every app a request names: in its path, its query or its JSON body.
+pub fn named_apps(path: &str, query: &str, body: Option<&Value>) -> Vec<String> {
+    let mut apps = Vec::new();
+    for prefix in APP_PATHS {
+        if let Some(app) = path
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.split('/').next())
+            .filter(|app| !app.is_empty() && !APP_ROUTES.contains(&path))
+        {
+            apps.push(app.to_string());
+        }
+    }
+    if let Ok(query) = web::Query::<BTreeMap<String, String>>::from_query(query) {
+        apps.extend(
+            APP_FIELDS
+                .iter()
+                .filter_map(|field| query.get(*field).cloned()),
+        );
+        apps.extend(multi_app::comma_list(query.get("apps").map(String::as_str)));
+    }
+    if let Some(body) = body {
+        apps.extend(
+            APP_FIELDS
+                .iter()
+                .filter_map(|field| body[*field].as_str().map(str::to_string)),
+        );
+        apps.extend(
+            body["apps"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|app| app.as_str().map(str::to_string)),
+        );
+    }
+    apps
+}
+
+/// The JSON body of a request that may change something, put back for the handler.
+pub async fn request_body(req: &mut ServiceRequest) -> Result<Option<Value>, Error> {
+    let is_json = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json || req.method().is_safe() {
+        return Ok(None);
+    }
+    let bytes = req.extract::<web::Bytes>().await?;
+    let body = serde_json::from_slice::<Value>(&bytes).ok();
+    let (_, mut payload) = actix_http::h1::Payload::create(true);
+    payload.unread_data(bytes);
+    req.set_payload(Payload::from(payload));
+    Ok(body)
+}
+
+/// Every app the request names, in its path, query or JSON body. The body is put back
+/// for the handler.
+pub async fn request_apps(req: &mut ServiceRequest) -> Result<Vec<String>, Error> {
+    let body = request_body(req).await?;
+    Ok(named_apps(req.path(), req.query_string(), body.as_ref()))
+}
+
+/// Why credentials scoped to `allowed` apps can't make a request naming `apps`, if they
+/// can't. Like a namespace's, they may only call open routes without naming an app.
+fn allowlist_refusal(allowed: &[String], apps: &[String], path: &str) -> Option<String> {
+    match apps.iter().find(|app| !allowed.contains(app)) {
+        Some(app) => Some(format!(
+            "These credentials can't touch {}, only {}",
+            app,
+            allowed.join(", ")
+        )),
+        None if apps.is_empty() && !OPEN_ROUTES.contains(&path) => {
+            Some("These credentials can only call routes that name one of their apps".to_string())
+        }
+        None => None,
+    }
+}
+
+/// Keeps namespaced callers to their own apps: every app a request names must carry the
+/// namespace's prefix, and requests naming none are refused unless the route is open to
+/// the namespace. The namespace is attached to the request for the handlers' checks.
+/// Callers whose credentials list the apps they may touch are kept to those the same way.
+pub async fn scope<B: MessageBody + 'static>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let identity = req.extensions().get::<Identity>().cloned();
+    let namespace = req
+        .app_data::<web::Data<Namespaces>>()
+        .zip(identity.as_ref())
+        .and_then(|(namespaces, identity)| namespaces.of(identity));
+    let allowed = identity.and_then(|identity| identity.apps);
+    if namespace.is_none() && allowed.is_none() {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+
+    let path = req.path().to_string();
+    let apps = request_apps(&mut req).await?;
+    if let Some(refusal) = allowed
+        .as_ref()
+        .and_then(|allowed| allowlist_refusal(allowed, &apps, &path))
+    {
+        log::info!("Refused {} {}: {}", req.method(), path, refusal);
+        return Ok(req.into_response(AppError::forbidden(refusal).into_response()));
+    }
+    let Some(namespace) = namespace else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let refusal = match apps.iter().find(|app| !namespace.owns(app)) {
+        Some(app) => Some(format!(
+            "Namespace {} can't touch {}: its apps are named {}*",
+            namespace.name, app, namespace.config.prefix
+        )),
+        None if apps.is_empty()
+            && !OPEN_ROUTES.contains(&path.as_str())
+            && !namespace
+                .config
+                .routes
+                .iter()
+                .any(|route| path.starts_with(route)) =>
+        {
+            Some(format!(
+                "Namespace {} can only call routes that name one of its apps",
+                namespace.name
+            ))
+        }
+        None => None,
+    };
+    if let Some(refusal) = refusal {
+        log::info!("Refused {} {}: {}", req.method(), path, refusal);
+        return Ok(req.into_response(AppError::forbidden(refusal).into_response()));
+    }
+
+    req.extensions_mut().insert(namespace);
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+async fn usage<B: Backend>(
+    backend: &B,
+    namespace: &Namespace,
+    limit: Option<&AdaptiveLimit>,
+) -> Result<Value, String> {
+    let apps = namespace.apps(backend).await?;
+    let machines = namespace.machines(backend, &apps, limit).await?;
+    Ok(json!({ "apps": apps, "machines": machines }))
+}
+
+/// The caller's namespace: its prefix, quotas and policy, and how much of the quotas its
+/// apps use.
+#[get("/v0/namespace")]
+async fn get_namespace(
+    req: HttpRequest,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let Some(namespace) = of(&req) else {
+        return AppError::not_found("Not in a namespace").into_response();
+    };
+    let config = &namespace.config;
+    let mut response = json!({
+        "name": namespace.name,
+        "prefix": config.prefix,
+        "org_slug": config.org_slug,
+        "max_apps": config.max_apps,
+        "max_machines": config.max_machines,
+        "regions": config.regions,
+        "images": config.images,
+        "max_cpus": config.max_cpus,
+        "max_memory_mb": config.max_memory_mb,
+    });
+    if config.org_slug.is_none() {
+        return HttpResponse::Ok().json(response);
+    }
+
+    let limit = req.app_data::<web::Data<AdaptiveLimit>>();
+    let usage = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        usage(
+            docker.get_ref(),
+            &namespace,
+            limit.map(|limit| limit.get_ref()),
+        )
+        .await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, false) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner())
+            .with_limit(limit.map(|limit| limit.clone().into_inner()));
+        usage(&client, &namespace, limit.map(|limit| limit.get_ref())).await
+    };
+    match usage {
+        Ok(usage) => {
+            response["usage"] = usage;
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_namespace);
+}
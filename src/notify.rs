@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use actix_web::web;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::annotations;
+use crate::config::{
+    EmailTarget, NotificationsConfig, OwnerRoute, SmtpConfig, SmtpTls, WebhookTarget,
+};
+use crate::store::Store;
+use flyd::models::Event;
+
+const DEFAULT_SUBJECT: &str = "[flyd] {kind} {app}";
+const DEFAULT_TEXT_TEMPLATE: &str =
+    "{kind} at {at}\n\napp: {app}\nmachine: {machine_id}\n\n{detail}\n";
+const DEFAULT_HTML_TEMPLATE: &str = "<h2>{kind}</h2>\n<p>{at}</p>\n<table>\n<tr><th align=\"left\">App</th><td>{app}</td></tr>\n<tr><th align=\"left\">Machine</th><td>{machine_id}</td></tr>\n</table>\n<pre>{detail}</pre>\n";
+
+pub fn matches(patterns: &[String], kind: &str) -> bool {
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => kind.starts_with(prefix),
+                None => pattern == kind,
+            })
+}
+
+impl WebhookTarget {
+    fn wants(&self, kind: &str) -> bool {
+        matches(&self.events, kind)
+    }
+}
+
+impl EmailTarget {
+    fn wants(&self, kind: &str) -> bool {
+        matches(&self.events, kind)
+    }
+}
+
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render(template: &str, event: &Event, html: bool) -> String {
+    let detail = serde_json::to_string_pretty(&event.detail).unwrap_or_default();
+    let fields = [
+        ("{kind}", event.kind.clone()),
+        ("{app}", event.app.clone().unwrap_or_default()),
+        ("{machine_id}", event.machine_id.clone().unwrap_or_default()),
+        ("{at}", event.at.to_rfc3339()),
+        ("{detail}", detail),
+    ];
+
+    let mut rendered = template.to_string();
+    for (placeholder, value) in fields {
+        let value = if html { escape_html(&value) } else { value };
+        rendered = rendered.replace(placeholder, &value);
+    }
+    rendered
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn from_config(config: &SmtpConfig) -> Result<Mailer, String> {
+        let mut builder = match config.tls {
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+            SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.host,
+            )),
+        }
+        .map_err(|e| format!("Invalid SMTP host {}: {}", config.host, e))?;
+
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let from = config
+            .from
+            .parse()
+            .map_err(|e| format!("Invalid SMTP from address {}: {}", config.from, e))?;
+
+        Ok(Mailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+
+    pub async fn send(
+        &self,
+        to: &[String],
+        subject: &str,
+        text: String,
+        html: String,
+    ) -> Result<(), String> {
+        let mut builder = Message::builder().from(self.from.clone()).subject(subject);
+        for address in to {
+            let mailbox: Mailbox = address
+                .parse()
+                .map_err(|e| format!("Invalid address {}: {}", address, e))?;
+            builder = builder.to(mailbox);
+        }
+
+        let message = builder
+            .multipart(MultiPart::alternative_plain_html(text, html))
+            .map_err(|e| e.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// How flyd reaches people: webhooks, email when SMTP is set up, and the routes to each
+/// owner's team.
+#[derive(Clone)]
+pub struct Notifier {
+    pub http: reqwest::Client,
+    pub mailer: Option<Mailer>,
+    owners: HashMap<String, OwnerRoute>,
+    default_route: Option<OwnerRoute>,
+}
+
+impl Notifier {
+    pub fn new(
+        http: reqwest::Client,
+        mailer: Option<Mailer>,
+        config: &NotificationsConfig,
+    ) -> Self {
+        Notifier {
+            http,
+            mailer,
+            owners: config.owners.clone(),
+            default_route: config.default_route.clone(),
+        }
+    }
+
+    pub fn routes_to_owners(&self) -> bool {
+        !self.owners.is_empty() || self.default_route.is_some()
+    }
+
+    /// The route for `app`, or one of its machines, with the owner it's for: the owner's
+    /// own route, else the default one.
+    pub async fn route(
+        &self,
+        store: &Store,
+        app: Option<&str>,
+        machine_id: Option<&str>,
+    ) -> Option<(Option<String>, &OwnerRoute)> {
+        let owner = match app {
+            Some(app) => annotations::owner(store, app, machine_id).await,
+            None => None,
+        };
+        match owner.as_ref().and_then(|owner| self.owners.get(owner)) {
+            Some(route) => Some((owner, route)),
+            None => self.default_route.as_ref().map(|route| (owner, route)),
+        }
+    }
+
+    pub async fn deliver(&self, route: &OwnerRoute, event: &Event) {
+        for url in &route.webhooks {
+            let result = self
+                .http
+                .post(url)
+                .json(event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                log::error!("Failed to deliver {} event to {}: {}", event.kind, url, e);
+            }
+        }
+        if route.emails.is_empty() {
+            return;
+        }
+        let Some(mailer) = &self.mailer else {
+            log::warn!(
+                "Owner route for {} has email recipients but [notifications.smtp] is not configured",
+                event.kind
+            );
+            return;
+        };
+        let target = EmailTarget {
+            to: route.emails.clone(),
+            events: Vec::new(),
+            subject: None,
+            text_template: None,
+            html_template: None,
+        };
+        if let Err(e) = send_email(mailer, &target, event).await {
+            log::error!(
+                "Failed to email {} event to {}: {}",
+                event.kind,
+                route.emails.join(", "),
+                e
+            );
+        }
+    }
+}
+
+async fn send_email(mailer: &Mailer, target: &EmailTarget, event: &Event) -> Result<(), String> {
+    let subject = render(
+        target.subject.as_deref().unwrap_or(DEFAULT_SUBJECT),
+        event,
+        false,
+    );
+    let text = render(
+        target
+            .text_template
+            .as_deref()
+            .unwrap_or(DEFAULT_TEXT_TEMPLATE),
+        event,
+        false,
+    );
+    let html = render(
+        target
+            .html_template
+            .as_deref()
+            .unwrap_or(DEFAULT_HTML_TEMPLATE),
+        event,
+        true,
+    );
+    mailer.send(&target.to, &subject, text, html).await
+}
+
+/// Delivers every event to the targets that want it, and to the route of the owner of
+/// the app or machine it's about.
+pub async fn run(
+    mut receiver: broadcast::Receiver<Event>,
+    config: NotificationsConfig,
+    notifier: Notifier,
+    store: web::Data<Store>,
+) {
+    let (http_client, mailer) = (&notifier.http, &notifier.mailer);
+    if mailer.is_none() && !config.emails.is_empty() {
+        log::warn!("Email notifications configured without [notifications.smtp], ignoring them");
+    }
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Notifier fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        for webhook in config
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.wants(&event.kind))
+        {
+            let result = http_client
+                .post(&webhook.url)
+                .json(&event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            if let Err(e) = result {
+                log::error!(
+                    "Failed to deliver {} event to {}: {}",
+                    event.kind,
+                    webhook.url,
+                    e
+                );
+            }
+        }
+
+        if notifier.routes_to_owners()
+            && let Some((_, route)) = notifier
+                .route(&store, event.app.as_deref(), event.machine_id.as_deref())
+                .await
+            && matches(&route.events, &event.kind)
+        {
+            notifier.deliver(route, &event).await;
+        }
+
+        let Some(mailer) = mailer else {
+            continue;
+        };
+        for target in config
+            .emails
+            .iter()
+            .filter(|target| target.wants(&event.kind))
+        {
+            if let Err(e) = send_email(mailer, target, &event).await {
+                log::error!(
+                    "Failed to email {} event to {}: {}",
+                    event.kind,
+                    target.to.join(", "),
+                    e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use rusty_s3::actions::ListObjectsV2;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+
+use crate::config::ObjectStorageConfig;
+
+/// How long the URLs flyd signs for its own requests stay valid.
+const REQUEST_SIGNATURE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum ObjectStoreError {
+    Http(reqwest::Error),
+    Status { status: StatusCode, body: String },
+    Parse(String),
+}
+
+impl std::fmt::Display for ObjectStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectStoreError::Http(e) => write!(f, "Object storage request failed: {}", e),
+            ObjectStoreError::Status { status, body } => {
+                write!(f, "Object storage returned {}: {}", status, body)
+            }
+            ObjectStoreError::Parse(e) => write!(f, "Invalid object storage response: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ObjectStoreError {
+    fn from(e: reqwest::Error) -> Self {
+        ObjectStoreError::Http(e)
+    }
+}
+
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+}
+
+/// An S3-compatible bucket (S3, Tigris, R2, MinIO...), spoken to with presigned URLs.
+pub struct ObjectStore {
+    bucket: Bucket,
+    credentials: Credentials,
+    http: reqwest::Client,
+}
+
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, ObjectStoreError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(ObjectStoreError::Status { status, body })
+}
+
+impl ObjectStore {
+    /// Credentials come from `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY`.
+    pub fn from_config(
+        config: &ObjectStorageConfig,
+        http: reqwest::Client,
+    ) -> Result<Self, String> {
+        let endpoint = config
+            .endpoint
+            .parse()
+            .map_err(|e| format!("Invalid object_storage.endpoint: {}", e))?;
+        let style = if config.path_style {
+            UrlStyle::Path
+        } else {
+            UrlStyle::VirtualHost
+        };
+        let bucket = Bucket::new(
+            endpoint,
+            style,
+            config.bucket.clone(),
+            config.region.clone(),
+        )
+        .map_err(|e| format!("Invalid object_storage bucket: {}", e))?;
+        let credentials = Credentials::from_env().ok_or(
+            "object_storage needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY".to_string(),
+        )?;
+        Ok(ObjectStore {
+            bucket,
+            credentials,
+            http,
+        })
+    }
+
+    pub async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        content_type: &str,
+    ) -> Result<(), ObjectStoreError> {
+        let url = self
+            .bucket
+            .put_object(Some(&self.credentials), key)
+            .sign(REQUEST_SIGNATURE_TTL);
+        let response = self
+            .http
+            .put(url)
+            .header("content-type", content_type)
+            .body(body)
+            .send()
+            .await?;
+        check(response).await?;
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ObjectStoreError> {
+        let url = self
+            .bucket
+            .get_object(Some(&self.credentials), key)
+            .sign(REQUEST_SIGNATURE_TTL);
+        let response = self.http.get(url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(check(response).await?.bytes().await?.to_vec()))
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), ObjectStoreError> {
+        let url = self
+            .bucket
+            .delete_object(Some(&self.credentials), key)
+            .sign(REQUEST_SIGNATURE_TTL);
+        check(self.http.delete(url).send().await?).await?;
+        Ok(())
+    }
+
+    pub async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, ObjectStoreError> {
+        let mut objects = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(Some(&self.credentials));
+            action.with_prefix(prefix);
+            if let Some(token) = &continuation_token {
+                action.with_continuation_token(token.as_str());
+            }
+            let url = action.sign(REQUEST_SIGNATURE_TTL);
+
+            let body = check(self.http.get(url).send().await?)
+                .await?
+                .text()
+                .await?;
+            let page = ListObjectsV2::parse_response(&body)
+                .map_err(|e| ObjectStoreError::Parse(e.to_string()))?;
+            objects.extend(page.contents.into_iter().map(|object| ObjectInfo {
+                key: object.key,
+                size: object.size,
+            }));
+
+            match page.next_continuation_token {
+                Some(token) => continuation_token = Some(token),
+                None => return Ok(objects),
+            }
+        }
+    }
+
+    /// A URL anyone can download `key` from until it expires.
+    pub fn presign_get(&self, key: &str, expires_in: Duration) -> String {
+        self.bucket
+            .get_object(Some(&self.credentials), key)
+            .sign(expires_in)
+            .to_string()
+    }
+}
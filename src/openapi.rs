@@ -0,0 +1,109 @@
+use std::sync::OnceLock;
+
+use actix_web::{HttpResponse, Responder, get, web};
+use serde_json::{Value, json};
+
+/// Generated by `build.rs` from the route attributes in `src/` and the types in
+/// `src/models.rs`.
+const GENERATED: &str = include_str!(concat!(env!("OUT_DIR"), "/openapi.json"));
+
+/// What each operation answers with on success, and whether it's a list of them. Handlers
+/// only name the types they take, so the ones they return are listed here.
+const RESPONSES: &[(&str, &str, bool)] = &[
+    ("list_machines", "Machine", true),
+    ("get_machine", "Machine", false),
+    ("create_machine", "Machine", false),
+    ("update_machine", "Machine", false),
+    ("patch_machine", "Machine", false),
+    ("json_patch_machine", "Machine", false),
+    ("batch_new", "BatchNewMachinesReport", false),
+    ("bulk_machines", "BulkMachinesReport", false),
+    ("list_budgets", "BudgetStatus", true),
+    ("get_app_budgets", "BudgetStatus", true),
+    ("get_preemptible", "PreemptibleReport", false),
+    ("get_app_annotations", "StoredAnnotations", false),
+    ("put_app_annotations", "StoredAnnotations", false),
+    ("get_machine_annotations", "StoredAnnotations", false),
+    ("put_machine_annotations", "StoredAnnotations", false),
+    ("version_info", "VersionInfo", false),
+];
+
+fn document() -> &'static Value {
+    static DOCUMENT: OnceLock<Value> = OnceLock::new();
+    DOCUMENT.get_or_init(|| {
+        let mut document: Value =
+            serde_json::from_str(GENERATED).expect("build.rs writes a valid OpenAPI document");
+        let responses = |operation: &Value| {
+            RESPONSES
+                .iter()
+                .find(|(id, _, _)| operation["operationId"] == *id)
+        };
+        for path in document["paths"]
+            .as_object_mut()
+            .into_iter()
+            .flat_map(|paths| paths.values_mut())
+        {
+            for operation in path
+                .as_object_mut()
+                .into_iter()
+                .flat_map(|operations| operations.values_mut())
+            {
+                let Some((_, schema, list)) = responses(operation) else {
+                    continue;
+                };
+                let mut schema = json!({ "$ref": format!("#/components/schemas/{}", schema) });
+                if *list {
+                    schema = json!({ "type": "array", "items": schema });
+                }
+                operation["responses"]["200"]["content"] =
+                    json!({ "application/json": { "schema": schema } });
+            }
+        }
+        document
+    })
+}
+
+/// The operation documented for `method` on a route pattern, e.g. `/v0/machines/{id}/ping`.
+pub fn operation(pattern: &str, method: &str) -> Option<&'static Value> {
+    document()["paths"]
+        .get(pattern)?
+        .get(method.to_lowercase())
+        .filter(|operation| operation.is_object())
+}
+
+/// The schema of a type in `src/models.rs`, e.g. `Machine`.
+pub fn schema(name: &str) -> Option<&'static Value> {
+    document()["components"]["schemas"].get(name)
+}
+
+const SWAGGER_UI: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>flyd API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+/// flyd's API, for generating clients against.
+#[get("/openapi.json")]
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(document())
+}
+
+#[get("/docs")]
+async fn swagger_ui() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi_json).service(swagger_ui);
+}
@@ -0,0 +1,125 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, web};
+
+use crate::config::{Config, MiddlewareGroup, MiddlewareStage};
+use crate::{
+    audit, auth, freezes, idempotency, namespaces, plugins, rate_limit, schemas, slo, usage,
+};
+
+/// Which middleware stages run, and in what order, for each route group. The app is
+/// wrapped in one slot per stage (a chain can't repeat one), and each slot runs the stage
+/// at its position in the request's chain, if any.
+pub struct Pipeline {
+    chain: Vec<MiddlewareStage>,
+    groups: Vec<MiddlewareGroup>,
+}
+
+/// Checks each stage is listed once and warns of orders that lose information. When
+/// callers may be scoped to apps, by a static key's `apps` or by API keys issued with
+/// them, namespaces must run after auth, or scoped callers could reach any app.
+fn validate(name: &str, chain: &[MiddlewareStage], scoped: bool) -> Result<(), String> {
+    for (position, stage) in chain.iter().enumerate() {
+        if chain[..position].contains(stage) {
+            return Err(format!("{} lists {:?} more than once", name, stage));
+        }
+    }
+    let position = |stage| chain.iter().position(|s| *s == stage);
+    if let (Some(usage), Some(auth)) = (
+        position(MiddlewareStage::Usage),
+        position(MiddlewareStage::Auth),
+    ) && usage < auth
+    {
+        log::warn!(
+            "{} runs usage before auth, so usage will be attributed to anonymous",
+            name
+        );
+    }
+    if let (Some(audit), Some(auth)) = (
+        position(MiddlewareStage::Audit),
+        position(MiddlewareStage::Auth),
+    ) && audit < auth
+    {
+        log::warn!(
+            "{} runs audit before auth, so audited requests will be attributed to anonymous",
+            name
+        );
+    }
+    if let (Some(freezes), Some(auth)) = (
+        position(MiddlewareStage::Freezes),
+        position(MiddlewareStage::Auth),
+    ) && freezes < auth
+    {
+        log::warn!(
+            "{} runs freezes before auth, so no caller can override a freeze",
+            name
+        );
+    }
+    if scoped
+        && let Some(auth) = position(MiddlewareStage::Auth)
+        && position(MiddlewareStage::Namespaces).is_none_or(|scope| scope < auth)
+    {
+        return Err(format!(
+            "{} doesn't run namespaces after auth, so credentials scoped to apps wouldn't be kept to them",
+            name
+        ));
+    }
+    Ok(())
+}
+
+impl Pipeline {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let scoped = config.auth.api_keys.is_some()
+            || config.auth.static_keys.iter().any(|key| key.apps.is_some());
+        let middleware = &config.middleware;
+        validate("middleware.chain", &middleware.chain, scoped)?;
+        for group in &middleware.groups {
+            validate(
+                &format!("middleware group {}", group.prefixes.join(",")),
+                &group.chain,
+                scoped,
+            )?;
+        }
+        Ok(Pipeline {
+            chain: middleware.chain.clone(),
+            groups: middleware.groups.clone(),
+        })
+    }
+
+    fn chain(&self, path: &str) -> &[MiddlewareStage] {
+        self.groups
+            .iter()
+            .find(|group| group.prefixes.iter().any(|prefix| path.starts_with(prefix)))
+            .map_or(&self.chain, |group| &group.chain)
+    }
+}
+
+/// Runs whichever stage sits at `position` in the request's chain.
+pub async fn slot(
+    position: usize,
+    req: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let stage = req
+        .app_data::<web::Data<Pipeline>>()
+        .and_then(|pipeline| pipeline.chain(req.path()).get(position).copied());
+
+    match stage {
+        None => next.call(req).await,
+        Some(MiddlewareStage::Slo) => slo::track(req, next).await,
+        Some(MiddlewareStage::RateLimit) => rate_limit::limit(req, next)
+            .await
+            .map(ServiceResponse::map_into_boxed_body),
+        Some(MiddlewareStage::Auth) => auth::authenticate(req, next)
+            .await
+            .map(ServiceResponse::map_into_boxed_body),
+        Some(MiddlewareStage::Audit) => audit::record(req, next).await,
+        Some(MiddlewareStage::Namespaces) => namespaces::scope(req, next).await,
+        Some(MiddlewareStage::Freezes) => freezes::enforce(req, next).await,
+        Some(MiddlewareStage::Idempotency) => idempotency::enforce(req, next).await,
+        Some(MiddlewareStage::Usage) => usage::track(req, next).await,
+        Some(MiddlewareStage::Plugins) => plugins::transform(req, next).await,
+        Some(MiddlewareStage::Schemas) => schemas::validate(req, next).await,
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::{PlacementQuery, PlacementReport, RegionPlacement};
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::capacity::CapacityMap;
+use crate::config::{PlacementConfig, PlacementStrategy};
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+/// Metadata recording which strategy placed a machine, and in which region.
+pub const PLACEMENT_METADATA_KEY: &str = "flyd_placement";
+pub const PLACEMENT_REGION_METADATA_KEY: &str = "flyd_placement_region";
+
+const ALL_APPS: &str = "*";
+
+/// Picks regions for machines created without one, by the app's `[placement]` rule.
+pub struct Placer {
+    rules: HashMap<String, PlacementConfig>,
+    /// Where each app's round robin is up to.
+    turns: Mutex<HashMap<String, usize>>,
+}
+
+impl Placer {
+    pub fn new(rules: HashMap<String, PlacementConfig>) -> Self {
+        Placer {
+            rules,
+            turns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Picks the region for a new machine of `app`, and records the decision in its create
+    /// body's metadata. Does nothing when the app has no rule, or every region of its rule
+    /// is blocked.
+    pub async fn place<B: Backend>(
+        &self,
+        backend: &B,
+        capacity: &CapacityMap,
+        app: &str,
+        body: &mut Value,
+    ) -> Result<(), String> {
+        let Some(rule) = self.rules.get(app).or_else(|| self.rules.get(ALL_APPS)) else {
+            return Ok(());
+        };
+        let mut regions = Vec::new();
+        for region in &rule.regions {
+            if !capacity.is_blocked(app, region).await {
+                regions.push(region);
+            }
+        }
+        if regions.is_empty() {
+            return Ok(());
+        }
+
+        let (strategy, region) = match rule.strategy {
+            PlacementStrategy::RoundRobin => {
+                let mut turns = self.turns.lock().unwrap();
+                let turn = turns.entry(app.to_string()).or_default();
+                let region = regions[*turn % regions.len()];
+                *turn = turn.wrapping_add(1);
+                ("round_robin", region)
+            }
+            PlacementStrategy::LeastPopulated => {
+                let machines = backend
+                    .list_machines(app)
+                    .await
+                    .map_err(|e| format!("Failed to list machines: {}", e))?;
+                let mut counts: HashMap<&str, usize> = HashMap::new();
+                for machine in machines.iter().filter(|machine| fleets::is_live(machine)) {
+                    if let Some(region) = machine["region"].as_str() {
+                        *counts.entry(region).or_default() += 1;
+                    }
+                }
+                // Ties go to the region listed first.
+                let region = regions
+                    .iter()
+                    .copied()
+                    .min_by_key(|region| counts.get(region.as_str()).copied().unwrap_or(0))
+                    .unwrap_or(regions[0]);
+                ("least_populated", region)
+            }
+        };
+        body["region"] = json!(region);
+        body["config"]["metadata"][PLACEMENT_METADATA_KEY] = json!(strategy);
+        body["config"]["metadata"][PLACEMENT_REGION_METADATA_KEY] = json!(region);
+        Ok(())
+    }
+}
+
+/// Groups the app's live machines by region, and within a region by the zone of the
+/// volume each mounts: machines sharing a zone share a host.
+async fn advise<B: Backend>(backend: &B, app: &str) -> Result<PlacementReport, String> {
+    let machines: Vec<Value> = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?
+        .into_iter()
+        .filter(fleets::is_live)
+        .collect();
+    let volumes = backend
+        .list_volumes(app)
+        .await
+        .map_err(|e| format!("Failed to list volumes: {}", e))?;
+    let zones: HashMap<&str, &str> = volumes
+        .iter()
+        .filter_map(|volume| Some((volume["id"].as_str()?, volume["zone"].as_str()?)))
+        .collect();
+
+    let mut regions: BTreeMap<String, RegionPlacement> = BTreeMap::new();
+    for machine in &machines {
+        let (Some(id), Some(region)) = (machine["id"].as_str(), machine["region"].as_str()) else {
+            continue;
+        };
+        let placement = regions
+            .entry(region.to_string())
+            .or_insert_with(|| RegionPlacement {
+                region: region.to_string(),
+                machines: 0,
+                zones: BTreeMap::new(),
+                unpinned: Vec::new(),
+            });
+        placement.machines += 1;
+        let zone = machine["config"]["mounts"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find_map(|mount| zones.get(mount["volume"].as_str()?));
+        match zone {
+            Some(zone) => placement
+                .zones
+                .entry(zone.to_string())
+                .or_default()
+                .push(id.to_string()),
+            None => placement.unpinned.push(id.to_string()),
+        }
+    }
+
+    let mut recommendations = Vec::new();
+    for placement in regions.values() {
+        if placement.machines == 1 && machines.len() > 1 {
+            recommendations.push(format!(
+                "{} has one machine in {}: a host failure there takes {} out of the region",
+                app, placement.region, app
+            ));
+        }
+        for (zone, ids) in &placement.zones {
+            if ids.len() > 1 {
+                recommendations.push(format!(
+                    "{} in {} share zone {}, so share a host: recreate their volumes with require_unique_zone",
+                    ids.join(", "),
+                    placement.region,
+                    zone
+                ));
+            }
+        }
+    }
+
+    Ok(PlacementReport {
+        app: app.to_string(),
+        regions: regions.into_values().collect(),
+        recommendations,
+    })
+}
+
+/// Whether an app's machines are spread so no single host holds all of a region's.
+#[get("/v0/apps/{app}/placement")]
+async fn get_placement(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<PlacementQuery>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let report = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        advise(docker.get_ref(), &path).await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner());
+        advise(&client, &path).await
+    };
+    match report {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_placement);
+}
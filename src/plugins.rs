@@ -0,0 +1,258 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, web};
+use rhai::{AST, Dynamic, Engine, EvalAltResult, Scope};
+use serde_json::{Value, json};
+
+use crate::config::PluginConfig;
+use crate::errors::AppError;
+
+/// Upper bound on script work per call, so a runaway loop can't wedge a worker.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+const ON_REQUEST: &str = "on_request";
+const ON_RESPONSE: &str = "on_response";
+
+struct Plugin {
+    name: String,
+    routes: Vec<String>,
+    ast: AST,
+    on_request: bool,
+    on_response: bool,
+}
+
+impl Plugin {
+    fn defines(&self, function: &str) -> bool {
+        match function {
+            ON_REQUEST => self.on_request,
+            _ => self.on_response,
+        }
+    }
+
+    fn applies_to(&self, path: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|route| path.starts_with(route))
+    }
+}
+
+enum Failure {
+    /// The script threw, e.g. to enforce a naming rule.
+    Rejected(String),
+    Broken(String),
+}
+
+/// Rhai scripts that rewrite JSON bodies on their way to the handlers and back, so
+/// deployments can inject defaults, strip fields or enforce conventions without forking.
+///
+/// A script defines `on_request(request)` and/or `on_response(response)`. Both receive a map
+/// with `method`, `path` and `body` (plus `query` or `status`) and return the replacement
+/// body, or `()` to leave it unchanged. Throwing from `on_request` rejects the request.
+pub struct Plugins {
+    engine: Engine,
+    plugins: Vec<Plugin>,
+}
+
+impl Plugins {
+    /// Returns `None` when no plugins are configured.
+    pub fn from_config(configs: &[PluginConfig]) -> Result<Option<Self>, String> {
+        if configs.is_empty() {
+            return Ok(None);
+        }
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let mut plugins = Vec::with_capacity(configs.len());
+        for config in configs {
+            let ast = engine
+                .compile_file(config.script.clone().into())
+                .map_err(|e| format!("Failed to load plugin {}: {}", config.name, e))?;
+            let defines = |name: &str| ast.iter_functions().any(|f| f.name == name);
+            let (on_request, on_response) = (defines(ON_REQUEST), defines(ON_RESPONSE));
+            if !on_request && !on_response {
+                return Err(format!(
+                    "Plugin {} defines neither {} nor {}",
+                    config.name, ON_REQUEST, ON_RESPONSE
+                ));
+            }
+            log::info!("Loaded plugin {} from {}", config.name, config.script);
+            plugins.push(Plugin {
+                name: config.name.clone(),
+                routes: config.routes.clone(),
+                ast,
+                on_request,
+                on_response,
+            });
+        }
+
+        Ok(Some(Plugins { engine, plugins }))
+    }
+
+    fn any_defines(&self, active: &[usize], function: &str) -> bool {
+        active
+            .iter()
+            .any(|&index| self.plugins[index].defines(function))
+    }
+
+    /// Runs `function` from each plugin in `active` in config order, threading the body
+    /// through. Returns `None` when no plugin changed it.
+    fn apply(
+        &self,
+        active: &[usize],
+        function: &str,
+        mut input: Value,
+    ) -> Result<Option<Value>, (String, Failure)> {
+        let mut changed = false;
+        for plugin in active.iter().map(|&index| &self.plugins[index]) {
+            if !plugin.defines(function) {
+                continue;
+            }
+            let failed = |failure| (plugin.name.clone(), failure);
+
+            let argument = rhai::serde::to_dynamic(&input)
+                .map_err(|e| failed(Failure::Broken(e.to_string())))?;
+            let result = self
+                .engine
+                .call_fn::<Dynamic>(&mut Scope::new(), &plugin.ast, function, (argument,))
+                .map_err(|e| match *e {
+                    EvalAltResult::ErrorRuntime(thrown, _) => {
+                        failed(Failure::Rejected(thrown.to_string()))
+                    }
+                    e => failed(Failure::Broken(e.to_string())),
+                })?;
+            if result.is_unit() {
+                continue;
+            }
+            input["body"] = rhai::serde::from_dynamic(&result)
+                .map_err(|e| failed(Failure::Broken(e.to_string())))?;
+            changed = true;
+        }
+        Ok(changed.then(|| input["body"].take()))
+    }
+}
+
+fn is_json(content_type: Option<&HeaderValue>) -> bool {
+    content_type
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+fn parse_body(body: &[u8]) -> Option<Value> {
+    if body.is_empty() {
+        return Some(Value::Null);
+    }
+    serde_json::from_slice(body).ok()
+}
+
+pub async fn transform<B: MessageBody + 'static>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let plugins = req.app_data::<web::Data<Plugins>>().cloned();
+    let active: Vec<usize> = plugins
+        .iter()
+        .flat_map(|plugins| plugins.plugins.iter().enumerate())
+        .filter(|(_, plugin)| plugin.applies_to(req.path()))
+        .map(|(index, _)| index)
+        .collect();
+    let Some(plugins) = plugins.filter(|_| !active.is_empty()) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+
+    let has_body = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .is_some_and(|length| length != "0");
+    if plugins.any_defines(&active, ON_REQUEST)
+        && (!has_body || is_json(req.headers().get(CONTENT_TYPE)))
+    {
+        let body = req.extract::<web::Bytes>().await?;
+        let request = parse_body(&body).map(|body| {
+            json!({
+                "method": method,
+                "path": path,
+                "query": req.query_string(),
+                "body": body,
+            })
+        });
+        let transformed = match request {
+            Some(request) => plugins.apply(&active, ON_REQUEST, request),
+            None => Ok(None),
+        };
+        let body = match transformed {
+            Ok(None) => body,
+            Ok(Some(transformed)) => {
+                let transformed = web::Bytes::from(transformed.to_string());
+                let headers = req.headers_mut();
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(transformed.len()));
+                transformed
+            }
+            Err((name, Failure::Rejected(reason))) => {
+                let response = AppError::bad_request(reason).into_response();
+                log::info!("Plugin {} rejected {} {}", name, method, path);
+                return Ok(req.into_response(response));
+            }
+            Err((name, Failure::Broken(e))) => {
+                log::error!("Plugin {} failed on request: {}", name, e);
+                let response = AppError::internal("Request plugin failed").into_response();
+                return Ok(req.into_response(response));
+            }
+        };
+        let (_, mut payload) = actix_http::h1::Payload::create(true);
+        payload.unread_data(body);
+        req.set_payload(Payload::from(payload));
+    }
+
+    let response = next.call(req).await?;
+    if !plugins.any_defines(&active, ON_RESPONSE) || !is_json(response.headers().get(CONTENT_TYPE))
+    {
+        return Ok(response.map_into_boxed_body());
+    }
+
+    let status = response.status();
+    let (request, response) = response.into_parts();
+    let (mut response, body) = response.into_parts();
+    let body = actix_web::body::to_bytes(body).await.map_err(|e| {
+        let e: Box<dyn std::error::Error> = e.into();
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    })?;
+
+    let transformed = match parse_body(&body) {
+        Some(parsed) => plugins.apply(
+            &active,
+            ON_RESPONSE,
+            json!({
+                "method": method,
+                "path": path,
+                "status": status.as_u16(),
+                "body": parsed,
+            }),
+        ),
+        None => Ok(None),
+    };
+    let body = match transformed {
+        Ok(None) => body,
+        Ok(Some(transformed)) => {
+            response.headers_mut().remove(CONTENT_LENGTH);
+            web::Bytes::from(transformed.to_string())
+        }
+        Err((name, Failure::Rejected(e) | Failure::Broken(e))) => {
+            log::error!("Plugin {} failed on response: {}", name, e);
+            let response = AppError::internal("Response plugin failed").into_response();
+            return Ok(ServiceResponse::new(request, response));
+        }
+    };
+
+    Ok(ServiceResponse::new(
+        request,
+        response.set_body(BoxBody::new(body)),
+    ))
+}
@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use flyd::models::PoolCheckoutRequest;
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::config::PoolConfig;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+pub const POOL_METADATA_KEY: &str = "flyd_pool";
+const IDLE: &str = "idle";
+const CHECKED_OUT: &str = "checked_out";
+const REPLENISH_INTERVAL: Duration = Duration::from_secs(30);
+const MACHINE_CHECKED_OUT: &str = "machine.checked_out";
+
+fn is_idle(machine: &Value, region: &str) -> bool {
+    fleets::is_live(machine)
+        && machine["state"] == "stopped"
+        && machine["region"] == region
+        && machine["config"]["metadata"][POOL_METADATA_KEY] == IDLE
+}
+
+/// Keeps `[[pools]]` topped up with stopped machines and hands them out. A machine leaves
+/// its pool when it's checked out, by its `flyd_pool` metadata turning from `idle` to
+/// `checked_out`.
+pub struct WarmPools {
+    pools: Vec<PoolConfig>,
+    /// Machines checked out that may still be listed as idle, so two checkouts never get
+    /// the same one.
+    claimed: Mutex<HashSet<(String, String)>>,
+    /// Pools being replenished, so a checkout and the loop don't both fill one.
+    replenishing: Mutex<HashSet<(String, String)>>,
+}
+
+impl WarmPools {
+    pub fn new(pools: Vec<PoolConfig>) -> Self {
+        WarmPools {
+            pools,
+            claimed: Mutex::new(HashSet::new()),
+            replenishing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Creates stopped machines until the pool has `size` idle ones.
+    async fn replenish<B: Backend>(&self, backend: &B, pool: &PoolConfig) -> Result<(), String> {
+        let key = (pool.app.clone(), pool.region.clone());
+        if !self.replenishing.lock().unwrap().insert(key.clone()) {
+            return Ok(());
+        }
+        let result = async {
+            let machines = backend
+                .list_machines(&pool.app)
+                .await
+                .map_err(|e| format!("Failed to list machines: {}", e))?;
+            let idle: HashSet<(String, String)> = machines
+                .iter()
+                .filter(|machine| is_idle(machine, &pool.region))
+                .filter_map(|machine| machine["id"].as_str())
+                .map(|id| (pool.app.clone(), id.to_string()))
+                .collect();
+            let idle = {
+                let mut claimed = self.claimed.lock().unwrap();
+                claimed.retain(|claim| claim.0 != pool.app || idle.contains(claim));
+                idle.iter()
+                    .filter(|machine| !claimed.contains(*machine))
+                    .count()
+            };
+            for _ in idle..pool.size {
+                let mut config = pool.config.clone();
+                config["metadata"][POOL_METADATA_KEY] = json!(IDLE);
+                let body = json!({ "region": pool.region, "config": config, "skip_launch": true });
+                backend
+                    .create_machine(&pool.app, &body)
+                    .await
+                    .map_err(|e| format!("Failed to create a pool machine: {}", e))?;
+            }
+            Ok(())
+        }
+        .await;
+        self.replenishing.lock().unwrap().remove(&key);
+        result
+    }
+
+    /// Claims an idle machine from the app's pool in `region`, or any of its pools, and
+    /// starts it.
+    async fn checkout<B: Backend>(
+        &self,
+        backend: &B,
+        app: &str,
+        region: Option<&str>,
+    ) -> Result<Option<Value>, String> {
+        let machines = backend
+            .list_machines(app)
+            .await
+            .map_err(|e| format!("Failed to list machines: {}", e))?;
+        let regions: Vec<&str> = self
+            .pools_of(app, region)
+            .map(|pool| pool.region.as_str())
+            .collect();
+        let machine = {
+            let mut claimed = self.claimed.lock().unwrap();
+            let machine = machines.into_iter().find(|machine| {
+                regions.iter().any(|region| is_idle(machine, region))
+                    && machine["id"]
+                        .as_str()
+                        .is_some_and(|id| !claimed.contains(&(app.to_string(), id.to_string())))
+            });
+            if let Some(id) = machine.as_ref().and_then(|machine| machine["id"].as_str()) {
+                claimed.insert((app.to_string(), id.to_string()));
+            }
+            machine
+        };
+        let Some(mut machine) = machine else {
+            return Ok(None);
+        };
+        let id = machine["id"].as_str().unwrap_or_default().to_string();
+        if let Err(e) = backend
+            .set_metadata(app, &id, POOL_METADATA_KEY, CHECKED_OUT)
+            .await
+        {
+            self.claimed
+                .lock()
+                .unwrap()
+                .remove(&(app.to_string(), id.clone()));
+            return Err(format!("Failed to check out machine {}: {}", id, e));
+        }
+        backend
+            .start_machine(app, &id)
+            .await
+            .map_err(|e| format!("Checked out machine {}, but failed to start it: {}", id, e))?;
+        machine["state"] = json!("starting");
+        machine["config"]["metadata"][POOL_METADATA_KEY] = json!(CHECKED_OUT);
+        Ok(Some(machine))
+    }
+
+    fn pools_of<'a>(
+        &'a self,
+        app: &'a str,
+        region: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a PoolConfig> {
+        self.pools.iter().filter(move |pool| {
+            pool.app == app && region.is_none_or(|region| region == pool.region)
+        })
+    }
+}
+
+/// Tops the pools up every 30 seconds, as checkouts can't replenish them while flyd
+/// isn't serving requests.
+pub async fn run<B: Backend>(pools: web::Data<WarmPools>, backend: B) {
+    let mut ticker = tokio::time::interval(REPLENISH_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for pool in &pools.pools {
+            if let Err(e) = pools.replenish(&backend, pool).await {
+                log::error!(
+                    "Failed to replenish the pool of {} in {}: {}",
+                    pool.app,
+                    pool.region,
+                    e
+                );
+            }
+        }
+    }
+}
+
+async fn checkout<B: Backend + Clone + 'static>(
+    pools: web::Data<WarmPools>,
+    events: &EventLog,
+    backend: B,
+    body: &PoolCheckoutRequest,
+) -> HttpResponse {
+    let result = pools
+        .checkout(&backend, &body.app_name, body.region.as_deref())
+        .await;
+    // The pool is topped up again whether or not it had a machine to give.
+    let (app, region) = (body.app_name.clone(), body.region.clone());
+    let replenished = pools.clone();
+    let replenisher = backend.clone();
+    actix_web::rt::spawn(async move {
+        for pool in replenished.pools_of(&app, region.as_deref()) {
+            if let Err(e) = replenished.replenish(&replenisher, pool).await {
+                log::error!(
+                    "Failed to replenish the pool of {} in {}: {}",
+                    pool.app,
+                    pool.region,
+                    e
+                );
+            }
+        }
+    });
+    match result {
+        Ok(Some(machine)) => {
+            events.record(
+                MACHINE_CHECKED_OUT,
+                Some(&body.app_name),
+                machine["id"].as_str(),
+                json!({ "region": machine["region"] }),
+            );
+            HttpResponse::Ok().json(machine)
+        }
+        Ok(None) => AppError::service_unavailable(format!(
+            "No idle machine in the pools of {}",
+            body.app_name
+        ))
+        .into_response(),
+        Err(e) => AppError::bad_gateway(e).into_response(),
+    }
+}
+
+/// Starts an idle machine from the app's pool and hands it out right away, without
+/// waiting for it to be started; the pool is replenished in the background.
+#[post("/v0/pool/checkout")]
+async fn checkout_machine(
+    req: HttpRequest,
+    body: web::Json<PoolCheckoutRequest>,
+    pools: web::Data<WarmPools>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    if pools
+        .pools_of(&body.app_name, body.region.as_deref())
+        .next()
+        .is_none()
+    {
+        return AppError::not_found(match &body.region {
+            Some(region) => format!("{} has no pool in {}", body.app_name, region),
+            None => format!("{} has no pool", body.app_name),
+        })
+        .into_response();
+    }
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return checkout(pools, &events, docker.get_ref().clone(), &body).await;
+    }
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    checkout(pools, &events, client, &body).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(checkout_machine);
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{HttpMessage, HttpRequest, Responder, get, web};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, Session};
+use flyd::models::PortForwardQuery;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::auth::{self, Identity};
+use crate::config::PortForwardConfig;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::reachability;
+use crate::shutdown;
+use crate::slo::SloTracker;
+
+/// Bytes read from the machine per WebSocket message.
+const CHUNK_BYTES: usize = 16 * 1024;
+
+/// Tunnels open at once, by a hash of the `Authorization` header that opened them.
+pub struct PortForwards {
+    config: PortForwardConfig,
+    open: Mutex<HashMap<String, usize>>,
+}
+
+/// One of a token's tunnels, given back when dropped.
+struct Slot {
+    forwards: web::Data<PortForwards>,
+    token: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.forwards.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.token) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.token);
+            }
+        }
+    }
+}
+
+impl PortForwards {
+    pub fn new(config: PortForwardConfig) -> Self {
+        PortForwards {
+            config,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn acquire(forwards: &web::Data<Self>, token: String) -> Option<Slot> {
+        let mut open = forwards.open.lock().unwrap();
+        let count = open.entry(token.clone()).or_default();
+        if *count >= forwards.config.max_connections_per_token {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            forwards: forwards.clone(),
+            token,
+        })
+    }
+}
+
+/// Why a tunnel closed.
+#[derive(Clone, Copy)]
+enum Ended {
+    ClientClosed,
+    MachineClosed,
+    Idle,
+    ShuttingDown,
+}
+
+impl Ended {
+    fn name(self) -> &'static str {
+        match self {
+            Ended::ClientClosed => "client_closed",
+            Ended::MachineClosed => "machine_closed",
+            Ended::Idle => "idle",
+            Ended::ShuttingDown => "shutting_down",
+        }
+    }
+}
+
+/// Copies bytes both ways until either side closes, neither sends anything for `idle`,
+/// or flyd starts shutting down. Returns the bytes sent to and received from the machine.
+async fn tunnel(
+    session: &mut Session,
+    messages: &mut AggregatedMessageStream,
+    machine: TcpStream,
+    idle: Duration,
+) -> (u64, u64, Ended) {
+    let (mut reader, mut writer) = machine.into_split();
+    let mut buf = vec![0; CHUNK_BYTES];
+    let (mut sent, mut received) = (0, 0);
+    let ended = loop {
+        tokio::select! {
+            message = messages.recv() => {
+                let bytes = match message {
+                    Some(Ok(AggregatedMessage::Binary(bytes))) => bytes,
+                    Some(Ok(AggregatedMessage::Text(text))) => text.into_bytes(),
+                    Some(Ok(AggregatedMessage::Ping(ping))) => {
+                        if session.pong(&ping).await.is_err() {
+                            break Ended::ClientClosed;
+                        }
+                        continue;
+                    }
+                    Some(Ok(AggregatedMessage::Pong(_))) => continue,
+                    Some(Ok(AggregatedMessage::Close(_)) | Err(_)) | None => {
+                        break Ended::ClientClosed;
+                    }
+                };
+                if writer.write_all(&bytes).await.is_err() {
+                    break Ended::MachineClosed;
+                }
+                sent += bytes.len() as u64;
+            }
+            read = reader.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break Ended::MachineClosed,
+                Ok(n) => {
+                    if session.binary(buf[..n].to_vec()).await.is_err() {
+                        break Ended::ClientClosed;
+                    }
+                    received += n as u64;
+                }
+            },
+            _ = tokio::time::sleep(idle) => break Ended::Idle,
+            _ = shutdown::draining() => break Ended::ShuttingDown,
+        }
+    };
+    (sent, received, ended)
+}
+
+/// Opens a WebSocket that tunnels bytes to `port` on the machine's private address, e.g.
+/// for a terminal or debugger in the browser. Machine output arrives as binary messages;
+/// text and binary messages from the client are both written to the machine as bytes.
+#[get("/v0/machines/proxy")]
+async fn proxy_machine(
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<PortForwardQuery>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+    forwards: web::Data<PortForwards>,
+    events: web::Data<EventLog>,
+) -> impl Responder {
+    if let Err(e) = actix_http::ws::handshake(req.head()) {
+        return AppError::bad_request(format!("Expected a WebSocket upgrade: {}", e))
+            .into_response();
+    }
+    let config = &forwards.config;
+    if !config.allowed_ports.is_empty() && !config.allowed_ports.contains(&query.port) {
+        return AppError::forbidden(format!("Port {} may not be forwarded", query.port))
+            .into_response();
+    }
+    let machine = match reachability::fetch_machine(
+        &req,
+        &query.app_name,
+        &query.machine_id,
+        query.use_private_api,
+        &http_client,
+        slo,
+    )
+    .await
+    {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+    let state = machine["state"].as_str().unwrap_or("unknown");
+    if state != "started" {
+        return AppError::conflict(format!(
+            "Machine {} is {}, not started",
+            query.machine_id, state
+        ))
+        .into_response();
+    }
+    let Some(ip) = machine["private_ip"]
+        .as_str()
+        .and_then(|address| address.parse::<IpAddr>().ok())
+    else {
+        return AppError::bad_gateway("Fly returned no private IP for the machine").into_response();
+    };
+
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .map(|header| auth::token_hash(header.as_bytes()))
+        .unwrap_or_default();
+    let Some(slot) = PortForwards::acquire(&forwards, token) else {
+        return AppError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "At most {} tunnels may be open at once per token",
+                config.max_connections_per_token
+            ),
+        )
+        .into_response();
+    };
+
+    let address = SocketAddr::new(ip, query.port);
+    let timeout = Duration::from_millis(config.connect_timeout_ms);
+    let upstream = match tokio::time::timeout(timeout, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            return AppError::bad_gateway(format!("Failed to connect to {}: {}", address, e))
+                .into_response();
+        }
+        Err(_) => {
+            return AppError::bad_gateway(format!(
+                "{} didn't accept a connection within {}ms",
+                address,
+                timeout.as_millis()
+            ))
+            .into_response();
+        }
+    };
+    let (response, mut session, messages) = match actix_ws::handle(&req, body) {
+        Ok(upgraded) => upgraded,
+        Err(e) => return e.error_response(),
+    };
+
+    let subject = req
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.subject.clone());
+    let idle = Duration::from_secs(config.idle_timeout_secs.max(1));
+    let query = query.into_inner();
+    log::info!(
+        "Forwarding to {} port {} of {} for {}",
+        query.machine_id,
+        query.port,
+        query.app_name,
+        subject.as_deref().unwrap_or("anonymous")
+    );
+    actix_web::rt::spawn(async move {
+        let _slot = slot;
+        let started = Instant::now();
+        let mut messages = messages.aggregate_continuations();
+        let (sent, received, ended) = tunnel(&mut session, &mut messages, upstream, idle).await;
+        let reason = match ended {
+            Ended::Idle => Some(CloseReason {
+                code: CloseCode::Normal,
+                description: Some(format!("No traffic for {}s", idle.as_secs())),
+            }),
+            Ended::ShuttingDown => Some(CloseCode::Away.into()),
+            Ended::MachineClosed => Some(CloseCode::Normal.into()),
+            Ended::ClientClosed => None,
+        };
+        let _ = session.close(reason).await;
+        events.record(
+            "machine.port_forward",
+            Some(&query.app_name),
+            Some(&query.machine_id),
+            json!({
+                "port": query.port,
+                "subject": subject,
+                "bytes_sent": sent,
+                "bytes_received": received,
+                "duration_secs": started.elapsed().as_secs(),
+                "ended": ended.name(),
+            }),
+        );
+    });
+    response
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(proxy_machine);
+}
@@ -0,0 +1,284 @@
+use std::time::Duration;
+
+use actix_web::{HttpResponse, Responder, get, web};
+use chrono::{TimeDelta, Utc};
+use flyd::models::{PreemptibleChurn, PreemptibleReport, Preemption, PreemptionReason};
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::capacity::CapacityMap;
+use crate::config::PreemptibleConfig;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets;
+use crate::pricing;
+use crate::store::{Store, StoreError};
+
+pub const TIER_METADATA_KEY: &str = "flyd_tier";
+const PREEMPTIBLE: &str = "preemptible";
+const PREEMPTIONS: &str = "preemptions";
+const CHURN: &str = "preemptible_churn";
+const MACHINE_PREEMPTED: &str = "machine.preempted";
+const MACHINE_RESTORED: &str = "machine.restored";
+
+pub fn is_preemptible(machine: &Value) -> bool {
+    machine["config"]["metadata"][TIER_METADATA_KEY] == PREEMPTIBLE
+}
+
+fn monthly_cost(machine: &Value) -> f64 {
+    pricing::machine_monthly_cost(&machine["config"]["guest"])
+}
+
+fn preemption_key(app: &str, machine_id: &str) -> String {
+    format!("{}/{}", app, machine_id)
+}
+
+async fn churn(store: &Store, app: &str) -> Result<PreemptibleChurn, StoreError> {
+    Ok(store
+        .get::<PreemptibleChurn>(CHURN, app)
+        .await?
+        .unwrap_or_else(|| PreemptibleChurn {
+            app: app.to_string(),
+            ..Default::default()
+        }))
+}
+
+async fn preempted(store: &Store, app: &str) -> Result<Vec<Preemption>, StoreError> {
+    let mut preempted: Vec<Preemption> = store
+        .list::<Preemption>(PREEMPTIONS)
+        .await?
+        .into_iter()
+        .filter(|preemption| preemption.app == app)
+        .collect();
+    preempted.sort_by_key(|preemption| preemption.preempted_at);
+    Ok(preempted)
+}
+
+/// The app's preemptible machines, and everything that keeps them stopped or brings
+/// them back.
+struct Tier<'a, B> {
+    config: &'a PreemptibleConfig,
+    backend: &'a B,
+    store: &'a Store,
+    events: &'a EventLog,
+    capacity: &'a CapacityMap,
+    drainer: Option<&'a web::Data<Drainer>>,
+}
+
+impl<B: Backend> Tier<'_, B> {
+    fn budget(&self, app: &str) -> Option<f64> {
+        self.config
+            .max_monthly_cost
+            .get(app)
+            .or_else(|| self.config.max_monthly_cost.get("*"))
+            .copied()
+    }
+
+    fn short_of_capacity(&self, region: Option<&str>) -> bool {
+        self.config.yield_to_capacity
+            && region.is_some_and(|region| self.capacity.is_rejecting(region))
+    }
+
+    async fn preempt(
+        &self,
+        app: &str,
+        machine: &Value,
+        reason: PreemptionReason,
+        churn: &mut PreemptibleChurn,
+    ) -> Result<(), String> {
+        let id = machine["id"].as_str().unwrap_or_default();
+        drain::drain(self.drainer, self.backend, app, id).await;
+        self.backend
+            .stop_machine(app, id)
+            .await
+            .map_err(|e| format!("Failed to stop machine {}: {}", id, e))?;
+
+        let preemption = Preemption {
+            app: app.to_string(),
+            machine_id: id.to_string(),
+            name: machine["name"].as_str().map(str::to_string),
+            region: machine["region"].as_str().map(str::to_string),
+            reason,
+            monthly_cost: monthly_cost(machine),
+            preempted_at: Utc::now(),
+            config: machine["config"].clone(),
+        };
+        self.store
+            .put(PREEMPTIONS, &preemption_key(app, id), &preemption)
+            .await
+            .map_err(|e| e.to_string())?;
+        churn.preemptions += 1;
+        churn.last_preempted_at = Some(preemption.preempted_at);
+        self.events.record(
+            MACHINE_PREEMPTED,
+            Some(app),
+            Some(id),
+            json!({ "reason": reason, "region": preemption.region, "monthly_cost": preemption.monthly_cost }),
+        );
+        Ok(())
+    }
+
+    /// Starts the preempted machine again, or recreates it from its config if it's gone.
+    async fn restore(
+        &self,
+        preemption: &Preemption,
+        machine: Option<&Value>,
+        churn: &mut PreemptibleChurn,
+    ) -> Result<(), String> {
+        let (app, id) = (&preemption.app, &preemption.machine_id);
+        let recreated = match machine {
+            // Someone else started it; nothing to restore.
+            Some(machine) if machine["state"] == "started" => None,
+            Some(_) => {
+                self.backend
+                    .start_machine(app, id)
+                    .await
+                    .map_err(|e| format!("Failed to start machine {}: {}", id, e))?;
+                drain::restore(self.drainer, self.backend, app, id).await;
+                churn.restorations += 1;
+                Some(false)
+            }
+            None => {
+                let body = json!({
+                    "name": preemption.name,
+                    "region": preemption.region,
+                    "config": preemption.config,
+                });
+                self.backend
+                    .create_machine(app, &body)
+                    .await
+                    .map_err(|e| format!("Failed to recreate machine {}: {}", id, e))?;
+                churn.recreations += 1;
+                Some(true)
+            }
+        };
+        self.store
+            .delete(PREEMPTIONS, &preemption_key(app, id))
+            .await
+            .map_err(|e| e.to_string())?;
+        if let Some(recreated) = recreated {
+            self.events.record(
+                MACHINE_RESTORED,
+                Some(app),
+                Some(id),
+                json!({ "reason": preemption.reason, "recreated": recreated }),
+            );
+        }
+        Ok(())
+    }
+
+    /// Stops preemptible machines in regions short of capacity, then the most expensive
+    /// ones until the app is under budget; then brings back, oldest first, those
+    /// preempted long enough ago that fit again.
+    async fn manage(&self, app: &str) -> Result<(), String> {
+        let machines: Vec<Value> = self
+            .backend
+            .list_machines(app)
+            .await
+            .map_err(|e| format!("Failed to list machines: {}", e))?
+            .into_iter()
+            .filter(fleets::is_live)
+            .collect();
+        let budget = self.budget(app);
+        let mut cost: f64 = machines
+            .iter()
+            .filter(|machine| machine["state"] == "started")
+            .map(monthly_cost)
+            .sum();
+        let mut churn = churn(self.store, app).await.map_err(|e| e.to_string())?;
+
+        let mut started: Vec<&Value> = machines
+            .iter()
+            .filter(|machine| machine["state"] == "started" && is_preemptible(machine))
+            .collect();
+        started.sort_by(|a, b| monthly_cost(b).total_cmp(&monthly_cost(a)));
+        let mut stopped = Vec::new();
+        for machine in started {
+            let reason = if self.short_of_capacity(machine["region"].as_str()) {
+                PreemptionReason::Capacity
+            } else if budget.is_some_and(|budget| cost > budget) {
+                PreemptionReason::Budget
+            } else {
+                continue;
+            };
+            self.preempt(app, machine, reason, &mut churn).await?;
+            cost -= monthly_cost(machine);
+            stopped.push(machine["id"].as_str().unwrap_or_default());
+        }
+
+        let cutoff = Utc::now() - TimeDelta::seconds(self.config.restore_after_secs as i64);
+        for preemption in preempted(self.store, app)
+            .await
+            .map_err(|e| e.to_string())?
+        {
+            // Just stopped, from the machines listed before that.
+            if stopped.contains(&preemption.machine_id.as_str()) {
+                continue;
+            }
+            let machine = machines
+                .iter()
+                .find(|machine| machine["id"] == preemption.machine_id);
+            let running = machine.is_some_and(|machine| machine["state"] == "started");
+            if !running
+                && (preemption.preempted_at > cutoff
+                    || self.short_of_capacity(preemption.region.as_deref())
+                    || budget.is_some_and(|budget| cost + preemption.monthly_cost > budget))
+            {
+                continue;
+            }
+            self.restore(&preemption, machine, &mut churn).await?;
+            if !running {
+                cost += preemption.monthly_cost;
+            }
+        }
+
+        self.store
+            .put(CHURN, app, &churn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+pub async fn run<B: Backend>(
+    config: PreemptibleConfig,
+    backend: B,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    capacity: web::Data<CapacityMap>,
+    drainer: Option<web::Data<Drainer>>,
+) {
+    let tier = Tier {
+        config: &config,
+        backend: &backend,
+        store: &store,
+        events: &events,
+        capacity: &capacity,
+        drainer: drainer.as_ref(),
+    };
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        for app in &config.apps {
+            if let Err(e) = tier.manage(app).await {
+                log::error!("Failed to manage preemptible machines of {}: {}", app, e);
+            }
+        }
+    }
+}
+
+/// The app's preempted machines and how often its preemptible machines have churned.
+#[get("/v0/apps/{app}/preemptible")]
+async fn get_preemptible(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    let report = match (churn(&store, &path).await, preempted(&store, &path).await) {
+        (Ok(churn), Ok(preempted)) => PreemptibleReport { churn, preempted },
+        (Err(e), _) | (_, Err(e)) => {
+            return AppError::internal(e.to_string()).into_response();
+        }
+    };
+    HttpResponse::Ok().json(report)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_preemptible);
+}
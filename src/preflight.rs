@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use chrono::Utc;
+use flyd::models::{DependencyStatus, PreflightReport, TokenState};
+
+use crate::api_keys::ApiKeys;
+use crate::audit::AuditLog;
+use crate::auth::Authenticator;
+use crate::client_ip::TrustedProxies;
+use crate::config::{BackendKind, Config};
+use crate::deploy_breaker::DeployBreaker;
+use crate::environments::Environments;
+use crate::events::EventLog;
+use crate::exec::ExecPolicies;
+use crate::fly_client;
+use crate::keep_warm::KeepWarm;
+use crate::log_sinks::LogSinks;
+use crate::namespaces::Namespaces;
+use crate::notify;
+use crate::object_store::ObjectStore;
+use crate::pipeline::Pipeline;
+use crate::plugins::Plugins;
+use crate::response_headers::ResponseHeaders;
+use crate::scripts::ScriptLibrary;
+use crate::siem::SiemExporters;
+use crate::signatures::ImageVerifier;
+use crate::store::Store;
+use crate::tls;
+use crate::token_health::TokenMonitor;
+use crate::virtual_hosts::VirtualHosts;
+use crate::write_queue::WriteQueue;
+
+/// Whether `flyd check` was asked for instead of serving.
+pub fn requested() -> bool {
+    std::env::args().nth(1).as_deref() == Some("check")
+}
+
+fn outcome(
+    target: Option<String>,
+    started: Option<Instant>,
+    result: Result<(), String>,
+) -> DependencyStatus {
+    DependencyStatus {
+        ok: result.is_ok(),
+        target,
+        latency_ms: started.map(|started| started.elapsed().as_millis() as u64),
+        error: result.err(),
+    }
+}
+
+async fn timed(
+    timeout: Duration,
+    target: Option<String>,
+    check: impl Future<Output = Result<(), String>>,
+) -> DependencyStatus {
+    let started = Instant::now();
+    let result = tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| Err(format!("No answer within {}ms", timeout.as_millis())));
+    outcome(target, Some(started), result)
+}
+
+/// Any answer short of a 5xx means the host is reachable, as for readiness.
+async fn upstream(http: &reqwest::Client, url: &str) -> Result<(), String> {
+    let status = http
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .status();
+    if status.is_server_error() {
+        return Err(format!("Answered {}", status));
+    }
+    Ok(())
+}
+
+fn ok<T>(result: Result<T, String>) -> Result<(), String> {
+    result.map(|_| ())
+}
+
+/// Builds everything configured as policy the way startup would, so a bad pattern, CIDR,
+/// key or certificate fails here rather than when flyd starts.
+fn policies(config: &Config, http: &reqwest::Client) -> BTreeMap<&'static str, Result<(), String>> {
+    let events = web::Data::new(EventLog::new(WriteQueue::new(&config.write_queue).0));
+    let auth = TrustedProxies::from_cidrs(&config.proxy.trusted_cidrs).and_then(|proxies| {
+        let api_keys = ApiKeys::from_config(config)?.map(Arc::new);
+        Authenticator::from_config(config, &proxies, api_keys.as_ref()).map(|_| ())
+    });
+    BTreeMap::from([
+        ("auth", auth),
+        ("middleware", ok(Pipeline::from_config(config))),
+        (
+            "response_headers",
+            ok(ResponseHeaders::from_config(&config.response_headers)),
+        ),
+        ("plugins", ok(Plugins::from_config(&config.plugins))),
+        ("namespaces", ok(Namespaces::from_config(config))),
+        ("virtual_hosts", ok(VirtualHosts::from_config(config))),
+        ("environments", ok(Environments::new(&config.environments))),
+        ("exec", ok(ExecPolicies::new(&config.exec, events.clone()))),
+        (
+            "scripts",
+            ok(ScriptLibrary::new(&config.scripts, events.clone())),
+        ),
+        ("log_sinks", ok(LogSinks::from_config(&config.log_sinks))),
+        (
+            "siem",
+            ok(SiemExporters::from_config(&config.siem, http.clone())),
+        ),
+        (
+            "keep_warm",
+            ok(KeepWarm::from_config(&config.keep_warm, http.clone())),
+        ),
+        ("audit", ok(AuditLog::from_config(config.audit.as_ref()))),
+        (
+            "deploy_breaker",
+            ok(DeployBreaker::from_config(
+                config,
+                http.clone(),
+                web::Data::new(Store::default()),
+                events,
+            )),
+        ),
+        (
+            "image_signatures",
+            ok(ImageVerifier::from_config(config, http.clone())),
+        ),
+        (
+            "object_storage",
+            ok(config
+                .object_storage
+                .as_ref()
+                .map(|storage| ObjectStore::from_config(storage, http.clone()))
+                .transpose()),
+        ),
+        (
+            "notifications",
+            ok(config
+                .notifications
+                .smtp
+                .as_ref()
+                .map(notify::Mailer::from_config)
+                .transpose()),
+        ),
+        (
+            "tls",
+            ok(config
+                .server
+                .tls
+                .as_ref()
+                .map(tls::server_config)
+                .transpose()),
+        ),
+    ])
+}
+
+/// Checks flyd's config, the upstream hosts it calls, its store's schema, every Fly token it
+/// holds and every policy it'd load, printing a [`PreflightReport`] as JSON. Returns whether
+/// every check passed.
+pub async fn run() -> bool {
+    let mut checks = BTreeMap::new();
+    match Config::load() {
+        Ok(config) => {
+            checks.insert("config".to_string(), outcome(None, None, Ok(())));
+            checks.extend(check(&config).await);
+        }
+        Err(e) => {
+            checks.insert("config".to_string(), outcome(None, None, Err(e)));
+        }
+    }
+    let report = PreflightReport {
+        ok: checks.values().all(|check| check.ok),
+        checks,
+        checked_at: Utc::now(),
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).unwrap_or_default()
+    );
+    report.ok
+}
+
+async fn check(config: &Config) -> BTreeMap<String, DependencyStatus> {
+    fly_client::configure(&config.upstream, &config.cache);
+    let http = reqwest::Client::new();
+    let timeout = Duration::from_millis(config.readiness.timeout_ms.max(1));
+    let mut checks = BTreeMap::new();
+
+    if config.backend == BackendKind::Fly {
+        let mut hosts = vec![("upstream.public", fly_client::api_hostname(false))];
+        if config.use_private_api {
+            hosts.push(("upstream.private", fly_client::api_hostname(true)));
+        }
+        for (name, url) in hosts {
+            let status = timed(timeout, Some(url.to_string()), upstream(&http, url)).await;
+            checks.insert(name.to_string(), status);
+        }
+    }
+
+    let store = timed(
+        timeout,
+        Some(config.store.path.clone()),
+        Store::check_schema(&config.store),
+    )
+    .await;
+    checks.insert("store".to_string(), store);
+
+    if config.backend == BackendKind::Fly {
+        let events = web::Data::new(EventLog::new(WriteQueue::new(&config.write_queue).0));
+        let monitor = TokenMonitor::new(config, http.clone(), events);
+        let started = Instant::now();
+        // Tokens a timed-out check didn't get to stay unknown, and fail below.
+        let _ = tokio::time::timeout(timeout, monitor.check()).await;
+        for status in monitor.statuses() {
+            let result = match status.state {
+                TokenState::Valid | TokenState::Expiring => Ok(()),
+                TokenState::Unknown => Err(status
+                    .error
+                    .unwrap_or_else(|| format!("No answer within {}ms", timeout.as_millis()))),
+                TokenState::Expired => Err(format!(
+                    "Expired{}",
+                    status
+                        .expires_at
+                        .map_or(String::new(), |at| format!(" at {}", at))
+                )),
+                TokenState::Invalid => Err(status.error.unwrap_or_else(|| "Invalid".to_string())),
+            };
+            checks.insert(
+                format!("token.{}", status.name),
+                outcome(Some(status.fingerprint), Some(started), result),
+            );
+        }
+    }
+
+    for (name, result) in policies(config, &http) {
+        checks.insert(format!("policy.{}", name), outcome(None, None, result));
+    }
+    checks
+}
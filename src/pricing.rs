@@ -0,0 +1,46 @@
+// Approximate list prices (USD per month) for a machine running the whole month, from
+// https://fly.io/docs/about/pricing/. Only creation estimates account for regions.
+const SHARED_CPU_MONTHLY: f64 = 1.94;
+const SHARED_CPU_INCLUDED_MEMORY_MB: u64 = 256;
+const PERFORMANCE_CPU_MONTHLY: f64 = 31.0;
+const PERFORMANCE_CPU_INCLUDED_MEMORY_MB: u64 = 2048;
+const EXTRA_MEMORY_GB_MONTHLY: f64 = 5.0;
+const VOLUME_GB_MONTHLY: f64 = 0.15;
+/// Compute prices in the regions that cost more, relative to the rest.
+const REGION_MULTIPLIERS: &[(&str, f64)] = &[
+    ("bom", 1.2),
+    ("gru", 1.3),
+    ("jnb", 1.3),
+    ("nrt", 1.15),
+    ("sin", 1.2),
+    ("syd", 1.2),
+];
+
+/// Estimated monthly cost of a machine with the given `guest` config if it stays started.
+pub fn machine_monthly_cost(guest: &serde_json::Value) -> f64 {
+    let cpus = guest["cpus"].as_u64().unwrap_or(1);
+    let memory_mb = guest["memory_mb"]
+        .as_u64()
+        .unwrap_or(SHARED_CPU_INCLUDED_MEMORY_MB);
+
+    let (cpu_price, included_memory_mb) = match guest["cpu_kind"].as_str() {
+        Some("performance") => (PERFORMANCE_CPU_MONTHLY, PERFORMANCE_CPU_INCLUDED_MEMORY_MB),
+        _ => (SHARED_CPU_MONTHLY, SHARED_CPU_INCLUDED_MEMORY_MB),
+    };
+
+    let extra_memory_mb = memory_mb.saturating_sub(cpus * included_memory_mb);
+    cpus as f64 * cpu_price + extra_memory_mb as f64 / 1024.0 * EXTRA_MEMORY_GB_MONTHLY
+}
+
+/// `machine_monthly_cost` for a machine in `region`, from the bundled regional prices.
+pub fn machine_monthly_cost_in(guest: &serde_json::Value, region: Option<&str>) -> f64 {
+    let multiplier = REGION_MULTIPLIERS
+        .iter()
+        .find(|(code, _)| Some(*code) == region)
+        .map_or(1.0, |(_, multiplier)| *multiplier);
+    machine_monthly_cost(guest) * multiplier
+}
+
+pub fn volume_monthly_cost(size_gb: u64) -> f64 {
+    size_gb as f64 * VOLUME_GB_MONTHLY
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::Utc;
+use flyd::models::{DependencyStatus, HealthReport};
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::config::{Config, ReadinessConfig};
+use crate::consistency::Consistency;
+use crate::docker::DockerBackend;
+use crate::fly_client;
+use crate::shutdown;
+use crate::store::Store;
+use crate::version::{self, UpdateChecker};
+
+/// Probes what flyd needs to serve, reusing the last result for `readiness.cache_secs`.
+pub struct Probes {
+    config: ReadinessConfig,
+    use_private_api: bool,
+    last: Mutex<Option<(Instant, HealthReport)>>,
+}
+
+impl Probes {
+    pub fn new(config: &Config) -> Self {
+        Probes {
+            use_private_api: config
+                .readiness
+                .use_private_api
+                .unwrap_or(config.use_private_api),
+            config: config.readiness.clone(),
+            last: Mutex::new(None),
+        }
+    }
+
+    async fn timed<E: Display>(
+        &self,
+        target: Option<String>,
+        probe: impl Future<Output = Result<(), E>>,
+    ) -> DependencyStatus {
+        let started = Instant::now();
+        let timeout = Duration::from_millis(self.config.timeout_ms.max(1));
+        let error = match tokio::time::timeout(timeout, probe).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("No answer within {}ms", timeout.as_millis())),
+        };
+        DependencyStatus {
+            ok: error.is_none(),
+            latency_ms: Some(started.elapsed().as_millis() as u64),
+            target,
+            error,
+        }
+    }
+
+    /// Any answer short of a 5xx means Fly is reachable; the probe carries no token, so
+    /// a 401 or 404 is expected.
+    async fn fly_api(&self, http: &reqwest::Client) -> DependencyStatus {
+        let url = fly_client::api_hostname(self.use_private_api);
+        self.timed(Some(url.to_string()), async {
+            let status = http
+                .get(url)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?
+                .status();
+            if status.is_server_error() {
+                return Err(format!("Answered {}", status));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn probe(
+        &self,
+        http: &reqwest::Client,
+        store: &Store,
+        consistency: &Consistency,
+        docker: Option<&DockerBackend>,
+    ) -> HealthReport {
+        let (fly_api, stored, docker) = tokio::join!(
+            self.fly_api(http),
+            self.timed(None, async {
+                store
+                    .get::<Value>("readiness", "probe")
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }),
+            async {
+                match docker {
+                    Some(docker) => Some(
+                        self.timed(None, async { docker.list_apps("").await.map(|_| ()) })
+                            .await,
+                    ),
+                    None => None,
+                }
+            },
+        );
+        let consistent = consistency.is_consistent();
+        let mut checks = BTreeMap::from([
+            ("fly_api".to_string(), fly_api),
+            ("store".to_string(), stored),
+            (
+                "consistency".to_string(),
+                DependencyStatus {
+                    ok: consistent,
+                    target: None,
+                    latency_ms: None,
+                    error: (!consistent).then(|| {
+                        "The startup check hasn't finished, or found issues it didn't repair"
+                            .to_string()
+                    }),
+                },
+            ),
+        ]);
+        if let Some(docker) = docker {
+            checks.insert("docker".to_string(), docker);
+        }
+        HealthReport {
+            ready: checks.values().all(|check| check.ok),
+            draining: false,
+            checks,
+            checked_at: Utc::now(),
+        }
+    }
+
+    async fn report(
+        &self,
+        http: &reqwest::Client,
+        store: &Store,
+        consistency: &Consistency,
+        docker: Option<&DockerBackend>,
+    ) -> HealthReport {
+        let cached = self
+            .last
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(at, _)| at.elapsed() < Duration::from_secs(self.config.cache_secs))
+            .map(|(_, report)| report.clone());
+        let mut report = match cached {
+            Some(report) => report,
+            None => {
+                let report = self.probe(http, store, consistency, docker).await;
+                for (name, check) in report.checks.iter().filter(|(_, check)| !check.ok) {
+                    log::warn!(
+                        "Readiness: {} is failing: {}",
+                        name,
+                        check.error.as_deref().unwrap_or_default()
+                    );
+                }
+                *self.last.lock().unwrap() = Some((Instant::now(), report.clone()));
+                report
+            }
+        };
+        report.draining = shutdown::is_draining();
+        report.ready &= !report.draining;
+        report
+    }
+}
+
+/// Whether flyd's process is up, whatever its dependencies are doing.
+#[get("/health/live")]
+async fn live(updates: web::Data<UpdateChecker>) -> impl Responder {
+    let mut response = HttpResponse::Ok();
+    if let Some(version) = updates.available() {
+        response.insert_header((version::UPDATE_AVAILABLE_HEADER, version));
+    }
+    response.json(json!({ "status": "ok" }))
+}
+
+/// Whether flyd can serve: 503 with the failing dependencies while it can't reach the
+/// Machines API or its store, before the startup check finishes, and while draining.
+#[get("/health/ready")]
+async fn ready(
+    req: HttpRequest,
+    probes: web::Data<Probes>,
+    http_client: web::Data<reqwest::Client>,
+    store: web::Data<Store>,
+    consistency: web::Data<Consistency>,
+) -> impl Responder {
+    let docker = req.app_data::<web::Data<DockerBackend>>();
+    let report = probes
+        .report(
+            &http_client,
+            &store,
+            &consistency,
+            docker.map(|docker| docker.get_ref()),
+        )
+        .await;
+    if report.ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(live).service(ready);
+}
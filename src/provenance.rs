@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::Utc;
+use flyd::models::{MachineProvenance, Provenance, ProvenanceQuery};
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+const GIT_SHA_KEY: &str = "flyd_git_sha";
+const BUILD_URL_KEY: &str = "flyd_build_url";
+const SBOM_KEY: &str = "flyd_sbom";
+const PROVENANCE: &str = "provenance";
+
+/// The provenance recorded in a machine config's metadata.
+pub fn read(config: &Value) -> Provenance {
+    let metadata = &config["metadata"];
+    let field = |key| metadata[key].as_str().map(str::to_string);
+    Provenance {
+        git_sha: field(GIT_SHA_KEY),
+        build_url: field(BUILD_URL_KEY),
+        sbom: field(SBOM_KEY),
+    }
+}
+
+/// Records `provenance` in the config's metadata, leaving fields it doesn't set alone.
+pub fn set(config: &mut Value, provenance: &Provenance) {
+    let fields = [
+        (
+            GIT_SHA_KEY,
+            provenance.git_sha.as_ref().map(|sha| sha.to_lowercase()),
+        ),
+        (BUILD_URL_KEY, provenance.build_url.clone()),
+        (SBOM_KEY, provenance.sbom.clone()),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            config["metadata"][key] = json!(value);
+        }
+    }
+}
+
+/// Validates provenance before it's recorded anywhere.
+pub fn check(provenance: &Provenance) -> Result<(), String> {
+    let mut config = json!({});
+    set(&mut config, provenance);
+    validate(&config)
+}
+
+fn is_url(value: &str, schemes: &[&str]) -> bool {
+    reqwest::Url::parse(value).is_ok_and(|url| schemes.contains(&url.scheme()))
+}
+
+/// Checks the provenance in a machine config's metadata, so the index only holds
+/// values that can be queried for.
+pub fn validate(config: &Value) -> Result<(), String> {
+    let provenance = read(config);
+    if let Some(sha) = &provenance.git_sha
+        && !((7..=40).contains(&sha.len()) && sha.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Err(format!("{} is not a git commit SHA", sha));
+    }
+    if let Some(url) = &provenance.build_url
+        && !is_url(url, &["http", "https"])
+    {
+        return Err(format!("Build URL {} is not an http(s) URL", url));
+    }
+    if let Some(sbom) = &provenance.sbom {
+        let is_digest = sbom.split_once(':').is_some_and(|(algorithm, hex)| {
+            matches!(algorithm, "sha256" | "sha384" | "sha512")
+                && !hex.is_empty()
+                && hex.chars().all(|c| c.is_ascii_hexdigit())
+        });
+        if !is_digest && !is_url(sbom, &["http", "https", "oci", "s3"]) {
+            return Err(format!(
+                "SBOM {} is neither a URL nor a digest like sha256:...",
+                sbom
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn key(app: &str, machine_id: &str) -> String {
+    format!("{}/{}", app, machine_id)
+}
+
+/// Indexes a machine as the Machines API returned it, or drops it from the index if it
+/// no longer has any provenance.
+pub async fn index(store: &Store, app: &str, machine: &Value) {
+    let Some(machine_id) = machine["id"].as_str() else {
+        return;
+    };
+    let provenance = read(&machine["config"]);
+    let result = if provenance == Provenance::default() {
+        store
+            .delete(PROVENANCE, &key(app, machine_id))
+            .await
+            .map(drop)
+    } else {
+        let entry = MachineProvenance {
+            app: app.to_string(),
+            machine_id: machine_id.to_string(),
+            name: machine["name"].as_str().map(str::to_string),
+            region: machine["region"].as_str().map(str::to_string),
+            image: machine["config"]["image"].as_str().map(str::to_string),
+            provenance,
+            recorded_at: Utc::now(),
+        };
+        store.put(PROVENANCE, &key(app, machine_id), &entry).await
+    };
+    if let Err(e) = result {
+        log::warn!(
+            "Failed to index provenance of machine {}: {}",
+            machine_id,
+            e
+        );
+    }
+}
+
+fn matches(entry: &MachineProvenance, query: &ProvenanceQuery) -> bool {
+    let exact =
+        |wanted: &Option<String>, value: &Option<String>| wanted.is_none() || wanted == value;
+    query.app.as_ref().is_none_or(|app| *app == entry.app)
+        && query.git_sha.as_ref().is_none_or(|sha| {
+            entry
+                .provenance
+                .git_sha
+                .as_ref()
+                .is_some_and(|recorded| recorded.starts_with(&sha.to_lowercase()))
+        })
+        && exact(&query.build_url, &entry.provenance.build_url)
+        && exact(&query.sbom, &entry.provenance.sbom)
+        && exact(&query.image, &entry.image)
+}
+
+/// The matching entries whose machines still exist and still run what was recorded.
+/// Entries that no longer hold are corrected or dropped from the index on the way.
+async fn verify<B: Backend>(
+    backend: &B,
+    store: &Store,
+    matched: Vec<MachineProvenance>,
+) -> Result<Vec<MachineProvenance>, String> {
+    let mut by_app: BTreeMap<String, Vec<MachineProvenance>> = BTreeMap::new();
+    for entry in matched {
+        by_app.entry(entry.app.clone()).or_default().push(entry);
+    }
+
+    let mut verified = Vec::new();
+    for (app, entries) in by_app {
+        let machines = backend
+            .list_machines(&app)
+            .await
+            .map_err(|e| format!("Failed to list machines of {}: {}", app, e))?;
+        for entry in entries {
+            let live = machines.iter().find(|machine| {
+                machine["id"] == entry.machine_id.as_str() && machine["state"] != "destroyed"
+            });
+            match live {
+                Some(machine)
+                    if read(&machine["config"]) == entry.provenance
+                        && machine["config"]["image"].as_str() == entry.image.as_deref() =>
+                {
+                    verified.push(entry);
+                }
+                Some(machine) => {
+                    index(store, &app, machine).await;
+                }
+                None => {
+                    if let Err(e) = store
+                        .delete(PROVENANCE, &key(&app, &entry.machine_id))
+                        .await
+                    {
+                        log::warn!(
+                            "Failed to drop provenance of machine {}: {}",
+                            entry.machine_id,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    }
+    Ok(verified)
+}
+
+/// Which machines run what, e.g. `?git_sha=1a2b3c4` for every machine running code from
+/// that commit, across apps.
+#[get("/v0/provenance")]
+async fn query_provenance(
+    req: HttpRequest,
+    query: web::Query<ProvenanceQuery>,
+    store: web::Data<Store>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let entries = match store.list::<MachineProvenance>(PROVENANCE).await {
+        Ok(entries) => entries,
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    let matched = entries
+        .into_iter()
+        .filter(|entry| matches(entry, &query))
+        .collect();
+
+    let verified = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        verify(docker.get_ref(), &store, matched).await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner());
+        verify(&client, &store, matched).await
+    };
+    match verified {
+        Ok(mut verified) => {
+            verified.sort_by(|a, b| (&a.app, &a.machine_id).cmp(&(&b.app, &b.machine_id)));
+            HttpResponse::Ok().json(verified)
+        }
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(query_provenance);
+}
@@ -0,0 +1,344 @@
+use serde_json::Value;
+
+/// A `q=` filter over machines, e.g.
+/// `state == "started" && region in ["iad", "ord"] && metadata.team == "payments"`.
+///
+/// Fields are dotted paths into the machine, falling back to its config, so `metadata.team`
+/// reads `config.metadata.team`. Comparisons are `==`, `!=`, `<`, `<=`, `>`, `>=`, `in` and
+/// `contains`, combined with `&&`, `||`, `!` and parentheses. A field on its own is true
+/// when it's set to anything but `false`, `null` or `""`.
+pub struct Expression(Node);
+
+enum Node {
+    Or(Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Compare(Operand, Op, Operand),
+    Truthy(Operand),
+}
+
+enum Operand {
+    Field(Vec<String>),
+    Literal(Value),
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    Contains,
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Ident(word) => write!(f, "{}", word),
+            Token::Str(text) => write!(f, "{:?}", text),
+            Token::Num(number) => write!(f, "{}", number),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 14] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "[", "]", ",",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = source.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        if c == '"' || c == '\'' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("unterminated string at {}", start)),
+                    Some('\\') => {
+                        if let Some(escaped) = chars.get(i + 1) {
+                            text.push(*escaped);
+                        }
+                        i += 2;
+                    }
+                    Some(quote) if *quote == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(other) => {
+                        text.push(*other);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push((start, Token::Str(text)));
+        } else if c.is_ascii_digit()
+            || (c == '-' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            i += 1;
+            while chars
+                .get(i)
+                .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+            {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text
+                .parse()
+                .map_err(|_| format!("invalid number {} at {}", text, start))?;
+            tokens.push((start, Token::Num(number)));
+        } else if c.is_alphabetic() || c == '_' {
+            while chars
+                .get(i)
+                .is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+            {
+                i += 1;
+            }
+            tokens.push((start, Token::Ident(chars[start..i].iter().collect())));
+        } else {
+            let rest: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) else {
+                return Err(format!("unexpected {} at {}", c, start));
+            };
+            i += symbol.len();
+            tokens.push((start, Token::Symbol(symbol)));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn at(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |(offset, _)| *offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, expected: &Token) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        if self.eat(&Token::Symbol(symbol)) {
+            Ok(())
+        } else {
+            Err(format!("expected {} at {}", symbol, self.at()))
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+        while self.eat(&Token::Symbol("||")) || self.eat(&Token::Ident("or".to_string())) {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while self.eat(&Token::Symbol("&&")) || self.eat(&Token::Ident("and".to_string())) {
+            node = Node::And(Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat(&Token::Symbol("!")) || self.eat(&Token::Ident("not".to_string())) {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::Symbol("(")) {
+            let node = self.or()?;
+            self.expect(")")?;
+            return Ok(node);
+        }
+        let left = self.operand()?;
+        let op = match self.peek() {
+            Some(Token::Symbol("==")) => Op::Eq,
+            Some(Token::Symbol("!=")) => Op::Ne,
+            Some(Token::Symbol("<")) => Op::Lt,
+            Some(Token::Symbol("<=")) => Op::Le,
+            Some(Token::Symbol(">")) => Op::Gt,
+            Some(Token::Symbol(">=")) => Op::Ge,
+            Some(Token::Ident(word)) if word == "in" => Op::In,
+            Some(Token::Ident(word)) if word == "contains" => Op::Contains,
+            _ => return Ok(Node::Truthy(left)),
+        };
+        self.position += 1;
+        Ok(Node::Compare(left, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let at = self.at();
+        match self.next() {
+            Some(Token::Str(text)) => Ok(Operand::Literal(Value::String(text))),
+            Some(Token::Num(number)) => Ok(Operand::Literal(number.into())),
+            Some(Token::Ident(word)) => Ok(match word.as_str() {
+                "true" => Operand::Literal(Value::Bool(true)),
+                "false" => Operand::Literal(Value::Bool(false)),
+                "null" => Operand::Literal(Value::Null),
+                _ => Operand::Field(word.split('.').map(str::to_string).collect()),
+            }),
+            Some(Token::Symbol("[")) => {
+                let mut items = Vec::new();
+                while !self.eat(&Token::Symbol("]")) {
+                    if !items.is_empty() {
+                        self.expect(",")?;
+                    }
+                    match self.operand()? {
+                        Operand::Literal(value) => items.push(value),
+                        Operand::Field(_) => {
+                            return Err(format!("lists may only hold values, at {}", at));
+                        }
+                    }
+                }
+                Ok(Operand::Literal(Value::Array(items)))
+            }
+            Some(token) => Err(format!("unexpected {} at {}", token, at)),
+            None => Err(format!("expected a field or value at {}", at)),
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Expression, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        end: source.len(),
+    };
+    let node = parser.or()?;
+    if parser.peek().is_some() {
+        return Err(format!("unexpected input at {}", parser.at()));
+    }
+    Ok(Expression(node))
+}
+
+/// The value at a dotted path of the machine, or of its config.
+pub fn field<'a>(machine: &'a Value, path: &str) -> &'a Value {
+    lookup(
+        machine,
+        &path.split('.').map(str::to_string).collect::<Vec<_>>(),
+    )
+}
+
+fn lookup<'a>(machine: &'a Value, path: &[String]) -> &'a Value {
+    let at = |root: &'a Value| {
+        path.iter()
+            .try_fold(root, |value, segment| value.get(segment))
+    };
+    at(machine)
+        .or_else(|| at(&machine["config"]))
+        .unwrap_or(&Value::Null)
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Orders values for sorting: numbers and strings among themselves, then anything else,
+/// then unset fields last.
+pub fn sort_order(a: &Value, b: &Value) -> std::cmp::Ordering {
+    let rank = |value: &Value| match value {
+        Value::Number(_) => 0,
+        Value::String(_) => 1,
+        Value::Null => 3,
+        _ => 2,
+    };
+    rank(a)
+        .cmp(&rank(b))
+        .then_with(|| order(a, b).unwrap_or(std::cmp::Ordering::Equal))
+}
+
+fn order(a: &Value, b: &Value) -> Option<std::cmp::Ordering> {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false)) && *value != ""
+}
+
+impl Node {
+    fn eval(&self, machine: &Value) -> bool {
+        let resolve = |operand: &Operand| match operand {
+            Operand::Field(path) => lookup(machine, path).clone(),
+            Operand::Literal(value) => value.clone(),
+        };
+        match self {
+            Node::Or(a, b) => a.eval(machine) || b.eval(machine),
+            Node::And(a, b) => a.eval(machine) && b.eval(machine),
+            Node::Not(node) => !node.eval(machine),
+            Node::Truthy(operand) => truthy(&resolve(operand)),
+            Node::Compare(left, op, right) => {
+                let (left, right) = (resolve(left), resolve(right));
+                match op {
+                    Op::Eq => equal(&left, &right),
+                    Op::Ne => !equal(&left, &right),
+                    Op::Lt => order(&left, &right).is_some_and(|o| o.is_lt()),
+                    Op::Le => order(&left, &right).is_some_and(|o| o.is_le()),
+                    Op::Gt => order(&left, &right).is_some_and(|o| o.is_gt()),
+                    Op::Ge => order(&left, &right).is_some_and(|o| o.is_ge()),
+                    Op::In => right
+                        .as_array()
+                        .is_some_and(|items| items.iter().any(|item| equal(item, &left))),
+                    Op::Contains => match (&left, &right) {
+                        (Value::String(text), Value::String(part)) => text.contains(part.as_str()),
+                        (Value::Array(items), _) => items.iter().any(|item| equal(item, &right)),
+                        (Value::Object(map), Value::String(key)) => map.contains_key(key),
+                        _ => false,
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl Expression {
+    pub fn matches(&self, machine: &Value) -> bool {
+        self.0.eval(machine)
+    }
+}
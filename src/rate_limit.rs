@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, web};
+use chrono::Utc;
+use redis::aio::ConnectionManager;
+
+use crate::auth::{Authenticator, Identity};
+use crate::client_ip::TrustedProxies;
+use crate::config::{RateLimitConfig, SharedBackend};
+use crate::diagnostics::{self, Phase};
+use crate::errors::AppError;
+
+const MEMORY_SWEEP_THRESHOLD: usize = 10_000;
+
+// GCRA on Redis' clock so replicas with skewed clocks still agree. Returns how many
+// milliseconds until the request would be allowed, or 0 when it is, and how far ahead of
+// now the caller's theoretical arrival time then is.
+const GCRA_SCRIPT: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local emission = tonumber(ARGV[1])
+local limit = tonumber(ARGV[2])
+local tat = tonumber(redis.call('GET', KEYS[1]) or now)
+if tat < now then tat = now end
+local new_tat = tat + emission
+if new_tat - now > limit then
+  return {new_tat - now - limit, tat - now}
+end
+redis.call('SET', KEYS[1], new_tat, 'PX', new_tat - now)
+return {0, new_tat - now}
+";
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+enum Backend {
+    /// Theoretical arrival time (ms) per caller.
+    Memory(Mutex<HashMap<String, i64>>),
+    Redis(ConnectionManager, redis::Script),
+}
+
+/// Generic cell rate limiting per caller: `burst` requests at once, refilled at
+/// `requests_per_minute`.
+pub struct RateLimiter {
+    requests_per_minute: u32,
+    burst: u32,
+    emission_ms: i64,
+    limit_ms: i64,
+    backend: Backend,
+}
+
+/// How a request fared against its caller's limit.
+struct Decision {
+    /// How long until the request would have been allowed, if it wasn't.
+    wait: Option<Duration>,
+    /// How far the caller's theoretical arrival time is ahead of now.
+    ahead_ms: i64,
+}
+
+impl RateLimiter {
+    /// Returns `None` when rate limiting is disabled.
+    pub fn from_config(
+        config: &RateLimitConfig,
+        redis: Option<&ConnectionManager>,
+    ) -> Result<Option<Self>, String> {
+        if config.requests_per_minute == 0 {
+            return Ok(None);
+        }
+
+        let backend = match (config.backend, redis) {
+            (SharedBackend::Memory, _) => Backend::Memory(Mutex::new(HashMap::new())),
+            (SharedBackend::Redis, Some(redis)) => {
+                Backend::Redis(redis.clone(), redis::Script::new(GCRA_SCRIPT))
+            }
+            (SharedBackend::Redis, None) => {
+                return Err("rate_limit.backend = \"redis\" requires [redis]".to_string());
+            }
+        };
+
+        let emission_ms = (60_000 / i64::from(config.requests_per_minute)).max(1);
+        Ok(Some(RateLimiter {
+            requests_per_minute: config.requests_per_minute,
+            burst: config.burst.max(1),
+            emission_ms,
+            limit_ms: emission_ms * i64::from(config.burst.max(1)),
+            backend,
+        }))
+    }
+
+    async fn check(&self, key: &str) -> Result<Decision, redis::RedisError> {
+        let (wait_ms, ahead_ms) = match &self.backend {
+            Backend::Memory(tats) => {
+                let now = Utc::now().timestamp_millis();
+                let mut tats = tats.lock().unwrap();
+                if tats.len() > MEMORY_SWEEP_THRESHOLD {
+                    tats.retain(|_, tat| *tat > now);
+                }
+                let tat = tats.get(key).copied().unwrap_or(now).max(now);
+                let new_tat = tat + self.emission_ms;
+                if new_tat - now > self.limit_ms {
+                    (new_tat - now - self.limit_ms, tat - now)
+                } else {
+                    tats.insert(key.to_string(), new_tat);
+                    (0, new_tat - now)
+                }
+            }
+            Backend::Redis(connection, script) => {
+                script
+                    .key(format!("flyd:ratelimit:{}", key))
+                    .arg(self.emission_ms)
+                    .arg(self.limit_ms)
+                    .invoke_async::<(i64, i64)>(&mut connection.clone())
+                    .await?
+            }
+        };
+
+        Ok(Decision {
+            wait: (wait_ms > 0).then(|| Duration::from_millis(wait_ms as u64)),
+            ahead_ms,
+        })
+    }
+
+    /// `RateLimit-Limit`, `-Remaining`, `-Reset` and `-Policy`, as in the IETF draft.
+    /// Reset is when the caller's full burst is available again.
+    fn headers(&self, decision: &Decision) -> [(HeaderName, HeaderValue); 4] {
+        let remaining = if decision.wait.is_some() {
+            0
+        } else {
+            ((self.limit_ms - decision.ahead_ms) / self.emission_ms).max(0)
+        };
+        let reset_secs = (decision.ahead_ms.max(0) as u64).div_ceil(1000);
+        [
+            (RATELIMIT_LIMIT, HeaderValue::from(self.burst)),
+            (RATELIMIT_REMAINING, HeaderValue::from(remaining)),
+            (RATELIMIT_RESET, HeaderValue::from(reset_secs)),
+            (
+                RATELIMIT_POLICY,
+                HeaderValue::from_str(&format!(
+                    "{};w=60;burst={}",
+                    self.requests_per_minute, self.burst
+                ))
+                .expect("the policy is ASCII"),
+            ),
+        ]
+    }
+}
+
+/// Whose bucket a request draws from: its caller's, when a provider that checks
+/// credentials identifies them, else its client IP's. The Authorization header itself
+/// won't do, since every made-up one would get a fresh bucket, and neither will the Fly
+/// token provider, which takes any header as a token.
+fn bucket(req: &ServiceRequest) -> String {
+    let identity = req.extensions().get::<Identity>().cloned().or_else(|| {
+        req.app_data::<web::Data<Authenticator>>()
+            .and_then(|authenticator| authenticator.identify(req.request()).ok().flatten())
+    });
+    match identity {
+        Some(identity) if identity.provider != "fly_token" => {
+            format!("subject:{}:{}", identity.provider, identity.subject)
+        }
+        _ => {
+            let ip = req
+                .app_data::<web::Data<TrustedProxies>>()
+                .and_then(|proxies| proxies.client_ip(req.peer_addr(), req.headers()));
+            format!("ip:{}", ip.map(|ip| ip.to_string()).unwrap_or_default())
+        }
+    }
+}
+
+pub async fn limit<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+
+    let key = bucket(&req);
+    let started = Instant::now();
+    let checked = limiter.check(&key).await;
+    diagnostics::record(Phase::Policy, started.elapsed());
+    let decision = match checked {
+        Ok(decision) => decision,
+        // Failing open: losing Redis shouldn't take the proxy down with it.
+        Err(e) => {
+            log::error!("Rate limiter unavailable, allowing request: {}", e);
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body);
+        }
+    };
+    let headers = limiter.headers(&decision);
+    if let Some(wait) = decision.wait {
+        let mut response = AppError::new(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded")
+            .with_retry_after(wait)
+            .into_response();
+        for (name, value) in headers {
+            response.headers_mut().insert(name, value);
+        }
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let mut response = next.call(req).await?;
+    for (name, value) in headers {
+        response.headers_mut().insert(name, value);
+    }
+    Ok(response.map_into_left_body())
+}
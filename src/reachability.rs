@@ -0,0 +1,397 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::{
+    CheckMachineQuery, CheckProtocol, PingQuery, PingReport, PortProbe, Reachability,
+    ServiceCheckReport,
+};
+use serde_json::Value;
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+/// Set on every Fly machine; without it flyd can't route to machines' private addresses.
+const PRIVATE_IP_ENV: &str = "FLY_PRIVATE_IP";
+
+fn service_ports(machine: &serde_json::Value) -> Vec<u16> {
+    let mut ports: Vec<u16> = machine["config"]["services"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|service| service["internal_port"].as_u64())
+        .filter_map(|port| u16::try_from(port).ok())
+        .collect();
+    ports.sort();
+    ports.dedup();
+    ports
+}
+
+async fn probe(
+    http: &reqwest::Client,
+    ip: IpAddr,
+    port: u16,
+    http_path: Option<&str>,
+    timeout: Duration,
+) -> (PortProbe, Option<ErrorKind>) {
+    let mut result = PortProbe {
+        port,
+        open: false,
+        rtt_ms: None,
+        http_status: None,
+        error: None,
+    };
+
+    let started = Instant::now();
+    let kind =
+        match tokio::time::timeout(timeout, TcpStream::connect(SocketAddr::new(ip, port))).await {
+            Ok(Ok(_)) => {
+                result.open = true;
+                result.rtt_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+                None
+            }
+            Ok(Err(e)) => {
+                result.error = Some(e.to_string());
+                Some(e.kind())
+            }
+            Err(_) => {
+                result.error = Some(format!("No answer within {}ms", timeout.as_millis()));
+                Some(ErrorKind::TimedOut)
+            }
+        };
+
+    if let (true, Some(path)) = (result.open, http_path) {
+        let url = format!("http://{}{}", SocketAddr::new(ip, port), path);
+        match http.get(url).timeout(timeout).send().await {
+            Ok(response) => result.http_status = Some(response.status().as_u16()),
+            Err(e) => result.error = Some(format!("HTTP check failed: {}", e)),
+        }
+    }
+
+    (result, kind)
+}
+
+/// The machine, for reaching over the private network from here.
+pub async fn fetch_machine(
+    req: &HttpRequest,
+    app_name: &str,
+    machine_id: &str,
+    use_private_api: bool,
+    http_client: &reqwest::Client,
+    slo: web::Data<SloTracker>,
+) -> Result<Value, HttpResponse> {
+    if std::env::var_os(PRIVATE_IP_ENV).is_none() {
+        return Err(AppError::service_unavailable(
+            "Reaching machines requires flyd to run inside the Fly private network",
+        )
+        .into_response());
+    }
+    let (headers, api_hostname) = prepare_request(req, use_private_api)?;
+    FlyClient::new(http_client.clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .get_machine(app_name, machine_id)
+        .await
+        .map_err(|e| AppError::from(&e).into_response())
+}
+
+/// Probes a machine's private address, telling "started but the app isn't listening"
+/// (connections refused) apart from "down" (no answer).
+#[get("/v0/machines/{id}/ping")]
+async fn ping_machine(
+    req: HttpRequest,
+    machine_id: web::Path<String>,
+    query: web::Query<PingQuery>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    // Anything but a path would change the host the probe goes to, e.g. `@evil.host/`.
+    if let Some(path) = &query.http_path
+        && !path.starts_with('/')
+    {
+        return AppError::bad_request(format!("http_path {} must start with /", path))
+            .into_response();
+    }
+    let machine = match fetch_machine(
+        &req,
+        &query.app_name,
+        &machine_id,
+        query.use_private_api,
+        &http_client,
+        slo,
+    )
+    .await
+    {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+
+    let state = machine["state"].as_str().unwrap_or("unknown").to_string();
+    let address = machine["private_ip"].as_str().map(str::to_string);
+    let mut report = PingReport {
+        machine_id: machine_id.into_inner(),
+        state,
+        address: address.clone(),
+        reachability: Reachability::NotStarted,
+        probes: Vec::new(),
+    };
+    if report.state != "started" {
+        return HttpResponse::Ok().json(report);
+    }
+
+    let Some(ip) = address.and_then(|address| address.parse::<IpAddr>().ok()) else {
+        return AppError::bad_gateway("Fly returned no private IP for the machine").into_response();
+    };
+    let ports = match query.port {
+        Some(port) => vec![port],
+        None => service_ports(&machine),
+    };
+    if ports.is_empty() {
+        return AppError::bad_request("Machine has no service ports; pass ?port=").into_response();
+    }
+
+    let timeout = Duration::from_millis(config.machines.ping_timeout_ms);
+    let mut refused = false;
+    for port in ports {
+        let (probe, kind) =
+            probe(&http_client, ip, port, query.http_path.as_deref(), timeout).await;
+        refused |= kind == Some(ErrorKind::ConnectionRefused);
+        report.probes.push(probe);
+    }
+
+    report.reachability = if report.probes.iter().any(|probe| probe.open) {
+        Reachability::Reachable
+    } else if refused {
+        Reachability::NotListening
+    } else {
+        Reachability::Unreachable
+    };
+
+    HttpResponse::Ok().json(report)
+}
+
+async fn check_http(
+    http: &reqwest::Client,
+    address: SocketAddr,
+    path: &str,
+    timeout: Duration,
+    report: &mut ServiceCheckReport,
+) -> Result<(), String> {
+    let url = format!("http://{}{}", address, path);
+    let status = http
+        .get(url)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|e| format!("HTTP check failed: {}", e))?
+        .status();
+    report.http_status = Some(status.as_u16());
+    report.healthy = status.is_success() || status.is_redirection();
+    Ok(())
+}
+
+/// gRPC needs HTTP/2, which machines' plaintext ports only speak to clients that assume it.
+fn grpc_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .expect("an HTTP/2 client builds with default settings")
+    })
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn take_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// The `status` of a `grpc.health.v1.HealthCheckResponse`, skipping any other fields.
+fn serving_status(mut message: &[u8]) -> Option<u64> {
+    let mut status = 0;
+    while !message.is_empty() {
+        let tag = take_varint(&mut message)?;
+        match tag & 7 {
+            0 => {
+                let value = take_varint(&mut message)?;
+                if tag >> 3 == 1 {
+                    status = value;
+                }
+            }
+            2 => {
+                let len = usize::try_from(take_varint(&mut message)?).ok()?;
+                message = message.get(len..)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(status)
+}
+
+async fn check_grpc(
+    address: SocketAddr,
+    service: &str,
+    timeout: Duration,
+    report: &mut ServiceCheckReport,
+) -> Result<(), String> {
+    let mut request = Vec::new();
+    if !service.is_empty() {
+        request.push(0x0a);
+        put_varint(&mut request, service.len() as u64);
+        request.extend_from_slice(service.as_bytes());
+    }
+    // A length-prefixed message: uncompressed, then its length.
+    let mut body = vec![0];
+    body.extend_from_slice(&(request.len() as u32).to_be_bytes());
+    body.extend_from_slice(&request);
+
+    let response = grpc_client()
+        .post(format!("http://{}/grpc.health.v1.Health/Check", address))
+        .header(reqwest::header::CONTENT_TYPE, "application/grpc")
+        .header(reqwest::header::TE, "trailers")
+        .body(body)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|e| format!("gRPC check failed: {}", e))?;
+    report.http_status = Some(response.status().as_u16());
+    if !response.status().is_success() {
+        return Err(format!("Answered HTTP {}", response.status()));
+    }
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    // Failed calls answer with no message, and their status in the headers.
+    if let Some(code) = header("grpc-status").and_then(|code| code.parse::<u32>().ok()) {
+        report.grpc_status = Some(code);
+        if code != 0 {
+            return Err(header("grpc-message")
+                .unwrap_or_else(|| format!("The call failed with gRPC status {}", code)));
+        }
+    }
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to read the gRPC response: {}", e))?;
+    let status = bytes
+        .get(5..)
+        .filter(|_| bytes[0] == 0)
+        .and_then(serving_status)
+        .ok_or("The server answered with no health status")?;
+    report.grpc_status.get_or_insert(0);
+    report.serving_status = Some(
+        match status {
+            1 => "SERVING",
+            2 => "NOT_SERVING",
+            3 => "SERVICE_UNKNOWN",
+            _ => "UNKNOWN",
+        }
+        .to_string(),
+    );
+    report.healthy = status == 1;
+    Ok(())
+}
+
+/// Asks the machine's service itself whether it's healthy: a GET of `path`, or the
+/// standard gRPC health check. A started machine can still be failing its checks.
+#[get("/v0/machines/{id}/check")]
+async fn check_machine(
+    req: HttpRequest,
+    machine_id: web::Path<String>,
+    query: web::Query<CheckMachineQuery>,
+    http_client: web::Data<reqwest::Client>,
+    config: web::Data<Config>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let path = query.path.as_deref().unwrap_or("/");
+    if !path.starts_with('/') {
+        return AppError::bad_request(format!("path {} must start with /", path)).into_response();
+    }
+    let machine = match fetch_machine(
+        &req,
+        &query.app_name,
+        &machine_id,
+        query.use_private_api,
+        &http_client,
+        slo,
+    )
+    .await
+    {
+        Ok(machine) => machine,
+        Err(response) => return response,
+    };
+
+    let mut report = ServiceCheckReport {
+        machine_id: machine_id.into_inner(),
+        state: machine["state"].as_str().unwrap_or("unknown").to_string(),
+        address: machine["private_ip"].as_str().map(str::to_string),
+        port: query
+            .port
+            .or_else(|| service_ports(&machine).first().copied()),
+        protocol: query.protocol,
+        healthy: false,
+        serving_status: None,
+        http_status: None,
+        grpc_status: None,
+        latency_ms: None,
+        error: None,
+    };
+    if report.state != "started" {
+        report.error = Some("Fly doesn't report the machine as started".to_string());
+        return HttpResponse::Ok().json(report);
+    }
+    let Some(ip) = report
+        .address
+        .as_ref()
+        .and_then(|address| address.parse::<IpAddr>().ok())
+    else {
+        return AppError::bad_gateway("Fly returned no private IP for the machine").into_response();
+    };
+    let Some(port) = report.port else {
+        return AppError::bad_request("Machine has no service ports; pass ?port=").into_response();
+    };
+
+    let address = SocketAddr::new(ip, port);
+    let timeout = Duration::from_millis(config.machines.ping_timeout_ms);
+    let started = Instant::now();
+    let checked = match query.protocol {
+        CheckProtocol::Http => check_http(&http_client, address, path, timeout, &mut report).await,
+        CheckProtocol::Grpc => {
+            let service = query.service.as_deref().unwrap_or_default();
+            check_grpc(address, service, timeout, &mut report).await
+        }
+    };
+    report.latency_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+    report.error = checked.err();
+    HttpResponse::Ok().json(report)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(ping_machine).service(check_machine);
+}
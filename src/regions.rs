@@ -0,0 +1,117 @@
+use actix_web::HttpRequest;
+use actix_web::web;
+
+use crate::client_ip::TrustedProxies;
+
+/// Header Fly's edge sets to the region that took the request, which is the one closest
+/// to the client.
+const FLY_REGION_HEADER: &str = "fly-region";
+
+/// Fly's regions and where they are, by their airport's latitude and longitude.
+const REGIONS: &[(&str, f64, f64)] = &[
+    ("ams", 52.31, 4.77),
+    ("arn", 59.65, 17.93),
+    ("atl", 33.64, -84.43),
+    ("bog", 4.70, -74.14),
+    ("bom", 19.09, 72.87),
+    ("bos", 42.36, -71.01),
+    ("cdg", 49.01, 2.55),
+    ("den", 39.86, -104.67),
+    ("dfw", 32.90, -97.04),
+    ("ewr", 40.69, -74.17),
+    ("eze", -34.82, -58.54),
+    ("fra", 50.03, 8.57),
+    ("gdl", 20.52, -103.31),
+    ("gig", -22.81, -43.25),
+    ("gru", -23.43, -46.47),
+    ("hkg", 22.31, 113.91),
+    ("iad", 38.94, -77.46),
+    ("jnb", -26.14, 28.24),
+    ("lax", 33.94, -118.41),
+    ("lhr", 51.47, -0.46),
+    ("mad", 40.47, -3.56),
+    ("mia", 25.79, -80.29),
+    ("nrt", 35.76, 140.39),
+    ("ord", 41.98, -87.90),
+    ("otp", 44.57, 26.10),
+    ("phx", 33.43, -112.01),
+    ("qro", 20.62, -100.19),
+    ("scl", -33.39, -70.79),
+    ("sea", 47.45, -122.31),
+    ("sin", 1.36, 103.99),
+    ("sjc", 37.36, -121.93),
+    ("syd", -33.95, 151.18),
+    ("waw", 52.17, 20.97),
+    ("yul", 45.47, -73.74),
+    ("yyz", 43.68, -79.63),
+];
+
+fn coordinates(region: &str) -> Option<(f64, f64)> {
+    REGIONS
+        .iter()
+        .find(|(code, _, _)| *code == region)
+        .map(|(_, latitude, longitude)| (*latitude, *longitude))
+}
+
+/// Great-circle distance in km.
+fn distance((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let half_dlat = (lat2 - lat1) / 2.0;
+    let half_dlon = (lon2 - lon1).to_radians() / 2.0;
+    let a = half_dlat.sin().powi(2) + lat1.cos() * lat2.cos() * half_dlon.sin().powi(2);
+    2.0 * 6371.0 * a.sqrt().asin()
+}
+
+/// A location hint: a region code, e.g. `lhr`, or `latitude,longitude`.
+pub fn locate(hint: &str) -> Result<(f64, f64), String> {
+    if let Some(location) = coordinates(hint.trim()) {
+        return Ok(location);
+    }
+    hint.split_once(',')
+        .and_then(|(latitude, longitude)| {
+            Some((
+                latitude.trim().parse::<f64>().ok()?,
+                longitude.trim().parse::<f64>().ok()?,
+            ))
+        })
+        .filter(|(latitude, longitude)| latitude.abs() <= 90.0 && longitude.abs() <= 180.0)
+        .ok_or_else(|| format!("{} is neither a region nor a latitude,longitude pair", hint))
+}
+
+/// The caller's location going by the edge region Fly routed them through, when the
+/// request came through a trusted proxy that sets it.
+pub fn caller_location(req: &HttpRequest) -> Option<(f64, f64)> {
+    let peer = req.peer_addr()?.ip();
+    req.app_data::<web::Data<TrustedProxies>>()
+        .filter(|proxies| proxies.is_trusted(peer))?;
+    req.headers()
+        .get(FLY_REGION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(coordinates)
+}
+
+/// `candidates`, or every Fly region without any, nearest to `location` first. Regions
+/// flyd has no coordinates for go last.
+pub fn nearest(location: (f64, f64), candidates: &[String]) -> Vec<String> {
+    let mut regions: Vec<(String, f64)> = if candidates.is_empty() {
+        REGIONS
+            .iter()
+            .map(|(code, latitude, longitude)| {
+                (
+                    code.to_string(),
+                    distance(location, (*latitude, *longitude)),
+                )
+            })
+            .collect()
+    } else {
+        candidates
+            .iter()
+            .map(|region| {
+                let km = coordinates(region).map_or(f64::INFINITY, |at| distance(location, at));
+                (region.clone(), km)
+            })
+            .collect()
+    };
+    regions.sort_by(|a, b| a.1.total_cmp(&b.1));
+    regions.into_iter().map(|(region, _)| region).collect()
+}
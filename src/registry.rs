@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use reqwest::StatusCode;
+use reqwest::header::{ACCEPT, WWW_AUTHENTICATE};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::RegistryConfig;
+
+pub const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+const DOCKER_HUB: &str = "docker.io";
+
+/// An image reference split the way a registry is asked about it.
+pub struct ImageRef {
+    /// The reference without its tag or digest, e.g. `registry.fly.io/my-app`.
+    pub name: String,
+    pub registry: String,
+    pub repository: String,
+    /// A tag, or a digest like `sha256:...`.
+    pub reference: String,
+}
+
+impl ImageRef {
+    pub fn parse(image: &str) -> Self {
+        let (rest, digest) = match image.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest)),
+            None => (image, None),
+        };
+        let (name, tag) = match rest.rfind(':') {
+            Some(colon) if !rest[colon..].contains('/') => {
+                (&rest[..colon], Some(&rest[colon + 1..]))
+            }
+            _ => (rest, None),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), path.to_string())
+            }
+            Some(_) => (DOCKER_HUB.to_string(), name.to_string()),
+            None => (DOCKER_HUB.to_string(), format!("library/{}", name)),
+        };
+        ImageRef {
+            name: name.to_string(),
+            registry,
+            repository,
+            reference: digest.or(tag).unwrap_or("latest").to_string(),
+        }
+    }
+}
+
+pub fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(bytes)))
+}
+
+/// Reads manifests and blobs from container registries, with the credentials configured
+/// under `[registries]`.
+pub struct RegistryClient {
+    http: reqwest::Client,
+    registries: HashMap<String, RegistryConfig>,
+    challenge: Regex,
+}
+
+impl RegistryClient {
+    pub fn new(http: reqwest::Client, registries: HashMap<String, RegistryConfig>) -> Self {
+        RegistryClient {
+            http,
+            registries,
+            challenge: Regex::new(r#"(\w+)="([^"]*)""#).expect("the pattern is valid"),
+        }
+    }
+
+    fn credentials(&self, registry: &str) -> Option<(&str, &str)> {
+        let registry = self.registries.get(registry)?;
+        Some((registry.username.as_deref()?, registry.password.as_deref()?))
+    }
+
+    pub fn session<'a>(&'a self, image: &'a ImageRef) -> Session<'a> {
+        Session {
+            client: self,
+            image,
+            token: None,
+        }
+    }
+}
+
+/// Requests about one image, holding the token its registry handed out.
+pub struct Session<'a> {
+    client: &'a RegistryClient,
+    image: &'a ImageRef,
+    token: Option<String>,
+}
+
+impl Session<'_> {
+    fn url(&self, path: &str) -> String {
+        let insecure = self
+            .client
+            .registries
+            .get(&self.image.registry)
+            .is_some_and(|registry| registry.insecure);
+        let scheme = if insecure { "http" } else { "https" };
+        let host = match self.image.registry.as_str() {
+            DOCKER_HUB | "index.docker.io" => "registry-1.docker.io",
+            host => host,
+        };
+        format!(
+            "{}://{}/v2/{}/{}",
+            scheme, host, self.image.repository, path
+        )
+    }
+
+    fn request(&self, url: &str, accept: &str) -> reqwest::RequestBuilder {
+        let request = self.client.http.get(url).header(ACCEPT, accept);
+        match (&self.token, self.client.credentials(&self.image.registry)) {
+            (Some(token), _) => request.bearer_auth(token),
+            (None, Some((username, password))) => request.basic_auth(username, Some(password)),
+            (None, None) => request,
+        }
+    }
+
+    /// Answers a bearer challenge with a token for pulling the repository.
+    async fn authenticate(&mut self, challenge: &str) -> Result<(), String> {
+        let params: HashMap<&str, &str> = self
+            .client
+            .challenge
+            .captures_iter(challenge)
+            .filter_map(|captures| Some((captures.get(1)?.as_str(), captures.get(2)?.as_str())))
+            .collect();
+        let Some(realm) = params.get("realm") else {
+            return Err(format!(
+                "{} asked for credentials flyd doesn't have",
+                self.image.registry
+            ));
+        };
+        let scope = params
+            .get("scope")
+            .map(|scope| scope.to_string())
+            .unwrap_or_else(|| format!("repository:{}:pull", self.image.repository));
+        let mut request = self.client.http.get(*realm).query(&[("scope", scope)]);
+        if let Some(service) = params.get("service") {
+            request = request.query(&[("service", service)]);
+        }
+        if let Some((username, password)) = self.client.credentials(&self.image.registry) {
+            request = request.basic_auth(username, Some(password));
+        }
+        let body: Value = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("Failed to get a token from {}: {}", realm, e))?
+            .json()
+            .await
+            .map_err(|e| format!("Failed to read a token from {}: {}", realm, e))?;
+        let token = body["token"].as_str().or(body["access_token"].as_str());
+        self.token = Some(
+            token
+                .ok_or_else(|| format!("{} returned no token", realm))?
+                .to_string(),
+        );
+        Ok(())
+    }
+
+    /// The response to a GET, or `None` for a 404.
+    pub async fn get(
+        &mut self,
+        path: &str,
+        accept: &str,
+    ) -> Result<Option<reqwest::Response>, String> {
+        let url = self.url(path);
+        let mut response = self
+            .request(&url, accept)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach {}: {}", self.image.registry, e))?;
+        if response.status() == StatusCode::UNAUTHORIZED && self.token.is_none() {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            self.authenticate(&challenge).await?;
+            response = self
+                .request(&url, accept)
+                .send()
+                .await
+                .map_err(|e| format!("Failed to reach {}: {}", self.image.registry, e))?;
+        }
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response)),
+            status => Err(format!(
+                "{} returned {} for {}",
+                self.image.registry, status, url
+            )),
+        }
+    }
+
+    pub async fn manifest_digest(&mut self) -> Result<String, String> {
+        if self.image.reference.starts_with("sha256:") {
+            return Ok(self.image.reference.clone());
+        }
+        let path = format!("manifests/{}", self.image.reference);
+        let Some(response) = self.get(&path, MANIFEST_TYPES).await? else {
+            return Err(format!("{} doesn't exist", self.image.name));
+        };
+        let header = response
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        match header {
+            Some(digest) => Ok(digest),
+            None => {
+                let body = response.bytes().await.map_err(|e| e.to_string())?;
+                Ok(sha256_digest(&body))
+            }
+        }
+    }
+}
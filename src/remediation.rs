@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use actix_web::web;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::backoff::{RestartBackoff, Verdict};
+use crate::config::{RemediationAction, RemediationConfig};
+use crate::drain::{self, Drainer};
+use crate::events::EventLog;
+
+const MACHINE_REMEDIATED: &str = "machine.remediated";
+const MACHINE_REMEDIATION_SKIPPED: &str = "machine.remediation_skipped";
+
+fn action_name(action: RemediationAction) -> &'static str {
+    match action {
+        RemediationAction::Restart => "restart",
+        RemediationAction::Recreate => "recreate",
+    }
+}
+
+/// Restarts or recreates started machines whose checks stay critical for
+/// `failing_for_secs`. A machine has to fail for that long again before it's remediated
+/// again, and no app has more than `max_per_hour` remediations in an hour; machines that
+/// keep failing back off and end up quarantined. Every action, and every one skipped for
+/// the budget, is recorded as an event.
+pub struct Remediation {
+    config: RemediationConfig,
+    /// When each machine was first seen failing, since it last passed or was remediated.
+    failing_since: HashMap<(String, String), DateTime<Utc>>,
+    /// Remediations per app in the last hour, oldest first.
+    recent: HashMap<String, VecDeque<DateTime<Utc>>>,
+    /// Machines already reported as skipped while still failing.
+    skipped: HashSet<(String, String)>,
+}
+
+impl Remediation {
+    pub fn new(config: RemediationConfig) -> Self {
+        Remediation {
+            config,
+            failing_since: HashMap::new(),
+            recent: HashMap::new(),
+            skipped: HashSet::new(),
+        }
+    }
+
+    fn failing_checks(&self, machine: &Value) -> Vec<String> {
+        machine["checks"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|check| check["status"] == "critical")
+            .filter_map(|check| check["name"].as_str())
+            .filter(|name| {
+                self.config.checks.is_empty() || self.config.checks.iter().any(|c| c == name)
+            })
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Whether the app has budget left this hour, forgetting remediations older than that.
+    fn has_budget(&mut self, app: &str) -> bool {
+        let cutoff = Utc::now() - TimeDelta::hours(1);
+        let recent = self.recent.entry(app.to_string()).or_default();
+        while recent.front().is_some_and(|at| *at <= cutoff) {
+            recent.pop_front();
+        }
+        recent.len() < self.config.max_per_hour
+    }
+
+    pub async fn check<B: Backend>(
+        &mut self,
+        backend: &B,
+        events: &EventLog,
+        backoff: &RestartBackoff,
+        drainer: Option<&web::Data<Drainer>>,
+        app: &str,
+        machines: &[Value],
+    ) {
+        let now = Utc::now();
+        let failing_for = TimeDelta::seconds(self.config.failing_for_secs as i64);
+        let mut seen = HashSet::new();
+        for machine in machines {
+            let Some(id) = machine["id"].as_str() else {
+                continue;
+            };
+            let key = (app.to_string(), id.to_string());
+            let failing = self.failing_checks(machine);
+            if machine["state"] != "started" || failing.is_empty() {
+                continue;
+            }
+            seen.insert(key.clone());
+            let since = *self.failing_since.entry(key.clone()).or_insert(now);
+            if now - since < failing_for {
+                continue;
+            }
+            // Tracked by name, which a recreated machine keeps.
+            let name = machine["name"].as_str().unwrap_or(id);
+            match backoff.verdict(app, name) {
+                Verdict::Go => {}
+                Verdict::Wait | Verdict::Quarantined => continue,
+                Verdict::Quarantine => {
+                    backoff
+                        .quarantine(backend, events, app, name, Some(id))
+                        .await;
+                    continue;
+                }
+            }
+            if !self.has_budget(app) {
+                if self.skipped.insert(key) {
+                    events.record(
+                        MACHINE_REMEDIATION_SKIPPED,
+                        Some(app),
+                        Some(id),
+                        json!({
+                            "action": action_name(self.config.action),
+                            "failing_checks": failing,
+                            "failing_since": since,
+                            "max_per_hour": self.config.max_per_hour,
+                        }),
+                    );
+                }
+                continue;
+            }
+
+            backoff.attempted(app, name);
+            let result = self.remediate(backend, drainer, app, machine).await;
+            if let Err(e) = &result {
+                log::error!("Failed to remediate machine {}: {}", id, e);
+            }
+            self.recent
+                .entry(app.to_string())
+                .or_default()
+                .push_back(now);
+            self.failing_since.remove(&key);
+            self.skipped.remove(&key);
+            seen.remove(&key);
+            events.record(
+                MACHINE_REMEDIATED,
+                Some(app),
+                Some(id),
+                json!({
+                    "action": action_name(self.config.action),
+                    "failing_checks": failing,
+                    "failing_since": since,
+                    "replacement_id": result.as_ref().ok().cloned().flatten(),
+                    "error": result.err(),
+                }),
+            );
+        }
+        // Machines of this app that passed, stopped or went away start over.
+        self.failing_since
+            .retain(|key, _| key.0 != app || seen.contains(key));
+        self.skipped
+            .retain(|key| key.0 != app || seen.contains(key));
+    }
+
+    /// Restarts or recreates the machine, returning the replacement's id if recreated.
+    async fn remediate<B: Backend>(
+        &self,
+        backend: &B,
+        drainer: Option<&web::Data<Drainer>>,
+        app: &str,
+        machine: &Value,
+    ) -> Result<Option<String>, String> {
+        let id = machine["id"].as_str().unwrap_or_default();
+        drain::drain(drainer, backend, app, id).await;
+        match self.config.action {
+            RemediationAction::Restart => {
+                backend
+                    .restart_machine(app, id)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                drain::restore(drainer, backend, app, id).await;
+                Ok(None)
+            }
+            RemediationAction::Recreate => {
+                backend
+                    .destroy_machine(app, id, None)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let body = json!({
+                    "name": machine["name"],
+                    "region": machine["region"],
+                    "config": machine["config"],
+                });
+                let created = backend
+                    .create_machine(app, &body)
+                    .await
+                    .map_err(|e| format!("Destroyed, but failed to create a replacement: {}", e))?;
+                Ok(created["id"].as_str().map(str::to_string))
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::{DateTime, Utc};
+use flyd::models::{AppSummary, FleetMode, FleetReport};
+
+use crate::backend::{Backend, resolve_apps};
+use crate::concurrency::{self, AdaptiveLimit};
+use crate::config::{Config, ReportConfig};
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::notify::{self, Notifier, escape_html};
+use crate::prepare_request;
+use crate::pricing::{machine_monthly_cost, volume_monthly_cost};
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+const INCIDENT_KINDS: &[&str] = &["fleet.drift", "fleet.corrected"];
+
+async fn report_apps<B: Backend>(backend: &B, report: &ReportConfig) -> Vec<String> {
+    match resolve_apps(backend, &report.apps, report.org_slug.as_deref()).await {
+        Ok(apps) => apps,
+        Err(e) => {
+            log::error!(
+                "Report {}: failed to list apps in {}: {}",
+                report.name,
+                report.org_slug.as_deref().unwrap_or_default(),
+                e
+            );
+            let mut apps = report.apps.clone();
+            apps.sort();
+            apps.dedup();
+            apps
+        }
+    }
+}
+
+async fn summarize_app<B: Backend>(
+    backend: &B,
+    store: &Store,
+    app: &str,
+) -> (AppSummary, Option<FleetMode>) {
+    let mut summary = AppSummary {
+        app: app.to_string(),
+        ..Default::default()
+    };
+
+    let machines = match backend.list_machines(app).await {
+        Ok(machines) => machines,
+        Err(e) => {
+            summary.error = Some(e.to_string());
+            return (summary, None);
+        }
+    };
+
+    summary.machines = machines.len();
+    for machine in &machines {
+        let state = machine["state"].as_str().unwrap_or("unknown");
+        *summary.by_state.entry(state.to_string()).or_default() += 1;
+        *summary
+            .by_region
+            .entry(machine["region"].as_str().unwrap_or("unknown").to_string())
+            .or_default() += 1;
+        if state == "started" {
+            summary.estimated_monthly_cost_usd += machine_monthly_cost(&machine["config"]["guest"]);
+        }
+    }
+
+    match backend.list_volumes(app).await {
+        Ok(volumes) => {
+            summary.volumes = volumes.len();
+            summary.estimated_monthly_cost_usd += volumes
+                .iter()
+                .map(|volume| volume_monthly_cost(volume["size_gb"].as_u64().unwrap_or_default()))
+                .sum::<f64>();
+        }
+        Err(e) => summary.error = Some(e.to_string()),
+    }
+
+    let mut mode = None;
+    if let Ok(Some(drift)) = fleets::drift_report(store, app).await {
+        summary.drift = drift.drift;
+        mode = Some(drift.mode);
+    }
+
+    (summary, mode)
+}
+
+fn recommendations(modes: &BTreeMap<String, FleetMode>, apps: &[AppSummary]) -> Vec<String> {
+    let mut recommendations = Vec::new();
+
+    for app in apps {
+        let stopped = app.by_state.get("stopped").copied().unwrap_or_default();
+        let started = app.by_state.get("started").copied().unwrap_or_default();
+        if stopped > 0 && started == 0 {
+            recommendations.push(format!(
+                "{} has {} stopped machines and none running; consider destroying them",
+                app.app, stopped
+            ));
+        }
+
+        if app.machines > 1 && app.by_region.len() == 1 {
+            recommendations.push(format!(
+                "{} runs all {} machines in a single region; consider spreading across regions",
+                app.app, app.machines
+            ));
+        }
+
+        if !app.drift.is_empty() {
+            let hint = match modes.get(&app.app) {
+                Some(FleetMode::Detect) => "; review it and switch the fleet to enforce mode",
+                _ => "",
+            };
+            recommendations.push(format!(
+                "{} has {} drifted resources{}",
+                app.app,
+                app.drift.len(),
+                hint
+            ));
+        }
+    }
+
+    recommendations
+}
+
+pub async fn compile<B: Backend>(
+    backend: &B,
+    store: &Store,
+    events: &EventLog,
+    limit: Option<&AdaptiveLimit>,
+    report: &ReportConfig,
+    period_start: DateTime<Utc>,
+) -> FleetReport {
+    let apps = report_apps(backend, report).await;
+
+    let mut summaries = Vec::new();
+    let mut modes = BTreeMap::new();
+    let summarized = concurrency::map(
+        limit,
+        apps.iter().map(|app| summarize_app(backend, store, app)),
+    )
+    .await;
+    for (app, (summary, mode)) in apps.iter().zip(summarized) {
+        if let Some(mode) = mode {
+            modes.insert(app.clone(), mode);
+        }
+        summaries.push(summary);
+    }
+
+    let mut incidents = BTreeMap::new();
+    for event in events.since(period_start) {
+        let in_scope = event.app.as_ref().is_some_and(|app| apps.contains(app));
+        if in_scope && INCIDENT_KINDS.contains(&event.kind.as_str()) {
+            *incidents.entry(event.kind).or_default() += 1;
+        }
+    }
+
+    FleetReport {
+        name: report.name.clone(),
+        generated_at: Utc::now(),
+        period_start,
+        total_machines: summaries.iter().map(|app| app.machines).sum(),
+        total_started: summaries
+            .iter()
+            .map(|app| app.by_state.get("started").copied().unwrap_or_default())
+            .sum(),
+        estimated_monthly_cost_usd: summaries
+            .iter()
+            .map(|app| app.estimated_monthly_cost_usd)
+            .sum(),
+        incidents,
+        recommendations: recommendations(&modes, &summaries),
+        apps: summaries,
+    }
+}
+
+fn render_text(report: &FleetReport) -> String {
+    let mut text = format!(
+        "Fleet report {} for {} to {}\n\n{} machines ({} started), ~${:.2}/month\n\n",
+        report.name,
+        report.period_start.format("%Y-%m-%d"),
+        report.generated_at.format("%Y-%m-%d"),
+        report.total_machines,
+        report.total_started,
+        report.estimated_monthly_cost_usd
+    );
+    for app in &report.apps {
+        text.push_str(&format!(
+            "{}: {} machines, {} volumes, ~${:.2}/month, {} drifted{}\n",
+            app.app,
+            app.machines,
+            app.volumes,
+            app.estimated_monthly_cost_usd,
+            app.drift.len(),
+            app.error
+                .as_ref()
+                .map(|e| format!(" (error: {})", e))
+                .unwrap_or_default()
+        ));
+    }
+    if !report.incidents.is_empty() {
+        text.push_str("\nIncidents:\n");
+        for (kind, count) in &report.incidents {
+            text.push_str(&format!("  {}: {}\n", kind, count));
+        }
+    }
+    if !report.recommendations.is_empty() {
+        text.push_str("\nRecommendations:\n");
+        for recommendation in &report.recommendations {
+            text.push_str(&format!("  - {}\n", recommendation));
+        }
+    }
+    text
+}
+
+fn render_html(report: &FleetReport) -> String {
+    let mut html = format!(
+        "<h2>Fleet report {}</h2>\n<p>{} to {}: {} machines ({} started), ~${:.2}/month</p>\n",
+        escape_html(&report.name),
+        report.period_start.format("%Y-%m-%d"),
+        report.generated_at.format("%Y-%m-%d"),
+        report.total_machines,
+        report.total_started,
+        report.estimated_monthly_cost_usd
+    );
+    html.push_str("<table>\n<tr><th>App</th><th>Machines</th><th>Volumes</th><th>Cost/month</th><th>Drifted</th></tr>\n");
+    for app in &report.apps {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>${:.2}</td><td>{}</td></tr>\n",
+            escape_html(&app.app),
+            app.machines,
+            app.volumes,
+            app.estimated_monthly_cost_usd,
+            app.drift.len()
+        ));
+    }
+    html.push_str("</table>\n");
+    if !report.incidents.is_empty() {
+        html.push_str("<h3>Incidents</h3>\n<ul>\n");
+        for (kind, count) in &report.incidents {
+            html.push_str(&format!("<li>{}: {}</li>\n", escape_html(kind), count));
+        }
+        html.push_str("</ul>\n");
+    }
+    if !report.recommendations.is_empty() {
+        html.push_str("<h3>Recommendations</h3>\n<ul>\n");
+        for recommendation in &report.recommendations {
+            html.push_str(&format!("<li>{}</li>\n", escape_html(recommendation)));
+        }
+        html.push_str("</ul>\n");
+    }
+    html
+}
+
+async fn deliver(notifier: &Notifier, report: &ReportConfig, compiled: &FleetReport) {
+    for url in &report.webhooks {
+        let result = notifier
+            .http
+            .post(url)
+            .json(compiled)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            log::error!("Failed to deliver report {} to {}: {}", report.name, url, e);
+        }
+    }
+
+    if report.emails.is_empty() {
+        return;
+    }
+    let Some(mailer) = &notifier.mailer else {
+        log::warn!(
+            "Report {} has email recipients but [notifications.smtp] is not configured",
+            report.name
+        );
+        return;
+    };
+    let subject = format!("[flyd] Fleet report {}", report.name);
+    if let Err(e) = mailer
+        .send(
+            &report.emails,
+            &subject,
+            render_text(compiled),
+            render_html(compiled),
+        )
+        .await
+    {
+        log::error!("Failed to email report {}: {}", report.name, e);
+    }
+}
+
+/// Sends each owner's route, and the default route, a report of just their apps.
+async fn deliver_to_owners<B: Backend>(
+    backend: &B,
+    store: &Store,
+    events: &EventLog,
+    limit: &AdaptiveLimit,
+    notifier: &Notifier,
+    report: &ReportConfig,
+    compiled: &FleetReport,
+) {
+    let mut owned: BTreeMap<String, ReportConfig> = BTreeMap::new();
+    for app in &compiled.apps {
+        let Some((owner, route)) = notifier.route(store, Some(&app.app), None).await else {
+            continue;
+        };
+        if !notify::matches(&route.events, "report") {
+            continue;
+        }
+        let owner = owner.unwrap_or_else(|| "unowned apps".to_string());
+        owned
+            .entry(owner.clone())
+            .or_insert_with(|| ReportConfig {
+                name: format!("{} for {}", report.name, owner),
+                apps: Vec::new(),
+                org_slug: None,
+                webhooks: route.webhooks.clone(),
+                emails: route.emails.clone(),
+                route_to_owners: false,
+                ..report.clone()
+            })
+            .apps
+            .push(app.app.clone());
+    }
+    for owned in owned.values() {
+        let compiled = compile(
+            backend,
+            store,
+            events,
+            Some(limit),
+            owned,
+            compiled.period_start,
+        )
+        .await;
+        deliver(notifier, owned, &compiled).await;
+    }
+}
+
+pub async fn run<B: Backend>(
+    report: ReportConfig,
+    backend: B,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    limit: web::Data<AdaptiveLimit>,
+    notifier: Notifier,
+) {
+    let period = Duration::from_secs(report.interval_hours.max(1) * 3600);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        ticker.tick().await;
+
+        let period_start = Utc::now() - period;
+        let compiled = compile(
+            &backend,
+            &store,
+            &events,
+            Some(&limit),
+            &report,
+            period_start,
+        )
+        .await;
+        log::info!(
+            "Report {}: {} apps, {} machines",
+            report.name,
+            compiled.apps.len(),
+            compiled.total_machines
+        );
+        deliver(&notifier, &report, &compiled).await;
+        if report.route_to_owners {
+            deliver_to_owners(
+                &backend, &store, &events, &limit, &notifier, &report, &compiled,
+            )
+            .await;
+        }
+    }
+}
+
+/// Compiles a configured report on demand with the caller's token, without delivering it.
+#[get("/v0/reports/{name}")]
+async fn preview_report(
+    req: HttpRequest,
+    name: web::Path<String>,
+    config: web::Data<Config>,
+    http_client: web::Data<reqwest::Client>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let Some(report) = config.reports.iter().find(|report| report.name == *name) else {
+        return AppError::not_found(format!("No report named {}", name)).into_response();
+    };
+    let period_start = Utc::now() - Duration::from_secs(report.interval_hours * 3600);
+    let limit = req.app_data::<web::Data<AdaptiveLimit>>();
+
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        let compiled = compile(
+            docker.get_ref(),
+            &store,
+            &events,
+            limit.map(|limit| limit.get_ref()),
+            report,
+            period_start,
+        )
+        .await;
+        return HttpResponse::Ok().json(compiled);
+    }
+
+    let (headers, api_hostname) = match prepare_request(&req, config.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_limit(limit.map(|limit| limit.clone().into_inner()));
+    HttpResponse::Ok().json(
+        compile(
+            &client,
+            &store,
+            &events,
+            limit.map(|limit| limit.get_ref()),
+            report,
+            period_start,
+        )
+        .await,
+    )
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(preview_report);
+}
@@ -0,0 +1,85 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest, web};
+
+use crate::cache::CacheStatus;
+use crate::config::ResponseHeadersConfig;
+
+const UPSTREAM_HOST_HEADER: HeaderName = HeaderName::from_static("x-flyd-upstream-host");
+const CACHE_HEADER: HeaderName = HeaderName::from_static("x-flyd-cache");
+
+/// The Machines API host a request was sent to, for `X-Flyd-Upstream-Host`.
+#[derive(Clone)]
+pub struct UpstreamHost(pub String);
+
+pub fn record_upstream(req: &HttpRequest, host: &str) {
+    req.extensions_mut().insert(UpstreamHost(host.to_string()));
+}
+
+/// Headers added to responses on their way out, per `response_headers`.
+pub struct ResponseHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    upstream_host: bool,
+    cache_status: bool,
+}
+
+impl ResponseHeaders {
+    pub fn from_config(config: &ResponseHeadersConfig) -> Result<Self, String> {
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::try_from(name.as_str())
+                    .map_err(|e| format!("Invalid response header name {}: {}", name, e))?;
+                let value = HeaderValue::try_from(value.as_str())
+                    .map_err(|e| format!("Invalid value for response header {}: {}", name, e))?;
+                Ok((name, value))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(ResponseHeaders {
+            headers,
+            upstream_host: config.upstream_host,
+            cache_status: config.cache_status,
+        })
+    }
+}
+
+pub async fn inject<B>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<B>, Error> {
+    let Some(injected) = req.app_data::<web::Data<ResponseHeaders>>().cloned() else {
+        return next.call(req).await;
+    };
+    let mut response = next.call(req).await?;
+
+    let (upstream_host, cache_status) = {
+        let extensions = response.request().extensions();
+        (
+            extensions.get::<UpstreamHost>().cloned(),
+            extensions.get::<CacheStatus>().copied(),
+        )
+    };
+    let headers = response.headers_mut();
+    for (name, value) in &injected.headers {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    if injected.upstream_host
+        && let Some(UpstreamHost(host)) = upstream_host
+        && let Ok(value) = HeaderValue::try_from(host)
+    {
+        headers.insert(UPSTREAM_HOST_HEADER, value);
+    }
+    if injected.cache_status
+        && let Some(status) = cache_status
+    {
+        headers.insert(
+            CACHE_HEADER,
+            HeaderValue::from_static(match status {
+                CacheStatus::Hit => "hit",
+                CacheStatus::Miss => "miss",
+            }),
+        );
+    }
+    Ok(response)
+}
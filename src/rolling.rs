@@ -0,0 +1,405 @@
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, Responder, post, web};
+use flyd::models::{DeployRequest, RollingDeployRequest};
+use futures_util::future::join_all;
+use serde_json::{Value, json};
+
+use crate::cordon;
+use crate::deploy_breaker::DeployBreaker;
+use crate::deploys;
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::jobs::{self, Jobs, Work};
+use crate::prepare_request;
+use crate::provenance;
+use crate::slo::SloTracker;
+use crate::store::Store;
+use crate::warm_up;
+
+const DEFAULT_HEALTH_TIMEOUT_SECS: u64 = 300;
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Leases outlive the update they guard by this much, in case releasing them fails.
+const LEASE_MARGIN_SECS: u64 = 60;
+
+/// A machine the deploy updated, with the config to roll it back to.
+struct Updated {
+    id: String,
+    previous: Value,
+    was_started: bool,
+}
+
+struct Failure {
+    /// Whether the machine got the new config before failing, and so needs rolling back.
+    updated: bool,
+    error: String,
+}
+
+/// Waits for the machine to be started with every check passing.
+async fn wait_healthy(
+    client: &FlyClient,
+    app: &str,
+    machine_id: &str,
+    deadline: Instant,
+) -> Result<(), String> {
+    loop {
+        let machine = client
+            .get_machine(app, machine_id)
+            .await
+            .map_err(|e| format!("Failed to get machine {}: {}", machine_id, e))?;
+        let failing = machine["checks"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|check| check["status"] != "passing");
+        let reason = match (machine["state"].as_str(), failing) {
+            (Some("started"), None) => return Ok(()),
+            (Some("started"), Some(check)) => {
+                format!("check {} is {}", check["name"], check["status"])
+            }
+            (state, _) => format!("it's {}", state.unwrap_or("unknown")),
+        };
+        if Instant::now() >= deadline {
+            return Err(format!("Machine {} isn't healthy: {}", machine_id, reason));
+        }
+        tokio::time::sleep(HEALTH_POLL_INTERVAL).await;
+    }
+}
+
+/// Puts `config` on the machine under a lease, and waits for it to come back healthy if
+/// it was started. Stopped machines are updated without starting them. With traffic
+/// steps, the machine is left cordoned for its step to put back into traffic.
+async fn update_one(
+    client: &FlyClient,
+    drainer: Option<&web::Data<Drainer>>,
+    request: &DeployRequest,
+    machine_id: &str,
+    config: Value,
+    was_started: bool,
+    timeout: Duration,
+) -> Result<(), Failure> {
+    let app = &request.app;
+    let nonce = client
+        .acquire_lease(app, machine_id, timeout.as_secs() + LEASE_MARGIN_SECS)
+        .await
+        .map_err(|e| Failure {
+            updated: false,
+            error: format!("Failed to lease machine {}: {}", machine_id, e),
+        })?;
+    // Released below, or by the job if it's stopped before getting there.
+    let lease = format!("lease {}/{}", app, machine_id);
+    let (owner, owned) = (client.clone(), (app.clone(), machine_id.to_string()));
+    let held = nonce.clone();
+    jobs::defer(
+        lease.clone(),
+        Box::new(move || {
+            Box::pin(async move {
+                let (app, machine_id) = owned;
+                owner
+                    .release_lease(&app, &machine_id, &held)
+                    .await
+                    .map(|()| Value::Null)
+                    .map_err(|e| e.to_string())
+            })
+        }),
+    );
+    let budget = timeout + Duration::from_secs(LEASE_MARGIN_SECS);
+    // Whether a machine that ran out of time was updated isn't known, so it's rolled back
+    // with the rest.
+    let result = jobs::step(&format!("update {}", machine_id), Some(budget), async {
+        drain::drain(drainer, client, app, machine_id).await;
+        let body = json!({ "config": config, "skip_launch": !was_started });
+        let machine = client
+            .update_machine(app, machine_id, &body, Some(&nonce))
+            .await
+            .map_err(|e| Failure {
+                updated: false,
+                error: format!("Failed to update machine {}: {}", machine_id, e),
+            })?;
+        let updated = |error| Failure {
+            updated: true,
+            error,
+        };
+        if was_started {
+            let deadline = Instant::now() + timeout;
+            client
+                .wait_for_state(
+                    app,
+                    machine_id,
+                    "started",
+                    machine["instance_id"].as_str(),
+                    timeout,
+                )
+                .await
+                .map_err(|e| updated(format!("Machine {} didn't start: {}", machine_id, e)))?;
+            wait_healthy(client, app, machine_id, deadline)
+                .await
+                .map_err(updated)?;
+            if let Some(warm_up) = &request.warm_up {
+                warm_up::run(client, app, &machine, warm_up)
+                    .await
+                    .map_err(|e| {
+                        updated(format!("Machine {} didn't warm up: {}", machine_id, e))
+                    })?;
+            }
+        }
+        if request.traffic_step.is_none() && !cordon::is_cordoned(&machine) {
+            drain::restore(drainer, client, app, machine_id).await;
+        }
+        Ok(())
+    })
+    .await
+    .unwrap_or_else(|error| {
+        Err(Failure {
+            updated: true,
+            error,
+        })
+    });
+    if let Err(e) = client.release_lease(app, machine_id, &nonce).await {
+        log::warn!("Failed to release the lease on {}: {}", machine_id, e);
+    }
+    jobs::settle(&lease);
+    result
+}
+
+/// Puts the updated machines back on their previous configs, returning those it managed
+/// to.
+async fn roll_back(
+    client: &FlyClient,
+    drainer: Option<&web::Data<Drainer>>,
+    request: &DeployRequest,
+    updated: &[Updated],
+    timeout: Duration,
+) -> Vec<String> {
+    // Machines go straight back into traffic as they're rolled back.
+    let request = &DeployRequest {
+        traffic_step: None,
+        ..request.clone()
+    };
+    let mut rolled_back = Vec::new();
+    for machine in updated.iter().rev() {
+        match update_one(
+            client,
+            drainer,
+            request,
+            &machine.id,
+            machine.previous.clone(),
+            machine.was_started,
+            timeout,
+        )
+        .await
+        {
+            Ok(()) => rolled_back.push(machine.id.clone()),
+            Err(failure) => log::error!(
+                "Rolling deploy of {}: rollback: {}",
+                request.app,
+                failure.error
+            ),
+        }
+    }
+    rolled_back
+}
+
+pub async fn deploy(
+    client: FlyClient,
+    store: web::Data<Store>,
+    drainer: Option<web::Data<Drainer>>,
+    breaker: Option<web::Data<DeployBreaker>>,
+    rolling: RollingDeployRequest,
+) -> Result<Value, String> {
+    let request = &rolling.deploy;
+    let timeout = Duration::from_secs(
+        rolling
+            .health_timeout_secs
+            .unwrap_or(DEFAULT_HEALTH_TIMEOUT_SECS),
+    );
+    let max_unavailable = rolling.max_unavailable.unwrap_or(1).max(1);
+    let machines = client
+        .list_machines(&request.app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?;
+    let live: Vec<&Value> = machines
+        .iter()
+        .filter(|machine| fleets::is_live(machine))
+        .filter(|machine| rolling.include_cordoned || !cordon::is_cordoned(machine))
+        .collect();
+    let total = live.len();
+    let previous_spec = deploys::update_fleet_spec(&store, request).await?;
+    let waves = match &request.rollout {
+        Some(rollout) => deploys::waves(rollout, live),
+        None => vec![(None, live)],
+    };
+
+    let (client, drainer) = (&client, drainer.as_ref());
+    let mut updated: Vec<Updated> = Vec::new();
+    let mut failure = None;
+    let mut failed = Vec::new();
+    let mut held = Vec::new();
+    'waves: for (region, machines) in waves {
+        for step in deploys::steps(request.traffic_step.as_ref(), machines) {
+            held.clear();
+            for batch in step.chunks(max_unavailable) {
+                if let Some(breaker) = &breaker
+                    && let Err(e) = breaker.wait_closed(&request.app).await
+                {
+                    failure = Some(e);
+                    break 'waves;
+                }
+                if request.traffic_step.is_some() {
+                    for machine in batch {
+                        if let Err(e) =
+                            deploys::hold(client, &request.app, machine, &mut held).await
+                        {
+                            failure = Some(e);
+                            break 'waves;
+                        }
+                    }
+                }
+                let results = join_all(batch.iter().map(|machine| {
+                    let mut config = machine["config"].clone();
+                    deploys::apply(request, &mut config);
+                    let id = machine["id"].as_str().unwrap_or_default();
+                    let was_started = machine["state"] == "started";
+                    async move {
+                        let result =
+                            update_one(client, drainer, request, id, config, was_started, timeout)
+                                .await;
+                        (id, was_started, result)
+                    }
+                }))
+                .await;
+                for (machine, (id, was_started, result)) in batch.iter().zip(results) {
+                    let rolled_out = match result {
+                        Ok(()) => true,
+                        Err(e) => {
+                            failure.get_or_insert(e.error);
+                            failed.push(id);
+                            e.updated
+                        }
+                    };
+                    if rolled_out {
+                        updated.push(Updated {
+                            id: id.to_string(),
+                            previous: machine["config"].clone(),
+                            was_started,
+                        });
+                    }
+                }
+                if failure.is_some() {
+                    break 'waves;
+                }
+                jobs::record_progress(updated.len(), total);
+            }
+            if let Some(traffic_step) = &request.traffic_step
+                && let Err(e) =
+                    deploys::shift(client, &request.app, &held, traffic_step, region.as_deref())
+                        .await
+            {
+                failure = Some(e);
+                break 'waves;
+            }
+        }
+    }
+
+    let ids: Vec<&String> = updated.iter().map(|machine| &machine.id).collect();
+    let Some(failure) = failure else {
+        return Ok(json!({ "updated_machines": ids }));
+    };
+    // Machines that failed stay cordoned, as after a failed warm-up.
+    held.retain(|id| !failed.contains(&id.as_str()));
+    deploys::release(client, &request.app, &held).await;
+    if rolling.keep_failed {
+        jobs::record_partial(json!({ "updated_machines": ids, "failed_machines": failed }));
+        return Err(format!("{}; left {:?} on the new version", failure, ids));
+    }
+    if let Some(spec) = previous_spec
+        && let Err(e) = fleets::save_fleet_spec(&store, &spec).await
+    {
+        log::error!("Failed to restore the fleet spec of {}: {}", request.app, e);
+    }
+    let rolled_back = roll_back(client, drainer, request, &updated, timeout).await;
+    jobs::record_partial(json!({
+        "updated_machines": ids,
+        "failed_machines": failed,
+        "rolled_back_machines": rolled_back,
+    }));
+    Err(format!(
+        "{}; rolled back {:?} of {:?}",
+        failure, rolled_back, ids
+    ))
+}
+
+/// Queues a rolling deploy, returning its job. It runs in the app's concurrency group,
+/// like any other deploy.
+#[post("/v0/deploy/rolling")]
+async fn create_rolling_deploy(
+    req: HttpRequest,
+    body: web::Json<RollingDeployRequest>,
+    http_client: web::Data<reqwest::Client>,
+    store: web::Data<Store>,
+    slo: web::Data<SloTracker>,
+    jobs: web::Data<Jobs>,
+    drainer: Option<web::Data<Drainer>>,
+) -> impl Responder {
+    let rolling = body.into_inner();
+    let request = &rolling.deploy;
+    if request.image.is_none() && request.config.is_none() {
+        return AppError::bad_request("A deploy needs an image or a config").into_response();
+    }
+    if let Some(provenance) = &request.provenance
+        && let Err(e) = provenance::check(provenance)
+    {
+        return AppError::bad_request(e).into_response();
+    }
+    let breaker = req.app_data::<web::Data<DeployBreaker>>().cloned();
+    if let Some(breaker) = &breaker
+        && let Some(response) = breaker.refusal([&request.app]).await
+    {
+        return response;
+    }
+    if req.app_data::<web::Data<DockerBackend>>().is_some() {
+        return AppError::bad_request(
+            "Rolling deploys lease machines, which only Fly can; use /v0/deploys",
+        )
+        .into_response();
+    }
+    let group = request
+        .group
+        .clone()
+        .unwrap_or_else(|| jobs.group_for(&request.app));
+    let app = request.app.clone();
+    let needs_approval = request.require_approval || jobs.requires_approval(&group);
+    let (headers, api_hostname) = match prepare_request(&req, request.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner())
+        .with_request_checks(&req);
+    let work: Work = Box::new(move || {
+        Box::pin(deploy(
+            client.clone(),
+            store.clone(),
+            drainer.clone(),
+            breaker.clone(),
+            rolling.clone(),
+        ))
+    });
+    let job = Jobs::submit(
+        &jobs,
+        "deploy.rolling",
+        Some(&app),
+        group,
+        needs_approval,
+        jobs::submitter(&req),
+        work,
+    );
+    jobs::accepted(&job)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_rolling_deploy);
+}
@@ -0,0 +1,231 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+use crate::config::{
+    Config, ScanFormat, ScanPolicy, Severity, UnscannedPolicy, VulnerabilityScansConfig,
+};
+use crate::events::EventLog;
+use crate::registry::{ImageRef, RegistryClient};
+
+const SCAN_WARNING: &str = "image.scan_warning";
+const SCAN_BLOCKED: &str = "image.scan_blocked";
+
+/// Vulnerabilities found, by severity.
+type Counts = BTreeMap<Severity, usize>;
+
+fn severity(name: &str) -> Severity {
+    match name.to_lowercase().as_str() {
+        "critical" => Severity::Critical,
+        "high" => Severity::High,
+        "medium" => Severity::Medium,
+        "low" => Severity::Low,
+        _ => Severity::Unknown,
+    }
+}
+
+/// The counts in a report, or `None` if the image hasn't been scanned (yet).
+fn parse(format: ScanFormat, report: &Value) -> Option<Counts> {
+    let mut counts = Counts::new();
+    match format {
+        ScanFormat::Trivy => {
+            let results = report["Results"].as_array()?;
+            for vulnerability in results
+                .iter()
+                .filter_map(|result| result["Vulnerabilities"].as_array())
+                .flatten()
+            {
+                let found = severity(vulnerability["Severity"].as_str().unwrap_or_default());
+                *counts.entry(found).or_default() += 1;
+            }
+        }
+        ScanFormat::Harbor => {
+            // Keyed by report MIME type; Harbor has one per scanner.
+            let overview = report["scan_overview"].as_object()?.values().next()?;
+            if overview["scan_status"] != "Success" {
+                return None;
+            }
+            for (name, count) in overview["summary"]["summary"].as_object()? {
+                *counts.entry(severity(name)).or_default() += count.as_u64().unwrap_or(0) as usize;
+            }
+        }
+    }
+    Some(counts)
+}
+
+/// `2 critical, 1 high` for the vulnerabilities at `threshold` or worse.
+fn describe(counts: &Counts, threshold: Severity) -> Option<String> {
+    let found: Vec<String> = counts
+        .iter()
+        .rev()
+        .filter(|(severity, count)| **severity >= threshold && **count > 0)
+        .map(|(severity, count)| format!("{} {:?}", count, severity).to_lowercase())
+        .collect();
+    (!found.is_empty()).then(|| found.join(", "))
+}
+
+/// Refuses images whose scan report has vulnerabilities at an app's `block` severity.
+pub struct ScanGate {
+    config: VulnerabilityScansConfig,
+    http: reqwest::Client,
+    registry: RegistryClient,
+    events: web::Data<EventLog>,
+    /// Reports by digest, `None` for images not scanned yet.
+    reports: Mutex<HashMap<String, (Option<Counts>, Instant)>>,
+}
+
+impl ScanGate {
+    pub fn from_config(
+        config: &Config,
+        http: reqwest::Client,
+        events: web::Data<EventLog>,
+    ) -> Option<Self> {
+        let scans = config.vulnerability_scans.clone()?;
+        Some(ScanGate {
+            config: scans,
+            registry: RegistryClient::new(http.clone(), config.registries.clone()),
+            http,
+            events,
+            reports: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn policy(&self, app: &str) -> ScanPolicy {
+        self.config
+            .policies
+            .get(app)
+            .or_else(|| self.config.policies.get("*"))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    async fn report(
+        &self,
+        image: &ImageRef,
+        digest: &str,
+        name: &str,
+    ) -> Result<Option<Counts>, String> {
+        let ttl = Duration::from_secs(self.config.cache_ttl_secs);
+        if let Some((counts, at)) = self.reports.lock().unwrap().get(digest)
+            && at.elapsed() < ttl
+        {
+            return Ok(counts.clone());
+        }
+
+        let url = self
+            .config
+            .url
+            .replace("{registry}", &image.registry)
+            .replace("{repository}", &image.repository)
+            .replace("{digest}", digest)
+            .replace("{image}", name);
+        let mut request = self.http.get(&url);
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach the scanner: {}", e))?;
+        let counts = match response.status() {
+            StatusCode::NOT_FOUND => None,
+            status if status.is_success() => {
+                let report: Value = response
+                    .json()
+                    .await
+                    .map_err(|e| format!("Failed to read the scan of {}: {}", name, e))?;
+                parse(self.config.format, &report)
+            }
+            status => return Err(format!("The scanner returned {} for {}", status, url)),
+        };
+        self.reports
+            .lock()
+            .unwrap()
+            .insert(digest.to_string(), (counts.clone(), Instant::now()));
+        Ok(counts)
+    }
+
+    fn warn(&self, app: &str, image: &str, digest: Option<&str>, reason: &str) {
+        log::warn!("Deploying {} to {} anyway: {}", image, app, reason);
+        self.events.record(
+            SCAN_WARNING,
+            Some(app),
+            None,
+            json!({ "image": image, "digest": digest, "reason": reason }),
+        );
+    }
+
+    fn block(&self, app: &str, image: &str, digest: Option<&str>, reason: String) -> String {
+        self.events.record(
+            SCAN_BLOCKED,
+            Some(app),
+            None,
+            json!({ "image": image, "digest": digest, "reason": reason }),
+        );
+        format!("{} is blocked for {}: {}", image, app, reason)
+    }
+
+    /// Checks the image of a machine config for `app` against the app's policy. Configs
+    /// without an image pass.
+    pub async fn check(&self, app: &str, config: &Value) -> Result<(), String> {
+        let Some(name) = config["image"].as_str() else {
+            return Ok(());
+        };
+        let policy = self.policy(app);
+        let image = ImageRef::parse(name);
+        let mut session = self.registry.session(&image);
+        let report = match session.manifest_digest().await {
+            Ok(digest) => self
+                .report(&image, &digest, name)
+                .await
+                .map(|counts| (digest, counts)),
+            Err(e) => Err(e),
+        };
+
+        let (digest, counts) = match report {
+            Ok((digest, Some(counts))) => (digest, counts),
+            unscanned => {
+                let (digest, reason) = match unscanned {
+                    Ok((digest, _)) => (Some(digest), "it hasn't been scanned".to_string()),
+                    Err(e) => (None, e),
+                };
+                return match policy.unscanned {
+                    UnscannedPolicy::Allow => Ok(()),
+                    UnscannedPolicy::Warn => {
+                        self.warn(app, name, digest.as_deref(), &reason);
+                        Ok(())
+                    }
+                    UnscannedPolicy::Block => Err(self.block(app, name, digest.as_deref(), reason)),
+                };
+            }
+        };
+
+        if let Some(found) = policy
+            .block
+            .and_then(|threshold| describe(&counts, threshold))
+        {
+            return Err(self.block(
+                app,
+                name,
+                Some(&digest),
+                format!("vulnerabilities found: {}", found),
+            ));
+        }
+        if let Some(found) = policy
+            .warn
+            .and_then(|threshold| describe(&counts, threshold))
+        {
+            self.warn(
+                app,
+                name,
+                Some(&digest),
+                &format!("vulnerabilities found: {}", found),
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use flyd::models::{
+    DeployRequest, MissedRunPolicy, NewSchedule, Schedule, ScheduleOwner, ScheduledOperation,
+};
+use serde_json::{Value, json};
+
+use crate::auth::{self, Identity};
+use crate::backend::Backend;
+use crate::config::{Config, SchedulesConfig, SnapshotsConfig};
+use crate::deploy_breaker::DeployBreaker;
+use crate::deploys;
+use crate::drain::{self, Drainer};
+use crate::environments;
+use crate::errors::AppError;
+use crate::fleets;
+use crate::jobs::{self, Jobs, Work};
+use crate::object_store::ObjectStore;
+use crate::snapshots;
+use crate::store::Store;
+
+pub const SCHEDULES: &str = "schedules";
+/// A run this late counts as missed, e.g. because flyd was down when it was due.
+const MISSED_AFTER: TimeDelta = TimeDelta::minutes(1);
+const DEFAULT_TASK_TIMEOUT_SECS: u64 = 3600;
+
+fn random_below(bound: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    u64::from_le_bytes(bytes) % bound.max(1)
+}
+
+/// The first run after `after`, jitter included; `None` when a one-shot schedule is done.
+fn next_run(schedule: &Schedule, after: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+    let next = match (&schedule.cron, schedule.at) {
+        (Some(expression), _) => {
+            let timezone = match &schedule.timezone {
+                Some(name) => {
+                    Tz::from_str(name).map_err(|_| format!("Unknown timezone {}", name))?
+                }
+                None => Tz::UTC,
+            };
+            let cron = Cron::from_str(expression)
+                .map_err(|e| format!("Invalid cron expression {}: {}", expression, e))?;
+            cron.find_next_occurrence(&after.with_timezone(&timezone), false)
+                .map_err(|e| format!("No next run for {}: {}", expression, e))?
+                .with_timezone(&Utc)
+        }
+        (None, Some(at)) if at > after => at,
+        (None, _) => return Ok(None),
+    };
+    let jitter = random_below(schedule.jitter_secs + 1);
+    Ok(Some(next + TimeDelta::seconds(jitter as i64)))
+}
+
+/// Creates or destroys machines, newest first, until the app has `count` live ones.
+/// New machines copy the oldest machine's config and region.
+async fn scale<B: Backend>(
+    backend: &B,
+    store: &Store,
+    drainer: Option<&web::Data<Drainer>>,
+    app: &str,
+    count: usize,
+) -> Result<Value, String> {
+    // The reconciler would undo it; a fleet is scaled through its spec.
+    if fleets::fleet_spec(store, app)
+        .await
+        .map_err(|e| e.to_string())?
+        .is_some()
+    {
+        return Err(format!(
+            "{} is a managed fleet, update its spec instead",
+            app
+        ));
+    }
+    let mut machines: Vec<Value> = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?
+        .into_iter()
+        .filter(fleets::is_live)
+        .collect();
+    machines.sort_by(|a, b| a["created_at"].as_str().cmp(&b["created_at"].as_str()));
+
+    let mut created = Vec::new();
+    let mut destroyed = Vec::new();
+    if machines.len() < count {
+        let Some(template) = machines.first() else {
+            return Err(format!("{} has no machine to scale up from", app));
+        };
+        let body = json!({ "region": template["region"], "config": template["config"] });
+        for _ in machines.len()..count {
+            let machine = backend
+                .create_machine(app, &body)
+                .await
+                .map_err(|e| format!("Failed to create machine: {}", e))?;
+            created.push(machine["id"].clone());
+        }
+    }
+    for machine in machines.iter().skip(count).rev() {
+        let Some(id) = machine["id"].as_str() else {
+            continue;
+        };
+        drain::drain(drainer, backend, app, id).await;
+        backend
+            .destroy_machine(app, id, None)
+            .await
+            .map_err(|e| format!("Failed to destroy machine {}: {}", id, e))?;
+        destroyed.push(id);
+    }
+    Ok(json!({ "created_machines": created, "destroyed_machines": destroyed }))
+}
+
+/// Everything a scheduled or commanded operation runs with.
+#[derive(Clone)]
+pub struct Context<B> {
+    pub backend: B,
+    pub store: web::Data<Store>,
+    pub jobs: web::Data<Jobs>,
+    pub drainer: Option<web::Data<Drainer>>,
+    pub breaker: Option<web::Data<DeployBreaker>>,
+    pub objects: Option<web::Data<ObjectStore>>,
+    pub snapshots: Option<SnapshotsConfig>,
+}
+
+pub async fn run_operation<B: Backend>(
+    context: Context<B>,
+    operation: ScheduledOperation,
+) -> Result<Value, String> {
+    let Context {
+        backend,
+        store,
+        drainer,
+        breaker,
+        objects,
+        snapshots,
+        ..
+    } = context;
+    match operation {
+        ScheduledOperation::RunTask {
+            app,
+            region,
+            mut config,
+            wait,
+            timeout_secs,
+        } => {
+            // A waited-for task is destroyed once its exit code is read.
+            config["auto_destroy"] = json!(!wait);
+            config["restart"] = json!({ "policy": "no" });
+            let machine = backend
+                .create_machine(&app, &json!({ "region": region, "config": config }))
+                .await
+                .map_err(|e| format!("Failed to create task machine: {}", e))?;
+            if !wait {
+                return Ok(json!({ "machine_id": machine["id"] }));
+            }
+            let id = machine["id"].as_str().unwrap_or_default();
+            jobs::record_partial(json!({ "machine_id": id }));
+            environments::await_exit(
+                &backend,
+                &app,
+                id,
+                timeout_secs.unwrap_or(DEFAULT_TASK_TIMEOUT_SECS),
+            )
+            .await
+        }
+        ScheduledOperation::Deploy { app, image, config } => {
+            let request = DeployRequest {
+                app,
+                image,
+                config,
+                provenance: None,
+                group: None,
+                require_approval: false,
+                warm_up: None,
+                rollout: None,
+                traffic_step: None,
+                use_private_api: false,
+            };
+            deploys::deploy(backend, store, drainer, breaker, request).await
+        }
+        ScheduledOperation::Scale { app, count } => {
+            scale(&backend, &store, drainer.as_ref(), &app, count).await
+        }
+        ScheduledOperation::Snapshot { app } => {
+            let (Some(objects), Some(snapshots)) = (objects, snapshots) else {
+                return Err("Snapshots are not configured".to_string());
+            };
+            let key = snapshots::snapshot_app(&backend, &store, &objects, &snapshots, &app).await?;
+            Ok(json!({ "key": key }))
+        }
+    }
+}
+
+/// Why the schedule mustn't run, if it mustn't: its creator no longer has the role
+/// schedules need, or the app isn't one their credentials could touch.
+fn refusal(schedule: &Schedule, config: &SchedulesConfig) -> Option<String> {
+    let Some(owner) = &schedule.created_by else {
+        return Some("it has no recorded creator".to_string());
+    };
+    let Some(role) = &config.admin_role else {
+        return Some("schedules.admin_role isn't set".to_string());
+    };
+    if !owner.roles.contains(role) {
+        return Some(format!("{} doesn't have role {}", owner.subject, role));
+    }
+    let app = schedule.operation.app();
+    if owner
+        .apps
+        .as_ref()
+        .is_some_and(|apps| !apps.iter().any(|allowed| allowed == app))
+    {
+        return Some(format!("{} may not act on {}", owner.subject, app));
+    }
+    None
+}
+
+/// Submits each due run as a job, so scheduled operations queue, need approval and show
+/// up in the jobs API like any other.
+pub async fn run<B: Backend + Clone + 'static>(context: Context<B>, config: SchedulesConfig) {
+    let Context { store, jobs, .. } = &context;
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+
+        let schedules = match store.list::<Schedule>(SCHEDULES).await {
+            Ok(schedules) => schedules,
+            Err(e) => {
+                log::error!("Failed to load schedules: {}", e);
+                continue;
+            }
+        };
+        let now = Utc::now();
+        for mut schedule in schedules {
+            let Some(due) = schedule.next_run_at.filter(|due| *due <= now) else {
+                continue;
+            };
+
+            if now - due > MISSED_AFTER && schedule.missed == MissedRunPolicy::Skip {
+                log::warn!("Skipping run of schedule {} missed at {}", schedule.id, due);
+            } else if let Some(reason) = refusal(&schedule, &config) {
+                log::warn!("Not running schedule {}: {}", schedule.id, reason);
+            } else {
+                let app = schedule.operation.app().to_string();
+                let group = jobs.group_for(&app);
+                let needs_approval = jobs.requires_approval(&group);
+                let (context, operation) = (context.clone(), schedule.operation.clone());
+                let work: Work =
+                    Box::new(move || Box::pin(run_operation(context.clone(), operation.clone())));
+                let job = Jobs::submit_scheduled(jobs, &schedule, group, needs_approval, work);
+                schedule.last_run_at = Some(now);
+                schedule.last_job_id = Some(job.id);
+            }
+
+            schedule.next_run_at = next_run(&schedule, now).unwrap_or_else(|e| {
+                log::error!("Schedule {} can't run again: {}", schedule.id, e);
+                None
+            });
+            // Don't bring back a schedule deleted while this run was submitted.
+            if matches!(
+                store.get::<Schedule>(SCHEDULES, &schedule.id).await,
+                Ok(Some(_))
+            ) && let Err(e) = store.put(SCHEDULES, &schedule.id, &schedule).await
+            {
+                log::error!("Failed to save schedule {}: {}", schedule.id, e);
+            }
+        }
+    }
+}
+
+/// The caller, if they may manage schedules.
+fn admin(req: &HttpRequest, config: &Config) -> Result<Identity, HttpResponse> {
+    let Some(role) = &config.schedules.admin_role else {
+        return Err(
+            AppError::forbidden("Managing schedules needs schedules.admin_role set")
+                .into_response(),
+        );
+    };
+    auth::require_role(req, Some(role))
+}
+
+/// The schedule, if the caller may manage it.
+async fn owned(
+    req: &HttpRequest,
+    config: &Config,
+    store: &Store,
+    id: &str,
+) -> Result<Schedule, HttpResponse> {
+    let identity = admin(req, config)?;
+    match store.get::<Schedule>(SCHEDULES, id).await {
+        Ok(Some(schedule)) if identity.may_touch(schedule.operation.app()) => Ok(schedule),
+        Ok(_) => Err(AppError::not_found(format!("No schedule {}", id)).into_response()),
+        Err(e) => Err(AppError::internal(e.to_string()).into_response()),
+    }
+}
+
+#[post("/v0/schedules")]
+async fn create_schedule(
+    req: HttpRequest,
+    body: web::Json<NewSchedule>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let identity = match admin(&req, &config) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let new = body.into_inner();
+    if !identity.may_touch(new.operation.app()) {
+        return AppError::forbidden(format!(
+            "These credentials may not act on {}",
+            new.operation.app()
+        ))
+        .into_response();
+    }
+    if new.cron.is_some() == new.at.is_some() {
+        return AppError::bad_request("A schedule needs exactly one of cron and at")
+            .into_response();
+    }
+    let now = Utc::now();
+    if new.at.is_some_and(|at| at <= now) {
+        return AppError::bad_request("at is in the past").into_response();
+    }
+
+    let mut id = [0u8; 8];
+    getrandom::fill(&mut id).expect("the OS random number generator is available");
+    let mut schedule = Schedule {
+        id: hex::encode(id),
+        operation: new.operation,
+        cron: new.cron,
+        timezone: new.timezone,
+        at: new.at,
+        jitter_secs: new.jitter_secs,
+        missed: new.missed,
+        max_retries: new.max_retries,
+        created_at: now,
+        next_run_at: None,
+        last_run_at: None,
+        last_job_id: None,
+        created_by: Some(ScheduleOwner {
+            subject: identity.subject,
+            roles: identity.roles,
+            apps: identity.apps,
+        }),
+    };
+    schedule.next_run_at = match next_run(&schedule, now) {
+        Ok(next) => next,
+        Err(e) => return AppError::bad_request(e).into_response(),
+    };
+
+    match store.put(SCHEDULES, &schedule.id, &schedule).await {
+        Ok(()) => HttpResponse::Created().json(schedule),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/schedules")]
+async fn list_schedules(
+    req: HttpRequest,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let identity = match admin(&req, &config) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    match store.list::<Schedule>(SCHEDULES).await {
+        Ok(schedules) => HttpResponse::Ok().json(
+            schedules
+                .into_iter()
+                .filter(|schedule| identity.may_touch(schedule.operation.app()))
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/schedules/{id}")]
+async fn get_schedule(
+    req: HttpRequest,
+    path: web::Path<String>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    match owned(&req, &config, &store, &path).await {
+        Ok(schedule) => HttpResponse::Ok().json(schedule),
+        Err(response) => response,
+    }
+}
+
+/// The schedule's runs, newest first, as the jobs they were submitted as. Only as many
+/// as `jobs.history` keeps are still around.
+#[get("/v0/schedules/{id}/runs")]
+async fn list_runs(
+    req: HttpRequest,
+    path: web::Path<String>,
+    store: web::Data<Store>,
+    jobs: web::Data<Jobs>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = owned(&req, &config, &store, &path).await {
+        return response;
+    }
+    match jobs.list().await {
+        Ok(all) => HttpResponse::Ok().json(
+            all.into_iter()
+                .filter(|job| job.schedule_id.as_deref() == Some(path.as_str()))
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+#[delete("/v0/schedules/{id}")]
+async fn delete_schedule(
+    req: HttpRequest,
+    path: web::Path<String>,
+    store: web::Data<Store>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(response) = owned(&req, &config, &store, &path).await {
+        return response;
+    }
+    match store.delete(SCHEDULES, &path).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => AppError::not_found(format!("No schedule {}", path)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_schedule)
+        .service(list_schedules)
+        .service(get_schedule)
+        .service(list_runs)
+        .service(delete_schedule);
+}
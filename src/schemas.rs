@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpRequest, HttpResponse, Responder, get, web};
+use chrono::Utc;
+use flyd::models::{FieldError, SchemaDrift};
+use serde_json::Value;
+
+use crate::auth;
+use crate::config::SchemasConfig;
+use crate::errors::AppError;
+use crate::namespaces;
+use crate::openapi;
+
+pub struct Schemas {
+    config: SchemasConfig,
+    /// Keyed by schema, endpoint and field.
+    drift: Mutex<BTreeMap<(String, String, String), SchemaDrift>>,
+}
+
+impl Schemas {
+    pub fn new(config: &SchemasConfig) -> Self {
+        Schemas {
+            config: config.clone(),
+            drift: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// With `schemas.strict`, checks what the Machines API answered with against the model
+    /// flyd reads it as, e.g. `Machine`. Differences are logged the first time they're seen
+    /// and kept for `/v0/schemas/drift`; the answer is used anyway.
+    pub fn check_upstream(&self, endpoint: &str, schema_name: &str, values: &[Value]) {
+        if !self.config.strict {
+            return;
+        }
+        let Some(schema) = openapi::schema(schema_name) else {
+            return;
+        };
+        let mut errors = Vec::new();
+        for value in values {
+            check(schema, value, "", &mut errors);
+        }
+        if errors.is_empty() {
+            return;
+        }
+        let now = Utc::now();
+        let mut drift = self.drift.lock().unwrap();
+        for error in errors {
+            let key = (
+                schema_name.to_string(),
+                endpoint.to_string(),
+                error.field.clone(),
+            );
+            let seen = drift.entry(key).or_insert_with(|| {
+                log::warn!(
+                    "Schema drift: {} answered {} with {}: {}",
+                    endpoint,
+                    schema_name,
+                    error.field,
+                    error.message
+                );
+                SchemaDrift {
+                    schema: schema_name.to_string(),
+                    endpoint: endpoint.to_string(),
+                    field: error.field,
+                    message: error.message.clone(),
+                    first_seen: now,
+                    last_seen: now,
+                    count: 0,
+                }
+            });
+            seen.message = error.message;
+            seen.last_seen = now;
+            seen.count += 1;
+        }
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn child(field: &str, name: &str) -> String {
+    if field.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", field, name)
+    }
+}
+
+fn add(errors: &mut Vec<FieldError>, field: &str, message: impl Into<String>) {
+    errors.push(FieldError {
+        field: if field.is_empty() { "body" } else { field }.to_string(),
+        message: message.into(),
+    });
+}
+
+/// Checks `value` against a schema from `/openapi.json`, adding an error for each field
+/// that's wrong. Only what serde would also refuse is checked: types, required fields
+/// and enum values. A field that may be left out may also be null.
+fn check(schema: &Value, value: &Value, field: &str, errors: &mut Vec<FieldError>) {
+    if let Some(name) = schema["$ref"].as_str() {
+        if let Some(schema) = name.rsplit('/').next().and_then(openapi::schema) {
+            check(schema, value, field, errors);
+        }
+        return;
+    }
+    for part in schema["allOf"].as_array().into_iter().flatten() {
+        check(part, value, field, errors);
+    }
+    if let Some(variants) = schema["oneOf"].as_array() {
+        let closest = variants
+            .iter()
+            .map(|variant| {
+                let mut found = Vec::new();
+                check(variant, value, field, &mut found);
+                found
+            })
+            .min_by_key(Vec::len);
+        errors.extend(closest.unwrap_or_default());
+    }
+
+    if let Some(expected) = schema["type"].as_str() {
+        let actual = type_of(value);
+        let matches = actual == expected || (expected == "number" && actual == "integer");
+        if !matches {
+            add(errors, field, format!("expected {}", expected));
+            return;
+        }
+    }
+    if let Some(allowed) = schema["enum"].as_array()
+        && !allowed.contains(value)
+    {
+        let allowed: Vec<String> = allowed
+            .iter()
+            .map(|value| value.as_str().map_or(value.to_string(), str::to_string))
+            .collect();
+        add(
+            errors,
+            field,
+            format!("expected one of {}", allowed.join(", ")),
+        );
+    }
+    if let Some(items) = value.as_array() {
+        for (i, item) in items.iter().enumerate() {
+            check(&schema["items"], item, &format!("{}[{}]", field, i), errors);
+        }
+    }
+    let Some(object) = value.as_object() else {
+        return;
+    };
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    for name in &required {
+        if !object.contains_key(*name) {
+            add(errors, &child(field, name), "is required");
+        }
+    }
+    let properties = schema["properties"].as_object();
+    for (name, value) in object {
+        let property = properties.and_then(|properties| properties.get(name));
+        if value.is_null() && !required.contains(&name.as_str()) {
+            continue;
+        }
+        match property {
+            Some(property) => check(property, value, &child(field, name), errors),
+            None if schema["additionalProperties"].is_object() => check(
+                &schema["additionalProperties"],
+                value,
+                &child(field, name),
+                errors,
+            ),
+            None => {}
+        }
+    }
+}
+
+/// Refuses a JSON body that doesn't match the model its endpoint takes with 422, naming
+/// every field that's wrong, e.g. `config.guest.memory_mb: expected integer`. Bodies that
+/// aren't JSON at all are left to the handler to refuse.
+pub async fn validate<B: MessageBody + 'static>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let schema = req
+        .app_data::<web::Data<Schemas>>()
+        .filter(|schemas| schemas.config.validate_requests)
+        .and_then(|_| openapi::operation(&req.match_pattern()?, req.method().as_str()))
+        .and_then(|operation| {
+            operation["requestBody"]["content"]["application/json"]["schema"].as_object()
+        })
+        .filter(|schema| !schema.is_empty());
+    let Some(schema) = schema else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let Some(body) = namespaces::request_body(&mut req).await? else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let mut errors = Vec::new();
+    check(&Value::Object(schema.clone()), &body, "", &mut errors);
+    if errors.is_empty() {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+    let summary = errors
+        .iter()
+        .map(|error| format!("{}: {}", error.field, error.message))
+        .collect::<Vec<_>>()
+        .join("; ");
+    Ok(req.into_response(
+        AppError::unprocessable(format!("Invalid request body: {}", summary))
+            .with_code("invalid_request")
+            .with_fields(errors)
+            .into_response(),
+    ))
+}
+
+/// Where the Machines API's answers have differed from flyd's models, with
+/// `schemas.strict` set.
+#[get("/v0/schemas/drift")]
+async fn list_drift(req: HttpRequest, schemas: web::Data<Schemas>) -> impl Responder {
+    let Some(role) = &schemas.config.admin_role else {
+        return AppError::forbidden("Reading drift needs schemas.admin_role set").into_response();
+    };
+    if let Err(response) = auth::require_role(&req, Some(role)) {
+        return response;
+    }
+    if !schemas.config.strict {
+        return AppError::not_found("Drift is only tracked with schemas.strict set")
+            .into_response();
+    }
+    let drift: Vec<SchemaDrift> = schemas.drift.lock().unwrap().values().cloned().collect();
+    HttpResponse::Ok().json(drift)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_drift);
+}
@@ -0,0 +1,296 @@
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::Utc;
+use flyd::models::{Script, ScriptParam, ScriptRunQuery, ScriptRunRequest};
+use regex::Regex;
+use serde_json::{Value, json};
+
+use crate::artifacts;
+use crate::auth;
+use crate::backend::Backend;
+use crate::config::ScriptConfig;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::exec;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::grants::Grants;
+use crate::prepare_request;
+use crate::sessions::{Recording, SessionRecorder};
+use crate::slo::SloTracker;
+
+const SCRIPT_RUN: &str = "script.run";
+/// Letters, digits, `_`, `.` and `-`, but not starting with `-`, so a value can't pass for
+/// an option, and never `..`, so it can't climb out of a path.
+const DEFAULT_PATTERN: &str =
+    r"(?:[A-Za-z0-9_]|\.[A-Za-z0-9_-])(?:[A-Za-z0-9_-]|\.[A-Za-z0-9_-])*\.?";
+
+struct Param {
+    /// As configured; `regex` is anchored to match whole values.
+    pattern: String,
+    regex: Regex,
+    default: Option<String>,
+}
+
+struct Entry {
+    config: ScriptConfig,
+    params: BTreeMap<String, Param>,
+}
+
+/// Replaces each `{name}` in `template` with its value in one pass, so values are never
+/// themselves expanded. Unknown names are left as they are.
+fn render(template: &str, values: &BTreeMap<String, String>) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after
+            .find('}')
+            .and_then(|end| Some((end, values.get(&after[..end])?)))
+        {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The scripts admins have blessed in `scripts`. Callers choose a script and fill in its
+/// parameters, but never the command itself.
+pub struct ScriptLibrary {
+    scripts: BTreeMap<String, Entry>,
+    events: web::Data<EventLog>,
+}
+
+impl ScriptLibrary {
+    pub fn new(
+        config: &HashMap<String, ScriptConfig>,
+        events: web::Data<EventLog>,
+    ) -> Result<Self, String> {
+        let mut scripts = BTreeMap::new();
+        for (name, script) in config {
+            if script.command.is_empty() {
+                return Err(format!("Script {} has an empty command", name));
+            }
+            let mut params = BTreeMap::new();
+            for (param, param_config) in &script.params {
+                let pattern = param_config.pattern.as_deref().unwrap_or(DEFAULT_PATTERN);
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| format!("Invalid pattern for {}.{}: {}", name, param, e))?;
+                params.insert(
+                    param.clone(),
+                    Param {
+                        pattern: pattern.to_string(),
+                        regex,
+                        default: param_config.default.clone(),
+                    },
+                );
+            }
+            scripts.insert(
+                name.clone(),
+                Entry {
+                    config: script.clone(),
+                    params,
+                },
+            );
+        }
+        Ok(ScriptLibrary { scripts, events })
+    }
+
+    fn command(entry: &Entry, mut values: BTreeMap<String, String>) -> Result<Vec<String>, String> {
+        if let Some(unknown) = values.keys().find(|name| !entry.params.contains_key(*name)) {
+            return Err(format!("Unknown parameter {}", unknown));
+        }
+        for (name, param) in &entry.params {
+            let value = match (values.get(name), &param.default) {
+                (Some(value), _) => value.clone(),
+                (None, Some(default)) => default.clone(),
+                (None, None) => return Err(format!("Missing parameter {}", name)),
+            };
+            if !param.regex.is_match(&value) {
+                return Err(format!("Invalid value for parameter {}", name));
+            }
+            values.insert(name.clone(), value);
+        }
+        Ok(entry
+            .config
+            .command
+            .iter()
+            .map(|arg| render(arg, &values))
+            .collect())
+    }
+}
+
+async fn run<B: Backend>(
+    backend: B,
+    query: &ScriptRunQuery,
+    command: &[String],
+    timeout_secs: u64,
+) -> Result<Value, String> {
+    backend
+        .exec_machine(&query.app, &query.machine, command, timeout_secs)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[get("/v0/scripts")]
+async fn list_scripts(library: web::Data<ScriptLibrary>) -> impl Responder {
+    let scripts: Vec<Script> = library
+        .scripts
+        .iter()
+        .map(|(name, entry)| Script {
+            name: name.clone(),
+            description: entry.config.description.clone(),
+            command: entry.config.command.clone(),
+            params: entry
+                .params
+                .iter()
+                .map(|(name, param)| ScriptParam {
+                    name: name.clone(),
+                    pattern: param.pattern.clone(),
+                    default: param.default.clone(),
+                })
+                .collect(),
+            role: entry.config.role.clone(),
+            apps: entry.config.apps.clone(),
+        })
+        .collect();
+    HttpResponse::Ok().json(scripts)
+}
+
+/// Runs a script on `machine` of `app` and returns its output. Every run, allowed or
+/// not, is recorded as a `script.run` event.
+#[post("/v0/scripts/{name}/run")]
+async fn run_script(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ScriptRunQuery>,
+    body: Option<web::Json<ScriptRunRequest>>,
+    library: web::Data<ScriptLibrary>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let Some(entry) = library.scripts.get(&name) else {
+        return AppError::not_found(format!("No script {}", name)).into_response();
+    };
+    let mut identity = match auth::require_role(&req, None) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    if let Some(grants) = req.app_data::<web::Data<Grants>>() {
+        identity = grants.elevate(&identity, &query.app, SCRIPT_RUN).await;
+    }
+
+    let recorder = req.app_data::<web::Data<SessionRecorder>>();
+    let record = |command: Option<&[String]>, outcome: Value| {
+        let command = command.map(|command| match recorder {
+            Some(recorder) => recorder.redact_command(command),
+            None => command.to_vec(),
+        });
+        library.events.record(
+            SCRIPT_RUN,
+            Some(&query.app),
+            Some(&query.machine),
+            json!({
+                "script": name,
+                "subject": identity.subject,
+                "command": command,
+                "outcome": outcome,
+            }),
+        );
+    };
+    if let Some(role) = &entry.config.role
+        && !identity.roles.contains(role)
+    {
+        record(None, json!({ "denied": "missing role" }));
+        return AppError::forbidden(format!("Running {} requires role {}", name, role))
+            .into_response();
+    }
+    if !entry.config.apps.is_empty() && !entry.config.apps.contains(&query.app) {
+        record(None, json!({ "denied": "app not allowed" }));
+        return AppError::forbidden(format!("{} can't run on {}", name, query.app)).into_response();
+    }
+    let values = body
+        .map(|body| body.into_inner().params)
+        .unwrap_or_default();
+    let command = match ScriptLibrary::command(entry, values) {
+        Ok(command) => command,
+        Err(e) => return AppError::bad_request(e).into_response(),
+    };
+
+    let started_at = Utc::now();
+    let timeout_secs = entry.config.timeout_secs;
+    let result = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        run(docker.get_ref().clone(), &query, &command, timeout_secs).await
+    } else {
+        let (headers, api_hostname) = match prepare_request(&req, false) {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner());
+        run(client, &query, &command, timeout_secs).await
+    };
+
+    let session_id = match recorder {
+        Some(recorder) => {
+            let recording = Recording {
+                kind: "script",
+                script: Some(&name),
+                app: &query.app,
+                machine_id: &query.machine,
+                subject: &identity.subject,
+                command: &command,
+                started_at,
+            };
+            recorder.record(recording, &result).await
+        }
+        None => None,
+    };
+    match result {
+        Ok(mut output) => {
+            let mut outcome = exec::output_summary(&output);
+            artifacts::offload(&req, &query.app, &name, &mut output).await;
+            outcome["session_id"] = json!(session_id);
+            record(Some(&command), outcome);
+            HttpResponse::Ok().json(output)
+        }
+        Err(e) => {
+            record(
+                Some(&command),
+                json!({ "error": e, "session_id": session_id }),
+            );
+            AppError::bad_gateway(e).into_response()
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_scripts).service(run_script);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_default_pattern_refuses_options_and_parent_directories() {
+        let regex = Regex::new(&format!("^(?:{})$", DEFAULT_PATTERN)).unwrap();
+        for allowed in ["users", "v1.2.3", "app_db-2", ".env", "x.", "a-"] {
+            assert!(regex.is_match(allowed), "{}", allowed);
+        }
+        for refused in ["", "-rf", "--help", "..", "a..b", "x/y", ".", "a b"] {
+            assert!(!regex.is_match(refused), "{}", refused);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::{DateTime, Utc};
+use flyd::models::{
+    ListSecretsRequest, RestartStaleRequest, SecretDigests, SecretDrift, StaleMachine,
+};
+use serde_json::{Value, json};
+
+use crate::config::{Config, SecretDriftConfig};
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets;
+use crate::fly_client::{self, FlyClient, WithChecks};
+use crate::jobs::{self, Jobs, Work};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+const DIGESTS: &str = "secret_digests";
+
+fn timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    value
+        .as_str()
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        .map(|at| at.with_timezone(&Utc))
+}
+
+/// Imports the app's secrets' digests. A change since the last import moves `changed_at`
+/// to when Fly says a secret was last set, or to now if it doesn't say or that's older;
+/// `just_changed` says flyd itself just set or unset one.
+pub async fn refresh(
+    client: &FlyClient,
+    store: &Store,
+    app: &str,
+    just_changed: bool,
+) -> Result<SecretDigests, String> {
+    let secrets = client
+        .list_secrets(app)
+        .await
+        .map_err(|e| format!("Failed to list secrets: {}", e))?;
+    let mut digests = BTreeMap::new();
+    let mut latest = None;
+    for secret in &secrets {
+        let Some(name) = secret["name"].as_str().or(secret["label"].as_str()) else {
+            continue;
+        };
+        let digest = secret["digest"].as_str().unwrap_or_default();
+        digests.insert(name.to_string(), digest.to_string());
+        latest = latest.max(timestamp(&secret["updated_at"]).or(timestamp(&secret["created_at"])));
+    }
+
+    let now = Utc::now();
+    let previous = store
+        .get::<SecretDigests>(DIGESTS, app)
+        .await
+        .map_err(|e| e.to_string())?;
+    let changed_at = match previous {
+        Some(previous) if previous.digests == digests => previous.changed_at,
+        Some(previous) => Some(latest.filter(|at| *at > previous.checked_at).unwrap_or(now)),
+        None if just_changed => Some(now),
+        None => latest,
+    };
+    let imported = SecretDigests {
+        app: app.to_string(),
+        digests,
+        changed_at,
+        checked_at: now,
+    };
+    store
+        .put(DIGESTS, app, &imported)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(imported)
+}
+
+/// When the machine last started: its newest `started` event, or when it was last updated
+/// if Fly didn't include its events.
+fn started_at(machine: &Value) -> Option<DateTime<Utc>> {
+    let started = machine["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|event| event["status"] == "started")
+        .filter_map(|event| DateTime::from_timestamp_millis(event["timestamp"].as_i64()?))
+        .max();
+    started.or_else(|| timestamp(&machine["updated_at"]))
+}
+
+pub fn stale(machines: &[Value], digests: &SecretDigests) -> Vec<StaleMachine> {
+    let Some(changed_at) = digests.changed_at else {
+        return Vec::new();
+    };
+    machines
+        .iter()
+        .filter(|machine| fleets::is_live(machine) && machine["state"] == "started")
+        .filter_map(|machine| {
+            let started_at = started_at(machine);
+            if started_at.is_some_and(|at| at >= changed_at) {
+                return None;
+            }
+            Some(StaleMachine {
+                id: machine["id"].as_str()?.to_string(),
+                name: machine["name"].as_str().map(str::to_string),
+                region: machine["region"].as_str().map(str::to_string),
+                started_at,
+            })
+        })
+        .collect()
+}
+
+/// The ids of the app's started machines running stale secrets, going by the digests last
+/// imported; none if they never were.
+pub async fn stale_ids(store: &Store, app: &str, machines: &[Value]) -> Vec<String> {
+    match store.get::<SecretDigests>(DIGESTS, app).await {
+        Ok(Some(digests)) => stale(machines, &digests)
+            .into_iter()
+            .map(|machine| machine.id)
+            .collect(),
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            log::warn!("Failed to load the secrets digests of {}: {}", app, e);
+            Vec::new()
+        }
+    }
+}
+
+async fn drift(client: &FlyClient, store: &Store, app: &str) -> Result<SecretDrift, String> {
+    let digests = refresh(client, store, app, false).await?;
+    let machines = client
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?;
+    Ok(SecretDrift {
+        app: app.to_string(),
+        changed_at: digests.changed_at,
+        checked_at: digests.checked_at,
+        stale_machines: stale(&machines, &digests),
+    })
+}
+
+/// Restarts the stale machines one at a time, each drained first and back in traffic once
+/// it's started again.
+async fn restart_stale(
+    client: FlyClient,
+    store: web::Data<Store>,
+    drainer: Option<web::Data<Drainer>>,
+    events: web::Data<EventLog>,
+    app: String,
+    timeout: Duration,
+) -> Result<Value, String> {
+    let drift = drift(&client, &store, &app).await?;
+    let mut restarted = Vec::new();
+    for machine in &drift.stale_machines {
+        drain::drain(drainer.as_ref(), &client, &app, &machine.id).await;
+        client
+            .restart_machine(&app, &machine.id)
+            .await
+            .map_err(|e| format!("Failed to restart machine {}: {}", machine.id, e))?;
+        client
+            .wait_for_state(&app, &machine.id, "started", None, timeout)
+            .await
+            .map_err(|e| format!("Machine {} didn't start again: {}", machine.id, e))?;
+        drain::restore(drainer.as_ref(), &client, &app, &machine.id).await;
+        restarted.push(machine.id.clone());
+    }
+    fly_client::cache().invalidate(&app);
+    if !restarted.is_empty() {
+        events.record(
+            "secrets.restarted",
+            Some(&app),
+            None,
+            json!({ "machines": restarted, "changed_at": drift.changed_at }),
+        );
+    }
+    Ok(json!({ "restarted_machines": restarted }))
+}
+
+pub async fn run(
+    config: SecretDriftConfig,
+    client: FlyClient,
+    store: web::Data<Store>,
+    drainer: Option<web::Data<Drainer>>,
+    events: web::Data<EventLog>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        for app in &config.apps {
+            let result = if config.auto_restart {
+                restart_stale(
+                    client.clone(),
+                    store.clone(),
+                    drainer.clone(),
+                    events.clone(),
+                    app.clone(),
+                    Duration::from_secs(config.restart_timeout_secs),
+                )
+                .await
+                .map(|_| ())
+            } else {
+                drift(&client, &store, app).await.map(|drift| {
+                    if !drift.stale_machines.is_empty() {
+                        log::info!(
+                            "{} of {}'s machines are running stale secrets",
+                            drift.stale_machines.len(),
+                            app
+                        );
+                    }
+                })
+            };
+            if let Err(e) = result {
+                log::error!("Secret drift: {}: {}", app, e);
+            }
+        }
+    }
+}
+
+fn client(
+    req: &HttpRequest,
+    use_private_api: bool,
+    http_client: &web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> Result<FlyClient, HttpResponse> {
+    let (headers, api_hostname) = prepare_request(req, use_private_api)?;
+    Ok(
+        FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner()),
+    )
+}
+
+/// Re-imports the app's secrets' digests and lists the started machines that predate
+/// their last change.
+#[get("/v0/secrets/drift")]
+async fn get_drift(
+    req: HttpRequest,
+    query: web::Query<ListSecretsRequest>,
+    store: web::Data<Store>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    match drift(&client, &store, &query.app_name).await {
+        Ok(drift) => HttpResponse::Ok().json(drift),
+        Err(e) => AppError::bad_gateway(e).into_response(),
+    }
+}
+
+/// Queues a rolling restart of the machines running stale secrets, returning its job.
+#[post("/v0/secrets/restart_stale")]
+async fn restart_stale_machines(
+    req: HttpRequest,
+    body: web::Json<RestartStaleRequest>,
+    store: web::Data<Store>,
+    jobs: web::Data<Jobs>,
+    drainer: Option<web::Data<Drainer>>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let Some(events) = req.app_data::<web::Data<EventLog>>().cloned() else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    let timeout = Duration::from_secs(
+        req.app_data::<web::Data<Config>>()
+            .and_then(|config| config.secret_drift.as_ref())
+            .map_or(SecretDriftConfig::default().restart_timeout_secs, |drift| {
+                drift.restart_timeout_secs
+            }),
+    );
+    let app = body.app_name.clone();
+    let group = jobs.group_for(&app);
+    let needs_approval = body.require_approval || jobs.requires_approval(&group);
+    let work: Work = Box::new(move || {
+        Box::pin(restart_stale(
+            client.clone(),
+            store.clone(),
+            drainer.clone(),
+            events.clone(),
+            app.clone(),
+            timeout,
+        ))
+    });
+    let job = Jobs::submit(
+        &jobs,
+        "secrets.restart_stale",
+        Some(&body.app_name),
+        group,
+        needs_approval,
+        jobs::submitter(&req),
+        work,
+    );
+    HttpResponse::Accepted().json(job)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_drift).service(restart_stale_machines);
+}
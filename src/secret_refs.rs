@@ -0,0 +1,504 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpMessage, HttpRequest};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rusty_s3::Credentials;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::auth::Identity;
+use crate::config::SecretManagersConfig;
+
+const SCHEME: &str = "secretref://";
+const GCP_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+/// Refresh GCP access tokens this long before Google says they expire.
+const TOKEN_SLACK: Duration = Duration::from_secs(60);
+
+/// An env var that was filled in from a secret manager.
+pub struct Resolved {
+    /// JSON pointer to the variable within the machine config.
+    pointer: String,
+    reference: String,
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// Resolves `secretref://{manager}/{path}[#{key}]` env values just before a machine
+/// config is sent to Fly. flyd caches nothing, but the values end up in the machine's
+/// config on Fly like any other env; Fly secrets suit values that mustn't.
+pub struct SecretResolver {
+    config: SecretManagersConfig,
+    http: reqwest::Client,
+    gcp_token: Mutex<Option<(String, Instant)>>,
+}
+
+/// A resolver acting for one caller.
+pub struct Scoped<'a> {
+    resolver: &'a SecretResolver,
+    roles: Vec<String>,
+}
+
+impl Scoped<'_> {
+    /// Replaces every `secretref://` value in the config's `env` and `processes[].env`, if
+    /// `[secret_managers.allow]` lets the caller use each in `app`'s machines.
+    pub async fn resolve(&self, app: &str, config: &mut Value) -> Result<Vec<Resolved>, String> {
+        self.resolver.resolve(app, &self.roles, config).await
+    }
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Picks `key` out of a JSON secret, or returns the whole secret without one.
+fn pick(secret: String, key: Option<&str>) -> Result<String, String> {
+    let Some(key) = key else {
+        return Ok(secret);
+    };
+    let fields: Value = serde_json::from_str(&secret)
+        .map_err(|_| format!("Secret isn't JSON, can't read #{}", key))?;
+    match &fields[key] {
+        Value::String(value) => Ok(value.clone()),
+        Value::Null => Err(format!("Secret has no key {}", key)),
+        value => Ok(value.to_string()),
+    }
+}
+
+impl SecretResolver {
+    pub fn new(config: SecretManagersConfig, http: reqwest::Client) -> Self {
+        SecretResolver {
+            config,
+            http,
+            gcp_token: Mutex::new(None),
+        }
+    }
+
+    /// Resolves for the request's caller.
+    pub fn scoped(&self, req: &HttpRequest) -> Scoped<'_> {
+        Scoped {
+            resolver: self,
+            roles: req
+                .extensions()
+                .get::<Identity>()
+                .map(|identity| identity.roles.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether a caller with `roles` may use the reference at `location` in `app`'s
+    /// machines. Paths with empty, `.` or `..` segments never are, so they can't climb out
+    /// of a prefix.
+    fn allows(&self, app: &str, roles: &[String], location: &str) -> bool {
+        let path = location.split_once('#').map_or(location, |(path, _)| path);
+        if path
+            .split('/')
+            .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+        {
+            return false;
+        }
+        self.config.allow.iter().any(|grant| {
+            path.starts_with(&grant.prefix)
+                && (grant.apps.is_empty() || grant.apps.iter().any(|allowed| allowed == app))
+                && (grant.roles.is_empty() || roles.iter().any(|role| grant.roles.contains(role)))
+        })
+    }
+
+    async fn resolve(
+        &self,
+        app: &str,
+        roles: &[String],
+        config: &mut Value,
+    ) -> Result<Vec<Resolved>, String> {
+        let mut pointers = vec!["/env".to_string()];
+        let processes = config["processes"].as_array().map_or(0, Vec::len);
+        pointers.extend((0..processes).map(|index| format!("/processes/{}/env", index)));
+
+        let mut resolved = Vec::new();
+        for pointer in pointers {
+            let Some(env) = config.pointer_mut(&pointer).and_then(Value::as_object_mut) else {
+                continue;
+            };
+            for (name, value) in env.iter_mut() {
+                let Some(reference) = value.as_str().filter(|value| value.starts_with(SCHEME))
+                else {
+                    continue;
+                };
+                let reference = reference.to_string();
+                if !self.allows(
+                    app,
+                    roles,
+                    reference.strip_prefix(SCHEME).unwrap_or_default(),
+                ) {
+                    return Err(format!(
+                        "{} isn't allowed for {} by [secret_managers.allow]",
+                        reference, name
+                    ));
+                }
+                let secret = self
+                    .fetch(&reference)
+                    .await
+                    .map_err(|e| format!("Failed to resolve {} for {}: {}", reference, name, e))?;
+                *value = Value::String(secret);
+                resolved.push(Resolved {
+                    pointer: format!("{}/{}", pointer, escape_pointer(name)),
+                    reference,
+                });
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Puts the references back into a machine returned by Fly, so flyd's responses (and
+    /// anything caching them) don't carry the secrets.
+    pub fn mask(machine: &mut Value, resolved: &[Resolved]) {
+        for resolved in resolved {
+            if let Some(value) = machine["config"].pointer_mut(&resolved.pointer) {
+                *value = json!(resolved.reference);
+            }
+        }
+    }
+
+    async fn fetch(&self, reference: &str) -> Result<String, String> {
+        let location = reference.strip_prefix(SCHEME).unwrap_or(reference);
+        let (location, key) = match location.split_once('#') {
+            Some((location, key)) => (location, Some(key)),
+            None => (location, None),
+        };
+        let (manager, path) = location
+            .split_once('/')
+            .ok_or("Missing the secret's path")?;
+        match manager {
+            "vault" => self.vault(path, key).await,
+            "aws-sm" => pick(self.aws(path).await?, key),
+            "gcp-sm" => pick(self.gcp(path).await?, key),
+            other => Err(format!("Unknown secret manager {}", other)),
+        }
+    }
+
+    async fn vault(&self, path: &str, key: Option<&str>) -> Result<String, String> {
+        let vault = self
+            .config
+            .vault
+            .as_ref()
+            .ok_or("secret_managers.vault is not configured")?;
+        let key = key.ok_or("Vault references need a #key")?;
+        let token = std::env::var("VAULT_TOKEN").map_err(|_| "VAULT_TOKEN is not set")?;
+
+        let mut request = self
+            .http
+            .get(format!(
+                "{}/v1/{}",
+                vault.address.trim_end_matches('/'),
+                path
+            ))
+            .header("x-vault-token", token);
+        if let Some(namespace) = &vault.namespace {
+            request = request.header("x-vault-namespace", namespace);
+        }
+        let body: Value = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // KV v2 nests the secret one level deeper than v1.
+        let data = if body["data"]["data"].is_object() {
+            &body["data"]["data"]
+        } else {
+            &body["data"]
+        };
+        match &data[key] {
+            Value::String(value) => Ok(value.clone()),
+            Value::Null => Err(format!("Secret has no key {}", key)),
+            value => Ok(value.to_string()),
+        }
+    }
+
+    /// `GetSecretValue`, signed with SigV4.
+    async fn aws(&self, secret_id: &str) -> Result<String, String> {
+        let aws = self
+            .config
+            .aws
+            .as_ref()
+            .ok_or("secret_managers.aws is not configured")?;
+        let credentials = Credentials::from_env().ok_or("AWS credentials are not set")?;
+
+        let host = format!("secretsmanager.{}.amazonaws.com", aws.region);
+        let body = json!({ "SecretId": secret_id }).to_string();
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let target = "secretsmanager.GetSecretValue";
+        let content_type = "application/x-amz-json-1.1";
+
+        let mut headers = vec![
+            ("content-type", content_type.to_string()),
+            ("host", host.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = credentials.token() {
+            headers.push(("x-amz-security-token", token.to_string()));
+        }
+        headers.push(("x-amz-target", target.to_string()));
+
+        let signed_headers: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        let signed_headers = signed_headers.join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{:x}",
+            canonical_headers,
+            signed_headers,
+            Sha256::digest(body.as_bytes())
+        );
+        let scope = format!("{}/{}/secretsmanager/aws4_request", date, aws.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let signing_key = ["secretsmanager", "aws4_request"].iter().fold(
+            hmac_sha256(
+                &hmac_sha256(format!("AWS4{}", credentials.secret()).as_bytes(), &date),
+                &aws.region,
+            ),
+            |key, part| hmac_sha256(&key, part),
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.key(),
+            scope,
+            signed_headers,
+            hex::encode(hmac_sha256(&signing_key, &string_to_sign))
+        );
+
+        let mut request = self
+            .http
+            .post(format!("https://{}/", host))
+            .header("authorization", authorization)
+            .body(body);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        let response: Value = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if let Some(secret) = response["SecretString"].as_str() {
+            return Ok(secret.to_string());
+        }
+        let binary = response["SecretBinary"]
+            .as_str()
+            .ok_or("Secret has no value")?;
+        let decoded = STANDARD.decode(binary).map_err(|e| e.to_string())?;
+        String::from_utf8(decoded).map_err(|_| "Secret isn't UTF-8".to_string())
+    }
+
+    async fn gcp_access_token(&self) -> Result<String, String> {
+        if let Some((token, expires)) = self.gcp_token.lock().unwrap().as_ref()
+            && Instant::now() < *expires
+        {
+            return Ok(token.clone());
+        }
+
+        let gcp = self
+            .config
+            .gcp
+            .as_ref()
+            .ok_or("secret_managers.gcp is not configured")?;
+        let path = match &gcp.credentials_file {
+            Some(path) => path.clone(),
+            None => std::env::var("GOOGLE_APPLICATION_CREDENTIALS")
+                .map_err(|_| "GOOGLE_APPLICATION_CREDENTIALS is not set")?,
+        };
+        let account: ServiceAccount = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Invalid service account file {}: {}", path, e))?;
+
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "iss": account.client_email,
+            "scope": GCP_SCOPE,
+            "aud": account.token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        });
+        let key = EncodingKey::from_rsa_pem(account.private_key.as_bytes())
+            .map_err(|e| format!("Invalid service account key: {}", e))?;
+        let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key)
+            .map_err(|e| e.to_string())?;
+
+        let response: Value = self
+            .http
+            .post(&account.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        let token = response["access_token"]
+            .as_str()
+            .ok_or("Token response has no access_token")?
+            .to_string();
+        let lifetime = Duration::from_secs(response["expires_in"].as_u64().unwrap_or(3600));
+        *self.gcp_token.lock().unwrap() = Some((
+            token.clone(),
+            Instant::now() + lifetime.saturating_sub(TOKEN_SLACK),
+        ));
+        Ok(token)
+    }
+
+    async fn gcp(&self, name: &str) -> Result<String, String> {
+        let token = self.gcp_access_token().await?;
+        let name = if name.contains("/versions/") {
+            name.to_string()
+        } else {
+            format!("{}/versions/latest", name)
+        };
+
+        let response: Value = self
+            .http
+            .get(format!(
+                "https://secretmanager.googleapis.com/v1/{}:access",
+                name
+            ))
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        let data = response["payload"]["data"]
+            .as_str()
+            .ok_or("Secret has no payload")?;
+        let decoded = STANDARD.decode(data).map_err(|e| e.to_string())?;
+        String::from_utf8(decoded).map_err(|_| "Secret isn't UTF-8".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::SecretRefGrant;
+
+    use super::*;
+
+    fn resolver(allow: Vec<SecretRefGrant>) -> SecretResolver {
+        SecretResolver::new(
+            SecretManagersConfig {
+                allow,
+                ..Default::default()
+            },
+            reqwest::Client::new(),
+        )
+    }
+
+    fn grant(prefix: &str, apps: &[&str], roles: &[&str]) -> SecretRefGrant {
+        SecretRefGrant {
+            prefix: prefix.to_string(),
+            apps: apps.iter().map(|app| app.to_string()).collect(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn masks_resolved_values_with_their_references() {
+        let mut machine = json!({
+            "config": {
+                "env": { "DB/PASSWORD": "hunter2", "PLAIN": "kept" },
+                "processes": [{ "env": { "TOKEN": "s3cr3t" } }],
+            },
+        });
+        let resolved = [
+            Resolved {
+                pointer: format!("/env/{}", escape_pointer("DB/PASSWORD")),
+                reference: "secretref://vault/secret/data/db#password".to_string(),
+            },
+            Resolved {
+                pointer: "/processes/0/env/TOKEN".to_string(),
+                reference: "secretref://aws-sm/token".to_string(),
+            },
+        ];
+        SecretResolver::mask(&mut machine, &resolved);
+        assert_eq!(
+            machine["config"],
+            json!({
+                "env": {
+                    "DB/PASSWORD": "secretref://vault/secret/data/db#password",
+                    "PLAIN": "kept",
+                },
+                "processes": [{ "env": { "TOKEN": "secretref://aws-sm/token" } }],
+            })
+        );
+    }
+
+    #[test]
+    fn allows_only_granted_references() {
+        let resolver = resolver(vec![
+            grant("vault/secret/data/web/", &["web"], &[]),
+            grant("aws-sm/", &[], &["ops"]),
+        ]);
+        let ops = ["ops".to_string()];
+
+        assert!(resolver.allows("web", &[], "vault/secret/data/web/db#password"));
+        assert!(!resolver.allows("api", &[], "vault/secret/data/web/db#password"));
+        assert!(!resolver.allows("web", &[], "vault/secret/data/billing/db#password"));
+        assert!(!resolver.allows("web", &[], "aws-sm/token"));
+        assert!(resolver.allows("api", &ops, "aws-sm/token"));
+    }
+
+    #[test]
+    fn refuses_paths_climbing_out_of_a_prefix() {
+        let resolver = resolver(vec![grant("vault/secret/data/web/", &[], &[])]);
+        assert!(!resolver.allows("web", &[], "vault/secret/data/web/../billing#password"));
+        assert!(!resolver.allows("web", &[], "vault/secret/data/web//db#password"));
+        assert!(!resolver.allows("web", &[], "vault/secret/data/web/./db#password"));
+    }
+
+    #[tokio::test]
+    async fn refuses_references_without_a_grant_before_fetching() {
+        let resolver = resolver(Vec::new());
+        let mut config = json!({ "env": { "TOKEN": "secretref://aws-sm/token" } });
+        let Err(error) = resolver.resolve("web", &[], &mut config).await else {
+            panic!("resolved a reference without a grant");
+        };
+        assert!(error.contains("isn't allowed"), "{}", error);
+        assert_eq!(config["env"]["TOKEN"], "secretref://aws-sm/token");
+    }
+}
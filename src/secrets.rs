@@ -0,0 +1,132 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use flyd::models::{ListSecretsRequest, SetSecretsRequest, UnsetSecretRequest};
+use serde_json::json;
+
+use crate::auth::Identity;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::secret_drift;
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+const SECRETS_SET: &str = "secrets.set";
+const SECRET_UNSET: &str = "secret.unset";
+
+fn client(
+    req: &HttpRequest,
+    use_private_api: bool,
+    http_client: &web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> Result<FlyClient, HttpResponse> {
+    let (headers, api_hostname) = prepare_request(req, use_private_api)?;
+    Ok(
+        FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner()),
+    )
+}
+
+/// Re-imports the digests right after a change, so machines started since don't count as
+/// stale.
+async fn changed(client: &FlyClient, store: &Store, app: &str) {
+    if let Err(e) = secret_drift::refresh(client, store, app, true).await {
+        log::warn!("Failed to import the secrets digests of {}: {}", app, e);
+    }
+}
+
+fn caller(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<Identity>()
+        .map(|identity| identity.subject.clone())
+}
+
+/// Sets the secrets in order, stopping at the first Fly refuses. Only the names of those
+/// set are recorded, in a `secrets.set` event; values never leave the request.
+#[post("/v0/secrets/set")]
+async fn set_secrets(
+    req: HttpRequest,
+    body: web::Json<SetSecretsRequest>,
+    events: web::Data<EventLog>,
+    store: web::Data<Store>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let mut set = Vec::new();
+    let mut failure = None;
+    for (name, value) in &body.secrets {
+        match client.set_secret(&body.app_name, name, value).await {
+            Ok(()) => set.push(name),
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        }
+    }
+    if !set.is_empty() {
+        changed(&client, &store, &body.app_name).await;
+        events.record(
+            SECRETS_SET,
+            Some(&body.app_name),
+            None,
+            json!({ "names": set, "by": caller(&req) }),
+        );
+    }
+    match failure {
+        Some(e) => AppError::from(&e).into_response(),
+        None => HttpResponse::Ok().json(json!({ "set": set })),
+    }
+}
+
+/// The app's secrets' names and digests; Fly never returns values.
+#[get("/v0/secrets/list")]
+async fn list_secrets(
+    req: HttpRequest,
+    query: web::Query<ListSecretsRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => match client.list_secrets(&query.app_name).await {
+            Ok(secrets) => HttpResponse::Ok().json(secrets),
+            Err(e) => AppError::from(&e).into_response(),
+        },
+        Err(response) => response,
+    }
+}
+
+#[delete("/v0/secrets/unset")]
+async fn unset_secret(
+    req: HttpRequest,
+    query: web::Query<UnsetSecretRequest>,
+    events: web::Data<EventLog>,
+    store: web::Data<Store>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    if let Err(e) = client.unset_secret(&query.app_name, &query.name).await {
+        return AppError::from(&e).into_response();
+    }
+    changed(&client, &store, &query.app_name).await;
+    events.record(
+        SECRET_UNSET,
+        Some(&query.app_name),
+        None,
+        json!({ "name": query.name, "by": caller(&req) }),
+    );
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(set_secrets)
+        .service(list_secrets)
+        .service(unset_secret);
+}
@@ -0,0 +1,234 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::{DateTime, Utc};
+use flyd::models::{Session, SessionsQuery};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::auth::{self, Identity};
+use crate::config::SessionsConfig;
+use crate::errors::AppError;
+use crate::namespaces;
+use crate::store::Store;
+
+const SESSIONS: &str = "sessions";
+
+/// What was run, by whom and where, before it's redacted and stored.
+pub struct Recording<'a> {
+    pub kind: &'a str,
+    pub script: Option<&'a str>,
+    pub app: &'a str,
+    pub machine_id: &'a str,
+    pub subject: &'a str,
+    pub command: &'a [String],
+    pub started_at: DateTime<Utc>,
+}
+
+/// Keeps transcripts of exec and script runs in the store, redacted, for security
+/// review. Redaction happens before storing, so secrets matching a rule never persist.
+pub struct SessionRecorder {
+    config: SessionsConfig,
+    redactions: Vec<(Regex, String)>,
+    store: web::Data<Store>,
+}
+
+fn truncate(text: &mut String, max_bytes: usize) -> bool {
+    if text.len() <= max_bytes {
+        return false;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    true
+}
+
+impl SessionRecorder {
+    /// Returns `None` unless `sessions.record` is set.
+    pub fn from_config(
+        config: &SessionsConfig,
+        store: web::Data<Store>,
+    ) -> Result<Option<Self>, String> {
+        if !config.record {
+            return Ok(None);
+        }
+        let redactions = config
+            .redact
+            .iter()
+            .map(|rule| {
+                Regex::new(&rule.pattern)
+                    .map(|regex| (regex, rule.replacement.clone()))
+                    .map_err(|e| format!("Invalid redaction pattern {}: {}", rule.pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Some(SessionRecorder {
+            config: config.clone(),
+            redactions,
+            store,
+        }))
+    }
+
+    fn redact(&self, text: &str, redactions: &mut usize) -> String {
+        let mut text = text.to_string();
+        for (regex, replacement) in &self.redactions {
+            let matches = regex.find_iter(&text).count();
+            if matches > 0 {
+                *redactions += matches;
+                text = regex.replace_all(&text, replacement.as_str()).into_owned();
+            }
+        }
+        text
+    }
+
+    /// The command as the audit trail may show it.
+    pub fn redact_command(&self, command: &[String]) -> Vec<String> {
+        command.iter().map(|arg| self.redact(arg, &mut 0)).collect()
+    }
+
+    /// Stores the transcript of a run, returning the session's id.
+    pub async fn record(
+        &self,
+        recording: Recording<'_>,
+        result: &Result<Value, String>,
+    ) -> Option<String> {
+        let mut redactions = 0;
+        let command = recording
+            .command
+            .iter()
+            .map(|arg| self.redact(arg, &mut redactions))
+            .collect();
+        let output = result.as_ref().ok();
+        let stream = |name: &str| output.and_then(|output| output[name].as_str());
+        let mut stdout = self.redact(stream("stdout").unwrap_or_default(), &mut redactions);
+        let mut stderr = self.redact(stream("stderr").unwrap_or_default(), &mut redactions);
+        let truncated = truncate(&mut stdout, self.config.max_transcript_bytes)
+            | truncate(&mut stderr, self.config.max_transcript_bytes);
+
+        let mut id = [0u8; 8];
+        getrandom::fill(&mut id).expect("the OS random number generator is available");
+        let session = Session {
+            id: hex::encode(id),
+            kind: recording.kind.to_string(),
+            script: recording.script.map(str::to_string),
+            app: recording.app.to_string(),
+            machine_id: recording.machine_id.to_string(),
+            subject: recording.subject.to_string(),
+            command,
+            started_at: recording.started_at,
+            finished_at: Utc::now(),
+            exit_code: output.and_then(|output| output["exit_code"].as_i64()),
+            stdout,
+            stderr,
+            error: result
+                .as_ref()
+                .err()
+                .map(|e| self.redact(e, &mut redactions)),
+            redactions,
+            truncated,
+        };
+        if let Err(e) = self.store.put(SESSIONS, &session.id, &session).await {
+            log::error!("Failed to record session {}: {}", session.id, e);
+            return None;
+        }
+        self.prune().await;
+        Some(session.id)
+    }
+
+    async fn prune(&self) {
+        let mut sessions = match self.store.list::<Session>(SESSIONS).await {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                log::error!("Failed to list sessions: {}", e);
+                return;
+            }
+        };
+        let excess = sessions.len().saturating_sub(self.config.history);
+        if excess == 0 {
+            return;
+        }
+        sessions.sort_by_key(|session| session.started_at);
+        for session in &sessions[..excess] {
+            if let Err(e) = self.store.delete(SESSIONS, &session.id).await {
+                log::error!("Failed to drop session {}: {}", session.id, e);
+            }
+        }
+    }
+
+    /// Transcripts hold what was run and printed on machines, so only reviewers may read
+    /// them, and only for apps they may touch.
+    fn reviewer(&self, req: &HttpRequest) -> Result<Identity, HttpResponse> {
+        let Some(role) = &self.config.reviewer_role else {
+            return Err(
+                AppError::forbidden("Reading sessions needs sessions.reviewer_role set")
+                    .into_response(),
+            );
+        };
+        auth::require_role(req, Some(role))
+    }
+}
+
+fn may_read(req: &HttpRequest, identity: &Identity, session: &Session) -> bool {
+    identity.may_touch(&session.app)
+        && namespaces::of(req).is_none_or(|namespace| namespace.owns(&session.app))
+}
+
+/// Newest first, each with its full transcript.
+#[get("/v0/sessions")]
+async fn list_sessions(
+    req: HttpRequest,
+    query: web::Query<SessionsQuery>,
+    recorder: Option<web::Data<SessionRecorder>>,
+) -> impl Responder {
+    let Some(recorder) = recorder else {
+        return AppError::not_found("Session recording is not enabled").into_response();
+    };
+    let identity = match recorder.reviewer(&req) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let mut sessions = match recorder.store.list::<Session>(SESSIONS).await {
+        Ok(sessions) => sessions,
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    sessions.retain(|session| {
+        may_read(&req, &identity, session)
+            && query.app.as_ref().is_none_or(|app| &session.app == app)
+            && query
+                .machine_id
+                .as_ref()
+                .is_none_or(|machine_id| &session.machine_id == machine_id)
+            && query
+                .subject
+                .as_ref()
+                .is_none_or(|subject| &session.subject == subject)
+    });
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.started_at));
+    sessions.truncate(query.limit.unwrap_or(100));
+    HttpResponse::Ok().json(sessions)
+}
+
+#[get("/v0/sessions/{id}")]
+async fn get_session(
+    req: HttpRequest,
+    path: web::Path<String>,
+    recorder: Option<web::Data<SessionRecorder>>,
+) -> impl Responder {
+    let Some(recorder) = recorder else {
+        return AppError::not_found("Session recording is not enabled").into_response();
+    };
+    let identity = match recorder.reviewer(&req) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    match recorder.store.get::<Session>(SESSIONS, &path).await {
+        Ok(Some(session)) if may_read(&req, &identity, &session) => {
+            HttpResponse::Ok().json(session)
+        }
+        Ok(_) => AppError::not_found(format!("No session {}", path)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_sessions).service(get_session);
+}
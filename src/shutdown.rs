@@ -0,0 +1,88 @@
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    /// No longer ready, and finishing requests in flight.
+    Draining,
+    /// Requests are done; background tasks are stopping.
+    Stopping,
+}
+
+static PHASE: LazyLock<watch::Sender<Phase>> = LazyLock::new(|| watch::channel(Phase::Running).0);
+static TASKS: Mutex<Vec<(&'static str, JoinHandle<()>)>> = Mutex::new(Vec::new());
+
+async fn reached(phase: Phase) {
+    let mut current = PHASE.subscribe();
+    let _ = current.wait_for(|current| *current >= phase).await;
+}
+
+pub fn is_draining() -> bool {
+    *PHASE.borrow() >= Phase::Draining
+}
+
+/// Resolves once flyd starts shutting down, for streams that would otherwise hold a
+/// connection open for the whole grace period.
+pub async fn draining() {
+    reached(Phase::Draining).await;
+}
+
+/// Spawns a background task that's stopped, at its next await, once requests in flight
+/// have drained.
+pub fn spawn(name: &'static str, task: impl Future<Output = ()> + 'static) {
+    let handle = actix_web::rt::spawn(async move {
+        tokio::select! {
+            _ = task => {}
+            _ = reached(Phase::Stopping) => log::debug!("Stopped {}", name),
+        }
+    });
+    let mut tasks = TASKS.lock().unwrap();
+    tasks.retain(|(_, handle)| !handle.is_finished());
+    tasks.push((name, handle));
+}
+
+/// Waits for SIGTERM or SIGINT, then stops answering `/ready`, gives load balancers
+/// `delay` to notice, and stops the server: it accepts no new connections and gives
+/// requests in flight until its shutdown timeout to finish.
+pub async fn on_signal(server: ServerHandle, delay: Duration) {
+    let (Ok(mut terminate), Ok(mut interrupt)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        log::error!("Failed to listen for SIGTERM and SIGINT; flyd will stop without draining");
+        return;
+    };
+    let received = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+    log::info!("Received {}, draining requests in flight", received);
+    PHASE.send_replace(Phase::Draining);
+    tokio::time::sleep(delay).await;
+    server.stop(true).await;
+}
+
+/// Stops the background tasks, waiting up to `grace` for them.
+pub async fn stop_tasks(grace: Duration) {
+    PHASE.send_replace(Phase::Stopping);
+    let tasks = std::mem::take(&mut *TASKS.lock().unwrap());
+    let stopped = tokio::time::timeout(grace, async {
+        for (_, handle) in tasks {
+            let _ = handle.await;
+        }
+    })
+    .await;
+    if stopped.is_err() {
+        log::warn!(
+            "Background tasks didn't stop within {}s; exiting anyway",
+            grace.as_secs()
+        );
+    }
+}
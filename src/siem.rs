@@ -0,0 +1,371 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use chrono::Utc;
+use flyd::models::Event;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::config::{SiemConfig, SiemFormat};
+use crate::log_sinks::{self, Transport};
+use crate::version::VERSION;
+use crate::{metrics, notify};
+
+const BATCH_SIZE: usize = 500;
+const DELIVERY_TICK: Duration = Duration::from_secs(1);
+const MAX_BACKOFF_SECS: u64 = 300;
+
+/// CEF severity, 0 to 10.
+fn severity(kind: &str) -> u8 {
+    if kind.ends_with(".destroyed") || kind.ends_with(".deleted") {
+        7
+    } else if ["denied", "refused", "failed", "issue"]
+        .iter()
+        .any(|word| kind.contains(word))
+    {
+        5
+    } else {
+        3
+    }
+}
+
+fn syslog_severity(severity: u8) -> u8 {
+    match severity {
+        7.. => 4,
+        5..=6 => 5,
+        _ => 6,
+    }
+}
+
+fn cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn cef(event: &Event) -> String {
+    let mut extension = vec![
+        ("rt", event.at.timestamp_millis().to_string()),
+        ("act", event.kind.clone()),
+        ("externalId", event.id.to_string()),
+    ];
+    if let Some(app) = &event.app {
+        extension.push(("cs1Label", "app".to_string()));
+        extension.push(("cs1", app.clone()));
+    }
+    if let Some(machine_id) = &event.machine_id {
+        extension.push(("cs2Label", "machine".to_string()));
+        extension.push(("cs2", machine_id.clone()));
+    }
+    if let Some(by) = event.detail["by"].as_str() {
+        extension.push(("suser", by.to_string()));
+    }
+    extension.push(("msg", event.detail.to_string()));
+    format!(
+        "CEF:0|flyd|flyd|{}|{}|{}|{}|{}",
+        cef_header(VERSION),
+        cef_header(&event.kind),
+        cef_header(&event.kind),
+        severity(&event.kind),
+        extension
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, cef_value(value)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    )
+}
+
+fn ecs(event: &Event, hostname: &str) -> String {
+    let mut document = json!({
+        "@timestamp": event.at,
+        "message": format!("{} {}", event.kind, event.detail),
+        "event": {
+            "id": event.id.to_string(),
+            "kind": "event",
+            "action": event.kind,
+            "dataset": "flyd.audit",
+            "module": "flyd",
+            "severity": severity(&event.kind),
+        },
+        "service": { "name": "flyd", "version": VERSION },
+        "host": { "hostname": hostname },
+        "labels": { "app": event.app, "machine_id": event.machine_id },
+        "flyd": { "detail": event.detail },
+    });
+    if let Some(by) = event.detail["by"].as_str() {
+        document["user"] = json!({ "name": by });
+    }
+    document.to_string()
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    attempts: u32,
+    retry_at: Option<Instant>,
+    delivered: u64,
+    failures: u64,
+    dropped: u64,
+}
+
+struct Exporter {
+    config: SiemConfig,
+    transport: Transport,
+    queue: Mutex<Queue>,
+}
+
+impl Exporter {
+    fn encode(&self, event: &Event, hostname: &str) -> String {
+        let line = match self.config.format {
+            SiemFormat::Cef => cef(event),
+            SiemFormat::Ecs => ecs(event, hostname),
+        };
+        match self.transport {
+            Transport::Http => line,
+            Transport::Udp(_) | Transport::Tcp(_) => log_sinks::syslog_frame(
+                syslog_severity(severity(&event.kind)),
+                event.at,
+                hostname,
+                &line,
+            ),
+        }
+    }
+
+    async fn send(&self, lines: &[String], http: &reqwest::Client) -> Result<(), String> {
+        if !matches!(self.transport, Transport::Http) {
+            return log_sinks::send_syslog(&self.transport, lines)
+                .await
+                .map_err(|e| e.to_string());
+        }
+        let content_type = match self.config.format {
+            SiemFormat::Cef => "text/plain",
+            SiemFormat::Ecs => "application/x-ndjson",
+        };
+        let mut request = http
+            .post(&self.config.url)
+            .header("content-type", content_type)
+            .timeout(Duration::from_secs(30));
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+        request
+            .body(lines.join("\n"))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map(drop)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Exports recorded events to each `[[siem]]` as CEF or ECS, in order and at least once.
+pub struct SiemExporters {
+    exporters: Vec<Exporter>,
+    http: reqwest::Client,
+    hostname: String,
+}
+
+impl SiemExporters {
+    pub fn from_config(
+        configs: &[SiemConfig],
+        http: reqwest::Client,
+    ) -> Result<Option<Self>, String> {
+        if configs.is_empty() {
+            return Ok(None);
+        }
+        let exporters = configs
+            .iter()
+            .map(|config| {
+                let transport =
+                    if config.url.starts_with("http://") || config.url.starts_with("https://") {
+                        Transport::Http
+                    } else {
+                        Transport::syslog(&config.url).ok_or_else(|| {
+                            format!(
+                                "SIEM {} must be http(s)://, udp://host:port or tcp://host:port",
+                                config.url
+                            )
+                        })?
+                    };
+                Ok(Exporter {
+                    config: config.clone(),
+                    transport,
+                    queue: Mutex::new(Queue::default()),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Some(SiemExporters {
+            exporters,
+            http,
+            hostname: std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string()),
+        }))
+    }
+
+    fn enqueue(&self, event: &Event) {
+        for exporter in &self.exporters {
+            if !notify::matches(&exporter.config.events, &event.kind) {
+                continue;
+            }
+            let mut queue = exporter.queue.lock().unwrap();
+            if queue.events.len() >= exporter.config.max_queued.max(1) {
+                queue.events.pop_front();
+                queue.dropped += 1;
+            }
+            queue.events.push_back(event.clone());
+        }
+    }
+
+    /// Sends the oldest undelivered events, unless the exporter is backing off.
+    async fn deliver(&self, exporter: &Exporter) {
+        let batch: Vec<Event> = {
+            let queue = exporter.queue.lock().unwrap();
+            if queue.retry_at.is_some_and(|at| Instant::now() < at) {
+                return;
+            }
+            queue.events.iter().take(BATCH_SIZE).cloned().collect()
+        };
+        if batch.is_empty() {
+            return;
+        }
+        let lines: Vec<String> = batch
+            .iter()
+            .map(|event| exporter.encode(event, &self.hostname))
+            .collect();
+        let result = exporter.send(&lines, &self.http).await;
+
+        let mut queue = exporter.queue.lock().unwrap();
+        match result {
+            Ok(()) => {
+                // Not just `batch.len()`: some of these may have been dropped for room
+                // while they were being sent.
+                let last = batch[batch.len() - 1].id;
+                while queue.events.front().is_some_and(|event| event.id <= last) {
+                    queue.events.pop_front();
+                }
+                queue.delivered += batch.len() as u64;
+                queue.attempts = 0;
+                queue.retry_at = None;
+            }
+            Err(e) => {
+                queue.attempts += 1;
+                queue.failures += 1;
+                let backoff = (exporter.config.retry_base_secs.max(1)
+                    << (queue.attempts - 1).min(16))
+                .min(MAX_BACKOFF_SECS);
+                queue.retry_at = Some(Instant::now() + Duration::from_secs(backoff));
+                log::warn!(
+                    "Failed to export {} events to {}, retrying in {}s: {}",
+                    batch.len(),
+                    exporter.config.url,
+                    backoff,
+                    e
+                );
+            }
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        let now = Utc::now();
+        let samples: Vec<(String, f64, f64, u64, u64, u64)> = self
+            .exporters
+            .iter()
+            .map(|exporter| {
+                let queue = exporter.queue.lock().unwrap();
+                let lag = queue.events.front().map_or(0.0, |event| {
+                    (now - event.at).num_milliseconds().max(0) as f64 / 1000.0
+                });
+                (
+                    exporter.config.url.clone(),
+                    lag,
+                    queue.events.len() as f64,
+                    queue.delivered,
+                    queue.failures,
+                    queue.dropped,
+                )
+            })
+            .collect();
+        metrics::header(
+            out,
+            "flyd_siem_lag_seconds",
+            "gauge",
+            "Age of the oldest event not yet exported to the SIEM.",
+        );
+        for (url, lag, ..) in &samples {
+            metrics::sample(
+                out,
+                "flyd_siem_lag_seconds",
+                &[("exporter", url.clone())],
+                *lag,
+            );
+        }
+        metrics::header(
+            out,
+            "flyd_siem_queued_events",
+            "gauge",
+            "Events waiting to be exported to the SIEM.",
+        );
+        for (url, _, queued, ..) in &samples {
+            metrics::sample(
+                out,
+                "flyd_siem_queued_events",
+                &[("exporter", url.clone())],
+                *queued,
+            );
+        }
+        for (name, help, index) in [
+            (
+                "flyd_siem_delivered_total",
+                "Events exported to the SIEM.",
+                0,
+            ),
+            (
+                "flyd_siem_failures_total",
+                "Failed deliveries to the SIEM.",
+                1,
+            ),
+            (
+                "flyd_siem_dropped_total",
+                "Events dropped because the SIEM's queue was full.",
+                2,
+            ),
+        ] {
+            metrics::header(out, name, "counter", help);
+            for (url, _, _, delivered, failures, dropped) in &samples {
+                let value = [delivered, failures, dropped][index];
+                metrics::sample(out, name, &[("exporter", url.clone())], *value as f64);
+            }
+        }
+    }
+}
+
+/// Queues every recorded event for the exporters that want it; each exporter delivers
+/// its queue on its own, so a slow SIEM holds up no other.
+pub async fn run(siem: web::Data<SiemExporters>, mut events: broadcast::Receiver<Event>) {
+    for index in 0..siem.exporters.len() {
+        let siem = siem.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticker = tokio::time::interval(DELIVERY_TICK);
+            loop {
+                ticker.tick().await;
+                siem.deliver(&siem.exporters[index]).await;
+            }
+        });
+    }
+    loop {
+        match events.recv().await {
+            Ok(event) => siem.enqueue(&event),
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("SIEM export fell behind, skipped {} events", skipped);
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use serde_json::{Value, json};
+
+use crate::config::{Config, ImageSignaturesConfig};
+use crate::registry::{ImageRef, MANIFEST_TYPES, RegistryClient, sha256_digest};
+
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// Refuses images without a cosign signature from one of the configured keys, as
+/// `cosign sign --key` stores it: a `sha256-<digest>.sig` manifest next to the image.
+pub struct ImageVerifier {
+    config: ImageSignaturesConfig,
+    keys: Vec<VerifyingKey>,
+    registry: RegistryClient,
+    /// Digests images were last verified at, by image as given.
+    verified: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl ImageVerifier {
+    pub fn from_config(config: &Config, http: reqwest::Client) -> Result<Option<Self>, String> {
+        let Some(signatures) = &config.image_signatures else {
+            return Ok(None);
+        };
+        if signatures.keys.is_empty() {
+            return Err("image_signatures needs at least one key".to_string());
+        }
+        let mut keys = Vec::new();
+        for key in &signatures.keys {
+            let pem = if key.trim_start().starts_with("-----BEGIN") {
+                key.clone()
+            } else {
+                std::fs::read_to_string(key)
+                    .map_err(|e| format!("Failed to read image signing key {}: {}", key, e))?
+            };
+            keys.push(
+                VerifyingKey::from_public_key_pem(&pem)
+                    .map_err(|e| format!("Invalid image signing key {}: {}", key, e))?,
+            );
+        }
+        Ok(Some(ImageVerifier {
+            config: signatures.clone(),
+            keys,
+            registry: RegistryClient::new(http, config.registries.clone()),
+            verified: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// The digest of the image manifest a trusted key signed.
+    async fn verify(&self, image: &str) -> Result<String, String> {
+        let ttl = Duration::from_secs(self.config.cache_ttl_secs);
+        if let Some((digest, at)) = self.verified.lock().unwrap().get(image)
+            && at.elapsed() < ttl
+        {
+            return Ok(digest.clone());
+        }
+
+        let parsed = ImageRef::parse(image);
+        let mut registry = self.registry.session(&parsed);
+        let digest = registry.manifest_digest().await?;
+        let path = format!("manifests/{}.sig", digest.replacen(':', "-", 1));
+        let Some(response) = registry.get(&path, MANIFEST_TYPES).await? else {
+            return Err(format!("{} is not signed", image));
+        };
+        let manifest: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to read the signature of {}: {}", image, e))?;
+
+        for layer in manifest["layers"].as_array().into_iter().flatten() {
+            let (Some(signature), Some(layer_digest)) = (
+                layer["annotations"][SIGNATURE_ANNOTATION].as_str(),
+                layer["digest"].as_str(),
+            ) else {
+                continue;
+            };
+            let Ok(signature) = STANDARD
+                .decode(signature)
+                .map_err(|e| e.to_string())
+                .and_then(|der| Signature::from_der(&der).map_err(|e| e.to_string()))
+            else {
+                continue;
+            };
+            let Some(response) = registry
+                .get(&format!("blobs/{}", layer_digest), "*/*")
+                .await?
+            else {
+                continue;
+            };
+            let payload = response.bytes().await.map_err(|e| e.to_string())?;
+            if sha256_digest(&payload) != layer_digest {
+                continue;
+            }
+            // The payload names the manifest it vouches for; a signature copied from
+            // another image won't name this one.
+            let signed: Value = serde_json::from_slice(&payload).unwrap_or_default();
+            if signed["critical"]["image"]["docker-manifest-digest"] != digest.as_str() {
+                continue;
+            }
+            if self
+                .keys
+                .iter()
+                .any(|key| key.verify(&payload, &signature).is_ok())
+            {
+                self.verified
+                    .lock()
+                    .unwrap()
+                    .insert(image.to_string(), (digest.clone(), Instant::now()));
+                return Ok(digest);
+            }
+        }
+        Err(format!("{} has no signature from a trusted key", image))
+    }
+
+    /// Verifies the image of a machine config for `app`, pinning it to the verified
+    /// digest if `pin_digest` is on. Configs without an image pass.
+    pub async fn check(&self, app: &str, config: &mut Value) -> Result<(), String> {
+        if !self.config.apps.is_empty() && !self.config.apps.iter().any(|name| name == app) {
+            return Ok(());
+        }
+        let Some(image) = config["image"].as_str().map(str::to_string) else {
+            return Ok(());
+        };
+        let digest = self.verify(&image).await?;
+        if self.config.pin_digest {
+            config["image"] = json!(format!("{}@{}", ImageRef::parse(&image).name, digest));
+        }
+        Ok(())
+    }
+}
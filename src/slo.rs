@@ -0,0 +1,326 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpResponse, Responder, get, web};
+use chrono::Utc;
+use flyd::models::SloStatus;
+
+use crate::config::SloConfig;
+use crate::events::EventLog;
+use crate::metrics;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Upstream,
+    Flyd,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Upstream => "upstream",
+            Scope::Flyd => "flyd",
+        }
+    }
+}
+
+struct Bucket {
+    minute: i64,
+    total: u64,
+    bad: u64,
+}
+
+#[derive(Default)]
+struct Series {
+    buckets: VecDeque<Bucket>,
+    alerting: bool,
+}
+
+impl Series {
+    fn totals(&self, since_minute: i64) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .filter(|bucket| bucket.minute > since_minute)
+            .fold((0, 0), |(total, bad), bucket| {
+                (total + bucket.total, bad + bucket.bad)
+            })
+    }
+}
+
+/// Collapses ids in a Machines API path so each route is tracked as one endpoint,
+/// e.g. `/v1/apps/my-app/machines/abc/lease` becomes `/v1/apps/{app}/machines/{id}/lease`.
+pub fn upstream_endpoint(method: &reqwest::Method, path: &str) -> String {
+    let mut normalized = Vec::new();
+    let mut previous = "";
+    for segment in path.split('/') {
+        normalized.push(match previous {
+            "apps" => "{app}",
+            "machines" | "volumes" | "snapshots" => "{id}",
+            "secrets" => "{name}",
+            "metadata" => "{key}",
+            _ => segment,
+        });
+        previous = segment;
+    }
+    format!("{} {}", method, normalized.join("/"))
+}
+
+fn burn_rate(total: u64, bad: u64, objective: f64) -> f64 {
+    let budget = 1.0 - objective;
+    if total == 0 || budget <= 0.0 {
+        return 0.0;
+    }
+    (bad as f64 / total as f64) / budget
+}
+
+/// Per-endpoint good/bad request counts in one-minute buckets, for error-budget burn rates.
+pub struct SloTracker {
+    config: SloConfig,
+    series: Mutex<HashMap<(Scope, String), Series>>,
+}
+
+impl SloTracker {
+    pub fn new(config: SloConfig) -> Self {
+        SloTracker {
+            config,
+            series: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn target(&self, endpoint: &str) -> (f64, u64) {
+        let matching = self
+            .config
+            .overrides
+            .iter()
+            .find(|target| target.endpoint == endpoint);
+        (
+            matching
+                .and_then(|target| target.objective)
+                .unwrap_or(self.config.objective),
+            matching
+                .and_then(|target| target.latency_ms)
+                .unwrap_or(self.config.latency_ms),
+        )
+    }
+
+    pub fn record(&self, scope: Scope, endpoint: String, elapsed: Duration, failed: bool) {
+        let (_, latency_ms) = self.target(&endpoint);
+        let bad = failed || elapsed > Duration::from_millis(latency_ms);
+
+        let minute = Utc::now().timestamp() / 60;
+        let oldest = minute - (self.config.long_window_secs / 60) as i64;
+
+        let mut series = self.series.lock().unwrap();
+        let series = series.entry((scope, endpoint)).or_default();
+        match series.buckets.back_mut() {
+            Some(bucket) if bucket.minute == minute => {
+                bucket.total += 1;
+                bucket.bad += bad as u64;
+            }
+            _ => series.buckets.push_back(Bucket {
+                minute,
+                total: 1,
+                bad: bad as u64,
+            }),
+        }
+        while series
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.minute <= oldest)
+        {
+            series.buckets.pop_front();
+        }
+    }
+
+    fn status_of(&self, scope: Scope, endpoint: &str, series: &Series) -> SloStatus {
+        let (objective, latency_ms) = self.target(endpoint);
+        let minute = Utc::now().timestamp() / 60;
+        let (requests, bad) = series.totals(minute - (self.config.long_window_secs / 60) as i64);
+        let (short_requests, short_bad) =
+            series.totals(minute - (self.config.short_window_secs / 60).max(1) as i64);
+
+        SloStatus {
+            scope: scope.as_str().to_string(),
+            endpoint: endpoint.to_string(),
+            objective,
+            latency_ms,
+            requests,
+            bad,
+            short_burn_rate: burn_rate(short_requests, short_bad, objective),
+            long_burn_rate: burn_rate(requests, bad, objective),
+            alerting: series.alerting,
+        }
+    }
+
+    pub fn status(&self) -> Vec<SloStatus> {
+        let series = self.series.lock().unwrap();
+        let mut statuses: Vec<SloStatus> = series
+            .iter()
+            .map(|((scope, endpoint), series)| self.status_of(*scope, endpoint, series))
+            .collect();
+        statuses.sort_by(|a, b| (&a.scope, &a.endpoint).cmp(&(&b.scope, &b.endpoint)));
+        statuses
+    }
+
+    /// Updates alert state and returns the endpoints that started or stopped alerting.
+    fn evaluate(&self) -> Vec<SloStatus> {
+        let short_minutes = (self.config.short_window_secs / 60).max(1) as i64;
+        let minute = Utc::now().timestamp() / 60;
+
+        let mut series = self.series.lock().unwrap();
+        let mut changed = Vec::new();
+        for ((scope, endpoint), series) in series.iter_mut() {
+            let mut status = self.status_of(*scope, endpoint, series);
+            let (short_requests, _) = series.totals(minute - short_minutes);
+            let alerting = short_requests >= self.config.min_requests
+                && status.short_burn_rate >= self.config.burn_rate_threshold
+                && status.long_burn_rate >= self.config.burn_rate_threshold;
+            if alerting != series.alerting {
+                series.alerting = alerting;
+                status.alerting = alerting;
+                changed.push(status);
+            }
+        }
+        changed
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        let statuses = self.status();
+        let labelled = |status: &SloStatus| {
+            vec![
+                ("scope", status.scope.clone()),
+                ("endpoint", status.endpoint.clone()),
+            ]
+        };
+
+        metrics::header(
+            out,
+            "flyd_slo_requests",
+            "gauge",
+            "Requests in the long SLO window.",
+        );
+        for status in &statuses {
+            metrics::sample(
+                out,
+                "flyd_slo_requests",
+                &labelled(status),
+                status.requests as f64,
+            );
+        }
+        metrics::header(
+            out,
+            "flyd_slo_bad_requests",
+            "gauge",
+            "Failed or slow requests in the long SLO window.",
+        );
+        for status in &statuses {
+            metrics::sample(
+                out,
+                "flyd_slo_bad_requests",
+                &labelled(status),
+                status.bad as f64,
+            );
+        }
+        metrics::header(
+            out,
+            "flyd_slo_burn_rate",
+            "gauge",
+            "Error budget burn rate; 1 spends the budget exactly over the SLO period.",
+        );
+        for status in &statuses {
+            for (window, rate) in [
+                ("short", status.short_burn_rate),
+                ("long", status.long_burn_rate),
+            ] {
+                let mut labels = labelled(status);
+                labels.push(("window", window.to_string()));
+                metrics::sample(out, "flyd_slo_burn_rate", &labels, rate);
+            }
+        }
+        metrics::header(
+            out,
+            "flyd_slo_alerting",
+            "gauge",
+            "1 while the endpoint is burning its error budget too fast.",
+        );
+        for status in &statuses {
+            metrics::sample(
+                out,
+                "flyd_slo_alerting",
+                &labelled(status),
+                status.alerting as u8 as f64,
+            );
+        }
+    }
+}
+
+pub async fn alert_loop(slo: web::Data<SloTracker>, events: web::Data<EventLog>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(
+        slo.config.evaluate_interval_secs.max(1),
+    ));
+    loop {
+        ticker.tick().await;
+
+        for status in slo.evaluate() {
+            let kind = if status.alerting {
+                log::warn!(
+                    "SLO burn rate alert for {} {}: {:.1}x short, {:.1}x long",
+                    status.scope,
+                    status.endpoint,
+                    status.short_burn_rate,
+                    status.long_burn_rate
+                );
+                "slo.burn_rate"
+            } else {
+                "slo.recovered"
+            };
+            events.record(
+                kind,
+                None,
+                None,
+                serde_json::to_value(&status).unwrap_or_default(),
+            );
+        }
+    }
+}
+
+/// Records latency and 5xx responses for flyd's own routes.
+pub async fn track<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let slo = req.app_data::<web::Data<SloTracker>>().cloned();
+    let endpoint = req
+        .match_pattern()
+        .map(|pattern| format!("{} {}", req.method(), pattern));
+    let started = Instant::now();
+
+    let result = next.call(req).await;
+
+    if let (Some(slo), Some(endpoint)) = (slo, endpoint) {
+        let status = match &result {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        slo.record(
+            Scope::Flyd,
+            endpoint,
+            started.elapsed(),
+            status.is_server_error(),
+        );
+    }
+    result
+}
+
+#[get("/v0/slo")]
+async fn slo_status(slo: web::Data<SloTracker>) -> impl Responder {
+    HttpResponse::Ok().json(slo.status())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(slo_status);
+}
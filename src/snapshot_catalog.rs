@@ -0,0 +1,324 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::{
+    CatalogedSnapshot, ListVolumesRequest, SnapshotCatalogQuery, SnapshotViolation, VolumeSnapshots,
+};
+use serde_json::{Value, json};
+
+use crate::config::{Config, SnapshotCatalogConfig};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+/// One record per volume, keyed `{app}/{volume_id}`.
+const CATALOG: &str = "snapshot_catalog";
+
+fn timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    value
+        .as_str()
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        .map(|at| at.with_timezone(&Utc))
+}
+
+/// Whether Fly was due to delete the snapshot by `now` anyway.
+fn expired(snapshot: &CatalogedSnapshot, retention_days: Option<u64>, now: DateTime<Utc>) -> bool {
+    match (snapshot.created_at, retention_days) {
+        (Some(created_at), Some(days)) => created_at + TimeDelta::days(days as i64) <= now,
+        _ => false,
+    }
+}
+
+fn violations(config: &SnapshotCatalogConfig, record: &VolumeSnapshots) -> Vec<SnapshotViolation> {
+    let mut violations: Vec<SnapshotViolation> = record
+        .snapshots
+        .iter()
+        .filter(|snapshot| snapshot.missing_since.is_some())
+        .map(|snapshot| SnapshotViolation::Missing {
+            snapshot_id: snapshot.id.clone(),
+        })
+        .collect();
+    // A destroyed volume takes no more snapshots; only losing the ones it has matters.
+    if record.destroyed {
+        return violations;
+    }
+
+    if record.schedule.is_none() {
+        violations.push(SnapshotViolation::BackupsDisabled);
+    }
+    let present: Vec<&CatalogedSnapshot> = record
+        .snapshots
+        .iter()
+        .filter(|snapshot| snapshot.missing_since.is_none())
+        .collect();
+    let latest_at = present
+        .iter()
+        .filter_map(|snapshot| snapshot.created_at)
+        .max();
+    let max_age = TimeDelta::hours(config.max_age_hours as i64);
+    if latest_at.is_none_or(|at| record.verified_at - at > max_age) {
+        violations.push(SnapshotViolation::Stale {
+            latest_at,
+            max_age_hours: config.max_age_hours,
+        });
+    }
+    if present.len() < config.min_snapshots {
+        violations.push(SnapshotViolation::TooFew {
+            count: present.len(),
+            min: config.min_snapshots,
+        });
+    }
+    if let Some(min) = config.min_retention_days
+        && let Some(retention_days) = record.retention_days
+        && retention_days < min
+    {
+        violations.push(SnapshotViolation::RetentionTooShort {
+            retention_days,
+            min,
+        });
+    }
+    violations
+}
+
+/// Brings the record of one volume up to date with the snapshots listed upstream. Known
+/// snapshots no longer listed are dropped once their retention has run out, and marked
+/// missing until then.
+fn update(
+    previous: Option<VolumeSnapshots>,
+    app: &str,
+    volume_id: &str,
+    volume: Option<&Value>,
+    listed: &[Value],
+    now: DateTime<Utc>,
+) -> VolumeSnapshots {
+    let mut record = previous.unwrap_or_else(|| VolumeSnapshots {
+        app: app.to_string(),
+        volume_id: volume_id.to_string(),
+        volume_name: None,
+        region: None,
+        schedule: None,
+        retention_days: None,
+        destroyed: false,
+        snapshots: Vec::new(),
+        violations: Vec::new(),
+        verified_at: now,
+    });
+    record.verified_at = now;
+    record.destroyed = volume.is_none();
+    if let Some(volume) = volume {
+        record.volume_name = volume["name"].as_str().map(str::to_string);
+        record.region = volume["region"].as_str().map(str::to_string);
+        record.schedule = (volume["auto_backup_enabled"] != false).then(|| "daily".to_string());
+        record.retention_days = volume["snapshot_retention"].as_u64();
+    }
+
+    for snapshot in listed {
+        let Some(id) = snapshot["id"].as_str() else {
+            continue;
+        };
+        let (size, status, created_at) = (
+            snapshot["size"].as_u64(),
+            snapshot["status"].as_str().map(str::to_string),
+            timestamp(&snapshot["created_at"]),
+        );
+        match record.snapshots.iter_mut().find(|known| known.id == id) {
+            Some(known) => {
+                known.size = size.or(known.size);
+                known.status = status;
+                known.created_at = created_at.or(known.created_at);
+                known.verified_at = now;
+                known.missing_since = None;
+            }
+            None => record.snapshots.push(CatalogedSnapshot {
+                id: id.to_string(),
+                size,
+                status,
+                created_at,
+                first_seen_at: now,
+                verified_at: now,
+                missing_since: None,
+            }),
+        }
+    }
+
+    let retention_days = record.retention_days;
+    record.snapshots.retain_mut(|snapshot| {
+        if snapshot.verified_at == now {
+            return true;
+        }
+        if expired(snapshot, retention_days, now) {
+            return false;
+        }
+        snapshot.missing_since.get_or_insert(now);
+        true
+    });
+    record.snapshots.sort_by_key(|snapshot| snapshot.created_at);
+    record
+}
+
+/// Re-lists `app`'s volumes and their snapshots, updating their records and alerting on
+/// violations that weren't there last time.
+pub async fn verify(
+    client: &FlyClient,
+    store: &Store,
+    events: &EventLog,
+    config: &SnapshotCatalogConfig,
+    app: &str,
+) -> Result<Vec<VolumeSnapshots>, String> {
+    let volumes: Vec<Value> = client
+        .list_volumes(app)
+        .await
+        .map_err(|e| format!("Failed to list volumes: {}", e))?
+        .into_iter()
+        .filter(|volume| {
+            !matches!(
+                volume["state"].as_str(),
+                Some("destroyed" | "destroying" | "pending_destroy")
+            )
+        })
+        .collect();
+    let records: Vec<VolumeSnapshots> = store
+        .list::<VolumeSnapshots>(CATALOG)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|record| record.app == app)
+        .collect();
+    let ids: BTreeSet<&str> = volumes
+        .iter()
+        .filter_map(|volume| volume["id"].as_str())
+        .chain(records.iter().map(|record| record.volume_id.as_str()))
+        .collect();
+
+    let mut verified = Vec::new();
+    for id in ids {
+        let volume = volumes.iter().find(|volume| volume["id"] == id);
+        let previous = records
+            .iter()
+            .find(|record| record.volume_id == id)
+            .cloned();
+        let listed = match client.list_snapshots(app, id).await {
+            Ok(listed) => listed,
+            // A destroyed volume's snapshots may no longer be listable; its record stands.
+            Err(e) if volume.is_none() => {
+                log::debug!("Snapshot catalog: {}: volume {}: {}", app, id, e);
+                verified.extend(previous);
+                continue;
+            }
+            Err(e) => return Err(format!("Failed to list snapshots of {}: {}", id, e)),
+        };
+
+        let before = previous
+            .as_ref()
+            .map(|record| record.violations.clone())
+            .unwrap_or_default();
+        let mut record = update(previous, app, id, volume, &listed, Utc::now());
+        record.violations = violations(config, &record);
+        for violation in record.violations.iter().filter(|v| !before.contains(v)) {
+            log::warn!("Snapshot catalog: {} volume {}: {:?}", app, id, violation);
+            events.record(
+                "snapshot.violation",
+                Some(app),
+                None,
+                json!({ "volume_id": id, "volume_name": record.volume_name, "violation": violation }),
+            );
+        }
+        if record.violations.is_empty() && !before.is_empty() {
+            events.record(
+                "snapshot.violations_resolved",
+                Some(app),
+                None,
+                json!({ "volume_id": id, "volume_name": record.volume_name }),
+            );
+        }
+
+        // Nothing left to watch once a destroyed volume's snapshots have all expired.
+        let key = format!("{}/{}", app, id);
+        let result = if record.destroyed && record.snapshots.is_empty() {
+            store.delete(CATALOG, &key).await.map(|_| ())
+        } else {
+            store.put(CATALOG, &key, &record).await
+        };
+        if let Err(e) = result {
+            log::error!("Failed to save the snapshot catalog of {}: {}", key, e);
+        }
+        verified.push(record);
+    }
+    Ok(verified)
+}
+
+pub async fn run(
+    config: SnapshotCatalogConfig,
+    client: FlyClient,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        for app in &config.apps {
+            if let Err(e) = verify(&client, &store, &events, &config, app).await {
+                log::error!("Snapshot catalog: {}: {}", app, e);
+            }
+        }
+    }
+}
+
+/// The cataloged volumes and their snapshots, as of their last verification.
+#[get("/v0/volumes/snapshots/catalog")]
+async fn get_catalog(
+    query: web::Query<SnapshotCatalogQuery>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    match store.list::<VolumeSnapshots>(CATALOG).await {
+        Ok(records) => {
+            let records: Vec<VolumeSnapshots> = records
+                .into_iter()
+                .filter(|record| query.app_name.as_ref().is_none_or(|app| &record.app == app))
+                .filter(|record| {
+                    query
+                        .volume_id
+                        .as_ref()
+                        .is_none_or(|id| &record.volume_id == id)
+                })
+                .filter(|record| !query.violations || !record.violations.is_empty())
+                .collect();
+            HttpResponse::Ok().json(records)
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+/// Verifies the app's snapshots now, with the caller's token, against the configured
+/// policy.
+#[post("/v0/volumes/snapshots/verify")]
+async fn verify_catalog(
+    req: HttpRequest,
+    body: web::Json<ListVolumesRequest>,
+    config: web::Data<Config>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    let policy = config.snapshot_catalog.clone().unwrap_or_default();
+    match verify(&client, &store, &events, &policy, &body.app_name).await {
+        Ok(records) => HttpResponse::Ok().json(records),
+        Err(e) => AppError::bad_gateway(e).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_catalog).service(verify_catalog);
+}
@@ -0,0 +1,255 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flyd::models::{InventorySnapshot, SnapshotInfo, SnapshotQuery};
+
+use crate::auth;
+use crate::backend::{Backend, resolve_apps};
+use crate::config::{Config, SnapshotFormat, SnapshotsConfig};
+use crate::errors::AppError;
+use crate::fleets;
+use crate::namespaces;
+use crate::object_store::ObjectStore;
+use crate::store::Store;
+
+/// Snapshot names are their UTC timestamp, so they sort oldest first.
+const NAME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+fn extension(format: SnapshotFormat) -> &'static str {
+    match format {
+        SnapshotFormat::Json => "json.gz",
+        SnapshotFormat::Yaml => "yaml.gz",
+    }
+}
+
+fn app_prefix(config: &SnapshotsConfig, app: &str) -> String {
+    format!("{}{}/", config.prefix, app)
+}
+
+fn taken_at(name: &str) -> Option<DateTime<Utc>> {
+    let stem = name.split('.').next()?;
+    NaiveDateTime::parse_from_str(stem, NAME_FORMAT)
+        .ok()
+        .map(|at| at.and_utc())
+}
+
+async fn take<B: Backend>(
+    backend: &B,
+    store: &Store,
+    app: &str,
+) -> Result<InventorySnapshot, String> {
+    let machines = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?;
+    let volumes = backend
+        .list_volumes(app)
+        .await
+        .map_err(|e| format!("Failed to list volumes: {}", e))?;
+    let fleet = fleets::fleet_spec(store, app)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(InventorySnapshot {
+        app: app.to_string(),
+        taken_at: Utc::now(),
+        machines,
+        volumes,
+        fleet,
+    })
+}
+
+fn encode(snapshot: &InventorySnapshot, format: SnapshotFormat) -> Result<Vec<u8>, String> {
+    let serialized = match format {
+        SnapshotFormat::Json => serde_json::to_vec_pretty(snapshot).map_err(|e| e.to_string())?,
+        SnapshotFormat::Yaml => serde_yaml_ng::to_string(snapshot)
+            .map_err(|e| e.to_string())?
+            .into_bytes(),
+    };
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&serialized)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| e.to_string())
+}
+
+/// Deletes the app's snapshots beyond `keep` and past `max_age_days`.
+async fn prune(objects: &ObjectStore, config: &SnapshotsConfig, app: &str) -> Result<(), String> {
+    let mut keys: Vec<String> = objects
+        .list(&app_prefix(config, app))
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|object| object.key)
+        .collect();
+    keys.sort();
+    keys.reverse();
+
+    let cutoff = config
+        .max_age_days
+        .map(|days| Utc::now() - Duration::from_secs(days * 86400));
+    for (position, key) in keys.iter().enumerate() {
+        let name = key.rsplit('/').next().unwrap_or(key);
+        let expired = cutoff.is_some_and(|cutoff| taken_at(name).is_some_and(|at| at < cutoff));
+        if (config.keep > 0 && position >= config.keep) || expired {
+            objects.delete(key).await.map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn snapshot_app<B: Backend>(
+    backend: &B,
+    store: &Store,
+    objects: &ObjectStore,
+    config: &SnapshotsConfig,
+    app: &str,
+) -> Result<String, String> {
+    let snapshot = take(backend, store, app).await?;
+    let key = format!(
+        "{}{}.{}",
+        app_prefix(config, app),
+        snapshot.taken_at.format(NAME_FORMAT),
+        extension(config.format)
+    );
+    let body = encode(&snapshot, config.format)?;
+    objects
+        .put(&key, body, "application/gzip")
+        .await
+        .map_err(|e| e.to_string())?;
+    prune(objects, config, app).await?;
+    Ok(key)
+}
+
+pub async fn run<B: Backend>(
+    config: SnapshotsConfig,
+    backend: B,
+    store: web::Data<Store>,
+    objects: web::Data<ObjectStore>,
+) {
+    let mut ticker =
+        tokio::time::interval(Duration::from_secs(config.interval_hours.max(1) * 3600));
+    loop {
+        ticker.tick().await;
+
+        let apps = match resolve_apps(&backend, &config.apps, config.org_slug.as_deref()).await {
+            Ok(apps) => apps,
+            Err(e) => {
+                log::error!("Failed to list apps to snapshot: {}", e);
+                continue;
+            }
+        };
+        for app in apps {
+            match snapshot_app(&backend, &store, &objects, &config, &app).await {
+                Ok(key) => log::info!("Snapshotted {} to {}", app, key),
+                Err(e) => log::error!("Failed to snapshot {}: {}", app, e),
+            }
+        }
+    }
+}
+
+/// Whether the caller may read `app`'s snapshots: only if they may touch the app.
+fn may_read(req: &HttpRequest, app: &str) -> Result<(), HttpResponse> {
+    let identity = auth::require_role(req, None)?;
+    if app.contains('/') {
+        return Err(AppError::bad_request("Invalid app name").into_response());
+    }
+    if !identity.may_touch(app) || namespaces::of(req).is_some_and(|namespace| !namespace.owns(app))
+    {
+        return Err(
+            AppError::forbidden(format!("You can't read {}'s snapshots", app)).into_response(),
+        );
+    }
+    Ok(())
+}
+
+#[get("/v0/snapshots")]
+async fn list_snapshots(
+    req: HttpRequest,
+    query: web::Query<SnapshotQuery>,
+    config: web::Data<Config>,
+    objects: Option<web::Data<ObjectStore>>,
+) -> impl Responder {
+    let (Some(config), Some(objects)) = (&config.snapshots, objects) else {
+        return AppError::not_found("Snapshots are not configured").into_response();
+    };
+    if let Err(response) = may_read(&req, &query.app) {
+        return response;
+    }
+
+    match objects.list(&app_prefix(config, &query.app)).await {
+        Ok(listed) => {
+            let mut snapshots: Vec<SnapshotInfo> = listed
+                .into_iter()
+                .map(|object| {
+                    let name = object
+                        .key
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default()
+                        .to_string();
+                    SnapshotInfo {
+                        app: query.app.clone(),
+                        taken_at: taken_at(&name),
+                        name,
+                        size_bytes: object.size,
+                    }
+                })
+                .collect();
+            snapshots.sort_by(|a, b| b.name.cmp(&a.name));
+            HttpResponse::Ok().json(snapshots)
+        }
+        Err(e) => AppError::bad_gateway(e.to_string()).into_response(),
+    }
+}
+
+/// A snapshot, decompressed, for restoring from or auditing.
+#[get("/v0/snapshots/{app}/{name}")]
+async fn get_snapshot(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    config: web::Data<Config>,
+    objects: Option<web::Data<ObjectStore>>,
+) -> impl Responder {
+    let (Some(config), Some(objects)) = (&config.snapshots, objects) else {
+        return AppError::not_found("Snapshots are not configured").into_response();
+    };
+    let (app, name) = path.into_inner();
+    if let Err(response) = may_read(&req, &app) {
+        return response;
+    }
+    if name.contains('/') {
+        return AppError::bad_request("Invalid snapshot name").into_response();
+    }
+
+    let compressed = match objects
+        .get(&format!("{}{}", app_prefix(config, &app), name))
+        .await
+    {
+        Ok(Some(compressed)) => compressed,
+        Ok(None) => {
+            return AppError::not_found(format!("No snapshot {} for {}", name, app))
+                .into_response();
+        }
+        Err(e) => return AppError::bad_gateway(e.to_string()).into_response(),
+    };
+    let mut body = Vec::new();
+    if let Err(e) = GzDecoder::new(compressed.as_slice()).read_to_end(&mut body) {
+        return AppError::internal(format!("Corrupt snapshot: {}", e)).into_response();
+    }
+
+    let content_type = if name.ends_with(".yaml.gz") {
+        "application/yaml"
+    } else {
+        "application/json"
+    };
+    HttpResponse::Ok().content_type(content_type).body(body)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_snapshots).service(get_snapshot);
+}
@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqliteConnectOptions;
+
+use crate::config::{StoreBackend, StoreConfig};
+
+#[derive(Debug)]
+pub enum StoreError {
+    Serde(serde_json::Error),
+    Sqlite(sqlx::Error),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Serde(e) => write!(f, "Failed to (de)serialize record: {}", e),
+            StoreError::Sqlite(e) => write!(f, "Store database error: {}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Serde(e)
+    }
+}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+enum Backend {
+    Memory(RwLock<HashMap<String, BTreeMap<String, serde_json::Value>>>),
+    Sqlite(SqlitePool),
+}
+
+/// Keyed JSON records grouped into named collections, kept in memory or in SQLite. Either
+/// way a collection lists in key order.
+pub struct Store {
+    backend: Backend,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Store {
+            backend: Backend::Memory(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+impl Store {
+    /// Opens the configured store, bringing a SQLite database's schema up to date first.
+    pub async fn from_config(config: &StoreConfig) -> Result<Self, String> {
+        match config.backend {
+            StoreBackend::Memory => Ok(Store::default()),
+            StoreBackend::Sqlite => {
+                let options = SqliteConnectOptions::new()
+                    .filename(&config.path)
+                    .create_if_missing(true);
+                let pool = SqlitePool::connect_with(options)
+                    .await
+                    .map_err(|e| format!("Failed to open {}: {}", config.path, e))?;
+                sqlx::migrate!()
+                    .run(&pool)
+                    .await
+                    .map_err(|e| format!("Failed to migrate {}: {}", config.path, e))?;
+                Ok(Store {
+                    backend: Backend::Sqlite(pool),
+                })
+            }
+        }
+    }
+
+    /// Checks that the configured SQLite database's schema is one this build can bring up
+    /// to date, without changing it: every migration it has applied must be one of ours,
+    /// unchanged. A database that doesn't exist yet passes, as it'd be created.
+    pub async fn check_schema(config: &StoreConfig) -> Result<(), String> {
+        if config.backend == StoreBackend::Memory || !Path::new(&config.path).exists() {
+            return Ok(());
+        }
+        let options = SqliteConnectOptions::new()
+            .filename(&config.path)
+            .read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", config.path, e))?;
+        let tracked: Option<(String,)> = sqlx::query_as(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+        )
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", config.path, e))?;
+        if tracked.is_none() {
+            return Ok(());
+        }
+        let applied: Vec<(i64, Vec<u8>, bool)> =
+            sqlx::query_as("SELECT version, checksum, success FROM _sqlx_migrations")
+                .fetch_all(&pool)
+                .await
+                .map_err(|e| format!("Failed to read {}'s migrations: {}", config.path, e))?;
+        let migrator = sqlx::migrate!();
+        for (version, checksum, success) in applied {
+            let Some(known) = migrator
+                .iter()
+                .find(|m| m.version == version && !m.migration_type.is_down_migration())
+            else {
+                return Err(format!(
+                    "{} has migration {}, which this build doesn't know; is it from a newer flyd?",
+                    config.path, version
+                ));
+            };
+            if *known.checksum != checksum[..] {
+                return Err(format!(
+                    "{}'s migration {} ({}) differs from this build's",
+                    config.path, version, known.description
+                ));
+            }
+            if !success {
+                return Err(format!(
+                    "{}'s migration {} ({}) failed partway",
+                    config.path, version, known.description
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        collection: &str,
+        key: &str,
+    ) -> Result<Option<T>, StoreError> {
+        let value = match &self.backend {
+            Backend::Memory(collections) => collections
+                .read()
+                .unwrap()
+                .get(collection)
+                .and_then(|records| records.get(key))
+                .cloned(),
+            Backend::Sqlite(pool) => {
+                let value: Option<String> = sqlx::query_scalar(
+                    "SELECT value FROM records WHERE collection = ? AND key = ?",
+                )
+                .bind(collection)
+                .bind(key)
+                .fetch_optional(pool)
+                .await?;
+                value
+                    .map(|value| serde_json::from_str(&value))
+                    .transpose()?
+            }
+        };
+        match value {
+            Some(value) => Ok(Some(serde_json::from_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn put<T: Serialize>(
+        &self,
+        collection: &str,
+        key: &str,
+        value: &T,
+    ) -> Result<(), StoreError> {
+        let value = serde_json::to_value(value)?;
+        match &self.backend {
+            Backend::Memory(collections) => {
+                collections
+                    .write()
+                    .unwrap()
+                    .entry(collection.to_string())
+                    .or_default()
+                    .insert(key.to_string(), value);
+            }
+            Backend::Sqlite(pool) => {
+                sqlx::query(
+                    "INSERT INTO records (collection, key, value) VALUES (?, ?, ?) \
+                     ON CONFLICT (collection, key) DO UPDATE SET value = excluded.value",
+                )
+                .bind(collection)
+                .bind(key)
+                .bind(value.to_string())
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete(&self, collection: &str, key: &str) -> Result<bool, StoreError> {
+        match &self.backend {
+            Backend::Memory(collections) => Ok(collections
+                .write()
+                .unwrap()
+                .get_mut(collection)
+                .and_then(|records| records.remove(key))
+                .is_some()),
+            Backend::Sqlite(pool) => {
+                let result = sqlx::query("DELETE FROM records WHERE collection = ? AND key = ?")
+                    .bind(collection)
+                    .bind(key)
+                    .execute(pool)
+                    .await?;
+                Ok(result.rows_affected() > 0)
+            }
+        }
+    }
+
+    pub async fn list<T: DeserializeOwned>(&self, collection: &str) -> Result<Vec<T>, StoreError> {
+        let values: Vec<serde_json::Value> = match &self.backend {
+            Backend::Memory(collections) => collections
+                .read()
+                .unwrap()
+                .get(collection)
+                .map(|records| records.values().cloned().collect())
+                .unwrap_or_default(),
+            Backend::Sqlite(pool) => {
+                let values: Vec<String> = sqlx::query_scalar(
+                    "SELECT value FROM records WHERE collection = ? ORDER BY key",
+                )
+                .bind(collection)
+                .fetch_all(pool)
+                .await?;
+                values
+                    .iter()
+                    .map(|value| serde_json::from_str(value))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        values
+            .into_iter()
+            .map(|value| serde_json::from_value(value).map_err(StoreError::from))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A SQLite store config for a database file of this test's own.
+    fn sqlite(name: &str) -> StoreConfig {
+        let path = std::env::temp_dir().join(format!("flyd-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        StoreConfig {
+            backend: StoreBackend::Sqlite,
+            path: path.to_string_lossy().into_owned(),
+        }
+    }
+
+    async fn tamper(config: &StoreConfig, sql: &str) {
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&config.path))
+            .await
+            .unwrap();
+        sqlx::query(sql).execute(&pool).await.unwrap();
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn migrates_a_new_database_and_keeps_records_across_opens() {
+        let config = sqlite("migrates");
+        Store::check_schema(&config).await.unwrap();
+
+        let store = Store::from_config(&config).await.unwrap();
+        store.put("things", "a", &1).await.unwrap();
+        drop(store);
+        Store::check_schema(&config).await.unwrap();
+        let store = Store::from_config(&config).await.unwrap();
+        assert_eq!(store.get::<i32>("things", "a").await.unwrap(), Some(1));
+
+        std::fs::remove_file(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_migration_rolls_back_to_an_empty_database() {
+        let config = sqlite("rolls-back");
+        drop(Store::from_config(&config).await.unwrap());
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&config.path))
+            .await
+            .unwrap();
+        sqlx::migrate!().undo(&pool, 0).await.unwrap();
+        let tables: Vec<(String,)> = sqlx::query_as(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name != '_sqlx_migrations'",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert!(tables.is_empty(), "{:?}", tables);
+        pool.close().await;
+        Store::check_schema(&config).await.unwrap();
+
+        std::fs::remove_file(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_a_half_migrated_database() {
+        let config = sqlite("half-migrated");
+        drop(Store::from_config(&config).await.unwrap());
+        tamper(&config, "UPDATE _sqlx_migrations SET success = FALSE").await;
+
+        let error = Store::check_schema(&config).await.unwrap_err();
+        assert!(error.contains("failed partway"), "{}", error);
+        assert!(Store::from_config(&config).await.is_err());
+
+        std::fs::remove_file(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_a_database_from_a_newer_flyd() {
+        let config = sqlite("newer");
+        drop(Store::from_config(&config).await.unwrap());
+        tamper(
+            &config,
+            "INSERT INTO _sqlx_migrations \
+             (version, description, success, checksum, execution_time) \
+             VALUES (9999, 'future', TRUE, X'00', 0)",
+        )
+        .await;
+
+        let error = Store::check_schema(&config).await.unwrap_err();
+        assert!(error.contains("newer flyd"), "{}", error);
+        assert!(Store::from_config(&config).await.is_err());
+
+        std::fs::remove_file(&config.path).unwrap();
+    }
+}
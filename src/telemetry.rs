@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, web};
+
+use crate::metrics;
+
+/// Upper bounds, in seconds, of the latency histograms' buckets.
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations at or under each bucket's bound, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &[(&str, String)]) {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let mut labels = labels.to_vec();
+            labels.push(("le", bound.to_string()));
+            metrics::sample(out, &format!("{}_bucket", name), &labels, cumulative as f64);
+        }
+        let mut labels_inf = labels.to_vec();
+        labels_inf.push(("le", "+Inf".to_string()));
+        metrics::sample(
+            out,
+            &format!("{}_bucket", name),
+            &labels_inf,
+            self.count as f64,
+        );
+        metrics::sample(out, &format!("{}_sum", name), labels, self.sum);
+        metrics::sample(out, &format!("{}_count", name), labels, self.count as f64);
+    }
+}
+
+/// Counts and times every request flyd serves, by route pattern so ids don't each get a
+/// series of their own.
+#[derive(Default)]
+pub struct HttpMetrics {
+    /// By method, route and status.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// By method and route.
+    durations: Mutex<BTreeMap<(String, String), Histogram>>,
+    in_flight: AtomicI64,
+}
+
+impl HttpMetrics {
+    fn record(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default() += 1;
+        self.durations
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), route.to_string()))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        metrics::header(
+            out,
+            "flyd_http_requests_total",
+            "counter",
+            "Requests served, by route and status.",
+        );
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            metrics::sample(
+                out,
+                "flyd_http_requests_total",
+                &[
+                    ("method", method.clone()),
+                    ("route", route.clone()),
+                    ("status", status.to_string()),
+                ],
+                *count as f64,
+            );
+        }
+        metrics::header(
+            out,
+            "flyd_http_request_duration_seconds",
+            "histogram",
+            "Time to serve a request, by route.",
+        );
+        for ((method, route), histogram) in self.durations.lock().unwrap().iter() {
+            histogram.render(
+                out,
+                "flyd_http_request_duration_seconds",
+                &[("method", method.clone()), ("route", route.clone())],
+            );
+        }
+        metrics::header(
+            out,
+            "flyd_http_requests_in_flight",
+            "gauge",
+            "Requests being served right now.",
+        );
+        metrics::sample(
+            out,
+            "flyd_http_requests_in_flight",
+            &[],
+            self.in_flight.load(Ordering::Relaxed) as f64,
+        );
+    }
+}
+
+/// Counts a request in flight until it's answered, even if the client goes away first.
+struct InFlight<'a>(&'a AtomicI64);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn track<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let Some(metrics) = req.app_data::<web::Data<HttpMetrics>>().cloned() else {
+        return next.call(req).await;
+    };
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+    let _in_flight = InFlight(&metrics.in_flight);
+    let started = Instant::now();
+    let response = next.call(req).await;
+    let status = match &response {
+        Ok(response) => response.status().as_u16(),
+        Err(e) => e.as_response_error().status_code().as_u16(),
+    };
+    metrics.record(&method, &route, status, started.elapsed());
+    response
+}
+
+/// Times every Machines API call and counts what it answered, by endpoint.
+#[derive(Default)]
+pub struct UpstreamMetrics {
+    /// By endpoint and status, or `error` when there was no answer.
+    responses: Mutex<BTreeMap<(String, String), u64>>,
+    durations: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl UpstreamMetrics {
+    pub fn record(&self, endpoint: &str, status: Option<u16>, elapsed: Duration) {
+        let status = status.map_or_else(|| "error".to_string(), |status| status.to_string());
+        *self
+            .responses
+            .lock()
+            .unwrap()
+            .entry((endpoint.to_string(), status))
+            .or_default() += 1;
+        self.durations
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        metrics::header(
+            out,
+            "flyd_upstream_responses_total",
+            "counter",
+            "Machines API calls, by endpoint and status; error for calls that got no answer.",
+        );
+        for ((endpoint, status), count) in self.responses.lock().unwrap().iter() {
+            metrics::sample(
+                out,
+                "flyd_upstream_responses_total",
+                &[("endpoint", endpoint.clone()), ("status", status.clone())],
+                *count as f64,
+            );
+        }
+        metrics::header(
+            out,
+            "flyd_upstream_request_duration_seconds",
+            "histogram",
+            "Machines API latency, by endpoint. Each retry is timed on its own.",
+        );
+        for (endpoint, histogram) in self.durations.lock().unwrap().iter() {
+            histogram.render(
+                out,
+                "flyd_upstream_request_duration_seconds",
+                &[("endpoint", endpoint.clone())],
+            );
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use flyd::models::{MachineTemplate, TemplateQuery};
+use serde_json::Value;
+
+use crate::errors::AppError;
+use crate::merge::fill_defaults;
+use crate::store::Store;
+
+const TEMPLATES: &str = "templates";
+
+/// Fills a create body from the template named in the request's `?template=`, if any.
+pub async fn apply(req: &HttpRequest, body: &mut Value) -> Result<(), HttpResponse> {
+    let Some(name) = web::Query::<TemplateQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().template)
+    else {
+        return Ok(());
+    };
+    let Some(store) = req.app_data::<web::Data<Store>>() else {
+        return Err(HttpResponse::InternalServerError().finish());
+    };
+    match store.get::<MachineTemplate>(TEMPLATES, &name).await {
+        Ok(Some(template)) => {
+            fill_defaults(body, &Value::Object(template.machine));
+            Ok(())
+        }
+        Ok(None) => Err(AppError::not_found(format!("No template {}", name)).into_response()),
+        Err(e) => Err(AppError::internal(e.to_string()).into_response()),
+    }
+}
+
+/// Registers the template, replacing any of the same name.
+#[post("/v0/templates")]
+async fn put_template(body: web::Json<MachineTemplate>, store: web::Data<Store>) -> impl Responder {
+    let template = body.into_inner();
+    if template.name.is_empty() {
+        return AppError::bad_request("A template needs a name").into_response();
+    }
+    match store.put(TEMPLATES, &template.name, &template).await {
+        Ok(()) => HttpResponse::Created().json(template),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/templates")]
+async fn list_templates(store: web::Data<Store>) -> impl Responder {
+    match store.list::<MachineTemplate>(TEMPLATES).await {
+        Ok(mut templates) => {
+            templates.sort_by(|a, b| a.name.cmp(&b.name));
+            HttpResponse::Ok().json(templates)
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/templates/{name}")]
+async fn get_template(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    match store.get::<MachineTemplate>(TEMPLATES, &path).await {
+        Ok(Some(template)) => HttpResponse::Ok().json(template),
+        Ok(None) => AppError::not_found(format!("No template {}", path)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[delete("/v0/templates/{name}")]
+async fn delete_template(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    match store.delete(TEMPLATES, &path).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => AppError::not_found(format!("No template {}", path)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(put_template)
+        .service(list_templates)
+        .service(get_template)
+        .service(delete_template);
+}
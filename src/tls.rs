@@ -0,0 +1,183 @@
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::rt::net::TcpStream;
+use actix_web::{Error, web};
+use rustls::RootCertStore;
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use sha2::{Digest, Sha256};
+
+use crate::config::TlsConfig;
+use crate::errors::AppError;
+
+/// The verified certificate a client presented, by the SHA-256 of its leaf.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    pub fingerprint: String,
+}
+
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))
+    };
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to read {}: {}", config.cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{} holds no certificates", config.cert_path));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key_path)?)
+        .map_err(|e| format!("Failed to read {}: {}", config.key_path, e))?
+        .ok_or_else(|| format!("{} holds no private key", config.key_path))?;
+    let key = ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("Unusable key in {}: {}", config.key_path, e))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &str| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    Some((modified(&config.cert_path)?, modified(&config.key_path)?))
+}
+
+/// Serves whichever certificate was last loaded, so renewing it on disk takes effect
+/// without a restart or dropping connections.
+#[derive(Debug)]
+pub struct Certificates {
+    current: RwLock<Arc<CertifiedKey>>,
+    loaded_from: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// The rustls config to serve `config`'s certificate with, and the certificates to keep
+/// reloading with [`reload`]. With `client_ca_path`, clients may present a certificate
+/// signed by it; [`require_client_certificate`] decides which routes need one.
+pub fn server_config(
+    config: &TlsConfig,
+) -> Result<(rustls::ServerConfig, Arc<Certificates>), String> {
+    let certificates = Arc::new(Certificates {
+        current: RwLock::new(Arc::new(load_certified_key(config)?)),
+        loaded_from: Mutex::new(modified(config)),
+    });
+    let provider = Arc::new(ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match &config.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+            for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                let cert = cert.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Bad CA certificate in {}: {}", path, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| format!("client_ca_path {}: {}", path, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok((
+        builder.with_cert_resolver(certificates.clone()),
+        certificates,
+    ))
+}
+
+/// Reloads the certificate and key every `reload_secs` that either file has changed,
+/// keeping the one being served if the new pair doesn't load.
+pub async fn reload(config: TlsConfig, certificates: Arc<Certificates>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.reload_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        let modified = modified(&config);
+        if modified.is_none() || *certificates.loaded_from.lock().unwrap() == modified {
+            continue;
+        }
+        match load_certified_key(&config) {
+            Ok(key) => {
+                *certificates.current.write().unwrap() = Arc::new(key);
+                *certificates.loaded_from.lock().unwrap() = modified;
+                log::info!("Reloaded the TLS certificate from {}", config.cert_path);
+            }
+            Err(e) => log::error!("Failed to reload the TLS certificate: {}", e),
+        }
+    }
+}
+
+/// Attaches the client's verified certificate, if it presented one, to the connection.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    if let Some(leaf) = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    {
+        extensions.insert(ClientCertificate {
+            fingerprint: hex::encode(Sha256::digest(leaf)),
+        });
+    }
+}
+
+/// The routes that take a verified client certificate, with `client_ca_path` set.
+pub struct ClientAuth {
+    pub routes: Vec<String>,
+}
+
+/// Refuses requests to `client_auth_routes` made without a verified client certificate.
+pub async fn require_client_certificate<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let required = req.app_data::<web::Data<ClientAuth>>().is_some_and(|auth| {
+        auth.routes
+            .iter()
+            .any(|route| req.path().starts_with(route))
+    });
+    if required {
+        let Some(certificate) = req.conn_data::<ClientCertificate>() else {
+            log::info!(
+                "Refused {} {}: no client certificate",
+                req.method(),
+                req.path()
+            );
+            return Ok(req.into_response(
+                AppError::forbidden("This route requires a client certificate").into_response(),
+            ));
+        };
+        log::debug!(
+            "{} {} with client certificate {}",
+            req.method(),
+            req.path(),
+            certificate.fingerprint
+        );
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
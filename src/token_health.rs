@@ -0,0 +1,305 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::{TokenHealth, TokenState, TokenStatus};
+use futures_util::future::join_all;
+use reqwest::StatusCode;
+use serde_json::json;
+
+use crate::auth;
+use crate::config::{Config, TokenHealthConfig};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{FlyClient, FlyError, api_hostname, authorization_value};
+
+/// Listing an org's apps is the cheapest call every token may make; tokens that aren't
+/// tied to an org are tried against the personal one.
+const DEFAULT_ORG: &str = "personal";
+
+/// A Fly token from the config, under every name it's configured with.
+struct Source {
+    names: Vec<String>,
+    token: String,
+    org: Option<String>,
+}
+
+/// Every Fly token flyd holds, each once.
+fn sources(config: &Config) -> Vec<Source> {
+    let mut named: Vec<(String, &String, Option<&String>)> = Vec::new();
+    if let Some(token) = &config.fly_api_token {
+        named.push(("FLY_API_TOKEN".to_string(), token, None));
+    }
+    for key in &config.auth.static_keys {
+        if let Some(token) = &key.fly_token {
+            named.push((format!("auth.static_keys.{}", key.subject), token, None));
+        }
+    }
+    let providers = [
+        (
+            "auth.jwt",
+            config
+                .auth
+                .jwt
+                .as_ref()
+                .and_then(|jwt| jwt.fly_token.as_ref()),
+        ),
+        (
+            "auth.oidc",
+            config
+                .auth
+                .oidc
+                .as_ref()
+                .and_then(|oidc| oidc.fly_token.as_ref()),
+        ),
+        (
+            "auth.mtls",
+            config
+                .auth
+                .mtls
+                .as_ref()
+                .and_then(|mtls| mtls.fly_token.as_ref()),
+        ),
+    ];
+    for (name, token) in providers {
+        if let Some(token) = token {
+            named.push((name.to_string(), token, None));
+        }
+    }
+    if let Some(api_keys) = &config.auth.api_keys {
+        for (name, token) in &api_keys.fly_tokens {
+            named.push((format!("auth.api_keys.fly_tokens.{}", name), token, None));
+        }
+    }
+    let mut orgs: Vec<_> = config.orgs.iter().collect();
+    orgs.sort_by_key(|(slug, _)| *slug);
+    for (slug, org) in orgs {
+        named.push((format!("orgs.{}", slug), &org.fly_token, Some(slug)));
+    }
+    let mut hosts: Vec<_> = config.virtual_hosts.iter().collect();
+    hosts.sort_by_key(|(host, _)| *host);
+    for (host, virtual_host) in hosts {
+        if let Some(token) = &virtual_host.fly_token {
+            named.push((
+                format!("virtual_hosts.{}", host),
+                token,
+                virtual_host.org_slug.as_ref(),
+            ));
+        }
+    }
+
+    let mut sources: Vec<Source> = Vec::new();
+    for (name, token, org) in named {
+        match sources.iter_mut().find(|source| &source.token == token) {
+            Some(source) => {
+                source.names.push(name);
+                source.org = source.org.take().or(org.cloned());
+            }
+            None => sources.push(Source {
+                names: vec![name],
+                token: token.clone(),
+                org: org.cloned(),
+            }),
+        }
+    }
+    sources
+}
+
+/// When the token says it expires, if it's a JWT.
+fn claimed_expiry(token: &str) -> Option<DateTime<Utc>> {
+    let token = token
+        .strip_prefix("Bearer ")
+        .or(token.strip_prefix("FlyV1 "))
+        .unwrap_or(token);
+    DateTime::from_timestamp(auth::unverified_claims(token)?["exp"].as_i64()?, 0)
+}
+
+/// What a check found, before expiry is taken into account.
+async fn probe(
+    http: &reqwest::Client,
+    use_private_api: bool,
+    source: &Source,
+) -> (TokenState, Option<String>) {
+    let Some(client) =
+        FlyClient::from_token(http.clone(), &source.token, api_hostname(use_private_api))
+    else {
+        return (
+            TokenState::Invalid,
+            Some("It isn't a valid header value".to_string()),
+        );
+    };
+    match client
+        .list_apps(source.org.as_deref().unwrap_or(DEFAULT_ORG))
+        .await
+    {
+        Ok(_) => (TokenState::Valid, None),
+        Err(FlyError::Status { status, body }) if status == StatusCode::UNAUTHORIZED => (
+            TokenState::Invalid,
+            Some(format!("Fly answered {}: {}", status, body)),
+        ),
+        // Authenticated, if not allowed into the org.
+        Err(FlyError::Status { status, .. }) if status.is_client_error() => {
+            (TokenState::Valid, None)
+        }
+        Err(e) => (TokenState::Unknown, Some(e.to_string())),
+    }
+}
+
+fn state_name(state: TokenState) -> &'static str {
+    match state {
+        TokenState::Unknown => "unknown",
+        TokenState::Valid => "valid",
+        TokenState::Expiring => "expiring",
+        TokenState::Expired => "expired",
+        TokenState::Invalid => "invalid",
+    }
+}
+
+pub struct TokenMonitor {
+    config: TokenHealthConfig,
+    http: reqwest::Client,
+    use_private_api: bool,
+    events: web::Data<EventLog>,
+    sources: Vec<Source>,
+    statuses: Mutex<Vec<TokenStatus>>,
+}
+
+impl TokenMonitor {
+    pub fn new(config: &Config, http: reqwest::Client, events: web::Data<EventLog>) -> Self {
+        let sources = sources(config);
+        let statuses = sources
+            .iter()
+            .map(|source| {
+                let expires_at = source
+                    .names
+                    .iter()
+                    .find_map(|name| config.token_health.expires_at.get(name).copied())
+                    .or_else(|| claimed_expiry(&source.token));
+                TokenStatus {
+                    name: source.names[0].clone(),
+                    also_used_by: source.names[1..].to_vec(),
+                    fingerprint: auth::token_hash(authorization_value(&source.token).as_bytes())
+                        [..12]
+                        .to_string(),
+                    state: TokenState::Unknown,
+                    checked_at: None,
+                    last_valid_at: None,
+                    expires_at,
+                    error: None,
+                }
+            })
+            .collect();
+        TokenMonitor {
+            config: config.token_health.clone(),
+            http,
+            use_private_api: config.use_private_api,
+            events,
+            sources,
+            statuses: Mutex::new(statuses),
+        }
+    }
+
+    /// Checks every token, alerting on each that has just become invalid, expired or
+    /// close to expiring.
+    pub async fn check(&self) {
+        let probed = join_all(
+            self.sources
+                .iter()
+                .map(|source| probe(&self.http, self.use_private_api, source)),
+        )
+        .await;
+        let now = Utc::now();
+        let warn_before = TimeDelta::days(self.config.warn_before_days as i64);
+        let mut alerts = Vec::new();
+        {
+            let mut statuses = self.statuses.lock().unwrap();
+            for (status, (probed, error)) in statuses.iter_mut().zip(probed) {
+                if probed == TokenState::Valid {
+                    status.last_valid_at = Some(now);
+                }
+                let state = match status.expires_at {
+                    Some(at) if at <= now => TokenState::Expired,
+                    Some(at) if probed != TokenState::Invalid && at - now <= warn_before => {
+                        TokenState::Expiring
+                    }
+                    _ => probed,
+                };
+                if state != status.state
+                    && matches!(
+                        state,
+                        TokenState::Invalid | TokenState::Expired | TokenState::Expiring
+                    )
+                {
+                    alerts.push((state, status.clone(), error.clone()));
+                }
+                status.state = state;
+                status.error = error;
+                status.checked_at = Some(now);
+            }
+        }
+
+        for (state, status, error) in alerts {
+            log::warn!(
+                "Fly token {} ({}) is {}{}",
+                status.name,
+                status.fingerprint,
+                state_name(state),
+                error.as_ref().map_or(String::new(), |e| format!(": {}", e))
+            );
+            self.events.record(
+                &format!("token.{}", state_name(state)),
+                None,
+                None,
+                json!({
+                    "name": status.name,
+                    "also_used_by": status.also_used_by,
+                    "fingerprint": status.fingerprint,
+                    "expires_at": status.expires_at,
+                    "error": error,
+                }),
+            );
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<TokenStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    fn admin(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(role) = &self.config.admin_role else {
+            return Err(AppError::forbidden(
+                "Checking token health needs token_health.admin_role set",
+            )
+            .into_response());
+        };
+        auth::require_role(req, Some(role)).map(|_| ())
+    }
+}
+
+pub async fn run(monitor: web::Data<TokenMonitor>) {
+    if monitor.sources.is_empty() {
+        return;
+    }
+    let interval = Duration::from_secs(monitor.config.interval_secs.max(1));
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        monitor.check().await;
+    }
+}
+
+/// How each Fly token flyd holds fared in its last check, by name and fingerprint.
+#[get("/v0/admin/tokens/status")]
+async fn get_status(req: HttpRequest, monitor: web::Data<TokenMonitor>) -> impl Responder {
+    if let Err(response) = monitor.admin(&req) {
+        return response;
+    }
+    HttpResponse::Ok().json(TokenHealth {
+        tokens: monitor.statuses(),
+    })
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_status);
+}
@@ -0,0 +1,290 @@
+use std::time::Duration;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, post, web};
+use chrono::Utc;
+use flyd::models::{Consequence, RestoreMachineRequest, TrashQuery, TrashedMachine};
+use serde_json::json;
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::config::{Config, TrashConfig};
+use crate::docker::DockerBackend;
+use crate::drain::{self, Drainer};
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fleets;
+use crate::fly_client::{self, FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+const TRASH: &str = "trash";
+/// Set on trashed machines, to when they were trashed.
+pub const TRASHED_METADATA_KEY: &str = "flyd_trashed_at";
+
+fn key(app: &str, machine_id: &str) -> String {
+    format!("{}/{}", app, machine_id)
+}
+
+/// Drains and stops the machine, and tags it for destroying once `config.retention_hours`
+/// pass.
+pub async fn trash<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    config: &TrashConfig,
+    app: &str,
+    machine_id: &str,
+    consequences: Vec<Consequence>,
+) -> Result<TrashedMachine, HttpResponse> {
+    let Some(store) = req.app_data::<web::Data<Store>>() else {
+        return Err(HttpResponse::InternalServerError().finish());
+    };
+    let machine = backend
+        .get_machine(app, machine_id)
+        .await
+        .map_err(|e| AppError::internal(format!("Failed to get machine: {}", e)).into_response())?;
+    if !machine["config"]["metadata"][TRASHED_METADATA_KEY].is_null() {
+        return Err(
+            AppError::conflict(format!("Machine {} is already in the trash", machine_id))
+                .into_response(),
+        );
+    }
+
+    let now = Utc::now();
+    let trashed = TrashedMachine {
+        app: app.to_string(),
+        machine_id: machine_id.to_string(),
+        name: machine["name"].as_str().map(str::to_string),
+        region: machine["region"].as_str().map(str::to_string),
+        previous_state: machine["state"].as_str().map(str::to_string),
+        trashed_by: req
+            .extensions()
+            .get::<Identity>()
+            .map(|identity| identity.subject.clone()),
+        trashed_at: now,
+        destroy_after: now + chrono::Duration::hours(config.retention_hours as i64),
+        consequences,
+    };
+    let drainer = req.app_data::<web::Data<Drainer>>();
+    drain::drain(drainer, backend, app, machine_id).await;
+    if !matches!(
+        trashed.previous_state.as_deref(),
+        Some("stopped" | "suspended")
+    ) && let Err(e) = backend.stop_machine(app, machine_id).await
+    {
+        return Err(AppError::internal(format!("Failed to stop: {}", e)).into_response());
+    }
+    if let Err(e) = backend
+        .set_metadata(app, machine_id, TRASHED_METADATA_KEY, &now.to_rfc3339())
+        .await
+    {
+        return Err(AppError::internal(format!("Failed to tag: {}", e)).into_response());
+    }
+    if let Err(e) = store.put(TRASH, &key(app, machine_id), &trashed).await {
+        return Err(AppError::internal(e.to_string()).into_response());
+    }
+    fly_client::cache().invalidate(app);
+    if let Some(events) = req.app_data::<web::Data<EventLog>>() {
+        events.record(
+            "machine.trashed",
+            Some(app),
+            Some(machine_id),
+            json!({
+                "by": trashed.trashed_by,
+                "destroy_after": trashed.destroy_after,
+                "consequences": trashed.consequences,
+            }),
+        );
+    }
+    Ok(trashed)
+}
+
+/// Destroys the trashed machine, unless it's gone or was restored some other way.
+async fn purge<B: Backend>(
+    backend: &B,
+    store: &Store,
+    events: &EventLog,
+    trashed: &TrashedMachine,
+) -> Result<(), String> {
+    let (app, machine_id) = (&trashed.app, &trashed.machine_id);
+    let machines = backend
+        .list_machines(app)
+        .await
+        .map_err(|e| format!("Failed to list machines: {}", e))?;
+    let tagged = machines
+        .iter()
+        .filter(|machine| fleets::is_live(machine))
+        .find(|machine| machine["id"] == machine_id.as_str())
+        .map(|machine| !machine["config"]["metadata"][TRASHED_METADATA_KEY].is_null());
+    match tagged {
+        Some(true) => {
+            backend
+                .destroy_machine(app, machine_id, None)
+                .await
+                .map_err(|e| format!("Failed to destroy: {}", e))?;
+            events.record(
+                "machine.destroyed",
+                Some(app),
+                Some(machine_id),
+                json!({
+                    "by": trashed.trashed_by,
+                    "trashed_at": trashed.trashed_at,
+                    "consequences": trashed.consequences,
+                }),
+            );
+            log::info!("Destroyed machine {} of {} from the trash", machine_id, app);
+        }
+        Some(false) => log::info!(
+            "Machine {} of {} lost its trash tag; keeping it",
+            machine_id,
+            app
+        ),
+        None => {}
+    }
+    store
+        .delete(TRASH, &key(app, machine_id))
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+pub async fn run<B: Backend>(
+    config: TrashConfig,
+    backend: B,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        let trashed = match store.list::<TrashedMachine>(TRASH).await {
+            Ok(trashed) => trashed,
+            Err(e) => {
+                log::error!("Failed to load the trash: {}", e);
+                continue;
+            }
+        };
+        let now = Utc::now();
+        for trashed in trashed
+            .iter()
+            .filter(|trashed| trashed.destroy_after <= now)
+        {
+            if let Err(e) = purge(&backend, &store, &events, trashed).await {
+                log::error!(
+                    "Trash: machine {} of {}: {}",
+                    trashed.machine_id,
+                    trashed.app,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Machines waiting to be destroyed, soonest first.
+#[get("/v0/trash")]
+async fn list_trash(query: web::Query<TrashQuery>, store: web::Data<Store>) -> impl Responder {
+    match store.list::<TrashedMachine>(TRASH).await {
+        Ok(mut trashed) => {
+            trashed.retain(|trashed| {
+                query
+                    .app_name
+                    .as_ref()
+                    .is_none_or(|app| trashed.app == *app)
+            });
+            trashed.sort_by_key(|trashed| trashed.destroy_after);
+            HttpResponse::Ok().json(trashed)
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+async fn restore<B: Backend>(
+    req: &HttpRequest,
+    backend: &B,
+    store: &Store,
+    app: &str,
+    machine_id: &str,
+) -> HttpResponse {
+    let trashed = match store
+        .get::<TrashedMachine>(TRASH, &key(app, machine_id))
+        .await
+    {
+        Ok(Some(trashed)) => trashed,
+        Ok(None) => {
+            return AppError::not_found(format!(
+                "Machine {} of {} isn't in the trash",
+                machine_id, app
+            ))
+            .into_response();
+        }
+        Err(e) => return AppError::internal(e.to_string()).into_response(),
+    };
+    if let Err(e) = backend
+        .delete_metadata(app, machine_id, TRASHED_METADATA_KEY)
+        .await
+    {
+        return AppError::internal(format!("Failed to untag: {}", e)).into_response();
+    }
+    if let Err(e) = store.delete(TRASH, &key(app, machine_id)).await {
+        return AppError::internal(e.to_string()).into_response();
+    }
+    // Trashing drained it.
+    if let Err(e) = backend.uncordon_machine(app, machine_id).await {
+        log::warn!("Failed to uncordon restored machine {}: {}", machine_id, e);
+    }
+    if trashed.previous_state.as_deref() == Some("started")
+        && let Err(e) = backend.start_machine(app, machine_id).await
+    {
+        return AppError::internal(format!("Restored, but failed to start: {}", e)).into_response();
+    }
+    fly_client::cache().invalidate(app);
+    if let Some(events) = req.app_data::<web::Data<EventLog>>() {
+        events.record(
+            "machine.restored",
+            Some(app),
+            Some(machine_id),
+            json!({
+                "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+                "trashed_at": trashed.trashed_at,
+            }),
+        );
+    }
+    HttpResponse::Ok().json(trashed)
+}
+
+/// Takes a machine back out of the trash, starting it again if it was started.
+#[post("/v0/trash/restore")]
+async fn restore_machine(
+    req: HttpRequest,
+    body: web::Json<RestoreMachineRequest>,
+    config: web::Data<Config>,
+    store: web::Data<Store>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    if config.trash.is_none() {
+        return AppError::not_found("The trash isn't configured").into_response();
+    }
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return restore(
+            &req,
+            docker.get_ref(),
+            &store,
+            &body.app_name,
+            &body.machine_id,
+        )
+        .await;
+    }
+    let (headers, api_hostname) = match prepare_request(&req, body.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    restore(&req, &client, &store, &body.app_name, &body.machine_id).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_trash).service(restore_machine);
+}
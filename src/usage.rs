@@ -0,0 +1,79 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpResponse, Responder, get, web};
+use chrono::Utc;
+use flyd::models::{UsageQuery, UsageRecord};
+
+use crate::auth::Identity;
+use crate::errors::AppError;
+use crate::store::Store;
+use crate::write_queue::{Write, WriteQueue};
+
+const USAGE: &str = "usage";
+
+/// Counts requests per identity and route through the write queue, so recording usage
+/// never waits on the store.
+pub async fn track<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let queue = req.app_data::<web::Data<WriteQueue>>().cloned();
+    let endpoint = req
+        .match_pattern()
+        .map(|pattern| format!("{} {}", req.method(), pattern));
+    let subject = req
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.subject.clone())
+        .unwrap_or_else(|| "anonymous".to_string());
+
+    let result = next.call(req).await;
+
+    if let (Some(queue), Some(endpoint)) = (queue, endpoint) {
+        let failed = match &result {
+            Ok(response) => !response.status().is_success(),
+            Err(_) => true,
+        };
+        let day = Utc::now().format("%Y-%m-%d").to_string();
+        let key = format!("{}|{}|{}", day, subject, endpoint);
+        let initial = UsageRecord {
+            day,
+            subject,
+            endpoint,
+            requests: 0,
+            errors: 0,
+        };
+        queue
+            .push(Write::Increment {
+                collection: USAGE,
+                key,
+                initial: serde_json::to_value(initial).unwrap_or_default(),
+                counters: vec![("requests", 1), ("errors", failed as u64)],
+            })
+            .await;
+    }
+    result
+}
+
+#[get("/v0/usage")]
+async fn list_usage(query: web::Query<UsageQuery>, store: web::Data<Store>) -> impl Responder {
+    let day = query
+        .day
+        .clone()
+        .unwrap_or_else(|| Utc::now().format("%Y-%m-%d").to_string());
+    match store.list::<UsageRecord>(USAGE).await {
+        Ok(records) => HttpResponse::Ok().json(
+            records
+                .into_iter()
+                .filter(|record| record.day == day)
+                .filter(|record| query.subject.as_ref().is_none_or(|s| &record.subject == s))
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_usage);
+}
@@ -0,0 +1,215 @@
+use flyd::models::{FieldError, FlyMachineConfig, MachineGuest, MachinePort, MachineService};
+use serde_json::Value;
+
+use crate::errors::AppError;
+
+const CPU_KINDS: &[&str] = &["shared", "performance"];
+const CPU_COUNTS: &[u32] = &[1, 2, 4, 8, 16];
+const MAX_SHARED_CPUS: u32 = 8;
+const MEMORY_STEP_MB: u32 = 256;
+const PROTOCOLS: &[&str] = &["tcp", "udp"];
+const HANDLERS: &[&str] = &["http", "tls", "proxy_proto", "pg_tls", "edge_http"];
+const RESTART_POLICIES: &[&str] = &["no", "always", "on-failure", "spot-price"];
+const MAX_PORT: u32 = 65535;
+
+/// The memory a machine of this kind may have per CPU, in MB.
+fn memory_per_cpu(cpu_kind: &str) -> (u32, u32) {
+    match cpu_kind {
+        "performance" => (2048, 8192),
+        _ => (256, 2048),
+    }
+}
+
+#[derive(Default)]
+struct Errors(Vec<FieldError>);
+
+impl Errors {
+    fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.0.push(FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn one_of(&mut self, field: &str, value: &str, allowed: &[&str]) {
+        if !allowed.contains(&value) {
+            self.add(
+                field,
+                format!("{} isn't one of {}", value, allowed.join(", ")),
+            );
+        }
+    }
+
+    fn port(&mut self, field: &str, port: u32) {
+        if port == 0 || port > MAX_PORT {
+            self.add(
+                field,
+                format!("{} isn't a port between 1 and {}", port, MAX_PORT),
+            );
+        }
+    }
+}
+
+fn guest(errors: &mut Errors, guest: &MachineGuest) {
+    let cpu_kind = guest.cpu_kind.as_deref().unwrap_or("shared");
+    errors.one_of("config.guest.cpu_kind", cpu_kind, CPU_KINDS);
+    let cpus = guest.cpus.unwrap_or(1);
+    if !CPU_COUNTS.contains(&cpus) {
+        errors.add(
+            "config.guest.cpus",
+            format!("{} isn't 1, 2, 4, 8 or 16", cpus),
+        );
+    } else if cpu_kind == "shared" && cpus > MAX_SHARED_CPUS {
+        errors.add(
+            "config.guest.cpus",
+            format!("Shared machines have at most {} CPUs", MAX_SHARED_CPUS),
+        );
+    }
+    let Some(memory_mb) = guest.memory_mb else {
+        return;
+    };
+    let (min, max) = memory_per_cpu(cpu_kind);
+    if memory_mb % MEMORY_STEP_MB != 0 {
+        errors.add(
+            "config.guest.memory_mb",
+            format!("{}MB isn't a multiple of {}MB", memory_mb, MEMORY_STEP_MB),
+        );
+    } else if memory_mb < min * cpus || memory_mb > max * cpus {
+        errors.add(
+            "config.guest.memory_mb",
+            format!(
+                "{} {}x machines take {}MB to {}MB, not {}MB",
+                cpu_kind,
+                cpus,
+                min * cpus,
+                max * cpus,
+                memory_mb
+            ),
+        );
+    }
+}
+
+fn port(errors: &mut Errors, field: &str, port: &MachinePort) {
+    match (port.port, port.start_port, port.end_port) {
+        (Some(single), None, None) => errors.port(&format!("{}.port", field), single),
+        (None, Some(start), Some(end)) => {
+            errors.port(&format!("{}.start_port", field), start);
+            errors.port(&format!("{}.end_port", field), end);
+            if start > end {
+                errors.add(
+                    field,
+                    format!("start_port {} is after end_port {}", start, end),
+                );
+            }
+        }
+        (None, None, None) => errors.add(field, "Needs a port or a start_port and end_port"),
+        _ => errors.add(
+            field,
+            "Takes either a port or a start_port and end_port, not both",
+        ),
+    }
+    for (i, handler) in port.handlers.iter().flatten().enumerate() {
+        errors.one_of(&format!("{}.handlers[{}]", field, i), handler, HANDLERS);
+    }
+}
+
+fn service(errors: &mut Errors, field: &str, service: &MachineService) {
+    match &service.protocol {
+        Some(protocol) => errors.one_of(&format!("{}.protocol", field), protocol, PROTOCOLS),
+        None => errors.add(&format!("{}.protocol", field), "is required"),
+    }
+    match service.internal_port {
+        Some(internal_port) => errors.port(&format!("{}.internal_port", field), internal_port),
+        None => errors.add(&format!("{}.internal_port", field), "is required"),
+    }
+    for (i, machine_port) in service.ports.iter().flatten().enumerate() {
+        port(errors, &format!("{}.ports[{}]", field, i), machine_port);
+    }
+}
+
+/// Checks a machine's `config` the way the Machines API would, before sending it there,
+/// so callers get every problem at once and which field it is with.
+pub fn machine_config(config: &Value) -> Result<(), AppError> {
+    let mut errors = Errors::default();
+    let config: FlyMachineConfig = match config {
+        Value::Null => FlyMachineConfig::default(),
+        config => serde_json::from_value(config.clone()).map_err(|e| {
+            AppError::unprocessable("Invalid machine config")
+                .with_code("invalid_machine_config")
+                .with_fields(vec![FieldError {
+                    field: "config".to_string(),
+                    message: e.to_string(),
+                }])
+        })?,
+    };
+
+    if config
+        .image
+        .as_deref()
+        .is_none_or(|image| image.trim().is_empty())
+    {
+        errors.add("config.image", "is required");
+    }
+    if let Some(machine_guest) = &config.guest {
+        guest(&mut errors, machine_guest);
+    }
+    for (i, machine_service) in config.services.iter().flatten().enumerate() {
+        service(
+            &mut errors,
+            &format!("config.services[{}]", i),
+            machine_service,
+        );
+    }
+    let mut paths = Vec::new();
+    for (i, mount) in config.mounts.iter().flatten().enumerate() {
+        let field = format!("config.mounts[{}]", i);
+        if mount.volume.as_deref().is_none_or(str::is_empty) {
+            errors.add(&format!("{}.volume", field), "is required");
+        }
+        match mount.path.as_deref() {
+            Some(path) if !path.starts_with('/') => errors.add(
+                &format!("{}.path", field),
+                format!("{} isn't an absolute path", path),
+            ),
+            Some(path) if paths.contains(&path) => errors.add(
+                &format!("{}.path", field),
+                format!("Another mount is already at {}", path),
+            ),
+            Some(path) => paths.push(path),
+            None => errors.add(&format!("{}.path", field), "is required"),
+        }
+    }
+    if let Some(restart) = &config.restart {
+        let policy = restart.policy.as_deref().unwrap_or("always");
+        errors.one_of("config.restart.policy", policy, RESTART_POLICIES);
+        if restart.max_retries.is_some() && policy != "on-failure" {
+            errors.add(
+                "config.restart.max_retries",
+                "Only applies to the on-failure policy",
+            );
+        }
+    }
+    for name in config.env.iter().flatten().map(|(name, _)| name) {
+        if name.is_empty() || name.contains(['=', '\0']) {
+            errors.add(
+                &format!("config.env.{}", name),
+                "Environment variable names can't be empty or contain = or NUL",
+            );
+        }
+    }
+
+    if errors.0.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::unprocessable(format!(
+        "Invalid machine config: {}",
+        errors
+            .0
+            .iter()
+            .map(|error| format!("{}: {}", error.field, error.message))
+            .collect::<Vec<_>>()
+            .join("; ")
+    ))
+    .with_code("invalid_machine_config")
+    .with_fields(errors.0))
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use serde_json::{Value, json};
+
+use crate::callbacks::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::config::ValidationWebhookConfig;
+
+/// An external check of every Machines API call that may change something, made with
+/// the payload flyd is about to send. The call goes ahead only if the webhook answers 200
+/// without `"allow": false`.
+pub struct ValidationWebhook {
+    config: ValidationWebhookConfig,
+}
+
+impl ValidationWebhook {
+    pub fn new(config: ValidationWebhookConfig) -> Self {
+        ValidationWebhook { config }
+    }
+
+    fn payload(request: &reqwest::Request) -> Value {
+        let path = request.url().path();
+        let mut segments = path.split('/').skip_while(|segment| *segment != "apps");
+        let app = segments.nth(1);
+        let mut segments = path.split('/').skip_while(|segment| *segment != "machines");
+        let machine_id = segments.nth(1).filter(|id| !id.is_empty());
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .and_then(|bytes| serde_json::from_slice::<Value>(bytes).ok());
+        json!({
+            "method": request.method().as_str(),
+            "path": path,
+            "query": request.url().query(),
+            "app": app,
+            "machine_id": machine_id,
+            "body": body,
+        })
+    }
+
+    /// Why the webhook blocks `request`, if it does.
+    pub async fn check(
+        &self,
+        http: &reqwest::Client,
+        request: &reqwest::Request,
+    ) -> Result<(), String> {
+        let body = serde_json::to_vec(&Self::payload(request)).unwrap_or_default();
+        let mut call = http
+            .post(&self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(Duration::from_millis(self.config.timeout_ms));
+        if let Some(secret) = &self.config.secret {
+            let timestamp = Utc::now().timestamp().to_string();
+            call = call
+                .header(SIGNATURE_HEADER, callbacks::sign(secret, &timestamp, &body))
+                .header(TIMESTAMP_HEADER, timestamp);
+        }
+        let operation = format!("{} {}", request.method(), request.url().path());
+
+        let response = match call.body(body).send().await {
+            Ok(response) => response,
+            Err(e) if self.config.fail_open => {
+                log::warn!(
+                    "Validation webhook unreachable, allowing {}: {}",
+                    operation,
+                    e
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(format!(
+                    "The validation webhook couldn't be reached to allow {}: {}",
+                    operation, e
+                ));
+            }
+        };
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let answer = serde_json::from_str::<Value>(&text).unwrap_or(Value::Null);
+        let reason = answer["reason"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| text.trim().to_string());
+        if status != reqwest::StatusCode::OK {
+            return Err(format!(
+                "The validation webhook denied {} ({}): {}",
+                operation, status, reason
+            ));
+        }
+        if answer["allow"] == false {
+            return Err(format!(
+                "The validation webhook denied {}: {}",
+                operation, reason
+            ));
+        }
+        Ok(())
+    }
+}
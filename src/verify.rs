@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use reqwest::header::AUTHORIZATION;
+use serde_json::Value;
+
+use crate::backend::Backend;
+use crate::config::{Config, VerifyHookConfig};
+use crate::fly_client::authorization_value;
+
+/// Runs the hooks a deploy stage must pass once it's healthy; a failing one rolls the
+/// deploy back. Prometheus is queried with `FLY_API_TOKEN`, as Fly's own needs it.
+pub struct Verifier {
+    http: reqwest::Client,
+    token: Option<String>,
+}
+
+impl Verifier {
+    pub fn new(config: &Config, http: reqwest::Client) -> Self {
+        Verifier {
+            http,
+            token: config.fly_api_token.clone(),
+        }
+    }
+
+    async fn http(&self, url: &str, timeout_secs: u64) -> Result<(), String> {
+        let response = self
+            .http
+            .get(url)
+            .timeout(Duration::from_secs(timeout_secs))
+            .send()
+            .await
+            .map_err(|e| format!("GET {} failed: {}", url, e))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("GET {} answered {}", url, response.status()))
+        }
+    }
+
+    async fn exec<B: Backend>(
+        &self,
+        backend: &B,
+        app: &str,
+        command: &[String],
+        timeout_secs: u64,
+    ) -> Result<(), String> {
+        let machines = backend
+            .list_machines(app)
+            .await
+            .map_err(|e| format!("Failed to list machines: {}", e))?;
+        let started: Vec<&str> = machines
+            .iter()
+            .filter(|machine| machine["state"] == "started")
+            .filter_map(|machine| machine["id"].as_str())
+            .collect();
+        if started.is_empty() {
+            return Err(format!(
+                "{} has no started machine to run {:?} on",
+                app, command
+            ));
+        }
+        for id in started {
+            let output = backend
+                .exec_machine(app, id, command, timeout_secs)
+                .await
+                .map_err(|e| format!("Failed to run {:?} on {}: {}", command, id, e))?;
+            if output["exit_code"].as_i64() != Some(0) {
+                return Err(format!(
+                    "{:?} exited with {} on {}: {}",
+                    command,
+                    output["exit_code"],
+                    id,
+                    output["stderr"].as_str().unwrap_or_default().trim()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn prometheus(&self, url: &str, query: &str) -> Result<(), String> {
+        let mut request = self
+            .http
+            .get(format!("{}/api/v1/query", url.trim_end_matches('/')))
+            .query(&[("query", query)]);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, authorization_value(token));
+        }
+        let body: Value = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("Failed to query {}: {}", query, e))?
+            .json()
+            .await
+            .map_err(|e| format!("Failed to query {}: {}", query, e))?;
+        let values: Vec<f64> = body["data"]["result"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|series| series["value"][1].as_str())
+            .filter_map(|value| value.parse().ok())
+            .collect();
+        if values.is_empty() {
+            Err(format!("{} returned nothing", query))
+        } else if values.contains(&0.0) {
+            Err(format!("{} returned 0", query))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs the hooks in order, stopping at the first that fails.
+    pub async fn run<B: Backend>(
+        &self,
+        backend: &B,
+        app: &str,
+        hooks: &[VerifyHookConfig],
+    ) -> Result<(), String> {
+        for hook in hooks {
+            match hook {
+                VerifyHookConfig::Http { url, timeout_secs } => {
+                    self.http(&url.replace("{app}", app), *timeout_secs).await
+                }
+                VerifyHookConfig::Exec {
+                    command,
+                    timeout_secs,
+                } => self.exec(backend, app, command, *timeout_secs).await,
+                VerifyHookConfig::Prometheus { url, query } => {
+                    self.prometheus(&url.replace("{app}", app), &query.replace("{app}", app))
+                        .await
+                }
+            }
+            .map_err(|e| format!("Verification failed: {}", e))?;
+        }
+        Ok(())
+    }
+}
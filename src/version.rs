@@ -0,0 +1,190 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{HttpResponse, Responder, get, web};
+use chrono::{DateTime, Utc};
+use flyd::models::VersionInfo;
+use reqwest::header::USER_AGENT;
+use serde_json::Value;
+
+use crate::config::UpdatesConfig;
+use crate::metrics;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &str = env!("FLYD_GIT_SHA");
+const BUILD_TIMESTAMP: &str = env!("FLYD_BUILD_TIMESTAMP");
+const FEATURES: &str = env!("FLYD_FEATURES");
+
+pub const UPDATE_AVAILABLE_HEADER: &str = "x-flyd-update-available";
+
+/// `0.10.2` as `[0, 10, 2]`, ignoring a leading `v` and anything after a `-` or `+`.
+fn parse(version: &str) -> Vec<u64> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn newer(candidate: &str, current: &str) -> bool {
+    parse(candidate) > parse(current)
+}
+
+#[derive(Clone)]
+struct Release {
+    version: String,
+    url: Option<String>,
+    checked_at: DateTime<Utc>,
+}
+
+/// The newest release seen in `updates.feed_url`, checked every `updates.interval_secs`.
+pub struct UpdateChecker {
+    config: UpdatesConfig,
+    http: reqwest::Client,
+    latest: Mutex<Option<Release>>,
+}
+
+impl UpdateChecker {
+    pub fn new(config: UpdatesConfig, http: reqwest::Client) -> Self {
+        UpdateChecker {
+            config,
+            http,
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// The newer release there is to update to, if any.
+    pub fn available(&self) -> Option<String> {
+        self.latest
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|release| newer(&release.version, VERSION))
+            .map(|release| release.version.clone())
+    }
+
+    async fn check(&self, feed_url: &str) -> Result<Release, String> {
+        let body: Value = self
+            .http
+            .get(feed_url)
+            // GitHub's API refuses requests without one.
+            .header(USER_AGENT, format!("flyd/{}", VERSION))
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        let version = body["version"]
+            .as_str()
+            .or(body["tag_name"].as_str())
+            .ok_or("The feed has no version or tag_name")?;
+        Ok(Release {
+            version: version.trim_start_matches('v').to_string(),
+            url: body["url"]
+                .as_str()
+                .or(body["html_url"].as_str())
+                .map(str::to_string),
+            checked_at: Utc::now(),
+        })
+    }
+
+    pub fn info(&self) -> VersionInfo {
+        let latest = self.latest.lock().unwrap().clone();
+        VersionInfo {
+            version: VERSION.to_string(),
+            git_sha: GIT_SHA.to_string(),
+            built_at: BUILD_TIMESTAMP
+                .parse()
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            features: FEATURES
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(str::to_string)
+                .collect(),
+            update_available: self.available().is_some(),
+            latest_version: latest.as_ref().map(|release| release.version.clone()),
+            release_url: latest.as_ref().and_then(|release| release.url.clone()),
+            checked_at: latest.map(|release| release.checked_at),
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        metrics::header(
+            out,
+            "flyd_build_info",
+            "gauge",
+            "Always 1, labelled with the running build.",
+        );
+        metrics::sample(
+            out,
+            "flyd_build_info",
+            &[
+                ("version", VERSION.to_string()),
+                ("git_sha", GIT_SHA.to_string()),
+            ],
+            1.0,
+        );
+        if self.config.feed_url.is_none() {
+            return;
+        }
+        metrics::header(
+            out,
+            "flyd_update_available",
+            "gauge",
+            "1 when the release feed has a newer version than this build.",
+        );
+        let latest = self.latest.lock().unwrap().clone();
+        let labels = match &latest {
+            Some(release) => vec![("latest", release.version.clone())],
+            None => Vec::new(),
+        };
+        let available = self.available().is_some();
+        metrics::sample(
+            out,
+            "flyd_update_available",
+            &labels,
+            if available { 1.0 } else { 0.0 },
+        );
+    }
+}
+
+/// Keeps `latest` current; logs once for each newer release it finds.
+pub async fn run(checker: web::Data<UpdateChecker>) {
+    let Some(feed_url) = checker.config.feed_url.clone() else {
+        return;
+    };
+    let mut ticker =
+        tokio::time::interval(Duration::from_secs(checker.config.interval_secs.max(60)));
+    loop {
+        ticker.tick().await;
+        match checker.check(&feed_url).await {
+            Ok(release) => {
+                let announced = checker.available();
+                *checker.latest.lock().unwrap() = Some(release);
+                if let Some(version) = checker.available()
+                    && announced.as_ref() != Some(&version)
+                {
+                    log::warn!("flyd {} is available; this is {}", version, VERSION);
+                }
+            }
+            Err(e) => log::error!("Failed to check {} for updates: {}", feed_url, e),
+        }
+    }
+}
+
+#[get("/version")]
+async fn version_info(checker: web::Data<UpdateChecker>) -> impl Responder {
+    HttpResponse::Ok().json(checker.info())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(version_info);
+}
@@ -0,0 +1,413 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Utc;
+use flyd::models::{ListMachinesRequest, MachinePage, SavedView, ViewMetricsQuery};
+use serde_json::{Map, Value, json};
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::metrics;
+use crate::prepare_request;
+use crate::query::{self, Expression};
+use crate::slo::SloTracker;
+use crate::store::Store;
+
+const VIEWS: &str = "views";
+
+/// What a request asks of a list of machines: its `q` and other filters, and the saved
+/// view it names.
+#[derive(Default)]
+pub struct View {
+    filters: Vec<Expression>,
+    states: Vec<String>,
+    name: Option<String>,
+    /// Metadata keys the machine must have, with the value if it matters.
+    metadata: Vec<(String, Option<String>)>,
+    fields: Vec<String>,
+    sort: Vec<String>,
+}
+
+/// Where a page of a list starts and how long it is.
+pub struct Page {
+    limit: Option<usize>,
+    offset: usize,
+    /// The sort keys of the last machine on the previous page.
+    after: Option<Vec<Value>>,
+}
+
+impl Page {
+    /// The page a list request asks for, if it asks for one.
+    pub fn from_query(query: &ListMachinesRequest) -> Result<Option<Page>, String> {
+        if query.limit.is_none() && query.cursor.is_none() && query.offset.is_none() {
+            return Ok(None);
+        }
+        if query.limit == Some(0) {
+            return Err("limit must be at least 1".to_string());
+        }
+        let after = match &query.cursor {
+            Some(cursor) => Some(
+                URL_SAFE_NO_PAD
+                    .decode(cursor)
+                    .ok()
+                    .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                    .ok_or_else(|| format!("Invalid cursor {}", cursor))?,
+            ),
+            None => None,
+        };
+        Ok(Some(Page {
+            limit: query.limit,
+            offset: query.offset.unwrap_or_default(),
+            after,
+        }))
+    }
+}
+
+fn invalid_field(field: &str) -> bool {
+    field.trim_start_matches('-').split('.').any(str::is_empty)
+}
+
+impl View {
+    /// The view a list or watch request asks for, with its `state`, `name` and `sort`.
+    pub async fn from_query(
+        req: &HttpRequest,
+        query: &ListMachinesRequest,
+    ) -> Result<View, HttpResponse> {
+        let mut view = Self::resolve(req, query.view.as_deref(), query.q.as_deref()).await?;
+        let list = |value: &Option<String>| -> Vec<String> {
+            value
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        view.states = list(&query.state);
+        view.name = query.name.clone();
+        view.metadata = list(&query.metadata)
+            .into_iter()
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) => (key.trim().to_string(), Some(value.trim().to_string())),
+                None => (pair, None),
+            })
+            .collect();
+        if query.sort.is_some() {
+            view.sort = list(&query.sort);
+            if let Some(field) = view.sort.iter().find(|field| invalid_field(field)) {
+                return Err(
+                    AppError::bad_request(format!("Invalid sort field {:?}", field))
+                        .into_response(),
+                );
+            }
+        }
+        Ok(view)
+    }
+
+    /// The view named by `view`, if any, narrowed by `q`.
+    pub async fn resolve(
+        req: &HttpRequest,
+        view: Option<&str>,
+        q: Option<&str>,
+    ) -> Result<View, HttpResponse> {
+        let mut resolved = match view {
+            Some(name) => Self::compile(&load(req, name).await?)
+                .map_err(|e| AppError::internal(e).into_response())?,
+            None => View::default(),
+        };
+        if let Some(q) = q {
+            resolved.filters.push(
+                query::parse(q).map_err(|e| {
+                    AppError::bad_request(format!("Invalid q: {}", e)).into_response()
+                })?,
+            );
+        }
+        Ok(resolved)
+    }
+
+    fn compile(view: &SavedView) -> Result<View, String> {
+        let filters = match &view.q {
+            Some(q) => vec![query::parse(q).map_err(|e| format!("Invalid q: {}", e))?],
+            None => Vec::new(),
+        };
+        if let Some(field) = view
+            .fields
+            .iter()
+            .chain(&view.sort)
+            .find(|field| invalid_field(field))
+        {
+            return Err(format!("Invalid field {:?}", field));
+        }
+        Ok(View {
+            filters,
+            fields: view.fields.clone(),
+            sort: view.sort.clone(),
+            ..View::default()
+        })
+    }
+
+    pub fn matches(&self, machine: &Value) -> bool {
+        self.filters.iter().all(|filter| filter.matches(machine))
+            && (self.states.is_empty()
+                || machine["state"]
+                    .as_str()
+                    .is_some_and(|state| self.states.iter().any(|s| s == state)))
+            && self.name.as_ref().is_none_or(|name| {
+                machine["name"]
+                    .as_str()
+                    .is_some_and(|machine_name| machine_name.contains(name.as_str()))
+            })
+            && self.metadata.iter().all(|(key, value)| {
+                match &machine["config"]["metadata"][key.as_str()] {
+                    Value::Null => false,
+                    actual => value.as_ref().is_none_or(|value| actual == value.as_str()),
+                }
+            })
+    }
+
+    /// What the machine is ordered by: the sort fields, then its ID to break ties.
+    fn sort_keys(&self, machine: &Value) -> Vec<Value> {
+        self.sort
+            .iter()
+            .map(|key| query::field(machine, key.trim_start_matches('-')).clone())
+            .chain([machine["id"].clone()])
+            .collect()
+    }
+
+    fn compare_keys(&self, a: &[Value], b: &[Value]) -> Ordering {
+        let descending = self.sort.iter().map(|key| key.starts_with('-'));
+        a.iter()
+            .zip(b)
+            .zip(descending.chain([false]))
+            .map(|((a, b), descending)| {
+                let ordering = query::sort_order(a, b);
+                if descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    /// Keeps the machines that match, sorted. Pages need an order even when none was asked
+    /// for, so they're sorted by ID.
+    fn select(&self, machines: &mut Vec<Value>, paged: bool) {
+        machines.retain(|machine| self.matches(machine));
+        if !self.sort.is_empty() || paged {
+            machines.sort_by(|a, b| self.compare_keys(&self.sort_keys(a), &self.sort_keys(b)));
+        }
+    }
+
+    /// Filters a list of machines, then sorts it and keeps the view's fields of each.
+    pub fn apply(&self, machines: &mut Value) {
+        let Value::Array(machines) = machines else {
+            return;
+        };
+        self.select(machines, false);
+        if !self.fields.is_empty() {
+            for machine in machines.iter_mut() {
+                *machine = self.project(machine);
+            }
+        }
+    }
+
+    /// Like `apply`, but answers with one page of the machines, as a `MachinePage`.
+    pub fn apply_paged(&self, machines: &mut Value, page: &Page) {
+        let Value::Array(all) = machines else {
+            return;
+        };
+        self.select(all, true);
+        let total = all.len();
+        let start = match &page.after {
+            Some(after) => all
+                .iter()
+                .position(|machine| self.compare_keys(&self.sort_keys(machine), after).is_gt())
+                .unwrap_or(total),
+            None => 0,
+        };
+        let start = (start + page.offset).min(total);
+        let end = page
+            .limit
+            .map_or(total, |limit| start.saturating_add(limit).min(total));
+        let next_cursor = (end < total && end > start)
+            .then(|| URL_SAFE_NO_PAD.encode(json!(self.sort_keys(&all[end - 1])).to_string()));
+        let mut paged: Vec<Value> = all.drain(start..end).collect();
+        if !self.fields.is_empty() {
+            for machine in paged.iter_mut() {
+                *machine = self.project(machine);
+            }
+        }
+        *machines = json!(MachinePage {
+            machines: paged,
+            total,
+            next_cursor,
+        });
+    }
+
+    /// The view's fields of the machine, nested as they are in it. A field found only
+    /// under `config` is kept at the path asked for.
+    fn project(&self, machine: &Value) -> Value {
+        let mut projected = Value::Object(Map::new());
+        for path in &self.fields {
+            let value = query::field(machine, path);
+            if value.is_null() {
+                continue;
+            }
+            let mut segments: Vec<&str> = path.split('.').collect();
+            let last = segments.pop().unwrap_or_default();
+            let mut target = &mut projected;
+            for segment in segments {
+                if !target[segment].is_object() {
+                    target[segment] = Value::Object(Map::new());
+                }
+                target = &mut target[segment];
+            }
+            target[last] = value.clone();
+        }
+        projected
+    }
+}
+
+async fn load(req: &HttpRequest, name: &str) -> Result<SavedView, HttpResponse> {
+    let Some(store) = req.app_data::<web::Data<Store>>() else {
+        return Err(HttpResponse::InternalServerError().finish());
+    };
+    match store.get::<SavedView>(VIEWS, name).await {
+        Ok(Some(view)) => Ok(view),
+        Ok(None) => Err(AppError::not_found(format!("No view {}", name)).into_response()),
+        Err(e) => Err(AppError::internal(e.to_string()).into_response()),
+    }
+}
+
+/// Saves the view, replacing any of the same name.
+#[post("/v0/views")]
+async fn put_view(
+    req: HttpRequest,
+    body: web::Json<SavedView>,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let mut view = body.into_inner();
+    if view.name.is_empty() {
+        return AppError::bad_request("A view needs a name").into_response();
+    }
+    if let Err(e) = View::compile(&view) {
+        return AppError::bad_request(e).into_response();
+    }
+    view.updated_by = req
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.subject.clone());
+    view.updated_at = Utc::now();
+    match store.put(VIEWS, &view.name, &view).await {
+        Ok(()) => HttpResponse::Created().json(view),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/views")]
+async fn list_views(store: web::Data<Store>) -> impl Responder {
+    match store.list::<SavedView>(VIEWS).await {
+        Ok(mut views) => {
+            views.sort_by(|a, b| a.name.cmp(&b.name));
+            HttpResponse::Ok().json(views)
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/views/{name}")]
+async fn get_view(req: HttpRequest, path: web::Path<String>) -> impl Responder {
+    match load(&req, &path).await {
+        Ok(view) => HttpResponse::Ok().json(view),
+        Err(response) => response,
+    }
+}
+
+#[delete("/v0/views/{name}")]
+async fn delete_view(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    match store.delete(VIEWS, &path).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => AppError::not_found(format!("No view {}", path)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+async fn render<B: Backend>(backend: &B, name: &str, app: &str, view: &View) -> HttpResponse {
+    let machines = match backend.list_machines(app).await {
+        Ok(machines) => machines,
+        Err(e) => {
+            return AppError::bad_gateway(format!("Failed to list machines of {}: {}", app, e))
+                .into_response();
+        }
+    };
+    let mut states: BTreeMap<String, u64> = BTreeMap::new();
+    for machine in machines
+        .iter()
+        .filter(|machine| fleets::is_live(machine) && view.matches(machine))
+    {
+        let state = machine["state"].as_str().unwrap_or("unknown");
+        *states.entry(state.to_string()).or_default() += 1;
+    }
+
+    let mut out = String::new();
+    metrics::header(
+        &mut out,
+        "flyd_view_machines",
+        "gauge",
+        "Machines in the view, by state.",
+    );
+    for (state, count) in states {
+        let labels = [
+            ("view", name.to_string()),
+            ("app", app.to_string()),
+            ("state", state),
+        ];
+        metrics::sample(&mut out, "flyd_view_machines", &labels, count as f64);
+    }
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(out)
+}
+
+/// Prometheus gauges of the app's machines in the view, for scraping with the caller's
+/// token.
+#[get("/metrics/views/{name}")]
+async fn view_metrics(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ViewMetricsQuery>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let view = match View::resolve(&req, Some(&path), None).await {
+        Ok(view) => view,
+        Err(response) => return response,
+    };
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return render(docker.get_ref(), &path, &query.app_name, &view).await;
+    }
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    render(&client, &path, &query.app_name, &view).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(put_view)
+        .service(list_views)
+        .service(get_view)
+        .service(delete_view)
+        .service(view_metrics);
+}
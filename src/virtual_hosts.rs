@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use actix_web::http::header::{HOST, HeaderValue};
+use actix_web::{HttpRequest, web};
+
+use crate::auth::{self, Identity};
+use crate::config::{Config, VirtualHostConfig};
+
+/// A Fly account flyd serves on a hostname of its own.
+pub struct VirtualHost {
+    pub name: String,
+    pub config: VirtualHostConfig,
+    fly_authorization: Option<HeaderValue>,
+}
+
+impl VirtualHost {
+    /// The Machines API this host's requests go to, if it isn't flyd's own.
+    pub fn api_hostname(&self, use_private: bool) -> Option<&str> {
+        if use_private {
+            self.config.private_hostname.as_deref()
+        } else {
+            self.config.public_hostname.as_deref()
+        }
+    }
+
+    /// Why the caller may not use this host, if they may not.
+    fn refusal(&self, identity: &Identity) -> Option<String> {
+        if !self.config.providers.is_empty()
+            && !self.config.providers.iter().any(|p| p == identity.provider)
+        {
+            return Some(format!(
+                "{} callers may not use {}",
+                identity.provider, self.name
+            ));
+        }
+        // Fly token callers keep acting with their own token.
+        if identity.provider == "fly_token"
+            || self.config.subjects.contains(&identity.subject)
+            || identity
+                .roles
+                .iter()
+                .any(|role| self.config.roles.contains(role))
+        {
+            return None;
+        }
+        Some(format!("{} may not use {}", identity.subject, self.name))
+    }
+}
+
+/// `[virtual_hosts]`, keyed by lowercase hostname.
+pub struct VirtualHosts {
+    hosts: HashMap<String, VirtualHost>,
+}
+
+impl VirtualHosts {
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        if config.virtual_hosts.is_empty() {
+            return Ok(None);
+        }
+        let mut hosts = HashMap::new();
+        for (name, host) in &config.virtual_hosts {
+            let fly_authorization = match &host.fly_token {
+                Some(token) => Some(
+                    auth::server_token("virtual_hosts", Some(token), config)
+                        .map_err(|e| format!("virtual_hosts.{}: {}", name, e))?,
+                ),
+                None => None,
+            };
+            hosts.insert(
+                name.to_ascii_lowercase(),
+                VirtualHost {
+                    name: name.clone(),
+                    config: host.clone(),
+                    fly_authorization,
+                },
+            );
+        }
+        Ok(Some(VirtualHosts { hosts }))
+    }
+}
+
+/// The hostname the request was sent to, without its port.
+fn hostname(req: &HttpRequest) -> Option<String> {
+    let host = match req.headers().get(HOST) {
+        Some(host) => host.to_str().ok()?,
+        // HTTP/2 requests carry it in the URI instead.
+        None => req.uri().host()?,
+    };
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if port.chars().all(|c| c.is_ascii_digit()) => hostname,
+        _ => host,
+    };
+    Some(hostname.to_ascii_lowercase())
+}
+
+/// The virtual host the request arrived on, if it's one.
+pub fn of(req: &HttpRequest) -> Option<&VirtualHost> {
+    let hosts = req.app_data::<web::Data<VirtualHosts>>()?;
+    hosts.hosts.get(&hostname(req)?)
+}
+
+/// Swaps the server token a caller would act with for their virtual host's, refusing
+/// callers the host isn't bound to.
+pub fn apply(req: &HttpRequest, identity: &mut Identity) -> Result<(), String> {
+    let Some(host) = of(req) else {
+        return Ok(());
+    };
+    if let Some(refusal) = host.refusal(identity) {
+        return Err(refusal);
+    }
+    if identity.provider == "fly_token" {
+        return Ok(());
+    }
+    if let Some(fly_authorization) = &host.fly_authorization {
+        identity.fly_authorization = fly_authorization.clone();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn hosts() -> web::Data<VirtualHosts> {
+        let mut config = Config::default();
+        config.virtual_hosts.insert(
+            "Acme.Example.com".to_string(),
+            VirtualHostConfig {
+                fly_token: Some("acme-token".to_string()),
+                providers: vec!["static_keys".to_string()],
+                subjects: vec!["alice".to_string()],
+                roles: vec!["acme".to_string()],
+                ..Default::default()
+            },
+        );
+        web::Data::new(VirtualHosts::from_config(&config).unwrap().unwrap())
+    }
+
+    fn identity(subject: &str, provider: &'static str, roles: &[&str]) -> Identity {
+        Identity {
+            subject: subject.to_string(),
+            provider,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            fly_authorization: HeaderValue::from_static("Bearer own"),
+            apps: None,
+        }
+    }
+
+    #[test]
+    fn selects_the_host_by_hostname_without_its_port() {
+        let req = TestRequest::default()
+            .insert_header((HOST, "ACME.example.com:8080"))
+            .app_data(hosts())
+            .to_http_request();
+        assert_eq!(of(&req).unwrap().name, "Acme.Example.com");
+
+        let req = TestRequest::default()
+            .insert_header((HOST, "other.example.com"))
+            .app_data(hosts())
+            .to_http_request();
+        assert!(of(&req).is_none());
+    }
+
+    #[test]
+    fn swaps_in_the_host_token_for_bound_callers() {
+        let req = TestRequest::default()
+            .insert_header((HOST, "acme.example.com"))
+            .app_data(hosts())
+            .to_http_request();
+
+        let mut alice = identity("alice", "static_keys", &[]);
+        apply(&req, &mut alice).unwrap();
+        assert_eq!(alice.fly_authorization, "Bearer acme-token");
+
+        let mut bob = identity("bob", "static_keys", &["acme"]);
+        apply(&req, &mut bob).unwrap();
+        assert_eq!(bob.fly_authorization, "Bearer acme-token");
+    }
+
+    #[test]
+    fn refuses_callers_the_host_isnt_bound_to() {
+        let req = TestRequest::default()
+            .insert_header((HOST, "acme.example.com"))
+            .app_data(hosts())
+            .to_http_request();
+
+        let mut mallory = identity("mallory", "static_keys", &["ops"]);
+        assert!(apply(&req, &mut mallory).is_err());
+        assert_eq!(mallory.fly_authorization, "Bearer own");
+
+        let mut alice = identity("alice", "jwt", &[]);
+        assert!(apply(&req, &mut alice).is_err());
+    }
+
+    #[test]
+    fn leaves_callers_on_other_hosts_alone() {
+        let req = TestRequest::default()
+            .insert_header((HOST, "other.example.com"))
+            .app_data(hosts())
+            .to_http_request();
+        let mut mallory = identity("mallory", "static_keys", &[]);
+        apply(&req, &mut mallory).unwrap();
+        assert_eq!(mallory.fly_authorization, "Bearer own");
+    }
+}
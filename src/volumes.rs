@@ -0,0 +1,191 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, post, web};
+use flyd::models::{ExtendVolumeRequest, ListVolumesRequest, NewVolumeRequest, VolumeRequest};
+use serde_json::{Value, json};
+
+use crate::auth::Identity;
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::docker::DockerBackend;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::fly_client::{FlyClient, FlyError, WithChecks};
+use crate::prepare_request;
+use crate::slo::SloTracker;
+
+const VOLUME_DELETED: &str = "volume.deleted";
+
+fn client(
+    req: &HttpRequest,
+    use_private_api: bool,
+    http_client: &web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> Result<FlyClient, HttpResponse> {
+    let (headers, api_hostname) = prepare_request(req, use_private_api)?;
+    Ok(
+        FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+            .with_slo(slo.into_inner()),
+    )
+}
+
+fn respond(result: Result<Value, FlyError>) -> HttpResponse {
+    match result {
+        Ok(value) => HttpResponse::Ok().json(value),
+        Err(e) => AppError::from(&e).into_response(),
+    }
+}
+
+/// Creates a volume. Unless the request says otherwise, it goes on a different host from
+/// the app's other volumes of the same name, per `capacity.require_unique_zone`.
+#[post("/v0/volumes/new")]
+async fn create_volume(
+    req: HttpRequest,
+    body: web::Json<NewVolumeRequest>,
+    config: web::Data<Config>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let mut volume = Value::Object(body.config.clone());
+    if volume.get("require_unique_zone").is_none() {
+        volume["require_unique_zone"] = json!(config.capacity.require_unique_zone);
+    }
+    respond(client.create_volume(&body.app_name, &volume).await)
+}
+
+#[get("/v0/volumes/list")]
+async fn list_volumes(
+    req: HttpRequest,
+    query: web::Query<ListVolumesRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let volumes = if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        docker
+            .list_volumes(&query.app_name)
+            .await
+            .map_err(|e| AppError::internal(e.to_string()).into_response())
+    } else {
+        match client(&req, query.use_private_api, &http_client, slo) {
+            Ok(client) => client
+                .list_volumes(&query.app_name)
+                .await
+                .map_err(|e| AppError::from(&e).into_response()),
+            Err(response) => Err(response),
+        }
+    };
+    match volumes {
+        Ok(volumes) => HttpResponse::Ok().json(volumes),
+        Err(response) => response,
+    }
+}
+
+#[get("/v0/volumes/get")]
+async fn get_volume(
+    req: HttpRequest,
+    query: web::Query<VolumeRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => respond(client.get_volume(&query.app_name, &query.volume_id).await),
+        Err(response) => response,
+    }
+}
+
+/// Grows a volume. Fly answers with the volume and whether its machine needs a restart.
+#[post("/v0/volumes/extend")]
+async fn extend_volume(
+    req: HttpRequest,
+    body: web::Json<ExtendVolumeRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => respond(
+            client
+                .extend_volume(&body.app_name, &body.volume_id, body.size_gb)
+                .await,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Deletes a volume and its data, recording a `volume.deleted` event.
+#[post("/v0/volumes/delete")]
+async fn delete_volume(
+    req: HttpRequest,
+    body: web::Json<VolumeRequest>,
+    events: web::Data<EventLog>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let client = match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let deleted = client.delete_volume(&body.app_name, &body.volume_id).await;
+    if deleted.is_ok() {
+        events.record(
+            VOLUME_DELETED,
+            Some(&body.app_name),
+            None,
+            json!({
+                "volume_id": body.volume_id,
+                "by": req.extensions().get::<Identity>().map(|identity| identity.subject.clone()),
+            }),
+        );
+    }
+    respond(deleted)
+}
+
+/// Takes a snapshot now, on top of Fly's daily ones.
+#[post("/v0/volumes/snapshot")]
+async fn snapshot_volume(
+    req: HttpRequest,
+    body: web::Json<VolumeRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, body.use_private_api, &http_client, slo) {
+        Ok(client) => match client
+            .create_snapshot(&body.app_name, &body.volume_id)
+            .await
+        {
+            Ok(()) => HttpResponse::Accepted().finish(),
+            Err(e) => AppError::from(&e).into_response(),
+        },
+        Err(response) => response,
+    }
+}
+
+#[get("/v0/volumes/snapshots")]
+async fn list_snapshots(
+    req: HttpRequest,
+    query: web::Query<VolumeRequest>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    match client(&req, query.use_private_api, &http_client, slo) {
+        Ok(client) => match client
+            .list_snapshots(&query.app_name, &query.volume_id)
+            .await
+        {
+            Ok(snapshots) => HttpResponse::Ok().json(snapshots),
+            Err(e) => AppError::from(&e).into_response(),
+        },
+        Err(response) => response,
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_volume)
+        .service(list_volumes)
+        .service(get_volume)
+        .service(extend_volume)
+        .service(delete_volume)
+        .service(snapshot_volume)
+        .service(list_snapshots);
+}
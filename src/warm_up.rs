@@ -0,0 +1,97 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use flyd::models::WarmUp;
+use serde_json::Value;
+
+use crate::backend::Backend;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+async fn http_once(http: &reqwest::Client, url: &str, timeout: Duration) -> Result<(), String> {
+    let response = http
+        .get(url)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} answered {}", url, response.status()))
+    }
+}
+
+async fn exec_once<B: Backend>(
+    backend: &B,
+    app: &str,
+    machine_id: &str,
+    command: &[String],
+    timeout_secs: u64,
+) -> Result<(), String> {
+    let output = backend
+        .exec_machine(app, machine_id, command, timeout_secs)
+        .await
+        .map_err(|e| e.to_string())?;
+    match output["exit_code"].as_i64() {
+        Some(0) => Ok(()),
+        code => Err(format!("Warm-up command exited with {:?}", code)),
+    }
+}
+
+/// Runs the warm-up on a machine that was just updated, retrying until it succeeds or its
+/// timeout is up. The machine is still starting, so early failures are expected.
+pub async fn run<B: Backend>(
+    backend: &B,
+    app: &str,
+    machine: &Value,
+    warm_up: &WarmUp,
+) -> Result<(), String> {
+    let machine_id = machine["id"].as_str().unwrap_or_default();
+    let timeout = match warm_up {
+        WarmUp::Delay { secs } => {
+            tokio::time::sleep(Duration::from_secs(*secs)).await;
+            return Ok(());
+        }
+        WarmUp::Http { timeout_secs, .. } | WarmUp::Exec { timeout_secs, .. } => {
+            Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+        }
+    };
+    let http = reqwest::Client::new();
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let result = match warm_up {
+            WarmUp::Http { port, path, .. } => {
+                let Some(ip) = machine["private_ip"]
+                    .as_str()
+                    .and_then(|address| address.parse::<IpAddr>().ok())
+                else {
+                    return Err("The machine has no private IP to warm up over HTTP".to_string());
+                };
+                let url = format!("http://{}{}", SocketAddr::new(ip, *port), path);
+                http_once(&http, &url, remaining.max(Duration::from_secs(1))).await
+            }
+            WarmUp::Exec { command, .. } => {
+                exec_once(
+                    backend,
+                    app,
+                    machine_id,
+                    command,
+                    remaining.as_secs().max(1),
+                )
+                .await
+            }
+            WarmUp::Delay { .. } => Ok(()),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if Instant::now() + RETRY_INTERVAL >= deadline => {
+                return Err(format!("Not warm after {}s: {}", timeout.as_secs(), e));
+            }
+            Err(e) => log::debug!("Machine {} isn't warm yet: {}", machine_id, e),
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
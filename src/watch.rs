@@ -0,0 +1,197 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::time::Duration;
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, web};
+use flyd::models::{ListMachinesRequest, MachineChange};
+use futures_util::{StreamExt, stream};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::docker::DockerBackend;
+use crate::fleets;
+use crate::fly_client::{FlyClient, WithChecks};
+use crate::prepare_request;
+use crate::shutdown;
+use crate::slo::SloTracker;
+use crate::views::View;
+
+pub const KEEPALIVE: &[u8] = b": keepalive\n\n";
+
+pub fn event(name: &str, data: &impl Serialize) -> web::Bytes {
+    web::Bytes::from(format!(
+        "event: {}\ndata: {}\n\n",
+        name,
+        serde_json::to_string(data).unwrap_or_default()
+    ))
+}
+
+fn change_kind(state: &str) -> &'static str {
+    match state {
+        "started" => "started",
+        "stopped" | "suspended" => "stopped",
+        _ => "changed",
+    }
+}
+
+/// One stream's view of the app: what it last saw of each machine, and the events not
+/// yet sent.
+struct Watch<B> {
+    backend: B,
+    app: String,
+    region: Option<String>,
+    view: View,
+    interval: Duration,
+    seen: Option<HashMap<String, MachineChange>>,
+    pending: VecDeque<web::Bytes>,
+}
+
+impl<B: Backend> Watch<B> {
+    /// Lists the machines and queues an event for each that was created, changed state
+    /// or went away since the last poll; the first poll queues a snapshot instead.
+    async fn poll(&mut self) {
+        let machines = match self.backend.list_machines(&self.app).await {
+            Ok(machines) => machines,
+            Err(e) => {
+                self.pending
+                    .push_back(event("error", &json!({ "error": e.to_string() })));
+                return;
+            }
+        };
+        let machines: Vec<Value> = machines
+            .into_iter()
+            .filter(|machine| {
+                fleets::is_live(machine)
+                    && self
+                        .region
+                        .as_ref()
+                        .is_none_or(|region| machine["region"] == region.as_str())
+                    && self.view.matches(machine)
+            })
+            .collect();
+        let current: HashMap<String, MachineChange> = machines
+            .iter()
+            .filter_map(|machine| {
+                Some((
+                    machine["id"].as_str()?.to_string(),
+                    MachineChange {
+                        machine_id: machine["id"].as_str()?.to_string(),
+                        name: machine["name"].as_str().map(str::to_string),
+                        region: machine["region"].as_str().map(str::to_string),
+                        state: machine["state"].as_str().map(str::to_string),
+                        previous_state: None,
+                    },
+                ))
+            })
+            .collect();
+
+        let Some(seen) = &self.seen else {
+            let mut snapshot = Value::Array(machines);
+            self.view.apply(&mut snapshot);
+            self.pending.push_back(event("snapshot", &snapshot));
+            self.seen = Some(current);
+            return;
+        };
+        for (id, machine) in &current {
+            match seen.get(id) {
+                None => self.pending.push_back(event("created", machine)),
+                Some(before) if before.state != machine.state => {
+                    let change = MachineChange {
+                        previous_state: before.state.clone(),
+                        ..machine.clone()
+                    };
+                    let kind = change_kind(machine.state.as_deref().unwrap_or_default());
+                    self.pending.push_back(event(kind, &change));
+                }
+                Some(_) => {}
+            }
+        }
+        for (id, before) in seen {
+            if !current.contains_key(id) {
+                let change = MachineChange {
+                    state: None,
+                    previous_state: before.state.clone(),
+                    ..before.clone()
+                };
+                self.pending.push_back(event("destroyed", &change));
+            }
+        }
+        self.seen = Some(current);
+    }
+}
+
+fn respond<B: Backend + 'static>(watch: Watch<B>) -> HttpResponse {
+    let events = stream::unfold(watch, |mut watch| async move {
+        loop {
+            if let Some(bytes) = watch.pending.pop_front() {
+                return Some((Ok::<_, Infallible>(bytes), watch));
+            }
+            if watch.seen.is_some() {
+                tokio::time::sleep(watch.interval).await;
+            }
+            watch.poll().await;
+            // Lets the stream notice a client that went away.
+            if watch.pending.is_empty() {
+                watch.pending.push_back(web::Bytes::from_static(KEEPALIVE));
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        // Ends at shutdown rather than holding the drain open; clients reconnect elsewhere.
+        .streaming(events.take_until(shutdown::draining()))
+}
+
+/// A Server-Sent Events stream of the app's machines: a `snapshot` of them, then a
+/// `created`, `started`, `stopped`, `changed` or `destroyed` event as each changes. flyd
+/// lists the machines every `machines.watch_interval_secs` for as long as it's open. With a
+/// `view`, `q`, `state` or `name`, only machines matching them are watched.
+#[get("/v0/machines/watch")]
+async fn watch_machines(
+    req: HttpRequest,
+    query: web::Query<ListMachinesRequest>,
+    config: web::Data<Config>,
+    http_client: web::Data<reqwest::Client>,
+    slo: web::Data<SloTracker>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let view = match View::from_query(&req, &query).await {
+        Ok(view) => view,
+        Err(response) => return response,
+    };
+    let interval = Duration::from_secs(config.machines.watch_interval_secs.max(1));
+    if let Some(docker) = req.app_data::<web::Data<DockerBackend>>() {
+        return respond(Watch {
+            backend: docker.get_ref().clone(),
+            app: query.app_name,
+            region: query.region,
+            view,
+            interval,
+            seen: None,
+            pending: VecDeque::new(),
+        });
+    }
+    let (headers, api_hostname) = match prepare_request(&req, query.use_private_api) {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let client = FlyClient::new(http_client.get_ref().clone(), headers, api_hostname)
+        .with_slo(slo.into_inner());
+    respond(Watch {
+        backend: client,
+        app: query.app_name,
+        region: query.region,
+        view,
+        interval,
+        seen: None,
+        pending: VecDeque::new(),
+    })
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(watch_machines);
+}
@@ -0,0 +1,239 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::time::Duration;
+
+use actix_web::{HttpResponse, Responder, delete, get, post, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use flyd::models::{Event, NewWebhook, Webhook};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::backend::Backend;
+use crate::callbacks::{self, MACHINE_STATE, MachineStates, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::config::WebhooksConfig;
+use crate::errors::AppError;
+use crate::events::EventLog;
+use crate::features::{self, Features};
+use crate::store::Store;
+
+const WEBHOOKS: &str = "webhooks";
+const DELIVERY_TICK: Duration = Duration::from_secs(1);
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    hex::encode(bytes)
+}
+
+fn wants(webhook: &Webhook, event: &Event) -> bool {
+    event.kind == MACHINE_STATE
+        && event
+            .app
+            .as_ref()
+            .is_some_and(|app| webhook.apps.contains(app))
+        && (webhook.states.is_empty()
+            || event.detail["state"]
+                .as_str()
+                .is_some_and(|state| webhook.states.iter().any(|wanted| wanted == state)))
+}
+
+struct Delivery {
+    url: String,
+    secret: String,
+    body: Vec<u8>,
+    attempts: u32,
+    due: DateTime<Utc>,
+}
+
+/// Polls the apps webhooks watch, so their machines' transitions become `machine.state`
+/// events even where no callbacks arrive. Apps in `polled` are left to the callbacks
+/// poller.
+pub async fn watch<B: Backend>(
+    backend: B,
+    config: WebhooksConfig,
+    polled: Vec<String>,
+    store: web::Data<Store>,
+    events: web::Data<EventLog>,
+    states: web::Data<MachineStates>,
+    features: web::Data<Features>,
+) {
+    let interval = Duration::from_secs(config.poll_interval_secs.max(1));
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if !features.enabled(features::WEBHOOKS) {
+            continue;
+        }
+        let webhooks = match store.list::<Webhook>(WEBHOOKS).await {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                log::error!("Failed to load webhooks: {}", e);
+                continue;
+            }
+        };
+        let apps: BTreeSet<&String> = webhooks
+            .iter()
+            .flat_map(|webhook| &webhook.apps)
+            .filter(|app| !polled.contains(app))
+            .collect();
+        for app in apps {
+            if let Err(e) = callbacks::poll_app(&backend, &events, &states, app).await {
+                log::error!("Failed to poll machine states for {}: {}", app, e);
+            }
+        }
+    }
+}
+
+async fn send(
+    http: &reqwest::Client,
+    config: &WebhooksConfig,
+    delivery: &Delivery,
+) -> Result<(), String> {
+    let timestamp = Utc::now().timestamp().to_string();
+    http.post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(
+            SIGNATURE_HEADER,
+            callbacks::sign(&delivery.secret, &timestamp, &delivery.body),
+        )
+        .header(TIMESTAMP_HEADER, timestamp)
+        .body(delivery.body.clone())
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Queues a delivery of each `machine.state` event to every webhook wanting it, and
+/// sends them in order, retrying each failed one with exponential backoff.
+pub async fn deliver(
+    mut receiver: broadcast::Receiver<Event>,
+    http: reqwest::Client,
+    config: WebhooksConfig,
+    store: web::Data<Store>,
+    features: web::Data<Features>,
+) {
+    let mut queue: VecDeque<Delivery> = VecDeque::new();
+    let mut ticker = tokio::time::interval(DELIVERY_TICK);
+    loop {
+        tokio::select! {
+            received = receiver.recv() => {
+                let event = match received {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Webhook delivery fell behind, skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                // Transitions while webhooks are off aren't delivered later.
+                if event.kind != MACHINE_STATE || !features.enabled(features::WEBHOOKS) {
+                    continue;
+                }
+                let webhooks = match store.list::<Webhook>(WEBHOOKS).await {
+                    Ok(webhooks) => webhooks,
+                    Err(e) => {
+                        log::error!("Failed to load webhooks: {}", e);
+                        continue;
+                    }
+                };
+                let body = serde_json::to_vec(&event).unwrap_or_default();
+                for webhook in webhooks.into_iter().filter(|webhook| wants(webhook, &event)) {
+                    queue.push_back(Delivery {
+                        url: webhook.url,
+                        secret: webhook.secret.unwrap_or_default(),
+                        body: body.clone(),
+                        attempts: 0,
+                        due: Utc::now(),
+                    });
+                }
+            }
+            _ = ticker.tick(), if features.enabled(features::WEBHOOKS) => {
+                let now = Utc::now();
+                let mut waiting = VecDeque::new();
+                while let Some(mut delivery) = queue.pop_front() {
+                    if delivery.due > now {
+                        waiting.push_back(delivery);
+                        continue;
+                    }
+                    delivery.attempts += 1;
+                    let Err(e) = send(&http, &config, &delivery).await else {
+                        continue;
+                    };
+                    if delivery.attempts >= config.max_attempts {
+                        log::error!(
+                            "Giving up on a webhook delivery to {} after {} attempts: {}",
+                            delivery.url,
+                            delivery.attempts,
+                            e
+                        );
+                        continue;
+                    }
+                    let backoff = config.retry_base_secs.max(1) << (delivery.attempts - 1).min(16);
+                    log::warn!(
+                        "Failed to deliver a webhook to {}, retrying in {}s: {}",
+                        delivery.url,
+                        backoff,
+                        e
+                    );
+                    delivery.due = Utc::now() + TimeDelta::seconds(backoff as i64);
+                    waiting.push_back(delivery);
+                }
+                queue = waiting;
+            }
+        }
+    }
+}
+
+#[post("/v0/webhooks")]
+async fn create_webhook(body: web::Json<NewWebhook>, store: web::Data<Store>) -> impl Responder {
+    let new = body.into_inner();
+    if reqwest::Url::parse(&new.url).is_err() {
+        return AppError::bad_request(format!("Invalid url {}", new.url)).into_response();
+    }
+    if new.apps.is_empty() {
+        return AppError::bad_request("A webhook needs apps to watch").into_response();
+    }
+    let webhook = Webhook {
+        id: random_hex(8),
+        url: new.url,
+        apps: new.apps,
+        states: new.states,
+        secret: Some(new.secret.unwrap_or_else(|| random_hex(24))),
+        created_at: Utc::now(),
+    };
+    match store.put(WEBHOOKS, &webhook.id, &webhook).await {
+        Ok(()) => HttpResponse::Created().json(webhook),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[get("/v0/webhooks")]
+async fn list_webhooks(store: web::Data<Store>) -> impl Responder {
+    match store.list::<Webhook>(WEBHOOKS).await {
+        Ok(mut webhooks) => {
+            webhooks.sort_by_key(|webhook| webhook.created_at);
+            for webhook in &mut webhooks {
+                webhook.secret = None;
+            }
+            HttpResponse::Ok().json(webhooks)
+        }
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+#[delete("/v0/webhooks/{id}")]
+async fn delete_webhook(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
+    match store.delete(WEBHOOKS, &path).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => AppError::not_found(format!("No webhook {}", path)).into_response(),
+        Err(e) => AppError::internal(e.to_string()).into_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_webhook)
+        .service(list_webhooks)
+        .service(delete_webhook);
+}
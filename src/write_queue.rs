@@ -0,0 +1,159 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use actix_web::web;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+
+use crate::config::WriteQueueConfig;
+use crate::metrics;
+use crate::store::{Store, StoreError};
+
+pub enum Write {
+    Put {
+        collection: &'static str,
+        key: String,
+        value: serde_json::Value,
+    },
+    /// Adds to numeric fields of a record, creating it from `initial` first if missing.
+    Increment {
+        collection: &'static str,
+        key: String,
+        initial: serde_json::Value,
+        counters: Vec<(&'static str, u64)>,
+    },
+}
+
+#[derive(Default)]
+struct Stats {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Bounded queue in front of the store for writes nobody waits on (events, usage), drained
+/// by a background writer. When the store falls behind, writes are dropped and counted
+/// rather than slowing down requests.
+#[derive(Clone)]
+pub struct WriteQueue {
+    sender: mpsc::Sender<Write>,
+    enqueue_timeout: Duration,
+    stats: Arc<Stats>,
+}
+
+impl WriteQueue {
+    pub fn new(config: &WriteQueueConfig) -> (Self, mpsc::Receiver<Write>) {
+        let (sender, receiver) = mpsc::channel(config.capacity.max(1));
+        let queue = WriteQueue {
+            sender,
+            enqueue_timeout: Duration::from_millis(config.enqueue_timeout_ms),
+            stats: Arc::new(Stats::default()),
+        };
+        (queue, receiver)
+    }
+
+    fn dropped(&self) {
+        let dropped = self.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped == 1 || dropped.is_multiple_of(1000) {
+            log::warn!("Write queue full, {} writes dropped so far", dropped);
+        }
+    }
+
+    /// Enqueues without waiting; for callers that can't await.
+    pub fn try_push(&self, write: Write) {
+        match self.sender.try_send(write) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.dropped(),
+            Err(TrySendError::Closed(_)) => log::error!("Write queue closed, dropping write"),
+        }
+    }
+
+    /// Enqueues, waiting up to `enqueue_timeout_ms` for room before dropping the write.
+    pub async fn push(&self, write: Write) {
+        if self.enqueue_timeout.is_zero() {
+            return self.try_push(write);
+        }
+        match self.sender.send_timeout(write, self.enqueue_timeout).await {
+            Ok(()) => {}
+            Err(SendTimeoutError::Timeout(_)) => self.dropped(),
+            Err(SendTimeoutError::Closed(_)) => log::error!("Write queue closed, dropping write"),
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        let depth = self.sender.max_capacity() - self.sender.capacity();
+        metrics::header(
+            out,
+            "flyd_write_queue_depth",
+            "gauge",
+            "Writes waiting for the background writer.",
+        );
+        metrics::sample(out, "flyd_write_queue_depth", &[], depth as f64);
+        for (name, help, value) in [
+            (
+                "flyd_write_queue_written_total",
+                "Writes applied to the store.",
+                &self.stats.written,
+            ),
+            (
+                "flyd_write_queue_dropped_total",
+                "Writes dropped because the queue was full.",
+                &self.stats.dropped,
+            ),
+            (
+                "flyd_write_queue_failed_total",
+                "Writes the store rejected.",
+                &self.stats.failed,
+            ),
+        ] {
+            metrics::header(out, name, "counter", help);
+            metrics::sample(out, name, &[], value.load(Ordering::Relaxed) as f64);
+        }
+    }
+}
+
+async fn apply(store: &Store, write: Write) -> Result<(), StoreError> {
+    match write {
+        Write::Put {
+            collection,
+            key,
+            value,
+        } => store.put(collection, &key, &value).await,
+        Write::Increment {
+            collection,
+            key,
+            initial,
+            counters,
+        } => {
+            let mut record = store
+                .get::<serde_json::Value>(collection, &key)
+                .await?
+                .unwrap_or(initial);
+            for (field, by) in counters {
+                record[field] = (record[field].as_u64().unwrap_or_default() + by).into();
+            }
+            store.put(collection, &key, &record).await
+        }
+    }
+}
+
+pub async fn run(
+    queue: WriteQueue,
+    mut receiver: mpsc::Receiver<Write>,
+    store: web::Data<Store>,
+    batch_size: usize,
+) {
+    let mut batch = Vec::with_capacity(batch_size.max(1));
+    while receiver.recv_many(&mut batch, batch_size.max(1)).await > 0 {
+        for write in batch.drain(..) {
+            match apply(&store, write).await {
+                Ok(()) => queue.stats.written.fetch_add(1, Ordering::Relaxed),
+                Err(e) => {
+                    log::error!("Write-behind failed: {}", e);
+                    queue.stats.failed.fetch_add(1, Ordering::Relaxed)
+                }
+            };
+        }
+    }
+}